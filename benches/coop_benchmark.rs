@@ -0,0 +1,77 @@
+//! Mixed workload: one client runs KEYS over a big keyspace in a loop while
+//! another issues GETs on the same worker thread. Reports GET latency with
+//! and without cooperative yielding.
+//!
+//! Run with `cargo bench --bench coop_benchmark`.
+use bytes::Bytes;
+use criterion::black_box;
+use redis_oxide::coop::Budget;
+use redis_oxide::keys::{key_interact, KeyOps};
+use redis_oxide::misc::all_keys;
+use redis_oxide::types::{State, StateRef};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const NUM_KEYS: usize = 200_000;
+const NUM_GETS: usize = 2_000;
+
+async fn mixed_workload(state: StateRef, yielding: bool) -> Vec<Duration> {
+    let done = Arc::new(AtomicBool::new(false));
+    let scanner = {
+        let state = state.clone();
+        let done = done.clone();
+        tokio::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                let mut budget = if yielding {
+                    Budget::default()
+                } else {
+                    Budget::unlimited()
+                };
+                black_box(all_keys(&state, &mut budget).await);
+                tokio::task::yield_now().await;
+            }
+        })
+    };
+    let mut latencies = Vec::with_capacity(NUM_GETS);
+    for _ in 0..NUM_GETS {
+        let start = Instant::now();
+        // Let the scanner run, as it would between two client requests.
+        tokio::task::yield_now().await;
+        black_box(key_interact(KeyOps::Get(Bytes::from_static(b"key-0")), state.clone()).await);
+        latencies.push(start.elapsed());
+    }
+    done.store(true, Ordering::Relaxed);
+    scanner.await.unwrap();
+    latencies
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort_unstable();
+    let pct = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:<12} GET latency p50={:?} p99={:?} max={:?}",
+        name,
+        pct(0.5),
+        pct(0.99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+fn main() {
+    let state = Arc::new(State::default());
+    for i in 0..NUM_KEYS {
+        state
+            .kv
+            .insert(format!("key-{}", i).into(), Bytes::from_static(b"value"));
+    }
+    // A single worker makes the scanner and the GETs compete for one thread.
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    report(
+        "no yielding",
+        rt.block_on(mixed_workload(state.clone(), false)),
+    );
+    report("yielding", rt.block_on(mixed_workload(state, true)));
+}
@@ -2,9 +2,9 @@ use bytes::Bytes;
 use bytes::BytesMut;
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use redis_oxide::asyncresp::RespParser;
-use redis_oxide::keys::{key_interact, KeyOps};
+use redis_oxide::keys::{key_interact, KeyOps, SetOptions};
 use redis_oxide::ops::{op_interact, translate};
-use redis_oxide::types::{RedisValueRef, ReturnValue, State};
+use redis_oxide::types::{RedisValueRef, ReturnValue, State, StateStore};
 use std::sync::Arc;
 use tokio_util::codec::Decoder;
 
@@ -31,10 +31,11 @@ fn bench_translate(c: &mut Criterion) {
         RedisValueRef::SimpleString(Bytes::from_static(b"foo")),
         RedisValueRef::SimpleString(value),
     ]);
+    let store = Arc::new(StateStore::default());
     let mut group = c.benchmark_group("translate");
     group.throughput(Throughput::Bytes(212));
     group.bench_function("translate", |b| {
-        b.iter(|| translate(black_box(value.clone())));
+        b.iter(|| translate(black_box(value.clone()), store.clone()));
     });
     group.finish();
 }
@@ -43,7 +44,11 @@ fn bench_interact(c: &mut Criterion) {
     let s = Arc::new(State::default());
     c.bench_function("KeyOps::Set", |b| {
         b.iter(|| async {
-            let f = KeyOps::Set(Bytes::from_static(b"foo"), Bytes::from_static(b"bar"));
+            let f = KeyOps::Set(
+                Bytes::from_static(b"foo"),
+                Bytes::from_static(b"bar"),
+                SetOptions::default(),
+            );
             key_interact(black_box(f), black_box(s.clone())).await;
         });
     });
@@ -53,15 +58,16 @@ fn bench_full_life_cycle(c: &mut Criterion) {
     c.bench_function("full_life_cycle", |b| {
         b.iter(|| async {
             let mut decoder = RespParser::default();
-            let s = Arc::new(State::default());
+            let store = Arc::new(StateStore::default());
+            let s = store.get_default();
             let scc = "*3\r\n$3\r\nset\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
             let mut buf = BytesMut::from(format!("{}", scc).as_str());
             let res = decoder
                 .decode(black_box(&mut buf))
                 .expect("parsing to work")
                 .unwrap();
-            let op = translate(black_box(res)).unwrap();
-            let res = op_interact(black_box(op), black_box(s.clone())).await;
+            let op = translate(black_box(res), store.clone()).unwrap();
+            let (res, _) = op_interact(black_box(op), black_box(s.clone())).await;
             assert_eq!(res, ReturnValue::Ok);
         });
     });
@@ -6,7 +6,7 @@ use std::str;
 
 use crate::types::{RedisValueRef, NULL_ARRAY, NULL_BULK_STRING};
 
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Debug)]
@@ -17,6 +17,18 @@ pub enum RESPError {
     IntParseFailure,
     BadBulkStringSize(i64),
     BadArraySize(i64),
+    /// Arrays nested deeper than the parser's max depth.
+    NestingTooDeep(usize),
+    /// An inline command with a quote left open.
+    UnbalancedQuotes,
+    /// A bulk string longer than the parser's limit.
+    BulkStringTooBig(i64),
+    /// An array with more elements than the parser's limit.
+    ArrayTooLong(i64),
+    /// An inline command without a line ending within the limit.
+    InlineTooLong,
+    /// An HTTP request, most likely a web page attacking the server.
+    CrossProtocol,
 }
 
 impl From<std::io::Error> for RESPError {
@@ -25,10 +37,105 @@ impl From<std::io::Error> for RESPError {
     }
 }
 
+impl std::fmt::Display for RESPError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RESPError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            RESPError::UnknownStartingByte => write!(f, "unknown type byte"),
+            RESPError::IOError(e) => write!(f, "{}", e),
+            RESPError::IntParseFailure => write!(f, "invalid integer"),
+            RESPError::BadBulkStringSize(_) | RESPError::BulkStringTooBig(_) => {
+                write!(f, "invalid bulk length")
+            }
+            RESPError::BadArraySize(_) | RESPError::ArrayTooLong(_) => {
+                write!(f, "invalid multibulk length")
+            }
+            RESPError::NestingTooDeep(_) => write!(f, "arrays nested too deep"),
+            RESPError::UnbalancedQuotes => write!(f, "unbalanced quotes in request"),
+            RESPError::InlineTooLong => write!(f, "too big inline request"),
+            RESPError::CrossProtocol => write!(f, "HTTP request sent to the RESP port"),
+        }
+    }
+}
+
+/// How deeply arrays may nest before a frame is refused. Commands are flat
+/// arrays, so this is generous; it only exists to bound recursion.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+/// Longest bulk string accepted, redis' default `proto-max-bulk-len`.
+pub const DEFAULT_MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+/// Most elements accepted in one array.
+pub const DEFAULT_MAX_ARRAY_LEN: u64 = 1024 * 1024;
+/// Longest inline command, as in redis.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// What the decoder accepts. Frames over a limit are refused with an error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RespLimits {
+    pub max_bulk_len: u64,
+    pub max_array_len: u64,
+    pub max_depth: usize,
+}
+
+impl Default for RespLimits {
+    fn default() -> Self {
+        RespLimits {
+            max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            max_array_len: DEFAULT_MAX_ARRAY_LEN,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+/// How far the frame at the start of the buffer has been decoded, so
+/// decoding resumes there once more bytes arrive instead of starting over.
+///
+/// Positions are offsets into the buffer, which only grows until the frame
+/// is split off.
 #[derive(Default)]
-pub struct RespParser;
+struct Progress {
+    /// Where decoding continues.
+    pos: usize,
+    /// Don't try again until the buffer holds this many bytes, e.g. the
+    /// rest of a bulk string.
+    need: usize,
+    /// Arrays being filled, outermost first: elements left, and elements so far.
+    arrays: Vec<(usize, Vec<RedisBufSplit>)>,
+}
 
-type RedisResult = Result<Option<(usize, RedisBufSplit)>, RESPError>;
+#[derive(Default)]
+pub struct RespParser {
+    limits: RespLimits,
+    progress: Progress,
+}
+
+impl RespLimits {
+    /// No size limits, for input we wrote ourselves (the AOF, a primary's
+    /// feed), where refusing a big frame would lose data.
+    pub fn unlimited() -> Self {
+        RespLimits {
+            max_bulk_len: u64::MAX,
+            max_array_len: u64::MAX,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl RespParser {
+    /// A parser refusing frames with arrays nested more than `max_depth` deep.
+    pub fn with_max_depth(max_depth: usize) -> Self {
+        RespParser::with_limits(RespLimits {
+            max_depth,
+            ..RespLimits::default()
+        })
+    }
+
+    pub fn with_limits(limits: RespLimits) -> Self {
+        RespParser {
+            limits,
+            progress: Progress::default(),
+        }
+    }
+}
 
 enum RedisBufSplit {
     String(BufSplit),
@@ -84,7 +191,7 @@ fn word(buf: &BytesMut, pos: usize) -> Option<(usize, BufSplit)> {
         return None;
     }
     memchr(b'\r', &buf[pos..]).and_then(|end| {
-        if end + 1 < buf.len() {
+        if pos + end + 1 < buf.len() {
             Some((pos + end + 2, BufSplit(pos, pos + end)))
         } else {
             None
@@ -103,89 +210,282 @@ fn int(buf: &BytesMut, pos: usize) -> Result<Option<(usize, i64)>, RESPError> {
     }
 }
 
-fn bulk_string(buf: &BytesMut, pos: usize) -> RedisResult {
-    match int(buf, pos)? {
-        Some((pos, -1)) => Ok(Some((pos, RedisBufSplit::NullBulkString))),
-        Some((pos, size)) if size >= 0 => {
-            let total_size = pos + size as usize;
-            if buf.len() < total_size + 2 {
-                Ok(None)
+/// One step of decoding.
+enum Step {
+    /// A whole value, and the position after it.
+    Value(usize, RedisBufSplit),
+    /// The header of an array of this many (at least one) elements, and the
+    /// position of its first element.
+    ArrayStart(usize, usize),
+    /// The buffer needs at least this many bytes to go on.
+    Incomplete(usize),
+}
+
+type StepResult = Result<Step, RESPError>;
+
+fn bulk_string(buf: &BytesMut, pos: usize, limits: &RespLimits) -> StepResult {
+    let (pos, size) = match int(buf, pos)? {
+        Some(header) => header,
+        None => return Ok(Step::Incomplete(buf.len() + 1)),
+    };
+    match size {
+        -1 => Ok(Step::Value(pos, RedisBufSplit::NullBulkString)),
+        size if size < 0 => Err(RESPError::BadBulkStringSize(size)),
+        size if size as u64 > limits.max_bulk_len => Err(RESPError::BulkStringTooBig(size)),
+        size => {
+            let end = pos.saturating_add(size as usize);
+            let next = end.saturating_add(2);
+            if buf.len() < next {
+                Ok(Step::Incomplete(next))
             } else {
-                let bb = RedisBufSplit::String(BufSplit(pos, total_size));
-                Ok(Some((total_size + 2, bb)))
+                Ok(Step::Value(next, RedisBufSplit::String(BufSplit(pos, end))))
             }
         }
-        Some((_pos, bad_size)) => Err(RESPError::BadBulkStringSize(bad_size)),
-        None => Ok(None),
     }
 }
 
-#[allow(clippy::unnecessary_wraps)]
-fn simple_string(buf: &BytesMut, pos: usize) -> RedisResult {
-    Ok(word(buf, pos).map(|(pos, word)| (pos, RedisBufSplit::String(word))))
+fn line(buf: &BytesMut, pos: usize, value: fn(BufSplit) -> RedisBufSplit) -> Step {
+    match word(buf, pos) {
+        Some((pos, word)) => Step::Value(pos, value(word)),
+        None => Step::Incomplete(buf.len() + 1),
+    }
+}
+
+fn resp_int(buf: &BytesMut, pos: usize) -> StepResult {
+    match int(buf, pos)? {
+        Some((pos, int)) => Ok(Step::Value(pos, RedisBufSplit::Int(int))),
+        None => Ok(Step::Incomplete(buf.len() + 1)),
+    }
 }
 
-#[allow(clippy::unnecessary_wraps)]
-fn error(buf: &BytesMut, pos: usize) -> RedisResult {
-    Ok(word(buf, pos).map(|(pos, word)| (pos, RedisBufSplit::Error(word))))
+fn array(buf: &BytesMut, pos: usize, limits: &RespLimits) -> StepResult {
+    let (pos, len) = match int(buf, pos)? {
+        Some(header) => header,
+        None => return Ok(Step::Incomplete(buf.len() + 1)),
+    };
+    match len {
+        -1 => Ok(Step::Value(pos, RedisBufSplit::NullArray)),
+        0 => Ok(Step::Value(pos, RedisBufSplit::Array(Vec::new()))),
+        len if len < 0 => Err(RESPError::BadArraySize(len)),
+        len if len as u64 > limits.max_array_len => Err(RESPError::ArrayTooLong(len)),
+        len => Ok(Step::ArrayStart(pos, len as usize)),
+    }
 }
 
-fn resp_int(buf: &BytesMut, pos: usize) -> RedisResult {
-    Ok(int(buf, pos)?.map(|(pos, int)| (pos, RedisBufSplit::Int(int))))
+/// Type bytes of RESP3 values clients never send. Lines starting with
+/// anything else that isn't RESP are inline commands.
+const RESP3_TYPES: &[u8] = b"_#,(!=%~>|";
+
+fn is_inline(first: u8) -> bool {
+    !b"+-$:*".contains(&first) && !RESP3_TYPES.contains(&first)
 }
 
-fn array(buf: &BytesMut, pos: usize) -> RedisResult {
-    match int(buf, pos)? {
-        None => Ok(None),
-        Some((pos, -1)) => Ok(Some((pos, RedisBufSplit::NullArray))),
-        Some((pos, num_elements)) if num_elements >= 0 => {
-            let mut values = Vec::with_capacity(num_elements as usize);
-            let mut curr_pos = pos;
-            for _ in 0..num_elements {
-                match parse(buf, curr_pos)? {
-                    Some((new_pos, value)) => {
-                        curr_pos = new_pos;
-                        values.push(value);
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// The byte of a `\xHH` escape starting at `pos`.
+fn hex_escape(line: &[u8], pos: usize) -> Option<u8> {
+    let hi = hex_digit(*line.get(pos + 2)?)?;
+    let lo = hex_digit(*line.get(pos + 3)?)?;
+    Some(hi * 16 + lo)
+}
+
+/// Split an inline command into arguments, like redis' `sdssplitargs`.
+///
+/// Arguments are separated by whitespace and may be quoted. Double quotes
+/// understand `\n`, `\r`, `\t`, `\b`, `\a`, `\xHH` and escaped
+/// characters; single quotes only `\'`. A closing quote must be followed by
+/// whitespace or the end of the line.
+fn split_inline(line: &[u8]) -> Result<Vec<Bytes>, RESPError> {
+    let mut args = Vec::new();
+    let mut pos = 0;
+    loop {
+        while pos < line.len() && line[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos == line.len() {
+            return Ok(args);
+        }
+        let mut arg = Vec::new();
+        let mut in_double = false;
+        let mut in_single = false;
+        loop {
+            let c = match line.get(pos) {
+                Some(c) => *c,
+                // Ran out of line inside quotes.
+                None if in_double || in_single => return Err(RESPError::UnbalancedQuotes),
+                None => break,
+            };
+            if in_double {
+                match (c, line.get(pos + 1).copied()) {
+                    (b'\\', Some(b'x')) if hex_escape(line, pos).is_some() => {
+                        arg.extend(hex_escape(line, pos));
+                        pos += 3;
+                    }
+                    (b'\\', Some(next)) => {
+                        arg.push(match next {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                        pos += 1;
+                    }
+                    (b'"', next) => {
+                        if next.map_or(false, |next| !next.is_ascii_whitespace()) {
+                            return Err(RESPError::UnbalancedQuotes);
+                        }
+                        pos += 1;
+                        break;
+                    }
+                    _ => arg.push(c),
+                }
+            } else if in_single {
+                match (c, line.get(pos + 1).copied()) {
+                    (b'\\', Some(b'\'')) => {
+                        arg.push(b'\'');
+                        pos += 1;
+                    }
+                    (b'\'', next) => {
+                        if next.map_or(false, |next| !next.is_ascii_whitespace()) {
+                            return Err(RESPError::UnbalancedQuotes);
+                        }
+                        pos += 1;
+                        break;
                     }
-                    None => return Ok(None),
+                    _ => arg.push(c),
+                }
+            } else {
+                match c {
+                    c if c.is_ascii_whitespace() => break,
+                    b'"' => in_double = true,
+                    b'\'' => in_single = true,
+                    _ => arg.push(c),
                 }
             }
-            Ok(Some((curr_pos, RedisBufSplit::Array(values))))
+            pos += 1;
         }
-        Some((_pos, bad_num_elements)) => Err(RESPError::BadArraySize(bad_num_elements)),
+        args.push(arg.into());
     }
 }
 
-fn parse(buf: &BytesMut, pos: usize) -> RedisResult {
-    if buf.is_empty() {
-        return Ok(None);
+/// An inline command: one line of space separated arguments, as typed into
+/// telnet. Returns the position after the line, and the command (None for a
+/// blank line).
+fn inline(buf: &BytesMut) -> Result<Option<(usize, Option<RedisValueRef>)>, RESPError> {
+    let end = match memchr(b'\n', buf) {
+        Some(end) if end > MAX_INLINE_LEN => return Err(RESPError::InlineTooLong),
+        Some(end) => end,
+        None if buf.len() > MAX_INLINE_LEN => return Err(RESPError::InlineTooLong),
+        None => return Ok(None),
+    };
+    let line = &buf[..end];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let args = split_inline(line)?;
+    // Like redis' securityWarningCommand: browsers can be made to send these.
+    if let Some(first) = args.first() {
+        if first.eq_ignore_ascii_case(b"post") || first.eq_ignore_ascii_case(b"host:") {
+            return Err(RESPError::CrossProtocol);
+        }
+    }
+    let command = if args.is_empty() {
+        None
+    } else {
+        Some(RedisValueRef::Array(
+            args.into_iter().map(RedisValueRef::BulkString).collect(),
+        ))
+    };
+    Ok(Some((end + 1, command)))
+}
+
+fn step(buf: &BytesMut, pos: usize, limits: &RespLimits) -> StepResult {
+    if buf.len() <= pos {
+        return Ok(Step::Incomplete(pos + 1));
     }
 
     match buf[pos] {
-        b'+' => simple_string(buf, pos + 1),
-        b'-' => error(buf, pos + 1),
-        b'$' => bulk_string(buf, pos + 1),
+        b'+' => Ok(line(buf, pos + 1, RedisBufSplit::String)),
+        b'-' => Ok(line(buf, pos + 1, RedisBufSplit::Error)),
+        b'$' => bulk_string(buf, pos + 1, limits),
         b':' => resp_int(buf, pos + 1),
-        b'*' => array(buf, pos + 1),
+        b'*' => array(buf, pos + 1, limits),
         _ => Err(RESPError::UnknownStartingByte),
     }
 }
 
+impl RespParser {
+    /// Decode the RESP frame at the start of `buf`, from where the last call stopped.
+    fn resume(&mut self, buf: &mut BytesMut) -> Result<Option<RedisValueRef>, RESPError> {
+        let progress = &mut self.progress;
+        if buf.len() < progress.need {
+            return Ok(None);
+        }
+        'values: loop {
+            let pos = progress.pos;
+            if buf.get(pos) == Some(&b'*') && progress.arrays.len() >= self.limits.max_depth {
+                return Err(RESPError::NestingTooDeep(pos));
+            }
+            let mut value = match step(buf, pos, &self.limits)? {
+                Step::Value(next, value) => {
+                    progress.pos = next;
+                    value
+                }
+                Step::ArrayStart(next, len) => {
+                    // Don't trust the declared size: every element takes at least three bytes.
+                    let room = (buf.len() - next) / 3;
+                    progress
+                        .arrays
+                        .push((len, Vec::with_capacity(len.min(room))));
+                    progress.pos = next;
+                    continue;
+                }
+                Step::Incomplete(need) => {
+                    progress.need = need;
+                    return Ok(None);
+                }
+            };
+            // Add the value to its array, and any arrays that completes to theirs.
+            while let Some((left, values)) = progress.arrays.last_mut() {
+                values.push(value);
+                *left -= 1;
+                if *left > 0 {
+                    continue 'values;
+                }
+                let (_, values) = progress.arrays.pop().expect("the array was just filled");
+                value = RedisBufSplit::Array(values);
+            }
+            let frame = buf.split_to(progress.pos);
+            *progress = Progress::default();
+            return Ok(Some(value.redis_value(&frame.freeze())));
+        }
+    }
+}
+
 impl Decoder for RespParser {
     type Item = RedisValueRef;
     type Error = RESPError;
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if buf.is_empty() {
-            return Ok(None);
-        }
-
-        match parse(buf, 0)? {
-            Some((pos, value)) => {
-                let our_data = buf.split_to(pos);
-                Ok(Some(value.redis_value(&our_data.freeze())))
+        // Inline commands can only start a frame.
+        while self.progress.pos == 0 && !buf.is_empty() && is_inline(buf[0]) {
+            match inline(buf)? {
+                Some((pos, command)) => {
+                    buf.advance(pos);
+                    if command.is_some() {
+                        return Ok(command);
+                    }
+                    // Blank lines are skipped, like redis does.
+                }
+                None => return Ok(None),
             }
-            None => Ok(None),
         }
+        let res = self.resume(buf);
+        if res.is_err() {
+            self.progress = Progress::default();
+        }
+        res
     }
 }
 
@@ -198,6 +498,39 @@ impl Encoder<RedisValueRef> for RespParser {
     }
 }
 
+/// Number of bytes needed to print `n` in decimal (including a sign).
+#[inline]
+fn int_len(n: i64) -> usize {
+    let mut len = if n < 0 { 2 } else { 1 };
+    let mut n = n.unsigned_abs();
+    while n >= 10 {
+        n /= 10;
+        len += 1;
+    }
+    len
+}
+
+impl RedisValueRef {
+    /// Estimate the number of bytes this value occupies once RESP encoded.
+    ///
+    /// Matches `write_redis_value` exactly, without allocating.
+    pub fn encoded_size(&self) -> usize {
+        match self {
+            RedisValueRef::Error(e) | RedisValueRef::SimpleString(e) => 1 + e.len() + 2,
+            RedisValueRef::ErrorMsg(e) => 1 + e.len() + 2,
+            RedisValueRef::BulkString(s) => 1 + int_len(s.len() as i64) + 2 + s.len() + 2,
+            RedisValueRef::Array(array) | RedisValueRef::Push(array) => {
+                1 + int_len(array.len() as i64)
+                    + 2
+                    + array.iter().map(RedisValueRef::encoded_size).sum::<usize>()
+            }
+            RedisValueRef::Int(i) => 1 + int_len(*i) + 2,
+            RedisValueRef::NullArray => NULL_ARRAY.len(),
+            RedisValueRef::NullBulkString => NULL_BULK_STRING.len(),
+        }
+    }
+}
+
 fn write_redis_value(item: RedisValueRef, dst: &mut BytesMut) {
     match item {
         RedisValueRef::Error(e) => {
@@ -230,6 +563,14 @@ fn write_redis_value(item: RedisValueRef, dst: &mut BytesMut) {
                 write_redis_value(redis_value, dst);
             }
         }
+        RedisValueRef::Push(array) => {
+            dst.extend_from_slice(b">");
+            dst.extend_from_slice(array.len().to_string().as_bytes());
+            dst.extend_from_slice(b"\r\n");
+            for redis_value in array {
+                write_redis_value(redis_value, dst);
+            }
+        }
         RedisValueRef::Int(i) => {
             dst.extend_from_slice(b":");
             dst.extend_from_slice(i.to_string().as_bytes());
@@ -435,4 +776,377 @@ mod resp_parser_tests {
         let s = "*-1\r\n";
         generic_test(s, t);
     }
+
+    #[test]
+    fn test_encoded_size() {
+        let values = vec![
+            RedisValueRef::Int(0),
+            RedisValueRef::Int(-1234),
+            RedisValueRef::Int(i64::MIN),
+            RedisValueRef::BulkString(Bytes::from_static(b"")),
+            RedisValueRef::BulkString(Bytes::from(vec![b'a'; 1234])),
+            RedisValueRef::SimpleString(ezs()),
+            RedisValueRef::Error(ezs()),
+            RedisValueRef::ErrorMsg(b"bad".to_vec()),
+            RedisValueRef::NullArray,
+            RedisValueRef::NullBulkString,
+            RedisValueRef::Array(vec![
+                RedisValueRef::Int(10),
+                RedisValueRef::Array(vec![RedisValueRef::BulkString(ezs())]),
+                RedisValueRef::NullBulkString,
+            ]),
+            RedisValueRef::Push(vec![RedisValueRef::BulkString(ezs())]),
+        ];
+        for value in values {
+            let mut buf = BytesMut::new();
+            RespParser::default()
+                .encode(value.clone(), &mut buf)
+                .unwrap();
+            assert_eq!(buf.len(), value.encoded_size(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_encode_push() {
+        let mut buf = BytesMut::new();
+        let push = RedisValueRef::Push(vec![
+            RedisValueRef::BulkString(Bytes::from_static(b"message")),
+            RedisValueRef::Int(1),
+        ]);
+        RespParser::default().encode(push, &mut buf).unwrap();
+        assert_eq!(&buf[..], b">2\r\n$7\r\nmessage\r\n:1\r\n");
+    }
+}
+
+/// Frames from the RESP2 and RESP3 specs, plus edge cases, checked both ways.
+/// They also seed the fuzzing below.
+#[cfg(test)]
+mod resp_spec_vectors {
+    use crate::asyncresp::{RESPError, RespLimits, RespParser, DEFAULT_MAX_DEPTH};
+    use crate::types::{bulk, RedisValueRef};
+    use bytes::{Bytes, BytesMut};
+    use proptest::prelude::*;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    enum Expect {
+        /// Decodes to the value, which encodes back to the same bytes.
+        RoundTrip(RedisValueRef),
+        /// Decodes to the value, but encodes differently (simple strings
+        /// decode as bulk strings).
+        Decodes(RedisValueRef),
+        /// Only ever sent by us.
+        Encodes(RedisValueRef),
+        /// Needs more bytes.
+        Incomplete,
+        /// Refused.
+        Invalid,
+    }
+
+    fn simple(s: &'static str) -> RedisValueRef {
+        RedisValueRef::SimpleString(Bytes::from_static(s.as_bytes()))
+    }
+
+    fn vectors() -> Vec<(&'static str, Expect)> {
+        use Expect::*;
+        use RedisValueRef::*;
+        vec![
+            // RESP2 spec examples
+            ("+OK\r\n", Decodes(bulk("OK"))),
+            (
+                "-Error message\r\n",
+                RoundTrip(Error(Bytes::from_static(b"Error message"))),
+            ),
+            (
+                "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+                RoundTrip(Error(Bytes::from_static(
+                    b"WRONGTYPE Operation against a key holding the wrong kind of value",
+                ))),
+            ),
+            (":1000\r\n", RoundTrip(Int(1000))),
+            ("$5\r\nhello\r\n", RoundTrip(bulk("hello"))),
+            ("$0\r\n\r\n", RoundTrip(bulk(""))),
+            ("$-1\r\n", RoundTrip(NullBulkString)),
+            ("*0\r\n", RoundTrip(Array(vec![]))),
+            (
+                "*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n",
+                RoundTrip(Array(vec![bulk("hello"), bulk("world")])),
+            ),
+            (
+                "*5\r\n:1\r\n:2\r\n:3\r\n:4\r\n$5\r\nhello\r\n",
+                RoundTrip(Array(vec![Int(1), Int(2), Int(3), Int(4), bulk("hello")])),
+            ),
+            ("*-1\r\n", RoundTrip(NullArray)),
+            (
+                "*2\r\n*3\r\n:1\r\n:2\r\n:3\r\n*2\r\n+Hello\r\n-World\r\n",
+                Decodes(Array(vec![
+                    Array(vec![Int(1), Int(2), Int(3)]),
+                    Array(vec![bulk("Hello"), Error(Bytes::from_static(b"World"))]),
+                ])),
+            ),
+            (
+                "*3\r\n$5\r\nhello\r\n$-1\r\n$5\r\nworld\r\n",
+                RoundTrip(Array(vec![bulk("hello"), NullBulkString, bulk("world")])),
+            ),
+            // RESP3 spec examples we send
+            (
+                ">4\r\n+pubsub\r\n+message\r\n+somechannel\r\n+this is the message\r\n",
+                Encodes(Push(vec![
+                    simple("pubsub"),
+                    simple("message"),
+                    simple("somechannel"),
+                    simple("this is the message"),
+                ])),
+            ),
+            (">0\r\n", Encodes(Push(vec![]))),
+            // RESP3 types clients never send
+            ("_\r\n", Invalid),
+            ("#t\r\n", Invalid),
+            (",1.23\r\n", Invalid),
+            ("(3492890328409238509324850943850943825024385\r\n", Invalid),
+            ("!21\r\nSYNTAX invalid syntax\r\n", Invalid),
+            ("=15\r\ntxt:Some string\r\n", Invalid),
+            ("%1\r\n+first\r\n:1\r\n", Invalid),
+            ("~1\r\n+orange\r\n", Invalid),
+            // Edge cases
+            (
+                "*2\r\n*-1\r\n$-1\r\n",
+                RoundTrip(Array(vec![NullArray, NullBulkString])),
+            ),
+            (
+                "*1\r\n*1\r\n*0\r\n",
+                RoundTrip(Array(vec![Array(vec![Array(vec![])])])),
+            ),
+            ("$4\r\na\r\nb\r\n", RoundTrip(bulk("a\r\nb"))),
+            ("$2\r\n\r\n\r\n", RoundTrip(bulk("\r\n"))),
+            (":-9223372036854775808\r\n", RoundTrip(Int(i64::MIN))),
+            (":9223372036854775808\r\n", Invalid),
+            // The biggest allowed sizes are waited for without allocating.
+            ("$536870912\r\nhello", Incomplete),
+            ("*1048576\r\n$5\r\nhello\r\n", Incomplete),
+            ("$5\r\nhel", Incomplete),
+            ("*2\r\n$5\r\nhello\r\n", Incomplete),
+            ("+OK\r", Incomplete),
+            ("$536870913\r\n", Invalid),
+            ("$9223372036854775807\r\n", Invalid),
+            ("*1048577\r\n", Invalid),
+            ("*1000000000\r\n", Invalid),
+            ("$-2\r\n", Invalid),
+            ("*-2\r\n", Invalid),
+            (":abc\r\n", Invalid),
+            // Inline commands
+            ("PING\r\n", Decodes(Array(vec![bulk("PING")]))),
+            ("?\r\n", Decodes(Array(vec![bulk("?")]))),
+            (
+                "set  key \"a b\"\n",
+                Decodes(Array(vec![bulk("set"), bulk("key"), bulk("a b")])),
+            ),
+            (
+                "get 'it\\'s'\r\n",
+                Decodes(Array(vec![bulk("get"), bulk("it's")])),
+            ),
+            ("PING", Incomplete),
+            ("set key \"a b\r\n", Invalid),
+            ("set key \"a\"b\r\n", Invalid),
+        ]
+    }
+
+    fn decode(input: &[u8]) -> Result<Option<RedisValueRef>, RESPError> {
+        RespParser::default().decode(&mut BytesMut::from(input))
+    }
+
+    fn encode(value: RedisValueRef) -> BytesMut {
+        let mut buf = BytesMut::new();
+        RespParser::default().encode(value, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_spec_vectors() {
+        for (input, expect) in vectors() {
+            let decoded = decode(input.as_bytes());
+            match expect {
+                Expect::RoundTrip(value) => {
+                    assert_eq!(decoded.unwrap(), Some(value.clone()), "{:?}", input);
+                    assert_eq!(&encode(value)[..], input.as_bytes(), "{:?}", input);
+                }
+                Expect::Decodes(value) => {
+                    assert_eq!(decoded.unwrap(), Some(value), "{:?}", input);
+                }
+                Expect::Encodes(value) => {
+                    assert_eq!(&encode(value)[..], input.as_bytes(), "{:?}", input);
+                }
+                Expect::Incomplete => assert_eq!(decoded.unwrap(), None, "{:?}", input),
+                Expect::Invalid => assert!(decoded.is_err(), "{:?}", input),
+            }
+        }
+    }
+
+    #[test]
+    fn test_inline_commands() {
+        let mut buf =
+            BytesMut::from(&b"\r\n  \r\nset k \"\\x41\\n\\xZZ\"\r\n*1\r\n$4\r\nPING\r\n"[..]);
+        let mut decoder = RespParser::default();
+        // Blank lines are skipped.
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(RedisValueRef::Array(vec![
+                bulk("set"),
+                bulk("k"),
+                bulk("A\nxZZ")
+            ]))
+        );
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(RedisValueRef::Array(vec![bulk("PING")]))
+        );
+        assert!(buf.is_empty());
+        assert_eq!(decode(b"\r\n").unwrap(), None);
+        assert!(matches!(
+            decode(b"POST / HTTP/1.1\r\n"),
+            Err(RESPError::CrossProtocol)
+        ));
+        assert!(matches!(
+            decode(b"Host: localhost:6379\r\n"),
+            Err(RESPError::CrossProtocol)
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut parser = RespParser::with_limits(RespLimits {
+            max_bulk_len: 3,
+            max_array_len: 2,
+            max_depth: DEFAULT_MAX_DEPTH,
+        });
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nget\r\n$1\r\na\r\n"[..]);
+        assert!(parser.decode(&mut buf).unwrap().is_some());
+        assert!(matches!(
+            parser.decode(&mut BytesMut::from(&b"$4\r\n"[..])),
+            Err(RESPError::BulkStringTooBig(4))
+        ));
+        assert!(matches!(
+            parser.decode(&mut BytesMut::from(&b"*3\r\n"[..])),
+            Err(RESPError::ArrayTooLong(3))
+        ));
+        // Inline commands must end within 64KB.
+        let long = vec![b'a'; 64 * 1024 + 1];
+        assert!(matches!(decode(&long), Err(RESPError::InlineTooLong)));
+        assert_eq!(decode(&long[..1024]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        let nested = |depth: usize| {
+            let mut frame = "*1\r\n".repeat(depth);
+            frame.push_str(":1\r\n");
+            BytesMut::from(frame.as_bytes())
+        };
+        let mut parser = RespParser::with_max_depth(3);
+        assert!(parser.decode(&mut nested(3)).unwrap().is_some());
+        assert!(matches!(
+            parser.decode(&mut nested(4)),
+            Err(RESPError::NestingTooDeep(_))
+        ));
+        // Refused before recursing all the way down, so the stack survives.
+        assert!(matches!(
+            decode(&nested(100_000)),
+            Err(RESPError::NestingTooDeep(_))
+        ));
+    }
+
+    #[test]
+    fn test_frames_are_consumed_exactly() {
+        let frames: Vec<_> = vectors()
+            .into_iter()
+            .filter_map(|(input, expect)| match expect {
+                Expect::RoundTrip(value) | Expect::Decodes(value) => Some((input, value)),
+                _ => None,
+            })
+            .collect();
+        let mut buf = BytesMut::new();
+        for (input, _) in frames.iter() {
+            buf.extend_from_slice(input.as_bytes());
+        }
+        let mut decoder = RespParser::default();
+        for (input, value) in frames {
+            assert_eq!(
+                decoder.decode(&mut buf).unwrap(),
+                Some(value),
+                "{:?}",
+                input
+            );
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decoding_resumes_across_reads() {
+        let frames: Vec<_> = vectors()
+            .into_iter()
+            .filter_map(|(input, expect)| match expect {
+                Expect::RoundTrip(value) | Expect::Decodes(value) => Some((input, value)),
+                _ => None,
+            })
+            .collect();
+        let input: Vec<u8> = frames.iter().flat_map(|(input, _)| input.bytes()).collect();
+        let expected: Vec<_> = frames.into_iter().map(|(_, value)| value).collect();
+        for chunk in 1..8 {
+            let mut decoder = RespParser::default();
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for bytes in input.chunks(chunk) {
+                buf.extend_from_slice(bytes);
+                while let Some(value) = decoder.decode(&mut buf).unwrap() {
+                    decoded.push(value);
+                }
+            }
+            assert_eq!(decoded, expected, "read {} bytes at a time", chunk);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_waits_for_the_rest_of_a_bulk_string() {
+        let mut decoder = RespParser::default();
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nset\r\n$10\r\nab"[..]);
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        // Neither re-parsed nor looked at until all ten bytes are in.
+        assert_eq!(decoder.progress.need, buf.len() + 10);
+        buf.extend_from_slice(b"cdefghij\r");
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"\n");
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(RedisValueRef::Array(vec![bulk("set"), bulk("abcdefghij")]))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn fuzz_truncated_frames(index in 0..1000usize, cut in 0..1000usize) {
+            let vectors = vectors();
+            let (input, expect) = &vectors[index % vectors.len()];
+            if let Expect::RoundTrip(_) | Expect::Decodes(_) = expect {
+                let cut = cut % input.len();
+                prop_assert!(decode(&input.as_bytes()[..cut]).unwrap().is_none());
+            }
+        }
+
+        #[test]
+        fn fuzz_mutated_frames(
+            index in 0..1000usize,
+            edits in proptest::collection::vec((any::<usize>(), any::<u8>()), 1..4),
+        ) {
+            let vectors = vectors();
+            let mut input = vectors[index % vectors.len()].0.as_bytes().to_vec();
+            for (at, byte) in edits {
+                let at = at % input.len();
+                input[at] = byte;
+            }
+            // Anything goes, as long as the decoder doesn't panic.
+            let mut buf = BytesMut::from(&input[..]);
+            let mut decoder = RespParser::default();
+            while let Ok(Some(_)) = decoder.decode(&mut buf) {}
+        }
+    }
 }
@@ -0,0 +1,402 @@
+//! Backing store for string keys, so redis-oxide can act as a cache in front
+//! of slower storage.
+//!
+//! Keys matching a configured prefix are persisted to a `BackingStore` after
+//! every write, either immediately (write-through) or batched on an interval
+//! (write-behind), and loaded back on a miss. Keys outside every prefix are
+//! untouched.
+//!
+//! Write-behind only keeps the latest value of each dirty key, so a crash
+//! loses at most one interval of writes.
+//!
+//! A failed write-through isn't undone: the value stays in memory, the
+//! client gets an error, and the key is written again on the next flush.
+//!
+//! FLUSHDB and FLUSHALL clear the store too, so flushed keys aren't loaded
+//! back on the next miss.
+
+use crate::hashing::sha1_hex;
+use crate::logger::LOGGER;
+use crate::startup::Config;
+use crate::types::{Index, Key, StateRef, Value};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// External storage for string values.
+pub trait BackingStore: Send + Sync + 'static {
+    fn load(&self, db: Index, key: &[u8]) -> io::Result<Option<Value>>;
+    fn store(&self, db: Index, key: &[u8], value: &[u8]) -> io::Result<()>;
+    fn delete(&self, db: Index, key: &[u8]) -> io::Result<()>;
+    /// Delete every key in `db`, or in every db if None.
+    fn clear(&self, db: Option<Index>) -> io::Result<()>;
+}
+
+/// Longest key stored under its own hex encoding; longer ones would
+/// overflow filename limits, so they're stored under their SHA1.
+const MAX_HEX_KEY: usize = 100;
+
+fn file_name(key: &[u8]) -> String {
+    if key.len() > MAX_HEX_KEY {
+        return format!("sha1-{}", sha1_hex(key));
+    }
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Makes temp file names unique within the process.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Stores each key in its own file, `<dir>/<db>/<hex key>`.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, db: Index, key: &[u8]) -> PathBuf {
+        self.dir.join(db.to_string()).join(file_name(key))
+    }
+}
+
+impl BackingStore for FileStore {
+    fn load(&self, db: Index, key: &[u8]) -> io::Result<Option<Value>> {
+        match fs::read(self.path(db, key)) {
+            Ok(contents) => Ok(Some(contents.into())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn store(&self, db: Index, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let path = self.path(db, key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write then rename so readers never see a partial value. Each
+        // write gets its own temp file, so concurrent writes don't mix.
+        let tmp = path.with_file_name(format!(
+            "{}.{}.{}.tmp",
+            file_name(key),
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(value)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)
+    }
+
+    fn delete(&self, db: Index, key: &[u8]) -> io::Result<()> {
+        match fs::remove_file(self.path(db, key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn clear(&self, db: Option<Index>) -> io::Result<()> {
+        let dir = match db {
+            Some(db) => self.dir.join(db.to_string()),
+            None => self.dir.clone(),
+        };
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::create_dir_all(&self.dir)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    Through,
+    Behind,
+}
+
+/// Parse a `prefix=through` or `prefix=behind` option.
+pub fn parse_prefix_policy(spec: &str) -> Result<(Bytes, WritePolicy), String> {
+    let (prefix, policy) = match spec.rfind('=') {
+        Some(at) => (&spec[..at], &spec[at + 1..]),
+        None => return Err(format!("expected PREFIX=through|behind, got {}", spec)),
+    };
+    let policy = match policy.to_lowercase().as_str() {
+        "through" => WritePolicy::Through,
+        "behind" => WritePolicy::Behind,
+        other => return Err(format!("unknown write policy {}", other)),
+    };
+    Ok((Bytes::copy_from_slice(prefix.as_bytes()), policy))
+}
+
+/// Latest value of each key waiting to be written behind. None means delete.
+type DirtyKeys = HashMap<(Index, Key), Option<Value>>;
+
+pub struct Backing {
+    store: Arc<dyn BackingStore>,
+    prefixes: Vec<(Bytes, WritePolicy)>,
+    dirty: Arc<Mutex<DirtyKeys>>,
+    /// Held while flushing, so a clear can't be undone by a flush that
+    /// took its keys before it.
+    flushing: Mutex<()>,
+}
+
+impl Backing {
+    pub fn new(store: Arc<dyn BackingStore>, prefixes: Vec<(Bytes, WritePolicy)>) -> Self {
+        Backing {
+            store,
+            prefixes,
+            dirty: Arc::default(),
+            flushing: Mutex::new(()),
+        }
+    }
+
+    /// Policy of the longest prefix matching `key`, if any.
+    pub fn policy(&self, key: &[u8]) -> Option<WritePolicy> {
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| *policy)
+    }
+
+    /// Load backed keys missing from `state`, so reads see stored values.
+    pub fn load_missing(&self, db: Index, state: &StateRef, keys: &[Key]) -> io::Result<()> {
+        for key in keys {
+            if self.policy(key).is_none() || state.kv.contains_key(key) {
+                continue;
+            }
+            // A pending write-behind is newer than what's stored.
+            if self.dirty.lock().contains_key(&(db, key.clone())) {
+                continue;
+            }
+            if let Some(value) = self.store.load(db, key)? {
+                state.kv.entry(key.clone()).or_insert(value);
+                state.negative_cache.invalidate(std::slice::from_ref(key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist the current value of written keys. Write-through keys that
+    /// fail are kept for the next flush, and the first error is returned.
+    pub fn persist(&self, db: Index, state: &StateRef, keys: &[Key]) -> io::Result<()> {
+        let mut first_err = None;
+        for key in keys {
+            let policy = match self.policy(key) {
+                Some(policy) => policy,
+                None => continue,
+            };
+            let value = state.kv.get(key).map(|v| v.value().clone());
+            let res = match (policy, &value) {
+                (WritePolicy::Through, Some(value)) => self.store.store(db, key, value),
+                (WritePolicy::Through, None) => self.store.delete(db, key),
+                (WritePolicy::Behind, _) => Ok(()),
+            };
+            match res {
+                Ok(()) if policy == WritePolicy::Through => {}
+                Ok(()) => {
+                    self.dirty.lock().insert((db, key.clone()), value);
+                }
+                Err(e) => {
+                    self.dirty.lock().insert((db, key.clone()), value);
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    }
+
+    /// Drop every key in `db` (or every db) from the store, along with
+    /// pending writes to it.
+    pub fn clear(&self, db: Option<Index>) -> io::Result<()> {
+        let _flushing = self.flushing.lock();
+        self.dirty
+            .lock()
+            .retain(|(key_db, _), _| db.map_or(false, |db| db != *key_db));
+        self.store.clear(db)
+    }
+
+    /// Write every dirty key. Failed keys are kept for the next flush,
+    /// unless they've been written again since.
+    pub fn flush(&self) {
+        let _flushing = self.flushing.lock();
+        let dirty = std::mem::take(&mut *self.dirty.lock());
+        for ((db, key), value) in dirty {
+            let res = match &value {
+                Some(value) => self.store.store(db, &key, value),
+                None => self.store.delete(db, &key),
+            };
+            if let Err(e) = res {
+                error!(LOGGER, "Failed to write behind a key: {}", e);
+                self.dirty.lock().entry((db, key)).or_insert(value);
+            }
+        }
+    }
+}
+
+/// Start the backing store if it was configured.
+pub fn start_backing(config: &Config) -> Result<Option<Arc<Backing>>, Box<dyn Error>> {
+    let dir = match &config.backing_dir {
+        Some(dir) => dir,
+        None if config.backing_prefixes.is_empty() => return Ok(None),
+        None => return Err("--backing-prefix needs --backing-dir".into()),
+    };
+    let prefixes = config
+        .backing_prefixes
+        .iter()
+        .map(|spec| parse_prefix_policy(spec.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    info!(
+        LOGGER,
+        "Backing {} key prefixes with {:?}",
+        prefixes.len(),
+        dir
+    );
+    let backing = Arc::new(Backing::new(
+        Arc::new(FileStore::new(dir.clone())?),
+        prefixes,
+    ));
+    // Also retries failed write-through keys.
+    let interval = Duration::from_millis(config.write_behind_ms);
+    let flusher = backing.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        flusher.flush();
+    });
+    Ok(Some(backing))
+}
+
+#[cfg(test)]
+mod test_backing {
+    use super::{parse_prefix_policy, Backing, BackingStore, FileStore, WritePolicy};
+    use crate::types::{State, StateRef};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    fn scratch_store(name: &str) -> Arc<FileStore> {
+        let dir = std::env::temp_dir().join(format!(
+            "redis-oxide-backing-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        Arc::new(FileStore::new(dir).unwrap())
+    }
+
+    #[test]
+    fn test_parse_prefix_policy() {
+        assert_eq!(
+            parse_prefix_policy("user:=through"),
+            Ok((Bytes::from_static(b"user:"), WritePolicy::Through))
+        );
+        assert_eq!(
+            parse_prefix_policy("a=b=Behind"),
+            Ok((Bytes::from_static(b"a=b"), WritePolicy::Behind))
+        );
+        assert!(parse_prefix_policy("user:").is_err());
+        assert!(parse_prefix_policy("user:=sometimes").is_err());
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let backing = Backing::new(
+            scratch_store("policy"),
+            vec![
+                (Bytes::from_static(b"user:"), WritePolicy::Behind),
+                (Bytes::from_static(b"user:admin:"), WritePolicy::Through),
+            ],
+        );
+        assert_eq!(backing.policy(b"user:1"), Some(WritePolicy::Behind));
+        assert_eq!(backing.policy(b"user:admin:1"), Some(WritePolicy::Through));
+        assert_eq!(backing.policy(b"session:1"), None);
+    }
+
+    #[test]
+    fn test_write_through_and_load() {
+        let store = scratch_store("through");
+        let backing = Backing::new(
+            store.clone(),
+            vec![(Bytes::from_static(b"b:"), WritePolicy::Through)],
+        );
+        let state: StateRef = Arc::new(State::default());
+        let keys = vec![Bytes::from_static(b"b:1"), Bytes::from_static(b"other")];
+        for key in &keys {
+            state.kv.insert(key.clone(), Bytes::from_static(b"v"));
+        }
+        backing.persist(0, &state, &keys).unwrap();
+        assert_eq!(
+            store.load(0, b"b:1").unwrap(),
+            Some(Bytes::from_static(b"v"))
+        );
+        assert_eq!(store.load(0, b"other").unwrap(), None);
+
+        let fresh: StateRef = Arc::new(State::default());
+        backing.load_missing(0, &fresh, &keys).unwrap();
+        assert!(fresh.kv.contains_key(&keys[0]));
+        assert!(!fresh.kv.contains_key(&keys[1]));
+
+        state.kv.remove(&keys[0]);
+        backing.persist(0, &state, &keys).unwrap();
+        assert_eq!(store.load(0, b"b:1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_behind() {
+        let store = scratch_store("behind");
+        let backing = Backing::new(
+            store.clone(),
+            vec![(Bytes::from_static(b"b:"), WritePolicy::Behind)],
+        );
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"b:1");
+        state.kv.insert(key.clone(), Bytes::from_static(b"old"));
+        backing.persist(0, &state, &[key.clone()]).unwrap();
+        state.kv.insert(key.clone(), Bytes::from_static(b"new"));
+        backing.persist(0, &state, &[key.clone()]).unwrap();
+        assert_eq!(store.load(0, &key).unwrap(), None);
+        backing.flush();
+        assert_eq!(
+            store.load(0, &key).unwrap(),
+            Some(Bytes::from_static(b"new"))
+        );
+    }
+
+    #[test]
+    fn test_clear() {
+        let store = scratch_store("clear");
+        let backing = Backing::new(
+            store.clone(),
+            vec![
+                (Bytes::from_static(b"t:"), WritePolicy::Through),
+                (Bytes::from_static(b"b:"), WritePolicy::Behind),
+            ],
+        );
+        let state: StateRef = Arc::new(State::default());
+        let keys = vec![Bytes::from_static(b"t:1"), Bytes::from_static(b"b:1")];
+        for key in &keys {
+            state.kv.insert(key.clone(), Bytes::from_static(b"v"));
+        }
+        backing.persist(0, &state, &keys).unwrap();
+        backing.persist(1, &state, &keys).unwrap();
+        backing.clear(Some(0)).unwrap();
+        backing.flush();
+        assert_eq!(store.load(0, b"t:1").unwrap(), None);
+        assert_eq!(store.load(0, b"b:1").unwrap(), None);
+        assert!(store.load(1, b"t:1").unwrap().is_some());
+        assert!(store.load(1, b"b:1").unwrap().is_some());
+
+        backing.clear(None).unwrap();
+        let fresh: StateRef = Arc::new(State::default());
+        backing.load_missing(1, &fresh, &keys).unwrap();
+        assert!(fresh.kv.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+//! Typed batches of commands, for embedding redis-oxide as a library.
+//!
+//! ```ignore
+//! let replies = Batch::new()
+//!     .set("a", "1")
+//!     .lpush("b", "x")
+//!     .get("a")
+//!     .execute(&state)
+//!     .await?;
+//! ```
+//!
+//! Every key is type checked before anything runs: if a key already holds
+//! another structure, or the batch uses it as two structures, nothing is
+//! executed. Commands then run in order, each locking only its own key, so
+//! nothing is held between commands (other clients may interleave).
+//!
+//! Like `--pipe`, batches skip the bookkeeping of `process_command`
+//! (save counting, CDC, backing stores).
+
+use crate::hashes::HashOps;
+use crate::keys::KeyOps;
+use crate::lists::ListOps;
+use crate::ops::{op_interact, Ops};
+use crate::sets::SetOps;
+use crate::sorted_sets::ZSetOps;
+use crate::types::{Index, Key, ReturnValue, Score, State, StateRef};
+use bytes::Bytes;
+use smallvec::smallvec;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// A key is used as a different structure than the one it holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WrongTypeError {
+    pub key: Key,
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl fmt::Display for WrongTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key {:?} holds {} but the batch uses it as {}",
+            self.key, self.found, self.expected
+        )
+    }
+}
+
+impl Error for WrongTypeError {}
+
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<Ops>,
+    /// Keys used by the batch, with the structure they're used as.
+    /// None deletes the key, so it may be used as any structure after.
+    keys: Vec<(Key, Option<&'static str>)>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    fn push(mut self, key: Key, structure: Option<&'static str>, op: Ops) -> Self {
+        self.keys.push((key, structure));
+        self.ops.push(op);
+        self
+    }
+
+    pub fn get(self, key: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        self.push(key.clone(), Some("kv"), Ops::Keys(KeyOps::Get(key)))
+    }
+
+    pub fn set(self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Keys(KeyOps::Set(key.clone(), value.into(), Default::default()));
+        self.push(key, Some("kv"), op)
+    }
+
+    pub fn del(self, key: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Keys(KeyOps::Del(smallvec![key.clone()]));
+        self.push(key, None, op)
+    }
+
+    pub fn lpush(self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Lists(ListOps::LPush(key.clone(), smallvec![value.into()]));
+        self.push(key, Some("lists"), op)
+    }
+
+    pub fn rpush(self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Lists(ListOps::RPush(key.clone(), smallvec![value.into()]));
+        self.push(key, Some("lists"), op)
+    }
+
+    pub fn lpop(self, key: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        self.push(
+            key.clone(),
+            Some("lists"),
+            Ops::Lists(ListOps::LPop(key, None)),
+        )
+    }
+
+    pub fn rpop(self, key: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        self.push(
+            key.clone(),
+            Some("lists"),
+            Ops::Lists(ListOps::RPop(key, None)),
+        )
+    }
+
+    pub fn lrange(self, key: impl Into<Bytes>, start: Index, stop: Index) -> Self {
+        let key = key.into();
+        let op = Ops::Lists(ListOps::LRange(key.clone(), start, stop));
+        self.push(key, Some("lists"), op)
+    }
+
+    pub fn sadd(self, key: impl Into<Bytes>, member: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Sets(SetOps::SAdd(key.clone(), smallvec![member.into()]));
+        self.push(key, Some("sets"), op)
+    }
+
+    pub fn srem(self, key: impl Into<Bytes>, member: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Sets(SetOps::SRem(key.clone(), smallvec![member.into()]));
+        self.push(key, Some("sets"), op)
+    }
+
+    pub fn sismember(self, key: impl Into<Bytes>, member: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Sets(SetOps::SIsMember(key.clone(), member.into()));
+        self.push(key, Some("sets"), op)
+    }
+
+    pub fn smembers(self, key: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        self.push(key.clone(), Some("sets"), Ops::Sets(SetOps::SMembers(key)))
+    }
+
+    pub fn hget(self, key: impl Into<Bytes>, field: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Hashes(HashOps::HGet(key.clone(), field.into()));
+        self.push(key, Some("hashes"), op)
+    }
+
+    pub fn hset(
+        self,
+        key: impl Into<Bytes>,
+        field: impl Into<Bytes>,
+        value: impl Into<Bytes>,
+    ) -> Self {
+        let key = key.into();
+        let op = Ops::Hashes(HashOps::HSet(key.clone(), field.into(), value.into()));
+        self.push(key, Some("hashes"), op)
+    }
+
+    pub fn hdel(self, key: impl Into<Bytes>, field: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Hashes(HashOps::HDel(key.clone(), smallvec![field.into()]));
+        self.push(key, Some("hashes"), op)
+    }
+
+    pub fn hgetall(self, key: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::Hashes(HashOps::HGetAll(key.clone()));
+        self.push(key, Some("hashes"), op)
+    }
+
+    pub fn zadd(self, key: impl Into<Bytes>, score: Score, member: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::ZSets(ZSetOps::ZAdd(
+            key.clone(),
+            Default::default(),
+            smallvec![(score, member.into())],
+        ));
+        self.push(key, Some("zsets"), op)
+    }
+
+    pub fn zscore(self, key: impl Into<Bytes>, member: impl Into<Bytes>) -> Self {
+        let key = key.into();
+        let op = Ops::ZSets(ZSetOps::ZScore(key.clone(), member.into()));
+        self.push(key, Some("zsets"), op)
+    }
+
+    pub fn zrange(self, key: impl Into<Bytes>, start: Index, stop: Index) -> Self {
+        let key = key.into();
+        let op = Ops::ZSets(ZSetOps::ZRange(key.clone(), start, stop, false));
+        self.push(key, Some("zsets"), op)
+    }
+
+    /// Check every key against `state` and the rest of the batch.
+    pub fn validate(&self, state: &State) -> Result<(), WrongTypeError> {
+        // What each key holds once the commands so far have run.
+        let mut holds: HashMap<&Key, Option<&'static str>> = HashMap::new();
+        for (key, used_as) in &self.keys {
+            let expected = match used_as {
+                Some(expected) => *expected,
+                None => {
+                    holds.insert(key, None);
+                    continue;
+                }
+            };
+            let found = match holds.get(key) {
+                Some(found) => *found,
+                None => state.structure_of(key),
+            };
+            match found {
+                Some(found) if found != expected => {
+                    return Err(WrongTypeError {
+                        key: key.clone(),
+                        expected,
+                        found,
+                    })
+                }
+                _ => {
+                    holds.insert(key, Some(expected));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the batch, returning one reply per command.
+    pub async fn execute(self, state: &StateRef) -> Result<Vec<ReturnValue>, WrongTypeError> {
+        self.validate(state)?;
+        let mut replies = Vec::with_capacity(self.ops.len());
+        for op in self.ops {
+            replies.push(op_interact(op, state.clone()).await.0);
+        }
+        Ok(replies)
+    }
+}
+
+#[cfg(test)]
+mod test_batch {
+    use super::{Batch, WrongTypeError};
+    use crate::types::{ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_execute() {
+        let state: StateRef = Arc::new(State::default());
+        let replies = Batch::new()
+            .set("a", "1")
+            .rpush("l", "x")
+            .rpush("l", "y")
+            .get("a")
+            .lrange("l", 0, -1)
+            .execute(&state)
+            .await
+            .unwrap();
+        assert_eq!(
+            replies,
+            vec![
+                ReturnValue::Ok,
+                ReturnValue::IntRes(1),
+                ReturnValue::IntRes(2),
+                ReturnValue::StringRes(Bytes::from_static(b"1")),
+                ReturnValue::MultiStringRes(vec!["x".into(), "y".into()]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_type_runs_nothing() {
+        let state: StateRef = Arc::new(State::default());
+        state
+            .lists
+            .insert(Bytes::from_static(b"l"), Default::default());
+        let res = Batch::new().set("a", "1").get("l").execute(&state).await;
+        assert_eq!(
+            res,
+            Err(WrongTypeError {
+                key: Bytes::from_static(b"l"),
+                expected: "kv",
+                found: "lists",
+            })
+        );
+        assert!(state.kv.is_empty());
+
+        // Conflicts within the batch are caught too.
+        let res = Batch::new().sadd("k", "m").get("k").execute(&state).await;
+        assert!(res.is_err());
+        let res = Batch::new().set("l2", "v").del("l2").execute(&state).await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deleted_keys_change_type() {
+        let state: StateRef = Arc::new(State::default());
+        let res = Batch::new()
+            .set("a", "1")
+            .del("a")
+            .lpush("a", "x")
+            .execute(&state)
+            .await;
+        assert!(res.is_ok());
+        assert!(state.lists.contains_key(&Bytes::from_static(b"a")));
+        // Keys already stored can be deleted and reused too.
+        let res = Batch::new().del("a").sadd("a", "m").execute(&state).await;
+        assert!(res.is_ok());
+        // But not used as something else before the delete.
+        let res = Batch::new().get("a").del("a").execute(&state).await;
+        assert!(res.is_err());
+    }
+}
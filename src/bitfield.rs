@@ -0,0 +1,291 @@
+//! BITFIELD: integers of any width, at any bit offset of a string.
+//!
+//! Fields are read and written most significant bit first, on top of the
+//! bit layout SETBIT uses. Signed fields go up to 64 bits and unsigned ones
+//! to 63, so every value fits an `i64` reply, like redis.
+
+use crate::keys::{get_bit, mutate_bytes, set_bit_in};
+use crate::types::{Count, Key, ReturnValue, State, Value};
+
+/// The type of a field, like `i8` or `u16`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl FieldType {
+    /// The widest fields: `i64` and `u63`.
+    pub const MAX_BITS: u32 = 64;
+
+    /// Parse `i<bits>` or `u<bits>`.
+    pub fn parse(name: &[u8]) -> Option<FieldType> {
+        let (signed, bits) = match name.split_first()? {
+            (b'i', bits) | (b'I', bits) => (true, bits),
+            (b'u', bits) | (b'U', bits) => (false, bits),
+            _ => return None,
+        };
+        let bits: u32 = std::str::from_utf8(bits).ok()?.parse().ok()?;
+        let max_bits = if signed {
+            Self::MAX_BITS
+        } else {
+            Self::MAX_BITS - 1
+        };
+        if bits == 0 || bits > max_bits {
+            return None;
+        }
+        Some(FieldType { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1 << (self.bits - 1)) - 1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+}
+
+/// What INCRBY and SET do when a value doesn't fit its field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Keep the low bits, like C integers.
+    Wrap,
+    /// Stick at the smallest or largest value.
+    Sat,
+    /// Leave the field as it is, and reply nil.
+    Fail,
+}
+
+/// A BITFIELD subcommand. Offsets are in bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldOp {
+    Get(FieldType, u64),
+    Set(FieldType, u64, Count),
+    IncrBy(FieldType, u64, Count),
+    /// Applies to the INCRBYs and SETs after it.
+    Overflow(Overflow),
+}
+
+impl FieldOp {
+    pub fn is_write(&self) -> bool {
+        matches!(self, FieldOp::Set(..) | FieldOp::IncrBy(..))
+    }
+
+    /// The length in bytes a string needs for this op's field.
+    fn len_needed(&self) -> usize {
+        match self {
+            FieldOp::Get(ty, offset)
+            | FieldOp::Set(ty, offset, _)
+            | FieldOp::IncrBy(ty, offset, _) => ((offset + ty.bits as u64 + 7) / 8) as usize,
+            FieldOp::Overflow(_) => 0,
+        }
+    }
+}
+
+fn read_field(bytes: &[u8], ty: FieldType, offset: u64) -> i128 {
+    let raw = (0..ty.bits as u64).fold(0u64, |acc, i| {
+        (acc << 1) | get_bit(bytes, offset + i) as u64
+    });
+    let raw = raw as i128;
+    if ty.signed && raw > ty.max() {
+        raw - (1 << ty.bits)
+    } else {
+        raw
+    }
+}
+
+fn write_field(bytes: &mut [u8], ty: FieldType, offset: u64, value: i128) {
+    for i in 0..ty.bits {
+        let on = (value >> (ty.bits - 1 - i)) & 1 == 1;
+        set_bit_in(bytes, offset + i as u64, on);
+    }
+}
+
+/// Fit `value` in a field of type `ty`, or None if it doesn't and `overflow` is FAIL.
+fn fit(value: i128, ty: FieldType, overflow: Overflow) -> Option<i128> {
+    if (ty.min()..=ty.max()).contains(&value) {
+        return Some(value);
+    }
+    match overflow {
+        Overflow::Fail => None,
+        Overflow::Sat => Some(value.clamp(ty.min(), ty.max())),
+        Overflow::Wrap => {
+            let low = value & ((1 << ty.bits) - 1);
+            Some(if low > ty.max() {
+                low - (1 << ty.bits)
+            } else {
+                low
+            })
+        }
+    }
+}
+
+/// Run the ops against `bytes`, which is long enough for every write.
+fn run_ops(bytes: &mut [u8], ops: &[FieldOp]) -> ReturnValue {
+    let mut overflow = Overflow::Wrap;
+    let mut replies = Vec::with_capacity(ops.len());
+    for op in ops {
+        let reply = match *op {
+            FieldOp::Get(ty, offset) => Some(read_field(bytes, ty, offset)),
+            FieldOp::Set(ty, offset, value) => fit(value as i128, ty, overflow).map(|value| {
+                let old = read_field(bytes, ty, offset);
+                write_field(bytes, ty, offset, value);
+                old
+            }),
+            FieldOp::IncrBy(ty, offset, by) => {
+                let old = read_field(bytes, ty, offset);
+                fit(old + by as i128, ty, overflow).map(|new| {
+                    write_field(bytes, ty, offset, new);
+                    new
+                })
+            }
+            FieldOp::Overflow(behaviour) => {
+                overflow = behaviour;
+                continue;
+            }
+        };
+        replies.push(match reply {
+            Some(value) => ReturnValue::IntRes(value as Count),
+            None => ReturnValue::Nil,
+        });
+    }
+    ReturnValue::Array(replies)
+}
+
+/// Run BITFIELD (or BITFIELD_RO) on `key`. Writes grow the string with zeros
+/// to fit their fields, and reading past the end reads zeros.
+pub fn bitfield(state: &State, key: Key, ops: &[FieldOp]) -> ReturnValue {
+    if !ops.iter().any(FieldOp::is_write) {
+        let bytes = state.kv.get(&key).map(|v| v.value().clone());
+        let bytes = bytes.unwrap_or_default();
+        let replies = ops.iter().filter_map(|op| match *op {
+            FieldOp::Get(ty, offset) => {
+                Some(ReturnValue::IntRes(read_field(&bytes, ty, offset) as Count))
+            }
+            _ => None,
+        });
+        return ReturnValue::Array(replies.collect());
+    }
+    // The key stays locked throughout, so the ops apply as one.
+    let mut value = state.kv.entry(key).or_insert_with(Value::new);
+    let len = ops
+        .iter()
+        .filter(|op| op.is_write())
+        .map(FieldOp::len_needed)
+        .max()
+        .unwrap_or(0);
+    mutate_bytes(&mut value, len, |bytes| run_ops(bytes, ops))
+}
+
+#[cfg(test)]
+mod test_bitfield {
+    use super::{bitfield, FieldOp, FieldType, Overflow};
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+
+    fn ty(name: &str) -> FieldType {
+        FieldType::parse(name.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_field_types() {
+        assert_eq!(
+            FieldType::parse(b"i64"),
+            Some(FieldType {
+                signed: true,
+                bits: 64
+            })
+        );
+        assert_eq!(
+            FieldType::parse(b"U8"),
+            Some(FieldType {
+                signed: false,
+                bits: 8
+            })
+        );
+        for bad in &["u64", "i0", "i65", "8", "x8", "i", "i-1"] {
+            assert_eq!(FieldType::parse(bad.as_bytes()), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_bitfield() {
+        let state = State::default();
+        let key = Bytes::from_static(b"k");
+        let run = |ops: &[FieldOp]| bitfield(&state, key.clone(), ops);
+        let ints = |values: &[i64]| {
+            ReturnValue::Array(values.iter().map(|v| ReturnValue::IntRes(*v)).collect())
+        };
+
+        // Reads don't create the key.
+        assert_eq!(run(&[FieldOp::Get(ty("u8"), 100)]), ints(&[0]));
+        assert!(!state.kv.contains_key(&key));
+
+        assert_eq!(
+            run(&[
+                FieldOp::Set(ty("u8"), 0, 255),
+                FieldOp::Get(ty("i8"), 0),
+                FieldOp::Get(ty("u4"), 4),
+            ]),
+            ints(&[0, -1, 15])
+        );
+        assert_eq!(*state.kv.get(&key).unwrap(), Bytes::from_static(b"\xff"));
+        // Fields needn't be byte aligned, and writes pad with zeros.
+        assert_eq!(
+            run(&[FieldOp::Set(ty("i5"), 13, -3), FieldOp::Get(ty("i5"), 13)]),
+            ints(&[0, -3])
+        );
+        assert_eq!(
+            *state.kv.get(&key).unwrap(),
+            Bytes::from_static(b"\xff\x07\x40")
+        );
+        assert_eq!(
+            run(&[
+                FieldOp::Set(ty("i64"), 0, i64::MIN),
+                FieldOp::Get(ty("i64"), 0)
+            ]),
+            ints(&[0xff07_4000_0000_0000u64 as i64, i64::MIN])
+        );
+    }
+
+    #[test]
+    fn test_overflow() {
+        let state = State::default();
+        let key = Bytes::from_static(b"k");
+        let run = |ops: &[FieldOp]| bitfield(&state, key.clone(), ops);
+        let two_bits = ty("u2");
+        let byte = ty("i8");
+        assert_eq!(
+            run(&[
+                FieldOp::IncrBy(two_bits, 0, 5),
+                FieldOp::Overflow(Overflow::Sat),
+                FieldOp::IncrBy(two_bits, 0, 5),
+                FieldOp::IncrBy(byte, 8, -200),
+                FieldOp::Overflow(Overflow::Fail),
+                FieldOp::IncrBy(two_bits, 0, 1),
+                FieldOp::Set(byte, 8, 128),
+                FieldOp::Get(byte, 8),
+                FieldOp::Overflow(Overflow::Wrap),
+                FieldOp::IncrBy(byte, 8, -1),
+            ]),
+            ReturnValue::Array(vec![
+                ReturnValue::IntRes(1),
+                ReturnValue::IntRes(3),
+                ReturnValue::IntRes(-128),
+                ReturnValue::Nil,
+                ReturnValue::Nil,
+                ReturnValue::IntRes(-128),
+                ReturnValue::IntRes(127),
+            ])
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::data_structures::receipt_map::{KeyTypes, Receipt};
-use crate::types::{Key, ReturnValue, StateRef};
+use crate::types::{ReturnValue, StateRef, StateStoreRef};
 
 use std::future::Future;
 use std::pin::Pin;
@@ -7,20 +7,39 @@ use std::task::{Context, Poll};
 
 pub type YieldingFn = Box<dyn Fn() -> Option<ReturnValue> + Send>;
 
+tokio::task_local! {
+    /// Set while a client's blocking command runs and scripts are atomic:
+    /// the store whose `script_lock` each attempt shares, see `KeyBlocking`.
+    pub static SCRIPT_GATE: StateStoreRef;
+}
+
+/// Retries `f` whenever one of `keys` is written, until it returns something.
+///
+/// With `SCRIPT_GATE` set, each attempt holds the script lock shared, like
+/// any other client command, so it can't land in the middle of an atomic
+/// script. It's let go while parked, so a script can run meanwhile.
 pub struct KeyBlocking {
     f: Box<dyn Fn() -> Option<ReturnValue> + Send>,
     state: StateRef,
-    key: Key,
+    /// Writes to any of these keys wake us up.
+    keys: Vec<KeyTypes>,
     receipt: Receipt,
+    gate: Option<StateStoreRef>,
 }
 
 impl KeyBlocking {
-    pub fn new(f: YieldingFn, state: StateRef, key: Key, receipt: Receipt) -> KeyBlocking {
+    pub fn new(
+        f: YieldingFn,
+        state: StateRef,
+        keys: Vec<KeyTypes>,
+        receipt: Receipt,
+    ) -> KeyBlocking {
         KeyBlocking {
             f,
             state,
-            key,
+            keys,
             receipt,
+            gate: SCRIPT_GATE.try_with(|store| store.clone()).ok(),
         }
     }
 }
@@ -28,16 +47,37 @@ impl KeyBlocking {
 impl Future for KeyBlocking {
     type Output = ReturnValue;
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.state.receipt_timed_out(self.receipt) {
-            return Poll::Ready(ReturnValue::Nil);
-        }
+        let _shared = match &self.gate {
+            Some(store) => match store.script_lock.try_read() {
+                Ok(shared) => Some(shared),
+                Err(_) => {
+                    // A script is running (or about to): try again once it's done.
+                    let (store, waker) = (store.clone(), cx.waker().clone());
+                    tokio::spawn(async move {
+                        let _ = store.script_lock.read().await;
+                        waker.wake();
+                    });
+                    return Poll::Pending;
+                }
+            },
+            None => None,
+        };
         match (self.f)() {
             Some(ret) => Poll::Ready(ret),
             None => {
                 let mut rm = self.state.reciept_map.lock();
-                rm.insert(self.receipt, cx.waker().clone(), KeyTypes::list(&self.key));
+                for key in self.keys.iter() {
+                    rm.insert(self.receipt, cx.waker().clone(), key.clone());
+                }
                 Poll::Pending
             }
         }
     }
 }
+
+/// Done, timed out or dropped with its client: stop waiting on every key.
+impl Drop for KeyBlocking {
+    fn drop(&mut self) {
+        self.state.reciept_map.lock().remove(self.receipt);
+    }
+}
@@ -1,25 +1,62 @@
-use crate::types::{Key, RedisBool, ReturnValue, StateRef, Value};
+use crate::data_structures::bloom_filter::BloomFilter;
+use crate::ops::RVec;
+use crate::types::{Count, Key, RedisBool, ReturnValue, StateRef, Value};
 use crate::{make_reader, op_variants};
-use growable_bloom_filter::GrowableBloom;
+use dashmap::mapref::entry::Entry;
 
 op_variants! {
     BloomOps,
     BInsert(Key, Value),
-    BContains(Key, Value)
+    BContains(Key, Value),
+    BfReserve(Key, f64, usize),
+    BfMAdd(Key, RVec<Value>),
+    BfMExists(Key, RVec<Value>),
+    BfInfo(Key)
 }
 
-const DESIRED_FAILURE_RATE: f64 = 0.05;
-const EST_INSERTS: usize = 10;
+impl BloomOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            BloomOps::BInsert(key, _) | BloomOps::BfReserve(key, ..) | BloomOps::BfMAdd(key, _) => {
+                Some(vec![key.clone()])
+            }
+            BloomOps::BContains(..) | BloomOps::BfMExists(..) | BloomOps::BfInfo(_) => None,
+        }
+    }
+}
+
+/// The settings of filters made by adding to a missing key.
+pub const DESIRED_FAILURE_RATE: f64 = 0.05;
+pub const EST_INSERTS: usize = 10;
+/// How much each filter holds relative to the last, for BF.INFO.
+const EXPANSION_RATE: usize = 2;
 
 make_reader!(blooms, read_blooms);
 
+fn info_reply(bloom: &BloomFilter) -> ReturnValue {
+    let field = |name: &'static str, value: usize| {
+        vec![
+            ReturnValue::StringRes(Value::from_static(name.as_bytes())),
+            ReturnValue::IntRes(value as Count),
+        ]
+    };
+    let mut reply = Vec::new();
+    reply.extend(field("Capacity", bloom.total_capacity()));
+    reply.extend(field("Size", bloom.size()));
+    reply.extend(field("Number of filters", bloom.filters()));
+    reply.extend(field("Number of items inserted", bloom.items()));
+    reply.extend(field("Expansion rate", EXPANSION_RATE));
+    ReturnValue::Array(reply)
+}
+
 pub async fn bloom_interact(bloom_op: BloomOps, state: StateRef) -> ReturnValue {
     match bloom_op {
         BloomOps::BInsert(bloom_key, value) => {
             state
                 .blooms
                 .entry(bloom_key)
-                .or_insert_with(|| GrowableBloom::new(DESIRED_FAILURE_RATE, EST_INSERTS))
+                .or_insert_with(|| BloomFilter::new(DESIRED_FAILURE_RATE, EST_INSERTS))
                 .insert(value);
             ReturnValue::Ok
         }
@@ -27,14 +64,55 @@ pub async fn bloom_interact(bloom_op: BloomOps, state: StateRef) -> ReturnValue
             .map(|bloom| bloom.contains(value) as RedisBool)
             .unwrap_or(0)
             .into(),
+        BloomOps::BfReserve(bloom_key, error_rate, capacity) => {
+            match state.blooms.entry(bloom_key) {
+                Entry::Occupied(_) => ReturnValue::Error(b"ERR item exists"),
+                Entry::Vacant(entry) => {
+                    entry.insert(BloomFilter::new(error_rate, capacity));
+                    ReturnValue::Ok
+                }
+            }
+        }
+        BloomOps::BfMAdd(bloom_key, values) => {
+            let mut bloom = state
+                .blooms
+                .entry(bloom_key)
+                .or_insert_with(|| BloomFilter::new(DESIRED_FAILURE_RATE, EST_INSERTS));
+            ReturnValue::Array(
+                values
+                    .into_iter()
+                    .map(|value| ReturnValue::IntRes(bloom.insert(value) as RedisBool))
+                    .collect(),
+            )
+        }
+        BloomOps::BfMExists(bloom_key, values) => {
+            let bloom = read_blooms!(state, &bloom_key);
+            ReturnValue::Array(
+                values
+                    .into_iter()
+                    .map(|value| {
+                        let found = bloom.as_ref().map_or(false, |bloom| bloom.contains(value));
+                        ReturnValue::IntRes(found as RedisBool)
+                    })
+                    .collect(),
+            )
+        }
+        BloomOps::BfInfo(bloom_key) => match read_blooms!(state, &bloom_key) {
+            Some(bloom) => info_reply(&bloom),
+            None => ReturnValue::Error(b"ERR not found"),
+        },
     }
 }
 
 #[cfg(test)]
 mod test_bloom {
     use crate::bloom::{bloom_interact, BloomOps};
-    use crate::types::{ReturnValue, State};
+    use crate::config::config_set;
+    use crate::ops::{translate, Ops};
+    use crate::test_util::command;
+    use crate::types::{ReturnValue, State, StateStore};
     use bytes::Bytes;
+    use smallvec::smallvec;
     use std::sync::Arc;
 
     #[tokio::test]
@@ -55,4 +133,59 @@ mod test_bloom {
         let res = bloom_interact(BloomOps::BContains(key, v), eng.clone()).await;
         assert_eq!(res, ReturnValue::IntRes(1));
     }
+
+    #[tokio::test]
+    async fn test_reserve_and_batches() {
+        let key = Bytes::from_static(b"key");
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let eng = Arc::new(State::default());
+        let res = bloom_interact(BloomOps::BfInfo(key.clone()), eng.clone()).await;
+        assert_eq!(res, ReturnValue::Error(b"ERR not found"));
+        let reserve = BloomOps::BfReserve(key.clone(), 0.001, 1000);
+        let res = bloom_interact(reserve.clone(), eng.clone()).await;
+        assert_eq!(res, ReturnValue::Ok);
+        let res = bloom_interact(reserve, eng.clone()).await;
+        assert_eq!(res, ReturnValue::Error(b"ERR item exists"));
+
+        let op = BloomOps::BfMAdd(key.clone(), smallvec![a.clone(), a.clone()]);
+        let res = bloom_interact(op, eng.clone()).await;
+        assert_eq!(
+            res,
+            ReturnValue::Array(vec![ReturnValue::IntRes(1), ReturnValue::IntRes(0)])
+        );
+        let op = BloomOps::BfMExists(key.clone(), smallvec![a, b]);
+        let res = bloom_interact(op, eng.clone()).await;
+        assert_eq!(
+            res,
+            ReturnValue::Array(vec![ReturnValue::IntRes(1), ReturnValue::IntRes(0)])
+        );
+
+        let info = match bloom_interact(BloomOps::BfInfo(key), eng.clone()).await {
+            ReturnValue::Array(info) => info,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(info[1], ReturnValue::IntRes(1000));
+        // 1000 items at 0.1% take about 14.4 bits each.
+        assert_eq!(info[3], ReturnValue::IntRes(1798));
+        assert_eq!(info[7], ReturnValue::IntRes(1));
+    }
+
+    #[test]
+    fn test_reserve_limits() {
+        let store = Arc::new(StateStore::default());
+        let reserve = |error_rate: &str, capacity: &str| {
+            translate(
+                command(&["BF.RESERVE", "key", error_rate, capacity]),
+                store.clone(),
+            )
+        };
+        assert!(matches!(reserve("0.01", "1000"), Ok(Ops::Blooms(_))));
+        assert!(reserve("1e-10", "1000").is_err());
+        // About 1.2GB of bits, past the 512MB a bitmap can take.
+        assert!(reserve("0.01", "1000000000").is_err());
+        // About 12MB.
+        assert!(reserve("0.01", "10000000").is_ok());
+        config_set(&store, &[("proto-max-bulk-len".into(), "1000000".into())]).unwrap();
+        assert!(reserve("0.01", "10000000").is_err());
+    }
 }
@@ -0,0 +1,327 @@
+//! Bulk loading (`--pipe`): apply a stream of commands before serving.
+//!
+//! Input is either RESP (like `redis-cli --pipe` takes) or inline commands,
+//! one per line; it's RESP if the first byte is `*`. Commands skip the
+//! per-command bookkeeping of `process_command` (save counting, CDC,
+//! backing stores, reply stats), so the caller should save once afterwards.
+//!
+//! To use every core, commands writing a single key, and reading no other,
+//! are spread across workers by key hash, which keeps the order of writes to
+//! any one key. Everything else (multi-key commands like SUNIONSTORE,
+//! SELECT, reads, ...) waits for the workers to finish what they have, then
+//! runs on its own.
+
+use crate::asyncresp::{RespLimits, RespParser};
+use crate::command_file::{error_message, parse_line};
+use crate::command_table::{command_keys, command_spec};
+use crate::hashing::internal_hash;
+use crate::logger::LOGGER;
+use crate::misc::misc_interact;
+use crate::ops::{op_interact, translate, Ops};
+use crate::scripting::ScriptingBridge;
+use crate::types::{Key, RedisValueRef, ReturnValue, StateRef, StateStoreRef};
+use futures::StreamExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot;
+use tokio_util::codec::FramedRead;
+
+/// Commands queued per worker before the reader waits.
+const WORKER_QUEUE: usize = 1024;
+/// Errors logged in full; the rest are only counted.
+const MAX_LOGGED_ERRORS: u64 = 10;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct LoadReport {
+    pub commands: u64,
+    pub errors: u64,
+}
+
+enum Job {
+    Run(StateRef, Ops),
+    /// Reply once every earlier job has ran.
+    Sync(oneshot::Sender<()>),
+}
+
+fn record_error(errors: &AtomicU64, command: u64, msg: &str) {
+    let seen = errors.fetch_add(1, Ordering::Relaxed);
+    if seen < MAX_LOGGED_ERRORS {
+        error!(LOGGER, "Bulk load command #{} failed: {}", command, msg);
+    }
+}
+
+/// The error message, if `res` is an error reply.
+fn reply_error(res: ReturnValue) -> Option<String> {
+    match res {
+        ReturnValue::Error(e) => Some(String::from_utf8_lossy(e).to_string()),
+        ReturnValue::Ident(reply @ RedisValueRef::Error(_))
+        | ReturnValue::Ident(reply @ RedisValueRef::ErrorMsg(_)) => Some(error_message(reply)),
+        _ => None,
+    }
+}
+
+/// The only key `command` reads or writes, if its spec finds exactly one.
+fn only_key(command: &RedisValueRef) -> Option<Key> {
+    let args = match command {
+        RedisValueRef::Array(args) => args,
+        _ => return None,
+    };
+    let movable = match args.first() {
+        Some(RedisValueRef::BulkString(name)) => {
+            command_spec(name).map_or(true, |spec| spec.flags.contains(&"movablekeys"))
+        }
+        _ => true,
+    };
+    let mut keys = command_keys(args);
+    keys.sort_unstable();
+    keys.dedup();
+    match &keys[..] {
+        [key] if !movable => Some(key.clone()),
+        _ => None,
+    }
+}
+
+struct Loader {
+    state_store: StateStoreRef,
+    scripting_bridge: Arc<ScriptingBridge>,
+    state: StateRef,
+    workers: Vec<Sender<(u64, Job)>>,
+    errors: Arc<AtomicU64>,
+    commands: u64,
+}
+
+impl Loader {
+    fn new(state_store: StateStoreRef, scripting_bridge: Arc<ScriptingBridge>) -> Self {
+        let errors = Arc::new(AtomicU64::new(0));
+        let num_workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let workers = (0..num_workers)
+            .map(|_| {
+                let (sx, mut rx) = channel::<(u64, Job)>(WORKER_QUEUE);
+                let errors = errors.clone();
+                tokio::spawn(async move {
+                    while let Some((command, job)) = rx.recv().await {
+                        match job {
+                            Job::Run(state, op) => {
+                                if let Some(e) = reply_error(op_interact(op, state).await.0) {
+                                    record_error(&errors, command, &e);
+                                }
+                            }
+                            Job::Sync(done) => {
+                                let _ = done.send(());
+                            }
+                        }
+                    }
+                });
+                sx
+            })
+            .collect();
+        Loader {
+            state: state_store.get_default(),
+            state_store,
+            scripting_bridge,
+            workers,
+            errors,
+            commands: 0,
+        }
+    }
+
+    /// Wait for the workers to finish every queued command.
+    async fn barrier(&self) {
+        for worker in &self.workers {
+            let (sx, rx) = oneshot::channel();
+            if worker.send((self.commands, Job::Sync(sx))).await.is_ok() {
+                let _ = rx.await;
+            }
+        }
+    }
+
+    async fn apply(&mut self, command: Result<RedisValueRef, String>) {
+        self.commands += 1;
+        let only_key = command.as_ref().ok().and_then(only_key);
+        let op = match command.map(|c| translate(c, self.state_store.clone())) {
+            Ok(Ok(op)) => op,
+            Ok(Err(e)) => {
+                let msg = error_message(RedisValueRef::from(e));
+                return record_error(&self.errors, self.commands, &msg);
+            }
+            Err(e) => return record_error(&self.errors, self.commands, &e),
+        };
+        // Commands reading other keys have to wait for earlier writes to them.
+        let single_key = match op.write_keys() {
+            Some(keys) if keys.len() == 1 && only_key.as_ref() == Some(&keys[0]) => {
+                Some(keys[0].clone())
+            }
+            _ => None,
+        };
+        match (op, single_key) {
+            (Ops::Misc(op), _) => {
+                self.barrier().await;
+                let res = misc_interact(
+                    op,
+                    &mut self.state,
+                    self.state_store.clone(),
+                    self.scripting_bridge.clone(),
+                )
+                .await;
+                if let Some(e) = reply_error(res) {
+                    record_error(&self.errors, self.commands, &e);
+                }
+            }
+            (op, Some(key)) => {
+                let worker = internal_hash(&key) as usize % self.workers.len();
+                let job = Job::Run(self.state.clone(), op);
+                if self.workers[worker]
+                    .send((self.commands, job))
+                    .await
+                    .is_err()
+                {
+                    record_error(&self.errors, self.commands, "bulk load worker died");
+                }
+            }
+            (op, None) => {
+                self.barrier().await;
+                if let Some(e) = reply_error(op_interact(op, self.state.clone()).await.0) {
+                    record_error(&self.errors, self.commands, &e);
+                }
+            }
+        }
+    }
+
+    async fn finish(self) -> LoadReport {
+        self.barrier().await;
+        LoadReport {
+            commands: self.commands,
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Apply every command in `input`, returning once they've all ran.
+pub async fn bulk_load<R: AsyncRead + Unpin>(
+    input: R,
+    state_store: StateStoreRef,
+    scripting_bridge: Arc<ScriptingBridge>,
+) -> std::io::Result<LoadReport> {
+    let mut input = BufReader::new(input);
+    let is_resp = input.fill_buf().await?.first() == Some(&b'*');
+    let mut loader = Loader::new(state_store, scripting_bridge);
+    if is_resp {
+        let mut frames = FramedRead::new(input, RespParser::with_limits(RespLimits::unlimited()));
+        while let Some(frame) = frames.next().await {
+            match frame {
+                Ok(command) => loader.apply(Ok(command)).await,
+                Err(e) => {
+                    // The stream can't be resynchronized after a framing error.
+                    let msg = format!("Invalid RESP in bulk load input: {:?}", e);
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+                }
+            }
+        }
+    } else {
+        let mut lines = input.lines();
+        while let Some(line) = lines.next_line().await? {
+            if let Some(command) = parse_line(&line) {
+                loader.apply(command).await;
+            }
+        }
+    }
+    Ok(loader.finish().await)
+}
+
+#[cfg(test)]
+mod test_bulk_load {
+    use super::{bulk_load, only_key, LoadReport};
+    use crate::command_file::parse_line;
+    use crate::scripting::ScriptingBridge;
+    use crate::types::{StateStore, StateStoreRef};
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::channel;
+
+    fn setup() -> (StateStoreRef, Arc<ScriptingBridge>) {
+        let (prog_sx, _prog_rx) = channel(1);
+        (
+            Arc::new(StateStore::default()),
+            ScriptingBridge::new(prog_sx),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_inline_load() {
+        let (state_store, bridge) = setup();
+        let input = "set a 1\n# comment\nrpush l x y\nrpush l z\nbogus\nselect 1\nset b 2\n";
+        let report = bulk_load(input.as_bytes(), state_store.clone(), bridge)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            LoadReport {
+                commands: 6,
+                errors: 1
+            }
+        );
+        let db0 = state_store.get_default();
+        assert_eq!(db0.lists.get(&Bytes::from_static(b"l")).unwrap().len(), 3);
+        assert!(db0.kv.contains_key(&Bytes::from_static(b"a")));
+        assert!(state_store
+            .get_or_create(1)
+            .kv
+            .contains_key(&Bytes::from_static(b"b")));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resp_load() {
+        let (state_store, bridge) = setup();
+        let mut input = Vec::new();
+        for i in 0..1000 {
+            let key = format!("k{}", i);
+            input.extend_from_slice(
+                format!(
+                    "*3\r\n$3\r\nset\r\n${}\r\n{}\r\n$1\r\nv\r\n",
+                    key.len(),
+                    key
+                )
+                .as_bytes(),
+            );
+        }
+        let report = bulk_load(&input[..], state_store.clone(), bridge)
+            .await
+            .unwrap();
+        assert_eq!(report.errors, 0);
+        assert_eq!(state_store.get_default().kv.len(), 1000);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reads_wait_for_earlier_writes() {
+        let (state_store, bridge) = setup();
+        let mut input = String::new();
+        for i in 0..2000 {
+            input += &format!("sadd s{} m{}\n", i % 8, i);
+        }
+        input += "sunionstore dest s0 s1 s2 s3 s4 s5 s6 s7\n";
+        let report = bulk_load(input.as_bytes(), state_store.clone(), bridge)
+            .await
+            .unwrap();
+        assert_eq!(report.errors, 0);
+        let db = state_store.get_default();
+        assert_eq!(
+            db.sets.get(&Bytes::from_static(b"dest")).unwrap().len(),
+            2000
+        );
+    }
+
+    #[test]
+    fn test_only_key() {
+        let command = |line: &str| parse_line(line).unwrap().unwrap();
+        assert_eq!(only_key(&command("sadd s a b")), Some(Bytes::from("s")));
+        assert_eq!(
+            only_key(&command("sunionstore s s")),
+            Some(Bytes::from("s"))
+        );
+        assert_eq!(only_key(&command("sunionstore dest a")), None);
+        assert_eq!(only_key(&command("bitop and dest a")), None);
+        assert_eq!(only_key(&command("sort s by w*")), None);
+    }
+}
@@ -0,0 +1,267 @@
+//! Change data capture (CDC).
+//!
+//! Every committed write is serialized as a line of JSON and handed to a
+//! `CdcSink` running on its own thread, so slow sinks never block the server:
+//! once CDC_CHANNEL_SIZE events are queued, further events are dropped (and
+//! counted as `cdc_dropped_events` in INFO) until the sink catches up.
+//!
+//! Keys and arguments are JSON strings when they're UTF-8, and otherwise
+//! `{"base64": "..."}` objects holding their exact bytes.
+
+use crate::logger::LOGGER;
+use crate::startup::Config;
+use crate::types::{Index, Key, RedisValueRef};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// How many events can be queued before new ones are dropped.
+const CDC_CHANNEL_SIZE: usize = 1024;
+
+/// A single committed write.
+#[derive(Debug, Serialize)]
+pub struct CdcEvent {
+    /// Milliseconds since the unix epoch.
+    pub timestamp_ms: u64,
+    /// Database index, if known.
+    pub db: Option<Index>,
+    /// Keys written by the command.
+    pub keys: Vec<CdcBytes>,
+    /// The command and its arguments.
+    pub command: Vec<CdcBytes>,
+}
+
+/// A key or argument, kept byte for byte.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum CdcBytes {
+    Utf8(String),
+    Binary { base64: String },
+}
+
+impl From<&[u8]> for CdcBytes {
+    fn from(b: &[u8]) -> Self {
+        match std::str::from_utf8(b) {
+            Ok(s) => CdcBytes::Utf8(s.to_string()),
+            Err(_) => CdcBytes::Binary {
+                base64: base64::encode(b),
+            },
+        }
+    }
+}
+
+impl CdcEvent {
+    pub fn new(db: Option<Index>, keys: &[Key], command: &RedisValueRef) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let command = match command {
+            RedisValueRef::Array(args) => args
+                .iter()
+                .map(|arg| match arg {
+                    RedisValueRef::BulkString(s) | RedisValueRef::SimpleString(s) => {
+                        CdcBytes::from(&s[..])
+                    }
+                    RedisValueRef::Int(i) => CdcBytes::Utf8(i.to_string()),
+                    other => CdcBytes::Utf8(format!("{:?}", other)),
+                })
+                .collect(),
+            RedisValueRef::BulkString(s) => vec![CdcBytes::from(&s[..])],
+            other => vec![CdcBytes::Utf8(format!("{:?}", other))],
+        };
+        CdcEvent {
+            timestamp_ms,
+            db,
+            keys: keys.iter().map(|k| CdcBytes::from(&k[..])).collect(),
+            command,
+        }
+    }
+}
+
+/// Destination for CDC events. Each call receives one serialized event.
+pub trait CdcSink: Send + 'static {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()>;
+}
+
+/// Appends newline delimited JSON to a file.
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink { file })
+    }
+}
+
+impl CdcSink for FileSink {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.file.write_all(line)?;
+        self.file.write_all(b"\n")
+    }
+}
+
+/// Streams newline delimited JSON to a TCP endpoint, reconnecting on failure.
+pub struct TcpSink {
+    addr: String,
+    stream: Option<TcpStream>,
+}
+
+impl TcpSink {
+    pub fn new(addr: String) -> Self {
+        TcpSink { addr, stream: None }
+    }
+}
+
+impl CdcSink for TcpSink {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(TcpStream::connect(&self.addr)?);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        let res = stream.write_all(line).and_then(|_| stream.write_all(b"\n"));
+        if res.is_err() {
+            // Drop the connection so the next event reconnects.
+            self.stream = None;
+        }
+        res
+    }
+}
+
+/// Handle used by the server to publish events.
+pub type CdcSender = Sender<CdcEvent>;
+
+/// Queue `event` for the sink without waiting. Returns false if it was
+/// dropped, because the sink is behind or gone.
+pub fn publish(cdc: &CdcSender, event: CdcEvent) -> bool {
+    match cdc.try_send(event) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => false,
+        Err(TrySendError::Closed(_)) => {
+            error!(LOGGER, "CDC sink has shut down, dropping event!");
+            false
+        }
+    }
+}
+
+fn sink_loop(mut events: Receiver<CdcEvent>, mut sink: Box<dyn CdcSink>) {
+    while let Some(event) = events.blocking_recv() {
+        let line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(LOGGER, "Failed to serialize CDC event {:?}: {}", event, e);
+                continue;
+            }
+        };
+        if let Err(e) = sink.write_line(&line) {
+            error!(LOGGER, "Failed to write CDC event: {}", e);
+        }
+    }
+}
+
+/// Spawn a thread draining events into `sink`.
+pub fn spawn_sink(sink: Box<dyn CdcSink>) -> CdcSender {
+    let (sx, rx) = channel(CDC_CHANNEL_SIZE);
+    std::thread::spawn(move || sink_loop(rx, sink));
+    sx
+}
+
+/// Start CDC if it was configured.
+pub fn start_cdc(config: &Config) -> Result<Option<CdcSender>, Box<dyn Error>> {
+    let sink: Box<dyn CdcSink> = match (&config.cdc_file, &config.cdc_tcp) {
+        (Some(_), Some(_)) => return Err("Only one of --cdc-file and --cdc-tcp can be used".into()),
+        (Some(path), None) => {
+            info!(LOGGER, "Writing CDC events to {:?}", path);
+            Box::new(FileSink::open(path)?)
+        }
+        (None, Some(addr)) => {
+            info!(LOGGER, "Streaming CDC events to {}", addr);
+            Box::new(TcpSink::new(addr.clone()))
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(spawn_sink(sink)))
+}
+
+#[cfg(test)]
+mod test_cdc {
+    use super::{publish, spawn_sink, CdcEvent, FileSink};
+    use crate::types::RedisValueRef;
+    use bytes::Bytes;
+    use tokio::sync::mpsc::channel;
+
+    fn set_event() -> CdcEvent {
+        let command = RedisValueRef::Array(vec![
+            RedisValueRef::BulkString(Bytes::from_static(b"SET")),
+            RedisValueRef::BulkString(Bytes::from_static(b"key")),
+            RedisValueRef::Int(1),
+        ]);
+        CdcEvent::new(Some(2), &[Bytes::from_static(b"key")], &command)
+    }
+
+    #[test]
+    fn test_event_json() {
+        let json: serde_json::Value = serde_json::to_value(set_event()).unwrap();
+        assert_eq!(json["db"], 2);
+        assert_eq!(json["keys"], serde_json::json!(["key"]));
+        assert_eq!(json["command"], serde_json::json!(["SET", "key", "1"]));
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        let key = Bytes::from_static(b"k\xff\x00");
+        let command = RedisValueRef::Array(vec![
+            RedisValueRef::BulkString(Bytes::from_static(b"DEL")),
+            RedisValueRef::BulkString(key.clone()),
+        ]);
+        let event = CdcEvent::new(None, &[key.clone()], &command);
+        let json: serde_json::Value = serde_json::to_value(event).unwrap();
+        let binary = serde_json::json!({ "base64": "a/8A" });
+        assert_eq!(json["keys"], serde_json::json!([binary]));
+        assert_eq!(json["command"], serde_json::json!(["DEL", binary]));
+        let decoded = base64::decode(json["keys"][0]["base64"].as_str().unwrap()).unwrap();
+        assert_eq!(decoded, key);
+    }
+
+    #[test]
+    fn test_full_queue_drops() {
+        let (cdc, _events) = channel(1);
+        assert!(publish(&cdc, set_event()));
+        assert!(!publish(&cdc, set_event()));
+        let (cdc, events) = channel(1);
+        drop(events);
+        assert!(!publish(&cdc, set_event()));
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let path =
+            std::env::temp_dir().join(format!("redis-oxide-test-cdc-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let cdc = spawn_sink(Box::new(FileSink::open(&path).unwrap()));
+        assert!(publish(&cdc, set_event()));
+        assert!(publish(&cdc, set_event()));
+        // The sink thread exits once every sender is gone.
+        drop(cdc);
+        let mut lines = 0;
+        for _ in 0..100 {
+            lines = std::fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .count();
+            if lines == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(lines, 2);
+        let _ = std::fs::remove_file(&path);
+    }
+}
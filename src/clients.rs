@@ -0,0 +1,284 @@
+//! Connected clients: CLIENT LIST, CLIENT KILL and friends.
+//!
+//! `socket_listener` registers every RESP connection here, through the
+//! `Client` its `Connection` holds, and dropping the `Client` unregisters
+//! it. CLIENT KILL wakes the connection's kill signal; the connection then
+//! stops serving and closes its socket.
+
+use crate::types::{Index, StateStoreRef, Value};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+tokio::task_local! {
+    /// The kill signal of the client whose command is running.
+    pub static KILL_SIGNAL: Arc<Notify>;
+}
+
+/// Wait until the client running this command is killed, or forever outside
+/// of a client's command. The signal is passed back on, for the connection
+/// to hang up on once the command returns.
+pub async fn killed() {
+    let signal = match KILL_SIGNAL.try_with(|signal| signal.clone()) {
+        Ok(signal) => signal,
+        Err(_) => return futures::future::pending().await,
+    };
+    signal.notified().await;
+    signal.notify_one();
+}
+
+pub type ClientId = u64;
+
+/// What a connection shows in CLIENT LIST.
+struct ClientInfo {
+    id: ClientId,
+    addr: SocketAddr,
+    connected: Instant,
+    name: Mutex<Option<Value>>,
+    /// When the last command was started, and its name.
+    last_command: Mutex<(Instant, String)>,
+    db: AtomicI64,
+    kill: Arc<Notify>,
+}
+
+#[derive(Default)]
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: DashMap<ClientId, Arc<ClientInfo>>,
+}
+
+/// Which clients CLIENT KILL closes. Every given filter must match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KillFilter {
+    pub id: Option<ClientId>,
+    pub addr: Option<String>,
+    /// Leave the calling client alone. On unless SKIPME no.
+    pub skip_me: bool,
+    /// `CLIENT KILL addr`, which replies OK or an error rather than a count.
+    pub legacy: bool,
+}
+
+impl Default for KillFilter {
+    fn default() -> Self {
+        KillFilter {
+            id: None,
+            addr: None,
+            skip_me: true,
+            legacy: false,
+        }
+    }
+}
+
+impl KillFilter {
+    fn matches(&self, client: &ClientInfo, me: ClientId) -> bool {
+        // The old form is allowed to kill its own connection.
+        if self.skip_me && !self.legacy && client.id == me {
+            return false;
+        }
+        self.id.map_or(true, |id| id == client.id)
+            && self
+                .addr
+                .as_ref()
+                .map_or(true, |addr| *addr == client.addr.to_string())
+    }
+}
+
+impl ClientRegistry {
+    /// Number of connected clients.
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// CLIENT LIST: one line per client, ordered by id.
+    pub fn list(&self) -> String {
+        let mut clients: Vec<Arc<ClientInfo>> = self
+            .clients
+            .iter()
+            .map(|client| client.value().clone())
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        let now = Instant::now();
+        let mut out = String::new();
+        for client in clients {
+            let name = client.name.lock().clone().unwrap_or_default();
+            let (last_at, command) = client.last_command.lock().clone();
+            let _ = writeln!(
+                out,
+                "id={} addr={} name={} age={} idle={} db={} cmd={}",
+                client.id,
+                client.addr,
+                String::from_utf8_lossy(&name),
+                now.duration_since(client.connected).as_secs(),
+                now.duration_since(last_at).as_secs(),
+                client.db.load(Ordering::Relaxed),
+                command
+            );
+        }
+        out
+    }
+
+    /// Close every client matching `filter`, on behalf of client `me`.
+    /// Returns how many were closed.
+    pub fn kill(&self, filter: &KillFilter, me: ClientId) -> usize {
+        let mut killed = 0;
+        for client in self.clients.iter() {
+            if filter.matches(client.value(), me) {
+                client.kill.notify_one();
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
+
+/// A connection's entry in the registry. Unregisters when dropped.
+pub struct Client {
+    info: Arc<ClientInfo>,
+    state_store: StateStoreRef,
+}
+
+impl Client {
+    pub fn new(state_store: StateStoreRef, addr: SocketAddr) -> Self {
+        let registry = &state_store.clients;
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+        let info = Arc::new(ClientInfo {
+            id,
+            addr,
+            connected: now,
+            name: Mutex::new(None),
+            last_command: Mutex::new((now, "NULL".into())),
+            db: AtomicI64::new(0),
+            kill: Arc::new(Notify::new()),
+        });
+        registry.clients.insert(id, info.clone());
+        Client { info, state_store }
+    }
+
+    pub fn id(&self) -> ClientId {
+        self.info.id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.info.addr
+    }
+
+    pub fn name(&self) -> Option<Value> {
+        self.info.name.lock().clone()
+    }
+
+    /// Name the connection. An empty name removes it.
+    pub fn set_name(&self, name: Value) {
+        *self.info.name.lock() = Some(name).filter(|name| !name.is_empty());
+    }
+
+    /// Note that the client started running `command`.
+    pub fn record_command(&self, command: &str) {
+        *self.info.last_command.lock() = (Instant::now(), command.to_string());
+    }
+
+    pub fn set_db(&self, db: Index) {
+        self.info.db.store(db, Ordering::Relaxed);
+    }
+
+    /// Woken when the client is killed.
+    pub fn kill_signal(&self) -> Arc<Notify> {
+        self.info.kill.clone()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.state_store.clients.clients.remove(&self.info.id);
+    }
+}
+
+#[cfg(test)]
+mod test_clients {
+    use super::{Client, KillFilter, KILL_SIGNAL};
+    use crate::timeouts::blocking_keys_timeout;
+    use crate::types::{ReturnValue, StateStore};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_list() {
+        let store = Arc::new(StateStore::default());
+        let a = Client::new(store.clone(), addr(1000));
+        let b = Client::new(store.clone(), addr(1001));
+        assert_eq!((a.id(), b.id()), (1, 2));
+        b.set_name(Bytes::from_static(b"worker"));
+        b.record_command("get");
+        b.set_db(3);
+        let list = store.clients.list();
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "id=1 addr=127.0.0.1:1000 name= age=0 idle=0 db=0 cmd=NULL",
+                "id=2 addr=127.0.0.1:1001 name=worker age=0 idle=0 db=3 cmd=get",
+            ]
+        );
+        b.set_name(Bytes::new());
+        assert_eq!(b.name(), None);
+        drop(a);
+        assert_eq!(store.clients.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_kill() {
+        let store = Arc::new(StateStore::default());
+        let me = Client::new(store.clone(), addr(1000));
+        let other = Client::new(store.clone(), addr(1001));
+        let by_id = KillFilter {
+            id: Some(me.id()),
+            ..KillFilter::default()
+        };
+        // Clients skip themselves unless asked not to.
+        assert_eq!(store.clients.kill(&by_id, me.id()), 0);
+        let by_addr = KillFilter {
+            addr: Some("127.0.0.1:1001".into()),
+            ..KillFilter::default()
+        };
+        assert_eq!(store.clients.kill(&by_addr, me.id()), 1);
+        // The signal is kept until the connection waits for it.
+        other.kill_signal().notified().await;
+        let everyone = KillFilter {
+            skip_me: false,
+            ..KillFilter::default()
+        };
+        assert_eq!(store.clients.kill(&everyone, me.id()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_kill_wakes_blocked_client() {
+        let store = Arc::new(StateStore::default());
+        let me = Client::new(store.clone(), addr(1000));
+        let blocked = Client::new(store.clone(), addr(1001));
+        let keys = vec![Bytes::from_static(b"list")];
+        let pop = blocking_keys_timeout(Box::new(|| None), store.get_default(), keys, 60);
+        let pop = tokio::spawn(KILL_SIGNAL.scope(blocked.kill_signal(), pop));
+        let by_id = KillFilter {
+            id: Some(blocked.id()),
+            ..KillFilter::default()
+        };
+        assert_eq!(store.clients.kill(&by_id, me.id()), 1);
+        assert_eq!(pop.await.unwrap(), ReturnValue::Nil);
+        // Left for the connection to hang up on.
+        blocked.kill_signal().notified().await;
+    }
+}
@@ -0,0 +1,89 @@
+//! Files of inline commands, one per line (e.g. seeding scripts).
+//!
+//! Lines are split like a shell would, so `set greeting "hello world"`
+//! is three arguments. Blank lines and lines starting with `#` are skipped.
+
+use crate::ops::translate;
+use crate::types::{RedisValueRef, StateStoreRef};
+use std::io::{self, BufRead};
+
+/// Parse an inline command. Returns None for blank lines and comments.
+pub fn parse_line(line: &str) -> Option<Result<RedisValueRef, String>> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return None;
+    }
+    let args = match shlex::split(trimmed) {
+        Some(args) => args,
+        None => return Some(Err("unbalanced quotes".into())),
+    };
+    let args = args
+        .into_iter()
+        .map(|arg| RedisValueRef::BulkString(arg.into()))
+        .collect();
+    Some(Ok(RedisValueRef::Array(args)))
+}
+
+/// The message of an error reply.
+pub fn error_message(reply: RedisValueRef) -> String {
+    match reply {
+        RedisValueRef::Error(e) => String::from_utf8_lossy(&e).to_string(),
+        RedisValueRef::ErrorMsg(e) => String::from_utf8_lossy(&e).to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// Check every command parses, without running anything.
+/// Returns (line number, error) for each bad line.
+pub fn validate_commands<R: BufRead>(
+    reader: R,
+    state_store: StateStoreRef,
+) -> io::Result<Vec<(usize, String)>> {
+    let mut errors = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let res = match parse_line(&line) {
+            None => continue,
+            Some(Ok(command)) => translate(command, state_store.clone())
+                .map(|_| ())
+                .map_err(|e| error_message(e.into())),
+            Some(Err(e)) => Err(e),
+        };
+        if let Err(e) = res {
+            errors.push((index + 1, e));
+        }
+    }
+    Ok(errors)
+}
+
+#[cfg(test)]
+mod test_command_file {
+    use super::{parse_line, validate_commands};
+    use crate::types::{RedisValueRef, StateStore};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(parse_line("# seed users"), None);
+        assert_eq!(
+            parse_line(r#"set greeting "hello world""#),
+            Some(Ok(RedisValueRef::Array(vec![
+                RedisValueRef::BulkString(Bytes::from_static(b"set")),
+                RedisValueRef::BulkString(Bytes::from_static(b"greeting")),
+                RedisValueRef::BulkString(Bytes::from_static(b"hello world")),
+            ])))
+        );
+        assert!(matches!(parse_line("set a \"b"), Some(Err(_))));
+    }
+
+    #[test]
+    fn test_validate_commands() {
+        let file = "set a b\n\n# comment\nnotacommand x\nget\nlpush l 1 2\n";
+        let errors = validate_commands(file.as_bytes(), Arc::new(StateStore::default())).unwrap();
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![4, 5]);
+        assert!(errors[0].1.contains("Unknown"), "{}", errors[0].1);
+    }
+}
@@ -0,0 +1,426 @@
+//! COMMAND: metadata about every command we accept.
+//!
+//! Clients like redis-cli ask for it on connect, for hints and to find keys
+//! in arguments. `translate` still decides what's accepted; keep this table
+//! in step with it when adding a command.
+
+use crate::types::{RedisValueRef, ReturnValue, Value};
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Argument count, counting the command name. Negative means at least
+    /// that many.
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    /// Position of the first key argument, or 0 without keys.
+    pub first_key: i64,
+    /// Position of the last key argument. Negative counts from the end.
+    pub last_key: i64,
+    /// Distance between key arguments.
+    pub step: i64,
+    pub group: &'static str,
+    pub summary: &'static str,
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    (first_key, last_key, step): (i64, i64, i64),
+    group: &'static str,
+    summary: &'static str,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+        group,
+        summary,
+    }
+}
+
+const WRITE: &[&str] = &["write", "denyoom"];
+const WRITE_FAST: &[&str] = &["write", "denyoom", "fast"];
+/// Writes that only ever free memory.
+const SHRINK: &[&str] = &["write", "fast"];
+const READ: &[&str] = &["readonly"];
+const READ_FAST: &[&str] = &["readonly", "fast"];
+const RANDOM: &[&str] = &["readonly", "random"];
+const BLOCKING: &[&str] = &["write", "noscript", "blocking"];
+const ADMIN: &[&str] = &["admin", "noscript", "loading", "stale"];
+const PUBSUB: &[&str] = &["pubsub", "noscript", "loading", "stale"];
+const CONNECTION: &[&str] = &["noscript", "loading", "stale", "fast"];
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+const TWO_KEYS: (i64, i64, i64) = (1, 2, 1);
+const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+
+#[rustfmt::skip]
+pub const COMMANDS: &[CommandSpec] = &[
+    // Keys and strings
+    spec("get", 2, READ_FAST, ONE_KEY, "string", "Get the value of a key"),
+    spec("set", -3, WRITE, ONE_KEY, "string", "Set the string value of a key"),
+    spec("setex", 4, WRITE, ONE_KEY, "string", "Set the value and expiration of a key"),
+    spec("psetex", 4, WRITE, ONE_KEY, "string", "Set the value and expiration in milliseconds of a key"),
+    spec("setnx", 3, WRITE_FAST, ONE_KEY, "string", "Set the value of a key, only if the key does not exist"),
+    spec("getset", 3, WRITE_FAST, ONE_KEY, "string", "Set the string value of a key and return its old value"),
+    spec("getdel", 2, SHRINK, ONE_KEY, "string", "Get the value of a key and delete the key"),
+    spec("getex", -2, WRITE_FAST, ONE_KEY, "string", "Get the value of a key and optionally set its expiration"),
+    spec("setbit", 4, WRITE, ONE_KEY, "bitmap", "Sets or clears the bit at offset in the string value stored at key"),
+    spec("getbit", 3, READ_FAST, ONE_KEY, "bitmap", "Returns the bit value at offset in the string value stored at key"),
+    spec("bitcount", -2, READ, ONE_KEY, "bitmap", "Count set bits in a string"),
+    spec("bitpos", -3, READ, ONE_KEY, "bitmap", "Find first bit set or clear in a string"),
+    spec("bitop", -4, WRITE, (2, -1, 1), "bitmap", "Perform bitwise operations between strings"),
+    spec("bitfield", -2, WRITE, ONE_KEY, "bitmap", "Perform arbitrary bitfield integer operations on strings"),
+    spec("bitfield_ro", -2, READ_FAST, ONE_KEY, "bitmap", "Perform arbitrary bitfield integer operations on strings. Read-only variant of BITFIELD"),
+    spec("mget", -2, READ_FAST, ALL_KEYS, "string", "Get the values of all the given keys"),
+    spec("mset", -3, WRITE, (1, -1, 2), "string", "Set multiple keys to multiple values"),
+    spec("incr", 2, WRITE_FAST, ONE_KEY, "string", "Increment the integer value of a key by one"),
+    spec("decr", 2, WRITE_FAST, ONE_KEY, "string", "Decrement the integer value of a key by one"),
+    spec("incrby", 3, WRITE_FAST, ONE_KEY, "string", "Increment the integer value of a key by the given amount"),
+    spec("decrby", 3, WRITE_FAST, ONE_KEY, "string", "Decrement the integer value of a key by the given number"),
+    spec("incrbyfloat", 3, WRITE_FAST, ONE_KEY, "string", "Increment the float value of a key by the given amount"),
+    spec("del", -2, &["write"], ALL_KEYS, "generic", "Delete a key"),
+    spec("exists", -2, READ_FAST, ALL_KEYS, "generic", "Determine if a key exists"),
+    spec("rename", 3, &["write"], TWO_KEYS, "generic", "Rename a key"),
+    spec("renamenx", 3, SHRINK, TWO_KEYS, "generic", "Rename a key, only if the new key does not exist"),
+    spec("dump", 2, READ, ONE_KEY, "generic", "Return a serialized version of the value stored at the specified key"),
+    spec("restore", -4, WRITE, ONE_KEY, "generic", "Create a key using the provided serialized value, previously obtained using DUMP"),
+    spec("type", 2, READ_FAST, ONE_KEY, "generic", "Determine the type stored at key"),
+    spec("keys", 2, &["readonly", "sort_for_script"], NO_KEYS, "generic", "Find all keys matching the given pattern"),
+    spec("copy", -3, WRITE, TWO_KEYS, "generic", "Copy a key"),
+    spec("sort", -2, &["write", "denyoom", "movablekeys"], ONE_KEY, "generic", "Sort the elements in a list, set or sorted set"),
+    spec("move", 3, SHRINK, ONE_KEY, "generic", "Move a key to another database"),
+    spec("randomkey", 1, RANDOM, NO_KEYS, "generic", "Return a random key from the keyspace"),
+    spec("scan", -2, RANDOM, NO_KEYS, "generic", "Incrementally iterate the keys space"),
+    spec("object", -2, READ, (2, 2, 1), "generic", "Inspect the internals of Redis objects"),
+    // Lists
+    spec("lpush", -3, WRITE_FAST, ONE_KEY, "list", "Prepend one or multiple elements to a list"),
+    spec("rpush", -3, WRITE_FAST, ONE_KEY, "list", "Append one or multiple elements to a list"),
+    spec("lpushx", -3, WRITE_FAST, ONE_KEY, "list", "Prepend an element to a list, only if the list exists"),
+    spec("rpushx", -3, WRITE_FAST, ONE_KEY, "list", "Append an element to a list, only if the list exists"),
+    spec("llen", 2, READ_FAST, ONE_KEY, "list", "Get the length of a list"),
+    spec("lpop", -2, SHRINK, ONE_KEY, "list", "Remove and get the first elements in a list"),
+    spec("rpop", -2, SHRINK, ONE_KEY, "list", "Remove and get the last elements in a list"),
+    spec("blpop", -3, BLOCKING, (1, -2, 1), "list", "Remove and get the first element in a list, or block until one is available"),
+    spec("brpop", -3, BLOCKING, (1, -2, 1), "list", "Remove and get the last element in a list, or block until one is available"),
+    spec("linsert", 5, WRITE, ONE_KEY, "list", "Insert an element before or after another element in a list"),
+    spec("lrem", 4, &["write"], ONE_KEY, "list", "Remove elements from a list"),
+    spec("lpos", -3, READ, ONE_KEY, "list", "Return the index of matching elements on a list"),
+    spec("lindex", 3, READ, ONE_KEY, "list", "Get an element from a list by its index"),
+    spec("lset", 4, WRITE, ONE_KEY, "list", "Set the value of an element in a list by its index"),
+    spec("lrange", 4, READ, ONE_KEY, "list", "Get a range of elements from a list"),
+    spec("ltrim", 4, &["write"], ONE_KEY, "list", "Trim a list to the specified range"),
+    spec("rpoplpush", 3, WRITE, TWO_KEYS, "list", "Remove the last element in a list, prepend it to another list and return it"),
+    spec("lmove", 5, WRITE, TWO_KEYS, "list", "Pop an element from a list, push it to another list and return it"),
+    spec("blmove", 6, &["write", "denyoom", "noscript", "blocking"], TWO_KEYS, "list", "Pop an element from a list, push it to another list and return it; or block until one is available"),
+    spec("brpoplpush", 4, &["write", "denyoom", "noscript", "blocking"], TWO_KEYS, "list", "Pop an element from a list, push it to another list and return it; or block until one is available"),
+    // Hashes
+    spec("hget", 3, READ_FAST, ONE_KEY, "hash", "Get the value of a hash field"),
+    spec("hset", -4, WRITE_FAST, ONE_KEY, "hash", "Set the string value of a hash field"),
+    spec("hsetnx", 4, WRITE_FAST, ONE_KEY, "hash", "Set the value of a hash field, only if the field does not exist"),
+    spec("hmset", -4, WRITE_FAST, ONE_KEY, "hash", "Set multiple hash fields to multiple values"),
+    spec("hexists", 3, READ_FAST, ONE_KEY, "hash", "Determine if a hash field exists"),
+    spec("hgetall", 2, &["readonly", "random"], ONE_KEY, "hash", "Get all the fields and values in a hash"),
+    spec("hmget", -3, READ_FAST, ONE_KEY, "hash", "Get the values of all the given hash fields"),
+    spec("hkeys", 2, &["readonly", "sort_for_script"], ONE_KEY, "hash", "Get all the fields in a hash"),
+    spec("hvals", 2, &["readonly", "sort_for_script"], ONE_KEY, "hash", "Get all the values in a hash"),
+    spec("hlen", 2, READ_FAST, ONE_KEY, "hash", "Get the number of fields in a hash"),
+    spec("hdel", -3, SHRINK, ONE_KEY, "hash", "Delete one or more hash fields"),
+    spec("hstrlen", 3, READ_FAST, ONE_KEY, "hash", "Get the length of the value of a hash field"),
+    spec("hincrby", 4, WRITE_FAST, ONE_KEY, "hash", "Increment the integer value of a hash field by the given number"),
+    spec("hincrbyfloat", 4, WRITE_FAST, ONE_KEY, "hash", "Increment the float value of a hash field by the given amount"),
+    spec("hrandfield", -2, RANDOM, ONE_KEY, "hash", "Get one or multiple random fields from a hash"),
+    spec("hscan", -3, RANDOM, ONE_KEY, "hash", "Incrementally iterate hash fields and associated values"),
+    // Sets
+    spec("sadd", -3, WRITE_FAST, ONE_KEY, "set", "Add one or more members to a set"),
+    spec("srem", -3, SHRINK, ONE_KEY, "set", "Remove one or more members from a set"),
+    spec("smembers", 2, &["readonly", "sort_for_script"], ONE_KEY, "set", "Get all the members in a set"),
+    spec("scard", 2, READ_FAST, ONE_KEY, "set", "Get the number of members in a set"),
+    spec("sismember", 3, READ_FAST, ONE_KEY, "set", "Determine if a given value is a member of a set"),
+    spec("smismember", -3, READ_FAST, ONE_KEY, "set", "Returns the membership associated with the given elements for a set"),
+    spec("sdiff", -2, &["readonly", "sort_for_script"], ALL_KEYS, "set", "Subtract multiple sets"),
+    spec("sunion", -2, &["readonly", "sort_for_script"], ALL_KEYS, "set", "Add multiple sets"),
+    spec("sinter", -2, &["readonly", "sort_for_script"], ALL_KEYS, "set", "Intersect multiple sets"),
+    spec("sdiffstore", -3, WRITE, ALL_KEYS, "set", "Subtract multiple sets and store the resulting set in a key"),
+    spec("sunionstore", -3, WRITE, ALL_KEYS, "set", "Add multiple sets and store the resulting set in a key"),
+    spec("sinterstore", -3, WRITE, ALL_KEYS, "set", "Intersect multiple sets and store the resulting set in a key"),
+    spec("smove", 4, SHRINK, TWO_KEYS, "set", "Move a member from one set to another"),
+    spec("spop", -2, &["write", "random", "fast"], ONE_KEY, "set", "Remove and return one or multiple random members from a set"),
+    spec("srandmember", -2, RANDOM, ONE_KEY, "set", "Get one or multiple random members from a set"),
+    spec("sscan", -3, RANDOM, ONE_KEY, "set", "Incrementally iterate Set elements"),
+    // Sorted sets
+    spec("zadd", -4, WRITE_FAST, ONE_KEY, "sorted_set", "Add one or more members to a sorted set, or update its score if it already exists"),
+    spec("zrem", -3, SHRINK, ONE_KEY, "sorted_set", "Remove one or more members from a sorted set"),
+    spec("zrange", -4, READ, ONE_KEY, "sorted_set", "Return a range of members in a sorted set"),
+    spec("zrevrange", -4, READ, ONE_KEY, "sorted_set", "Return a range of members in a sorted set, by index, with scores ordered from high to low"),
+    spec("zrangebyscore", -4, READ, ONE_KEY, "sorted_set", "Return a range of members in a sorted set, by score"),
+    spec("zrevrangebyscore", -4, READ, ONE_KEY, "sorted_set", "Return a range of members in a sorted set, by score, with scores ordered from high to low"),
+    spec("zrangebylex", -4, READ, ONE_KEY, "sorted_set", "Return a range of members in a sorted set, by lexicographical range"),
+    spec("zrevrangebylex", -4, READ, ONE_KEY, "sorted_set", "Return a range of members in a sorted set, by lexicographical range, ordered from higher to lower strings"),
+    spec("zcard", 2, READ_FAST, ONE_KEY, "sorted_set", "Get the number of members in a sorted set"),
+    spec("zscore", 3, READ_FAST, ONE_KEY, "sorted_set", "Get the score associated with the given member in a sorted set"),
+    spec("zincrby", 4, WRITE_FAST, ONE_KEY, "sorted_set", "Increment the score of a member in a sorted set"),
+    spec("zcount", 4, READ_FAST, ONE_KEY, "sorted_set", "Count the members in a sorted set with scores within the given values"),
+    spec("zrank", 3, READ_FAST, ONE_KEY, "sorted_set", "Determine the index of a member in a sorted set"),
+    spec("zremrangebyscore", 4, &["write"], ONE_KEY, "sorted_set", "Remove all members in a sorted set within the given scores"),
+    spec("zremrangebyrank", 4, &["write"], ONE_KEY, "sorted_set", "Remove all members in a sorted set within the given indexes"),
+    spec("zpopmax", -2, SHRINK, ONE_KEY, "sorted_set", "Remove and return members with the highest scores in a sorted set"),
+    spec("zpopmin", -2, SHRINK, ONE_KEY, "sorted_set", "Remove and return members with the lowest scores in a sorted set"),
+    spec("zscan", -3, RANDOM, ONE_KEY, "sorted_set", "Incrementally iterate sorted sets elements and associated scores"),
+    // HyperLogLog
+    spec("pfadd", -2, WRITE_FAST, ONE_KEY, "hyperloglog", "Adds the specified elements to the specified HyperLogLog"),
+    spec("pfcount", -2, READ, ALL_KEYS, "hyperloglog", "Return the approximated cardinality of the set(s) observed by the HyperLogLog at key(s)"),
+    spec("pfmerge", -2, WRITE, ALL_KEYS, "hyperloglog", "Merge N different HyperLogLogs into a single one"),
+    // Geo
+    spec("geoadd", -5, WRITE, ONE_KEY, "geo", "Add one or more geospatial items in the geospatial index represented using a sorted set"),
+    spec("geopos", -2, READ, ONE_KEY, "geo", "Returns longitude and latitude of members of a geospatial index"),
+    spec("geodist", -4, READ, ONE_KEY, "geo", "Returns the distance between two members of a geospatial index"),
+    spec("geosearch", -7, READ, ONE_KEY, "geo", "Query a sorted set representing a geospatial index to fetch members inside an area of a box or a circle."),
+    // Streams
+    spec("xadd", -5, WRITE_FAST, ONE_KEY, "stream", "Appends a new entry to a stream"),
+    spec("xlen", 2, READ_FAST, ONE_KEY, "stream", "Return the number of entries in a stream"),
+    spec("xrange", -4, READ, ONE_KEY, "stream", "Return a range of elements in a stream, with IDs matching the specified IDs interval"),
+    spec("xrevrange", -4, READ, ONE_KEY, "stream", "Return a range of elements in a stream, with IDs matching the specified IDs interval, in reverse order (from greater to smaller IDs) compared to XRANGE"),
+    spec("xread", -4, &["readonly", "blocking", "movablekeys"], NO_KEYS, "stream", "Return never seen elements in multiple streams, with IDs greater than the ones reported by the caller for each stream. Can block."),
+    spec("xgroup", -2, WRITE, NO_KEYS, "stream", "Create, destroy or move a consumer group"),
+    spec("xreadgroup", -7, &["write", "blocking", "movablekeys"], NO_KEYS, "stream", "Return new entries from a stream using a consumer group, or access the history of the pending entries for a given consumer. Can block."),
+    spec("xack", -4, SHRINK, ONE_KEY, "stream", "Marks a pending message as correctly processed, effectively removing it from the pending entries list of the consumer group. Return value of the command is the number of messages successfully acknowledged, that is, the IDs we were actually able to resolve in the PEL."),
+    spec("xpending", -3, READ, ONE_KEY, "stream", "Return information and entries from a stream consumer group pending entries list, that are messages fetched but never acknowledged."),
+    spec("xclaim", -6, SHRINK, ONE_KEY, "stream", "Changes (or acquires) ownership of a message in a consumer group, as if the message was delivered to the specified consumer."),
+    spec("xautoclaim", -6, SHRINK, ONE_KEY, "stream", "Changes (or acquires) ownership of messages in a consumer group, as if the messages were delivered to the specified consumer."),
+    // Bloom and cuckoo filters, count-min sketches, top-k and stacks
+    spec("binsert", 3, WRITE_FAST, ONE_KEY, "bloom", "Add a value to a bloom filter"),
+    spec("bcontains", 3, READ_FAST, ONE_KEY, "bloom", "Determine if a value may be in a bloom filter"),
+    spec("bf.reserve", 4, WRITE, ONE_KEY, "bloom", "Create a bloom filter with a given error rate and capacity"),
+    spec("bf.madd", -3, WRITE_FAST, ONE_KEY, "bloom", "Add one or more values to a bloom filter"),
+    spec("bf.mexists", -3, READ_FAST, ONE_KEY, "bloom", "Determine if one or more values may be in a bloom filter"),
+    spec("bf.info", 2, READ_FAST, ONE_KEY, "bloom", "Get the capacity, size and number of items of a bloom filter"),
+    spec("cf.add", 3, WRITE_FAST, ONE_KEY, "cuckoo", "Add a value to a cuckoo filter"),
+    spec("cf.exists", 3, READ_FAST, ONE_KEY, "cuckoo", "Determine if a value may be in a cuckoo filter"),
+    spec("cf.del", 3, SHRINK, ONE_KEY, "cuckoo", "Remove one copy of a value from a cuckoo filter"),
+    spec("cf.count", 3, READ_FAST, ONE_KEY, "cuckoo", "Get how many times a value may have been added to a cuckoo filter"),
+    spec("cms.initbydim", 4, WRITE, ONE_KEY, "cms", "Create a count-min sketch with a given width and depth"),
+    spec("cms.initbyprob", 4, WRITE, ONE_KEY, "cms", "Create a count-min sketch sized for a given error and probability of exceeding it"),
+    spec("cms.incrby", -4, WRITE_FAST, ONE_KEY, "cms", "Increase the counts of one or more items in a count-min sketch"),
+    spec("cms.query", -3, READ_FAST, ONE_KEY, "cms", "Get the estimated counts of one or more items in a count-min sketch"),
+    spec("cms.merge", -4, &["write", "denyoom", "movablekeys"], ONE_KEY, "cms", "Merge count-min sketches into another, with optional weights"),
+    spec("cms.info", 2, READ_FAST, ONE_KEY, "cms", "Get the width, depth and total count of a count-min sketch"),
+    spec("topk.reserve", -3, WRITE, ONE_KEY, "topk", "Create a top-k tracker for the k most frequent items"),
+    spec("topk.add", -3, WRITE_FAST, ONE_KEY, "topk", "Count one or more items in a top-k tracker, replying with any items they pushed out of the top k"),
+    spec("topk.query", -3, READ_FAST, ONE_KEY, "topk", "Determine if one or more items are in the top k"),
+    spec("topk.count", -3, READ_FAST, ONE_KEY, "topk", "Get the estimated counts of one or more items in a top-k tracker"),
+    spec("topk.list", -2, READ, ONE_KEY, "topk", "Get the top k items, optionally with their estimated counts"),
+    spec("stpush", -3, WRITE_FAST, ONE_KEY, "stack", "Push one or more values onto a stack"),
+    spec("stpop", 2, SHRINK, ONE_KEY, "stack", "Remove and get the top value of a stack"),
+    spec("stpopn", 3, SHRINK, ONE_KEY, "stack", "Remove and get up to count values from the top of a stack"),
+    spec("stpeek", 2, READ_FAST, ONE_KEY, "stack", "Get the top value of a stack"),
+    spec("stsize", 2, READ_FAST, ONE_KEY, "stack", "Get the number of values in a stack"),
+    // Queues
+    spec("qpush", -3, WRITE_FAST, ONE_KEY, "queue", "Add one or more jobs to the back of a queue"),
+    spec("qpop", -2, &["write", "blocking"], ONE_KEY, "queue", "Hand out the next job of a queue, to be acknowledged before a visibility timeout. Can block."),
+    spec("qack", -3, SHRINK, ONE_KEY, "queue", "Acknowledge handed out jobs of a queue, so they aren't handed out again"),
+    spec("qclaim", 4, SHRINK, ONE_KEY, "queue", "Hand out a job of a queue until a unix time in ms. QPOPs are logged as this."),
+    // Pub/Sub
+    spec("publish", 3, &["pubsub", "loading", "stale", "fast", "may_replicate"], NO_KEYS, "pubsub", "Post a message to a channel"),
+    spec("subscribe", -2, PUBSUB, NO_KEYS, "pubsub", "Listen for messages published to the given channels"),
+    spec("psubscribe", -2, PUBSUB, NO_KEYS, "pubsub", "Listen for messages published to channels matching the given patterns"),
+    spec("unsubscribe", -1, PUBSUB, NO_KEYS, "pubsub", "Stop listening for messages posted to the given channels"),
+    spec("punsubscribe", -1, PUBSUB, NO_KEYS, "pubsub", "Stop listening for messages posted to channels matching the given patterns"),
+    // Scripting
+    spec("script", -2, &["noscript"], NO_KEYS, "scripting", "Run an x7 program, or manage the script cache"),
+    spec("eval", -3, &["noscript", "movablekeys"], NO_KEYS, "scripting", "Execute an x7 script server side"),
+    spec("evalsha", -3, &["noscript", "movablekeys"], NO_KEYS, "scripting", "Execute a cached x7 script server side by its SHA1"),
+    spec("function", -2, &["noscript"], NO_KEYS, "scripting", "List, delete or dump functions defined with def-redis-fn"),
+    // Connection
+    spec("ping", -1, &["stale", "fast"], NO_KEYS, "connection", "Ping the server"),
+    spec("echo", 2, &["fast"], NO_KEYS, "connection", "Echo the given string"),
+    spec("select", 2, &["loading", "stale", "fast"], NO_KEYS, "connection", "Change the selected database for the current connection"),
+    spec("hello", -1, CONNECTION, NO_KEYS, "connection", "Handshake with Redis"),
+    spec("quit", -1, CONNECTION, NO_KEYS, "connection", "Close the connection"),
+    spec("auth", -2, &["noscript", "loading", "stale", "fast", "no_auth"], NO_KEYS, "connection", "Authenticate to the server"),
+    spec("client", -2, ADMIN, NO_KEYS, "connection", "A container for client connection commands"),
+    // Server
+    spec("command", -1, &["random", "loading", "stale"], NO_KEYS, "server", "Get array of Redis command details"),
+    spec("config", -2, ADMIN, NO_KEYS, "server", "Get, set or save configuration parameters"),
+    spec("info", -1, &["random", "loading", "stale"], NO_KEYS, "server", "Get information and statistics about the server"),
+    spec("latency", -2, ADMIN, NO_KEYS, "server", "Latency monitor subcommands"),
+    spec("slowlog", -2, ADMIN, NO_KEYS, "server", "Manage the Redis slow queries log"),
+    spec("debug", -2, ADMIN, NO_KEYS, "server", "A container for debugging commands"),
+    spec("memory", -2, READ, (2, 2, 1), "server", "Estimate memory usage"),
+    spec("save", 1, ADMIN, NO_KEYS, "server", "Synchronously save the dataset to disk"),
+    spec("bgsave", 1, ADMIN, NO_KEYS, "server", "Asynchronously save the dataset to disk"),
+    spec("shutdown", -1, ADMIN, NO_KEYS, "server", "Synchronously save the dataset to disk and then shut down the server"),
+    spec("lastsave", 1, &["random", "loading", "stale", "fast"], NO_KEYS, "server", "Get the UNIX time stamp of the last successful save to disk"),
+    spec("bgrewriteaof", 1, ADMIN, NO_KEYS, "server", "Asynchronously rewrite the append-only file"),
+    spec("dbsize", 1, READ_FAST, NO_KEYS, "server", "Return the number of keys in the selected database"),
+    spec("swapdb", 3, &["write", "fast"], NO_KEYS, "server", "Swaps two Redis databases"),
+    spec("flushall", -1, &["write"], NO_KEYS, "server", "Remove all keys from all databases"),
+    spec("flushdb", -1, &["write"], NO_KEYS, "server", "Remove all keys from the current database"),
+    spec("replicaof", 3, ADMIN, NO_KEYS, "server", "Make the server a replica of another instance, or promote it as master"),
+    spec("slaveof", 3, ADMIN, NO_KEYS, "server", "Make the server a replica of another instance, or promote it as master"),
+    spec("replconf", -1, ADMIN, NO_KEYS, "server", "An internal command for configuring the replication stream"),
+    spec("psync", -3, ADMIN, NO_KEYS, "server", "Internal command used for replication"),
+    spec("sync", 1, ADMIN, NO_KEYS, "server", "Internal command used for replication"),
+    spec("monitor", 1, ADMIN, NO_KEYS, "server", "Listen for all requests received by the server in real time"),
+    spec("role", 1, CONNECTION, NO_KEYS, "server", "Return the role of the instance in the context of replication"),
+    spec("wait", 3, &["noscript", "blocking"], NO_KEYS, "generic", "Wait for the synchronous replication of all the write commands sent in the context of the current connection"),
+    spec("waitaof", 4, &["noscript", "blocking"], NO_KEYS, "generic", "Wait for all write commands sent by the client to be fsynced to the AOF (or dump file) locally and/or on replicas"),
+    // Ours
+    spec("oxide", 2, ADMIN, NO_KEYS, "oxide", "Report which redis commands are supported"),
+    spec("tree", -1, READ, NO_KEYS, "oxide", "Show the keyspace as a tree"),
+    spec("printcmds", 1, ADMIN, NO_KEYS, "oxide", "Log the commands this server accepts"),
+];
+
+/// The spec of command `name`, in any case.
+pub fn command_spec(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
+/// The key arguments of `command` (a name and its arguments), as its spec
+/// finds them.
+pub fn command_keys(command: &[RedisValueRef]) -> Vec<Value> {
+    let spec = match command.first() {
+        Some(RedisValueRef::BulkString(name)) => command_spec(name),
+        _ => None,
+    };
+    let spec = match spec {
+        Some(spec) if spec.first_key > 0 => spec,
+        _ => return Vec::new(),
+    };
+    let last_key = match spec.last_key {
+        last if last < 0 => command.len() as i64 + last,
+        last => last,
+    };
+    (spec.first_key..=last_key)
+        .step_by(spec.step.max(1) as usize)
+        .filter_map(|i| match command.get(i as usize) {
+            Some(RedisValueRef::BulkString(key)) => Some(key.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn info_entry(spec: &CommandSpec) -> ReturnValue {
+    let flags = spec
+        .flags
+        .iter()
+        .map(|flag| ReturnValue::Ident(RedisValueRef::SimpleString((*flag).into())))
+        .collect();
+    ReturnValue::Array(vec![
+        ReturnValue::StringRes(spec.name.into()),
+        ReturnValue::IntRes(spec.arity),
+        ReturnValue::Array(flags),
+        ReturnValue::IntRes(spec.first_key),
+        ReturnValue::IntRes(spec.last_key),
+        ReturnValue::IntRes(spec.step),
+    ])
+}
+
+/// COMMAND: details of every command.
+pub fn command_reply() -> ReturnValue {
+    ReturnValue::Array(COMMANDS.iter().map(info_entry).collect())
+}
+
+/// COMMAND INFO: details of each named command, or nil for unknown ones.
+pub fn command_info_reply(names: &[Value]) -> ReturnValue {
+    let entries = names
+        .iter()
+        .map(|name| command_spec(name).map_or(ReturnValue::Nil, info_entry))
+        .collect();
+    ReturnValue::Array(entries)
+}
+
+/// COMMAND DOCS: name and docs of each named command, or every one if none
+/// are. Unknown names are left out.
+pub fn command_docs_reply(names: &[Value]) -> ReturnValue {
+    let specs: Vec<&CommandSpec> = if names.is_empty() {
+        COMMANDS.iter().collect()
+    } else {
+        names.iter().filter_map(|name| command_spec(name)).collect()
+    };
+    let mut reply = Vec::with_capacity(specs.len() * 2);
+    for spec in specs {
+        reply.push(ReturnValue::StringRes(spec.name.into()));
+        reply.push(ReturnValue::MultiStringRes(vec![
+            "summary".into(),
+            spec.summary.into(),
+            "group".into(),
+            spec.group.into(),
+        ]));
+    }
+    ReturnValue::Array(reply)
+}
+
+#[cfg(test)]
+mod test_command_table {
+    use super::{command_info_reply, command_keys, command_spec, COMMANDS};
+    use crate::compat::{compat_report, CompatStatus};
+    use crate::ops::{translate, OpsError};
+    use crate::test_util::command_args;
+    use crate::types::{RedisValueRef, ReturnValue, StateStore};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_table_matches_translate() {
+        let store = Arc::new(StateStore::default());
+        for spec in COMMANDS {
+            let cmd = RedisValueRef::BulkString(spec.name.into());
+            let known = !matches!(translate(cmd, store.clone()), Err(OpsError::UnknownOp));
+            assert!(known, "{} isn't accepted", spec.name);
+        }
+        for (command, status) in compat_report(store) {
+            if status != CompatStatus::Missing {
+                assert!(
+                    command_spec(command.as_bytes()).is_some(),
+                    "{} has no spec",
+                    command
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_command_keys() {
+        let keys = |args: &[&'static str]| -> Vec<Bytes> {
+            args.iter()
+                .map(|arg| Bytes::from_static(arg.as_bytes()))
+                .collect()
+        };
+        assert_eq!(command_keys(&command_args(&["GET", "a"])), keys(&["a"]));
+        let mset = command_args(&["mset", "a", "1", "b", "2"]);
+        assert_eq!(command_keys(&mset), keys(&["a", "b"]));
+        assert_eq!(
+            command_keys(&command_args(&["DEL", "a", "b"])),
+            keys(&["a", "b"])
+        );
+        assert!(command_keys(&command_args(&["PING"])).is_empty());
+        assert!(command_keys(&command_args(&["nope", "a"])).is_empty());
+    }
+
+    #[test]
+    fn test_command_info() {
+        let names = vec![Bytes::from_static(b"MSET"), Bytes::from_static(b"nope")];
+        let reply = command_info_reply(&names);
+        let entries = match reply {
+            ReturnValue::Array(entries) => entries,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(entries[1], ReturnValue::Nil);
+        match &entries[0] {
+            ReturnValue::Array(info) => {
+                assert_eq!(info[0], ReturnValue::StringRes("mset".into()));
+                assert_eq!(info[1], ReturnValue::IntRes(-3));
+                assert_eq!(info[3..], [1, -1, 2].map(ReturnValue::IntRes));
+            }
+            other => panic!("unexpected entry {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Redis compatibility reporting.
+//!
+//! `translate` is the source of truth for which commands exist; this module
+//! only knows the list of redis commands and which of ours are incomplete.
+
+use crate::ops::{translate, OpsError};
+use crate::types::{RedisValueRef, ReturnValue, StateStoreRef};
+
+/// Redis commands (as of 6.2) users may expect to work.
+#[rustfmt::skip]
+const REDIS_COMMANDS: &[&str] = &[
+    // Keys and strings
+    "append", "bitcount", "bitfield", "bitop", "bitpos", "copy", "decr", "decrby", "del", "dump",
+    "exists", "expire", "expireat", "get", "getbit", "getdel", "getex", "getrange", "getset",
+    "incr", "incrby", "incrbyfloat", "keys", "mget", "migrate", "move", "mset", "msetnx",
+    "object", "persist", "pexpire", "pexpireat", "psetex", "pttl", "randomkey", "rename",
+    "renamenx", "restore", "scan", "set", "setbit", "setex", "setnx", "setrange", "sort",
+    "strlen", "touch", "ttl", "type", "unlink", "wait",
+    // Lists
+    "blmove", "blpop", "brpop", "brpoplpush", "lindex", "linsert", "llen", "lmove", "lpop",
+    "lpos", "lpush", "lpushx", "lrange", "lrem", "lset", "ltrim", "rpop", "rpoplpush", "rpush",
+    "rpushx",
+    // Hashes
+    "hdel", "hexists", "hget", "hgetall", "hincrby", "hincrbyfloat", "hkeys", "hlen", "hmget",
+    "hmset", "hrandfield", "hscan", "hset", "hsetnx", "hstrlen", "hvals",
+    // Sets
+    "sadd", "scard", "sdiff", "sdiffstore", "sinter", "sinterstore", "sismember", "smembers",
+    "smismember", "smove", "spop", "srandmember", "srem", "sscan", "sunion", "sunionstore",
+    // Sorted sets
+    "bzpopmax", "bzpopmin", "zadd", "zcard", "zcount", "zdiff", "zdiffstore", "zincrby",
+    "zinter", "zinterstore", "zlexcount", "zmscore", "zpopmax", "zpopmin", "zrandmember",
+    "zrange", "zrangebylex", "zrangebyscore", "zrangestore", "zrank", "zrem", "zremrangebylex",
+    "zremrangebyrank", "zremrangebyscore", "zrevrange", "zrevrangebylex", "zrevrangebyscore",
+    "zrevrank", "zscan", "zscore", "zunion", "zunionstore",
+    // HyperLogLog
+    "pfadd", "pfcount", "pfmerge",
+    // Geo
+    "geoadd", "geodist", "geohash", "geopos", "georadius", "georadiusbymember", "geosearch",
+    "geosearchstore",
+    // Streams
+    "xack", "xadd", "xautoclaim", "xclaim", "xdel", "xgroup", "xinfo", "xlen", "xpending",
+    "xrange", "xread", "xreadgroup", "xrevrange", "xtrim",
+    // Pub/Sub
+    "psubscribe", "publish", "pubsub", "punsubscribe", "subscribe", "unsubscribe",
+    // Transactions
+    "discard", "exec", "multi", "unwatch", "watch",
+    // Scripting
+    "eval", "evalsha", "function", "script",
+    // Connection
+    "auth", "client", "echo", "hello", "ping", "quit", "reset", "select",
+    // Server
+    "bgrewriteaof", "bgsave", "command", "config", "dbsize", "debug", "flushall", "flushdb",
+    "info", "lastsave", "latency", "lolwut", "memory", "monitor", "psync", "replicaof", "role",
+    "save", "shutdown", "slaveof", "slowlog", "swapdb", "sync", "time",
+];
+
+/// Commands we accept, but which are missing options or behave differently.
+/// Keep this up to date when filling in a command!
+const PARTIAL_COMMANDS: &[(&str, &str)] = &[
+    ("info", "no cpu or commandstats sections"),
+    (
+        "psync",
+        "only redis-oxide replicas, which are sent a snapshot in the redis-oxide dump format",
+    ),
+    (
+        "sync",
+        "only redis-oxide replicas, which are sent a snapshot in the redis-oxide dump format",
+    ),
+    (
+        "wait",
+        "replicas ack writes once applied in memory, not once synced to disk",
+    ),
+    (
+        "blpop",
+        "integer timeouts only, and 0 doesn't block forever",
+    ),
+    (
+        "brpop",
+        "integer timeouts only, and 0 doesn't block forever",
+    ),
+    (
+        "blmove",
+        "integer timeouts only, and 0 doesn't block forever",
+    ),
+    (
+        "brpoplpush",
+        "integer timeouts only, and 0 doesn't block forever",
+    ),
+    ("hset", "only a single field"),
+    ("xadd", "no MINID or LIMIT, and MAXLEN ~ trims exactly"),
+    ("xgroup", "only CREATE, SETID and DESTROY"),
+    ("xclaim", "no LASTID"),
+    ("restore", "no IDLETIME or FREQ"),
+    ("sort", "lists and sets only, not sorted sets"),
+    ("zrange", "no BYSCORE/BYLEX/REV/LIMIT options"),
+    (
+        "script",
+        "runs x7 programs, only the LOAD, EXISTS, KILL and FLUSH subcommands",
+    ),
+    ("eval", "runs x7 programs, not Lua"),
+    ("evalsha", "runs x7 programs, not Lua"),
+    (
+        "function",
+        "only LIST, DELETE and DUMP, of functions defined with def-redis-fn",
+    ),
+    (
+        "object",
+        "only REFCOUNT and ENCODING, encodings are approximate",
+    ),
+    ("latency", "no GRAPH or HISTOGRAM"),
+    (
+        "command",
+        "no GETKEYS or LIST, and DOCS only has the summary and group",
+    ),
+    ("config", "only GET, SET and REWRITE"),
+    ("memory", "only USAGE and STATS, which estimate"),
+    (
+        "debug",
+        "only SLEEP, OBJECT, SET-ACTIVE-EXPIRE, JMAP and QUICKACK",
+    ),
+    ("bgsave", "no SCHEDULE"),
+    ("shutdown", "only NOSAVE and SAVE"),
+    (
+        "hello",
+        "no AUTH/SETNAME, RESP3 only changes pub/sub replies to push frames",
+    ),
+    ("auth", "only the default user"),
+    (
+        "client",
+        "only LIST, ID, GETNAME, SETNAME and KILL (by ID or ADDR)",
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompatStatus {
+    Implemented,
+    Partial(&'static str),
+    Missing,
+}
+
+impl CompatStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompatStatus::Implemented => "implemented",
+            CompatStatus::Partial(_) => "partial",
+            CompatStatus::Missing => "missing",
+        }
+    }
+}
+
+/// Whether the parser knows about the given command.
+fn is_known_command(command: &'static str, state_store: StateStoreRef) -> bool {
+    let cmd = RedisValueRef::BulkString(command.into());
+    !matches!(translate(cmd, state_store), Err(OpsError::UnknownOp))
+}
+
+/// The support status of every redis command.
+pub fn compat_report(state_store: StateStoreRef) -> Vec<(&'static str, CompatStatus)> {
+    REDIS_COMMANDS
+        .iter()
+        .map(|&command| {
+            let status = if !is_known_command(command, state_store.clone()) {
+                CompatStatus::Missing
+            } else {
+                PARTIAL_COMMANDS
+                    .iter()
+                    .find(|(partial, _)| *partial == command)
+                    .map_or(CompatStatus::Implemented, |(_, reason)| {
+                        CompatStatus::Partial(reason)
+                    })
+            };
+            (command, status)
+        })
+        .collect()
+}
+
+/// Count of (implemented, partial, missing) commands.
+pub fn compat_summary(state_store: StateStoreRef) -> (usize, usize, usize) {
+    compat_report(state_store)
+        .iter()
+        .fold((0, 0, 0), |(imp, part, miss), (_, status)| match status {
+            CompatStatus::Implemented => (imp + 1, part, miss),
+            CompatStatus::Partial(_) => (imp, part + 1, miss),
+            CompatStatus::Missing => (imp, part, miss + 1),
+        })
+}
+
+/// Reply for OXIDE COMPAT: one [command, status, note] triple per redis command.
+pub fn compat_reply(state_store: StateStoreRef) -> ReturnValue {
+    let rows = compat_report(state_store)
+        .into_iter()
+        .map(|(command, status)| {
+            let note = match status {
+                CompatStatus::Partial(reason) => reason,
+                _ => "",
+            };
+            ReturnValue::MultiStringRes(vec![command.into(), status.name().into(), note.into()])
+        })
+        .collect();
+    ReturnValue::Array(rows)
+}
+
+#[cfg(test)]
+mod test_compat {
+    use crate::compat::{compat_report, CompatStatus, PARTIAL_COMMANDS, REDIS_COMMANDS};
+    use crate::types::StateStore;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_compat_report() {
+        let report = compat_report(Arc::new(StateStore::default()));
+        assert_eq!(report.len(), REDIS_COMMANDS.len());
+        let status = |name| report.iter().find(|(c, _)| *c == name).unwrap().1;
+        assert_eq!(status("get"), CompatStatus::Implemented);
+        assert_eq!(status("lolwut"), CompatStatus::Missing);
+        assert!(matches!(status("zrange"), CompatStatus::Partial(_)));
+    }
+
+    #[test]
+    fn test_partial_commands_are_implemented() {
+        let report = compat_report(Arc::new(StateStore::default()));
+        for (command, _) in PARTIAL_COMMANDS {
+            let (_, status) = report.iter().find(|(c, _)| c == command).unwrap();
+            assert_ne!(*status, CompatStatus::Missing, "{} is missing", command);
+        }
+    }
+}
@@ -0,0 +1,656 @@
+//! CONFIG GET/SET/REWRITE: settings that can be read and changed while running.
+//!
+//! `RuntimeConfig` starts out from the command line (`startup::Config`), then
+//! `--config-file` is applied on top, like a series of CONFIG SETs. CONFIG
+//! REWRITE saves the current values of every mutable parameter back to it.
+
+use crate::asyncresp::RespLimits;
+use crate::eviction::EvictionPolicy;
+use crate::glob::glob_match;
+use crate::keyspace_events::{self, format_flags, parse_flags};
+use crate::startup::Config;
+use crate::types::StateStore;
+use parking_lot::{Mutex, RwLock};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+pub struct RuntimeConfig {
+    pub port: u64,
+    pub bind: Vec<IpAddr>,
+    pub dir: String,
+    /// Where CONFIG REWRITE saves to.
+    pub config_file: Option<PathBuf>,
+    ops_until_save: AtomicU64,
+    max_reply_bytes: AtomicU64,
+    big_reply_bytes: AtomicU64,
+    maxmemory: AtomicU64,
+    maxmemory_policy: RwLock<EvictionPolicy>,
+    maxmemory_samples: AtomicUsize,
+    negative_cache_size: AtomicUsize,
+    notify_keyspace_events: AtomicU32,
+    requirepass: RwLock<Option<String>>,
+    masterauth: RwLock<Option<String>>,
+    protected_mode: AtomicBool,
+    proto_max_bulk_len: AtomicU64,
+    proto_max_array_len: AtomicU64,
+    atomic_scripts: AtomicBool,
+    script_time_limit: AtomicU64,
+    script_max_memory: AtomicU64,
+    script_max_table_elements: AtomicU32,
+    /// Held by CONFIG REWRITE, so two don't share a temp file.
+    rewriting: Mutex<()>,
+}
+
+impl RuntimeConfig {
+    pub fn new(config: &Config) -> Self {
+        RuntimeConfig {
+            port: config.port,
+            bind: config.bind.clone(),
+            dir: config
+                .data_dir
+                .as_ref()
+                .map_or_else(String::new, |dir| dir.to_string_lossy().into_owned()),
+            config_file: config.config_file.clone(),
+            ops_until_save: AtomicU64::new(config.ops_until_save),
+            max_reply_bytes: AtomicU64::new(config.max_reply_bytes),
+            big_reply_bytes: AtomicU64::new(config.big_reply_bytes),
+            maxmemory: AtomicU64::new(config.maxmemory),
+            // Already checked by structopt.
+            maxmemory_policy: RwLock::new(
+                EvictionPolicy::parse(&config.maxmemory_policy).unwrap_or_default(),
+            ),
+            maxmemory_samples: AtomicUsize::new(config.maxmemory_samples),
+            negative_cache_size: AtomicUsize::new(config.negative_cache_size),
+            notify_keyspace_events: AtomicU32::new(0),
+            requirepass: RwLock::new(config.requirepass.clone().filter(|pass| !pass.is_empty())),
+            masterauth: RwLock::new(config.masterauth.clone().filter(|pass| !pass.is_empty())),
+            protected_mode: AtomicBool::new(config.protected_mode == "yes"),
+            proto_max_bulk_len: AtomicU64::new(config.proto_max_bulk_len),
+            proto_max_array_len: AtomicU64::new(config.proto_max_array_len),
+            atomic_scripts: AtomicBool::new(config.atomic_scripts),
+            script_time_limit: AtomicU64::new(config.script_time_limit),
+            script_max_memory: AtomicU64::new(config.script_max_memory),
+            script_max_table_elements: AtomicU32::new(config.script_max_table_elements),
+            rewriting: Mutex::new(()),
+        }
+    }
+
+    /// Save the dump file after this many commands.
+    pub fn ops_until_save(&self) -> u64 {
+        self.ops_until_save.load(Ordering::Relaxed)
+    }
+
+    /// Replies bigger than this are refused. 0 disables the limit.
+    pub fn max_reply_bytes(&self) -> u64 {
+        self.max_reply_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Replies at least this big are logged. 0 disables the warning.
+    pub fn big_reply_bytes(&self) -> u64 {
+        self.big_reply_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes the dbs may use before keys are evicted. 0 disables the limit.
+    pub fn maxmemory(&self) -> u64 {
+        self.maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub fn maxmemory_policy(&self) -> EvictionPolicy {
+        *self.maxmemory_policy.read()
+    }
+
+    /// Keys of each db sampled to pick one to evict, at least 1.
+    pub fn maxmemory_samples(&self) -> usize {
+        self.maxmemory_samples.load(Ordering::Relaxed).max(1)
+    }
+
+    /// Misses remembered per db. 0 disables the negative cache.
+    pub fn negative_cache_size(&self) -> usize {
+        self.negative_cache_size.load(Ordering::Relaxed)
+    }
+
+    /// Keyspace notification flags, see `keyspace_events`.
+    pub fn notify_keyspace_events(&self) -> u32 {
+        self.notify_keyspace_events.load(Ordering::Relaxed)
+    }
+
+    /// The password clients must AUTH with, if any.
+    pub fn requirepass(&self) -> Option<String> {
+        self.requirepass.read().clone()
+    }
+
+    /// The password replicas AUTH to their primary with, if any.
+    pub fn masterauth(&self) -> Option<String> {
+        self.masterauth.read().clone()
+    }
+
+    /// Refuse clients from other hosts while there's no password.
+    pub fn protected_mode(&self) -> bool {
+        self.protected_mode.load(Ordering::Relaxed)
+    }
+
+    /// Run each script alone, with no other client's commands in between.
+    pub fn atomic_scripts(&self) -> bool {
+        self.atomic_scripts.load(Ordering::Relaxed)
+    }
+
+    /// Milliseconds an atomic script may run before other clients get BUSY
+    /// errors instead of waiting for it. 0 waits forever.
+    pub fn script_time_limit(&self) -> u64 {
+        self.script_time_limit.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of memory each WASM program may grow to. 0 disables the limit.
+    pub fn script_max_memory(&self) -> u64 {
+        self.script_max_memory.load(Ordering::Relaxed)
+    }
+
+    /// Elements each table of a WASM program may grow to. 0 disables the limit.
+    pub fn script_max_table_elements(&self) -> u32 {
+        self.script_max_table_elements.load(Ordering::Relaxed)
+    }
+
+    /// What new client connections may send. Connections keep the limits
+    /// they started with.
+    pub fn resp_limits(&self) -> RespLimits {
+        // 0 disables a limit.
+        let limit = |len: &AtomicU64| match len.load(Ordering::Relaxed) {
+            0 => u64::MAX,
+            len => len,
+        };
+        RespLimits {
+            max_bulk_len: limit(&self.proto_max_bulk_len),
+            max_array_len: limit(&self.proto_max_array_len),
+            ..RespLimits::default()
+        }
+    }
+}
+
+type Getter = fn(&StateStore) -> String;
+type Setter = fn(&StateStore, &str) -> Result<(), String>;
+
+struct Param {
+    name: &'static str,
+    get: Getter,
+    /// None for parameters fixed at startup.
+    set: Option<Setter>,
+}
+
+fn parse_u64(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| "argument couldn't be parsed into an integer".to_string())
+}
+
+fn parse_i64(value: &str) -> Result<i64, String> {
+    value
+        .parse()
+        .map_err(|_| "argument couldn't be parsed into an integer".to_string())
+}
+
+fn yes_no(b: bool) -> String {
+    if b { "yes" } else { "no" }.into()
+}
+
+fn parse_yes_no(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+const PARAMS: &[Param] = &[
+    Param {
+        name: "port",
+        get: |store| store.config.port.to_string(),
+        set: None,
+    },
+    Param {
+        name: "bind",
+        get: |store| {
+            let addrs: Vec<String> = store.config.bind.iter().map(|ip| ip.to_string()).collect();
+            addrs.join(" ")
+        },
+        set: None,
+    },
+    Param {
+        name: "dir",
+        get: |store| store.config.dir.clone(),
+        set: None,
+    },
+    Param {
+        name: "memory-only",
+        get: |store| yes_no(store.memory_only),
+        set: None,
+    },
+    Param {
+        name: "ops-until-save",
+        get: |store| store.config.ops_until_save().to_string(),
+        set: Some(|store, value| {
+            let ops = parse_u64(value)?;
+            store.config.ops_until_save.store(ops, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "max-reply-bytes",
+        get: |store| store.config.max_reply_bytes().to_string(),
+        set: Some(|store, value| {
+            let bytes = parse_u64(value)?;
+            store.config.max_reply_bytes.store(bytes, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "big-reply-bytes",
+        get: |store| store.config.big_reply_bytes().to_string(),
+        set: Some(|store, value| {
+            let bytes = parse_u64(value)?;
+            store.config.big_reply_bytes.store(bytes, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "maxmemory",
+        get: |store| store.config.maxmemory().to_string(),
+        set: Some(|store, value| {
+            let bytes = parse_u64(value)?;
+            store.config.maxmemory.store(bytes, Ordering::Relaxed);
+            // Without a limit there's no need to keep estimates up to date.
+            if bytes == 0 {
+                for db in store.states.iter() {
+                    db.memory.stop();
+                }
+            }
+            Ok(())
+        }),
+    },
+    Param {
+        name: "maxmemory-policy",
+        get: |store| store.config.maxmemory_policy().name().to_string(),
+        set: Some(|store, value| {
+            *store.config.maxmemory_policy.write() = EvictionPolicy::parse(value)?;
+            Ok(())
+        }),
+    },
+    Param {
+        name: "maxmemory-samples",
+        get: |store| store.config.maxmemory_samples().to_string(),
+        set: Some(|store, value| match parse_u64(value)? {
+            0 => Err("argument must be greater than 0".to_string()),
+            samples => {
+                let samples = samples as usize;
+                store
+                    .config
+                    .maxmemory_samples
+                    .store(samples, Ordering::Relaxed);
+                Ok(())
+            }
+        }),
+    },
+    Param {
+        name: "negative-cache-size",
+        get: |store| store.config.negative_cache_size().to_string(),
+        set: Some(|store, value| {
+            let size = parse_u64(value)? as usize;
+            store
+                .config
+                .negative_cache_size
+                .store(size, Ordering::Relaxed);
+            for db in store.states.iter() {
+                db.negative_cache.set_capacity(size);
+            }
+            Ok(())
+        }),
+    },
+    Param {
+        name: "notify-keyspace-events",
+        get: |store| format_flags(store.config.notify_keyspace_events()),
+        set: Some(|store, value| {
+            let flags = parse_flags(value)?;
+            store
+                .config
+                .notify_keyspace_events
+                .store(flags, Ordering::Relaxed);
+            for db in store.states.iter() {
+                db.writes.keep_events(keyspace_events::enabled(flags));
+            }
+            Ok(())
+        }),
+    },
+    Param {
+        name: "latency-monitor-threshold",
+        get: |store| store.latency.threshold().to_string(),
+        set: Some(|store, value| {
+            store.latency.set_threshold(parse_u64(value)?);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "requirepass",
+        get: |store| store.config.requirepass().unwrap_or_default(),
+        set: Some(|store, value| {
+            // Clients already authenticated stay so, like in redis.
+            let pass = Some(value.to_string()).filter(|pass| !pass.is_empty());
+            *store.config.requirepass.write() = pass;
+            Ok(())
+        }),
+    },
+    Param {
+        name: "masterauth",
+        get: |store| store.config.masterauth().unwrap_or_default(),
+        set: Some(|store, value| {
+            // Used from the next time we connect to the primary.
+            let pass = Some(value.to_string()).filter(|pass| !pass.is_empty());
+            *store.config.masterauth.write() = pass;
+            Ok(())
+        }),
+    },
+    Param {
+        name: "protected-mode",
+        get: |store| yes_no(store.config.protected_mode()),
+        set: Some(|store, value| {
+            let on = parse_yes_no(value)?;
+            store.config.protected_mode.store(on, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "atomic-scripts",
+        get: |store| yes_no(store.config.atomic_scripts()),
+        set: Some(|store, value| {
+            let on = parse_yes_no(value)?;
+            store.config.atomic_scripts.store(on, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "script-time-limit",
+        get: |store| store.config.script_time_limit().to_string(),
+        set: Some(|store, value| {
+            let ms = parse_u64(value)?;
+            store.config.script_time_limit.store(ms, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "script-max-memory",
+        get: |store| store.config.script_max_memory().to_string(),
+        set: Some(|store, value| {
+            let bytes = parse_u64(value)?;
+            store
+                .config
+                .script_max_memory
+                .store(bytes, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "script-max-table-elements",
+        get: |store| store.config.script_max_table_elements().to_string(),
+        set: Some(|store, value| {
+            let elements = parse_u64(value)?.min(u32::MAX as u64) as u32;
+            store
+                .config
+                .script_max_table_elements
+                .store(elements, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "proto-max-bulk-len",
+        get: |store| {
+            let len = store.config.proto_max_bulk_len.load(Ordering::Relaxed);
+            len.to_string()
+        },
+        set: Some(|store, value| {
+            let len = parse_u64(value)?;
+            store
+                .config
+                .proto_max_bulk_len
+                .store(len, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "proto-max-array-len",
+        get: |store| {
+            let len = store.config.proto_max_array_len.load(Ordering::Relaxed);
+            len.to_string()
+        },
+        set: Some(|store, value| {
+            let len = parse_u64(value)?;
+            store
+                .config
+                .proto_max_array_len
+                .store(len, Ordering::Relaxed);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "slowlog-log-slower-than",
+        get: |store| store.slowlog.threshold().to_string(),
+        set: Some(|store, value| {
+            store.slowlog.set_threshold(parse_i64(value)?);
+            Ok(())
+        }),
+    },
+    Param {
+        name: "slowlog-max-len",
+        get: |store| store.slowlog.max_len().to_string(),
+        set: Some(|store, value| {
+            store.slowlog.set_max_len(parse_u64(value)? as usize);
+            Ok(())
+        }),
+    },
+];
+
+fn param(name: &str) -> Option<&'static Param> {
+    PARAMS.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+/// (name, value) of every parameter matching one of `patterns`.
+pub fn config_get(store: &StateStore, patterns: &[String]) -> Vec<(&'static str, String)> {
+    PARAMS
+        .iter()
+        .filter(|p| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.to_lowercase().as_bytes(), p.name.as_bytes()))
+        })
+        .map(|p| (p.name, (p.get)(store)))
+        .collect()
+}
+
+/// Set every (name, value) pair, or none of them if one is refused.
+pub fn config_set(store: &StateStore, pairs: &[(String, String)]) -> Result<(), String> {
+    let mut changes = Vec::with_capacity(pairs.len());
+    for (name, value) in pairs {
+        let p = param(name).ok_or_else(|| {
+            format!(
+                "Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            )
+        })?;
+        let set = p.set.ok_or_else(|| {
+            format!(
+                "CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                p.name
+            )
+        })?;
+        // Bad values only show up when setting, so remember what to roll back to.
+        changes.push((p.name, set, value, (p.get)(store)));
+    }
+    for (i, (name, set, value, _)) in changes.iter().enumerate() {
+        if let Err(e) = set(store, value) {
+            for (_, set, _, previous) in changes[..i].iter() {
+                let _ = set(store, previous);
+            }
+            return Err(format!(
+                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                name, e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The config file contents for the current values of every mutable parameter.
+fn rewritten(store: &StateStore) -> String {
+    PARAMS
+        .iter()
+        .filter(|p| p.set.is_some())
+        .map(|p| match (p.get)(store) {
+            // Like redis, so every line still has a value.
+            value if value.is_empty() => format!("{} \"\"\n", p.name),
+            value => format!("{} {}\n", p.name, value),
+        })
+        .collect()
+}
+
+/// Write `contents` next to `path` and rename it over, so a crash mid-write
+/// leaves the old file whole rather than a truncated one.
+fn replace_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    let written = std::fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    });
+    match written.and_then(|_| std::fs::rename(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Save the mutable parameters to the config file.
+pub fn config_rewrite(store: &StateStore) -> Result<(), String> {
+    let path = store
+        .config
+        .config_file
+        .as_ref()
+        .ok_or_else(|| "The server is running without a config file".to_string())?;
+    let _rewriting = store.config.rewriting.lock();
+    replace_file(path, &rewritten(store)).map_err(|e| format!("Rewriting config file: {}", e))
+}
+
+/// Apply `name value` lines, skipping blanks and `#` comments.
+pub fn apply_config(store: &StateStore, contents: &str) -> Result<(), String> {
+    let mut pairs = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((name, value)) => {
+                let value = match value.trim() {
+                    "\"\"" => "",
+                    value => value,
+                };
+                pairs.push((name.to_string(), value.to_string()))
+            }
+            None => return Err(format!("line {}: expected a name and a value", number + 1)),
+        }
+    }
+    config_set(store, &pairs)
+}
+
+/// Apply the config file, if there is one. A missing file is left for
+/// CONFIG REWRITE to create.
+pub fn load_config_file(store: &StateStore) -> Result<(), String> {
+    let path = match &store.config.config_file {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    match std::fs::read_to_string(path) {
+        Ok(contents) => apply_config(store, &contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("reading {}: {}", path.to_string_lossy(), e)),
+    }
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::{apply_config, config_get, config_set, replace_file, rewritten};
+    use crate::types::StateStore;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_get_and_set() {
+        let store = StateStore::default();
+        assert!(config_set(&store, &pairs(&[("MAXMEMORY", "100")])).is_ok());
+        assert_eq!(store.config.maxmemory(), 100);
+        let got = config_get(&store, &["max*".to_string()]);
+        assert_eq!(
+            got,
+            vec![
+                ("max-reply-bytes", "0".to_string()),
+                ("maxmemory", "100".to_string()),
+                ("maxmemory-policy", "noeviction".to_string()),
+                ("maxmemory-samples", "1".to_string())
+            ]
+        );
+        // Nothing is set if any pair is refused.
+        let refused = pairs(&[("maxmemory", "5"), ("port", "1")]);
+        assert!(config_set(&store, &refused).is_err());
+        let refused = pairs(&[("maxmemory", "5"), ("ops-until-save", "x")]);
+        assert!(config_set(&store, &refused).is_err());
+        assert_eq!(store.config.maxmemory(), 100);
+        assert!(config_set(&store, &pairs(&[("nope", "1")])).is_err());
+    }
+
+    #[test]
+    fn test_resp_limits() {
+        let store = StateStore::default();
+        assert_eq!(store.config.resp_limits().max_bulk_len, u64::MAX);
+        config_set(&store, &pairs(&[("proto-max-bulk-len", "10")])).unwrap();
+        config_set(&store, &pairs(&[("proto-max-array-len", "3")])).unwrap();
+        let limits = store.config.resp_limits();
+        assert_eq!((limits.max_bulk_len, limits.max_array_len), (10, 3));
+    }
+
+    #[test]
+    fn test_rewrite_round_trip() {
+        let store = StateStore::default();
+        config_set(&store, &pairs(&[("latency-monitor-threshold", "7")])).unwrap();
+        let contents = rewritten(&store);
+        assert!(contents.contains("latency-monitor-threshold 7\n"));
+        assert!(!contents.contains("port"));
+        assert!(contents.contains("notify-keyspace-events \"\"\n"));
+
+        let fresh = StateStore::default();
+        apply_config(&fresh, &format!("# saved\n\n{}", contents)).unwrap();
+        assert_eq!(fresh.latency.threshold(), 7);
+        assert!(apply_config(&fresh, "maxmemory").is_err());
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let dir =
+            std::env::temp_dir().join(format!("redis-oxide-test-config-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("redis.conf");
+        std::fs::write(&path, "maxmemory 1\n").unwrap();
+        replace_file(&path, "maxmemory 2\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "maxmemory 2\n");
+        // Only the config file is left behind.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
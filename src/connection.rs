@@ -0,0 +1,496 @@
+//! Per-connection state: the RESP version picked with HELLO, whether the
+//! client has authenticated, pub/sub subscriptions, whether the client is
+//! monitoring, and its entry in the client registry (for the CLIENT commands).
+//!
+//! With `requirepass` set, a connection may only AUTH, QUIT or HELLO until
+//! it has authenticated, and HELLO only to authenticate with its AUTH option.
+//!
+//! A RESP2 connection subscribed to anything may only run the commands in
+//! `SUBSCRIBED_COMMANDS`, as its replies would be indistinguishable from
+//! messages. RESP3 clients get messages as push frames, so they may run
+//! anything.
+//!
+//! Monitoring clients are sent every command processed, as simple strings,
+//! between the replies to their own commands.
+
+use crate::clients::Client;
+use crate::hashing::sha1;
+use crate::misc::MiscOps;
+use crate::ops::{translate, Ops};
+use crate::pubsub::Subscriber;
+use crate::types::{bulk, RedisValueRef, StateStoreRef, Value};
+use std::future::pending;
+use std::net::SocketAddr;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// What a RESP2 connection can run while subscribed.
+const SUBSCRIBED_COMMANDS: &[&str] = &[
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "ping",
+    "quit",
+    "reset",
+];
+
+/// What a connection that hasn't authenticated yet can run.
+const UNAUTHENTICATED_COMMANDS: &[&str] = &["auth", "hello", "quit"];
+
+pub struct Connection {
+    protocol: Protocol,
+    authenticated: bool,
+    subscriber: Subscriber,
+    client: Client,
+    state_store: StateStoreRef,
+    closing: bool,
+    /// Set once the client asked to MONITOR.
+    monitor: Option<Receiver<Value>>,
+}
+
+/// The lowercased name and arguments of `redis_value`, if it's a command.
+fn command_parts(redis_value: &RedisValueRef) -> Option<(String, &[RedisValueRef])> {
+    match redis_value {
+        RedisValueRef::Array(array) => match array.split_first() {
+            Some((RedisValueRef::BulkString(name), args)) => {
+                Some((String::from_utf8_lossy(name).to_lowercase(), args))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+const HELLO_NOAUTH: &[u8] = b"NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time";
+
+const NO_PASSWORD: &[u8] = b"ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?";
+
+/// Compare digests rather than the passwords themselves, so how long the
+/// comparison takes says nothing about the password.
+pub(crate) fn same_password(given: &[u8], expected: &[u8]) -> bool {
+    sha1(given)
+        .iter()
+        .zip(sha1(expected).iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Wait for the next monitor line, or forever if we aren't monitoring.
+async fn next_line(monitor: &mut Option<Receiver<Value>>) -> Value {
+    let monitor = match monitor {
+        Some(monitor) => monitor,
+        None => return pending().await,
+    };
+    loop {
+        match monitor.recv().await {
+            Ok(line) => return line,
+            // Too slow to keep up: skip what we missed.
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return pending().await,
+        }
+    }
+}
+
+impl Connection {
+    pub fn new(state_store: StateStoreRef, addr: SocketAddr) -> Self {
+        Connection {
+            protocol: Protocol::Resp2,
+            authenticated: state_store.config.requirepass().is_none(),
+            subscriber: Subscriber::new(state_store.clone()),
+            client: Client::new(state_store.clone(), addr),
+            state_store,
+            closing: false,
+            monitor: None,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Whether the client asked to QUIT. The connection should be closed
+    /// once the replies to the current command are flushed.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Turn pub/sub replies and messages into push frames for RESP3 clients.
+    fn frame(&self, reply: RedisValueRef) -> RedisValueRef {
+        match (self.protocol, reply) {
+            (Protocol::Resp3, RedisValueRef::Array(array)) => RedisValueRef::Push(array),
+            (_, reply) => reply,
+        }
+    }
+
+    /// HELLO [protover [AUTH username password]]: authenticate if asked to,
+    /// switch protocols, and describe the server. Nothing changes if the
+    /// password is wrong.
+    fn hello(&mut self, redis_value: &RedisValueRef) -> RedisValueRef {
+        let (version, auth) = match translate(redis_value.clone(), self.state_store.clone()) {
+            Ok(Ops::Misc(MiscOps::Hello(version, auth))) => (version, auth),
+            Ok(_) => unreachable!("HELLO translates to MiscOps::Hello"),
+            Err(e) => return e.into(),
+        };
+        if let Some((user, password)) = auth {
+            if let refused @ RedisValueRef::ErrorMsg(_) = self.authenticate(Some(user), password) {
+                return refused;
+            }
+        }
+        if !self.authenticated {
+            return RedisValueRef::ErrorMsg(HELLO_NOAUTH.to_vec());
+        }
+        match version {
+            Some(3) => self.protocol = Protocol::Resp3,
+            Some(_) => self.protocol = Protocol::Resp2,
+            None => {}
+        }
+        let proto = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        RedisValueRef::Array(vec![
+            bulk("server"),
+            bulk("redis-oxide"),
+            bulk("version"),
+            bulk(env!("CARGO_PKG_VERSION")),
+            bulk("proto"),
+            RedisValueRef::Int(proto),
+            bulk("id"),
+            RedisValueRef::Int(self.client.id() as i64),
+            bulk("mode"),
+            bulk("standalone"),
+            bulk("role"),
+            bulk("master"),
+            bulk("modules"),
+            RedisValueRef::Array(Vec::new()),
+        ])
+    }
+
+    /// AUTH [username] password.
+    fn auth(&mut self, redis_value: &RedisValueRef) -> RedisValueRef {
+        match translate(redis_value.clone(), self.state_store.clone()) {
+            Ok(Ops::Misc(MiscOps::Auth(user, password))) => self.authenticate(user, password),
+            Ok(_) => unreachable!("AUTH translates to MiscOps::Auth"),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Check the password of AUTH or HELLO's AUTH option. The only user is
+    /// "default".
+    fn authenticate(&mut self, user: Option<Value>, password: Value) -> RedisValueRef {
+        let required = self.state_store.config.requirepass();
+        let authenticated = match (&user, required) {
+            (Some(user), _) if &user[..] != b"default" => false,
+            (Some(_), None) => true,
+            (None, None) => return RedisValueRef::ErrorMsg(NO_PASSWORD.to_vec()),
+            (_, Some(required)) => same_password(&password, required.as_bytes()),
+        };
+        if !authenticated {
+            return RedisValueRef::ErrorMsg(
+                b"WRONGPASS invalid username-password pair or user is disabled.".to_vec(),
+            );
+        }
+        self.authenticated = true;
+        RedisValueRef::SimpleString("OK".into())
+    }
+
+    /// Whether the client may run commands.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// MONITOR: send the client every command processed from now on.
+    fn monitor(&mut self, redis_value: &RedisValueRef) -> RedisValueRef {
+        if let Err(e) = translate(redis_value.clone(), self.state_store.clone()) {
+            return e.into();
+        }
+        if self.monitor.is_none() {
+            self.monitor = Some(self.state_store.monitor.subscribe());
+        }
+        RedisValueRef::SimpleString("OK".into())
+    }
+
+    /// CLIENT LIST, ID, GETNAME, SETNAME and KILL.
+    fn client_command(&mut self, redis_value: &RedisValueRef) -> RedisValueRef {
+        let op = match translate(redis_value.clone(), self.state_store.clone()) {
+            Ok(Ops::Misc(op)) => op,
+            Ok(_) => unreachable!("CLIENT translates to MiscOps"),
+            Err(e) => return e.into(),
+        };
+        let clients = &self.state_store.clients;
+        match op {
+            MiscOps::ClientList() => bulk(clients.list()),
+            MiscOps::ClientId() => RedisValueRef::Int(self.client.id() as i64),
+            MiscOps::ClientGetName() => self
+                .client
+                .name()
+                .map_or(RedisValueRef::NullBulkString, RedisValueRef::BulkString),
+            MiscOps::ClientSetName(name) => {
+                self.client.set_name(name);
+                RedisValueRef::SimpleString("OK".into())
+            }
+            MiscOps::ClientKill(filter) => {
+                let killed = clients.kill(&filter, self.client.id());
+                match (filter.legacy, killed) {
+                    (false, killed) => RedisValueRef::Int(killed as i64),
+                    (true, 0) => RedisValueRef::ErrorMsg(b"ERR No such client".to_vec()),
+                    (true, _) => RedisValueRef::SimpleString("OK".into()),
+                }
+            }
+            _ => unreachable!("CLIENT translates to the client ops"),
+        }
+    }
+
+    /// Run `redis_value` if it acts on the connection itself (AUTH, HELLO,
+    /// QUIT, MONITOR, CLIENT and the SUBSCRIBE family), or refuse it if it
+    /// isn't allowed right now.
+    /// Returns None for commands to run as usual.
+    pub fn run_command(&mut self, redis_value: &RedisValueRef) -> Option<Vec<RedisValueRef>> {
+        let (name, args) = command_parts(redis_value)?;
+        if !self.authenticated && !UNAUTHENTICATED_COMMANDS.contains(&name.as_str()) {
+            let err = b"NOAUTH Authentication required.".to_vec();
+            return Some(vec![RedisValueRef::ErrorMsg(err)]);
+        }
+        match name.as_str() {
+            "auth" => return Some(vec![self.auth(redis_value)]),
+            "hello" => return Some(vec![self.hello(redis_value)]),
+            "monitor" => return Some(vec![self.monitor(redis_value)]),
+            "client" => return Some(vec![self.client_command(redis_value)]),
+            "quit" => {
+                self.closing = true;
+                return Some(vec![RedisValueRef::SimpleString("OK".into())]);
+            }
+            _ => {}
+        }
+        if let Some(replies) = self.subscriber.run_command(redis_value) {
+            return Some(replies.into_iter().map(|r| self.frame(r)).collect());
+        }
+        if self.protocol == Protocol::Resp3 || self.subscriber.count() == 0 {
+            return None;
+        }
+        if name == "ping" && args.len() <= 1 {
+            // Subscribed RESP2 clients get PING replies shaped like messages.
+            let message = args.first().cloned().unwrap_or_else(|| bulk(""));
+            return Some(vec![RedisValueRef::Array(vec![bulk("pong"), message])]);
+        }
+        if SUBSCRIBED_COMMANDS.contains(&name.as_str()) {
+            return None;
+        }
+        let err = format!(
+            "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            name
+        );
+        Some(vec![RedisValueRef::ErrorMsg(err.into_bytes())])
+    }
+
+    /// Wait for the next message published to our subscriptions, or the
+    /// next command to show a monitoring client. None if the client fell too
+    /// far behind on its messages, and should be disconnected.
+    pub async fn next_message(&mut self) -> Option<RedisValueRef> {
+        let message = tokio::select! {
+            message = self.subscriber.next_message() => message?,
+            line = next_line(&mut self.monitor) => return Some(RedisValueRef::SimpleString(line)),
+        };
+        Some(self.frame(message))
+    }
+}
+
+#[cfg(test)]
+mod test_connection {
+    use super::{Connection, Protocol};
+    use crate::config::config_set;
+    use crate::monitor::Origin;
+    use crate::test_util::command;
+    use crate::types::{RedisValueRef, StateStore};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn is_error(replies: &[RedisValueRef]) -> bool {
+        matches!(replies, [RedisValueRef::ErrorMsg(_)])
+    }
+
+    #[test]
+    fn test_resp2_subscriber_mode() {
+        let mut conn = Connection::new(Arc::new(StateStore::default()), peer(1));
+        assert_eq!(conn.run_command(&command(&["get", "a"])), None);
+        conn.run_command(&command(&["subscribe", "news"]));
+        let refused = conn.run_command(&command(&["get", "a"])).unwrap();
+        assert!(is_error(&refused));
+        assert_eq!(
+            conn.run_command(&command(&["ping"])),
+            Some(vec![RedisValueRef::Array(vec![
+                RedisValueRef::BulkString("pong".into()),
+                RedisValueRef::BulkString("".into()),
+            ])])
+        );
+        // Leaving every channel leaves subscriber mode.
+        conn.run_command(&command(&["unsubscribe"]));
+        assert_eq!(conn.run_command(&command(&["get", "a"])), None);
+    }
+
+    #[tokio::test]
+    async fn test_resp3_subscriber_mode() {
+        let store = Arc::new(StateStore::default());
+        let mut conn = Connection::new(store.clone(), peer(1));
+        conn.run_command(&command(&["hello", "3"]));
+        assert_eq!(conn.protocol(), Protocol::Resp3);
+        let replies = conn.run_command(&command(&["subscribe", "news"])).unwrap();
+        assert!(matches!(replies[..], [RedisValueRef::Push(_)]));
+        assert_eq!(conn.run_command(&command(&["get", "a"])), None);
+        assert_eq!(conn.run_command(&command(&["ping"])), None);
+        store.pubsub.publish(b"news", &Bytes::from_static(b"hi"));
+        assert!(matches!(
+            conn.next_message().await,
+            Some(RedisValueRef::Push(_))
+        ));
+
+        // Back to RESP2, with its restrictions.
+        conn.run_command(&command(&["hello", "2"]));
+        assert!(is_error(
+            &conn.run_command(&command(&["get", "a"])).unwrap()
+        ));
+        assert!(is_error(
+            &conn.run_command(&command(&["hello", "4"])).unwrap()
+        ));
+        assert_eq!(conn.protocol(), Protocol::Resp2);
+    }
+
+    #[test]
+    fn test_quit() {
+        let mut conn = Connection::new(Arc::new(StateStore::default()), peer(1));
+        conn.run_command(&command(&["subscribe", "news"]));
+        assert!(!conn.is_closing());
+        assert_eq!(
+            conn.run_command(&command(&["quit"])),
+            Some(vec![RedisValueRef::SimpleString("OK".into())])
+        );
+        assert!(conn.is_closing());
+    }
+
+    #[tokio::test]
+    async fn test_monitor() {
+        let store = Arc::new(StateStore::default());
+        let mut conn = Connection::new(store.clone(), peer(1));
+        assert!(is_error(
+            &conn.run_command(&command(&["monitor", "now"])).unwrap()
+        ));
+        assert!(!store.monitor.is_watched());
+        assert_eq!(
+            conn.run_command(&command(&["monitor"])),
+            Some(vec![RedisValueRef::SimpleString("OK".into())])
+        );
+        store
+            .monitor
+            .publish(Some(0), Origin::Script, &command(&["get", "a"]));
+        match conn.next_message().await {
+            Some(RedisValueRef::SimpleString(line)) => {
+                assert!(line.ends_with(br#" [0 script] "get" "a""#))
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+        drop(conn);
+        assert!(!store.monitor.is_watched());
+    }
+
+    #[tokio::test]
+    async fn test_client_commands() {
+        let store = Arc::new(StateStore::default());
+        let mut conn = Connection::new(store.clone(), peer(1));
+        let other = Connection::new(store.clone(), peer(2));
+        let id = conn.client().id() as i64;
+        assert_eq!(
+            conn.run_command(&command(&["client", "id"])),
+            Some(vec![RedisValueRef::Int(id)])
+        );
+        assert_eq!(
+            conn.run_command(&command(&["client", "getname"])),
+            Some(vec![RedisValueRef::NullBulkString])
+        );
+        assert!(is_error(
+            &conn
+                .run_command(&command(&["client", "setname", "a b"]))
+                .unwrap()
+        ));
+        conn.run_command(&command(&["client", "setname", "web"]));
+        assert_eq!(
+            conn.run_command(&command(&["client", "getname"])),
+            Some(vec![RedisValueRef::BulkString("web".into())])
+        );
+        match &conn.run_command(&command(&["client", "list"])).unwrap()[..] {
+            [RedisValueRef::BulkString(list)] => {
+                let list = String::from_utf8_lossy(list);
+                assert_eq!(list.lines().count(), 2);
+                assert!(list.contains("addr=127.0.0.1:1 name=web"));
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        let killed = conn.run_command(&command(&["client", "kill", "127.0.0.1:3"]));
+        assert!(is_error(&killed.unwrap()));
+        let killed = conn.run_command(&command(&["client", "kill", "addr", "127.0.0.1:1"]));
+        assert_eq!(killed, Some(vec![RedisValueRef::Int(0)]));
+        let killed = conn.run_command(&command(&["client", "kill", "127.0.0.1:2"]));
+        assert_eq!(killed, Some(vec![RedisValueRef::SimpleString("OK".into())]));
+        other.client().kill_signal().notified().await;
+    }
+
+    #[test]
+    fn test_auth() {
+        let store = Arc::new(StateStore::default());
+        let pass = vec![("requirepass".to_string(), "secret".to_string())];
+        config_set(&store, &pass).unwrap();
+        let mut conn = Connection::new(store.clone(), peer(1));
+        assert!(!conn.is_authenticated());
+        let refused = conn.run_command(&command(&["get", "a"])).unwrap();
+        assert!(is_error(&refused));
+        let refused = conn.run_command(&command(&["client", "id"])).unwrap();
+        assert!(is_error(&refused));
+        let wrong = conn.run_command(&command(&["auth", "nope"])).unwrap();
+        assert!(is_error(&wrong));
+        let wrong = conn.run_command(&command(&["auth", "admin", "secret"]));
+        assert!(is_error(&wrong.unwrap()));
+        assert!(!conn.is_authenticated());
+        assert_eq!(
+            conn.run_command(&command(&["auth", "default", "secret"])),
+            Some(vec![RedisValueRef::SimpleString("OK".into())])
+        );
+        assert!(conn.is_authenticated());
+        assert_eq!(conn.run_command(&command(&["get", "a"])), None);
+
+        // HELLO authenticates with its AUTH option, and only then switches.
+        let mut conn = Connection::new(store.clone(), peer(3));
+        let refused = conn.run_command(&command(&["hello", "3"])).unwrap();
+        assert!(is_error(&refused));
+        let wrong = command(&["hello", "3", "auth", "default", "nope"]);
+        assert!(is_error(&conn.run_command(&wrong).unwrap()));
+        assert_eq!(conn.protocol(), Protocol::Resp2);
+        let hello = command(&["hello", "3", "AUTH", "default", "secret"]);
+        assert!(!is_error(&conn.run_command(&hello).unwrap()));
+        assert!(conn.is_authenticated());
+        assert_eq!(conn.protocol(), Protocol::Resp3);
+        let partial = command(&["hello", "3", "auth", "default"]);
+        assert!(is_error(&conn.run_command(&partial).unwrap()));
+
+        // Without a password, everyone is authenticated.
+        let mut conn = Connection::new(Arc::new(StateStore::default()), peer(2));
+        assert!(conn.is_authenticated());
+        assert!(is_error(
+            &conn.run_command(&command(&["auth", "secret"])).unwrap()
+        ));
+    }
+}
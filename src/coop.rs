@@ -0,0 +1,129 @@
+//! Cooperative yielding for commands that walk big collections.
+//!
+//! Tokio can't preempt a task, so a KEYS over a million keys stalls every
+//! other connection scheduled on the same worker until it's done. Long
+//! running ops spend a `Budget` as they go, and yield back to the scheduler
+//! once it runs out.
+//!
+//! Never yield while holding a DashMap guard: another task on this worker
+//! may be waiting on the same shard. Yield between shards or collections.
+
+use dashmap::DashMap;
+use std::hash::Hash;
+
+/// Elements processed between yields.
+pub const YIELD_EVERY: usize = 4096;
+
+pub struct Budget {
+    every: usize,
+    remaining: usize,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget::new(YIELD_EVERY)
+    }
+}
+
+impl Budget {
+    pub fn new(every: usize) -> Self {
+        Budget {
+            every,
+            remaining: every,
+        }
+    }
+
+    /// A budget that never yields. Useful for comparison in benchmarks.
+    pub fn unlimited() -> Self {
+        Budget::new(usize::MAX)
+    }
+
+    /// Spend `n` units of work, yielding if the budget ran out.
+    pub async fn spend(&mut self, n: usize) {
+        if self.take(n) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Returns true (and refills) if the budget ran out.
+    fn take(&mut self, n: usize) -> bool {
+        if n < self.remaining {
+            self.remaining -= n;
+            false
+        } else {
+            self.remaining = self.every;
+            true
+        }
+    }
+}
+
+fn shard_keys<K: Clone, V>(shard: &std::collections::HashMap<K, V>) -> Vec<K> {
+    shard.keys().cloned().collect()
+}
+
+/// Collect every key in `map`, one shard at a time, yielding between shards.
+pub async fn collect_keys<K, V>(map: &DashMap<K, V>, budget: &mut Budget, out: &mut Vec<K>)
+where
+    K: Eq + Hash + Clone,
+{
+    for shard in map.shards() {
+        let keys = shard_keys(&shard.read());
+        let n = keys.len();
+        out.extend(keys);
+        budget.spend(n).await;
+    }
+}
+
+/// Call `f` on every entry in `map`, one shard at a time, yielding between shards.
+///
+/// `f` runs while the shard is read locked, so it must not touch `map`.
+pub async fn visit_entries<K, V, F>(map: &DashMap<K, V>, budget: &mut Budget, mut f: F)
+where
+    K: Eq + Hash,
+    F: FnMut(&K, &V),
+{
+    for shard in map.shards() {
+        let n = {
+            let shard = shard.read();
+            for (key, value) in shard.iter() {
+                f(key, value.get());
+            }
+            shard.len()
+        };
+        budget.spend(n).await;
+    }
+}
+
+#[cfg(test)]
+mod test_coop {
+    use super::{collect_keys, visit_entries, Budget};
+    use dashmap::DashMap;
+
+    #[test]
+    fn test_budget_refills() {
+        let mut budget = Budget::new(10);
+        assert!(!budget.take(4));
+        assert!(!budget.take(5));
+        assert!(budget.take(1));
+        assert!(!budget.take(9));
+        assert!(budget.take(100));
+        assert!(!Budget::unlimited().take(1_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_collect_keys() {
+        let map: DashMap<u32, ()> = (0..10_000).map(|i| (i, ())).collect();
+        let mut keys = Vec::new();
+        collect_keys(&map, &mut Budget::new(16), &mut keys).await;
+        keys.sort_unstable();
+        assert_eq!(keys, (0..10_000).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_visit_entries() {
+        let map: DashMap<u32, u32> = (0..10_000).map(|i| (i, 2 * i)).collect();
+        let mut sum = 0;
+        visit_entries(&map, &mut Budget::new(16), |_, v| sum += *v as u64).await;
+        assert_eq!(sum, (0..10_000).map(|i| 2 * i as u64).sum::<u64>());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::data_structures::count_min_sketch::CountMinSketch;
+use crate::ops::RVec;
+use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use crate::{make_reader, op_variants};
+use dashmap::mapref::entry::Entry;
+
+op_variants! {
+    CountMinOps,
+    CmsInitByDim(Key, usize, usize),
+    CmsInitByProb(Key, f64, f64),
+    CmsIncrBy(Key, RVec<(Value, Count)>),
+    CmsQuery(Key, RVec<Value>),
+    CmsMerge(Key, RVec<(Key, Count)>),
+    CmsInfo(Key)
+}
+
+impl CountMinOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            CountMinOps::CmsInitByDim(key, ..)
+            | CountMinOps::CmsInitByProb(key, ..)
+            | CountMinOps::CmsIncrBy(key, _)
+            | CountMinOps::CmsMerge(key, _) => Some(vec![key.clone()]),
+            CountMinOps::CmsQuery(..) | CountMinOps::CmsInfo(_) => None,
+        }
+    }
+}
+
+make_reader!(count_mins, read_count_mins);
+
+const MISSING: ReturnValue = ReturnValue::Error(b"CMS: key does not exist");
+
+fn init(state: &StateRef, key: Key, cms: CountMinSketch) -> ReturnValue {
+    match state.count_mins.entry(key) {
+        Entry::Occupied(_) => ReturnValue::Error(b"CMS: key already exists"),
+        Entry::Vacant(entry) => {
+            entry.insert(cms);
+            ReturnValue::Ok
+        }
+    }
+}
+
+fn counts(counts: impl Iterator<Item = u64>) -> ReturnValue {
+    ReturnValue::Array(
+        counts
+            .map(|count| ReturnValue::IntRes(count.min(Count::MAX as u64) as Count))
+            .collect(),
+    )
+}
+
+pub async fn count_min_interact(count_min_op: CountMinOps, state: StateRef) -> ReturnValue {
+    match count_min_op {
+        CountMinOps::CmsInitByDim(key, width, depth) => {
+            init(&state, key, CountMinSketch::new(width, depth))
+        }
+        CountMinOps::CmsInitByProb(key, error, probability) => {
+            init(&state, key, CountMinSketch::with_error(error, probability))
+        }
+        CountMinOps::CmsIncrBy(key, increments) => match state.count_mins.get_mut(&key) {
+            Some(mut cms) => counts(
+                increments
+                    .iter()
+                    .map(|(item, by)| cms.increment(item, *by as u64)),
+            ),
+            None => MISSING,
+        },
+        CountMinOps::CmsQuery(key, items) => match read_count_mins!(state, &key) {
+            Some(cms) => counts(items.iter().map(|item| cms.estimate(item))),
+            None => MISSING,
+        },
+        CountMinOps::CmsMerge(dest, sources) => {
+            // Copy the sources first, as the destination may be one of them.
+            let mut weighed = Vec::with_capacity(sources.len());
+            for (key, weight) in sources {
+                match read_count_mins!(state, &key) {
+                    Some(cms) => weighed.push((cms.clone(), weight)),
+                    None => return MISSING,
+                }
+            }
+            match state.count_mins.get_mut(&dest) {
+                Some(mut cms) => {
+                    if cms.merge(&weighed) {
+                        ReturnValue::Ok
+                    } else {
+                        ReturnValue::Error(b"CMS: width/depth is not equal")
+                    }
+                }
+                None => MISSING,
+            }
+        }
+        CountMinOps::CmsInfo(key) => match read_count_mins!(state, &key) {
+            Some(cms) => {
+                let field = |name: &'static str, value: u64| {
+                    vec![
+                        ReturnValue::StringRes(Value::from_static(name.as_bytes())),
+                        ReturnValue::IntRes(value as Count),
+                    ]
+                };
+                let mut reply = Vec::new();
+                reply.extend(field("width", cms.width() as u64));
+                reply.extend(field("depth", cms.depth() as u64));
+                reply.extend(field("count", cms.count()));
+                ReturnValue::Array(reply)
+            }
+            None => MISSING,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_count_min {
+    use crate::count_min::{count_min_interact, CountMinOps};
+    use crate::ops::{translate, Ops};
+    use crate::test_util::command;
+    use crate::types::{ReturnValue, State, StateStore};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    fn ints(ints: &[i64]) -> ReturnValue {
+        ReturnValue::Array(ints.iter().map(|i| ReturnValue::IntRes(*i)).collect())
+    }
+
+    #[tokio::test]
+    async fn test_incrby_query_merge() {
+        let (a, b, dest) = (
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"dest"),
+        );
+        let (x, y) = (Bytes::from_static(b"x"), Bytes::from_static(b"y"));
+        let eng = Arc::new(State::default());
+        let res = count_min_interact(
+            CountMinOps::CmsQuery(a.clone(), smallvec![x.clone()]),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(res, ReturnValue::Error(b"CMS: key does not exist"));
+
+        for key in [&a, &b, &dest].iter() {
+            let init = CountMinOps::CmsInitByDim((*key).clone(), 100, 5);
+            let res = count_min_interact(init, eng.clone()).await;
+            assert_eq!(res, ReturnValue::Ok);
+        }
+        let init = CountMinOps::CmsInitByProb(a.clone(), 0.01, 0.01);
+        let res = count_min_interact(init, eng.clone()).await;
+        assert_eq!(res, ReturnValue::Error(b"CMS: key already exists"));
+
+        let incr = CountMinOps::CmsIncrBy(a.clone(), smallvec![(x.clone(), 2), (x.clone(), 3)]);
+        assert_eq!(count_min_interact(incr, eng.clone()).await, ints(&[2, 5]));
+        let incr = CountMinOps::CmsIncrBy(b.clone(), smallvec![(x.clone(), 1), (y.clone(), 4)]);
+        assert_eq!(count_min_interact(incr, eng.clone()).await, ints(&[1, 4]));
+
+        let merge = CountMinOps::CmsMerge(dest.clone(), smallvec![(a.clone(), 1), (b.clone(), 2)]);
+        assert_eq!(
+            count_min_interact(merge, eng.clone()).await,
+            ReturnValue::Ok
+        );
+        let query = CountMinOps::CmsQuery(dest.clone(), smallvec![x.clone(), y]);
+        assert_eq!(count_min_interact(query, eng.clone()).await, ints(&[7, 8]));
+
+        let small = Bytes::from_static(b"small");
+        let init = CountMinOps::CmsInitByDim(small.clone(), 10, 5);
+        count_min_interact(init, eng.clone()).await;
+        let merge = CountMinOps::CmsMerge(dest.clone(), smallvec![(a, 1), (small, 1)]);
+        assert_eq!(
+            count_min_interact(merge, eng.clone()).await,
+            ReturnValue::Error(b"CMS: width/depth is not equal")
+        );
+
+        let info = count_min_interact(CountMinOps::CmsInfo(dest), eng.clone()).await;
+        assert_eq!(
+            info,
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(Bytes::from_static(b"width")),
+                ReturnValue::IntRes(100),
+                ReturnValue::StringRes(Bytes::from_static(b"depth")),
+                ReturnValue::IntRes(5),
+                ReturnValue::StringRes(Bytes::from_static(b"count")),
+                ReturnValue::IntRes(15),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let store = Arc::new(StateStore::default());
+        let parse = |args: &[&str]| translate(command(args), store.clone());
+        let ok = |res| matches!(res, Ok(Ops::CountMins(_)));
+        assert!(ok(parse(&["CMS.INITBYDIM", "key", "1000", "5"])));
+        assert!(ok(parse(&["CMS.INITBYPROB", "key", "0.001", "0.01"])));
+        // Overflows a usize.
+        let huge = i64::MAX.to_string();
+        assert!(parse(&["CMS.INITBYDIM", "key", &huge, "3"]).is_err());
+        assert!(parse(&["CMS.INITBYPROB", "key", "1e-300", "0.5"]).is_err());
+        // 1GB of counters.
+        assert!(parse(&["CMS.INITBYDIM", "key", "134217728", "1"]).is_err());
+    }
+}
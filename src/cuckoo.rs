@@ -0,0 +1,100 @@
+use crate::data_structures::cuckoo_filter::{CuckooFilter, FilterFull};
+use crate::types::{Count, Key, RedisBool, ReturnValue, StateRef, Value};
+use crate::{make_reader, op_variants};
+
+op_variants! {
+    CuckooOps,
+    CfAdd(Key, Value),
+    CfExists(Key, Value),
+    CfDel(Key, Value),
+    CfCount(Key, Value)
+}
+
+impl CuckooOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            CuckooOps::CfAdd(key, _) | CuckooOps::CfDel(key, _) => Some(vec![key.clone()]),
+            CuckooOps::CfExists(..) | CuckooOps::CfCount(..) => None,
+        }
+    }
+}
+
+/// Items the first table of a filter made by CF.ADD holds, like RedisBloom.
+const DEFAULT_CAPACITY: usize = 1024;
+
+make_reader!(cuckoos, read_cuckoos);
+
+pub async fn cuckoo_interact(cuckoo_op: CuckooOps, state: StateRef) -> ReturnValue {
+    match cuckoo_op {
+        CuckooOps::CfAdd(key, value) => {
+            let added = state
+                .cuckoos
+                .entry(key)
+                .or_insert_with(|| CuckooFilter::new(DEFAULT_CAPACITY))
+                .insert(&value);
+            match added {
+                Ok(()) => ReturnValue::IntRes(1),
+                Err(FilterFull) => ReturnValue::Error(b"ERR filter is full"),
+            }
+        }
+        CuckooOps::CfExists(key, value) => read_cuckoos!(state, &key)
+            .map(|cf| cf.contains(&value) as RedisBool)
+            .unwrap_or(0)
+            .into(),
+        CuckooOps::CfDel(key, value) => match state.cuckoos.get_mut(&key) {
+            Some(mut cf) => ReturnValue::IntRes(cf.remove(&value) as RedisBool),
+            None => ReturnValue::Error(b"ERR not found"),
+        },
+        CuckooOps::CfCount(key, value) => read_cuckoos!(state, &key)
+            .map(|cf| cf.count(&value) as Count)
+            .unwrap_or(0)
+            .into(),
+    }
+}
+
+#[cfg(test)]
+mod test_cuckoo {
+    use crate::cuckoo::{cuckoo_interact, CuckooOps};
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_add_count_del() {
+        let (key, v) = (Bytes::from_static(b"key"), Bytes::from_static(b"v"));
+        let eng = Arc::new(State::default());
+        let res = cuckoo_interact(CuckooOps::CfDel(key.clone(), v.clone()), eng.clone()).await;
+        assert_eq!(res, ReturnValue::Error(b"ERR not found"));
+        let res = cuckoo_interact(CuckooOps::CfExists(key.clone(), v.clone()), eng.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(0));
+
+        for _ in 0..2 {
+            let res = cuckoo_interact(CuckooOps::CfAdd(key.clone(), v.clone()), eng.clone()).await;
+            assert_eq!(res, ReturnValue::IntRes(1));
+        }
+        let res = cuckoo_interact(CuckooOps::CfCount(key.clone(), v.clone()), eng.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(2));
+
+        let del = CuckooOps::CfDel(key.clone(), v.clone());
+        let res = cuckoo_interact(del.clone(), eng.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(1));
+        let res = cuckoo_interact(CuckooOps::CfExists(key.clone(), v.clone()), eng.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(1));
+        cuckoo_interact(del.clone(), eng.clone()).await;
+        let res = cuckoo_interact(del, eng.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(0));
+        let res = cuckoo_interact(CuckooOps::CfExists(key.clone(), v.clone()), eng.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(0));
+
+        let add = CuckooOps::CfAdd(key, v);
+        for _ in 0..4 {
+            assert_eq!(
+                cuckoo_interact(add.clone(), eng.clone()).await,
+                ReturnValue::IntRes(1)
+            );
+        }
+        let res = cuckoo_interact(add, eng.clone()).await;
+        assert_eq!(res, ReturnValue::Error(b"ERR filter is full"));
+    }
+}
@@ -0,0 +1,141 @@
+use growable_bloom_filter::GrowableBloom;
+use std::hash::Hash;
+
+/// How much each filter added to a full one holds, relative to the last.
+const GROWTH_FACTOR: usize = 2;
+/// How much each added filter tightens its error rate, so the overall rate holds.
+const TIGHTENING_RATIO: f64 = 0.85;
+/// Below this the filter is mostly hash functions; BF.RESERVE refuses it.
+pub const MIN_ERROR_RATE: f64 = 1e-9;
+
+/// The bits a filter for `capacity` items at `error_rate` takes,
+/// `-n ln(p) / ln(2)^2`.
+pub fn bits_for(error_rate: f64, capacity: f64) -> f64 {
+    -capacity * error_rate.ln() / std::f64::consts::LN_2.powi(2)
+}
+
+/// A scalable bloom filter, with the settings it was made with.
+///
+/// Filters are added as the capacity fills (like `GrowableBloom`, whose
+/// sizing the `GROWTH_FACTOR` and `TIGHTENING_RATIO` mirror), so more than
+/// `capacity` items can go in without passing `error_rate`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    filter: GrowableBloom,
+    error_rate: f64,
+    capacity: usize,
+    /// Items which weren't in the filter when added.
+    items: usize,
+}
+
+impl BloomFilter {
+    pub fn new(error_rate: f64, capacity: usize) -> BloomFilter {
+        BloomFilter {
+            filter: GrowableBloom::new(error_rate, capacity),
+            error_rate,
+            capacity,
+            items: 0,
+        }
+    }
+
+    /// Wrap a filter made with these settings, holding an unknown number of items.
+    pub fn from_filter(filter: GrowableBloom, error_rate: f64, capacity: usize) -> BloomFilter {
+        BloomFilter {
+            filter,
+            error_rate,
+            capacity,
+            items: 0,
+        }
+    }
+
+    /// Add `item`. Returns false if it may have been added already.
+    pub fn insert<T: Hash>(&mut self, item: T) -> bool {
+        if self.filter.contains(&item) {
+            return false;
+        }
+        self.filter.insert(&item);
+        self.items += 1;
+        true
+    }
+
+    pub fn contains<T: Hash>(&self, item: T) -> bool {
+        self.filter.contains(&item)
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        self.error_rate
+    }
+
+    /// The number of items the first filter was sized for.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of items added (not counting ones which seemed present).
+    pub fn items(&self) -> usize {
+        self.items
+    }
+
+    /// How many filters hold the items so far, and how many items they're sized for.
+    fn grown(&self) -> (usize, usize) {
+        let (mut filters, mut holds, mut next) = (1, self.capacity, self.capacity);
+        while holds < self.items {
+            next = next.saturating_mul(GROWTH_FACTOR);
+            holds = holds.saturating_add(next);
+            filters += 1;
+        }
+        (filters, holds)
+    }
+
+    pub fn filters(&self) -> usize {
+        self.grown().0
+    }
+
+    /// The number of items the filters so far are sized for.
+    pub fn total_capacity(&self) -> usize {
+        self.grown().1
+    }
+
+    /// The bytes of bits used by all the filters, each sized for its
+    /// capacity at its error rate.
+    pub fn size(&self) -> usize {
+        let (mut capacity, mut error_rate) = (self.capacity as f64, self.error_rate);
+        let mut bits = 0.0;
+        for _ in 0..self.filters() {
+            bits += bits_for(error_rate, capacity);
+            capacity *= GROWTH_FACTOR as f64;
+            error_rate *= TIGHTENING_RATIO;
+        }
+        (bits / 8.0).ceil() as usize
+    }
+}
+
+#[cfg(test)]
+mod test_bloom_filter {
+    use crate::data_structures::bloom_filter::BloomFilter;
+
+    #[test]
+    fn test_insert_contains() {
+        let mut bloom = BloomFilter::new(0.01, 100);
+        assert!(!bloom.contains("a"));
+        assert!(bloom.insert("a"));
+        assert!(!bloom.insert("a"));
+        assert!(bloom.contains("a"));
+        assert_eq!(bloom.items(), 1);
+    }
+
+    #[test]
+    fn test_grows_past_capacity() {
+        let mut bloom = BloomFilter::new(0.01, 10);
+        assert_eq!(bloom.filters(), 1);
+        let one_filter = bloom.size();
+        for i in 0..40 {
+            bloom.insert(i);
+        }
+        assert!((0..40).all(|i| bloom.contains(i)));
+        // 10 + 20 doesn't hold 40 items, 10 + 20 + 40 does.
+        assert_eq!(bloom.filters(), 3);
+        assert_eq!(bloom.total_capacity(), 70);
+        assert!(bloom.size() > one_filter * 3);
+    }
+}
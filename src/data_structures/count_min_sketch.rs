@@ -0,0 +1,196 @@
+use crate::hashing::{mix, stable_hash};
+use std::convert::TryFrom;
+
+/// A count-min sketch: estimates how many times items were counted, in
+/// fixed space.
+///
+/// Each item has one counter per row, picked by its hash. Counting bumps
+/// them all, and the smallest is the estimate: never too low, and too high
+/// by at most `2 / width` of the total count with probability
+/// `1 - 0.5^depth`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "StoredSketch")]
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    /// `depth` rows of `width` counters.
+    counters: Vec<u64>,
+    /// The sum of every increment.
+    count: u64,
+}
+
+#[derive(Deserialize)]
+struct StoredSketch {
+    width: usize,
+    depth: usize,
+    counters: Vec<u64>,
+    count: u64,
+}
+
+impl TryFrom<StoredSketch> for CountMinSketch {
+    type Error = &'static str;
+
+    /// Refuse sketches hashing into counters they don't have.
+    fn try_from(stored: StoredSketch) -> Result<CountMinSketch, Self::Error> {
+        if stored.width == 0
+            || stored.width.checked_mul(stored.depth) != Some(stored.counters.len())
+        {
+            return Err("count-min sketch counters don't match its width and depth");
+        }
+        Ok(CountMinSketch {
+            width: stored.width,
+            depth: stored.depth,
+            counters: stored.counters,
+            count: stored.count,
+        })
+    }
+}
+
+/// The bytes of counters a sketch of this width and depth takes, or None
+/// if that doesn't fit in a usize.
+pub fn size_for(width: usize, depth: usize) -> Option<usize> {
+    width
+        .checked_mul(depth)?
+        .checked_mul(std::mem::size_of::<u64>())
+}
+
+/// The width and depth for estimates off by at most `error` of the total
+/// count, and further off with at most `probability`, like RedisBloom.
+pub fn dimensions(error: f64, probability: f64) -> (usize, usize) {
+    // Casts saturate, so tiny errors give usize::MAX rather than wrapping.
+    let width = (2.0 / error).ceil() as usize;
+    let depth = (probability.ln() / 0.5f64.ln()).ceil() as usize;
+    (width, depth.max(1))
+}
+
+impl CountMinSketch {
+    /// Callers check the size with `size_for` first.
+    pub fn new(width: usize, depth: usize) -> CountMinSketch {
+        let counters = width
+            .checked_mul(depth)
+            .expect("count-min sketch sizes are checked when parsed");
+        CountMinSketch {
+            width,
+            depth,
+            counters: vec![0; counters],
+            count: 0,
+        }
+    }
+
+    pub fn with_error(error: f64, probability: f64) -> CountMinSketch {
+        let (width, depth) = dimensions(error, probability);
+        CountMinSketch::new(width, depth)
+    }
+
+    /// The index of `item`'s counter in each row.
+    fn cells(&self, item: &[u8]) -> impl Iterator<Item = usize> {
+        let (hash, width) = (stable_hash(item), self.width);
+        (0..self.depth).map(move |row| {
+            row * width + (mix(hash.wrapping_add(row as u64)) % width as u64) as usize
+        })
+    }
+
+    /// Count `item` `by` more times. Returns its new estimate.
+    pub fn increment(&mut self, item: &[u8], by: u64) -> u64 {
+        let mut estimate = u64::MAX;
+        for cell in self.cells(item) {
+            let counter = &mut self.counters[cell];
+            *counter = counter.saturating_add(by);
+            estimate = estimate.min(*counter);
+        }
+        self.count = self.count.saturating_add(by);
+        estimate
+    }
+
+    pub fn estimate(&self, item: &[u8]) -> u64 {
+        self.cells(item)
+            .map(|cell| self.counters[cell])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Replace the counts with the sum of `sources`' counts, each times its
+    /// weight. Returns false, changing nothing, unless every source has
+    /// this sketch's width and depth.
+    pub fn merge(&mut self, sources: &[(CountMinSketch, i64)]) -> bool {
+        let same_size = |(source, _): &(CountMinSketch, i64)| {
+            (source.width, source.depth) == (self.width, self.depth)
+        };
+        if !sources.iter().all(same_size) {
+            return false;
+        }
+        let weighed = |count: &dyn Fn(&CountMinSketch) -> u64| {
+            let sum: i128 = sources
+                .iter()
+                .map(|(source, weight)| count(source) as i128 * *weight as i128)
+                .sum();
+            sum.max(0).min(u64::MAX as i128) as u64
+        };
+        for cell in 0..self.counters.len() {
+            self.counters[cell] = weighed(&|source| source.counters[cell]);
+        }
+        self.count = weighed(&|source| source.count);
+        true
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Bytes of counters.
+    pub fn size(&self) -> usize {
+        self.counters.len() * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod test_count_min_sketch {
+    use crate::data_structures::count_min_sketch::{dimensions, size_for, CountMinSketch};
+
+    #[test]
+    fn test_estimates_never_low() {
+        let mut cms = CountMinSketch::new(50, 4);
+        for i in 0..200u32 {
+            cms.increment(&i.to_be_bytes(), u64::from(i % 7));
+        }
+        assert!((0..200u32).all(|i| cms.estimate(&i.to_be_bytes()) >= u64::from(i % 7)));
+        assert_eq!(cms.increment(b"missing", 0), cms.estimate(b"missing"));
+        assert_eq!(cms.count(), (0..200).map(|i| i % 7).sum::<u64>());
+    }
+
+    #[test]
+    fn test_with_error() {
+        let cms = CountMinSketch::with_error(0.001, 0.01);
+        assert_eq!((cms.width(), cms.depth()), (2000, 7));
+        assert_eq!(cms.size(), 2000 * 7 * 8);
+        assert_eq!(size_for(2000, 7), Some(cms.size()));
+        assert_eq!(dimensions(1e-300, 0.5), (usize::MAX, 1));
+        assert_eq!(size_for(usize::MAX, 1), None);
+        assert_eq!(size_for(usize::MAX / 2, 3), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let (mut a, mut b) = (CountMinSketch::new(10, 2), CountMinSketch::new(10, 2));
+        a.increment(b"x", 3);
+        b.increment(b"x", 1);
+        b.increment(b"y", 5);
+        let mut merged = CountMinSketch::new(10, 2);
+        assert!(merged.merge(&[(a, 2), (b.clone(), 1)]));
+        assert_eq!(merged.estimate(b"x"), 7);
+        assert!(merged.estimate(b"y") >= 5);
+        assert_eq!(merged.count(), 12);
+
+        let before = merged.clone();
+        assert!(!merged.merge(&[(b, 1), (CountMinSketch::new(10, 3), 1)]));
+        assert_eq!(merged, before);
+    }
+}
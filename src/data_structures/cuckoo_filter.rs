@@ -0,0 +1,322 @@
+use crate::hashing::{mix, stable_hash};
+use std::convert::TryFrom;
+
+/// Fingerprints per bucket.
+const BUCKET_SIZE: usize = 4;
+/// How many fingerprints an insert may move before a table counts as full.
+const MAX_KICKS: usize = 500;
+/// An empty slot. Fingerprints are never 0.
+const EMPTY: u8 = 0;
+/// Copies of one item a table holds, half the slots the item can go in, so
+/// adding the same item over and over can't fill tables by itself.
+const MAX_DUPLICATES: usize = BUCKET_SIZE;
+/// Most tables a filter grows to. The last holds 2^15 times what the first does.
+const MAX_TABLES: usize = 16;
+
+/// Returned when an item can't be added, because the filter has all the
+/// tables it may have or already holds as many copies of the item as it can.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterFull;
+
+/// One fixed size table of buckets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Table {
+    /// `BUCKET_SIZE` slots per bucket, with a power of two buckets.
+    slots: Vec<u8>,
+}
+
+impl Table {
+    fn new(buckets: usize) -> Table {
+        Table {
+            slots: vec![EMPTY; buckets.next_power_of_two() * BUCKET_SIZE],
+        }
+    }
+
+    fn mask(&self) -> usize {
+        self.slots.len() / BUCKET_SIZE - 1
+    }
+
+    /// The two buckets `hash` can live in. Either is the other's alternate.
+    fn buckets(&self, hash: u64, fingerprint: u8) -> (usize, usize) {
+        let first = hash as usize & self.mask();
+        (first, self.alternate(first, fingerprint))
+    }
+
+    fn alternate(&self, bucket: usize, fingerprint: u8) -> usize {
+        (bucket ^ mix(fingerprint as u64) as usize) & self.mask()
+    }
+
+    fn bucket(&self, bucket: usize) -> &[u8] {
+        &self.slots[bucket * BUCKET_SIZE..(bucket + 1) * BUCKET_SIZE]
+    }
+
+    fn bucket_mut(&mut self, bucket: usize) -> &mut [u8] {
+        &mut self.slots[bucket * BUCKET_SIZE..(bucket + 1) * BUCKET_SIZE]
+    }
+
+    /// Put `fingerprint` in an empty slot of `bucket`, if it has one.
+    fn put(&mut self, bucket: usize, fingerprint: u8) -> bool {
+        match self.bucket_mut(bucket).iter_mut().find(|s| **s == EMPTY) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Insert without moving anything, if either bucket has room.
+    fn try_put(&mut self, hash: u64, fingerprint: u8) -> bool {
+        let (first, second) = self.buckets(hash, fingerprint);
+        self.put(first, fingerprint) || self.put(second, fingerprint)
+    }
+
+    /// Insert, moving other fingerprints to their alternate buckets to make
+    /// room. Returns false, with nothing moved, if the table is too full.
+    fn insert(&mut self, hash: u64, fingerprint: u8) -> bool {
+        if self.try_put(hash, fingerprint) {
+            return true;
+        }
+        // Victims are picked without randomness, so replicas end up alike.
+        let (mut bucket, _) = self.buckets(hash, fingerprint);
+        let mut homeless = fingerprint;
+        let mut moves = Vec::new();
+        for kick in 0..MAX_KICKS {
+            let slot = (homeless as usize + kick) % BUCKET_SIZE;
+            std::mem::swap(&mut self.bucket_mut(bucket)[slot], &mut homeless);
+            moves.push((bucket, slot));
+            bucket = self.alternate(bucket, homeless);
+            if self.put(bucket, homeless) {
+                return true;
+            }
+        }
+        for (bucket, slot) in moves.into_iter().rev() {
+            std::mem::swap(&mut self.bucket_mut(bucket)[slot], &mut homeless);
+        }
+        false
+    }
+
+    fn count(&self, hash: u64, fingerprint: u8) -> usize {
+        let (first, second) = self.buckets(hash, fingerprint);
+        let count = |bucket| {
+            self.bucket(bucket)
+                .iter()
+                .filter(|s| **s == fingerprint)
+                .count()
+        };
+        // Both buckets are the same one for some fingerprints.
+        count(first) + if first != second { count(second) } else { 0 }
+    }
+
+    fn remove(&mut self, hash: u64, fingerprint: u8) -> bool {
+        let (first, second) = self.buckets(hash, fingerprint);
+        for bucket in [first, second].iter() {
+            if let Some(slot) = self
+                .bucket_mut(*bucket)
+                .iter_mut()
+                .find(|s| **s == fingerprint)
+            {
+                *slot = EMPTY;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// A scalable cuckoo filter: like a bloom filter, but items can be removed
+/// and counted (up to false positives).
+///
+/// Items are stored as 8 bit fingerprints, in one of two buckets picked by
+/// their hash. Once a table is full another twice its size is added, up to
+/// `MAX_TABLES`, though each table adds to the false positive rate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "StoredFilter")]
+pub struct CuckooFilter {
+    tables: Vec<Table>,
+    /// The number of items the first table was sized for.
+    capacity: usize,
+    items: usize,
+}
+
+#[derive(Deserialize)]
+struct StoredFilter {
+    tables: Vec<Table>,
+    capacity: usize,
+    items: usize,
+}
+
+impl TryFrom<StoredFilter> for CuckooFilter {
+    type Error = &'static str;
+
+    /// Refuse filters without tables, with tables that aren't a power of
+    /// two buckets, or counting items other than the fingerprints they hold.
+    fn try_from(stored: StoredFilter) -> Result<CuckooFilter, Self::Error> {
+        if stored.tables.is_empty() || stored.tables.len() > MAX_TABLES {
+            return Err("cuckoo filter has no tables, or too many");
+        }
+        let buckets = |t: &Table| t.slots.len() / BUCKET_SIZE;
+        if stored
+            .tables
+            .iter()
+            .any(|t| t.slots.len() % BUCKET_SIZE != 0 || !buckets(t).is_power_of_two())
+        {
+            return Err("cuckoo filter tables are a power of two buckets");
+        }
+        let filled: usize = stored
+            .tables
+            .iter()
+            .map(|t| t.slots.iter().filter(|s| **s != EMPTY).count())
+            .sum();
+        if filled != stored.items {
+            return Err("cuckoo filter items don't match its fingerprints");
+        }
+        Ok(CuckooFilter {
+            tables: stored.tables,
+            capacity: stored.capacity,
+            items: stored.items,
+        })
+    }
+}
+
+impl CuckooFilter {
+    pub fn new(capacity: usize) -> CuckooFilter {
+        let buckets = (capacity.max(1) + BUCKET_SIZE - 1) / BUCKET_SIZE;
+        CuckooFilter {
+            tables: vec![Table::new(buckets)],
+            capacity,
+            items: 0,
+        }
+    }
+
+    fn hash(item: &[u8]) -> (u64, u8) {
+        let hash = mix(stable_hash(item));
+        let fingerprint = ((hash >> 32) % 255 + 1) as u8;
+        (hash, fingerprint)
+    }
+
+    /// Add `item`, even if it's there already.
+    pub fn insert(&mut self, item: &[u8]) -> Result<(), FilterFull> {
+        let (hash, fingerprint) = Self::hash(item);
+        let has_room = |t: &Table| t.count(hash, fingerprint) < MAX_DUPLICATES;
+        if self
+            .tables
+            .iter_mut()
+            .any(|t| has_room(t) && t.try_put(hash, fingerprint))
+        {
+            self.items += 1;
+            return Ok(());
+        }
+        let tables = self.tables.len();
+        let last = self.tables.last_mut().expect("filters have a table");
+        // A new table would only fill up with more copies.
+        if !has_room(last) {
+            return Err(FilterFull);
+        }
+        if !last.insert(hash, fingerprint) {
+            if tables >= MAX_TABLES {
+                return Err(FilterFull);
+            }
+            let mut table = Table::new(last.slots.len() / BUCKET_SIZE * 2);
+            table.try_put(hash, fingerprint);
+            self.tables.push(table);
+        }
+        self.items += 1;
+        Ok(())
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.count(item) > 0
+    }
+
+    /// How many times `item` may have been added (and not removed).
+    pub fn count(&self, item: &[u8]) -> usize {
+        let (hash, fingerprint) = Self::hash(item);
+        self.tables.iter().map(|t| t.count(hash, fingerprint)).sum()
+    }
+
+    /// Remove one copy of `item`. Returns false if it wasn't there. Only
+    /// remove items which were added, or another item may be removed instead.
+    pub fn remove(&mut self, item: &[u8]) -> bool {
+        let (hash, fingerprint) = Self::hash(item);
+        let removed = self
+            .tables
+            .iter_mut()
+            .rev()
+            .any(|t| t.remove(hash, fingerprint));
+        if removed {
+            self.items -= 1;
+        }
+        removed
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn items(&self) -> usize {
+        self.items
+    }
+
+    /// Bytes of fingerprint slots.
+    pub fn size(&self) -> usize {
+        self.tables.iter().map(|t| t.slots.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test_cuckoo_filter {
+    use crate::data_structures::cuckoo_filter::{CuckooFilter, FilterFull, MAX_TABLES};
+
+    #[test]
+    fn test_insert_count_remove() {
+        let mut cf = CuckooFilter::new(100);
+        assert!(!cf.contains(b"a"));
+        cf.insert(b"a").unwrap();
+        cf.insert(b"a").unwrap();
+        assert_eq!(cf.count(b"a"), 2);
+        assert!(cf.remove(b"a"));
+        assert_eq!(cf.count(b"a"), 1);
+        assert!(cf.remove(b"a"));
+        assert!(!cf.contains(b"a"));
+        assert!(!cf.remove(b"a"));
+        assert_eq!(cf.items(), 0);
+    }
+
+    #[test]
+    fn test_grows_when_full() {
+        let mut cf = CuckooFilter::new(8);
+        let items: Vec<Vec<u8>> = (0..1000).map(|i: u32| i.to_be_bytes().to_vec()).collect();
+        for item in items.iter() {
+            cf.insert(item).unwrap();
+        }
+        assert!(items.iter().all(|item| cf.contains(item)));
+        assert!(cf.size() >= 1000);
+        for item in items.iter() {
+            assert!(cf.remove(item));
+        }
+        assert_eq!(cf.items(), 0);
+        assert!(cf.tables.iter().all(|t| t.slots.iter().all(|s| *s == 0)));
+    }
+
+    #[test]
+    fn test_duplicates_dont_grow() {
+        let mut cf = CuckooFilter::new(100);
+        for _ in 0..4 {
+            cf.insert(b"a").unwrap();
+        }
+        assert_eq!(cf.insert(b"a"), Err(FilterFull));
+        assert_eq!((cf.count(b"a"), cf.items()), (4, 4));
+        assert_eq!(cf.tables.len(), 1);
+        cf.insert(b"b").unwrap();
+    }
+
+    #[test]
+    fn test_stops_growing() {
+        let mut cf = CuckooFilter::new(1);
+        let full = (0..1_000_000u32).find(|i| cf.insert(&i.to_be_bytes()).is_err());
+        assert!(full.is_some());
+        assert_eq!(cf.tables.len(), MAX_TABLES);
+        assert_eq!(cf.items(), full.unwrap() as usize);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::hashing::{mix, stable_hash};
+use crate::types::Value;
+use std::convert::TryFrom;
+
+/// A counter shared by whichever item last claimed it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct Bucket {
+    fingerprint: u32,
+    count: u64,
+}
+
+/// Tracks the `k` most frequent items of a stream (HeavyKeeper).
+///
+/// Each item has one bucket per row, picked by its hash. A bucket counts the
+/// item whose fingerprint it holds; other items decay its count with
+/// probability `decay^count`, and take it over once it reaches zero. So
+/// frequent items keep their buckets, and the largest count of an item's
+/// buckets estimates how often it was seen. The `k` items with the largest
+/// estimates so far are kept.
+///
+/// Decays are decided by a generator kept with the filter, so the same adds
+/// in the same order leave replicas with the same counts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "StoredTopK")]
+pub struct TopK {
+    k: usize,
+    width: usize,
+    depth: usize,
+    decay: f64,
+    /// `depth` rows of `width` buckets.
+    buckets: Vec<Bucket>,
+    /// The top items so far with their estimates, at most `k`.
+    top: Vec<(Value, u64)>,
+    rng: u64,
+}
+
+#[derive(Deserialize)]
+struct StoredTopK {
+    k: usize,
+    width: usize,
+    depth: usize,
+    decay: f64,
+    buckets: Vec<Bucket>,
+    top: Vec<(Value, u64)>,
+    rng: u64,
+}
+
+impl TryFrom<StoredTopK> for TopK {
+    type Error = &'static str;
+
+    /// Refuse trackers hashing into buckets they don't have.
+    fn try_from(stored: StoredTopK) -> Result<TopK, Self::Error> {
+        if stored.width == 0 || stored.width.checked_mul(stored.depth) != Some(stored.buckets.len())
+        {
+            return Err("top-k buckets don't match its width and depth");
+        }
+        if stored.top.len() > stored.k {
+            return Err("top-k holds more than k items");
+        }
+        Ok(TopK {
+            k: stored.k,
+            width: stored.width,
+            depth: stored.depth,
+            decay: stored.decay,
+            buckets: stored.buckets,
+            top: stored.top,
+            rng: stored.rng,
+        })
+    }
+}
+
+/// The bytes a tracker of these dimensions allocates up front, or None if
+/// that doesn't fit in a usize.
+pub fn size_for(k: usize, width: usize, depth: usize) -> Option<usize> {
+    let buckets = width
+        .checked_mul(depth)?
+        .checked_mul(std::mem::size_of::<Bucket>())?;
+    let top = k.checked_mul(std::mem::size_of::<(Value, u64)>())?;
+    buckets.checked_add(top)
+}
+
+impl TopK {
+    /// Callers check the size with `size_for` first.
+    pub fn new(k: usize, width: usize, depth: usize, decay: f64) -> TopK {
+        let buckets = width
+            .checked_mul(depth)
+            .expect("top-k sizes are checked when parsed");
+        TopK {
+            k,
+            width,
+            depth,
+            decay,
+            buckets: vec![Bucket::default(); buckets],
+            top: Vec::with_capacity(k),
+            rng: 0,
+        }
+    }
+
+    /// `item`'s fingerprint and the index of its bucket in each row.
+    fn locate(&self, item: &[u8]) -> (u32, impl Iterator<Item = usize>) {
+        let (hash, width) = (stable_hash(item), self.width);
+        let fingerprint = (mix(hash) >> 32) as u32;
+        let buckets = (0..self.depth).map(move |row| {
+            row * width + (mix(hash.wrapping_add(row as u64 + 1)) % width as u64) as usize
+        });
+        (fingerprint, buckets)
+    }
+
+    /// A number in [0, 1), from splitmix64.
+    fn next_random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        (mix(self.rng) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Count `item` once. Returns the item it pushed out of the top `k`,
+    /// if any.
+    pub fn add(&mut self, item: &Value) -> Option<Value> {
+        let (fingerprint, cells) = self.locate(item);
+        let mut estimate = 0;
+        for cell in cells {
+            let bucket = self.buckets[cell];
+            if bucket.count == 0 || bucket.fingerprint == fingerprint {
+                self.buckets[cell] = Bucket {
+                    fingerprint,
+                    count: bucket.count + 1,
+                };
+            } else if self.next_random() < self.decay.powf(bucket.count as f64) {
+                self.buckets[cell].count -= 1;
+                if self.buckets[cell].count == 0 {
+                    self.buckets[cell] = Bucket {
+                        fingerprint,
+                        count: 1,
+                    };
+                }
+            }
+            let bucket = self.buckets[cell];
+            if bucket.fingerprint == fingerprint {
+                estimate = estimate.max(bucket.count);
+            }
+        }
+        self.promote(item, estimate)
+    }
+
+    /// Update the top items now `item` is estimated at `estimate`.
+    fn promote(&mut self, item: &Value, estimate: u64) -> Option<Value> {
+        if let Some(top) = self.top.iter_mut().find(|(top, _)| top == item) {
+            top.1 = top.1.max(estimate);
+            return None;
+        }
+        if self.top.len() < self.k {
+            self.top.push((item.clone(), estimate));
+            return None;
+        }
+        let (min, _) = self
+            .top
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (top, count))| (*count, top.clone()))?;
+        if estimate <= self.top[min].1 {
+            return None;
+        }
+        let (expelled, _) = std::mem::replace(&mut self.top[min], (item.clone(), estimate));
+        Some(expelled)
+    }
+
+    /// Whether `item` is one of the top `k` so far.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.top.iter().any(|(top, _)| top == item)
+    }
+
+    /// How many times `item` was seen, estimated by its buckets.
+    pub fn count(&self, item: &[u8]) -> u64 {
+        let (fingerprint, cells) = self.locate(item);
+        cells
+            .map(|cell| self.buckets[cell])
+            .filter(|bucket| bucket.fingerprint == fingerprint)
+            .map(|bucket| bucket.count)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The top items with their estimates, most frequent first.
+    pub fn list(&self) -> Vec<(Value, u64)> {
+        let mut top = self.top.clone();
+        top.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+        top
+    }
+
+    /// Bytes of buckets and top items.
+    pub fn size(&self) -> usize {
+        self.buckets.len() * std::mem::size_of::<Bucket>()
+            + self
+                .top
+                .iter()
+                .map(|(item, _)| item.len() + std::mem::size_of::<(Value, u64)>())
+                .sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod test_heavy_keeper {
+    use crate::data_structures::heavy_keeper::TopK;
+    use crate::types::Value;
+
+    fn item(i: u32) -> Value {
+        Value::from(format!("item{}", i))
+    }
+
+    #[test]
+    fn test_finds_heavy_hitters() {
+        let mut topk = TopK::new(3, 50, 4, 0.9);
+        // item0 is seen 200 times, item1 150 and item2 100, among 500
+        // items seen 5 times each.
+        for round in 0..200 {
+            for heavy in 0..3 {
+                if round < 200 - 50 * heavy {
+                    topk.add(&item(heavy));
+                }
+            }
+            if round < 5 {
+                (3..503).for_each(|light| {
+                    topk.add(&item(light));
+                });
+            }
+        }
+        let listed: Vec<Value> = topk.list().into_iter().map(|(item, _)| item).collect();
+        assert_eq!(listed, vec![item(0), item(1), item(2)]);
+        assert!(topk.contains(&item(0)) && !topk.contains(&item(3)));
+        assert!(topk.count(&item(0)) <= 200 && topk.count(&item(0)) > 150);
+    }
+
+    #[test]
+    fn test_expels_the_smallest() {
+        let mut topk = TopK::new(1, 10, 2, 0.9);
+        assert_eq!(topk.add(&item(0)), None);
+        assert_eq!(topk.add(&item(1)), None);
+        assert_eq!(topk.add(&item(1)), Some(item(0)));
+        assert_eq!(topk.list(), vec![(item(1), 2)]);
+    }
+}
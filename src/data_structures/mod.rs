@@ -1,3 +1,11 @@
+pub mod bloom_filter;
+pub mod count_min_sketch;
+pub mod cuckoo_filter;
+pub mod heavy_keeper;
+pub mod negative_cache;
+pub mod queue;
 pub mod receipt_map;
+pub mod skiplist;
 pub mod sorted_set;
 pub mod stack;
+pub mod stream;
@@ -0,0 +1,151 @@
+use growable_bloom_filter::GrowableBloom;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// False positive rate of the underlying bloom filter.
+const NEGATIVE_CACHE_ERROR_RATE: f64 = 0.001;
+
+#[derive(Default)]
+struct Inner {
+    bloom: Option<GrowableBloom>,
+    len: usize,
+    generation: u64,
+}
+
+/// Remembers keys that recently missed, so repeated misses can skip the keyspace.
+///
+/// It's backed by a bloom filter, so a false positive can make an existing key
+/// read as missing (with odds bounded by NEGATIVE_CACHE_ERROR_RATE). It's opt-in for that reason.
+/// The filter is reset once `capacity` misses are remembered, or when a remembered key is written.
+#[derive(Default)]
+pub struct NegativeCache {
+    capacity: AtomicUsize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl NegativeCache {
+    /// Set the number of misses to remember. Zero disables the cache.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::SeqCst);
+        let mut inner = self.inner.lock();
+        inner.bloom = None;
+        inner.len = 0;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity.load(Ordering::Relaxed) != 0
+    }
+
+    /// Token to pass to `insert` after a lookup misses. Writes in between
+    /// change the generation, so a racing write can't be cached as a miss.
+    pub fn generation(&self) -> u64 {
+        if !self.is_enabled() {
+            return 0;
+        }
+        self.inner.lock().generation
+    }
+
+    /// Check whether the key is known to be missing. Counts hits and misses.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let hit = self
+            .inner
+            .lock()
+            .bloom
+            .as_ref()
+            .map_or(false, |bloom| bloom.contains(key));
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+
+    /// Remember that `key` was missing as of `generation`.
+    pub fn insert(&self, key: &[u8], generation: u64) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        if inner.generation != generation {
+            return;
+        }
+        if inner.bloom.is_none() || inner.len >= capacity {
+            inner.bloom = Some(GrowableBloom::new(NEGATIVE_CACHE_ERROR_RATE, capacity));
+            inner.len = 0;
+        }
+        if let Some(bloom) = inner.bloom.as_mut() {
+            bloom.insert(key);
+        }
+        inner.len += 1;
+    }
+
+    /// Forget the given keys after they were written.
+    pub fn invalidate(&self, keys: &[impl AsRef<[u8]>]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        let remembered = inner.bloom.as_ref().map_or(false, |bloom| {
+            keys.iter().any(|key| bloom.contains(key.as_ref()))
+        });
+        if remembered {
+            inner.bloom = None;
+            inner.len = 0;
+        }
+    }
+
+    /// Lookups answered by the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups that had to go to the keyspace.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test_negative_cache {
+    use crate::data_structures::negative_cache::NegativeCache;
+
+    #[test]
+    fn test_disabled() {
+        let cache = NegativeCache::default();
+        cache.insert(b"foo", cache.generation());
+        assert!(!cache.contains(b"foo"));
+    }
+
+    #[test]
+    fn test_insert_and_invalidate() {
+        let cache = NegativeCache::default();
+        cache.set_capacity(10);
+        assert!(!cache.contains(b"foo"));
+        cache.insert(b"foo", cache.generation());
+        assert!(cache.contains(b"foo"));
+        cache.invalidate(&[b"foo"]);
+        assert!(!cache.contains(b"foo"));
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[test]
+    fn test_racing_write_not_cached() {
+        let cache = NegativeCache::default();
+        cache.set_capacity(10);
+        cache.insert(b"bar", cache.generation());
+        let generation = cache.generation();
+        // A write lands between the lookup and the insert.
+        cache.invalidate(&[b"foo"]);
+        cache.insert(b"foo", generation);
+        assert!(!cache.contains(b"foo"));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::types::Value;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::mem::size_of;
+
+/// Jobs are numbered from 1 in the order they were pushed.
+pub type JobId = u64;
+
+/// A job queue with acknowledgements.
+///
+/// Popping a job hands it out until a deadline rather than removing it. It's
+/// only gone once acked; jobs still pending at their deadline go back to the
+/// front of the queue, to be handed out again.
+///
+/// Pending jobs are also indexed by deadline, so finding those due back
+/// doesn't go through them all. The index isn't stored: it's rebuilt from
+/// the pending jobs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredQueue")]
+pub struct Queue {
+    ready: VecDeque<(JobId, Value)>,
+    /// Handed out and not yet acked, with the unix ms they're due back at.
+    pending: BTreeMap<JobId, (Value, u64)>,
+    last_id: JobId,
+    #[serde(skip)]
+    deadlines: BTreeSet<(u64, JobId)>,
+}
+
+#[derive(Deserialize)]
+struct StoredQueue {
+    ready: VecDeque<(JobId, Value)>,
+    pending: BTreeMap<JobId, (Value, u64)>,
+    last_id: JobId,
+}
+
+impl From<StoredQueue> for Queue {
+    fn from(stored: StoredQueue) -> Queue {
+        let deadlines = stored
+            .pending
+            .iter()
+            .map(|(id, (_, deadline))| (*deadline, *id))
+            .collect();
+        Queue {
+            ready: stored.ready,
+            pending: stored.pending,
+            last_id: stored.last_id,
+            deadlines,
+        }
+    }
+}
+
+impl Queue {
+    /// Add a job to the back of the queue.
+    pub fn push(&mut self, value: Value) -> JobId {
+        self.last_id += 1;
+        self.ready.push_back((self.last_id, value));
+        self.last_id
+    }
+
+    /// Put pending jobs due back by `now` at the front of the queue, oldest
+    /// first. Returns how many there were.
+    pub fn requeue_expired(&mut self, now: u64) -> usize {
+        let due = self.deadlines.range(..(now.saturating_add(1), 0));
+        let mut expired: Vec<(u64, JobId)> = due.copied().collect();
+        expired.sort_unstable_by_key(|(_, id)| *id);
+        for (deadline, id) in expired.iter().rev() {
+            self.deadlines.remove(&(*deadline, *id));
+            if let Some((value, _)) = self.pending.remove(id) {
+                self.ready.push_front((*id, value));
+            }
+        }
+        expired.len()
+    }
+
+    /// Hand out the next job, to be acked within `visibility_ms`.
+    pub fn pop(&mut self, now: u64, visibility_ms: u64) -> Option<(JobId, Value)> {
+        self.requeue_expired(now);
+        let (id, value) = self.ready.pop_front()?;
+        self.hand_out(id, value.clone(), now.saturating_add(visibility_ms));
+        Some((id, value))
+    }
+
+    fn hand_out(&mut self, id: JobId, value: Value, deadline: u64) {
+        self.pending.insert(id, (value, deadline));
+        self.deadlines.insert((deadline, id));
+    }
+
+    /// Hand out job `id` until `deadline`, whether it's ready or pending.
+    /// Returns false if it was acked. QPOPs are logged as this, so a replay
+    /// hands out the same job until the same time.
+    pub fn claim(&mut self, id: JobId, deadline: u64) -> bool {
+        let value = match self.pending.remove(&id) {
+            Some((value, due)) => {
+                self.deadlines.remove(&(due, id));
+                value
+            }
+            // Popped jobs were at the front.
+            None => match self.ready.iter().position(|(job, _)| *job == id) {
+                Some(at) => self.ready.remove(at).expect("position is in the queue").1,
+                None => return false,
+            },
+        };
+        self.hand_out(id, value, deadline);
+        true
+    }
+
+    /// Finish a pending job. Returns false if `id` isn't pending.
+    pub fn ack(&mut self, id: JobId) -> bool {
+        match self.pending.remove(&id) {
+            Some((_, deadline)) => self.deadlines.remove(&(deadline, id)),
+            None => false,
+        }
+    }
+
+    /// When pending job `id` is due back.
+    pub fn deadline(&self, id: JobId) -> Option<u64> {
+        self.pending.get(&id).map(|(_, deadline)| *deadline)
+    }
+
+    /// Jobs waiting to be handed out.
+    pub fn len(&self) -> usize {
+        self.ready.len()
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// When the first pending job is due back, if any are pending.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.deadlines.iter().next().map(|(deadline, _)| *deadline)
+    }
+
+    /// True once every job was acked.
+    pub fn is_empty(&self) -> bool {
+        self.ready.is_empty() && self.pending.is_empty()
+    }
+
+    /// Every job, ready or pending, with its value.
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.ready
+            .iter()
+            .map(|(_, value)| value)
+            .chain(self.pending.values().map(|(value, _)| value))
+    }
+
+    /// Bytes of every job's value and the slots holding them, spare ready
+    /// slots included.
+    pub fn size(&self) -> usize {
+        let values: usize = self.values().map(|value| value.len()).sum();
+        values
+            + self.ready.capacity() * size_of::<(JobId, Value)>()
+            + self.pending.len() * size_of::<(JobId, (Value, u64))>()
+            + self.deadlines.len() * size_of::<(u64, JobId)>()
+    }
+}
+
+#[cfg(test)]
+mod test_queue {
+    use crate::data_structures::queue::Queue;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_pop_ack_requeue() {
+        let mut queue = Queue::default();
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        assert_eq!(queue.push(a.clone()), 1);
+        assert_eq!(queue.push(b.clone()), 2);
+        assert_eq!(queue.pop(100, 50), Some((1, a.clone())));
+        assert_eq!(queue.pop(100, 10), Some((2, b.clone())));
+        assert_eq!(queue.pop(100, 10), None);
+        assert_eq!((queue.len(), queue.pending_len()), (0, 2));
+
+        // Job 2 is due back at 110, job 1 at 150.
+        assert!(queue.ack(2));
+        assert!(!queue.ack(2));
+        assert_eq!(queue.pop(149, 10), None);
+        assert_eq!(queue.pop(150, 10), Some((1, a)));
+        assert!(queue.ack(1));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_requeued_jobs_go_first() {
+        let mut queue = Queue::default();
+        for value in ["a", "b", "c"].iter() {
+            queue.push(Bytes::from_static(value.as_bytes()));
+        }
+        queue.pop(0, 10);
+        queue.pop(0, 10);
+        assert_eq!(queue.requeue_expired(10), 2);
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop(10, 10).map(|(id, _)| id)).collect();
+        assert_eq!(order, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_claim() {
+        let mut queue = Queue::default();
+        for value in ["a", "b", "c"].iter() {
+            queue.push(Bytes::from_static(value.as_bytes()));
+        }
+        // Straight from the ready jobs, or moving a pending job's deadline.
+        assert!(queue.claim(2, 50));
+        assert!(queue.claim(2, 30));
+        assert_eq!(queue.deadline(2), Some(30));
+        assert_eq!(queue.next_deadline(), Some(30));
+        assert_eq!(queue.pop(0, 100).map(|(id, _)| id), Some(1));
+        assert!(queue.ack(2));
+        assert!(!queue.claim(2, 30));
+        assert_eq!(queue.next_deadline(), Some(100));
+        assert_eq!(queue.requeue_expired(99), 0);
+        assert_eq!(queue.requeue_expired(100), 1);
+        assert_eq!((queue.len(), queue.pending_len()), (2, 0));
+    }
+
+    #[test]
+    fn test_deadlines_rebuilt() {
+        let mut queue = Queue::default();
+        queue.push(Bytes::from_static(b"a"));
+        queue.push(Bytes::from_static(b"b"));
+        queue.pop(0, 10);
+        let stored = rmps::to_vec(&queue).unwrap();
+        let loaded: Queue = rmps::from_slice(&stored).unwrap();
+        assert_eq!(loaded, queue);
+        assert_eq!(loaded.next_deadline(), Some(10));
+    }
+}
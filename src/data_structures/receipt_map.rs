@@ -1,26 +1,39 @@
-use seahash::hash;
+use crate::types::Key;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::task::Waker;
 
 pub type Receipt = u32;
 
-#[derive(Hash, Debug, PartialEq, Eq)]
+/// What a blocked client is waiting on. Holds the key itself, so a write to
+/// one key never wakes clients waiting on another.
+#[derive(Hash, Debug, Clone, PartialEq, Eq)]
 pub enum KeyTypes {
-    List(u64),
+    List(Key),
+    Stream(Key),
+    Queue(Key),
 }
 
 impl KeyTypes {
     pub fn list(key: &[u8]) -> KeyTypes {
-        KeyTypes::List(hash(key))
+        KeyTypes::List(Key::copy_from_slice(key))
+    }
+
+    pub fn stream(key: &[u8]) -> KeyTypes {
+        KeyTypes::Stream(Key::copy_from_slice(key))
+    }
+
+    pub fn queue(key: &[u8]) -> KeyTypes {
+        KeyTypes::Queue(Key::copy_from_slice(key))
     }
 }
 
+/// Who waits on which keys. A receipt is forgotten by every key it waits
+/// on as soon as it's woken, or `remove`d when its client stops waiting.
 #[derive(Default, Debug)]
 pub struct RecieptMap {
     counter: Receipt,
-    wakers: HashMap<Receipt, Waker>,
-    timed_out: HashSet<Receipt>,
+    /// The waker of each waiting receipt, and the keys it waits on.
+    waiting: HashMap<Receipt, (Waker, Vec<KeyTypes>)>,
     keys: HashMap<KeyTypes, Vec<Receipt>>,
 }
 
@@ -30,36 +43,127 @@ impl RecieptMap {
         self.counter
     }
 
+    /// Wake `item` on the next write to `key`. A receipt may wait on several
+    /// keys, and is only woken once.
     pub fn insert(&mut self, receipt: Receipt, item: Waker, key: KeyTypes) {
-        self.wakers.insert(receipt, item);
+        let (waker, keys) = self
+            .waiting
+            .entry(receipt)
+            .or_insert_with(|| (item.clone(), Vec::new()));
+        *waker = item;
+        // Polling again re-registers on every key; don't queue twice.
+        if keys.contains(&key) {
+            return;
+        }
+        keys.push(key.clone());
         self.keys.entry(key).or_default().push(receipt);
     }
 
-    pub fn receipt_timed_out(&self, receipt: Receipt) -> bool {
-        self.timed_out.contains(&receipt)
+    /// Stop `receipt` waiting on any key, returning its waker.
+    pub fn remove(&mut self, receipt: Receipt) -> Option<Waker> {
+        let (waker, keys) = self.waiting.remove(&receipt)?;
+        for key in keys {
+            if let Some(receipts) = self.keys.get_mut(&key) {
+                receipts.retain(|r| *r != receipt);
+                if receipts.is_empty() {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+        Some(waker)
     }
 
     pub fn wake_with_key(&mut self, key: KeyTypes) {
-        let v = self.keys.get_mut(&key);
-        if v.is_none() {
-            return;
+        let receipt = match self.keys.get(&key).and_then(|v| v.last()) {
+            Some(receipt) => *receipt,
+            None => return,
+        };
+        if let Some(waker) = self.remove(receipt) {
+            waker.wake();
         }
-        let v = v.unwrap();
-        while let Some(receipt) = v.pop() {
-            match self.wakers.remove(&receipt) {
-                Some(waker) => {
-                    waker.wake();
-                    break;
-                }
-                None => continue,
-            };
+    }
+
+    /// Wake everyone waiting on `key`, for writes that every waiter can see
+    /// (like a stream entry), rather than one of them can take.
+    pub fn wake_all_with_key(&mut self, key: KeyTypes) {
+        for receipt in self.keys.remove(&key).unwrap_or_default() {
+            if let Some(waker) = self.remove(receipt) {
+                waker.wake();
+            }
         }
     }
+}
 
-    pub fn timeout_receipt(&mut self, receipt: Receipt) {
-        self.timed_out.insert(receipt);
-        if let Some(waker) = self.wakers.remove(&receipt) {
-            waker.wake();
+#[cfg(test)]
+mod test_receipt_map {
+    use super::{KeyTypes, RecieptMap};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
         }
     }
+
+    #[test]
+    fn test_wakes_only_the_written_key() {
+        let mut rm = RecieptMap::default();
+        let (a, b) = (
+            Arc::new(CountingWaker::default()),
+            Arc::new(CountingWaker::default()),
+        );
+        let receipt = rm.get_receipt();
+        rm.insert(receipt, Waker::from(a.clone()), KeyTypes::list(b"a"));
+        let receipt = rm.get_receipt();
+        rm.insert(receipt, Waker::from(b.clone()), KeyTypes::list(b"b"));
+
+        rm.wake_with_key(KeyTypes::list(b"b"));
+        assert_eq!(a.0.load(Ordering::SeqCst), 0);
+        assert_eq!(b.0.load(Ordering::SeqCst), 1);
+        // Nobody is left waiting on "b".
+        rm.wake_with_key(KeyTypes::list(b"b"));
+        assert_eq!(b.0.load(Ordering::SeqCst), 1);
+        assert!(!rm.keys.contains_key(&KeyTypes::list(b"b")));
+    }
+
+    #[test]
+    fn test_wake_all() {
+        let mut rm = RecieptMap::default();
+        let wakers: Vec<_> = (0..3).map(|_| Arc::new(CountingWaker::default())).collect();
+        for waker in &wakers {
+            let receipt = rm.get_receipt();
+            rm.insert(receipt, Waker::from(waker.clone()), KeyTypes::stream(b"s"));
+        }
+        // Lists and streams don't share waiters, even on the same key.
+        rm.wake_all_with_key(KeyTypes::list(b"s"));
+        assert!(wakers.iter().all(|w| w.0.load(Ordering::SeqCst) == 0));
+        rm.wake_all_with_key(KeyTypes::stream(b"s"));
+        assert!(wakers.iter().all(|w| w.0.load(Ordering::SeqCst) == 1));
+        assert!(rm.keys.is_empty());
+    }
+
+    #[test]
+    fn test_receipts_leave_every_key() {
+        let mut rm = RecieptMap::default();
+        let waker = Arc::new(CountingWaker::default());
+        let receipt = rm.get_receipt();
+        for key in [b"a", b"b"].iter() {
+            rm.insert(receipt, Waker::from(waker.clone()), KeyTypes::list(*key));
+        }
+        rm.wake_with_key(KeyTypes::list(b"a"));
+        assert_eq!(waker.0.load(Ordering::SeqCst), 1);
+        assert!(rm.keys.is_empty() && rm.waiting.is_empty());
+
+        // Clients that stop waiting, say on a timeout, are forgotten too.
+        let receipt = rm.get_receipt();
+        rm.insert(receipt, Waker::from(waker.clone()), KeyTypes::queue(b"q"));
+        assert!(rm.remove(receipt).is_some());
+        assert!(rm.keys.is_empty() && rm.waiting.is_empty());
+        assert!(rm.remove(receipt).is_none());
+    }
 }
@@ -0,0 +1,411 @@
+//! An indexable skiplist, like the one redis keeps sorted sets in.
+//!
+//! Every link records how many elements it skips over (its span), so finding
+//! the element at a rank, or the rank of an element, is O(log n) like inserts
+//! and removals are. Nodes live in one arena and link to each other by index.
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+
+const MAX_LEVEL: usize = 32;
+
+type NodeId = usize;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Link {
+    /// None past the last element.
+    next: Option<NodeId>,
+    /// Elements moved over by following this link, counting `next` itself.
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    value: T,
+    prev: Option<NodeId>,
+    links: Vec<Link>,
+}
+
+/// Elements kept in order, each at most once.
+#[derive(Debug, Clone)]
+pub struct SkipList<T> {
+    /// Links out of the head, which comes before every element.
+    head: Vec<Link>,
+    /// Levels in use.
+    level: usize,
+    nodes: Vec<Option<Node<T>>>,
+    /// Free slots in `nodes`.
+    free: Vec<NodeId>,
+    tail: Option<NodeId>,
+    len: usize,
+}
+
+impl<T> Default for SkipList<T> {
+    fn default() -> Self {
+        SkipList {
+            head: vec![Link::default(); MAX_LEVEL],
+            level: 1,
+            nodes: Vec::new(),
+            free: Vec::new(),
+            tail: None,
+            len: 0,
+        }
+    }
+}
+
+/// Each level up holds a quarter of the elements of the one below.
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && rand::random::<u32>() % 4 == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl<T: Ord> SkipList<T> {
+    pub fn new() -> Self {
+        SkipList::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn node(&self, id: NodeId) -> &Node<T> {
+        self.nodes[id].as_ref().expect("linked nodes are live")
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node<T> {
+        self.nodes[id].as_mut().expect("linked nodes are live")
+    }
+
+    /// The links out of `at`, or out of the head if it's None.
+    fn links(&self, at: Option<NodeId>) -> &[Link] {
+        match at {
+            Some(id) => &self.node(id).links,
+            None => &self.head,
+        }
+    }
+
+    fn links_mut(&mut self, at: Option<NodeId>) -> &mut [Link] {
+        match at {
+            Some(id) => &mut self.node_mut(id).links,
+            None => &mut self.head,
+        }
+    }
+
+    fn alloc(&mut self, node: Node<T>) -> NodeId {
+        match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = Some(node);
+                id
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// The last node at each level before the first element not less than
+    /// `value`, and the rank of each.
+    fn predecessors(&self, value: &T) -> ([Option<NodeId>; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [None; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut at = None;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.links(at)[i].next {
+                if self.node(next).value >= *value {
+                    break;
+                }
+                rank[i] += self.links(at)[i].span;
+                at = Some(next);
+            }
+            update[i] = at;
+        }
+        (update, rank)
+    }
+
+    /// Add `value`, which must not be in the list already.
+    pub fn insert(&mut self, value: T) {
+        let (mut update, mut rank) = self.predecessors(&value);
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = None;
+                self.head[i].span = self.len;
+            }
+            self.level = level;
+        }
+        let id = self.alloc(Node {
+            value,
+            prev: update[0],
+            links: vec![Link::default(); level],
+        });
+        for i in 0..level {
+            let before = self.links(update[i])[i];
+            let skipped = rank[0] - rank[i];
+            self.node_mut(id).links[i] = Link {
+                next: before.next,
+                span: before.span - skipped,
+            };
+            self.links_mut(update[i])[i] = Link {
+                next: Some(id),
+                span: skipped + 1,
+            };
+        }
+        for i in level..self.level {
+            self.links_mut(update[i])[i].span += 1;
+        }
+        match self.node(id).links[0].next {
+            Some(next) => self.node_mut(next).prev = Some(id),
+            None => self.tail = Some(id),
+        }
+        self.len += 1;
+    }
+
+    /// Remove `value`, returning it if it was in the list.
+    pub fn remove(&mut self, value: &T) -> Option<T> {
+        let (update, _) = self.predecessors(value);
+        let id = self.links(update[0])[0].next?;
+        if self.node(id).value != *value {
+            return None;
+        }
+        for i in 0..self.level {
+            let link = self.links(update[i])[i];
+            if link.next == Some(id) {
+                let removed = self.node(id).links[i];
+                self.links_mut(update[i])[i] = Link {
+                    next: removed.next,
+                    span: link.span + removed.span - 1,
+                };
+            } else {
+                self.links_mut(update[i])[i].span -= 1;
+            }
+        }
+        let node = self.nodes[id].take().expect("linked nodes are live");
+        match node.links[0].next {
+            Some(next) => self.node_mut(next).prev = node.prev,
+            None => self.tail = node.prev,
+        }
+        while self.level > 1 && self.head[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+        self.free.push(id);
+        self.len -= 1;
+        Some(node.value)
+    }
+
+    /// The rank of `value`, starting from 0, if it's in the list.
+    pub fn rank(&self, value: &T) -> Option<usize> {
+        let (update, rank) = self.predecessors(value);
+        match self.links(update[0])[0].next {
+            Some(id) if self.node(id).value == *value => Some(rank[0]),
+            _ => None,
+        }
+    }
+
+    /// How many elements from the start `pred` holds for, which must be
+    /// every element before some point and none after it.
+    pub fn count_while<F: Fn(&T) -> bool>(&self, pred: F) -> usize {
+        let mut count = 0;
+        let mut at = None;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.links(at)[i].next {
+                if !pred(&self.node(next).value) {
+                    break;
+                }
+                count += self.links(at)[i].span;
+                at = Some(next);
+            }
+        }
+        count
+    }
+
+    /// The node ranked `rank`, starting from 0.
+    fn node_at(&self, rank: usize) -> Option<NodeId> {
+        if rank >= self.len {
+            return None;
+        }
+        // The head is at position 0, so the node is at rank + 1.
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut at = None;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.links(at)[i].next {
+                let span = self.links(at)[i].span;
+                if traversed + span > target {
+                    break;
+                }
+                traversed += span;
+                at = Some(next);
+            }
+            if traversed == target {
+                return at;
+            }
+        }
+        None
+    }
+
+    /// Iterate in order.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            at: self.head[0].next,
+            rev: false,
+        }
+    }
+
+    /// Iterate in reverse order.
+    pub fn iter_rev(&self) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            at: self.tail,
+            rev: true,
+        }
+    }
+
+    /// Iterate in order, from the first element not less than `value`.
+    pub fn iter_from(&self, value: &T) -> Iter<'_, T> {
+        let (update, _) = self.predecessors(value);
+        Iter {
+            list: self,
+            at: self.links(update[0])[0].next,
+            rev: false,
+        }
+    }
+
+    /// Iterate in order, from the element ranked `rank`.
+    pub fn iter_at(&self, rank: usize) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            at: self.node_at(rank),
+            rev: false,
+        }
+    }
+
+    /// Iterate in reverse order, from the element ranked `rank`.
+    pub fn iter_rev_at(&self, rank: usize) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            at: self.node_at(rank),
+            rev: true,
+        }
+    }
+}
+
+pub struct Iter<'a, T> {
+    list: &'a SkipList<T>,
+    at: Option<NodeId>,
+    rev: bool,
+}
+
+impl<'a, T: Ord> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let node = self.list.node(self.at?);
+        self.at = if self.rev {
+            node.prev
+        } else {
+            node.links[0].next
+        };
+        Some(&node.value)
+    }
+}
+
+// Stored as a plain sequence, like the BTreeSet sorted sets used to be kept
+// in, so older dumps still load.
+impl<T: Ord + Serialize> Serialize for SkipList<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Up front, as formats like bincode need the length first.
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for value in self.iter() {
+            seq.serialize_element(value)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, T: Ord + Deserialize<'de>> Deserialize<'de> for SkipList<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut list = SkipList::new();
+        for value in Vec::<T>::deserialize(deserializer)? {
+            list.insert(value);
+        }
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod test_skiplist {
+    use super::SkipList;
+
+    #[test]
+    fn test_insert_remove_and_rank() {
+        let mut list = SkipList::new();
+        for value in (0..200).rev() {
+            list.insert(value * 2);
+        }
+        assert_eq!(list.len(), 200);
+        assert!(list.iter().cloned().eq((0..200).map(|v| v * 2)));
+        assert_eq!(list.rank(&0), Some(0));
+        assert_eq!(list.rank(&100), Some(50));
+        assert_eq!(list.rank(&101), None);
+        for value in (0..200).filter(|v| v % 3 == 0) {
+            assert_eq!(list.remove(&(value * 2)), Some(value * 2));
+        }
+        assert_eq!(list.remove(&0), None);
+        let left: Vec<i32> = (0..200).filter(|v| v % 3 != 0).map(|v| v * 2).collect();
+        assert!(list.iter().cloned().eq(left.iter().cloned()));
+        assert!(list.iter_rev().cloned().eq(left.iter().rev().cloned()));
+        for (rank, value) in left.iter().enumerate() {
+            assert_eq!(list.rank(value), Some(rank));
+            assert_eq!(list.iter_at(rank).next(), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let mut list = SkipList::new();
+        for value in &[5, 1, 3] {
+            list.insert(*value);
+        }
+        assert!(list.iter_from(&2).cloned().eq(vec![3, 5]));
+        assert!(list.iter_from(&3).cloned().eq(vec![3, 5]));
+        assert_eq!(list.iter_from(&6).next(), None);
+        assert!(list.iter_rev_at(1).cloned().eq(vec![3, 1]));
+        assert_eq!(list.iter_at(3).next(), None);
+    }
+
+    #[test]
+    fn test_count_while() {
+        let mut list = SkipList::new();
+        for value in (0..500).rev() {
+            list.insert(value);
+        }
+        assert_eq!(list.count_while(|v| *v < 0), 0);
+        assert_eq!(list.count_while(|v| *v < 137), 137);
+        assert_eq!(list.count_while(|v| *v <= 137), 138);
+        assert_eq!(list.count_while(|_| true), 500);
+    }
+
+    #[test]
+    fn test_serde_matches_btreeset() {
+        let mut list = SkipList::new();
+        for value in &[5, 1, 3] {
+            list.insert(*value);
+        }
+        let set: std::collections::BTreeSet<i32> = [5, 1, 3].iter().cloned().collect();
+        let encoded = bincode::serialize(&list).unwrap();
+        assert_eq!(encoded, bincode::serialize(&set).unwrap());
+        let decoded: SkipList<i32> = bincode::deserialize(&encoded).unwrap();
+        assert!(decoded.iter().eq(list.iter()));
+    }
+}
@@ -1,20 +1,21 @@
 // Clippy does not like SortedSet. TODO: Figure out if we can fix this.
 #![allow(clippy::mutable_key_type)]
 
+use crate::data_structures::skiplist::SkipList;
 use crate::ops::RVec;
 use crate::types::{Count, Index, Key, Score};
 use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
-use std::collections::{BTreeSet, HashMap};
-
-// TODO: Use convenient-skiplist
+use std::collections::HashMap;
+use std::ops::{Bound, Range};
 
 // TODO: Why doesn't this actually allow it?
 #[allow(clippy::mutable_key_type)]
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct SortedSetMember {
     pub score: Score,
-    pub member: String,
+    /// Raw member bytes. Ties in score are ordered by these, like redis does.
+    pub member: Key,
 }
 
 impl PartialOrd<SortedSetMember> for SortedSetMember {
@@ -79,15 +80,58 @@ impl SortedSetMember {
     fn new(key: &[u8], score: Score) -> Self {
         SortedSetMember {
             score,
-            member: String::from_utf8_lossy(key).to_string(),
+            member: Key::copy_from_slice(key),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// One end of a ZRANGEBYLEX range: `-`, `+`, `[member` or `(member`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Included(Key),
+    Excluded(Key),
+}
+
+fn above_score(min: &Bound<Score>, score: Score) -> bool {
+    match min {
+        Bound::Included(min) => score >= *min,
+        Bound::Excluded(min) => score > *min,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below_score(max: &Bound<Score>, score: Score) -> bool {
+    match max {
+        Bound::Included(max) => score <= *max,
+        Bound::Excluded(max) => score < *max,
+        Bound::Unbounded => true,
+    }
+}
+
+fn above_lex(min: &LexBound, member: &[u8]) -> bool {
+    match min {
+        LexBound::Min => true,
+        LexBound::Max => false,
+        LexBound::Included(min) => member >= &min[..],
+        LexBound::Excluded(min) => member > &min[..],
+    }
+}
+
+fn below_lex(max: &LexBound, member: &[u8]) -> bool {
+    match max {
+        LexBound::Min => false,
+        LexBound::Max => true,
+        LexBound::Included(max) => member <= &max[..],
+        LexBound::Excluded(max) => member < &max[..],
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct SortedSet {
     members_hash: HashMap<Key, Score>,
-    scores: BTreeSet<SortedSetMember>,
+    scores: SkipList<SortedSetMember>,
 }
 
 #[allow(unused)]
@@ -112,6 +156,16 @@ impl SortedSet {
             .sum()
     }
 
+    /// Set the score of `key`, adding it if it's new. Returns its previous score.
+    pub fn insert(&mut self, key: Key, score: Score) -> Option<Score> {
+        let previous = self.members_hash.insert(key.clone(), score);
+        if let Some(previous) = previous {
+            self.scores.remove(&SortedSetMember::new(&key, previous));
+        }
+        self.scores.insert(SortedSetMember::new(&key, score));
+        previous
+    }
+
     /// Remove the following keys from the sorted set
     pub fn remove(&mut self, keys: &[Key]) -> Count {
         keys.iter()
@@ -130,6 +184,11 @@ impl SortedSet {
         self.members_hash.remove(key);
     }
 
+    /// Iterate over (member, score) pairs, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Score)> {
+        self.members_hash.iter()
+    }
+
     /// Returns the number of members stored in the set.
     pub fn card(&self) -> Count {
         self.members_hash.len() as Count
@@ -142,33 +201,140 @@ impl SortedSet {
 
     /// Get all members between (lower, upper) scores
     pub fn range(&self, range: (Score, Score)) -> RVec<SortedSetMember> {
-        // TODO: Use a more efficient method. I should use a skiplist or an AVL tree.
-        // Another option is to retackle the rangebounds stuff, but the semantics are different.
-        // I want to be able to compare by score AND member when inserting/removing,
-        // but only by score in this case. Need to figure out how to encode that.
-        self.scores
-            .iter()
-            .filter(|mem| range.0 <= mem.score && mem.score <= range.1)
+        self.range_by_score(Bound::Included(range.0), Bound::Included(range.1))
             .cloned()
             .collect()
     }
 
-    /// Remove count (default: 1) maximum members from the sorted set
-    pub fn pop_max(&mut self, count: Count) -> Vec<SortedSetMember> {
-        let count = count as usize; // TODO: What if it's negative?
-        let ret: Vec<SortedSetMember> = self.scores.iter().rev().take(count).cloned().collect();
-        for key in ret.iter().map(|s| s.member.clone()) {
-            self.remove(&[key.into()]);
+    /// Members ranked `start` to `stop`, both inclusive. Negative ranks count
+    /// from the end, like ZRANGE. With `rev`, rank 0 is the highest score.
+    pub fn range_by_rank(&self, start: Index, stop: Index, rev: bool) -> Vec<SortedSetMember> {
+        let len = self.scores.len() as Index;
+        let start = if start < 0 {
+            (start + len).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            stop + len
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return Vec::new();
+        }
+        let take = (stop - start + 1) as usize;
+        if rev {
+            let ranked = self.scores.iter_rev_at((len - 1 - start) as usize);
+            ranked.take(take).cloned().collect()
+        } else {
+            let ranked = self.scores.iter_at(start as usize);
+            ranked.take(take).cloned().collect()
+        }
+    }
+
+    /// Ranks of the members with scores between `min` and `max`.
+    pub fn score_ranks(&self, min: &Bound<Score>, max: &Bound<Score>) -> Range<usize> {
+        let start = self.scores.count_while(|m| !above_score(min, m.score));
+        let end = self.scores.count_while(|m| below_score(max, m.score));
+        start..end.max(start)
+    }
+
+    /// Ranks of the members between `min` and `max` by their bytes. Like
+    /// redis, this assumes every member has the same score.
+    pub fn lex_ranks(&self, min: &LexBound, max: &LexBound) -> Range<usize> {
+        let start = self.scores.count_while(|m| !above_lex(min, &m.member));
+        let end = self.scores.count_while(|m| below_lex(max, &m.member));
+        start..end.max(start)
+    }
+
+    /// The members ranked in `ranks`, lowest first, or highest first with `rev`.
+    pub fn members_in(
+        &self,
+        ranks: Range<usize>,
+        rev: bool,
+    ) -> impl Iterator<Item = &SortedSetMember> {
+        let members = if ranks.is_empty() {
+            self.scores.iter_at(self.scores.len())
+        } else if rev {
+            self.scores.iter_rev_at(ranks.end - 1)
+        } else {
+            self.scores.iter_at(ranks.start)
+        };
+        members.take(ranks.len())
+    }
+
+    /// Members with scores between `min` and `max`, lowest first.
+    pub fn range_by_score(
+        &self,
+        min: Bound<Score>,
+        max: Bound<Score>,
+    ) -> impl Iterator<Item = &SortedSetMember> {
+        self.members_in(self.score_ranks(&min, &max), false)
+    }
+
+    /// Members between `min` and `max` by their bytes, in order.
+    pub fn range_by_lex(
+        &self,
+        min: &LexBound,
+        max: &LexBound,
+    ) -> impl Iterator<Item = &SortedSetMember> {
+        self.members_in(self.lex_ranks(min, max), false)
+    }
+
+    /// Add `by` to the score of `key`, adding it with score `by` if it's new.
+    /// Returns the new score, or None if it would be NaN (like inf + -inf).
+    pub fn incr(&mut self, key: Key, by: Score) -> Option<Score> {
+        let score = match self.score(key.clone()) {
+            Some(current) => Score::new(current.value() + by.value())?,
+            None => by,
+        };
+        self.insert(key, score);
+        Some(score)
+    }
+
+    /// Number of members with scores between `min` and `max`, from the
+    /// ranks of the ends, without walking the members between them.
+    pub fn count_by_score(&self, min: Bound<Score>, max: Bound<Score>) -> Count {
+        self.score_ranks(&min, &max).len() as Count
+    }
+
+    /// Remove members with scores between `min` and `max`. Returns how many were.
+    pub fn remove_range_by_score(&mut self, min: Bound<Score>, max: Bound<Score>) -> Count {
+        let members: Vec<Key> = self
+            .range_by_score(min, max)
+            .map(|m| m.member.clone())
+            .collect();
+        self.remove(&members)
+    }
+
+    /// Remove members ranked `start` to `stop`, like ZRANGE counts them.
+    /// Returns how many were.
+    pub fn remove_range_by_rank(&mut self, start: Index, stop: Index) -> Count {
+        let members: Vec<Key> = self
+            .range_by_rank(start, stop, false)
+            .into_iter()
+            .map(|m| m.member)
+            .collect();
+        self.remove(&members)
+    }
+
+    /// Remove up to count maximum members from the sorted set, highest first
+    pub fn pop_max(&mut self, count: usize) -> Vec<SortedSetMember> {
+        let count = count.min(self.scores.len());
+        let ret: Vec<SortedSetMember> = self.scores.iter_rev().take(count).cloned().collect();
+        for mem in ret.iter() {
+            self.remove(&[mem.member.clone()]);
         }
         ret
     }
 
-    /// Remove count (default: 1) minimum members from the sorted set
-    pub fn pop_min(&mut self, count: Count) -> Vec<SortedSetMember> {
-        let count = count as usize; // TODO: What if it's negative?
+    /// Remove up to count minimum members from the sorted set, lowest first
+    pub fn pop_min(&mut self, count: usize) -> Vec<SortedSetMember> {
+        let count = count.min(self.scores.len());
         let ret: Vec<SortedSetMember> = self.scores.iter().take(count).cloned().collect();
-        for key in ret.iter().map(|s| s.member.clone()) {
-            self.remove(&[key.into()]);
+        for mem in ret.iter() {
+            self.remove(&[mem.member.clone()]);
         }
         ret
     }
@@ -180,26 +346,28 @@ impl SortedSet {
 
     /// Get the rank of a given key in the sorted set
     pub fn rank(&self, key: Key) -> Option<Index> {
+        let score = *self.members_hash.get(&key)?;
         self.scores
-            .iter()
-            .position(|s| s.member.as_bytes() == &*key)
-            .map(|pos| pos as Index)
+            .rank(&SortedSetMember::new(&key, score))
+            .map(|rank| rank as Index)
     }
 }
 
 #[cfg(test)]
 mod test_sorted_sets_ds {
-    use crate::data_structures::sorted_set::{SortedSet, SortedSetMember};
+    use crate::data_structures::sorted_set::{LexBound, SortedSet, SortedSetMember};
     use crate::ops::RVec;
     use crate::types::{Key, Score};
     use bytes::Bytes;
     use smallvec::smallvec;
+    use std::borrow::Borrow;
+    use std::ops::Bound;
 
     fn get_multiple_entries() -> RVec<(Score, Key)> {
         smallvec![
-            (1, Bytes::from_static(b"hi_0")),
-            (3, Bytes::from_static(b"hi_1")),
-            (5, Bytes::from_static(b"hi_2")),
+            (1.into(), Bytes::from_static(b"hi_0")),
+            (3.into(), Bytes::from_static(b"hi_1")),
+            (5.into(), Bytes::from_static(b"hi_2")),
         ]
     }
 
@@ -214,7 +382,7 @@ mod test_sorted_sets_ds {
     #[test]
     fn test_add() {
         let mut ss = SortedSet::new();
-        assert_eq!(1, ss.add(smallvec![(2, Bytes::from_static(b"hi"))]));
+        assert_eq!(1, ss.add(smallvec![(2.into(), Bytes::from_static(b"hi"))]));
         assert_eq!(
             get_multiple_entries().len() as i64,
             ss.add(get_multiple_entries())
@@ -227,20 +395,21 @@ mod test_sorted_sets_ds {
         let mut ss = SortedSet::new();
 
         ss.add(smallvec![
-            (1, Bytes::from_static(b"hi_0")),
-            (3, Bytes::from_static(b"hi_1")),
-            (5, Bytes::from_static(b"hi_2")),
+            (1.into(), Bytes::from_static(b"hi_0")),
+            (3.into(), Bytes::from_static(b"hi_1")),
+            (5.into(), Bytes::from_static(b"hi_2")),
         ]);
         let expected: RVec<SortedSetMember> = smallvec![
-            SortedSetMember::new(&Bytes::from_static(b"hi_0"), 1),
-            SortedSetMember::new(&Bytes::from_static(b"hi_1"), 3),
-            SortedSetMember::new(&Bytes::from_static(b"hi_2"), 5),
+            SortedSetMember::new(&Bytes::from_static(b"hi_0"), 1.into()),
+            SortedSetMember::new(&Bytes::from_static(b"hi_1"), 3.into()),
+            SortedSetMember::new(&Bytes::from_static(b"hi_2"), 5.into()),
         ];
-        assert_eq!(ss.range((1, 5)), expected);
-        let expected: RVec<SortedSetMember> = smallvec![SortedSetMember::new(&b"hi_1".to_vec(), 3)];
-        assert_eq!(ss.range((2, 4)), expected);
+        assert_eq!(ss.range((1.into(), 5.into())), expected);
+        let expected: RVec<SortedSetMember> =
+            smallvec![SortedSetMember::new(&b"hi_1".to_vec(), 3.into())];
+        assert_eq!(ss.range((2.into(), 4.into())), expected);
         let empty_vec: RVec<SortedSetMember> = RVec::new();
-        assert_eq!(ss.range((20, 40)), empty_vec);
+        assert_eq!(ss.range((20.into(), 40.into())), empty_vec);
     }
 
     #[test]
@@ -258,17 +427,19 @@ mod test_sorted_sets_ds {
         assert_eq!(0, ss.card());
     }
 
-    // XXX: Fix test case. Am moving to proper skiplist later.
     #[test]
     fn test_pop_max() {
         let mut ss = SortedSet::new();
         assert_eq!(ss.pop_max(10), Vec::new());
         ss.add(get_multiple_entries());
         let entries = get_multiple_sorted_set_entries();
-        let first_two: Vec<SortedSetMember> = entries.iter().cloned().collect();
-        ss.pop_max(2); // TODO: Fix this test case.
-                       // assert_eq!(ss.pop_max(2).as_slice(), &first_two[1..]);
-        assert_eq!(ss.pop_max(2).as_slice(), &[first_two[0].clone()]);
+        // Highest score first.
+        assert_eq!(
+            ss.pop_max(2).as_slice(),
+            &[entries[2].clone(), entries[1].clone()]
+        );
+        assert_eq!(ss.pop_max(2).as_slice(), &[entries[0].clone()]);
+        assert_eq!(ss.card(), 0);
     }
     #[test]
     fn test_pop_min() {
@@ -280,4 +451,170 @@ mod test_sorted_sets_ds {
         assert_eq!(ss.pop_min(2).as_slice(), &last_two[..2]);
         assert_eq!(ss.pop_min(2).as_slice(), &[last_two[2].clone()]);
     }
+
+    #[test]
+    fn test_incr() {
+        let mut ss = SortedSet::new();
+        let key = Bytes::from_static(b"hi");
+        assert_eq!(ss.incr(key.clone(), 2.into()), Some(2.into()));
+        assert_eq!(ss.incr(key.clone(), (-5).into()), Some((-3).into()));
+        assert_eq!(ss.range_by_rank(0, -1, false).len(), 1);
+        let inf = Score::new(f64::INFINITY).unwrap();
+        let neg_inf = Score::new(f64::NEG_INFINITY).unwrap();
+        assert_eq!(ss.incr(key.clone(), inf), Some(inf));
+        assert_eq!(ss.incr(key.clone(), neg_inf), None);
+        assert_eq!(ss.score(key), Some(inf));
+    }
+
+    #[test]
+    fn test_remove_ranges() {
+        let mut ss = SortedSet::new();
+        ss.add(get_multiple_entries());
+        let (min, max) = (Bound::Excluded(1.into()), Bound::Unbounded);
+        assert_eq!(ss.count_by_score(min, max), 2);
+        let (min, max) = (Bound::Included(3.into()), Bound::Excluded(1.into()));
+        assert_eq!(ss.count_by_score(min, max), 0);
+        assert_eq!(
+            ss.remove_range_by_score(Bound::Unbounded, Bound::Included(1.into())),
+            1
+        );
+        assert_eq!(
+            members(ss.range_by_rank(0, -1, false)),
+            keys(&["hi_1", "hi_2"])
+        );
+        ss.add(get_multiple_entries());
+        assert_eq!(ss.remove_range_by_rank(-2, -1), 2);
+        assert_eq!(members(ss.range_by_rank(0, -1, false)), keys(&["hi_0"]));
+        assert_eq!(ss.remove_range_by_rank(5, 10), 0);
+    }
+
+    #[test]
+    fn test_non_utf8_members() {
+        let mut ss = SortedSet::new();
+        let (a, b) = (
+            Bytes::from_static(b"\xff\xfe"),
+            Bytes::from_static(b"\xff\xff"),
+        );
+        assert_eq!(
+            2,
+            ss.add(smallvec![(1.into(), b.clone()), (1.into(), a.clone())])
+        );
+        // Ties in score are ordered by raw bytes, and members aren't mangled.
+        let members: Vec<Key> = ss
+            .range((1.into(), 1.into()))
+            .into_iter()
+            .map(|m| m.member)
+            .collect();
+        assert_eq!(members, vec![a.clone(), b.clone()]);
+        assert_eq!(ss.rank(b.clone()), Some(1));
+        assert_eq!(ss.score(a.clone()), Some(1.into()));
+        assert_eq!(ss.pop_min(1), vec![SortedSetMember::new(&a, 1.into())]);
+        assert_eq!(ss.rank(a), None);
+        assert_eq!(ss.card(), 1);
+    }
+
+    #[test]
+    fn test_insert_updates_scores() {
+        let mut ss = SortedSet::new();
+        let a = Bytes::from_static(b"a");
+        let half = Score::new(0.5).unwrap();
+        assert_eq!(ss.insert(a.clone(), 2.into()), None);
+        ss.add(smallvec![(1.into(), Bytes::from_static(b"b"))]);
+        assert_eq!(ss.insert(a.clone(), half), Some(2.into()));
+        assert_eq!(ss.card(), 2);
+        assert_eq!(ss.rank(a.clone()), Some(0));
+        let expected: RVec<SortedSetMember> = smallvec![SortedSetMember::new(&a, half)];
+        assert_eq!(ss.range((half, half)), expected);
+    }
+
+    fn members<M: Borrow<SortedSetMember>>(found: impl IntoIterator<Item = M>) -> Vec<Key> {
+        found
+            .into_iter()
+            .map(|m| m.borrow().member.clone())
+            .collect()
+    }
+
+    fn keys(names: &[&'static str]) -> Vec<Key> {
+        names
+            .iter()
+            .map(|n| Bytes::from_static(n.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_range_by_rank() {
+        let mut ss = SortedSet::new();
+        ss.add(get_multiple_entries());
+        assert_eq!(
+            members(ss.range_by_rank(0, -1, false)),
+            keys(&["hi_0", "hi_1", "hi_2"])
+        );
+        assert_eq!(
+            members(ss.range_by_rank(-2, 10, false)),
+            keys(&["hi_1", "hi_2"])
+        );
+        assert_eq!(members(ss.range_by_rank(0, 0, true)), keys(&["hi_2"]));
+        assert!(ss.range_by_rank(2, 1, false).is_empty());
+        assert!(ss.range_by_rank(5, 10, false).is_empty());
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let mut ss = SortedSet::new();
+        ss.add(get_multiple_entries());
+        let (one, three) = (Score::from(1), Score::from(3));
+        assert_eq!(
+            members(ss.range_by_score(Bound::Included(one), Bound::Included(three))),
+            keys(&["hi_0", "hi_1"])
+        );
+        assert_eq!(
+            members(ss.range_by_score(Bound::Excluded(one), Bound::Unbounded)),
+            keys(&["hi_1", "hi_2"])
+        );
+        assert_eq!(
+            members(ss.range_by_score(Bound::Unbounded, Bound::Excluded(three))),
+            keys(&["hi_0"])
+        );
+        let inf = Score::new(f64::INFINITY).unwrap();
+        assert!(ss
+            .range_by_score(Bound::Excluded(three), Bound::Excluded(three))
+            .next()
+            .is_none());
+        assert_eq!(
+            ss.range_by_score(Bound::Included(one), Bound::Included(inf))
+                .count(),
+            3
+        );
+        let ranks = ss.score_ranks(&Bound::Excluded(one), &Bound::Unbounded);
+        assert_eq!(ranks, 1..3);
+        assert_eq!(members(ss.members_in(ranks, true)), keys(&["hi_2", "hi_1"]));
+        assert_eq!(members(ss.members_in(1..1, true)), keys(&[]));
+    }
+
+    #[test]
+    fn test_range_by_lex() {
+        let mut ss = SortedSet::new();
+        ss.add(smallvec![
+            (0.into(), Bytes::from_static(b"hi_0")),
+            (0.into(), Bytes::from_static(b"hi_1")),
+            (0.into(), Bytes::from_static(b"hi_2")),
+        ]);
+        let hi_1 = Bytes::from_static(b"hi_1");
+        assert_eq!(
+            members(ss.range_by_lex(&LexBound::Min, &LexBound::Max)),
+            keys(&["hi_0", "hi_1", "hi_2"])
+        );
+        assert_eq!(
+            members(ss.range_by_lex(&LexBound::Excluded(hi_1.clone()), &LexBound::Max)),
+            keys(&["hi_2"])
+        );
+        assert_eq!(
+            members(ss.range_by_lex(&LexBound::Min, &LexBound::Included(hi_1))),
+            keys(&["hi_0", "hi_1"])
+        );
+        assert!(ss
+            .range_by_lex(&LexBound::Max, &LexBound::Min)
+            .next()
+            .is_none());
+    }
 }
@@ -18,6 +18,12 @@ impl<T: Clone> Stack<T> {
         self.inner.pop()
     }
 
+    /// Pop up to `count` items, top first.
+    pub fn pop_n(&mut self, count: usize) -> Vec<T> {
+        let rest = self.inner.len().saturating_sub(count);
+        self.inner.drain(rest..).rev().collect()
+    }
+
     pub fn peek(&self) -> Option<T> {
         self.inner.last().cloned()
     }
@@ -25,6 +31,20 @@ impl<T: Clone> Stack<T> {
     pub fn size(&self) -> Count {
         self.inner.len() as Count
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// How many items fit before the stack reallocates.
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// Iterate from the bottom of the stack to the top.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.inner.iter()
+    }
 }
 
 #[cfg(test)]
@@ -52,4 +72,16 @@ mod test_stack {
         assert_eq!(s.peek(), Some(4));
         assert_eq!(s.size(), 2);
     }
+
+    #[test]
+    fn test_pop_n() {
+        let mut s = Stack::new();
+        (1..=3).for_each(|i| {
+            s.push(i);
+        });
+        assert_eq!(s.pop_n(2), vec![3, 2]);
+        assert_eq!(s.pop_n(5), vec![1]);
+        assert!(s.is_empty());
+        assert_eq!(s.pop_n(1), Vec::<i32>::new());
+    }
 }
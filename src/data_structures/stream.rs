@@ -0,0 +1,680 @@
+use crate::types::Value;
+use serde::ser::{Serialize, Serializer};
+use std::collections::btree_map::Range;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem::size_of;
+use std::ops::Bound;
+
+/// The ID of a stream entry: a unix time in milliseconds, and a sequence
+/// number telling apart entries added within the same millisecond.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    /// Parse `<ms>-<seq>`, or a bare `<ms>` with `seq` as its sequence number.
+    pub fn parse(id: &[u8], seq: u64) -> Option<StreamId> {
+        let id = std::str::from_utf8(id).ok()?;
+        let (ms, seq) = match id.find('-') {
+            Some(at) => (&id[..at], id[at + 1..].parse().ok()?),
+            None => (id, seq),
+        };
+        Some(StreamId::new(ms.parse().ok()?, seq))
+    }
+
+    /// The smallest ID after this one.
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    /// The greatest ID before this one.
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_sub(1)?, u64::MAX)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID XADD is asked to give a new entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewId {
+    /// `*`: the current time, or just after the last ID if the clock is behind it.
+    Auto,
+    /// `<ms>-*`: the next free sequence number within `ms`.
+    AutoSeq(u64),
+    Exact(StreamId),
+}
+
+impl NewId {
+    pub fn parse(id: &[u8]) -> Option<NewId> {
+        if id == b"*" {
+            return Some(NewId::Auto);
+        }
+        if let Some(ms) = id.strip_suffix(b"-*") {
+            let ms = std::str::from_utf8(ms).ok()?.parse().ok()?;
+            return Some(NewId::AutoSeq(ms));
+        }
+        StreamId::parse(id, 0).map(NewId::Exact)
+    }
+}
+
+/// Why an entry couldn't be added.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddError {
+    /// 0-0 is never a valid ID.
+    Zero,
+    /// IDs must be greater than any added before.
+    TooSmall,
+}
+
+/// The field-value pairs of an entry, in the order they were given.
+pub type Fields = Vec<(Value, Value)>;
+
+/// A stream's entries, by ID.
+pub type Entries = BTreeMap<StreamId, Fields>;
+
+/// An entry handed to a consumer of a group, and not yet acknowledged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingEntry {
+    pub consumer: Value,
+    /// When it was last delivered, in unix milliseconds.
+    pub delivered_at: u64,
+    pub deliveries: u64,
+}
+
+impl PendingEntry {
+    /// Milliseconds since it was last delivered.
+    pub fn idle(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.delivered_at)
+    }
+}
+
+/// A consumer of a group.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Consumer {
+    /// When it last read or claimed entries, in unix milliseconds.
+    pub seen_at: u64,
+    /// The IDs of the entries pending for it.
+    pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    pub fn pending(&self) -> &BTreeSet<StreamId> {
+        &self.pending
+    }
+}
+
+/// Consumers sharing a stream: each entry goes to one of them, and stays
+/// pending until it's acknowledged.
+///
+/// Every consumer keeps the IDs of its own pending entries, so reading them
+/// again doesn't go through everyone's. Only the pending entries list and
+/// when each consumer was seen are stored: consumers' lists are rebuilt
+/// from it.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(from = "StoredGroup")]
+pub struct ConsumerGroup {
+    /// The greatest ID handed to any consumer. Reading `>` starts after it.
+    last_delivered: StreamId,
+    /// The pending entries list, of every consumer.
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<Value, Consumer>,
+}
+
+#[derive(Serialize)]
+struct StoredGroupRef<'a> {
+    last_delivered: StreamId,
+    pending: &'a BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<&'a Value, u64>,
+}
+
+#[derive(Deserialize)]
+struct StoredGroup {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    #[serde(default)]
+    consumers: BTreeMap<Value, u64>,
+}
+
+impl Serialize for ConsumerGroup {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        StoredGroupRef {
+            last_delivered: self.last_delivered,
+            pending: &self.pending,
+            consumers: self
+                .consumers
+                .iter()
+                .map(|(name, consumer)| (name, consumer.seen_at))
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl From<StoredGroup> for ConsumerGroup {
+    fn from(stored: StoredGroup) -> ConsumerGroup {
+        let mut consumers: BTreeMap<Value, Consumer> = stored
+            .consumers
+            .into_iter()
+            .map(|(name, seen_at)| {
+                let consumer = Consumer {
+                    seen_at,
+                    pending: BTreeSet::new(),
+                };
+                (name, consumer)
+            })
+            .collect();
+        for (id, entry) in &stored.pending {
+            let consumer = consumers.entry(entry.consumer.clone()).or_default();
+            consumer.seen_at = consumer.seen_at.max(entry.delivered_at);
+            consumer.pending.insert(*id);
+        }
+        ConsumerGroup {
+            last_delivered: stored.last_delivered,
+            pending: stored.pending,
+            consumers,
+        }
+    }
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId) -> ConsumerGroup {
+        ConsumerGroup {
+            last_delivered,
+            ..ConsumerGroup::default()
+        }
+    }
+
+    pub fn last_delivered(&self) -> StreamId {
+        self.last_delivered
+    }
+
+    /// Read `>` after `id` from now on, as XGROUP SETID does.
+    pub fn set_last_delivered(&mut self, id: StreamId) {
+        self.last_delivered = id;
+    }
+
+    /// Every pending entry, by ID.
+    pub fn pending(&self) -> &BTreeMap<StreamId, PendingEntry> {
+        &self.pending
+    }
+
+    /// Every consumer seen, by name.
+    pub fn consumers(&self) -> &BTreeMap<Value, Consumer> {
+        &self.consumers
+    }
+
+    /// Note that `consumer` was seen at `now_ms`, adding it if it's new.
+    pub fn see(&mut self, consumer: &Value, now_ms: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(consumer.clone()).or_default();
+        consumer.seen_at = now_ms;
+        consumer
+    }
+
+    /// Make `id` pending as `entry` says, taking it from whoever had it.
+    fn set_pending(&mut self, id: StreamId, entry: PendingEntry, now_ms: u64) {
+        let consumer = entry.consumer.clone();
+        // Taken away first, as the old consumer may be the new one too.
+        if let Some(old) = self.pending.insert(id, entry) {
+            self.remove_from_consumer(&old.consumer, id);
+        }
+        self.see(&consumer, now_ms).pending.insert(id);
+    }
+
+    fn remove_from_consumer(&mut self, consumer: &Value, id: StreamId) {
+        if let Some(consumer) = self.consumers.get_mut(consumer) {
+            consumer.pending.remove(&id);
+        }
+    }
+
+    /// Hand `consumer` up to `count` entries no consumer has had yet. They're
+    /// pending until acknowledged, unless `noack`.
+    pub fn deliver_new(
+        &mut self,
+        entries: &Entries,
+        consumer: &Value,
+        count: usize,
+        noack: bool,
+        now_ms: u64,
+    ) -> Vec<(StreamId, Fields)> {
+        self.see(consumer, now_ms);
+        let new: Vec<(StreamId, Fields)> = entries
+            .range((Bound::Excluded(self.last_delivered), Bound::Unbounded))
+            .take(count)
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect();
+        if let Some((id, _)) = new.last() {
+            self.last_delivered = *id;
+        }
+        if !noack {
+            for (id, _) in &new {
+                let pending = PendingEntry {
+                    consumer: consumer.clone(),
+                    delivered_at: now_ms,
+                    deliveries: 1,
+                };
+                self.set_pending(*id, pending, now_ms);
+            }
+        }
+        new
+    }
+
+    /// Hand `consumer` up to `count` of its own pending entries after `after`
+    /// again. Entries trimmed from the stream since come back without fields.
+    pub fn redeliver(
+        &mut self,
+        entries: &Entries,
+        consumer: &Value,
+        after: StreamId,
+        count: usize,
+        now_ms: u64,
+    ) -> Vec<(StreamId, Option<Fields>)> {
+        let ids: Vec<StreamId> = self
+            .see(consumer, now_ms)
+            .pending
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .take(count)
+            .copied()
+            .collect();
+        ids.into_iter()
+            .map(|id| {
+                if let Some(pending) = self.pending.get_mut(&id) {
+                    pending.delivered_at = now_ms;
+                    pending.deliveries += 1;
+                }
+                (id, entries.get(&id).cloned())
+            })
+            .collect()
+    }
+
+    /// Acknowledge `id`, returning whether it was pending.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        match self.pending.remove(&id) {
+            Some(entry) => {
+                self.remove_from_consumer(&entry.consumer, id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Give pending entry `id` to `consumer` if it's been idle for at least
+    /// `min_idle` milliseconds, returning it to update.
+    pub fn claim(
+        &mut self,
+        id: StreamId,
+        consumer: &Value,
+        min_idle: u64,
+        now_ms: u64,
+    ) -> Option<&mut PendingEntry> {
+        let old = self.pending.get(&id)?;
+        if old.idle(now_ms) < min_idle {
+            return None;
+        }
+        let old = old.consumer.clone();
+        self.remove_from_consumer(&old, id);
+        self.see(consumer, now_ms).pending.insert(id);
+        let pending = self.pending.get_mut(&id)?;
+        pending.consumer = consumer.clone();
+        Some(pending)
+    }
+
+    /// Make `id` pending for `consumer`, as XCLAIM's FORCE does, unless it already is.
+    pub fn force_pending(&mut self, id: StreamId, consumer: &Value, now_ms: u64) {
+        if self.pending.contains_key(&id) {
+            return;
+        }
+        let pending = PendingEntry {
+            consumer: consumer.clone(),
+            delivered_at: now_ms,
+            deliveries: 0,
+        };
+        self.set_pending(id, pending, now_ms);
+    }
+
+    /// Bytes of the pending entries and consumers, with the nodes holding them.
+    fn size(&self) -> usize {
+        let pending: usize = self
+            .pending
+            .values()
+            .map(|entry| size_of::<(StreamId, PendingEntry)>() + entry.consumer.len())
+            .sum();
+        let consumers: usize = self
+            .consumers
+            .iter()
+            .map(|(name, consumer)| {
+                name.len()
+                    + size_of::<(Value, Consumer)>()
+                    + consumer.pending.len() * size_of::<StreamId>()
+            })
+            .sum();
+        size_of::<ConsumerGroup>() + pending + consumers
+    }
+}
+
+/// Entries ordered by their ID, which only ever grows.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stream {
+    entries: Entries,
+    /// The greatest ID added, which new IDs must exceed even once it's trimmed away.
+    last_id: StreamId,
+    #[serde(default)]
+    groups: BTreeMap<Value, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// The ID `id` resolves to at `now_ms`, if it's greater than the last one.
+    fn resolve(&self, id: NewId, now_ms: u64) -> Result<StreamId, AddError> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if now_ms > last.ms => StreamId::new(now_ms, 0),
+            NewId::Auto => last.next().ok_or(AddError::TooSmall)?,
+            NewId::AutoSeq(ms) if ms == last.ms => last.next().ok_or(AddError::TooSmall)?,
+            NewId::AutoSeq(0) => StreamId::new(0, 1),
+            NewId::AutoSeq(ms) => StreamId::new(ms, 0),
+            NewId::Exact(id) => id,
+        };
+        if id == StreamId::MIN {
+            return Err(AddError::Zero);
+        }
+        if id <= last {
+            return Err(AddError::TooSmall);
+        }
+        Ok(id)
+    }
+
+    /// Add an entry, returning the ID it got. `now_ms` is what `*` uses.
+    pub fn add(&mut self, id: NewId, fields: Fields, now_ms: u64) -> Result<StreamId, AddError> {
+        let id = self.resolve(id, now_ms)?;
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// Drop the oldest entries until at most `maxlen` are left. Returns how many went.
+    pub fn trim(&mut self, maxlen: usize) -> usize {
+        let excess = self.len().saturating_sub(maxlen);
+        for _ in 0..excess {
+            let first = *self.entries.keys().next().unwrap();
+            self.entries.remove(&first);
+        }
+        excess
+    }
+
+    /// Entries with IDs between `start` and `end`, both included.
+    pub fn range(&self, start: StreamId, end: StreamId) -> Range<'_, StreamId, Fields> {
+        if start > end {
+            return self.entries.range(start..start);
+        }
+        self.entries.range(start..=end)
+    }
+
+    /// Entries with IDs greater than `id`.
+    pub fn after(&self, id: StreamId) -> Range<'_, StreamId, Fields> {
+        self.entries.range((Bound::Excluded(id), Bound::Unbounded))
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<&Fields> {
+        self.entries.get(&id)
+    }
+
+    /// Add a group reading after `last_delivered`. Returns false if `name` is taken.
+    pub fn create_group(&mut self, name: Value, last_delivered: StreamId) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        self.groups.insert(name, ConsumerGroup::new(last_delivered));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    /// The group `name`, with the entries it hands out.
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<(&mut ConsumerGroup, &Entries)> {
+        let entries = &self.entries;
+        self.groups.get_mut(name).map(|group| (group, entries))
+    }
+
+    /// Iterate over every entry, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.entries.iter()
+    }
+
+    /// Bytes of the entries and consumer groups, with the slots and nodes
+    /// holding them.
+    pub fn size(&self) -> usize {
+        let entries: usize = self
+            .entries
+            .values()
+            .map(|fields| {
+                let pairs: usize = fields.iter().map(|(f, v)| f.len() + v.len()).sum();
+                size_of::<(StreamId, Fields)>()
+                    + fields.capacity() * size_of::<(Value, Value)>()
+                    + pairs
+            })
+            .sum();
+        let groups: usize = self
+            .groups
+            .iter()
+            .map(|(name, group)| name.len() + size_of::<Value>() + group.size())
+            .sum();
+        entries + groups
+    }
+}
+
+#[cfg(test)]
+mod test_stream {
+    use super::{AddError, NewId, Stream, StreamId};
+    use bytes::Bytes;
+
+    fn fields() -> Vec<(Bytes, Bytes)> {
+        vec![(Bytes::from_static(b"f"), Bytes::from_static(b"v"))]
+    }
+
+    fn ids(stream: &Stream) -> Vec<String> {
+        stream.iter().map(|(id, _)| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(
+            StreamId::parse(b"5", u64::MAX),
+            Some(StreamId::new(5, u64::MAX))
+        );
+        for bad in &["", "-", "5-", "a-1", "1-b", "-1"] {
+            assert_eq!(StreamId::parse(bad.as_bytes(), 0), None, "{}", bad);
+        }
+        assert_eq!(NewId::parse(b"*"), Some(NewId::Auto));
+        assert_eq!(NewId::parse(b"7-*"), Some(NewId::AutoSeq(7)));
+        assert_eq!(NewId::parse(b"7"), Some(NewId::Exact(StreamId::new(7, 0))));
+        assert_eq!(NewId::parse(b"x-*"), None);
+    }
+
+    #[test]
+    fn test_ids_only_grow() {
+        let mut s = Stream::new();
+        assert_eq!(
+            s.add(NewId::Exact(StreamId::MIN), fields(), 10),
+            Err(AddError::Zero)
+        );
+        assert_eq!(
+            s.add(NewId::AutoSeq(0), fields(), 10),
+            Ok(StreamId::new(0, 1))
+        );
+        assert_eq!(s.add(NewId::Auto, fields(), 10), Ok(StreamId::new(10, 0)));
+        // The clock went backwards.
+        assert_eq!(s.add(NewId::Auto, fields(), 3), Ok(StreamId::new(10, 1)));
+        assert_eq!(
+            s.add(NewId::AutoSeq(10), fields(), 3),
+            Ok(StreamId::new(10, 2))
+        );
+        assert_eq!(
+            s.add(NewId::AutoSeq(9), fields(), 3),
+            Err(AddError::TooSmall)
+        );
+        assert_eq!(
+            s.add(NewId::Exact(StreamId::new(10, 2)), fields(), 3),
+            Err(AddError::TooSmall)
+        );
+        assert_eq!(
+            s.add(NewId::Exact(StreamId::new(20, 5)), fields(), 3),
+            Ok(StreamId::new(20, 5))
+        );
+        // Trimming doesn't let smaller IDs back in.
+        assert_eq!(s.trim(0), 5);
+        assert!(s.is_empty());
+        assert_eq!(s.add(NewId::Auto, fields(), 3), Ok(StreamId::new(20, 6)));
+        assert_eq!(s.last_id(), StreamId::new(20, 6));
+        s.add(NewId::Exact(StreamId::MAX), fields(), 3).unwrap();
+        assert_eq!(s.add(NewId::Auto, fields(), 3), Err(AddError::TooSmall));
+    }
+
+    #[test]
+    fn test_range_and_trim() {
+        let mut s = Stream::new();
+        for ms in 1..=5 {
+            s.add(NewId::Auto, fields(), ms).unwrap();
+        }
+        let range = |start, end| -> Vec<u64> { s.range(start, end).map(|(id, _)| id.ms).collect() };
+        assert_eq!(
+            range(StreamId::new(2, 0), StreamId::new(4, 0)),
+            vec![2, 3, 4]
+        );
+        assert_eq!(range(StreamId::new(2, 1), StreamId::MAX), vec![3, 4, 5]);
+        assert!(range(StreamId::new(4, 0), StreamId::new(2, 0)).is_empty());
+        let after: Vec<u64> = s.after(StreamId::new(3, 0)).map(|(id, _)| id.ms).collect();
+        assert_eq!(after, vec![4, 5]);
+        assert_eq!(s.after(StreamId::MAX).count(), 0);
+
+        assert_eq!(s.trim(2), 3);
+        assert_eq!(ids(&s), vec!["4-0", "5-0"]);
+        assert_eq!(s.trim(2), 0);
+    }
+
+    #[test]
+    fn test_consumer_groups() {
+        let mut s = Stream::new();
+        for ms in 1..=4 {
+            s.add(NewId::Auto, fields(), ms).unwrap();
+        }
+        let (alice, bob) = (Bytes::from_static(b"alice"), Bytes::from_static(b"bob"));
+        assert!(s.create_group(Bytes::from_static(b"g"), StreamId::new(1, 0)));
+        assert!(!s.create_group(Bytes::from_static(b"g"), StreamId::MIN));
+
+        let (group, entries) = s.group_mut(b"g").unwrap();
+        let new = group.deliver_new(entries, &alice, 2, false, 100);
+        let new: Vec<u64> = new.iter().map(|(id, _)| id.ms).collect();
+        assert_eq!(new, vec![2, 3]);
+        assert_eq!(group.deliver_new(entries, &bob, 5, true, 100).len(), 1);
+        assert!(group.deliver_new(entries, &bob, 5, false, 100).is_empty());
+        assert_eq!(group.last_delivered(), StreamId::new(4, 0));
+        // NOACK left nothing pending for bob.
+        assert_eq!(group.pending().len(), 2);
+
+        assert!(group.ack(StreamId::new(2, 0)));
+        assert!(!group.ack(StreamId::new(2, 0)));
+        let again = group.redeliver(entries, &alice, StreamId::MIN, 10, 150);
+        assert_eq!(again, vec![(StreamId::new(3, 0), Some(fields()))]);
+        let pending = &group.pending()[&StreamId::new(3, 0)];
+        assert_eq!((pending.deliveries, pending.idle(170)), (2, 20));
+
+        assert!(group.claim(StreamId::new(3, 0), &bob, 50, 170).is_none());
+        let claimed = group.claim(StreamId::new(3, 0), &bob, 50, 200).unwrap();
+        assert_eq!(claimed.consumer, bob);
+        assert!(group
+            .redeliver(entries, &alice, StreamId::MIN, 10, 200)
+            .is_empty());
+
+        // Consumers' own lists are rebuilt from the pending entries.
+        let stored: Stream = rmps::from_read_ref(&rmps::to_vec(&s).unwrap()).unwrap();
+        assert_eq!(stored, s);
+        let group = stored.group(b"g").unwrap();
+        let consumers: Vec<usize> = group
+            .consumers()
+            .values()
+            .map(|c| c.pending().len())
+            .collect();
+        assert_eq!(consumers, vec![0, 1]);
+
+        // Entries trimmed away while pending come back without fields.
+        s.trim(0);
+        let (group, entries) = s.group_mut(b"g").unwrap();
+        let gone = group.redeliver(entries, &bob, StreamId::MIN, 10, 200);
+        assert_eq!(gone, vec![(StreamId::new(3, 0), None)]);
+        assert!(s.destroy_group(b"g"));
+        assert!(s.group(b"g").is_none());
+    }
+
+    #[test]
+    fn test_redeliver_to_same_consumer() {
+        let mut s = Stream::new();
+        s.add(NewId::Auto, fields(), 1).unwrap();
+        let alice = Bytes::from_static(b"alice");
+        assert!(s.create_group(Bytes::from_static(b"g"), StreamId::MIN));
+        let (group, entries) = s.group_mut(b"g").unwrap();
+        assert_eq!(group.deliver_new(entries, &alice, 10, false, 100).len(), 1);
+        // XGROUP SETID g 0, then alice reads its own pending entry as new.
+        group.set_last_delivered(StreamId::MIN);
+        assert_eq!(group.deliver_new(entries, &alice, 10, false, 200).len(), 1);
+        assert_eq!(group.pending().len(), 1);
+        assert_eq!(group.consumers()[&alice].pending().len(), 1);
+        let again = group.redeliver(entries, &alice, StreamId::MIN, 10, 300);
+        assert_eq!(again, vec![(StreamId::new(1, 0), Some(fields()))]);
+    }
+
+    #[test]
+    fn test_next_prev() {
+        assert_eq!(StreamId::new(1, u64::MAX).next(), Some(StreamId::new(2, 0)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::new(2, 0).prev(), Some(StreamId::new(1, u64::MAX)));
+        assert_eq!(StreamId::MIN.prev(), None);
+    }
+}
@@ -1,20 +1,39 @@
+use crate::asyncresp::{RespLimits, RespParser};
+use crate::backing::start_backing;
+use crate::bulk_load::bulk_load;
+use crate::cdc::start_cdc;
+use crate::config::{load_config_file, RuntimeConfig};
+use crate::dump_format;
+use crate::key_dump::dump_key;
+use crate::latency;
 use crate::logger::LOGGER;
+use crate::rdb;
+use crate::replication::Replication;
+use crate::scripting::ScriptingBridge;
+use crate::snapshot::{Snapshot, SnapshotStats};
 use crate::startup::Config;
-use crate::types::{DumpFile, StateStore, StateStoreRef};
+use crate::stats::Stats;
+use crate::streams::{xadd_as_added, GROUP_READS};
+use crate::types::{
+    bulk, DumpFile, FlushProtection, Index, Key, RedisValueRef, ReturnValue, State, StateRef,
+    StateStore, StateStoreRef,
+};
+use bytes::BytesMut;
 use directories::ProjectDirs;
 use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Seek;
-use std::io::SeekFrom;
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::ops::Deref;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::task;
 use tokio::time::interval;
+use tokio_util::codec::{Decoder, Encoder};
 
 const SAVE_STATE_PERIOD_SEC: u64 = 60;
 const SAVE_STATE_PERIOD: u64 = SAVE_STATE_PERIOD_SEC * 1000;
@@ -33,28 +52,596 @@ macro_rules! fatal_panic {
     }};
 }
 
-/// Dump the current state to the dump_file
-fn dump_state(state: StateStoreRef, dump_file: &mut File) -> Result<(), Box<dyn Error>> {
-    dump_file.seek(SeekFrom::Start(0))?;
-    rmps::encode::write(dump_file, &state)
-        .map_err(|e| fatal_panic!("Could not write state!", e.to_string()))
-        .unwrap();
+/// Failpoints for crash-recovery tests.
+///
+/// A test arms a failpoint by name, and the next time the persistence code
+/// reaches it an error is returned, as if the process died right there.
+#[cfg(test)]
+mod failpoints {
+    use std::cell::RefCell;
+    use std::io;
+
+    thread_local! {
+        static ARMED: RefCell<Option<&'static str>> = RefCell::new(None);
+    }
+
+    pub fn arm(name: &'static str) {
+        ARMED.with(|a| *a.borrow_mut() = Some(name));
+    }
+
+    pub fn hit(name: &'static str) -> io::Result<()> {
+        ARMED.with(|a| {
+            if *a.borrow() == Some(name) {
+                *a.borrow_mut() = None;
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("failpoint {}", name),
+                ))
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+#[cfg(not(test))]
+mod failpoints {
+    #[inline(always)]
+    pub fn hit(_name: &'static str) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where a dump is written before being renamed over the real dump file.
+fn temp_dump_path(dump_path: &Path) -> PathBuf {
+    let mut tmp = dump_path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Counts the bytes of a save as they're written, for INFO persistence.
+struct SaveWriter<'a, W> {
+    out: W,
+    stats: &'a Stats,
+}
+
+impl<W: Write> Write for SaveWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.stats.record_save_bytes(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// How the dbs are split over dump files (`--dump-layout`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpLayout {
+    /// Every db in the dump file.
+    Single,
+    /// Each db in a file of its own, dump-<db>.rodb.
+    Db,
+    /// Each structure of each db in a file of its own, dump-<db>-<structure>.rodb.
+    Structure,
+}
+
+impl Default for DumpLayout {
+    fn default() -> Self {
+        DumpLayout::Single
+    }
+}
+
+impl DumpLayout {
+    fn from_config(config: &Config) -> Self {
+        match config.dump_layout.as_str() {
+            "db" => DumpLayout::Db,
+            "structure" => DumpLayout::Structure,
+            _ => DumpLayout::Single,
+        }
+    }
+}
+
+/// Which dbs are saved to dump files and logged to the AOF (`--persist-dbs`).
+/// The others only live in memory.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PersistDbs(Option<HashSet<Index>>);
+
+impl PersistDbs {
+    fn from_config(config: &Config) -> Self {
+        if config.persist_dbs.is_empty() {
+            return PersistDbs(None);
+        }
+        PersistDbs(Some(config.persist_dbs.iter().copied().collect()))
+    }
+
+    pub fn persists(&self, db: Index) -> bool {
+        self.0.as_ref().map_or(true, |dbs| dbs.contains(&db))
+    }
+}
+
+/// Where db `db`, or just its `structure`, is dumped when the layout splits
+/// the dump file.
+fn split_dump_path(dump_path: &Path, db: Index, structure: Option<&str>) -> PathBuf {
+    let stem = dump_path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match structure {
+        Some(structure) => format!("{}-{}-{}.rodb", stem, db, structure),
+        None => format!("{}-{}.rodb", stem, db),
+    };
+    dump_path.with_file_name(name)
+}
+
+/// The files next to dump_path named like split dump files, with `suffix`
+/// in place of ".rodb", in order.
+fn split_dump_files(dump_path: &Path, suffix: &str) -> io::Result<Vec<PathBuf>> {
+    let prefix = format!(
+        "{}-",
+        dump_path.file_stem().unwrap_or_default().to_string_lossy()
+    );
+    let dir = match dump_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let db = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .and_then(|rest| rest.splitn(2, '-').next())
+            .and_then(|db| db.parse::<Index>().ok());
+        if db.is_some() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Where a split dump file that couldn't be read is moved to.
+fn unreadable_dump_path(path: &Path) -> PathBuf {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(".unreadable");
+    PathBuf::from(aside)
+}
+
+/// Where save `id` writes the dump file at `path`, until the save is
+/// committed and it's renamed into place.
+fn pending_dump_path(path: &Path, id: u64) -> PathBuf {
+    let mut pending = path.as_os_str().to_owned();
+    pending.push(format!(".{}.tmp", id));
+    PathBuf::from(pending)
+}
+
+/// Lists the files of a save, which is complete once this is in place.
+fn manifest_path(dump_path: &Path) -> PathBuf {
+    let mut manifest = dump_path.as_os_str().to_owned();
+    manifest.push(".manifest");
+    PathBuf::from(manifest)
+}
+
+/// The files a save wrote, by name, next to the dump file.
+#[derive(Debug, Serialize, Deserialize)]
+struct SaveManifest {
+    id: u64,
+    files: Vec<String>,
+}
+
+impl SaveManifest {
+    fn paths(&self, dump_path: &Path) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .map(|name| dump_path.with_file_name(name))
+            .collect()
+    }
+}
+
+fn read_manifest(dump_path: &Path) -> Result<Option<SaveManifest>, Box<dyn Error>> {
+    match std::fs::read(manifest_path(dump_path)) {
+        Ok(contents) => Ok(Some(rmps::from_read_ref(&contents)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Commit a save: write its manifest next to the dump file, atomically.
+/// From then on a load reads this save's files and no others.
+fn write_manifest(dump_path: &Path, manifest: &SaveManifest) -> Result<(), Box<dyn Error>> {
+    let path = manifest_path(dump_path);
+    let tmp_path = temp_dump_path(&path);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&rmps::to_vec(manifest)?)?;
+    tmp.sync_all()?;
+    failpoints::hit("dump-before-rename")?;
+    std::fs::rename(&tmp_path, &path)?;
+    failpoints::hit("dump-after-rename")?;
+    sync_parent_dir(&path)?;
+    Ok(())
+}
+
+/// The pending files (see `pending_dump_path`) next to dump_path, of any save.
+fn pending_dump_files(dump_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = dump_path.file_name().unwrap_or_default().to_string_lossy();
+    let prefix = format!(
+        "{}-",
+        dump_path.file_stem().unwrap_or_default().to_string_lossy()
+    );
+    let dir = match dump_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let pending = path
+            .file_name()
+            .and_then(|file| file.to_str())
+            .and_then(|file| file.strip_suffix(".tmp"))
+            .and_then(|file| file.rsplit_once('.'))
+            .filter(|(_, id)| id.parse::<u64>().is_ok())
+            .map_or(false, |(file, _)| {
+                file == name || (file.starts_with(&prefix) && file.ends_with(".rodb"))
+            });
+        if pending {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Finish the last committed save: rename its files into place if a crash
+/// stopped it before, and remove the files of saves that never committed.
+/// Returns its manifest, or None if nothing was saved since saves had them.
+fn finish_save(dump_path: &Path) -> Result<Option<SaveManifest>, Box<dyn Error>> {
+    let manifest = read_manifest(dump_path)?;
+    let mut finished = false;
+    if let Some(manifest) = &manifest {
+        for path in manifest.paths(dump_path) {
+            let pending = pending_dump_path(&path, manifest.id);
+            match std::fs::rename(&pending, &path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                res => {
+                    res?;
+                    finished = true;
+                }
+            }
+        }
+    }
+    let leftovers = pending_dump_files(dump_path)?;
+    for path in &leftovers {
+        std::fs::remove_file(path)?;
+    }
+    if finished || !leftovers.is_empty() {
+        sync_parent_dir(dump_path)?;
+    }
+    Ok(manifest)
+}
+
+/// Write the dump file at `path` for save `id`: the header, then whatever
+/// `write` writes.
+///
+/// It's written to a pending file and synced. Once every file of the save
+/// is written, the save is committed with `write_manifest`, and `finish_save`
+/// renames them into place. A crash at any point leaves either the previous
+/// save or the new one, never a mix of both.
+fn write_dump_file<T>(
+    path: &Path,
+    id: u64,
+    stats: &Stats,
+    write: impl FnOnce(&mut SaveWriter<'_, BufWriter<File>>) -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    let tmp_path = pending_dump_path(path, id);
+    failpoints::hit("dump-before-write")?;
+    let mut tmp = SaveWriter {
+        out: BufWriter::new(File::create(&tmp_path)?),
+        stats,
+    };
+    dump_format::write_header(&mut tmp)?;
+    failpoints::hit("dump-mid-write")?;
+    let written = write(&mut tmp)?;
+    let tmp = tmp.out.into_inner().map_err(|e| e.into_error())?;
+    failpoints::hit("dump-before-sync")?;
+    tmp.sync_all()?;
+    Ok(written)
+}
+
+/// Dump the current state to dump_path, or to the files next to it that the
+/// layout splits it into. Dbs that aren't persisted are left out.
+///
+/// The files are written as `write_dump_file` does, so a crash mid-save
+/// leaves the previous save to load, or this one once it's committed. Once
+/// every file is in place, those the save didn't write (of another layout,
+/// or of dbs flushed or no longer persisted since) are removed.
+///
+/// Dbs are copied and written one at a time, each a shard at a time, so
+/// writers aren't held up for the whole copy and only one db is ever held
+/// twice in memory. Returns how much writers were held up.
+fn dump_state(state: &StateStore, dump_path: &Path) -> Result<SnapshotStats, Box<dyn Error>> {
+    let dbs: Vec<_> = state
+        .dbs()
+        .into_iter()
+        .filter(|(index, _)| state.persist_dbs.persists(*index))
+        .collect();
+    let id = finish_save(dump_path)?.map_or(0, |last| last.id) + 1;
+    state.stats.record_save_dbs(dbs.len());
+    // Every key of these dbs is written out, dirty or not.
+    for (_, db) in &dbs {
+        db.writes.take_dirty();
+    }
+    let mut stats = SnapshotStats::default();
+    let mut written = Vec::new();
+    match state.dump_layout {
+        DumpLayout::Single => {
+            stats = write_dump_file(dump_path, id, &state.stats, |out| {
+                let mut stats = SnapshotStats::default();
+                for (index, db) in &dbs {
+                    stats = stats.merge(dump_format::write_db(out, *index, db, &[], true)?);
+                    state.stats.record_save_db();
+                }
+                Ok(stats)
+            })?;
+            written.push(dump_path.to_path_buf());
+        }
+        DumpLayout::Db => {
+            for (index, db) in &dbs {
+                let path = split_dump_path(dump_path, *index, None);
+                let db_stats = write_dump_file(&path, id, &state.stats, |out| {
+                    dump_format::write_db(out, *index, db, &[], true)
+                })?;
+                stats = stats.merge(db_stats);
+                written.push(path);
+                state.stats.record_save_db();
+            }
+        }
+        DumpLayout::Structure => {
+            for (index, db) in &dbs {
+                let snapshot = db.chunked_snapshot();
+                for name in dump_format::structure_names() {
+                    if dump_format::structure_len(&snapshot, name) == 0 {
+                        continue;
+                    }
+                    let path = split_dump_path(dump_path, *index, Some(name));
+                    write_dump_file(&path, id, &state.stats, |out| {
+                        dump_format::write_structure(out, *index, &snapshot, name)
+                    })?;
+                    written.push(path);
+                }
+                stats = stats.merge(snapshot.stats);
+                state.stats.record_save_db();
+            }
+        }
+    }
+    let files = written
+        .iter()
+        .filter_map(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    write_manifest(dump_path, &SaveManifest { id, files })?;
+    finish_save(dump_path)?;
+    let mut stale = split_dump_files(dump_path, ".rodb")?;
+    stale.push(dump_path.to_path_buf());
+    stale.retain(|path| !written.contains(path));
+    for path in &stale {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    if !stale.is_empty() {
+        sync_parent_dir(dump_path)?;
+    }
+    Ok(stats)
+}
+
+/// Make a rename into `path` durable.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
     Ok(())
 }
 
+/// Where a dump in an older format is kept once it's been loaded.
+fn backup_dump_path(dump_path: &Path) -> PathBuf {
+    let mut backup = dump_path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+/// Decode the dump file at dump_path with `decode`, or None if there's no
+/// dump yet.
+///
+/// A dump this build can't read is left alone. One in an older format is
+/// copied aside first, as the next save overwrites it with the current format,
+/// which older builds may not read.
+fn read_dump_file<T>(
+    dump_path: &Path,
+    decode: impl FnOnce(&[u8]) -> Result<T, Box<dyn Error>>,
+) -> Result<Option<T>, Box<dyn Error>> {
+    let contents = match std::fs::read(dump_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if contents.is_empty() {
+        return Ok(None);
+    }
+    let decoded = decode(&contents).map_err(|e| {
+        format!(
+            "couldn't load {:?}, which has been left as it was: {}",
+            dump_path, e
+        )
+    })?;
+    if !dump_format::is_current(&contents) {
+        let backup = backup_dump_path(dump_path);
+        std::fs::write(&backup, &contents)?;
+        info!(
+            LOGGER,
+            "Loaded {:?} from an older format, the original is kept at {:?}", dump_path, backup
+        );
+    }
+    Ok(Some(decoded))
+}
+
+/// Read the state in dump_path, or an empty state if there's no dump yet.
+fn read_state(dump_path: &Path, lossy: bool) -> Result<StateStore, Box<dyn Error>> {
+    failpoints::hit("load-before-read")?;
+    let state = read_dump_file(dump_path, |contents| {
+        dump_format::read_state(contents, lossy)
+    })?;
+    Ok(state.unwrap_or_default())
+}
+
+/// Read the last save, from dump_path or the files split from it.
+///
+/// The save's manifest says which files it wrote, whatever the layout is
+/// now, so files left over from other saves are never mixed in. Without one
+/// (saved by an older build), the files of `layout` are read, or those of
+/// another layout when there are none of this one.
+fn read_dumps(
+    dump_path: &Path,
+    layout: DumpLayout,
+    lossy: bool,
+) -> Result<StateStore, Box<dyn Error>> {
+    let split = match finish_save(dump_path)? {
+        Some(manifest) => manifest.paths(dump_path),
+        None => {
+            let split = split_dump_files(dump_path, ".rodb")?;
+            if split.is_empty() || (layout == DumpLayout::Single && dump_path.exists()) {
+                return read_state(dump_path, lossy);
+            }
+            split
+        }
+    };
+    if split.iter().any(|path| path == dump_path) {
+        return read_state(dump_path, lossy);
+    }
+    read_split_dumps(split, lossy)
+}
+
+/// Read split dump files into one state.
+///
+/// A split file that can't be read doesn't stop the others loading: it's
+/// moved aside to <file>.unreadable, and its data is missing.
+fn read_split_dumps(split: Vec<PathBuf>, lossy: bool) -> Result<StateStore, Box<dyn Error>> {
+    let mut dbs = HashMap::new();
+    for path in split {
+        match read_dump_file(&path, |contents| dump_format::read_dbs(contents, lossy)) {
+            Ok(loaded) => dump_format::merge_dbs(&mut dbs, loaded.unwrap_or_default()),
+            Err(e) => {
+                let aside = unreadable_dump_path(&path);
+                std::fs::rename(&path, &aside)?;
+                error!(
+                    LOGGER,
+                    "Loading without it! {}. It's been moved to {:?}", e, aside
+                );
+            }
+        }
+    }
+    let store = StateStore::default();
+    for (index, state) in dbs {
+        store.states.insert(index, Arc::new(state));
+    }
+    Ok(store)
+}
+
 /// Load state from the dump_file
 pub fn load_state(dump_file: DumpFile, config: &Config) -> Result<StateStoreRef, Box<dyn Error>> {
-    let mut contents = dump_file.lock();
-    if contents.metadata()?.len() == 0 {
-        return Ok(Arc::new(StateStore::default()));
+    let dump_path = dump_file.lock();
+    let mut state_store = match &config.import_rdb {
+        Some(rdb_path) => {
+            info!(LOGGER, "Importing RDB file {:?}", rdb_path);
+            rdb::read_rdb(&std::fs::read(rdb_path)?)?
+        }
+        // The AOF has every write since it was started, dumped ones included.
+        None if config.appendonly && aof_path(&dump_path).exists() => StateStore::default(),
+        None => {
+            let store = read_dumps(
+                &dump_path,
+                DumpLayout::from_config(config),
+                config.lossy_load,
+            )?;
+            // Dbs that aren't persisted start empty, whatever an older save had.
+            let persist_dbs = PersistDbs::from_config(config);
+            store.states.retain(|db, _| persist_dbs.persists(*db));
+            store
+        }
+    };
+    configure_state_store(&mut state_store, config)?;
+    Ok(Arc::new(state_store))
+}
+
+/// Write `state` to `path` as a redis RDB file, the same way dumps are written.
+pub fn export_rdb(state: &StateStore, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut encoded = Vec::new();
+    let left_out = rdb::write_rdb(&mut encoded, state)?;
+    if left_out != 0 {
+        warn!(
+            LOGGER,
+            "Left {} streams and keys of our own types out of the RDB file", left_out
+        );
     }
+    let tmp_path = temp_dump_path(path);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&encoded)?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)?;
+    Ok(())
+}
 
-    contents.seek(SeekFrom::Start(0))?;
-    let mut state_store: StateStore = rmps::decode::from_read(&*contents)?;
-    state_store.commands_threshold = config.ops_until_save;
+/// Apply the runtime configuration to a freshly loaded StateStore.
+pub fn configure_state_store(
+    state_store: &mut StateStore,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    if config.appendonly && config.memory_only {
+        return Err("--appendonly can't be used with --memory-only".into());
+    }
+    state_store.config = RuntimeConfig::new(config);
     state_store.memory_only = config.memory_only;
-
-    Ok(Arc::new(state_store))
+    state_store.cdc = start_cdc(config)?;
+    state_store.backing = start_backing(config)?;
+    state_store
+        .latency
+        .set_threshold(config.latency_monitor_threshold);
+    state_store
+        .slowlog
+        .set_threshold(config.slowlog_log_slower_than);
+    state_store.slowlog.set_max_len(config.slowlog_max_len);
+    state_store.protect_flush = match config.protect_flush.as_str() {
+        "confirm" => FlushProtection::Confirm,
+        "disabled" => FlushProtection::Disabled,
+        _ => FlushProtection::Off,
+    };
+    state_store.pfadd_cardinality_reply = config.pfadd_reply == "cardinality";
+    state_store.dump_layout = DumpLayout::from_config(config);
+    state_store.persist_dbs = PersistDbs::from_config(config);
+    state_store.replication = Replication::new(config.repl_backlog_size);
+    for state in state_store.states.iter() {
+        state
+            .negative_cache
+            .set_capacity(config.negative_cache_size);
+        state
+            .pfadd_cardinality_reply
+            .store(state_store.pfadd_cardinality_reply, Ordering::Relaxed);
+    }
+    load_config_file(state_store).map_err(|e| format!("Bad config file: {}", e))?;
+    Ok(())
 }
 
 /// Make the data directory (directory where the dump file lives)
@@ -87,7 +674,7 @@ fn default_data_dir() -> PathBuf {
 
 /// Get the dump file
 ///
-/// Panics if a data directory cannot be found, or file cannot be opened.
+/// Panics if a data directory cannot be found or created.
 pub fn get_dump_file(config: &Config) -> DumpFile {
     let data_dir: PathBuf = match &config.data_dir {
         Some(dir) => dir.to_path_buf(),
@@ -99,41 +686,104 @@ pub fn get_dump_file(config: &Config) -> DumpFile {
 
     let dump_file = data_dir.join("dump.rodb");
     info!(LOGGER, "Dump File Location: {:?}", dump_file);
-    let opened_file = match OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .append(false)
-        .open(dump_file)
-    {
-        Ok(f) => f,
-        Err(e) => fatal_panic!(format!("Failed to open dump file! {}", e)),
-    };
-    // TODO: Use tokio locks here
-    Arc::new(Mutex::new(opened_file))
+    // A leftover temporary dump is from a save that never finished.
+    let _ = std::fs::remove_file(temp_dump_path(&dump_file));
+    for tmp in split_dump_files(&dump_file, ".rodb.tmp").unwrap_or_default() {
+        let _ = std::fs::remove_file(tmp);
+    }
+    Arc::new(Mutex::new(dump_file))
+}
+
+/// Dump the state to the dump file, recording how it went.
+fn save_to(state: &StateStore, dump_file: &DumpFile) -> Result<(), Box<dyn Error>> {
+    let dump_path = dump_file.lock();
+    let started = Instant::now();
+    let stats = dump_state(state, &dump_path)?;
+    state.latency.record(latency::SAVE, started.elapsed());
+    state.latency.record(latency::SAVE_LOCK, stats.longest_lock);
+    state.stats.record_save();
+    if !stats.consistent {
+        debug!(
+            LOGGER,
+            "Writes landed while saving, the dump may hold some of them partly"
+        );
+    }
+    Ok(())
+}
+
+/// Why SAVE or BGSAVE didn't save.
+#[derive(Debug)]
+pub enum SaveError {
+    InProgress,
+    Failed(Box<dyn Error>),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::InProgress => write!(f, "Background save already in progress"),
+            SaveError::Failed(e) => write!(f, "Failed to save: {}", e),
+        }
+    }
 }
 
+impl Error for SaveError {}
+
+/// Save the state now, on this thread (SAVE).
+pub fn save_now(state: &StateStore, dump_file: &DumpFile) -> Result<(), SaveError> {
+    if !state.stats.start_save() {
+        return Err(SaveError::InProgress);
+    }
+    let res = task::block_in_place(|| save_to(state, dump_file));
+    state.stats.finish_save(res.is_ok());
+    res.map_err(SaveError::Failed)
+}
+
+/// Make every write so far durable (WAITAOF): sync the AOF if it's on,
+/// otherwise save the dump file.
+pub fn sync_writes(state: &StateStore) -> Result<(), Box<dyn Error>> {
+    if let Some(aof) = state.aof.get() {
+        return Ok(task::block_in_place(|| aof.sync_now())?);
+    }
+    match state.dump_file.get() {
+        Some(dump_file) => Ok(save_now(state, dump_file)?),
+        None => Err("nothing is saved to disk (--memory-only)".into()),
+    }
+}
+
+/// Save the state on a blocking thread (BGSAVE), so no event loop waits on it.
+/// Each db is copied a shard at a time, so writers barely wait either.
+pub fn bgsave(state: StateStoreRef, dump_file: DumpFile) -> Result<(), SaveError> {
+    if !state.stats.start_save() {
+        return Err(SaveError::InProgress);
+    }
+    task::spawn_blocking(move || {
+        let res = save_to(&state, &dump_file);
+        if let Err(e) = &res {
+            error!(LOGGER, "FAILED TO DUMP STATE! {}", e);
+        }
+        state.stats.finish_save(res.is_ok());
+    });
+    Ok(())
+}
+
+/// Save the state in the background, unless a save is running already.
 pub fn save_state(state: StateStoreRef, dump_file: DumpFile) {
     info!(
         LOGGER,
-        "Saving state ({}s or >={} ops ran)...", SAVE_STATE_PERIOD_SEC, state.commands_threshold
+        "Saving state ({}s or >={} ops ran)...",
+        SAVE_STATE_PERIOD_SEC,
+        state.config.ops_until_save()
     );
-    match dump_file.try_lock() {
-        Some(mut file) => {
-            if let Err(e) = task::block_in_place(|| dump_state(state, &mut file)) {
-                fatal_panic!("FAILED TO DUMP STATE!", e.to_string());
-            }
-        }
-        None => debug!(
+    if bgsave(state, dump_file).is_err() {
+        debug!(
             LOGGER,
             "Failed to save state! Someone else is currently writing..."
-        ),
+        );
     }
 }
 
-/// Save the current State to Dumpfile.
-///
-/// Panics if state fails to dump.
+/// Save the current State to Dumpfile every so often.
 pub async fn save_state_interval(state: StateStoreRef, dump_file: DumpFile) {
     let mut interval = interval(Duration::from_millis(SAVE_STATE_PERIOD));
     loop {
@@ -145,3 +795,893 @@ pub async fn save_state_interval(state: StateStoreRef, dump_file: DumpFile) {
         }
     }
 }
+
+/// How often the AOF is synced to disk (`--appendfsync`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AofFsync {
+    /// After every write. Nothing acknowledged is lost, but every write waits on the disk.
+    Always,
+    /// Once a second, losing at most a second of writes.
+    EverySec,
+    /// Whenever the OS gets around to it.
+    No,
+}
+
+impl AofFsync {
+    fn from_config(config: &Config) -> Self {
+        match config.appendfsync.as_str() {
+            "always" => AofFsync::Always,
+            "no" => AofFsync::No,
+            _ => AofFsync::EverySec,
+        }
+    }
+}
+
+/// The append-only file (`--appendonly`): each committed write, as the
+/// RESP command that made it, with a SELECT whenever the db changes.
+///
+/// Most writes are logged as they were sent. SET, GETEX and RESTORE are
+/// logged as the RESTORE of what they left (with an absolute deadline), so a
+/// replay doesn't restart their TTLs, and so is SPOP, so a replay pops the
+/// same members. As what it does depends on when it ran, QPOP is logged as a
+/// QCLAIM of the job it handed out, see `queue::qpop_as_claim`, and
+/// XREADGROUP, XCLAIM and XAUTOCLAIM are logged as an XCLAIM (or XACK) of
+/// each entry they handed out, see `streams::group_read_as_claims`.
+///
+/// Blocking pops are logged as the pop they did (an LPOP, RPOP or LMOVE),
+/// so a replay never waits, and not at all if they timed out. Keys expired
+/// by commands or the sweep are logged as DELs, see `expiry::log_expired`.
+///
+/// Writes hold the store's `write_order` from running until they're logged,
+/// so the log has them in the order they landed. Blocking pops are the
+/// exception, as they can wait forever: one may be logged just after a write
+/// that followed it.
+///
+/// Writes to dbs that aren't persisted aren't logged.
+pub struct Aof {
+    path: PathBuf,
+    fsync: AofFsync,
+    persist_dbs: PersistDbs,
+    log: Mutex<AofLog>,
+    rewriting: AtomicBool,
+}
+
+struct AofLog {
+    file: File,
+    /// The db the log last SELECTed.
+    db: Option<Index>,
+    /// Whether anything was written since the last fsync.
+    dirty: bool,
+    /// Writes logged during a rewrite, to copy into the new log.
+    rewrite_buffer: Option<BytesMut>,
+}
+
+/// Ops logged as the state of their keys rather than as sent.
+const LOGGED_AS_STATE: &[&str] = &["Set", "GetEx", "Restore", "SPop", EXPIRED];
+
+/// The op name keys expired by commands or the sweep are logged under.
+pub const EXPIRED: &str = "Expired";
+
+fn encode_command(command: RedisValueRef, dst: &mut BytesMut) {
+    // Encoding into memory can't fail.
+    let _ = RespParser::default().encode(command, dst);
+}
+
+fn select_command(db: Index) -> RedisValueRef {
+    RedisValueRef::Array(vec![bulk("SELECT"), bulk(db.to_string())])
+}
+
+/// A command putting `key` back as it is now: a RESTORE, or a DEL if it's gone.
+fn key_state_command(state: &State, key: &Key) -> RedisValueRef {
+    match dump_key(state, key) {
+        Some(payload) => RedisValueRef::Array(vec![
+            bulk("RESTORE"),
+            bulk(key.clone()),
+            bulk(state.expiry(key).unwrap_or(0).to_string()),
+            bulk(payload),
+            bulk("REPLACE"),
+            bulk("ABSTTL"),
+        ]),
+        None => RedisValueRef::Array(vec![bulk("DEL"), bulk(key.clone())]),
+    }
+}
+
+/// The command to log for a blocking pop `op` that replied `res`: the pop
+/// it did, without blocking, so neither a replay nor a replica waits. BLPOP
+/// and BRPOP become an LPOP or RPOP of the key they popped from, and BLMOVE
+/// and BRPOPLPUSH an LMOVE. None if nothing was popped. Other ops' commands
+/// are logged as they are.
+pub fn unblocked_command(
+    op: &str,
+    command: RedisValueRef,
+    res: &ReturnValue,
+) -> Option<RedisValueRef> {
+    let pop = match op {
+        "BLPop" => "LPOP",
+        "BRPop" => "RPOP",
+        "BLMove" => "LMOVE",
+        _ => return Some(command),
+    };
+    let args = match command {
+        RedisValueRef::Array(args) => args,
+        _ => return None,
+    };
+    match res {
+        ReturnValue::MultiStringRes(popped) if op != "BLMove" => Some(RedisValueRef::Array(vec![
+            bulk(pop),
+            bulk(popped.get(0)?.clone()),
+        ])),
+        // BLMOVE source destination wherefrom whereto timeout, or
+        // BRPOPLPUSH source destination timeout.
+        ReturnValue::StringRes(_) if op == "BLMove" => {
+            let mut lmove = vec![bulk(pop)];
+            lmove.extend(args.get(1..3)?.iter().cloned());
+            match args.get(3..5) {
+                Some(ends) if args.len() == 6 => lmove.extend(ends.iter().cloned()),
+                _ => lmove.extend(vec![bulk("RIGHT"), bulk("LEFT")]),
+            }
+            Some(RedisValueRef::Array(lmove))
+        }
+        _ => None,
+    }
+}
+
+/// Encode a committed write to db `db` the way the AOF logs it and replicas
+/// get it, SELECTing `db` first unless `selected` says it already is.
+/// `op` is the op's name, and `keys` the keys it wrote.
+pub fn encode_write(
+    dst: &mut BytesMut,
+    selected: &mut Option<Index>,
+    db: Index,
+    state: &State,
+    op: &str,
+    keys: &[Key],
+    command: &RedisValueRef,
+) {
+    if *selected != Some(db) {
+        encode_command(select_command(db), dst);
+        *selected = Some(db);
+    }
+    if LOGGED_AS_STATE.contains(&op) {
+        for key in keys {
+            encode_command(key_state_command(state, key), dst);
+        }
+    } else if GROUP_READS.contains(&op) {
+        // Already turned into the commands to log.
+        if let RedisValueRef::Array(commands) = command {
+            for command in commands {
+                encode_command(command.clone(), dst);
+            }
+        }
+    } else if op == "XAdd" {
+        encode_command(xadd_as_added(state, &keys[0], command), dst);
+    } else {
+        encode_command(command.clone(), dst);
+    }
+}
+
+/// The dbs `store` persists, in order.
+fn persisted_dbs(store: &StateStore) -> Vec<(Index, StateRef)> {
+    let mut dbs = store.dbs();
+    dbs.retain(|(db, _)| store.persist_dbs.persists(*db));
+    dbs
+}
+
+/// Commands putting back every key in `dbs`.
+fn state_commands_of<S: Deref<Target = State>>(dbs: &[(Index, S)]) -> BytesMut {
+    let mut commands = BytesMut::new();
+    for (db, state) in dbs {
+        encode_command(select_command(*db), &mut commands);
+        for key in state.keys() {
+            if state.structure_of(&key).is_some() {
+                encode_command(key_state_command(state, &key), &mut commands);
+            }
+        }
+    }
+    commands
+}
+
+/// Commands putting back every key in `store`, of the dbs it persists.
+fn state_commands(store: &StateStore) -> BytesMut {
+    state_commands_of(&persisted_dbs(store))
+}
+
+/// Where the AOF lives, next to the dump file.
+fn aof_path(dump_path: &Path) -> PathBuf {
+    dump_path.with_file_name("appendonly.aof")
+}
+
+/// Write `contents` as the AOF at `path`, the same way dumps are written.
+/// Returns the file, to append to.
+fn write_aof(path: &Path, contents: &[u8]) -> io::Result<File> {
+    let tmp_path = temp_dump_path(path);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)?;
+    Ok(tmp)
+}
+
+impl Aof {
+    fn new(path: PathBuf, fsync: AofFsync, persist_dbs: PersistDbs, file: File) -> Self {
+        Aof {
+            path,
+            fsync,
+            persist_dbs,
+            log: Mutex::new(AofLog {
+                file,
+                db: None,
+                dirty: false,
+                rewrite_buffer: None,
+            }),
+            rewriting: AtomicBool::new(false),
+        }
+    }
+
+    /// Log a committed write to db `db`. `op` is the op's name, and `keys` the keys it wrote.
+    pub fn append(
+        &self,
+        db: Index,
+        state: &State,
+        op: &str,
+        keys: &[Key],
+        command: &RedisValueRef,
+    ) -> io::Result<()> {
+        if !self.persist_dbs.persists(db) {
+            return Ok(());
+        }
+        let mut entry = BytesMut::new();
+        let mut log = self.log.lock();
+        encode_write(&mut entry, &mut log.db, db, state, op, keys, command);
+        if let Err(e) = log.file.write_all(&entry) {
+            // We don't know how much of the SELECT made it.
+            log.db = None;
+            return Err(e);
+        }
+        if let Some(buffer) = log.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(&entry);
+        }
+        match self.fsync {
+            AofFsync::Always => log.file.sync_data()?,
+            AofFsync::EverySec => log.dirty = true,
+            AofFsync::No => {}
+        }
+        Ok(())
+    }
+
+    /// Sync whatever was logged since the last sync, without holding up writers meanwhile.
+    fn sync(&self) -> io::Result<()> {
+        let file = {
+            let mut log = self.log.lock();
+            if !log.dirty {
+                return Ok(());
+            }
+            log.dirty = false;
+            log.file.try_clone()?
+        };
+        file.sync_data()
+    }
+
+    /// Sync everything logged so far, even if a sync is already under way.
+    pub fn sync_now(&self) -> io::Result<()> {
+        let file = {
+            let mut log = self.log.lock();
+            log.dirty = false;
+            log.file.try_clone()?
+        };
+        file.sync_data()
+    }
+
+    /// Whether BGREWRITEAOF is running.
+    pub fn rewriting(&self) -> bool {
+        self.rewriting.load(Ordering::SeqCst)
+    }
+
+    /// Mark a rewrite as started. False if one already is.
+    pub fn start_rewrite(&self) -> bool {
+        !self.rewriting.swap(true, Ordering::SeqCst)
+    }
+
+    /// Replace the log with the commands putting back `store` as it is, dropping the
+    /// history that led there. Must follow a successful `start_rewrite`.
+    ///
+    /// Writers only wait while the dbs are copied, not while the copies are
+    /// encoded or written out: their writes go to both logs until the new
+    /// one takes over.
+    pub async fn rewrite(&self, store: &StateStore) -> io::Result<()> {
+        let dbs: Vec<(Index, Snapshot)> = {
+            let _order = store.write_order.write().await;
+            let mut log = self.log.lock();
+            log.rewrite_buffer = Some(BytesMut::new());
+            // Have the buffer start with a SELECT.
+            log.db = None;
+            drop(log);
+            task::block_in_place(|| {
+                persisted_dbs(store)
+                    .into_iter()
+                    .map(|(db, state)| (db, state.snapshot()))
+                    .collect()
+            })
+        };
+        let snapshot = task::block_in_place(|| state_commands_of(&dbs));
+        drop(dbs);
+        let res = task::block_in_place(|| self.finish_rewrite(&snapshot));
+        if res.is_err() {
+            self.log.lock().rewrite_buffer = None;
+            let _ = std::fs::remove_file(temp_dump_path(&self.path));
+        }
+        self.rewriting.store(false, Ordering::SeqCst);
+        res
+    }
+
+    fn finish_rewrite(&self, snapshot: &[u8]) -> io::Result<()> {
+        let tmp_path = temp_dump_path(&self.path);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(snapshot)?;
+        tmp.sync_all()?;
+        let mut log = self.log.lock();
+        if let Some(buffer) = log.rewrite_buffer.take() {
+            tmp.write_all(&buffer)?;
+            tmp.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+        log.file = tmp;
+        log.dirty = false;
+        Ok(())
+    }
+}
+
+/// How many bytes of `contents` are whole commands. Only the end can be
+/// torn (by a crash mid-append); anything else unreadable is an error.
+fn complete_commands_len(contents: &[u8]) -> Result<usize, Box<dyn Error>> {
+    let mut buf = BytesMut::from(contents);
+    let mut parser = RespParser::with_limits(RespLimits::unlimited());
+    loop {
+        match parser.decode(&mut buf) {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(contents.len() - buf.len()),
+            Err(e) => {
+                let at = contents.len() - buf.len();
+                return Err(format!("Corrupt AOF command at byte {}: {:?}", at, e).into());
+            }
+        }
+    }
+}
+
+async fn replay_aof_file(
+    path: &Path,
+    state: StateStoreRef,
+    scripting_bridge: Arc<ScriptingBridge>,
+) -> Result<(), Box<dyn Error>> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let complete = complete_commands_len(&contents)?;
+    if complete < contents.len() {
+        warn!(
+            LOGGER,
+            "Dropping a torn command ({} bytes) at the end of the AOF",
+            contents.len() - complete
+        );
+    }
+    let report = bulk_load(&contents[..complete], state, scripting_bridge).await?;
+    if report.errors != 0 {
+        warn!(
+            LOGGER,
+            "{} of the {} AOF commands failed", report.errors, report.commands
+        );
+    }
+    info!(LOGGER, "Replayed {} AOF commands", report.commands);
+    Ok(())
+}
+
+/// Apply the commands in the AOF, if there is one (`load_state` skipped the dump for it).
+pub async fn replay_aof(
+    state: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+) -> Result<(), Box<dyn Error>> {
+    let path = aof_path(&dump_file.lock());
+    replay_aof_file(&path, state, scripting_bridge).await
+}
+
+/// Start logging writes to the AOF. It's first written afresh from the current
+/// state, which also drops any torn command left at its end.
+pub fn start_aof(
+    state: StateStoreRef,
+    dump_file: DumpFile,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let path = aof_path(&dump_file.lock());
+    info!(LOGGER, "AOF Location: {:?}", path);
+    let file = write_aof(&path, &state_commands(&state))?;
+    let fsync = AofFsync::from_config(config);
+    let persist_dbs = state.persist_dbs.clone();
+    if state
+        .aof
+        .set(Aof::new(path, fsync, persist_dbs, file))
+        .is_err()
+    {
+        return Err("The AOF was already started".into());
+    }
+    if fsync == AofFsync::EverySec {
+        tokio::spawn(aof_fsync_interval(state));
+    }
+    Ok(())
+}
+
+/// Sync the AOF once a second, for `--appendfsync everysec`.
+async fn aof_fsync_interval(state: StateStoreRef) {
+    let mut interval = interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if let Some(aof) = state.aof.get() {
+            if let Err(e) = task::block_in_place(|| aof.sync()) {
+                error!(LOGGER, "Failed to sync the AOF: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_database {
+    use super::{
+        backup_dump_path, complete_commands_len, dump_state, failpoints, pending_dump_files,
+        read_dumps, read_state, replay_aof_file, split_dump_files, split_dump_path, state_commands,
+        temp_dump_path, unblocked_command, unreadable_dump_path, write_aof, Aof, AofFsync,
+        DumpLayout, PersistDbs,
+    };
+    use crate::expiry::log_expired;
+    use crate::expiry::now_ms;
+    use crate::scripting::ScriptingBridge;
+    use crate::startup::Config;
+    use crate::test_util::command;
+    use crate::types::{Index, RedisValueRef, ReturnValue, StateStore};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use structopt::StructOpt;
+    use tokio::sync::mpsc::channel;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("redis-oxide-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn store_with(value: &'static str) -> StateStore {
+        let store = StateStore::default();
+        store.get_default().kv.insert(
+            Bytes::from_static(b"key"),
+            Bytes::from_static(value.as_bytes()),
+        );
+        store
+    }
+
+    fn value_in(store: &StateStore) -> Option<Bytes> {
+        store
+            .get_default()
+            .kv
+            .get(&Bytes::from_static(b"key"))
+            .map(|v| v.value().clone())
+    }
+
+    #[test]
+    fn test_missing_dump_loads_empty() {
+        let dir = scratch_dir("missing");
+        let store = read_state(&dir.join("dump.rodb"), false).unwrap();
+        assert!(store.states.is_empty());
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let dir = scratch_dir("round-trip");
+        let path = dir.join("dump.rodb");
+        dump_state(&store_with("first"), &path).unwrap();
+        dump_state(&store_with("second"), &path).unwrap();
+        assert_eq!(
+            value_in(&read_state(&path, false).unwrap()),
+            Some("second".into())
+        );
+        assert!(!temp_dump_path(&path).exists());
+    }
+
+    #[test]
+    fn test_save_progress() {
+        let dir = scratch_dir("progress");
+        let path = dir.join("dump.rodb");
+        let store = store_with("value");
+        store
+            .get_or_create(3)
+            .kv
+            .insert(Bytes::from_static(b"other"), Bytes::from_static(b"value"));
+        assert!(store.stats.start_save());
+        dump_state(&store, &path).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        let progress = store.stats.save_progress().unwrap();
+        assert_eq!((progress.dbs_done, progress.dbs), (2, 2));
+        assert_eq!(progress.bytes, size);
+        store.stats.finish_save(true);
+        assert_eq!(store.stats.save_progress(), None);
+        assert_eq!(store.stats.last_save_bytes(), size);
+        assert!(store.stats.last_save_secs() >= 0);
+        assert_eq!(read_state(&path, false).unwrap().states.len(), 2);
+    }
+
+    #[test]
+    fn test_crash_during_dump_recovers() {
+        let cases = [
+            ("dump-before-write", "old"),
+            ("dump-mid-write", "old"),
+            ("dump-before-sync", "old"),
+            ("dump-before-rename", "old"),
+            ("dump-after-rename", "new"),
+        ];
+        for (failpoint, survivor) in cases.iter() {
+            let dir = scratch_dir(failpoint);
+            let path = dir.join("dump.rodb");
+            dump_state(&store_with("old"), &path).unwrap();
+            failpoints::arm(*failpoint);
+            assert!(dump_state(&store_with("new"), &path).is_err());
+            let recovered = read_dumps(&path, DumpLayout::Single, false).unwrap();
+            assert_eq!(
+                value_in(&recovered),
+                Some(Bytes::from(*survivor)),
+                "crash at {}",
+                failpoint
+            );
+            // The next save goes through despite any leftover temp file.
+            dump_state(&store_with("next"), &path).unwrap();
+            assert_eq!(
+                value_in(&read_state(&path, false).unwrap()),
+                Some("next".into())
+            );
+            assert!(pending_dump_files(&path).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_crash_during_first_dump_loads_empty() {
+        let dir = scratch_dir("first");
+        let path = dir.join("dump.rodb");
+        failpoints::arm("dump-before-rename");
+        assert!(dump_state(&store_with("new"), &path).is_err());
+        assert!(read_state(&path, false).unwrap().states.is_empty());
+    }
+
+    #[test]
+    fn test_failed_load_reports_error() {
+        let dir = scratch_dir("load");
+        let path = dir.join("dump.rodb");
+        dump_state(&store_with("old"), &path).unwrap();
+        failpoints::arm("load-before-read");
+        assert!(read_state(&path, false).is_err());
+        assert_eq!(
+            value_in(&read_state(&path, false).unwrap()),
+            Some("old".into())
+        );
+    }
+
+    #[test]
+    fn test_older_dump_is_backed_up() {
+        let dir = scratch_dir("backup");
+        let path = dir.join("dump.rodb");
+        dump_state(&store_with("new"), &path).unwrap();
+        read_state(&path, false).unwrap();
+        assert!(!backup_dump_path(&path).exists());
+
+        // A StateStore as it was before dumps were versioned, with only
+        // the structures there were then.
+        let old = store_with("old").get_default();
+        let structures = (
+            &old.kv,
+            &old.sets,
+            &old.lists,
+            &old.hashes,
+            &old.zsets,
+            &old.blooms,
+            &old.stacks,
+            &old.hyperloglogs,
+        );
+        let dbs: HashMap<_, _> = std::iter::once((0 as Index, structures)).collect();
+        let legacy = rmps::to_vec(&(dbs,)).unwrap();
+        std::fs::write(&path, &legacy).unwrap();
+        assert_eq!(
+            value_in(&read_state(&path, false).unwrap()),
+            Some("old".into())
+        );
+        assert_eq!(std::fs::read(backup_dump_path(&path)).unwrap(), legacy);
+    }
+
+    #[test]
+    fn test_unreadable_dump_is_left_alone() {
+        let dir = scratch_dir("unreadable");
+        let path = dir.join("dump.rodb");
+        std::fs::write(&path, b"\xc1").unwrap();
+        let err = read_state(&path, false).err().unwrap().to_string();
+        assert!(err.contains("left as it was"), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), b"\xc1");
+        assert!(!backup_dump_path(&path).exists());
+    }
+
+    /// store_with(value), plus a key and a list in db 3.
+    fn store_with_db3(value: &'static str) -> StateStore {
+        let store = store_with(value);
+        let db3 = store.get_or_create(3);
+        db3.kv
+            .insert(Bytes::from_static(b"other"), Bytes::from_static(b"v"));
+        db3.lists.insert(
+            Bytes::from_static(b"list"),
+            vec![Bytes::from_static(b"a")].into(),
+        );
+        store
+    }
+
+    #[test]
+    fn test_split_layouts() {
+        let dir = scratch_dir("split");
+        let path = dir.join("dump.rodb");
+        let mut store = store_with_db3("value");
+        store.dump_layout = DumpLayout::Db;
+        dump_state(&store, &path).unwrap();
+        assert!(split_dump_path(&path, 0, None).exists());
+        assert!(split_dump_path(&path, 3, None).exists());
+        assert!(!path.exists());
+        let loaded = read_dumps(&path, DumpLayout::Db, false).unwrap();
+        assert_eq!(value_in(&loaded), Some("value".into()));
+        assert_eq!(loaded.get_or_create(3).lists.len(), 1);
+
+        // A save in another layout replaces the files of the old one.
+        store.dump_layout = DumpLayout::Structure;
+        dump_state(&store, &path).unwrap();
+        assert!(!split_dump_path(&path, 3, None).exists());
+        assert!(split_dump_path(&path, 3, Some("kv")).exists());
+        assert!(split_dump_path(&path, 3, Some("lists")).exists());
+        assert!(!split_dump_path(&path, 0, Some("lists")).exists());
+        // There's no dump.rodb, so the split files are read.
+        let loaded = read_dumps(&path, DumpLayout::Single, false).unwrap();
+        assert_eq!(value_in(&loaded), Some("value".into()));
+        let db3 = loaded.get_or_create(3);
+        assert_eq!((db3.kv.len(), db3.lists.len()), (1, 1));
+
+        store.dump_layout = DumpLayout::Single;
+        dump_state(&store, &path).unwrap();
+        assert!(path.exists());
+        assert!(split_dump_files(&path, ".rodb").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_crash_while_changing_layout() {
+        let other_in = |store: &StateStore| {
+            store
+                .get_or_create(3)
+                .kv
+                .get(&Bytes::from_static(b"other"))
+                .map(|v| v.value().clone())
+        };
+        let cases = [
+            (DumpLayout::Single, "dump-before-rename", "old"),
+            (DumpLayout::Single, "dump-after-rename", "new"),
+            (DumpLayout::Structure, "dump-before-rename", "old"),
+            (DumpLayout::Structure, "dump-after-rename", "new"),
+        ];
+        for (from, failpoint, survivor) in cases.iter() {
+            let dir = scratch_dir(&format!("layout-{:?}-{}", from, failpoint));
+            let path = dir.join("dump.rodb");
+            let mut old = store_with_db3("old");
+            old.dump_layout = *from;
+            dump_state(&old, &path).unwrap();
+            // The new save has another "other" in db 3, and no list. It
+            // crashes once every per-db file is written.
+            let mut new = store_with("new");
+            new.get_or_create(3)
+                .kv
+                .insert(Bytes::from_static(b"other"), Bytes::from_static(b"w"));
+            new.dump_layout = DumpLayout::Db;
+            failpoints::arm(*failpoint);
+            assert!(dump_state(&new, &path).is_err());
+
+            // Either save loads whole, without the files of the other.
+            let loaded = read_dumps(&path, DumpLayout::Db, false).unwrap();
+            let case = format!("{:?} to db, crash at {}", from, failpoint);
+            let (other, lists) = if *survivor == "old" {
+                ("v", 1)
+            } else {
+                ("w", 0)
+            };
+            assert_eq!(value_in(&loaded), Some(Bytes::from(*survivor)), "{}", case);
+            assert_eq!(other_in(&loaded), Some(Bytes::from(other)), "{}", case);
+            assert_eq!(loaded.get_or_create(3).lists.len(), lists, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_unreadable_split_file_is_moved_aside() {
+        let dir = scratch_dir("split-unreadable");
+        let path = dir.join("dump.rodb");
+        let mut store = store_with_db3("value");
+        store.dump_layout = DumpLayout::Db;
+        dump_state(&store, &path).unwrap();
+        let db3 = split_dump_path(&path, 3, None);
+        std::fs::write(&db3, b"\xc1").unwrap();
+        let loaded = read_dumps(&path, DumpLayout::Db, false).unwrap();
+        assert_eq!(value_in(&loaded), Some("value".into()));
+        assert!(!loaded.states.contains_key(&3));
+        assert!(!db3.exists());
+        assert_eq!(std::fs::read(unreadable_dump_path(&db3)).unwrap(), b"\xc1");
+    }
+
+    #[test]
+    fn test_persist_dbs() {
+        let dir = scratch_dir("persist-dbs");
+        let path = dir.join("dump.rodb");
+        let config = Config::from_iter(&["redis-oxide", "--persist-dbs", "0,1"]);
+        let mut store = store_with_db3("value");
+        store.persist_dbs = PersistDbs::from_config(&config);
+        assert!(store.persist_dbs.persists(1) && !store.persist_dbs.persists(3));
+        dump_state(&store, &path).unwrap();
+        let loaded = read_state(&path, false).unwrap();
+        assert_eq!(value_in(&loaded), Some("value".into()));
+        assert!(!loaded.states.contains_key(&3));
+
+        // Nor is db 3 logged to the AOF.
+        let aof_path = dir.join("appendonly.aof");
+        let aof = start_aof_at(&store, &aof_path);
+        let keys = [Bytes::from_static(b"other")];
+        let set = command(&["SET", "other", "w"]);
+        aof.append(3, &store.get_or_create(3), "Set", &keys, &set)
+            .unwrap();
+        let logged = std::fs::read(&aof_path).unwrap();
+        assert!(!logged.windows(5).any(|w| w == b"other"));
+    }
+
+    fn start_aof_at(store: &StateStore, path: &Path) -> Aof {
+        let file = write_aof(path, &state_commands(store)).unwrap();
+        Aof::new(
+            path.to_path_buf(),
+            AofFsync::Always,
+            store.persist_dbs.clone(),
+            file,
+        )
+    }
+
+    async fn replayed(path: &Path) -> Arc<StateStore> {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        replay_aof_file(path, store.clone(), ScriptingBridge::new(prog_sx))
+            .await
+            .unwrap();
+        store
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_aof_replay() {
+        let dir = scratch_dir("aof-replay");
+        let path = dir.join("appendonly.aof");
+        let store = store_with("old");
+        let aof = start_aof_at(&store, &path);
+        // SET is logged as what it left, TTL and all.
+        let db0 = store.get_default();
+        let deadline = now_ms() + 100_000;
+        db0.kv.insert(Bytes::from_static(b"key"), "new".into());
+        db0.set_expiry(Bytes::from_static(b"key"), deadline);
+        let keys = [Bytes::from_static(b"key")];
+        let set = command(&["SET", "key", "new", "PX", "100000"]);
+        aof.append(0, &db0, "Set", &keys, &set).unwrap();
+        // Everything else as sent, after a SELECT.
+        let keys = [Bytes::from_static(b"l")];
+        let rpush = command(&["RPUSH", "l", "x", "y"]);
+        aof.append(1, &store.get_or_create(1), "RPush", &keys, &rpush)
+            .unwrap();
+        // A crash mid-append leaves a torn command.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"*2\r\n$3\r\nDEL\r\n$1").unwrap();
+
+        let restored = replayed(&path).await;
+        assert_eq!(value_in(&restored), Some("new".into()));
+        assert_eq!(restored.get_default().expiry(b"key"), Some(deadline));
+        let l = restored.get_or_create(1);
+        assert_eq!(l.lists.get(&Bytes::from_static(b"l")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_blocking_pops_are_logged_unblocked() {
+        let popped = ReturnValue::MultiStringRes(vec!["b".into(), "x".into()]);
+        let blpop = command(&["BLPOP", "a", "b", "0"]);
+        assert_eq!(
+            unblocked_command("BLPop", blpop.clone(), &popped),
+            Some(command(&["LPOP", "b"]))
+        );
+        let timed_out = ReturnValue::Ident(RedisValueRef::NullArray);
+        assert_eq!(unblocked_command("BLPop", blpop, &timed_out), None);
+        let moved = ReturnValue::StringRes("x".into());
+        let blmove = command(&["BLMOVE", "a", "b", "LEFT", "LEFT", "0"]);
+        assert_eq!(
+            unblocked_command("BLMove", blmove, &moved),
+            Some(command(&["LMOVE", "a", "b", "LEFT", "LEFT"]))
+        );
+        let brpoplpush = command(&["BRPOPLPUSH", "a", "b", "0"]);
+        assert_eq!(
+            unblocked_command("BLMove", brpoplpush, &moved),
+            Some(command(&["LMOVE", "a", "b", "RIGHT", "LEFT"]))
+        );
+        let rpush = command(&["RPUSH", "a", "x"]);
+        assert_eq!(
+            unblocked_command("RPush", rpush.clone(), &ReturnValue::IntRes(1)),
+            Some(rpush)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_expired_keys_are_logged() {
+        let dir = scratch_dir("aof-expired");
+        let path = dir.join("appendonly.aof");
+        let store = store_with("old");
+        let aof = start_aof_at(&store, &path);
+        assert!(store.aof.set(aof).is_ok());
+        let db0 = store.get_default();
+        db0.set_expiry(Bytes::from_static(b"key"), 0);
+        assert!(db0.expire_if_due(b"key"));
+        let expired = db0.take_expired();
+        log_expired(&store, 0, &db0, &expired, true, false).unwrap();
+        assert_eq!(value_in(&*replayed(&path).await), None);
+
+        // Brought back since, it's logged as it is now.
+        db0.kv.insert(Bytes::from_static(b"key"), "new".into());
+        log_expired(&store, 0, &db0, &expired, false, false).unwrap();
+        assert_eq!(value_in(&*replayed(&path).await), Some("new".into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_aof_rewrite() {
+        let dir = scratch_dir("aof-rewrite");
+        let path = dir.join("appendonly.aof");
+        let store = store_with("first");
+        let aof = start_aof_at(&store, &path);
+        let db0 = store.get_default();
+        let keys = [Bytes::from_static(b"counter")];
+        for _ in 0..100 {
+            aof.append(0, &db0, "Incr", &keys, &command(&["INCR", "counter"]))
+                .unwrap();
+        }
+        db0.kv.insert(Bytes::from_static(b"counter"), "100".into());
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        assert!(aof.start_rewrite());
+        assert!(!aof.start_rewrite());
+        aof.rewrite(&store).await.unwrap();
+        assert!(!aof.rewriting());
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+        // Writes after the rewrite go to the new log.
+        let keys = [Bytes::from_static(b"after")];
+        aof.append(0, &db0, "MSet", &keys, &command(&["MSET", "after", "1"]))
+            .unwrap();
+
+        let restored = replayed(&path).await;
+        let db0 = restored.get_default();
+        assert_eq!(value_in(&restored), Some("first".into()));
+        assert_eq!(
+            db0.kv.get(&Bytes::from_static(b"counter")).unwrap().value(),
+            &Bytes::from_static(b"100")
+        );
+        assert!(db0.kv.contains_key(&Bytes::from_static(b"after")));
+    }
+
+    #[test]
+    fn test_corrupt_aof_is_an_error() {
+        assert_eq!(
+            complete_commands_len(b"*1\r\n$4\r\nPING\r\n*1").unwrap(),
+            14
+        );
+        assert!(complete_commands_len(b"*1\r\n$4\r\nPING\r\n!oops\r\n").is_err());
+    }
+}
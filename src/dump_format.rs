@@ -0,0 +1,1002 @@
+//! On-disk / on-wire format for whole-state transfers (dump files, warm restarts).
+//!
+//! A stream starts with `MAGIC`, then a framed `Capabilities` header saying
+//! which structures the writer knows about and how it stores them. Every
+//! non-empty structure of every db follows in its own frame, so a reader can
+//! refuse a stream it can't fully understand up front, or skip the parts it
+//! doesn't know instead of failing halfway through deserialization.
+//!
+//! Frames are a big-endian length followed by that many bytes: a u32 for
+//! the capabilities header, and a u64 for every frame after it (a u32 in
+//! format 1, which couldn't hold structures of 4GiB or more).
+//! Streams without the magic are legacy dumps (a bare rmp StateStore), whose
+//! structures are all migrated from their first schema.
+//!
+//! Structures written under an older schema are brought up to date by the
+//! `MIGRATIONS` on the way in.
+
+use crate::bloom::{DESIRED_FAILURE_RATE, EST_INSERTS};
+use crate::data_structures::bloom_filter::BloomFilter;
+use crate::data_structures::sorted_set::SortedSet;
+use crate::data_structures::stream::Stream;
+use crate::hashing::sha1;
+use crate::logger::LOGGER;
+use crate::snapshot::SnapshotStats;
+use crate::types::{Index, Key, State, StateStore};
+use growable_bloom_filter::GrowableBloom;
+use serde::de::IgnoredAny;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+const MAGIC: &[u8; 4] = b"RDOX";
+/// Bump when the framing itself changes.
+pub const FORMAT_VERSION: u32 = 2;
+/// Cargo features which change what can be stored.
+const FEATURES: &[&str] = &[];
+
+/// Every structure of a `State` holding keys, with a description of how
+/// it's stored. Change a description whenever that structure's layout
+/// changes, and add new structures here so everything walking them sees them.
+///
+/// `for_each_structure!(callback!(args))` expands to
+/// `callback!(args kv: "<schema>", sets: "<schema>", ...)`. With
+/// `with_expirations, ` in front, `expirations` is passed last too, for code
+/// handling the whole state rather than its keys.
+#[macro_export]
+macro_rules! for_each_structure {
+    ($callback:ident!($($args:tt)*)) => {
+        $crate::for_each_structure! { @list $callback!($($args)*) [] }
+    };
+    (with_expirations, $callback:ident!($($args:tt)*)) => {
+        $crate::for_each_structure! {
+            @list $callback!($($args)*) [, expirations: "DashMap<Bytes, u64> unix ms v1"]
+        }
+    };
+    (@list $callback:ident!($($args:tt)*) [$($extra:tt)*]) => {
+        $callback! { $($args)*
+            kv: "DashMap<Bytes, Bytes> v1",
+            sets: "DashMap<Bytes, HashSet<Bytes>> v1",
+            lists: "DashMap<Bytes, VecDeque<Bytes>> v1",
+            hashes: "DashMap<Bytes, HashMap<Bytes, Bytes>> v1",
+            zsets: "DashMap<Bytes, SortedSet> f64 scores v3",
+            blooms: "DashMap<Bytes, BloomFilter> settings v2",
+            stacks: "DashMap<Bytes, Stack<Bytes>> v1",
+            hyperloglogs: "DashMap<Bytes, HyperLogLog<Bytes>> v1",
+            streams: "DashMap<Bytes, Stream> consumers v3",
+            cuckoos: "DashMap<Bytes, CuckooFilter> v1",
+            count_mins: "DashMap<Bytes, CountMinSketch> v1",
+            topks: "DashMap<Bytes, TopK> v1",
+            queues: "DashMap<Bytes, Queue> v1"
+            $($extra)*
+        }
+    };
+}
+
+/// The structures `for_each_structure!` declares, and how they're stored.
+macro_rules! structures {
+    ($($field:ident: $schema:expr),* $(,)?) => {
+        const STRUCTURES: &[(&str, &str)] = &[$((stringify!($field), $schema)),*];
+
+        pub fn structure_len(state: &State, name: &str) -> usize {
+            match name {
+                $(stringify!($field) => state.$field.len(),)*
+                _ => 0,
+            }
+        }
+
+        fn encode_structure(state: &State, name: &str) -> Result<Vec<u8>, rmps::encode::Error> {
+            match name {
+                $(stringify!($field) => rmps::to_vec(&state.$field),)*
+                _ => Ok(Vec::new()),
+            }
+        }
+
+        /// Move the structures holding anything in `from` into `into`.
+        fn move_structures(into: &mut State, from: &mut State) {
+            $(if from.$field.len() != 0 {
+                into.$field = std::mem::take(&mut from.$field);
+            })*
+        }
+
+        /// Returns false if `name` isn't a structure we know.
+        fn decode_structure(
+            state: &mut State,
+            name: &str,
+            bytes: &[u8],
+        ) -> Result<bool, rmps::decode::Error> {
+            match name {
+                $(stringify!($field) => {
+                    state.$field = rmps::from_read_ref(bytes)?;
+                    Ok(true)
+                })*
+                _ => Ok(false),
+            }
+        }
+    };
+}
+
+for_each_structure!(with_expirations, structures!());
+
+fn schema_hash(name: &str, schema: &str) -> u64 {
+    let digest = sha1(format!("{} {}", name, schema).as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Re-encodes a structure written under one schema in the next one.
+type Migration = fn(&[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+
+/// (structure, old schema, new schema, migration). Add one whenever a schema
+/// above changes, so older dumps keep loading.
+const MIGRATIONS: &[(&str, &str, &str, Migration)] = &[
+    (
+        "zsets",
+        "DashMap<Bytes, SortedSet> v1",
+        "DashMap<Bytes, SortedSet> f64 scores v3",
+        integer_scores_to_float,
+    ),
+    (
+        "zsets",
+        "DashMap<Bytes, SortedSet> v2",
+        "DashMap<Bytes, SortedSet> f64 scores v3",
+        integer_scores_to_float,
+    ),
+    (
+        "streams",
+        "DashMap<Bytes, Stream> v1",
+        "DashMap<Bytes, Stream> consumers v3",
+        add_consumer_groups,
+    ),
+    (
+        "streams",
+        "DashMap<Bytes, Stream> consumer groups v2",
+        "DashMap<Bytes, Stream> consumers v3",
+        add_consumers,
+    ),
+    (
+        "blooms",
+        "DashMap<Bytes, GrowableBloom> v1",
+        "DashMap<Bytes, BloomFilter> settings v2",
+        add_bloom_settings,
+    ),
+];
+
+/// A sorted set from before scores were floats. v1 and v2 only differ in
+/// how the score index holds members, and the index is rebuilt anyway.
+#[derive(Deserialize)]
+struct IntegerScoreSortedSet {
+    members_hash: HashMap<Key, i64>,
+    #[serde(rename = "scores")]
+    _scores: IgnoredAny,
+}
+
+fn integer_scores_to_float(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let old: HashMap<Key, IntegerScoreSortedSet> = rmps::from_read_ref(bytes)?;
+    let zsets: HashMap<Key, SortedSet> = old
+        .into_iter()
+        .map(|(key, old)| {
+            let mut zset = SortedSet::new();
+            for (member, score) in old.members_hash {
+                zset.insert(member, score.into());
+            }
+            (key, zset)
+        })
+        .collect();
+    Ok(rmps::to_vec(&zsets)?)
+}
+
+/// Streams from before consumer groups decode as streams without any.
+fn add_consumer_groups(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let streams: HashMap<Key, Stream> = rmps::from_read_ref(bytes)?;
+    Ok(rmps::to_vec(&streams)?)
+}
+
+/// Consumer groups from before they kept their consumers get them back from
+/// their pending entries.
+fn add_consumers(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let streams: HashMap<Key, Stream> = rmps::from_read_ref(bytes)?;
+    Ok(rmps::to_vec(&streams)?)
+}
+
+/// Bloom filters from before BF.RESERVE were all made by BINSERT, with its
+/// settings. How many items they hold isn't known, so they count from 0.
+fn add_bloom_settings(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let old: HashMap<Key, GrowableBloom> = rmps::from_read_ref(bytes)?;
+    let blooms: HashMap<Key, BloomFilter> = old
+        .into_iter()
+        .map(|(key, filter)| {
+            let bloom = BloomFilter::from_filter(filter, DESIRED_FAILURE_RATE, EST_INSERTS);
+            (key, bloom)
+        })
+        .collect();
+    Ok(rmps::to_vec(&blooms)?)
+}
+
+/// The migrations taking `name` from the schema hashed `from` to ours, or
+/// None if there's no way there.
+fn migration_path(name: &str, mut from: u64) -> Option<Vec<Migration>> {
+    let target = Capabilities::local().schema(name)?;
+    let mut path = Vec::new();
+    while from != target {
+        if path.len() == MIGRATIONS.len() {
+            return None;
+        }
+        let (_, _, to, migration) = MIGRATIONS
+            .iter()
+            .find(|(structure, old, _, _)| *structure == name && schema_hash(name, old) == from)?;
+        path.push(*migration);
+        from = schema_hash(name, to);
+    }
+    Some(path)
+}
+
+/// Decode structure `name`, written under `schema`, into `state`, running
+/// it through `MIGRATIONS` first if that's an older schema.
+pub fn decode_migrated(
+    state: &mut State,
+    name: &str,
+    schema: &str,
+    bytes: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let path = migration_path(name, schema_hash(name, schema))
+        .ok_or_else(|| format!("can't migrate {} from {}", name, schema))?;
+    let mut bytes = Cow::Borrowed(bytes);
+    for migrate in path {
+        bytes = Cow::Owned(migrate(&bytes)?);
+    }
+    if !decode_structure(state, name, &bytes)? {
+        return Err(format!("unknown structure {}", name).into());
+    }
+    Ok(())
+}
+
+/// The schema we store structure `name` under.
+pub fn current_schema(name: &str) -> Option<&'static str> {
+    STRUCTURES
+        .iter()
+        .find(|(structure, _)| *structure == name)
+        .map(|(_, schema)| *schema)
+}
+
+/// The structures of a db in a legacy dump, in the order they're stored,
+/// with the schema they were stored under then.
+const LEGACY_STRUCTURES: &[(&str, &str)] = &[
+    ("kv", "DashMap<Bytes, Bytes> v1"),
+    ("sets", "DashMap<Bytes, HashSet<Bytes>> v1"),
+    ("lists", "DashMap<Bytes, VecDeque<Bytes>> v1"),
+    ("hashes", "DashMap<Bytes, HashMap<Bytes, Bytes>> v1"),
+    ("zsets", "DashMap<Bytes, SortedSet> v1"),
+    ("blooms", "DashMap<Bytes, GrowableBloom> v1"),
+    ("stacks", "DashMap<Bytes, Stack<Bytes>> v1"),
+    ("hyperloglogs", "DashMap<Bytes, HyperLogLog<Bytes>> v1"),
+];
+
+/// Walks msgpack values without decoding them, so each structure of a
+/// legacy dump can be migrated on its own.
+struct Msgpack<'a>(&'a [u8]);
+
+impl<'a> Msgpack<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.0.len() < n {
+            return Err("truncated legacy dump".into());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    /// A big-endian unsigned integer of `n` bytes.
+    fn uint(&mut self, n: usize) -> Result<usize, Box<dyn Error>> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize))
+    }
+
+    fn array_len(&mut self) -> Result<usize, Box<dyn Error>> {
+        match self.take(1)?[0] {
+            marker @ 0x90..=0x9f => Ok((marker & 0x0f) as usize),
+            0xdc => self.uint(2),
+            0xdd => self.uint(4),
+            _ => Err("expected an array in the legacy dump".into()),
+        }
+    }
+
+    fn map_len(&mut self) -> Result<usize, Box<dyn Error>> {
+        match self.take(1)?[0] {
+            marker @ 0x80..=0x8f => Ok((marker & 0x0f) as usize),
+            0xde => self.uint(2),
+            0xdf => self.uint(4),
+            _ => Err("expected a map in the legacy dump".into()),
+        }
+    }
+
+    /// The bytes of the next value, nested values and all.
+    fn value(&mut self) -> Result<&'a [u8], Box<dyn Error>> {
+        let start = self.0;
+        let mut pending: usize = 1;
+        while pending > 0 {
+            pending -= 1;
+            let marker = self.take(1)?[0];
+            // (bytes to skip, values nested in this one)
+            let (skip, nested) = match marker {
+                0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, 0),
+                0x80..=0x8f => (0, 2 * (marker & 0x0f) as usize),
+                0x90..=0x9f => (0, (marker & 0x0f) as usize),
+                0xa0..=0xbf => ((marker & 0x1f) as usize, 0),
+                0xc4 | 0xd9 => (self.uint(1)?, 0),
+                0xc5 | 0xda => (self.uint(2)?, 0),
+                0xc6 | 0xdb => (self.uint(4)?, 0),
+                0xc7 => (self.uint(1)? + 1, 0),
+                0xc8 => (self.uint(2)? + 1, 0),
+                0xc9 => (self.uint(4)? + 1, 0),
+                0xcc | 0xd0 => (1, 0),
+                0xcd | 0xd1 => (2, 0),
+                0xca | 0xce | 0xd2 => (4, 0),
+                0xcb | 0xcf | 0xd3 => (8, 0),
+                0xd4 => (2, 0),
+                0xd5 => (3, 0),
+                0xd6 => (5, 0),
+                0xd7 => (9, 0),
+                0xd8 => (17, 0),
+                0xdc => (0, self.uint(2)?),
+                0xdd => (0, self.uint(4)?),
+                0xde => (0, 2 * self.uint(2)?),
+                0xdf => (0, 2 * self.uint(4)?),
+                0xc1 => return Err("bad msgpack marker in the legacy dump".into()),
+            };
+            self.take(skip)?;
+            pending += nested;
+        }
+        Ok(&start[..start.len() - self.0.len()])
+    }
+}
+
+/// Read a legacy dump: a StateStore as `[{index: [structure, ...]}]`, the
+/// structures in `LEGACY_STRUCTURES` order. Each is migrated from the
+/// schema it had then, like those of a versioned dump.
+fn read_legacy(bytes: &[u8]) -> Result<StateStore, Box<dyn Error>> {
+    let mut legacy = Msgpack(bytes);
+    if legacy.array_len()? == 0 {
+        return Err("the legacy dump has no dbs".into());
+    }
+    let store = StateStore::default();
+    for _ in 0..legacy.map_len()? {
+        let index: Index = rmps::from_read_ref(legacy.value()?)?;
+        let mut state = State::default();
+        for n in 0..legacy.array_len()? {
+            let body = legacy.value()?;
+            let (name, schema) = LEGACY_STRUCTURES
+                .get(n)
+                .ok_or("the legacy dump has more structures than there were")?;
+            decode_migrated(&mut state, name, schema, body)?;
+        }
+        store.states.insert(index, Arc::new(state));
+    }
+    Ok(store)
+}
+
+/// What a redis-oxide build can read and write.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub format_version: u32,
+    pub crate_version: String,
+    pub features: Vec<String>,
+    /// (structure name, schema hash)
+    pub structures: Vec<(String, u64)>,
+}
+
+impl Capabilities {
+    pub fn local() -> Self {
+        Capabilities {
+            format_version: FORMAT_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION").into(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            structures: STRUCTURES
+                .iter()
+                .map(|(name, schema)| (name.to_string(), schema_hash(name, schema)))
+                .collect(),
+        }
+    }
+
+    fn schema(&self, name: &str) -> Option<u64> {
+        self.structures
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, hash)| *hash)
+    }
+
+    /// Structures we know which `peer` is missing or stores differently.
+    pub fn unsupported_by(&self, peer: &Capabilities) -> Vec<String> {
+        self.structures
+            .iter()
+            .filter(|(name, hash)| peer.schema(name) != Some(*hash))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+/// Structures holding data in `store` that `peer` can't read.
+pub fn unreadable_by(store: &StateStore, peer: &Capabilities) -> Vec<String> {
+    Capabilities::local()
+        .unsupported_by(peer)
+        .into_iter()
+        .filter(|name| store.states.iter().any(|db| structure_len(&db, name) != 0))
+        .collect()
+}
+
+/// Identifies which db and structure the next frame holds.
+#[derive(Serialize, Deserialize)]
+struct SectionHeader {
+    db: Index,
+    structure: String,
+}
+
+/// Write the capabilities header's frame, with a u32 length.
+fn write_frame<W: Write>(out: &mut W, frame: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(frame.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame of 4GiB or more"))?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(frame)
+}
+
+/// Write a frame after the header, with a u64 length.
+fn write_long_frame<W: Write>(out: &mut W, frame: &[u8]) -> io::Result<()> {
+    out.write_all(&(frame.len() as u64).to_be_bytes())?;
+    out.write_all(frame)
+}
+
+struct Frames<'a>(&'a [u8]);
+
+impl<'a> Frames<'a> {
+    /// The next frame with a u32 length, like the capabilities header.
+    fn next_frame(&mut self) -> Result<Option<&'a [u8]>, Box<dyn Error>> {
+        self.next_sized(4)
+    }
+
+    /// The next frame after the header, in a stream of `format_version`.
+    fn next_body_frame(&mut self, format_version: u32) -> Result<Option<&'a [u8]>, Box<dyn Error>> {
+        if format_version >= 2 {
+            self.next_sized(8)
+        } else {
+            self.next_sized(4)
+        }
+    }
+
+    fn next_sized(&mut self, width: usize) -> Result<Option<&'a [u8]>, Box<dyn Error>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        if self.0.len() < width {
+            return Err("truncated frame length".into());
+        }
+        let (len, rest) = self.0.split_at(width);
+        let mut be = [0; 8];
+        be[8 - width..].copy_from_slice(len);
+        let len = u64::from_be_bytes(be);
+        if (rest.len() as u64) < len {
+            return Err(
+                format!("truncated frame: wanted {} bytes, {} left", len, rest.len()).into(),
+            );
+        }
+        let (frame, rest) = rest.split_at(len as usize);
+        self.0 = rest;
+        Ok(Some(frame))
+    }
+}
+
+/// Write `store`, leaving out the structures in `skip`.
+///
+/// Each db is encoded from a snapshot, so ops moving data between structures
+/// can't tear. With `chunked` set, those are chunked snapshots, which keep
+/// writers waiting far less but may catch ops halfway.
+pub fn write_state<W: Write>(
+    out: &mut W,
+    store: &StateStore,
+    skip: &[String],
+    chunked: bool,
+) -> Result<SnapshotStats, Box<dyn Error>> {
+    write_header(out)?;
+    let mut stats = SnapshotStats::default();
+    for (index, db) in store.dbs() {
+        stats = stats.merge(write_db(out, index, &db, skip, chunked)?);
+    }
+    Ok(stats)
+}
+
+/// Write what comes before the dbs: the magic bytes and our capabilities.
+pub fn write_header<W: Write>(out: &mut W) -> Result<(), Box<dyn Error>> {
+    out.write_all(MAGIC)?;
+    write_frame(out, &rmps::to_vec(&Capabilities::local())?)?;
+    Ok(())
+}
+
+/// Write db `index` (after `write_header`), leaving out the structures in
+/// `skip`. Only this db is copied, and each structure is written as soon
+/// as it's encoded, so a big store is never held twice over.
+pub fn write_db<W: Write>(
+    out: &mut W,
+    index: Index,
+    db: &State,
+    skip: &[String],
+    chunked: bool,
+) -> Result<SnapshotStats, Box<dyn Error>> {
+    let snapshot = if chunked {
+        db.chunked_snapshot()
+    } else {
+        db.snapshot()
+    };
+    for name in structure_names() {
+        if !skip.iter().any(|s| s == name) {
+            write_structure(out, index, &snapshot, name)?;
+        }
+    }
+    Ok(snapshot.stats)
+}
+
+/// The name of every structure in a `State`, in the order they're written.
+pub fn structure_names() -> impl Iterator<Item = &'static str> {
+    STRUCTURES.iter().map(|(name, _)| *name)
+}
+
+/// Write structure `name` of db `index` (after `write_header`), unless it's
+/// empty. `state` should be a snapshot, see `write_db`.
+pub fn write_structure<W: Write>(
+    out: &mut W,
+    index: Index,
+    state: &State,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    if structure_len(state, name) == 0 {
+        return Ok(());
+    }
+    let header = SectionHeader {
+        db: index,
+        structure: name.to_string(),
+    };
+    write_long_frame(out, &rmps::to_vec(&header)?)?;
+    write_long_frame(out, &encode_structure(state, name)?)?;
+    Ok(())
+}
+
+fn read_capabilities(frames: &mut Frames<'_>) -> Result<Capabilities, Box<dyn Error>> {
+    let header = frames.next_frame()?.ok_or("missing capabilities header")?;
+    Ok(rmps::from_read_ref(header)?)
+}
+
+/// Whether `bytes` were written in the current format with our schemas, so
+/// saving what they load gives back the same layout.
+pub fn is_current(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+        && read_capabilities(&mut Frames(&bytes[MAGIC.len()..])).map_or(false, |peer| {
+            peer.format_version == FORMAT_VERSION
+                && peer.unsupported_by(&Capabilities::local()).is_empty()
+        })
+}
+
+/// Read a state written by `write_state` (or a legacy dump).
+///
+/// Structures written under an older schema are migrated. Streams holding
+/// data in structures we can't read are refused, unless `lossy` is set, in
+/// which case that data is dropped with a warning.
+pub fn read_state(bytes: &[u8], lossy: bool) -> Result<StateStore, Box<dyn Error>> {
+    if !bytes.starts_with(MAGIC) {
+        return read_legacy(bytes).map_err(|e| {
+            format!(
+                "couldn't decode legacy dump, from before dumps were versioned: {}",
+                e
+            )
+            .into()
+        });
+    }
+    let store = StateStore::default();
+    for (index, state) in read_dbs(bytes, lossy)? {
+        store.states.insert(index, Arc::new(state));
+    }
+    Ok(store)
+}
+
+/// Read the dbs in a stream written by `write_state`, as `read_state` does.
+/// Legacy dumps aren't read.
+pub fn read_dbs(bytes: &[u8], lossy: bool) -> Result<HashMap<Index, State>, Box<dyn Error>> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("not a dump: the magic bytes are missing".into());
+    }
+    let mut frames = Frames(&bytes[MAGIC.len()..]);
+    let peer = read_capabilities(&mut frames)?;
+    if peer.format_version > FORMAT_VERSION {
+        return Err(format!(
+            "state was written by redis-oxide {} in format {}, we only understand up to {}",
+            peer.crate_version, peer.format_version, FORMAT_VERSION
+        )
+        .into());
+    }
+    let mut unreadable = Vec::new();
+    let mut migrations = HashMap::new();
+    for (name, hash) in &peer.structures {
+        match migration_path(name, *hash) {
+            Some(path) => {
+                migrations.insert(name.clone(), path);
+            }
+            None => unreadable.push(name.clone()),
+        }
+    }
+    let mut dropped: Vec<String> = Vec::new();
+    let mut dbs: HashMap<Index, State> = HashMap::new();
+    while let Some(section) = frames.next_body_frame(peer.format_version)? {
+        let section: SectionHeader = rmps::from_read_ref(section)?;
+        let body = frames
+            .next_body_frame(peer.format_version)?
+            .ok_or_else(|| format!("missing body for {}", section.structure))?;
+        if unreadable.contains(&section.structure) {
+            if !lossy {
+                return Err(format!(
+                    "state was written by redis-oxide {} with data in {}, which we can't read. \
+                     Use --lossy-load to drop it.",
+                    peer.crate_version, section.structure
+                )
+                .into());
+            }
+            if !dropped.contains(&section.structure) {
+                dropped.push(section.structure);
+            }
+            continue;
+        }
+        let mut body = Cow::Borrowed(body);
+        for migrate in migrations.get(&section.structure).into_iter().flatten() {
+            body = Cow::Owned(migrate(&body).map_err(|e| {
+                format!(
+                    "couldn't migrate {} written by redis-oxide {}: {}",
+                    section.structure, peer.crate_version, e
+                )
+            })?);
+        }
+        let state = dbs.entry(section.db).or_default();
+        let known = decode_structure(state, &section.structure, &body).map_err(|e| {
+            format!(
+                "couldn't decode {} of db {}: {}",
+                section.structure, section.db, e
+            )
+        })?;
+        if !known {
+            return Err(format!("undeclared structure {}", section.structure).into());
+        }
+    }
+    if !dropped.is_empty() {
+        warn!(
+            LOGGER,
+            "Dropped structures we can't read from redis-oxide {}: {}",
+            peer.crate_version,
+            dropped.join(", ")
+        );
+    }
+    Ok(dbs)
+}
+
+/// Add the dbs read from one stream to those read from others, for a state
+/// split over several streams. Structures holding anything in `from`
+/// replace those in `into`.
+pub fn merge_dbs(into: &mut HashMap<Index, State>, from: HashMap<Index, State>) {
+    for (index, mut state) in from {
+        move_structures(into.entry(index).or_default(), &mut state);
+    }
+}
+
+#[cfg(test)]
+mod test_dump_format {
+    use super::{
+        add_bloom_settings, add_consumer_groups, is_current, read_state, schema_hash,
+        unreadable_by, write_frame, write_long_frame, write_state, Capabilities, Frames,
+        SectionHeader, MAGIC,
+    };
+    use crate::data_structures::bloom_filter::BloomFilter;
+    use crate::data_structures::stack::Stack;
+    use crate::data_structures::stream::{Fields, Stream, StreamId};
+    use crate::types::StateStore;
+    use bytes::Bytes;
+    use growable_bloom_filter::GrowableBloom;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn sample_store() -> StateStore {
+        let store = StateStore::default();
+        let db = store.get_or_create(3);
+        db.kv
+            .insert(Bytes::from_static(b"k"), Bytes::from_static(b"v"));
+        let mut stack = Stack::new();
+        stack.push(Bytes::from_static(b"s"));
+        db.stacks.insert(Bytes::from_static(b"stack"), stack);
+        store
+    }
+
+    fn older_peer() -> Capabilities {
+        let mut peer = Capabilities::local();
+        peer.structures.retain(|(name, _)| name != "stacks");
+        peer
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = Vec::new();
+        write_state(&mut buf, &sample_store(), &[], false).unwrap();
+        assert!(buf.starts_with(MAGIC));
+        let store = read_state(&buf, false).unwrap();
+        let db = store.get_or_create(3);
+        assert_eq!(
+            db.kv.get(&Bytes::from_static(b"k")).unwrap().value(),
+            &Bytes::from_static(b"v")
+        );
+        assert_eq!(db.stacks.len(), 1);
+    }
+
+    #[test]
+    fn test_chunked_round_trip() {
+        let mut atomic = Vec::new();
+        write_state(&mut atomic, &sample_store(), &[], false).unwrap();
+        let mut chunked = Vec::new();
+        let stats = write_state(&mut chunked, &sample_store(), &[], true).unwrap();
+        assert!(stats.consistent);
+        assert_eq!(chunked, atomic);
+    }
+
+    /// A sorted set member as they were first stored, with an integer score.
+    #[derive(Serialize)]
+    struct LegacyMember {
+        score: i64,
+        member: String,
+    }
+
+    #[derive(Serialize)]
+    struct LegacySortedSet {
+        members_hash: HashMap<Bytes, i64>,
+        scores: Vec<LegacyMember>,
+    }
+
+    /// A db as they were first stored. Empty structures are encoded the same
+    /// whatever they hold.
+    #[derive(Serialize)]
+    struct LegacyState {
+        kv: HashMap<Bytes, Bytes>,
+        sets: HashMap<Bytes, ()>,
+        lists: HashMap<Bytes, ()>,
+        hashes: HashMap<Bytes, ()>,
+        zsets: HashMap<Bytes, LegacySortedSet>,
+        blooms: HashMap<Bytes, ()>,
+        stacks: HashMap<Bytes, ()>,
+        hyperloglogs: HashMap<Bytes, ()>,
+    }
+
+    #[derive(Serialize)]
+    struct LegacyStore {
+        states: HashMap<i64, LegacyState>,
+    }
+
+    #[test]
+    fn test_legacy_dump() {
+        let member = LegacyMember {
+            score: 5,
+            member: "m".into(),
+        };
+        let mut members_hash = HashMap::new();
+        members_hash.insert(Bytes::from_static(b"m"), 5);
+        let mut zsets = HashMap::new();
+        zsets.insert(
+            Bytes::from_static(b"z"),
+            LegacySortedSet {
+                members_hash,
+                scores: vec![member],
+            },
+        );
+        let mut kv = HashMap::new();
+        kv.insert(Bytes::from_static(b"k"), Bytes::from_static(b"v"));
+        let db = LegacyState {
+            kv,
+            sets: HashMap::new(),
+            lists: HashMap::new(),
+            hashes: HashMap::new(),
+            zsets,
+            blooms: HashMap::new(),
+            stacks: HashMap::new(),
+            hyperloglogs: HashMap::new(),
+        };
+        let mut states = HashMap::new();
+        states.insert(3, db);
+        let buf = rmps::to_vec(&LegacyStore { states }).unwrap();
+
+        let store = read_state(&buf, false).unwrap();
+        let db = store.get_or_create(3);
+        assert_eq!(db.kv.len(), 1);
+        let zset = db.zsets.get(&Bytes::from_static(b"z")).unwrap();
+        assert_eq!(zset.score(Bytes::from_static(b"m")), Some(5.into()));
+        assert!(read_state(&buf[..buf.len() - 1], false).is_err());
+    }
+
+    #[test]
+    fn test_unreadable_structures() {
+        let store = sample_store();
+        assert_eq!(unreadable_by(&store, &older_peer()), vec!["stacks"]);
+        assert!(unreadable_by(&store, &Capabilities::local()).is_empty());
+        // Nothing is lost if the structure is empty.
+        store.get_or_create(3).stacks.clear();
+        assert!(unreadable_by(&store, &older_peer()).is_empty());
+    }
+
+    #[test]
+    fn test_refuse_or_drop_unknown_structures() {
+        // Pretend the writer has data in a structure we don't know.
+        let mut caps = Capabilities::local();
+        caps.structures.push(("graphs".into(), 42));
+        let mut buf = MAGIC.to_vec();
+        write_frame(&mut buf, &rmps::to_vec(&caps).unwrap()).unwrap();
+        let mut rest = Vec::new();
+        write_state(&mut rest, &sample_store(), &[], false).unwrap();
+        let mut frames = Frames(&rest[MAGIC.len()..]);
+        frames.next_frame().unwrap();
+        buf.extend_from_slice(frames.0);
+        let graphs = SectionHeader {
+            db: 3,
+            structure: "graphs".into(),
+        };
+        write_long_frame(&mut buf, &rmps::to_vec(&graphs).unwrap()).unwrap();
+        write_long_frame(&mut buf, b"opaque").unwrap();
+
+        let err = read_state(&buf, false).err().unwrap().to_string();
+        assert!(err.contains("graphs"), "{}", err);
+        let store = read_state(&buf, true).unwrap();
+        assert_eq!(store.get_or_create(3).kv.len(), 1);
+        assert_eq!(store.get_or_create(3).stacks.len(), 1);
+    }
+
+    #[test]
+    fn test_known_but_empty_structures_are_fine() {
+        let mut caps = Capabilities::local();
+        caps.structures.push(("graphs".into(), 42));
+        let mut buf = MAGIC.to_vec();
+        write_frame(&mut buf, &rmps::to_vec(&caps).unwrap()).unwrap();
+        assert!(read_state(&buf, false).unwrap().states.is_empty());
+    }
+
+    /// A sorted set as v2 stored it: integer scores, and a score index.
+    #[derive(Serialize)]
+    struct IntegerScoreSortedSet {
+        members_hash: HashMap<Bytes, i64>,
+        scores: Vec<(i64, Bytes)>,
+    }
+
+    fn v2_zsets_stream() -> Vec<u8> {
+        let mut caps = Capabilities::local();
+        for (name, hash) in caps.structures.iter_mut() {
+            if name == "zsets" {
+                *hash = schema_hash("zsets", "DashMap<Bytes, SortedSet> v2");
+            }
+        }
+        let members = vec![
+            (Bytes::from_static(b"a"), 5),
+            (Bytes::from_static(b"b"), -2),
+        ];
+        let mut zsets = HashMap::new();
+        zsets.insert(
+            Bytes::from_static(b"zset"),
+            IntegerScoreSortedSet {
+                members_hash: members.iter().cloned().collect(),
+                scores: members.iter().map(|(m, s)| (*s, m.clone())).collect(),
+            },
+        );
+        let mut buf = MAGIC.to_vec();
+        write_frame(&mut buf, &rmps::to_vec(&caps).unwrap()).unwrap();
+        let header = SectionHeader {
+            db: 0,
+            structure: "zsets".into(),
+        };
+        write_long_frame(&mut buf, &rmps::to_vec(&header).unwrap()).unwrap();
+        write_long_frame(&mut buf, &rmps::to_vec(&zsets).unwrap()).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_migrate_integer_scores() {
+        let buf = v2_zsets_stream();
+        assert!(!is_current(&buf));
+        let store = read_state(&buf, false).unwrap();
+        let db = store.get_or_create(0);
+        let zset = db.zsets.get(&Bytes::from_static(b"zset")).unwrap();
+        assert_eq!(zset.score(Bytes::from_static(b"a")), Some(5.into()));
+        assert_eq!(zset.rank(Bytes::from_static(b"b")), Some(0));
+        assert_eq!(zset.card(), 2);
+    }
+
+    #[test]
+    fn test_migrate_streams_without_groups() {
+        #[derive(Serialize)]
+        struct StreamV1 {
+            entries: BTreeMap<StreamId, Fields>,
+            last_id: StreamId,
+        }
+        let id = StreamId::new(5, 1);
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            id,
+            vec![(Bytes::from_static(b"f"), Bytes::from_static(b"v"))],
+        );
+        let mut old = HashMap::new();
+        old.insert(
+            Bytes::from_static(b"s"),
+            StreamV1 {
+                entries,
+                last_id: id,
+            },
+        );
+
+        let migrated = add_consumer_groups(&rmps::to_vec(&old).unwrap()).unwrap();
+        let streams: HashMap<Bytes, Stream> = rmps::from_read_ref(&migrated).unwrap();
+        let stream = &streams[&Bytes::from_static(b"s")];
+        assert_eq!((stream.len(), stream.last_id()), (1, id));
+        assert!(stream.group(b"g").is_none());
+    }
+
+    #[test]
+    fn test_migrate_blooms_without_settings() {
+        let mut filter = GrowableBloom::new(0.05, 10);
+        filter.insert(&"v");
+        let mut old = HashMap::new();
+        old.insert(Bytes::from_static(b"b"), filter);
+
+        let migrated = add_bloom_settings(&rmps::to_vec(&old).unwrap()).unwrap();
+        let blooms: HashMap<Bytes, BloomFilter> = rmps::from_read_ref(&migrated).unwrap();
+        let bloom = &blooms[&Bytes::from_static(b"b")];
+        assert!(bloom.contains(&"v"));
+        assert_eq!((bloom.capacity(), bloom.error_rate()), (10, 0.05));
+    }
+
+    #[test]
+    fn test_current_streams() {
+        let mut buf = Vec::new();
+        write_state(&mut buf, &sample_store(), &[], false).unwrap();
+        assert!(is_current(&buf));
+        assert!(!is_current(&rmps::to_vec(&sample_store()).unwrap()));
+    }
+
+    #[test]
+    fn test_undecodable_structure_is_named() {
+        let mut buf = MAGIC.to_vec();
+        write_frame(&mut buf, &rmps::to_vec(&Capabilities::local()).unwrap()).unwrap();
+        let header = SectionHeader {
+            db: 7,
+            structure: "kv".into(),
+        };
+        write_long_frame(&mut buf, &rmps::to_vec(&header).unwrap()).unwrap();
+        write_long_frame(&mut buf, b"\xc1").unwrap();
+        let err = read_state(&buf, false).err().unwrap().to_string();
+        assert!(err.contains("kv of db 7"), "{}", err);
+    }
+
+    #[test]
+    fn test_format_1_frames() {
+        // Format 1 framed everything with a u32 length.
+        let mut caps = Capabilities::local();
+        caps.format_version = 1;
+        let mut buf = MAGIC.to_vec();
+        write_frame(&mut buf, &rmps::to_vec(&caps).unwrap()).unwrap();
+        let mut kv = HashMap::new();
+        kv.insert(Bytes::from_static(b"k"), Bytes::from_static(b"v"));
+        let header = SectionHeader {
+            db: 0,
+            structure: "kv".into(),
+        };
+        write_frame(&mut buf, &rmps::to_vec(&header).unwrap()).unwrap();
+        write_frame(&mut buf, &rmps::to_vec(&kv).unwrap()).unwrap();
+        assert!(!is_current(&buf));
+        let store = read_state(&buf, false).unwrap();
+        assert_eq!(store.get_or_create(0).kv.len(), 1);
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        let mut buf = Vec::new();
+        write_state(&mut buf, &sample_store(), &[], false).unwrap();
+        buf.truncate(buf.len() - 1);
+        assert!(read_state(&buf, false).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Running redis-oxide inside another program, without the TCP listener.
+//!
+//! A `Builder` starts what the server would (the StateStore, scripting, the
+//! dump file and AOF, the expiry sweep) from the same settings as its flags,
+//! on the caller's tokio runtime, which must be multi-threaded. The
+//! `Embedded` handle it returns runs commands through `process_command`,
+//! exactly like a RESP client:
+//!
+//! - `Builder::new().memory_only().start().await?` for a plain cache
+//! - `Builder::from_args(&["--appendonly", "--maxmemory", "104857600"])?` for
+//!   any other setting
+//!
+//! Commands about the connection itself (SUBSCRIBE, MULTI, CLIENT, ...) need
+//! a RESP connection, as there isn't one here. SHUTDOWN is refused, as it
+//! would exit the host program.
+
+use crate::database::{get_dump_file, load_state, replay_aof, save_state_interval, start_aof};
+use crate::expiry::expire_keys_interval;
+use crate::logger::LOGGER;
+use crate::monitor::Origin;
+use crate::scripting::{handle_redis_cmd, ScriptingBridge, ScriptingEngine};
+use crate::server::process_command;
+use crate::startup::Config;
+use crate::types::{DumpFile, Index, RedisValueRef, StateStoreRef};
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use structopt::StructOpt;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::mpsc::channel;
+
+/// Settings for an embedded server, defaulting to the server's.
+pub struct Builder {
+    config: Config,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            config: Config::from_iter(&["redis-oxide"]),
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Settings from server flags, e.g. `["--appendonly"]`.
+    pub fn from_args<I, S>(args: I) -> Result<Self, structopt::clap::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let args = std::iter::once("redis-oxide".into()).chain(args.into_iter().map(Into::into));
+        Ok(Builder {
+            config: Config::from_iter_safe(args)?,
+        })
+    }
+
+    /// Keep the dump file (and AOF) in `dir` instead of the default data dir.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Don't save anything to disk.
+    pub fn memory_only(mut self) -> Self {
+        self.config.memory_only = true;
+        self
+    }
+
+    /// Load x7 functions or WASM modules from `dir`, like --scripts-dir.
+    pub fn scripts_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.scripts_dir = Some(dir.into());
+        self
+    }
+
+    /// Load the state, and start scripting, saving and the expiry sweep.
+    /// Must be called within a multi-threaded tokio runtime, which runs them
+    /// from then on.
+    pub async fn start(self) -> Result<Embedded, Box<dyn Error>> {
+        // Saves, AOF appends and the backing store use `block_in_place`,
+        // which panics on a current_thread runtime.
+        if Handle::current().runtime_flavor() != RuntimeFlavor::MultiThread {
+            return Err("redis-oxide needs a multi-threaded tokio runtime".into());
+        }
+        let config = self.config;
+        let dump_file = get_dump_file(&config);
+        let state_store = load_state(dump_file.clone(), &config)?;
+        state_store.embedded.store(true, Ordering::Relaxed);
+        if !config.memory_only {
+            let _ = state_store.dump_file.set(dump_file.clone());
+        }
+
+        let (prog_string_sx, prog_string_rx) = channel(12);
+        let (cmd_result_sx, cmd_result_rx) = channel(12);
+        let scripting_bridge = ScriptingBridge::new(prog_string_sx);
+        match ScriptingEngine::new(
+            prog_string_rx,
+            cmd_result_sx,
+            state_store.clone(),
+            dump_file.clone(),
+            scripting_bridge.clone(),
+            &config,
+        ) {
+            Ok(scripting_engine) => {
+                std::thread::spawn(|| scripting_engine.main_loop());
+            }
+            Err(e) => {
+                warn!(
+                    LOGGER,
+                    "Scripting disabled, the engine failed to start: {}", e
+                );
+                state_store
+                    .scripting_disabled
+                    .store(true, Ordering::Relaxed);
+            }
+        }
+        tokio::spawn(handle_redis_cmd(
+            cmd_result_rx,
+            state_store.clone(),
+            dump_file.clone(),
+            scripting_bridge.clone(),
+        ));
+
+        if config.appendonly {
+            replay_aof(
+                state_store.clone(),
+                dump_file.clone(),
+                scripting_bridge.clone(),
+            )
+            .await?;
+            start_aof(state_store.clone(), dump_file.clone(), &config)?;
+        }
+        tokio::spawn(expire_keys_interval(state_store.clone()));
+        if !config.memory_only {
+            tokio::spawn(save_state_interval(state_store.clone(), dump_file.clone()));
+        }
+        Ok(Embedded {
+            state_store,
+            dump_file,
+            scripting_bridge,
+            db: 0,
+        })
+    }
+}
+
+/// A handle on an embedded server. Clones share the server.
+#[derive(Clone)]
+pub struct Embedded {
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+    db: Index,
+}
+
+impl Embedded {
+    /// Run a command, given as its name and arguments, on this handle's db.
+    ///
+    /// Every command starts on that db, so SELECT has no lasting effect:
+    /// use `select` for a handle on another db.
+    pub async fn command(&self, command: Vec<RedisValueRef>) -> RedisValueRef {
+        let mut state = self.state_store.get_or_create(self.db);
+        process_command(
+            &mut state,
+            self.state_store.clone(),
+            self.dump_file.clone(),
+            self.scripting_bridge.clone(),
+            RedisValueRef::Array(command),
+            Origin::Embedded,
+        )
+        .await
+    }
+
+    /// A handle running commands on db `db`.
+    pub fn select(&self, db: Index) -> Embedded {
+        Embedded { db, ..self.clone() }
+    }
+
+    /// The server's StateStore, to read directly.
+    pub fn state_store(&self) -> StateStoreRef {
+        self.state_store.clone()
+    }
+}
+
+#[cfg(test)]
+mod test_embedded {
+    use super::Builder;
+    use crate::test_util::command_args;
+    use crate::types::RedisValueRef;
+    use bytes::Bytes;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_embedded_commands() {
+        let dir =
+            std::env::temp_dir().join(format!("redis-oxide-test-embedded-{}", std::process::id()));
+        let redis = Builder::new()
+            .data_dir(&dir)
+            .memory_only()
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(
+            redis.command(command_args(&["SET", "key", "value"])).await,
+            RedisValueRef::SimpleString(Bytes::from_static(b"OK"))
+        );
+        assert_eq!(
+            redis.command(command_args(&["GET", "key"])).await,
+            RedisValueRef::BulkString(Bytes::from_static(b"value"))
+        );
+
+        // SELECT doesn't outlive its command; `select` does.
+        redis.command(command_args(&["SELECT", "1"])).await;
+        let db1 = redis.select(1);
+        assert_eq!(
+            db1.command(command_args(&["GET", "key"])).await,
+            RedisValueRef::NullBulkString
+        );
+        db1.command(command_args(&["SET", "key", "other"])).await;
+        assert_eq!(
+            redis.command(command_args(&["GET", "key"])).await,
+            RedisValueRef::BulkString(Bytes::from_static(b"value"))
+        );
+        assert_eq!(redis.state_store().get_or_create(1).kv.len(), 1);
+        assert!(matches!(
+            redis.command(command_args(&["NOSUCHCOMMAND"])).await,
+            RedisValueRef::Error(_) | RedisValueRef::ErrorMsg(_)
+        ));
+        // SHUTDOWN would exit the program embedding us.
+        assert!(matches!(
+            redis.command(command_args(&["SHUTDOWN", "NOSAVE"])).await,
+            RedisValueRef::Error(_)
+        ));
+        assert_eq!(
+            redis.command(command_args(&["GET", "key"])).await,
+            RedisValueRef::BulkString(Bytes::from_static(b"value"))
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(Builder::from_args(&["--maxmemory", "1000"]).is_ok());
+        assert!(Builder::from_args(&["--no-such-flag"]).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_embedded_persistence() {
+        let dir = std::env::temp_dir().join(format!(
+            "redis-oxide-test-embedded-aof-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let start = || {
+            Builder::from_args(&["--appendonly"])
+                .unwrap()
+                .data_dir(&dir)
+                .start()
+        };
+        let redis = start().await.unwrap();
+        assert_eq!(
+            redis.command(command_args(&["SET", "key", "value"])).await,
+            RedisValueRef::SimpleString(Bytes::from_static(b"OK"))
+        );
+        assert_eq!(
+            redis.command(command_args(&["SAVE"])).await,
+            RedisValueRef::SimpleString(Bytes::from_static(b"OK"))
+        );
+        redis.command(command_args(&["SET", "other", "1"])).await;
+
+        // A restart loads the dump file and replays the AOF.
+        let restarted = start().await.unwrap();
+        for &(key, value) in [("key", "value"), ("other", "1")].iter() {
+            assert_eq!(
+                restarted.command(command_args(&["GET", key])).await,
+                RedisValueRef::BulkString(Bytes::from_static(value.as_bytes()))
+            );
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_needs_multi_thread_runtime() {
+        assert!(Builder::new().memory_only().start().await.is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Safe rendering of untrusted bytes (keys, values, commands) for logs and
+//! human-readable output.
+//!
+//! Printable ASCII is passed through, everything else is escaped the way
+//! redis does it (\n, \r, \t, \xNN), and long strings are truncated.
+
+use std::fmt;
+
+/// Default number of bytes shown before truncating.
+pub const MAX_DISPLAY_BYTES: usize = 128;
+
+/// Escape `bytes`, showing at most `max` of them.
+pub fn escape_bytes(bytes: &[u8], max: usize) -> String {
+    let mut out = String::with_capacity(bytes.len().min(max) + 2);
+    push_escaped(&mut out, &bytes[..bytes.len().min(max)]);
+    if bytes.len() > max {
+        out.push_str(&format!("...({} more bytes)", bytes.len() - max));
+    }
+    out
+}
+
+fn push_escaped(out: &mut String, bytes: &[u8]) {
+    for &b in bytes {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+}
+
+/// Quoted, escaped and truncated display of some bytes.
+///
+/// ```
+/// use redis_oxide::escape::Escaped;
+/// assert_eq!(Escaped(b"a\x1b[2J").to_string(), r#""a\x1b[2J""#);
+/// ```
+pub struct Escaped<'a>(pub &'a [u8]);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", escape_bytes(self.0, MAX_DISPLAY_BYTES))
+    }
+}
+
+impl fmt::Debug for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Display a list of byte strings as `["a", "b"]`, escaping each.
+pub struct EscapedList<'a, T: AsRef<[u8]>>(pub &'a [T]);
+
+impl<T: AsRef<[u8]>> fmt::Display for EscapedList<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|item| Escaped(item.as_ref())))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test_escape {
+    use super::{escape_bytes, Escaped, EscapedList, MAX_DISPLAY_BYTES};
+
+    #[test]
+    fn test_escape_control_bytes() {
+        assert_eq!(escape_bytes(b"plain key", 64), "plain key");
+        assert_eq!(escape_bytes(b"a\r\nb\t\x00\x7f", 64), r"a\r\nb\t\x00\x7f");
+        assert_eq!(escape_bytes(b"\"q\" \\", 64), r#"\"q\" \\"#);
+        assert_eq!(escape_bytes("é".as_bytes(), 64), r"\xc3\xa9");
+        assert_eq!(escape_bytes(&[0xff, 0xfe], 64), r"\xff\xfe");
+    }
+
+    #[test]
+    fn test_escape_truncates() {
+        assert_eq!(escape_bytes(b"abcdef", 3), "abc...(3 more bytes)");
+        assert_eq!(escape_bytes(b"abc", 3), "abc");
+        let long = vec![b'x'; MAX_DISPLAY_BYTES * 2];
+        let shown = Escaped(&long).to_string();
+        assert!(shown.ends_with(&format!("...({} more bytes)\"", MAX_DISPLAY_BYTES)));
+    }
+
+    #[test]
+    fn test_escaped_list() {
+        let items = vec![b"a\n".to_vec(), b"b".to_vec()];
+        assert_eq!(EscapedList(&items).to_string(), r#"["a\n", "b"]"#);
+    }
+}
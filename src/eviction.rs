@@ -0,0 +1,315 @@
+//! Maxmemory: evicting keys to stay under the limit, or refusing writes.
+//!
+//! Memory is the sum of every db's `MemoryTracker` estimate, so the limit is
+//! as rough as the estimates. Before a write runs over the limit, keys are
+//! evicted as the policy says until the estimate is back under it. Writes
+//! which could use more memory are refused when that isn't possible.
+//!
+//! Evictions are logged to the AOF and fed to replicas as DELs. Replicas
+//! don't evict on their own, like redis.
+
+use crate::command_table::command_keys;
+use crate::expiry::now_ms;
+use crate::logger::LOGGER;
+use crate::state::sample_keys;
+use crate::types::{Index, Key, RedisValueRef, State, StateStore};
+use crate::write_effects::WriteEffects;
+use rand::Rng;
+use tokio::task;
+
+/// Which keys go when we're over maxmemory (`--maxmemory-policy`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    /// Refuse writes which could use more memory.
+    NoEviction,
+    AllKeysRandom,
+    /// The least recently used keys first.
+    AllKeysLru,
+    /// Keys with a deadline, the nearest deadline first.
+    VolatileTtl,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::NoEviction
+    }
+}
+
+/// Every policy's name, as --maxmemory-policy and CONFIG SET take it.
+pub const POLICIES: &[&str] = &[
+    "noeviction",
+    "allkeys-random",
+    "allkeys-lru",
+    "volatile-ttl",
+];
+
+impl EvictionPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(format!("argument must be one of {}", POLICIES.join(", "))),
+        }
+    }
+}
+
+pub const OOM_ERROR: &[u8] = b"OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Estimated bytes used by every db. Starts tracking the dbs that weren't.
+pub fn used_memory(store: &StateStore) -> usize {
+    store
+        .states
+        .iter()
+        .map(|db| db.memory.used(db.value()))
+        .sum()
+}
+
+/// Note the keys `command` uses as used now, when least recently used keys
+/// are evicted.
+pub fn record_access(store: &StateStore, state: &State, command: &RedisValueRef) {
+    if store.config.maxmemory() == 0
+        || store.config.maxmemory_policy() != EvictionPolicy::AllKeysLru
+    {
+        return;
+    }
+    if let RedisValueRef::Array(command) = command {
+        let keys = command_keys(command);
+        state
+            .memory
+            .touch(keys.iter().map(|key| &key[..]), now_ms());
+    }
+}
+
+/// Candidates kept between sampling rounds, like redis's eviction pool.
+const POOL_SIZE: usize = 16;
+
+/// A key `policy` may evict: its rank (the lowest goes first), db and bytes.
+type Candidate = (u64, Index, Key, usize);
+
+/// Sample `samples` keys of each db that `policy` may evict, or every one
+/// of a db with no more.
+fn sample_candidates(store: &StateStore, policy: EvictionPolicy, samples: usize) -> Vec<Candidate> {
+    let mut rng = rand::thread_rng();
+    let mut candidates = Vec::new();
+    for db in store.states.iter() {
+        match policy {
+            EvictionPolicy::NoEviction => return Vec::new(),
+            EvictionPolicy::AllKeysRandom | EvictionPolicy::AllKeysLru => {
+                for (key, usage) in db.memory.sample(samples) {
+                    let rank = match policy {
+                        EvictionPolicy::AllKeysLru => usage.accessed,
+                        _ => rng.gen(),
+                    };
+                    candidates.push((rank, *db.key(), key, usage.bytes));
+                }
+            }
+            EvictionPolicy::VolatileTtl => {
+                for key in sample_keys(&db.expirations, samples) {
+                    let deadline = db.expiry(&key);
+                    if let (Some(deadline), Some(usage)) = (deadline, db.memory.usage_of(&key)) {
+                        candidates.push((deadline, *db.key(), key, usage.bytes));
+                    }
+                }
+            }
+        }
+    }
+    candidates
+}
+
+/// Evict keys until we're back under maxmemory, returning them by db and
+/// whether we are.
+///
+/// Like redis, keys aren't all ranked: each round samples
+/// `maxmemory-samples` keys of every db into a pool of the best
+/// candidates seen so far, and evicts the best of the pool.
+fn evict(store: &StateStore) -> (Vec<(Index, Key)>, bool) {
+    let limit = store.config.maxmemory() as usize;
+    let mut used = used_memory(store);
+    let mut evicted = Vec::new();
+    if limit == 0 || used <= limit {
+        return (evicted, true);
+    }
+    let (policy, samples) = (
+        store.config.maxmemory_policy(),
+        store.config.maxmemory_samples(),
+    );
+    let mut pool: Vec<Candidate> = Vec::with_capacity(POOL_SIZE + samples);
+    while used > limit {
+        for candidate in sample_candidates(store, policy, samples) {
+            let (_, db, key, _) = &candidate;
+            if !pool.iter().any(|(_, d, k, _)| d == db && k == key) {
+                pool.push(candidate);
+            }
+        }
+        pool.sort_unstable_by_key(|(rank, ..)| *rank);
+        pool.truncate(POOL_SIZE);
+        if pool.is_empty() {
+            break;
+        }
+        let (_, db, key, bytes) = pool.remove(0);
+        let state = match store.states.get(&db) {
+            Some(state) => state.clone(),
+            None => continue,
+        };
+        if state.remove_key(&key) {
+            WriteEffects::new("Evicted", vec![key.clone()]).apply(&state);
+            used = used.saturating_sub(bytes);
+            evicted.push((db, key));
+        } else {
+            // Gone since it was tracked; stop sampling it.
+            state.memory.written(&state, &[key]);
+        }
+    }
+    store.stats.record_evictions(evicted.len() as u64);
+    (evicted, used <= limit)
+}
+
+/// Evict keys if we're over maxmemory, logging them to the AOF and to
+/// replicas when `feeding`. Returns whether we're under the limit now.
+pub fn make_room(store: &StateStore, feeding: bool) -> bool {
+    if store.replication.is_replica() {
+        return true;
+    }
+    let (evicted, under) = evict(store);
+    for (db, key) in evicted {
+        let state = store.get_or_create(db);
+        let command = RedisValueRef::Array(vec![
+            RedisValueRef::BulkString("DEL".into()),
+            RedisValueRef::BulkString(key.clone()),
+        ]);
+        let keys = [key];
+        if let Some(aof) = store.aof.get() {
+            let logged = task::block_in_place(|| aof.append(db, &state, "Del", &keys, &command));
+            if let Err(e) = logged {
+                error!(LOGGER, "Failed to log an eviction to the AOF: {}", e);
+            }
+        }
+        if feeding {
+            store.replication.feed(db, &state, "Del", &keys, &command);
+        }
+    }
+    under
+}
+
+#[cfg(test)]
+mod test_eviction {
+    use super::{make_room, record_access, used_memory, EvictionPolicy};
+    use crate::config::config_set;
+    use crate::expiry::now_ms;
+    use crate::types::{RedisValueRef, StateStore};
+    use bytes::Bytes;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// A store holding keys "0".."n" with 100 byte strings, in db 0.
+    fn store_with_keys(n: usize) -> StateStore {
+        let store = StateStore::default();
+        let db = store.get_default();
+        for i in 0..n {
+            db.kv
+                .insert(Bytes::from(i.to_string()), Bytes::from(vec![b'x'; 100]));
+        }
+        store
+    }
+
+    #[test]
+    fn test_policy_names() {
+        for name in super::POLICIES {
+            assert_eq!(EvictionPolicy::parse(name).unwrap().name(), *name);
+        }
+        assert!(EvictionPolicy::parse("volatile-lfu").is_err());
+    }
+
+    #[test]
+    fn test_noeviction_refuses() {
+        let store = store_with_keys(10);
+        assert_eq!(used_memory(&store), 10 * 101);
+        config_set(&store, &pairs(&[("maxmemory", "500")])).unwrap();
+        assert!(!make_room(&store, false));
+        assert_eq!(store.get_default().kv.len(), 10);
+    }
+
+    #[test]
+    fn test_allkeys_lru_keeps_recent_keys() {
+        let store = store_with_keys(10);
+        let db = store.get_default();
+        // Sampling every key, the least recently used go first.
+        let settings = pairs(&[
+            ("maxmemory", "500"),
+            ("maxmemory-policy", "allkeys-lru"),
+            ("maxmemory-samples", "10"),
+        ]);
+        config_set(&store, &settings).unwrap();
+        used_memory(&store);
+        let get = |key: &'static str| {
+            RedisValueRef::Array(vec![
+                RedisValueRef::BulkString(Bytes::from_static(b"GET")),
+                RedisValueRef::BulkString(Bytes::from_static(key.as_bytes())),
+            ])
+        };
+        for key in ["7", "8", "9"].iter() {
+            record_access(&store, &db, &get(key));
+        }
+        assert!(make_room(&store, false));
+        assert!(used_memory(&store) <= 500);
+        assert_eq!(db.kv.len(), 4);
+        for key in ["7", "8", "9"].iter() {
+            assert!(db.kv.contains_key(key.as_bytes()));
+        }
+        assert_eq!(store.stats.evicted_keys(), 6);
+    }
+
+    #[test]
+    fn test_volatile_ttl_only_evicts_expiring_keys() {
+        let store = store_with_keys(10);
+        let db = store.get_default();
+        for i in 0..3 {
+            db.set_expiry(Bytes::from(i.to_string()), now_ms() + 60_000 - i);
+        }
+        let settings = pairs(&[
+            ("maxmemory", "850"),
+            ("maxmemory-policy", "volatile-ttl"),
+            ("maxmemory-samples", "10"),
+        ]);
+        config_set(&store, &settings).unwrap();
+        assert!(make_room(&store, false));
+        // The nearest deadlines went first.
+        assert!(db.kv.contains_key(&b"0"[..]));
+        assert!(!db.kv.contains_key(&b"1"[..]) && !db.kv.contains_key(&b"2"[..]));
+
+        config_set(&store, &pairs(&[("maxmemory", "100")])).unwrap();
+        assert!(!make_room(&store, false));
+        assert_eq!(db.kv.len(), 7);
+    }
+
+    #[test]
+    fn test_samples_few_keys() {
+        let store = store_with_keys(1000);
+        let settings = pairs(&[
+            ("maxmemory", "50000"),
+            ("maxmemory-policy", "allkeys-random"),
+            ("maxmemory-samples", "3"),
+        ]);
+        config_set(&store, &settings).unwrap();
+        assert!(make_room(&store, false));
+        assert!(used_memory(&store) <= 50000);
+        assert!(used_memory(&store) > 50000 - 2 * 104);
+        assert!(config_set(&store, &pairs(&[("maxmemory-samples", "0")])).is_err());
+    }
+}
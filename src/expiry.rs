@@ -0,0 +1,276 @@
+//! Key expiry. Deadlines are unix times in milliseconds, kept per db in
+//! `State::expirations` (so they survive a restart).
+//!
+//! Like redis, keys are expired lazily, when a command touches them, and by
+//! a periodic sweep so keys nobody reads again don't live forever.
+//!
+//! Like redis' active expire cycle, the sweep doesn't look at every key:
+//! each step looks at the next `KEYS_PER_STEP` keys with a deadline, and
+//! the sweep keeps stepping while more than `ACCEPTABLE_STALE` percent of
+//! them were due, for at most `SWEEP_BUDGET`.
+//!
+//! Expired keys are also kept until `take_expired`, so they can be deleted
+//! from backing stores, and logged as deleted to the AOF and replicas by
+//! `log_expired`.
+
+use crate::database::EXPIRED;
+use crate::keyspace_events::publish_keyspace_events;
+use crate::logger::LOGGER;
+use crate::types::{Index, Key, RedisValueRef, State, StateRef, StateStore, StateStoreRef};
+use crate::write_effects::WriteEffects;
+use bytes::Bytes;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task;
+use tokio::time::interval;
+
+/// How often the sweep runs.
+const SWEEP_PERIOD: Duration = Duration::from_millis(100);
+/// Keys with a deadline looked at by each step of the sweep.
+const KEYS_PER_STEP: usize = 20;
+/// Percentage of due keys in a step under which the sweep stops.
+const ACCEPTABLE_STALE: usize = 10;
+/// Longest the sweep runs each period, a quarter of it like redis.
+const SWEEP_BUDGET: Duration = Duration::from_millis(25);
+
+/// Unix time, in milliseconds.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl State {
+    /// Expire `key` at unix time `deadline_ms`.
+    pub fn set_expiry(&self, key: Key, deadline_ms: u64) {
+        self.expirations.insert(key, deadline_ms);
+    }
+
+    /// Make `key` persistent again. Returns whether it had a deadline.
+    pub fn clear_expiry(&self, key: &[u8]) -> bool {
+        self.expirations.remove(key).is_some()
+    }
+
+    /// The deadline of `key`, if it has one.
+    pub fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.expirations.get(key).map(|deadline| *deadline)
+    }
+
+    /// Remove `key` if its deadline has passed. Returns whether it was expired.
+    pub fn expire_if_due(&self, key: &[u8]) -> bool {
+        self.expire_if_due_at(key, now_ms())
+    }
+
+    fn expire_if_due_at(&self, key: &[u8], now: u64) -> bool {
+        // remove_if keeps the check and the removal atomic, so a key given a
+        // new deadline in between isn't dropped.
+        if self
+            .expirations
+            .remove_if(key, |_, deadline| *deadline <= now)
+            .is_none()
+        {
+            return false;
+        }
+        self.remove_key(key);
+        let key = Key::copy_from_slice(key);
+        self.expired.lock().push(key.clone());
+        WriteEffects::new("Expired", vec![key]).apply(self);
+        true
+    }
+
+    /// Keys expired since the last call.
+    pub fn take_expired(&self) -> Vec<Key> {
+        std::mem::take(&mut *self.expired.lock())
+    }
+
+    /// Look at up to `count` keys with a deadline, carrying on from where the
+    /// last step stopped, and expire the due ones. Returns how many keys
+    /// were looked at, and how many expired.
+    pub fn expire_step(&self, count: usize) -> (usize, usize) {
+        let now = now_ms();
+        let shards = self.expirations.shards();
+        let mut due = Vec::new();
+        let mut seen = 0;
+        let mut cursor = self.expire_cursor.lock();
+        let start_offset = cursor.1;
+        // At most one lap, so few deadlines aren't looked at twice a step.
+        for lap in 0..=shards.len() {
+            let (shard, offset) = *cursor;
+            let shard = shard % shards.len();
+            // Back in the shard we started in, only up to where we started.
+            let left = if lap == shards.len() {
+                start_offset.min(count - seen)
+            } else {
+                count - seen
+            };
+            let mut taken = 0;
+            for (key, deadline) in shards[shard].read().iter().skip(offset).take(left) {
+                taken += 1;
+                if *deadline.get() <= now {
+                    due.push(key.clone());
+                }
+            }
+            seen += taken;
+            if seen == count {
+                *cursor = (shard, offset + taken);
+                break;
+            }
+            *cursor = (shard + 1, 0);
+        }
+        drop(cursor);
+        let expired = due
+            .iter()
+            .filter(|key| self.expire_if_due_at(key, now))
+            .count();
+        (seen, expired)
+    }
+}
+
+/// Expire keys of `db` until few of those looked at are due, or `until`.
+fn sweep(db: &State, until: Instant) {
+    loop {
+        let (seen, expired) = db.expire_step(KEYS_PER_STEP);
+        if seen == 0 || expired * 100 <= seen * ACCEPTABLE_STALE || Instant::now() >= until {
+            return;
+        }
+    }
+}
+
+/// Log `keys`, expired from db `index`, to the AOF and, if `feeding`, to
+/// replicas. The caller must hold the store's `write_order` exclusively.
+///
+/// With `ordered`, the caller has held it since the keys expired, so they're
+/// logged as DELs. Otherwise a write may have brought a key back (and been
+/// logged) since, so each is logged as what it is now: a DEL, or a RESTORE
+/// of what's there.
+pub fn log_expired(
+    state_store: &StateStore,
+    index: Index,
+    db: &State,
+    keys: &[Key],
+    ordered: bool,
+    feeding: bool,
+) -> io::Result<()> {
+    let mut del = vec![RedisValueRef::BulkString(Bytes::from_static(b"DEL"))];
+    del.extend(keys.iter().cloned().map(RedisValueRef::BulkString));
+    let command = RedisValueRef::Array(del);
+    let op = if ordered { "Del" } else { EXPIRED };
+    if feeding {
+        state_store.replication.feed(index, db, op, keys, &command);
+    }
+    match state_store.aof.get() {
+        Some(aof) => aof.append(index, db, op, keys, &command),
+        None => Ok(()),
+    }
+}
+
+/// Log keys expired in `db` as deleted, and delete them from the backing
+/// store if there's one, so they aren't loaded back on a miss.
+async fn forget_expired(state_store: &StateStoreRef, index: Index, db: &StateRef) {
+    let expired = db.take_expired();
+    if expired.is_empty() {
+        return;
+    }
+    if state_store.aof.get().is_some() || state_store.replication.feeding() {
+        let _order = state_store.write_order.write().await;
+        let feeding = state_store.replication.feeding();
+        let logged =
+            task::block_in_place(|| log_expired(state_store, index, db, &expired, false, feeding));
+        if let Err(e) = logged {
+            error!(LOGGER, "Failed to log expired keys to the AOF: {}", e);
+        }
+    }
+    let backing = match &state_store.backing {
+        Some(backing) if !expired.is_empty() => backing.clone(),
+        _ => return,
+    };
+    let db = db.clone();
+    let res = tokio::task::spawn_blocking(move || backing.persist(index, &db, &expired)).await;
+    if let Ok(Err(e)) = res {
+        error!(
+            LOGGER,
+            "Failed to delete expired keys from the backing store: {}", e
+        );
+    }
+}
+
+/// Periodically drop expired keys from every db, and publish the keyspace
+/// notifications commands haven't.
+pub async fn expire_keys_interval(state: StateStoreRef) {
+    let mut interval = interval(SWEEP_PERIOD);
+    loop {
+        interval.tick().await;
+        // Replicas leave expiring keys to their primary's DELs.
+        let active = !state.active_expire_disabled.load(Ordering::Relaxed)
+            && !state.replication.is_replica();
+        let until = Instant::now() + SWEEP_BUDGET;
+        let dbs: Vec<_> = state
+            .states
+            .iter()
+            .map(|db| (*db.key(), db.value().clone()))
+            .collect();
+        for (index, db) in dbs {
+            if active {
+                sweep(&db, until);
+            }
+            forget_expired(&state, index, &db).await;
+            publish_keyspace_events(&state, index, &db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_expiry {
+    use super::now_ms;
+    use crate::types::State;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_expire_if_due() {
+        let state = State::default();
+        let key = Bytes::from_static(b"k");
+        state.kv.insert(key.clone(), key.clone());
+        state.set_expiry(key.clone(), now_ms() + 60_000);
+        assert!(!state.expire_if_due(&key));
+        assert!(state.kv.contains_key(&key));
+        state.set_expiry(key.clone(), now_ms() - 1);
+        assert!(state.expire_if_due(&key));
+        assert!(!state.kv.contains_key(&key));
+        assert_eq!(state.expiry(&key), None);
+    }
+
+    #[test]
+    fn test_expire_step() {
+        let state = State::default();
+        for (name, deadline) in [("a", 0), ("b", 1), ("c", u64::MAX)].iter() {
+            let key = Bytes::from_static(name.as_bytes());
+            state.sets.insert(key.clone(), Default::default());
+            state.set_expiry(key, *deadline);
+        }
+        assert_eq!(state.expire_step(20), (3, 2));
+        assert_eq!(state.sets.len(), 1);
+        assert_eq!(state.expirations.len(), 1);
+        assert_eq!(state.take_expired().len(), 2);
+        assert!(state.take_expired().is_empty());
+    }
+
+    #[test]
+    fn test_steps_carry_on() {
+        let state = State::default();
+        for i in 0..100 {
+            let key = Bytes::from(format!("k{}", i));
+            state.kv.insert(key.clone(), key.clone());
+            state.set_expiry(key, if i % 2 == 0 { 0 } else { u64::MAX });
+        }
+        let mut expired = 0;
+        // Each step only looks at a few keys, but they all get looked at.
+        for _ in 0..10 {
+            let (seen, step_expired) = state.expire_step(20);
+            assert!(seen <= 20);
+            expired += step_expired;
+        }
+        assert_eq!(expired, 50);
+        assert_eq!(state.kv.len(), 50);
+    }
+}
@@ -0,0 +1,494 @@
+//! GEO commands, on top of sorted sets.
+//!
+//! Like redis, a location is a sorted set member whose score is a 52 bit
+//! geohash: 26 bits of latitude interleaved with 26 bits of longitude. Nearby
+//! locations share score prefixes, so a search only reads the score ranges
+//! of the cells around its origin, then measures what it finds.
+
+use crate::ops::RVec;
+use crate::sorted_sets::{zset_interact, ZAddOptions, ZSetOps};
+use crate::types::{Count, Key, ReturnValue, Score, StateRef};
+use crate::{make_reader, op_variants};
+use std::cmp::Ordering::Equal;
+use std::ops::Bound;
+
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+/// The limits of Web Mercator, like redis.
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+/// Bits per coordinate. Interleaved, they make scores a double holds exactly.
+const STEP: u32 = 26;
+/// The earth's radius redis measures with, in meters.
+const EARTH_RADIUS_M: f64 = 6372797.560856;
+/// Half the earth's circumference.
+const MERCATOR_MAX: f64 = 20037726.37;
+
+/// A distance unit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Meters,
+    Kilometers,
+    Miles,
+    Feet,
+}
+
+impl Unit {
+    /// Parse `m`, `km`, `mi` or `ft`.
+    pub fn parse(name: &[u8]) -> Option<Unit> {
+        match name.to_ascii_lowercase().as_slice() {
+            b"m" => Some(Unit::Meters),
+            b"km" => Some(Unit::Kilometers),
+            b"mi" => Some(Unit::Miles),
+            b"ft" => Some(Unit::Feet),
+            _ => None,
+        }
+    }
+
+    /// How many meters one of this unit is.
+    pub fn meters(self) -> f64 {
+        match self {
+            Unit::Meters => 1.0,
+            Unit::Kilometers => 1000.0,
+            Unit::Miles => 1609.34,
+            Unit::Feet => 0.3048,
+        }
+    }
+}
+
+/// Where GEOSEARCH searches around.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoOrigin {
+    /// FROMMEMBER
+    Member(Key),
+    /// FROMLONLAT
+    LonLat(f64, f64),
+}
+
+/// The area GEOSEARCH covers, in meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoShape {
+    /// BYRADIUS
+    Radius(f64),
+    /// BYBOX width height
+    Box(f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoSort {
+    Asc,
+    Desc,
+}
+
+/// The options of GEOSEARCH.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoSearchOptions {
+    pub origin: GeoOrigin,
+    pub shape: GeoShape,
+    /// The unit of the shape, and of the distances replied.
+    pub unit: Unit,
+    pub sort: Option<GeoSort>,
+    /// COUNT n, and whether ANY n will do.
+    pub count: Option<(usize, bool)>,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+op_variants! {
+    GeoOps,
+    GeoAdd(Key, ZAddOptions, RVec<(Score, Key)>),
+    GeoPos(Key, RVec<Key>),
+    GeoDist(Key, Key, Key, Unit),
+    GeoSearch(Key, GeoSearchOptions)
+}
+
+impl GeoOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            GeoOps::GeoAdd(key, ..) => Some(vec![key.clone()]),
+            GeoOps::GeoPos(..) | GeoOps::GeoDist(..) | GeoOps::GeoSearch(..) => None,
+        }
+    }
+}
+
+make_reader!(zsets, read_zsets);
+
+/// Spread the bits of `v` to the even bits of the result.
+fn spread(v: u32) -> u64 {
+    let mut x = v as u64;
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Undo `spread`, gathering the even bits of `x`.
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+    ((x | (x >> 16)) & 0x0000_0000_ffff_ffff) as u32
+}
+
+/// The geohash of a cell: latitude on the even bits, longitude on the odd ones.
+fn interleave(lat_cell: u32, lon_cell: u32) -> u64 {
+    spread(lat_cell) | (spread(lon_cell) << 1)
+}
+
+/// The (longitude, latitude) cells of a location, with `step` bits each.
+fn cells(lon: f64, lat: f64, step: u32) -> (u32, u32) {
+    let scale = (1u64 << step) as f64;
+    let cell = |v: f64, min: f64, max: f64| {
+        let cell = ((v - min) / (max - min) * scale) as u64;
+        cell.min((1 << step) - 1) as u32
+    };
+    (cell(lon, LON_MIN, LON_MAX), cell(lat, LAT_MIN, LAT_MAX))
+}
+
+/// Whether redis can index this location.
+pub fn is_valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+/// The geohash of a location, as the score GEOADD gives it.
+pub fn score(lon: f64, lat: f64) -> Option<Score> {
+    if !is_valid(lon, lat) {
+        return None;
+    }
+    let (lon_cell, lat_cell) = cells(lon, lat, STEP);
+    Score::new(interleave(lat_cell, lon_cell) as f64)
+}
+
+/// The (longitude, latitude) at the center of the cell of `hash`.
+fn decode(hash: u64) -> (f64, f64) {
+    let scale = (1u64 << STEP) as f64;
+    let center = |cell: u32, min: f64, max: f64| {
+        let center = min + (cell as f64 + 0.5) / scale * (max - min);
+        center.max(min).min(max)
+    };
+    let lat = center(squash(hash), LAT_MIN, LAT_MAX);
+    let lon = center(squash(hash >> 1), LON_MIN, LON_MAX);
+    (lon, lat)
+}
+
+fn location(score: Score) -> (f64, f64) {
+    decode(score.value() as u64)
+}
+
+/// The distance in meters between two locations, along the earth.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    2.0 * EARTH_RADIUS_M * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+/// Whether the 3x3 cells around a cell at `step` reach `half_width` and
+/// `half_height` meters away from anywhere in it.
+fn cells_cover(step: u32, lat: f64, half_width: f64, half_height: f64) -> bool {
+    let scale = (1u64 << step) as f64;
+    let cell_height = ((LAT_MAX - LAT_MIN) / scale).to_radians() * EARTH_RADIUS_M;
+    // Cells narrow away from the equator, so measure at the far edge.
+    let far_lat = (lat.abs() + (half_height / EARTH_RADIUS_M).to_degrees()).min(90.0);
+    let cell_width =
+        ((LON_MAX - LON_MIN) / scale).to_radians() * EARTH_RADIUS_M * far_lat.to_radians().cos();
+    cell_height >= half_height && cell_width >= half_width
+}
+
+/// The score ranges, each [start, end), of the cells to search for anything
+/// within `half_width` and `half_height` meters of a location.
+fn search_ranges(lon: f64, lat: f64, half_width: f64, half_height: f64) -> Vec<(u64, u64)> {
+    // The finest step whose cells are about as wide as the search.
+    let mut step = STEP;
+    let mut reach = half_width.max(half_height);
+    if reach > 0.0 {
+        step = 1;
+        while reach < MERCATOR_MAX && step < STEP + 2 {
+            reach *= 2.0;
+            step += 1;
+        }
+        step = step.saturating_sub(2).max(1).min(STEP);
+    }
+    while step > 1 && !cells_cover(step, lat, half_width, half_height) {
+        step -= 1;
+    }
+    let (lon_cell, lat_cell) = cells(lon, lat, step);
+    let cell_count = 1i64 << step;
+    let shift = 2 * (STEP - step);
+    let mut ranges = Vec::with_capacity(9);
+    for lat_offset in -1..=1 {
+        let lat_cell = lat_cell as i64 + lat_offset;
+        if lat_cell < 0 || lat_cell >= cell_count {
+            continue;
+        }
+        for lon_offset in -1..=1 {
+            // Longitude wraps around.
+            let lon_cell = (lon_cell as i64 + lon_offset).rem_euclid(cell_count);
+            let hash = interleave(lat_cell as u32, lon_cell as u32);
+            ranges.push((hash << shift, (hash + 1) << shift));
+        }
+    }
+    ranges.sort_unstable();
+    ranges.dedup();
+    ranges
+}
+
+/// The distance from (`lon`, `lat`) to a location within `shape` around
+/// it, or None if the location is outside.
+fn distance_within(shape: GeoShape, lon: f64, lat: f64, to_lon: f64, to_lat: f64) -> Option<f64> {
+    if let GeoShape::Box(width, height) = shape {
+        let lat_distance = (to_lat - lat).to_radians().abs() * EARTH_RADIUS_M;
+        if lat_distance > height / 2.0 {
+            return None;
+        }
+        if distance(to_lon, to_lat, lon, to_lat) > width / 2.0 {
+            return None;
+        }
+    }
+    let d = distance(lon, lat, to_lon, to_lat);
+    match shape {
+        GeoShape::Radius(radius) if d > radius => None,
+        _ => Some(d),
+    }
+}
+
+/// A location GEOSEARCH found.
+struct Found {
+    member: Key,
+    distance: f64,
+    score: Score,
+}
+
+fn coord_reply(lon: f64, lat: f64) -> ReturnValue {
+    ReturnValue::Array(vec![
+        ReturnValue::StringRes(lon.to_string().into()),
+        ReturnValue::StringRes(lat.to_string().into()),
+    ])
+}
+
+fn distance_reply(meters: f64, unit: Unit) -> ReturnValue {
+    ReturnValue::StringRes(format!("{:.4}", meters / unit.meters()).into())
+}
+
+fn geo_search(state: &StateRef, key: &Key, opts: GeoSearchOptions) -> ReturnValue {
+    let zset = match read_zsets!(state, key) {
+        Some(zset) => zset,
+        None => return ReturnValue::Array(Vec::new()),
+    };
+    let (lon, lat) = match opts.origin {
+        GeoOrigin::LonLat(lon, lat) => (lon, lat),
+        GeoOrigin::Member(ref member) => match zset.score(member.clone()) {
+            Some(score) => location(score),
+            None => return ReturnValue::Error(b"ERR could not decode requested zset member"),
+        },
+    };
+    let (half_width, half_height) = match opts.shape {
+        GeoShape::Radius(radius) => (radius, radius),
+        GeoShape::Box(width, height) => (width / 2.0, height / 2.0),
+    };
+    let mut found = Vec::new();
+    for (start, end) in search_ranges(lon, lat, half_width, half_height) {
+        let start = Bound::Included(Score::new(start as f64).expect("hashes are numbers"));
+        let end = Bound::Excluded(Score::new(end as f64).expect("hashes are numbers"));
+        for member in zset.range_by_score(start, end) {
+            let (to_lon, to_lat) = location(member.score);
+            if let Some(distance) = distance_within(opts.shape, lon, lat, to_lon, to_lat) {
+                found.push(Found {
+                    member: member.member.clone(),
+                    distance,
+                    score: member.score,
+                });
+            }
+        }
+    }
+    drop(zset);
+
+    // ANY takes the first matches; otherwise COUNT takes the closest.
+    let sort = match opts.count {
+        Some((count, true)) => {
+            found.truncate(count);
+            opts.sort
+        }
+        Some(_) => opts.sort.or(Some(GeoSort::Asc)),
+        None => opts.sort,
+    };
+    // Distances are never NaN.
+    let by_distance = |a: &Found, b: &Found| a.distance.partial_cmp(&b.distance).unwrap_or(Equal);
+    match sort {
+        Some(GeoSort::Asc) => found.sort_by(by_distance),
+        Some(GeoSort::Desc) => found.sort_by(|a, b| by_distance(b, a)),
+        None => {}
+    }
+    if let Some((count, _)) = opts.count {
+        found.truncate(count);
+    }
+
+    if !(opts.with_coord || opts.with_dist || opts.with_hash) {
+        return ReturnValue::MultiStringRes(found.into_iter().map(|f| f.member).collect());
+    }
+    let replies = found
+        .into_iter()
+        .map(|f| {
+            let mut reply = vec![ReturnValue::StringRes(f.member)];
+            if opts.with_dist {
+                reply.push(distance_reply(f.distance, opts.unit));
+            }
+            if opts.with_hash {
+                reply.push(ReturnValue::IntRes(f.score.value() as Count));
+            }
+            if opts.with_coord {
+                let (lon, lat) = location(f.score);
+                reply.push(coord_reply(lon, lat));
+            }
+            ReturnValue::Array(reply)
+        })
+        .collect();
+    ReturnValue::Array(replies)
+}
+
+pub async fn geo_interact(geo_op: GeoOps, state: StateRef) -> ReturnValue {
+    match geo_op {
+        GeoOps::GeoAdd(key, opts, member_scores) => {
+            zset_interact(ZSetOps::ZAdd(key, opts, member_scores), state).await
+        }
+        GeoOps::GeoPos(key, members) => {
+            let zset = read_zsets!(state, &key);
+            let replies = members
+                .into_iter()
+                .map(
+                    |member| match zset.as_ref().and_then(|zset| zset.score(member)) {
+                        Some(score) => {
+                            let (lon, lat) = location(score);
+                            coord_reply(lon, lat)
+                        }
+                        None => ReturnValue::Nil,
+                    },
+                )
+                .collect();
+            ReturnValue::Array(replies)
+        }
+        GeoOps::GeoDist(key, a, b, unit) => {
+            let zset = match read_zsets!(state, &key) {
+                Some(zset) => zset,
+                None => return ReturnValue::Nil,
+            };
+            match (zset.score(a), zset.score(b)) {
+                (Some(a), Some(b)) => {
+                    let ((lon1, lat1), (lon2, lat2)) = (location(a), location(b));
+                    distance_reply(distance(lon1, lat1, lon2, lat2), unit)
+                }
+                _ => ReturnValue::Nil,
+            }
+        }
+        GeoOps::GeoSearch(key, opts) => geo_search(&state, &key, opts),
+    }
+}
+
+#[cfg(test)]
+mod test_geo {
+    use super::{
+        decode, distance, geo_interact, score, GeoOps, GeoOrigin, GeoSearchOptions, GeoShape,
+        GeoSort, Unit,
+    };
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    #[test]
+    fn test_geohash() {
+        // The scores redis gives Palermo and Catania.
+        let (lon, lat) = PALERMO;
+        assert_eq!(score(lon, lat).unwrap().value(), 3479099956230698.0);
+        let (lon, lat) = CATANIA;
+        assert_eq!(score(lon, lat).unwrap().value(), 3479447370796909.0);
+        let (lon, lat) = decode(3479099956230698);
+        assert!((lon - PALERMO.0).abs() < 1e-5 && (lat - PALERMO.1).abs() < 1e-5);
+        assert_eq!(score(0.0, 86.0), None);
+        assert_eq!(score(-181.0, 0.0), None);
+        let d = distance(PALERMO.0, PALERMO.1, CATANIA.0, CATANIA.1);
+        assert!((d - 166274.15).abs() < 1.0, "{}", d);
+    }
+
+    #[tokio::test]
+    async fn test_geo_search() {
+        let key = Bytes::from_static(b"Sicily");
+        let eng = Arc::new(State::default());
+        let place = |name: &'static str, (lon, lat): (f64, f64)| {
+            (
+                score(lon, lat).unwrap(),
+                Bytes::from_static(name.as_bytes()),
+            )
+        };
+        let places = smallvec![place("Palermo", PALERMO), place("Catania", CATANIA)];
+        let added = geo_interact(
+            GeoOps::GeoAdd(key.clone(), Default::default(), places),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(added, ReturnValue::IntRes(2));
+
+        let dist = GeoOps::GeoDist(
+            key.clone(),
+            Bytes::from_static(b"Palermo"),
+            Bytes::from_static(b"Catania"),
+            Unit::Kilometers,
+        );
+        assert_eq!(
+            geo_interact(dist, eng.clone()).await,
+            ReturnValue::StringRes(Bytes::from_static(b"166.2742"))
+        );
+
+        let search = |shape, sort| GeoSearchOptions {
+            origin: GeoOrigin::LonLat(15.0, 37.0),
+            shape,
+            unit: Unit::Kilometers,
+            sort,
+            count: None,
+            with_coord: false,
+            with_dist: true,
+            with_hash: false,
+        };
+        let found = |name: &'static str, dist: &'static str| {
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(Bytes::from_static(name.as_bytes())),
+                ReturnValue::StringRes(Bytes::from_static(dist.as_bytes())),
+            ])
+        };
+        let opts = search(GeoShape::Radius(200_000.0), Some(GeoSort::Asc));
+        assert_eq!(
+            geo_interact(GeoOps::GeoSearch(key.clone(), opts), eng.clone()).await,
+            ReturnValue::Array(vec![
+                found("Catania", "56.4413"),
+                found("Palermo", "190.4424")
+            ])
+        );
+        let opts = search(GeoShape::Radius(100_000.0), None);
+        assert_eq!(
+            geo_interact(GeoOps::GeoSearch(key.clone(), opts), eng.clone()).await,
+            ReturnValue::Array(vec![found("Catania", "56.4413")])
+        );
+        let opts = search(GeoShape::Box(400_000.0, 400_000.0), Some(GeoSort::Desc));
+        assert_eq!(
+            geo_interact(GeoOps::GeoSearch(key.clone(), opts), eng.clone()).await,
+            ReturnValue::Array(vec![
+                found("Palermo", "190.4424"),
+                found("Catania", "56.4413")
+            ])
+        );
+
+        let mut opts = search(GeoShape::Radius(1.0), None);
+        opts.origin = GeoOrigin::Member(Bytes::from_static(b"Nowhere"));
+        assert!(geo_interact(GeoOps::GeoSearch(key, opts), eng.clone())
+            .await
+            .is_error());
+    }
+}
@@ -0,0 +1,115 @@
+/// Whether `string` matches the redis style glob `pattern`, as used by KEYS
+/// and the SCAN family.
+///
+/// `*` matches any run of bytes, `?` any single byte, `[abc]`, `[a-z]` and
+/// `[^abc]` a byte in (or not in) a class, and `\` escapes the next byte.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*`: (pattern after it, string position).
+    let mut star: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+            star = Some((p, s));
+            continue;
+        }
+        if p < pattern.len() {
+            if let Some(next) = match_byte(pattern, p, string[s]) {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+        // Let the last `*` swallow one more byte and try again.
+        match star {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                star = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// If the token at `pattern[p]` matches `c`, the index of the next token.
+fn match_byte(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    let (matched, next) = match pattern[p] {
+        b'?' => (true, p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c, p + 2),
+        b'[' => return match_class(pattern, p + 1, c),
+        literal => (literal == c, p + 1),
+    };
+    if matched {
+        Some(next)
+    } else {
+        None
+    }
+}
+
+/// Match a `[...]` class starting at `pattern[p]` (just after the `[`).
+fn match_class(pattern: &[u8], mut p: usize, c: u8) -> Option<usize> {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= lo <= c && c <= hi;
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    // Like redis, an unterminated class runs to the end of the pattern.
+    let next = std::cmp::min(p + 1, pattern.len());
+    if matched != negate {
+        Some(next)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_glob {
+    use super::glob_match;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(!glob_match(b"user:*", b"session:1"));
+        assert!(glob_match(b"*:*:name", b"user:1:name"));
+        assert!(!glob_match(b"*:*:name", b"user:1:age"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"a**b", b"ab"));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h[b-a]llo", b"hallo"));
+        assert!(glob_match(b"\\*", b"*"));
+        assert!(!glob_match(b"\\*", b"a"));
+        assert!(glob_match(b"[\\]]", b"]"));
+    }
+}
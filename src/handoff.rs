@@ -0,0 +1,292 @@
+//! Warm restarts: hand the dataset to a freshly started process over a unix socket.
+//!
+//! The old process is started with `--handoff-socket <path>`, and the new one with
+//! `--handoff-from <path>`. When the new process connects, the old one stops
+//! accepting commands, waits for in-flight commands to finish and streams its
+//! StateStore. The new process loads it and acks, and the old one exits. If
+//! the state can't be sent, or the new process doesn't ack, the old process
+//! goes back to serving instead.
+//!
+//! The socket is only usable by the user running the server.
+//!
+//! The new process first sends its capabilities. If it can't read some of
+//! our data (e.g. it's an older version without stacks), the handoff is
+//! refused and we keep serving, unless it was started with --lossy-load.
+
+use crate::database::configure_state_store;
+use crate::dump_format::{self, unreadable_by, Capabilities};
+use crate::logger::LOGGER;
+use crate::shutdown::drain;
+use crate::startup::Config;
+use crate::types::StateStoreRef;
+use std::error::Error;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task;
+use tokio::time::timeout;
+
+/// Handoff requests are tiny; anything bigger is garbage.
+const MAX_REQUEST_BYTES: usize = 1 << 20;
+
+/// How long a new process gets to send its request once connected.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the state transfer may take, from sending the first byte to
+/// the new process acking it. We don't serve commands meanwhile.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sent back by the new process once it loaded the state.
+const ACK: u8 = 0x06;
+
+/// Sent by the new process when it connects.
+#[derive(Serialize, Deserialize)]
+struct HandoffRequest {
+    capabilities: Capabilities,
+    lossy: bool,
+}
+
+async fn read_request(stream: &mut UnixStream) -> Result<HandoffRequest, Box<dyn Error>> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_REQUEST_BYTES {
+        return Err(format!("handoff request of {} bytes is too big", len).into());
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(rmps::from_read_ref(&buf)?)
+}
+
+/// Stop serving commands, wait for in-flight ones, stream the state and wait
+/// for the new process to ack it. If any of that fails, commands are served
+/// again and the error returned.
+async fn send_state(
+    mut stream: UnixStream,
+    state_store: &StateStoreRef,
+    skip: Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    if !drain(state_store, 0).await {
+        warn!(LOGGER, "Handing off with commands still in flight!");
+    }
+    let store = state_store.clone();
+    let encoded = task::spawn_blocking(move || {
+        let mut encoded = Vec::new();
+        dump_format::write_state(&mut encoded, &store, &skip, false)
+            .map(|_| encoded)
+            .map_err(|e| e.to_string())
+    });
+    let sent = async {
+        let encoded = encoded.await??;
+        info!(LOGGER, "Handing off {} bytes of state...", encoded.len());
+        let transfer = async {
+            stream.write_all(&encoded).await?;
+            stream.shutdown().await?;
+            stream.read_u8().await
+        };
+        match timeout(TRANSFER_TIMEOUT, transfer).await {
+            Ok(Ok(ACK)) => Ok::<_, Box<dyn Error>>(()),
+            Ok(Ok(other)) => Err(format!("unexpected handoff ack {:#x}", other).into()),
+            // Most likely the new process couldn't load the state and exited.
+            Ok(Err(e)) => Err(format!("the new process didn't ack the state: {}", e).into()),
+            Err(_) => Err("the new process didn't ack the state in time".into()),
+        }
+    }
+    .await;
+    if sent.is_err() {
+        state_store.handing_off.store(false, Ordering::SeqCst);
+    }
+    sent
+}
+
+/// Bind the handoff socket at `path`, only usable by our own user. It's
+/// bound under a temporary name and renamed into place once its permissions
+/// are set, so nobody else can connect in between.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    let _ = std::fs::remove_file(&tmp);
+    let listener = UnixListener::bind(&tmp)?;
+    std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+    // A previous process may have left its socket behind.
+    let _ = std::fs::remove_file(path);
+    std::fs::rename(&tmp, path)?;
+    Ok(listener)
+}
+
+/// Wait for a new process to ask for our state and send it over. Requests
+/// from processes that can't read our data are refused, and we keep waiting.
+async fn hand_off(
+    listener: &UnixListener,
+    state_store: &StateStoreRef,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                error!(LOGGER, "Bad handoff request: {}", e);
+                continue;
+            }
+            Err(_) => {
+                error!(LOGGER, "Handoff request timed out");
+                continue;
+            }
+        };
+        let unreadable = unreadable_by(state_store, &request.capabilities);
+        if unreadable.is_empty() || request.lossy {
+            if !unreadable.is_empty() {
+                warn!(
+                    LOGGER,
+                    "New process can't read {}, dropping it as requested",
+                    unreadable.join(", ")
+                );
+            }
+            info!(LOGGER, "New process connected, handing off state...");
+            return send_state(stream, state_store, unreadable).await;
+        }
+        // Dropping the stream tells the new process we refused.
+        error!(
+            LOGGER,
+            "Refusing handoff to redis-oxide {}: it can't read {} (start it with --lossy-load to drop them)",
+            request.capabilities.crate_version,
+            unreadable.join(", ")
+        );
+    }
+}
+
+/// Listen for handoffs on `path`, and exit once one was acked. A failed
+/// handoff is logged, and we keep serving (and listening).
+pub async fn handoff_listener(path: impl AsRef<Path>, state_store: StateStoreRef) {
+    let path = path.as_ref();
+    let listener = match bind_private(path) {
+        Ok(l) => l,
+        Err(e) => {
+            error!(LOGGER, "Could not listen for handoffs on {:?}: {}", path, e);
+            return;
+        }
+    };
+    info!(LOGGER, "Listening for handoffs on {:?}", path);
+    loop {
+        match hand_off(&listener, &state_store).await {
+            Ok(()) => break,
+            Err(e) => error!(LOGGER, "Handoff failed, still serving: {}", e),
+        }
+    }
+    // The new process has the state, and serves from now on.
+    state_store.stop_accepting.notify_one();
+    info!(LOGGER, "Handoff complete, exiting.");
+    let _ = std::fs::remove_file(path);
+    std::process::exit(0);
+}
+
+/// Take over the state of the process listening on `path`.
+pub async fn receive_handoff(
+    path: impl AsRef<Path>,
+    config: &Config,
+) -> Result<StateStoreRef, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(path.as_ref()).await?;
+    let request = rmps::to_vec(&HandoffRequest {
+        capabilities: Capabilities::local(),
+        lossy: config.lossy_load,
+    })?;
+    stream.write_u32(request.len() as u32).await?;
+    stream.write_all(&request).await?;
+    let mut encoded = Vec::new();
+    // Draining and encoding a big state can take a while.
+    timeout(TRANSFER_TIMEOUT, stream.read_to_end(&mut encoded))
+        .await
+        .map_err(|_| "timed out waiting for the running server's state")??;
+    if encoded.is_empty() {
+        return Err("the running server refused the handoff, see its log".into());
+    }
+    info!(LOGGER, "Received {} bytes of state", encoded.len());
+    let mut state_store = dump_format::read_state(&encoded, config.lossy_load)?;
+    configure_state_store(&mut state_store, config)?;
+    // Until it sees this, the old process can still go back to serving.
+    stream.write_u8(ACK).await?;
+    Ok(Arc::new(state_store))
+}
+
+#[cfg(test)]
+mod test_handoff {
+    use super::{bind_private, hand_off, receive_handoff, HandoffRequest};
+    use crate::dump_format::Capabilities;
+    use crate::startup::Config;
+    use crate::types::StateStore;
+    use bytes::Bytes;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use structopt::StructOpt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn test_handoff_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "redis-oxide-test-handoff-{}.sock",
+            std::process::id()
+        ));
+        let old = Arc::new(StateStore::default());
+        old.get_or_create(2)
+            .kv
+            .insert(Bytes::from_static(b"key"), Bytes::from_static(b"value"));
+        let listener = bind_private(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let sender = {
+            let old = old.clone();
+            tokio::spawn(async move { hand_off(&listener, &old).await.map_err(|e| e.to_string()) })
+        };
+        let config = Config::from_iter(&["redis-oxide"]);
+        let new = receive_handoff(&path, &config).await.unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(
+            new.get_or_create(2)
+                .kv
+                .get(&b"key"[..])
+                .map(|v| v.value().clone()),
+            Some(Bytes::from_static(b"value"))
+        );
+        // The old process stopped serving, and it's up to the caller to exit.
+        assert!(old.handing_off.load(Ordering::SeqCst));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_handoff_without_ack_keeps_serving() {
+        let path = std::env::temp_dir().join(format!(
+            "redis-oxide-test-handoff-noack-{}.sock",
+            std::process::id()
+        ));
+        let old = Arc::new(StateStore::default());
+        let listener = bind_private(&path).unwrap();
+        let sender = {
+            let old = old.clone();
+            tokio::spawn(async move { hand_off(&listener, &old).await.map_err(|e| e.to_string()) })
+        };
+        // Take the state, then go away as if loading it failed.
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        let request = rmps::to_vec(&HandoffRequest {
+            capabilities: Capabilities::local(),
+            lossy: false,
+        })
+        .unwrap();
+        stream.write_u32(request.len() as u32).await.unwrap();
+        stream.write_all(&request).await.unwrap();
+        let mut encoded = Vec::new();
+        stream.read_to_end(&mut encoded).await.unwrap();
+        assert!(!encoded.is_empty());
+        drop(stream);
+
+        assert!(sender.await.unwrap().is_err());
+        assert!(!old.handing_off.load(Ordering::SeqCst));
+        let _ = std::fs::remove_file(&path);
+    }
+}
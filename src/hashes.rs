@@ -1,7 +1,10 @@
 use crate::op_variants;
 use crate::ops::RVec;
+use crate::scan::{scan_members, scan_reply, ScanOptions};
 use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use crate::write_effects::untouched;
 use crate::{make_reader, make_writer};
+use rand::seq::SliceRandom;
 use std::collections::hash_map::Entry;
 
 op_variants! {
@@ -14,18 +17,125 @@ op_variants! {
     HKeys(Key),
     HMSet(Key, RVec<(Key, Value)>),
     HIncrBy(Key, Key, Count),
+    HIncrByFloat(Key, Key, f64),
+    HRandField(Key, Option<Count>, bool),
     HLen(Key),
     HDel(Key, RVec<Key>),
     HVals(Key),
     HStrLen(Key, Key),
-    HSetNX(Key, Key, Value)
+    HSetNX(Key, Key, Value),
+    HScan(Key, u64, ScanOptions)
+}
+
+impl HashOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            HashOps::HGet(..)
+            | HashOps::HExists(..)
+            | HashOps::HGetAll(_)
+            | HashOps::HMGet(..)
+            | HashOps::HKeys(_)
+            | HashOps::HLen(_)
+            | HashOps::HVals(_)
+            | HashOps::HStrLen(..)
+            | HashOps::HRandField(..)
+            | HashOps::HScan(..) => None,
+            HashOps::HSet(key, _, _)
+            | HashOps::HMSet(key, _)
+            | HashOps::HIncrBy(key, _, _)
+            | HashOps::HIncrByFloat(key, _, _)
+            | HashOps::HDel(key, _)
+            | HashOps::HSetNX(key, _, _) => Some(vec![key.clone()]),
+        }
+    }
 }
 
 make_reader!(hashes, read_hashes);
 make_writer!(hashes, write_hashes);
 
+/// Add `by` to the integer in `field` (0 if it's missing), returning the new value.
+fn incr_field_by(state: &StateRef, key: Key, field: Key, by: Count) -> ReturnValue {
+    let mut hash = state.hashes.entry(key).or_default();
+    let current: Count = match hash.get(&field) {
+        Some(value) => match std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+            Some(current) => current,
+            None => return ReturnValue::Error(b"ERR hash value is not an integer"),
+        },
+        None => 0,
+    };
+    match current.checked_add(by) {
+        Some(new) => {
+            hash.insert(field, new.to_string().into());
+            ReturnValue::IntRes(new)
+        }
+        None => ReturnValue::Error(b"ERR increment or decrement would overflow"),
+    }
+}
+
+/// Like `incr_field_by`, but for floats. Replies with the new value as a string.
+fn incr_field_by_float(state: &StateRef, key: Key, field: Key, by: f64) -> ReturnValue {
+    let mut hash = state.hashes.entry(key).or_default();
+    let current: f64 = match hash.get(&field) {
+        Some(value) => match std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+            Some(current) => current,
+            None => return ReturnValue::Error(b"ERR hash value is not a float"),
+        },
+        None => 0.0,
+    };
+    let new = current + by;
+    if !new.is_finite() {
+        return ReturnValue::Error(b"ERR increment would produce NaN or Infinity");
+    }
+    let new: Value = new.to_string().into();
+    hash.insert(field, new.clone());
+    ReturnValue::StringRes(new)
+}
+
+/// HRANDFIELD: without a count, one random field. A positive count picks up
+/// to that many distinct fields, a negative one exactly that many, maybe
+/// repeating some.
+fn rand_fields(state: &StateRef, key: Key, count: Option<Count>, with_values: bool) -> ReturnValue {
+    let hash = match read_hashes!(state, &key) {
+        Some(hash) => hash,
+        None if count.is_none() => return ReturnValue::Nil,
+        None => return ReturnValue::MultiStringRes(Vec::new()),
+    };
+    let fields: Vec<(&Key, &Value)> = hash.iter().collect();
+    let mut rng = rand::thread_rng();
+    let picked: Vec<&(&Key, &Value)> = match count {
+        None => {
+            return fields.choose(&mut rng).map_or(ReturnValue::Nil, |(f, _)| {
+                ReturnValue::StringRes((*f).clone())
+            })
+        }
+        Some(count) if count >= 0 => fields.choose_multiple(&mut rng, count as usize).collect(),
+        Some(count) => (0..count.unsigned_abs())
+            .filter_map(|_| fields.choose(&mut rng))
+            .collect(),
+    };
+    let mut ret = Vec::with_capacity(picked.len() * 2);
+    for (field, value) in picked {
+        ret.push((*field).clone());
+        if with_values {
+            ret.push((*value).clone());
+        }
+    }
+    ReturnValue::MultiStringRes(ret)
+}
+
 pub async fn hash_interact(hash_op: HashOps, state: StateRef) -> ReturnValue {
     match hash_op {
+        HashOps::HScan(key, cursor, opts) => {
+            let (next, fields) = read_hashes!(state, &key)
+                .map(|hash| {
+                    let fields = hash.iter().map(|(f, v)| (f.clone(), v.clone()));
+                    scan_members(fields, |(f, _)| &f[..], cursor, &opts)
+                })
+                .unwrap_or_default();
+            let flat = fields.into_iter().flat_map(|(f, v)| vec![f, v]).collect();
+            scan_reply(next, flat)
+        }
         HashOps::HGet(key, field) => match read_hashes!(state, &key) {
             None => ReturnValue::Nil,
             Some(hash) => hash
@@ -43,6 +153,10 @@ pub async fn hash_interact(hash_op: HashOps, state: StateRef) -> ReturnValue {
                 ReturnValue::IntRes(if v { 1 } else { 0 })
             }),
 
+        // Fields come back in the hash's (arbitrary) iteration order, each
+        // followed by its value. The whole reply is built under one lock, so
+        // it's a consistent snapshot of the hash. HKEYS and HVALS give the
+        // same guarantee individually, but their orders may differ between calls.
         HashOps::HGetAll(key) => match read_hashes!(state, &key) {
             Some(hash) => {
                 let mut ret = Vec::with_capacity(hash.len() * 2);
@@ -88,24 +202,10 @@ pub async fn hash_interact(hash_op: HashOps, state: StateRef) -> ReturnValue {
             state.hashes.entry(key).or_default().extend(key_values);
             ReturnValue::Ok
         }
-        HashOps::HIncrBy(key, field, count) => {
-            let mut hash = state.hashes.entry(key).or_default();
-            let mut curr_value = match hash.get(&field) {
-                Some(value) => {
-                    let i64_repr = std::str::from_utf8(value)
-                        .map(|e| e.parse::<i64>())
-                        .unwrap();
-                    if i64_repr.is_err() {
-                        return ReturnValue::Error(b"Bad Type!");
-                    }
-                    i64_repr.unwrap()
-                }
-                None => 0,
-            };
-            curr_value += count;
-            let new_value = Value::from(curr_value.to_string());
-            hash.insert(field, new_value);
-            ReturnValue::Ok
+        HashOps::HIncrBy(key, field, by) => incr_field_by(&state, key, field, by),
+        HashOps::HIncrByFloat(key, field, by) => incr_field_by_float(&state, key, field, by),
+        HashOps::HRandField(key, count, with_values) => {
+            rand_fields(&state, key, count, with_values)
         }
         HashOps::HLen(key) => read_hashes!(state, &key)
             .map_or(0, |hash| hash.len() as Count)
@@ -119,13 +219,17 @@ pub async fn hash_interact(hash_op: HashOps, state: StateRef) -> ReturnValue {
         //     Some(hash) => ReturnValue::IntRes(hash.len() as Count),
         //     None => ReturnValue::IntRes(0),
         // },
-        HashOps::HDel(key, fields) => match write_hashes!(state, &key) {
-            Some(mut hash) => {
-                let res = fields.iter().filter_map(|field| hash.remove(field)).count();
-                ReturnValue::IntRes(res as Count)
+        HashOps::HDel(key, fields) => {
+            let deleted = match write_hashes!(state, &key) {
+                Some(mut hash) => fields.iter().filter_map(|field| hash.remove(field)).count(),
+                None => 0,
+            };
+            state.hashes.remove_if(&key, |_, hash| hash.is_empty());
+            if deleted == 0 {
+                untouched();
             }
-            None => ReturnValue::IntRes(0),
-        },
+            ReturnValue::IntRes(deleted as Count)
+        }
         HashOps::HVals(key) => match read_hashes!(state, &key) {
             Some(hash) => {
                 ReturnValue::Array(hash.values().cloned().map(ReturnValue::StringRes).collect())
@@ -144,8 +248,88 @@ pub async fn hash_interact(hash_op: HashOps, state: StateRef) -> ReturnValue {
                 ent.insert(value);
                 ReturnValue::IntRes(1)
             } else {
+                untouched();
                 ReturnValue::IntRes(0)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test_hashes {
+    use crate::hashes::{hash_interact, HashOps};
+    use crate::types::{ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_hincrby() {
+        let eng: StateRef = Arc::new(State::default());
+        let (key, field) = (Bytes::from_static(b"key"), Bytes::from_static(b"field"));
+        let incr = |by| HashOps::HIncrBy(key.clone(), field.clone(), by);
+        assert_eq!(
+            hash_interact(incr(5), eng.clone()).await,
+            ReturnValue::IntRes(5)
+        );
+        assert_eq!(
+            hash_interact(incr(-7), eng.clone()).await,
+            ReturnValue::IntRes(-2)
+        );
+        assert!(hash_interact(incr(i64::MIN), eng.clone()).await.is_error());
+        let incr = |by| HashOps::HIncrByFloat(key.clone(), field.clone(), by);
+        assert_eq!(
+            hash_interact(incr(2.5), eng.clone()).await,
+            ReturnValue::StringRes("0.5".into())
+        );
+        // No longer an integer.
+        let op = HashOps::HIncrBy(key.clone(), field.clone(), 1);
+        assert!(hash_interact(op, eng.clone()).await.is_error());
+    }
+
+    #[tokio::test]
+    async fn test_hdel_removes_empty() {
+        let eng: StateRef = Arc::new(State::default());
+        let (key, field) = (Bytes::from_static(b"key"), Bytes::from_static(b"field"));
+        let set = HashOps::HSet(key.clone(), field.clone(), Bytes::from_static(b"v"));
+        hash_interact(set, eng.clone()).await;
+        let del = HashOps::HDel(key.clone(), smallvec![field.clone(), field]);
+        assert_eq!(
+            hash_interact(del, eng.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert!(!eng.hashes.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn test_hrandfield() {
+        let eng: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"key");
+        let rand = |count, with_values| HashOps::HRandField(key.clone(), count, with_values);
+        assert_eq!(
+            hash_interact(rand(None, false), eng.clone()).await,
+            ReturnValue::Nil
+        );
+        let fields = smallvec![
+            (Bytes::from_static(b"a"), Bytes::from_static(b"1")),
+            (Bytes::from_static(b"b"), Bytes::from_static(b"2")),
+        ];
+        hash_interact(HashOps::HMSet(key.clone(), fields), eng.clone()).await;
+        let count = |reply| match reply {
+            ReturnValue::MultiStringRes(fields) => fields.len(),
+            _ => panic!("expected fields"),
+        };
+        assert_eq!(
+            count(hash_interact(rand(Some(5), false), eng.clone()).await),
+            2
+        );
+        assert_eq!(
+            count(hash_interact(rand(Some(-5), false), eng.clone()).await),
+            5
+        );
+        assert_eq!(
+            count(hash_interact(rand(Some(1), true), eng.clone()).await),
+            2
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! Minimal HTTP/JSON API (enabled by the "http" feature).
+//!
+//! Requests are translated into redis commands and ran through
+//! `process_command`, exactly like RESP clients. Replies are JSON: strings
+//! (lossily decoded as UTF-8), integers, arrays and null. Errors reply with
+//! status 400 and `{"error": "..."}`.
+//!
+//! - `GET /keys/<key>`: GET
+//! - `PUT /keys/<key>` (value as a JSON string): SET
+//! - `DELETE /keys/<key>`: DEL
+//! - `GET /lists/<key>`, `/sets/<key>`, `/hashes/<key>`: the whole structure
+//! - `POST /command` with a JSON array of strings: any command
+//!
+//! Every path takes an optional `?db=<index>`, defaulting to 0.
+//!
+//! Browsers can reach localhost too, so requests must name localhost in
+//! their Host header (against DNS rebinding), and bodies must be sent as
+//! `application/json`, which pages can't do cross-origin without asking
+//! first. With `requirepass` set, requests also need an
+//! `Authorization: Bearer <password>` header. Admin commands are refused.
+
+use crate::connection::same_password;
+use crate::logger::LOGGER;
+use crate::monitor::Origin;
+use crate::scripting::ScriptingBridge;
+use crate::server::{command_name, has_flag, process_command};
+use crate::types::{bulk, DumpFile, Index, RedisValueRef, StateStoreRef};
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value as Json};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Largest request body we accept.
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone)]
+struct HttpContext {
+    port: u16,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+}
+
+fn lossy(b: &[u8]) -> Json {
+    Json::String(String::from_utf8_lossy(b).to_string())
+}
+
+/// Convert a reply to JSON, or the error message if it's an error.
+fn to_json(reply: RedisValueRef) -> Result<Json, String> {
+    let json = match reply {
+        RedisValueRef::BulkString(s) | RedisValueRef::SimpleString(s) => lossy(&s),
+        RedisValueRef::Error(e) => return Err(String::from_utf8_lossy(&e).to_string()),
+        RedisValueRef::ErrorMsg(e) => return Err(String::from_utf8_lossy(&e).to_string()),
+        RedisValueRef::Int(i) => json!(i),
+        RedisValueRef::Array(values) | RedisValueRef::Push(values) => {
+            Json::Array(values.into_iter().map(to_json).collect::<Result<_, _>>()?)
+        }
+        RedisValueRef::NullArray | RedisValueRef::NullBulkString => Json::Null,
+    };
+    Ok(json)
+}
+
+fn json_response(status: StatusCode, body: Json) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("static response parts are valid")
+}
+
+fn error_response(status: StatusCode, msg: &str) -> Response<Body> {
+    json_response(status, json!({ "error": msg }))
+}
+
+fn from_hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Decode %XX escapes in a path segment. Malformed escapes are kept as is.
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (from_hex(bytes[i + 1]), from_hex(bytes[i + 2])) {
+                out.push(hi << 4 | lo);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+/// Whether `host` is a Host header naming localhost, on our port if any.
+fn is_local_host(host: &str, port: u16) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, host_port)) if !host_port.ends_with(']') => {
+            if host_port.parse() != Ok(port) {
+                return false;
+            }
+            name
+        }
+        _ => host,
+    };
+    ["localhost", "127.0.0.1", "[::1]"].contains(&name.to_lowercase().as_str())
+}
+
+/// Refuse requests that could come from a web page, or that don't carry
+/// the password when one is required.
+fn check_request(
+    req: &Request<Body>,
+    port: u16,
+    requirepass: Option<String>,
+) -> Result<(), Response<Body>> {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if !header(HOST).map_or(false, |host| is_local_host(host, port)) {
+        return Err(error_response(
+            StatusCode::FORBIDDEN,
+            "the Host header must be localhost",
+        ));
+    }
+    let has_body = req.method() == Method::POST || req.method() == Method::PUT;
+    let json = header(CONTENT_TYPE).map_or(false, |ty| {
+        ty.split(';').next().map(str::trim) == Some("application/json")
+    });
+    if has_body && !json {
+        return Err(error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "the body must be sent as application/json",
+        ));
+    }
+    if let Some(password) = requirepass {
+        let token = header(AUTHORIZATION).and_then(|auth| auth.strip_prefix("Bearer "));
+        if !token.map_or(false, |token| {
+            same_password(token.as_bytes(), password.as_bytes())
+        }) {
+            return Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                "NOAUTH Authentication required.",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The `db` query parameter.
+fn db_index(req: &Request<Body>) -> Result<Index, String> {
+    let query = match req.uri().query() {
+        Some(query) => query,
+        None => return Ok(0),
+    };
+    for pair in query.split('&') {
+        if let Some(index) = pair.strip_prefix("db=") {
+            return index
+                .parse()
+                .map_err(|_| format!("invalid db index {}", index));
+        }
+    }
+    Ok(0)
+}
+
+/// Read the body, refusing ones over MAX_BODY_BYTES.
+async fn read_body(body: &mut Body) -> Result<Bytes, Response<Body>> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error_response(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        if buf.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body is too large",
+            ));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// The redis command a request maps to.
+async fn request_command(req: &mut Request<Body>) -> Result<Vec<RedisValueRef>, Response<Body>> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    let (resource, key) = (segments.next().unwrap_or(""), segments.next());
+    let key = key.map(|k| bulk(percent_decode(k)));
+    let command = |name: &'static [u8], key: RedisValueRef, rest: &[&[u8]]| {
+        let mut cmd = vec![bulk(name), key];
+        cmd.extend(rest.iter().map(|arg| bulk(Bytes::copy_from_slice(arg))));
+        cmd
+    };
+    let cmd = match (&method, resource, key) {
+        (&Method::GET, "keys", Some(key)) => command(b"get", key, &[]),
+        (&Method::PUT, "keys", Some(key)) => {
+            let body = read_body(req.body_mut()).await?;
+            let value: String = serde_json::from_slice(&body).map_err(|e| {
+                error_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("expected a JSON string: {}", e),
+                )
+            })?;
+            command(b"set", key, &[value.as_bytes()])
+        }
+        (&Method::DELETE, "keys", Some(key)) => command(b"del", key, &[]),
+        (&Method::GET, "lists", Some(key)) => command(b"lrange", key, &[&b"0"[..], &b"-1"[..]]),
+        (&Method::GET, "sets", Some(key)) => command(b"smembers", key, &[]),
+        (&Method::GET, "hashes", Some(key)) => command(b"hgetall", key, &[]),
+        (&Method::POST, "command", None) => {
+            let body = read_body(req.body_mut()).await?;
+            let args: Vec<String> = serde_json::from_slice(&body).map_err(|e| {
+                error_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("expected a JSON array of strings: {}", e),
+                )
+            })?;
+            args.into_iter().map(bulk).collect()
+        }
+        _ => return Err(error_response(StatusCode::NOT_FOUND, "no such endpoint")),
+    };
+    Ok(cmd)
+}
+
+async fn handle(
+    ctx: HttpContext,
+    peer: SocketAddr,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if let Err(response) = check_request(&req, ctx.port, ctx.state_store.config.requirepass()) {
+        return Ok(response);
+    }
+    let db = match db_index(&req) {
+        Ok(db) => db,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e)),
+    };
+    let cmd = match request_command(&mut req).await {
+        Ok(cmd) => cmd,
+        Err(response) => return Ok(response),
+    };
+    let cmd = RedisValueRef::Array(cmd);
+    if has_flag(&command_name(&cmd), "admin") {
+        return Ok(error_response(
+            StatusCode::FORBIDDEN,
+            "admin commands can't be ran over HTTP",
+        ));
+    }
+    let mut state = ctx.state_store.get_or_create(db);
+    let reply = process_command(
+        &mut state,
+        ctx.state_store.clone(),
+        ctx.dump_file.clone(),
+        ctx.scripting_bridge.clone(),
+        cmd,
+        Origin::Client(peer),
+    )
+    .await;
+    let response = match to_json(reply) {
+        Ok(json) => json_response(StatusCode::OK, json),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    Ok(response)
+}
+
+/// Serve the HTTP API on localhost.
+pub async fn serve(
+    port: u16,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+) {
+    let ctx = HttpContext {
+        port,
+        state_store,
+        dump_file,
+        scripting_bridge,
+    };
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let (ctx, peer) = (ctx.clone(), conn.remote_addr());
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(ctx.clone(), peer, req))) }
+    });
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_svc),
+        Err(e) => {
+            error!(LOGGER, "Could not start the HTTP API on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!(LOGGER, "HTTP API listening on: {}", addr);
+    if let Err(e) = server.await {
+        error!(LOGGER, "HTTP API stopped: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test_http {
+    use super::{check_request, is_local_host, percent_decode, to_json};
+    use crate::types::RedisValueRef;
+    use bytes::Bytes;
+    use hyper::{Body, Request, StatusCode};
+    use serde_json::json;
+
+    #[test]
+    fn test_is_local_host() {
+        assert!(is_local_host("localhost:8080", 8080));
+        assert!(is_local_host("127.0.0.1", 8080));
+        assert!(is_local_host("[::1]:8080", 8080));
+        assert!(is_local_host("[::1]", 8080));
+        assert!(!is_local_host("localhost:8081", 8080));
+        assert!(!is_local_host("evil.example:8080", 8080));
+    }
+
+    #[test]
+    fn test_check_request() {
+        let request = |method, content_type: Option<&str>, auth: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(method)
+                .uri("/command")
+                .header("host", "localhost:8080");
+            if let Some(content_type) = content_type {
+                builder = builder.header("content-type", content_type);
+            }
+            if let Some(auth) = auth {
+                builder = builder.header("authorization", auth);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let status = |req: Request<Body>, pass: Option<&str>| {
+            check_request(&req, 8080, pass.map(String::from))
+                .err()
+                .map(|response| response.status())
+        };
+        assert_eq!(status(request("GET", None, None), None), None);
+        let json = Some("application/json; charset=utf-8");
+        assert_eq!(status(request("POST", json, None), None), None);
+        assert_eq!(
+            status(request("POST", Some("text/plain"), None), None),
+            Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        );
+        assert_eq!(
+            status(request("PUT", None, None), None),
+            Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        );
+        assert_eq!(
+            status(request("GET", None, None), Some("secret")),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(request("GET", None, Some("Bearer nope")), Some("secret")),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(request("GET", None, Some("Bearer secret")), Some("secret")),
+            None
+        );
+        let mut rebound = request("GET", None, None);
+        rebound
+            .headers_mut()
+            .insert("host", "evil.example:8080".parse().unwrap());
+        assert_eq!(status(rebound, None), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("user%3A1"), b"user:1".to_vec());
+        assert_eq!(percent_decode("100%"), b"100%".to_vec());
+        assert_eq!(percent_decode("%zz%41"), b"%zzA".to_vec());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let reply = RedisValueRef::Array(vec![
+            RedisValueRef::BulkString(Bytes::from_static(b"a")),
+            RedisValueRef::Int(2),
+            RedisValueRef::NullBulkString,
+        ]);
+        assert_eq!(to_json(reply), Ok(json!(["a", 2, null])));
+        let err = RedisValueRef::ErrorMsg(b"ERR nope".to_vec());
+        assert_eq!(to_json(err), Err("ERR nope".to_string()));
+    }
+}
@@ -1,6 +1,11 @@
 use crate::ops::RVec;
 use crate::types::{Key, ReturnValue, StateRef, Value};
+use crate::write_effects::untouched;
 use crate::{make_reader, op_variants};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
 
 op_variants! {
     HyperLogLogOps,
@@ -9,6 +14,18 @@ op_variants! {
     PfMerge(Key, RVec<Key>)
 }
 
+impl HyperLogLogOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            HyperLogLogOps::PfAdd(key, _) | HyperLogLogOps::PfMerge(key, _) => {
+                Some(vec![key.clone()])
+            }
+            HyperLogLogOps::PfCount(_) => None,
+        }
+    }
+}
+
 make_reader!(hyperloglogs, read_hyperloglogs);
 
 // Error ratio from http://antirez.com/news/75
@@ -18,17 +35,60 @@ fn default_hyperloglog() -> amadeus_streaming::HyperLogLog<Value> {
     amadeus_streaming::HyperLogLog::new(HYPERLOGLOG_ERROR_RATIO)
 }
 
+/// Feeds the bytes written to it to a hasher, without keeping them.
+struct HashWriter(DefaultHasher);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A hash of the encoded state of `pf`, to tell whether a push changed it.
+/// It's hashed as it's encoded, so the registers are never copied.
+fn registers_hash(pf: &amadeus_streaming::HyperLogLog<Value>) -> u64 {
+    let mut hasher = HashWriter(DefaultHasher::new());
+    rmps::encode::write(&mut hasher, pf).expect("hyperloglogs always encode");
+    hasher.0.finish()
+}
+
 pub async fn hyperloglog_interact(hyperloglog_op: HyperLogLogOps, state: StateRef) -> ReturnValue {
     match hyperloglog_op {
         HyperLogLogOps::PfAdd(key, values) => {
+            let created = !state.hyperloglogs.contains_key(&key);
             let mut pf_ref = state
                 .hyperloglogs
                 .entry(key)
                 .or_insert_with(default_hyperloglog);
-            let curr_card = pf_ref.len() as i64;
-            values.into_iter().for_each(|e| pf_ref.push(&e));
-            let new_card = pf_ref.len() as i64;
-            ReturnValue::IntRes((new_card != curr_card).into())
+            let changed = if state.pfadd_cardinality_reply.load(Ordering::Relaxed) {
+                let curr_card = pf_ref.len();
+                values.iter().for_each(|e| pf_ref.push(e));
+                pf_ref.len() != curr_card
+            } else if created || values.is_empty() {
+                values.iter().for_each(|e| pf_ref.push(e));
+                if !created {
+                    untouched();
+                }
+                created
+            } else {
+                // The registers are only reachable through serde, so they're
+                // encoded (and hashed) before and after: two passes over
+                // 16KiB. Only the registers (and totals derived from them)
+                // change on push.
+                let before = registers_hash(&pf_ref);
+                values.iter().for_each(|e| pf_ref.push(e));
+                let changed = registers_hash(&pf_ref) != before;
+                if !changed {
+                    untouched();
+                }
+                changed
+            };
+            ReturnValue::IntRes(changed.into())
         }
         HyperLogLogOps::PfCount(keys) => {
             // If there's only key, read that. redis appears to return zero if it doesn't exist.
@@ -61,3 +121,79 @@ pub async fn hyperloglog_interact(hyperloglog_op: HyperLogLogOps, state: StateRe
         }
     }
 }
+
+#[cfg(test)]
+mod test_hyperloglog {
+    use super::{default_hyperloglog, hyperloglog_interact, registers_hash, HyperLogLogOps};
+    use crate::types::{ReturnValue, State, Value};
+    use bytes::Bytes;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    fn registers(pf: &amadeus_streaming::HyperLogLog<Value>) -> Vec<u8> {
+        rmps::to_vec(pf).unwrap()
+    }
+
+    async fn pfadd(state: &Arc<State>, values: &[Value]) -> ReturnValue {
+        let op = HyperLogLogOps::PfAdd(Bytes::from_static(b"pf"), values.iter().cloned().collect());
+        hyperloglog_interact(op, state.clone()).await
+    }
+
+    /// Replies of adding a duplicate heavy stream one element at a time.
+    async fn replies(cardinality_reply: bool) -> Vec<ReturnValue> {
+        let state = Arc::new(State::default());
+        state
+            .pfadd_cardinality_reply
+            .store(cardinality_reply, Ordering::Relaxed);
+        let mut replies = Vec::new();
+        for i in 0..5000 {
+            let value: Value = (i % 500).to_string().into();
+            replies.push(pfadd(&state, &[value]).await);
+        }
+        replies
+    }
+
+    #[tokio::test]
+    async fn test_pfadd_replies() {
+        let ones = |replies: &[ReturnValue]| {
+            replies
+                .iter()
+                .filter(|r| **r == ReturnValue::IntRes(1))
+                .count()
+        };
+        let by_registers = replies(false).await;
+        let by_cardinality = replies(true).await;
+        // Re-adding an element never changes a register.
+        assert_eq!(ones(&by_registers[500..]), 0);
+        // A register can grow without moving the estimate, never the reverse.
+        assert!(ones(&by_registers) >= ones(&by_cardinality));
+        assert!(ones(&by_registers) > 0);
+
+        // Like redis, creating the key counts as a change.
+        let state = Arc::new(State::default());
+        assert_eq!(pfadd(&state, &[]).await, ReturnValue::IntRes(1));
+        assert_eq!(pfadd(&state, &[]).await, ReturnValue::IntRes(0));
+        let a = Bytes::from_static(b"a");
+        assert_eq!(pfadd(&state, &[a.clone()]).await, ReturnValue::IntRes(1));
+        assert_eq!(pfadd(&state, &[a.clone(), a]).await, ReturnValue::IntRes(0));
+    }
+
+    #[test]
+    fn test_encoding_is_stable() {
+        let mut pf = default_hyperloglog();
+        for i in 0..1000 {
+            pf.push(&Value::from(i.to_string()));
+        }
+        let encoded = registers(&pf);
+        let mut decoded: amadeus_streaming::HyperLogLog<Value> =
+            rmps::from_read_ref(&encoded).unwrap();
+        assert_eq!(registers(&decoded), encoded);
+        assert_eq!(decoded.len(), pf.len());
+        // A decoded hyperloglog keeps hashing elements into the same registers.
+        let more: Vec<Value> = (1000..1100).map(|i| i.to_string().into()).collect();
+        more.iter().for_each(|e| pf.push(e));
+        more.iter().for_each(|e| decoded.push(e));
+        assert_eq!(registers(&decoded), registers(&pf));
+        assert_eq!(registers_hash(&decoded), registers_hash(&pf));
+    }
+}
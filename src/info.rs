@@ -0,0 +1,291 @@
+//! INFO: server metrics, grouped in sections like redis'.
+//!
+//! `INFO` alone (or `INFO all`) lists every section, `INFO stats keyspace`
+//! only the ones named. Unknown sections are skipped.
+
+use crate::eviction::used_memory;
+use crate::replication::Role;
+use crate::types::{ReturnValue, StateStore, Value};
+use std::sync::atomic::Ordering;
+
+/// Every section, in the order they're listed.
+const SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "keyspace",
+];
+
+/// Resident memory of this process, in bytes, when the platform tells us.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn server(store: &StateStore, lines: &mut Vec<String>) {
+    let uptime = store.stats.uptime().as_secs();
+    lines.push(concat!("redis_version", ":", env!("CARGO_PKG_VERSION")).into());
+    lines.push("redis_mode:standalone".into());
+    lines.push("arch_bits:64".into());
+    lines.push(format!("process_id:{}", std::process::id()));
+    lines.push(format!("uptime_in_seconds:{}", uptime));
+    lines.push(format!("uptime_in_days:{}", uptime / (24 * 60 * 60)));
+}
+
+fn clients(store: &StateStore, lines: &mut Vec<String>) {
+    lines.push(format!(
+        "connected_clients:{}",
+        store.stats.connected_clients()
+    ));
+}
+
+fn memory(store: &StateStore, lines: &mut Vec<String>) {
+    if let Some(rss) = resident_memory() {
+        lines.push(format!("used_memory_rss:{}", rss));
+    }
+    // Only estimated while there's a limit, see `MemoryTracker`.
+    let maxmemory = store.config.maxmemory();
+    if maxmemory != 0 {
+        lines.push(format!("used_memory:{}", used_memory(store)));
+    }
+    lines.push(format!("maxmemory:{}", maxmemory));
+    let policy = store.config.maxmemory_policy();
+    lines.push(format!("maxmemory_policy:{}", policy.name()));
+    lines.push(format!("script_cache_size:{}", store.script_cache.len()));
+}
+
+fn persistence(store: &StateStore, lines: &mut Vec<String>) {
+    lines.push("loading:0".into());
+    lines.push(format!(
+        "rdb_changes_since_last_save:{}",
+        store.commands_ran_since_save.load(Ordering::SeqCst)
+    ));
+    lines.push(format!("rdb_last_save_time:{}", store.stats.last_save()));
+    let progress = store.stats.save_progress();
+    lines.push(format!(
+        "rdb_bgsave_in_progress:{}",
+        progress.is_some() as u8
+    ));
+    // How far the running save got, -1 (and 0s) while there's none.
+    lines.push(format!(
+        "rdb_current_bgsave_time_sec:{}",
+        progress.map_or(-1, |p| p.secs as i64)
+    ));
+    let progress = progress.unwrap_or_default();
+    lines.push(format!("rdb_current_save_bytes:{}", progress.bytes));
+    lines.push(format!("rdb_current_save_dbs_done:{}", progress.dbs_done));
+    lines.push(format!("rdb_current_save_dbs:{}", progress.dbs));
+    let status = if store.stats.last_save_ok() {
+        "ok"
+    } else {
+        "err"
+    };
+    lines.push(format!("rdb_last_bgsave_status:{}", status));
+    lines.push(format!(
+        "rdb_last_bgsave_time_sec:{}",
+        store.stats.last_save_secs()
+    ));
+    lines.push(format!(
+        "rdb_last_save_bytes:{}",
+        store.stats.last_save_bytes()
+    ));
+    lines.push(format!("memory_only:{}", store.memory_only as u8));
+    let aof = store.aof.get();
+    lines.push(format!("aof_enabled:{}", aof.is_some() as u8));
+    lines.push(format!(
+        "aof_rewrite_in_progress:{}",
+        aof.map_or(false, |aof| aof.rewriting()) as u8
+    ));
+}
+
+fn stats(store: &StateStore, lines: &mut Vec<String>) {
+    let stats = &store.stats;
+    lines.push(format!(
+        "total_connections_received:{}",
+        stats.total_connections()
+    ));
+    lines.push(format!(
+        "total_commands_processed:{}",
+        stats.total_commands()
+    ));
+    lines.push(format!("instantaneous_ops_per_sec:{}", stats.ops_per_sec()));
+    lines.push(format!("script_cache_hits:{}", store.script_cache.hits()));
+    lines.push(format!(
+        "script_cache_misses:{}",
+        store.script_cache.misses()
+    ));
+    let (hits, misses) = store.states.iter().fold((0, 0), |(h, m), db| {
+        (h + db.negative_cache.hits(), m + db.negative_cache.misses())
+    });
+    lines.push(format!("negative_cache_hits:{}", hits));
+    lines.push(format!("negative_cache_misses:{}", misses));
+    lines.push(format!("evicted_keys:{}", stats.evicted_keys()));
+    lines.push(format!("cdc_dropped_events:{}", stats.cdc_dropped_events()));
+    lines.push(format!("flushes:{}", stats.flushes()));
+    for (structure, count) in stats.flushed_keys() {
+        lines.push(format!("flushed_keys_{}:{}", structure, count));
+    }
+    lines.push(format!("big_replies:{}", stats.big_replies()));
+    for (command, hist) in stats.reply_sizes() {
+        let buckets: Vec<String> = hist
+            .buckets()
+            .map(|(bound, count)| match bound {
+                usize::MAX => format!("lt_inf={}", count),
+                bound => format!("lt_{}={}", bound, count),
+            })
+            .collect();
+        lines.push(format!(
+            "replysize_{}:calls={},bytes={},max={},{}",
+            command,
+            hist.calls(),
+            hist.total_bytes(),
+            hist.max_bytes(),
+            buckets.join(",")
+        ));
+    }
+}
+
+/// Our role, the primary we follow or the replicas we feed, and the backlog.
+fn replication(store: &StateStore, lines: &mut Vec<String>) {
+    let replication = &store.replication;
+    match replication.role() {
+        Role::Primary => lines.push("role:master".to_string()),
+        Role::Replica(link) => {
+            lines.push("role:slave".to_string());
+            lines.push(format!("master_host:{}", link.host));
+            lines.push(format!("master_port:{}", link.port));
+            let status = if link.up { "up" } else { "down" };
+            lines.push(format!("master_link_status:{}", status));
+            let last_io = link.last_io.map_or(-1, |at| at.elapsed().as_secs() as i64);
+            lines.push(format!("master_last_io_seconds_ago:{}", last_io));
+            lines.push(format!("slave_repl_offset:{}", link.offset));
+            lines.push("slave_read_only:1".to_string());
+        }
+    }
+    let replicas = replication.replicas();
+    lines.push(format!("connected_slaves:{}", replicas.len()));
+    for (i, replica) in replicas.iter().enumerate() {
+        lines.push(format!(
+            "slave{}:ip={},port={},state=online,offset={},lag={}",
+            i,
+            replica.addr.ip(),
+            replica.addr.port(),
+            replica.acked,
+            replica.last_ack.elapsed().as_secs()
+        ));
+    }
+    let (size, first_offset, histlen) = replication.backlog_info();
+    lines.push(format!("master_replid:{}", replication.replid()));
+    lines.push(format!("master_repl_offset:{}", replication.offset()));
+    lines.push(format!("repl_backlog_active:{}", (histlen > 0) as u8));
+    lines.push(format!("repl_backlog_size:{}", size));
+    lines.push(format!("repl_backlog_first_byte_offset:{}", first_offset));
+    lines.push(format!("repl_backlog_histlen:{}", histlen));
+}
+
+/// One line per non-empty db, like `db0:keys=3,expires=1`.
+fn keyspace(store: &StateStore, lines: &mut Vec<String>) {
+    let mut dbs: Vec<_> = store
+        .states
+        .iter()
+        .map(|db| (*db.key(), db.key_count(), db.expirations.len()))
+        .filter(|(_, keys, _)| *keys != 0)
+        .collect();
+    dbs.sort_unstable();
+    for (index, keys, expires) in dbs {
+        lines.push(format!("db{}:keys={},expires={}", index, keys, expires));
+    }
+}
+
+/// Whether `section` was asked for.
+fn wanted(requested: &[Value], section: &str) -> bool {
+    requested.is_empty()
+        || requested.iter().any(|r| {
+            let r = String::from_utf8_lossy(r).to_lowercase();
+            r == section || r == "all" || r == "everything" || r == "default"
+        })
+}
+
+/// The INFO reply for the `requested` sections, or every one if empty.
+pub fn info_reply(store: &StateStore, requested: &[Value]) -> ReturnValue {
+    let mut lines: Vec<String> = Vec::new();
+    for &section in SECTIONS.iter().filter(|s| wanted(requested, s)) {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        let mut title = section.to_string();
+        title[..1].make_ascii_uppercase();
+        lines.push(format!("# {}", title));
+        match section {
+            "server" => server(store, &mut lines),
+            "clients" => clients(store, &mut lines),
+            "memory" => memory(store, &mut lines),
+            "persistence" => persistence(store, &mut lines),
+            "stats" => stats(store, &mut lines),
+            "replication" => replication(store, &mut lines),
+            "keyspace" => keyspace(store, &mut lines),
+            _ => unreachable!("unlisted section {}", section),
+        }
+    }
+    ReturnValue::StringRes(lines.join("\r\n").into())
+}
+
+#[cfg(test)]
+mod test_info {
+    use super::info_reply;
+    use crate::types::{ReturnValue, StateStore};
+    use bytes::Bytes;
+
+    fn info(store: &StateStore, sections: &[&'static str]) -> String {
+        let sections: Vec<Bytes> = sections
+            .iter()
+            .map(|s| Bytes::from_static(s.as_bytes()))
+            .collect();
+        match info_reply(store, &sections) {
+            ReturnValue::StringRes(s) => String::from_utf8(s.to_vec()).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sections() {
+        let store = StateStore::default();
+        let everything = info(&store, &[]);
+        for title in &[
+            "# Server",
+            "# Clients",
+            "# Persistence",
+            "# Replication",
+            "# Keyspace",
+        ] {
+            assert!(everything.contains(title));
+        }
+        let some = info(&store, &["CLIENTS", "keyspace"]);
+        assert!(some.starts_with("# Clients\r\nconnected_clients:0"));
+        assert!(!some.contains("# Server"));
+        assert_eq!(info(&store, &["nope"]), "");
+        assert!(info(&store, &["replication"])
+            .starts_with("# Replication\r\nrole:master\r\nconnected_slaves:0"));
+    }
+
+    #[test]
+    fn test_keyspace() {
+        let store = StateStore::default();
+        let db = store.get_or_create(3);
+        let key = Bytes::from_static(b"key");
+        db.kv.insert(key.clone(), key.clone());
+        db.sets
+            .insert(Bytes::from_static(b"set"), Default::default());
+        db.set_expiry(key, u64::MAX);
+        store.get_or_create(1);
+        assert_eq!(
+            info(&store, &["keyspace"]),
+            "# Keyspace\r\ndb3:keys=2,expires=1"
+        );
+    }
+}
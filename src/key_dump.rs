@@ -0,0 +1,493 @@
+//! Serialized single keys, the payload of DUMP/RESTORE style transfers.
+//!
+//! A payload is an rmp encoded `KeyDump`, then a big-endian u16 format
+//! version and the first 8 bytes of the SHA1 of everything before them (like
+//! redis' RDB version and CRC64 trailer). Payloads from a newer version, or
+//! that were corrupted in transit, are refused rather than half restored.
+//!
+//! Like redis', payloads don't carry the key's TTL; RESTORE takes it separately.
+//!
+//! Values from older versions go through the dump file `MIGRATIONS`.
+
+use crate::dump_format::{current_schema, decode_migrated};
+use crate::for_each_structure;
+use crate::hashing::sha1;
+use crate::types::{Key, State};
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+
+/// Bump when the payload layout changes. Version 2 has float sorted set
+/// scores, version 3 bloom filters with their settings, and version 4
+/// consumer groups with their consumers.
+pub const DUMP_VERSION: u16 = 4;
+/// Version and checksum bytes at the end of every payload.
+const TRAILER_LEN: usize = 2 + 8;
+
+/// (last version, structure, schema): how structures were stored up to a
+/// version, for those whose schema changed since.
+const OLD_SCHEMAS: &[(u16, &str, &str)] = &[
+    (1, "zsets", "DashMap<Bytes, SortedSet> v1"),
+    (2, "blooms", "DashMap<Bytes, GrowableBloom> v1"),
+    (3, "streams", "DashMap<Bytes, Stream> consumer groups v2"),
+];
+
+/// How `structure` was stored by payloads of `version`.
+fn schema_at(version: u16, structure: &str) -> Option<&'static str> {
+    OLD_SCHEMAS
+        .iter()
+        .find(|(last, name, _)| *name == structure && version <= *last)
+        .map(|(_, _, schema)| *schema)
+        .or_else(|| current_schema(structure))
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyDump {
+    /// Which structure of `State` the value belongs in.
+    structure: String,
+    /// The rmp encoded value.
+    value: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RestoreError {
+    /// Bad version or checksum, or a value that doesn't decode.
+    BadPayload,
+    /// The key exists and REPLACE wasn't given.
+    BusyKey,
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::BadPayload => write!(f, "DUMP payload version or checksum are wrong"),
+            RestoreError::BusyKey => write!(f, "BUSYKEY Target key name already exists."),
+        }
+    }
+}
+
+impl Error for RestoreError {}
+
+impl From<rmps::decode::Error> for RestoreError {
+    fn from(_: rmps::decode::Error) -> Self {
+        RestoreError::BadPayload
+    }
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 8] {
+    sha1(bytes)[..8].try_into().unwrap()
+}
+
+/// Encode the value of `key`, whichever structure holds it.
+macro_rules! dump_from {
+    ($state:expr, $key:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            if let Some(value) = $state.$type.get($key) {
+                return Some(KeyDump {
+                    structure: stringify!($type).into(),
+                    value: rmps::to_vec(value.value()).expect("values always encode"),
+                });
+            }
+        )*
+    };
+}
+
+/// Move `key` from whichever structure of `from` holds it into `into`.
+macro_rules! move_value {
+    ($from:expr, $into:expr, $key:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            if let Some((key, value)) = $from.$type.remove(&$key) {
+                $into.$type.insert(key, value);
+                return;
+            }
+        )*
+    };
+}
+
+fn find_dump(state: &State, key: &Key) -> Option<KeyDump> {
+    for_each_structure!(dump_from!(state, key,));
+    None
+}
+
+/// Serialize `key`, or None if it doesn't exist.
+pub fn dump_key(state: &State, key: &Key) -> Option<Vec<u8>> {
+    let dump = find_dump(state, key)?;
+    let mut payload = rmps::to_vec(&dump).expect("dumps always encode");
+    payload.extend_from_slice(&DUMP_VERSION.to_be_bytes());
+    let sum = checksum(&payload);
+    payload.extend_from_slice(&sum);
+    Some(payload)
+}
+
+/// Check the trailer of `payload`, returning the encoded `KeyDump` and its version.
+fn verify(payload: &[u8]) -> Result<(&[u8], u16), RestoreError> {
+    if payload.len() < TRAILER_LEN {
+        return Err(RestoreError::BadPayload);
+    }
+    let (signed, sum) = payload.split_at(payload.len() - 8);
+    let (body, version) = signed.split_at(signed.len() - 2);
+    let version = u16::from_be_bytes(version.try_into().unwrap());
+    if version > DUMP_VERSION || checksum(signed) != sum {
+        return Err(RestoreError::BadPayload);
+    }
+    Ok((body, version))
+}
+
+/// Decode a payload into a scratch state holding only `key`, migrating
+/// values from older versions.
+fn decode(key: &Key, payload: &[u8]) -> Result<State, RestoreError> {
+    let (body, version) = verify(payload)?;
+    let dump: KeyDump = rmps::from_read_ref(body)?;
+    let schema = schema_at(version, &dump.structure).ok_or(RestoreError::BadPayload)?;
+    // Structures are migrated as maps of keys to values: make one of this key.
+    let mut map = vec![0x81];
+    map.extend(rmps::to_vec(key).expect("keys always encode"));
+    map.extend_from_slice(&dump.value);
+    let mut scratch = State::default();
+    decode_migrated(&mut scratch, &dump.structure, schema, &map)
+        .map_err(|_| RestoreError::BadPayload)?;
+    Ok(scratch)
+}
+
+fn move_into(from: &State, into: &State, key: Key) {
+    for_each_structure!(move_value!(from, into, key,));
+}
+
+/// Recreate a dumped value under `key`. Nothing changes if this fails.
+pub fn restore_key(
+    state: &State,
+    key: Key,
+    payload: &[u8],
+    replace: bool,
+) -> Result<(), RestoreError> {
+    if state.structure_of(&key).is_some() && !replace {
+        return Err(RestoreError::BusyKey);
+    }
+    // Decode before touching the key, so a bad payload doesn't delete it.
+    let scratch = decode(&key, payload)?;
+    if state.structure_of(&key).is_some() {
+        state.remove_key(&key);
+    }
+    move_into(&scratch, state, key);
+    Ok(())
+}
+
+/// COPY `key` to `dest_key` in `dest`, TTL included. Returns false if `key`
+/// is missing, or `dest_key` exists and `replace` wasn't given.
+pub fn copy_key(state: &State, key: &Key, dest: &State, dest_key: Key, replace: bool) -> bool {
+    let payload = match dump_key(state, key) {
+        Some(payload) => payload,
+        None => return false,
+    };
+    match restore_key(dest, dest_key.clone(), &payload, replace) {
+        Ok(()) => {
+            if let Some(deadline) = state.expiry(key) {
+                dest.set_expiry(dest_key, deadline);
+            }
+            true
+        }
+        Err(RestoreError::BusyKey) => false,
+        Err(RestoreError::BadPayload) => unreachable!("fresh payloads restore"),
+    }
+}
+
+#[cfg(test)]
+mod test_key_dump {
+    use super::{copy_key, dump_key, restore_key, RestoreError};
+    use crate::data_structures::bloom_filter::BloomFilter;
+    use crate::data_structures::count_min_sketch::CountMinSketch;
+    use crate::data_structures::cuckoo_filter::CuckooFilter;
+    use crate::data_structures::heavy_keeper::TopK;
+    use crate::data_structures::queue::Queue;
+    use crate::data_structures::sorted_set::SortedSet;
+    use crate::data_structures::stack::Stack;
+    use crate::data_structures::stream::{NewId, Stream};
+    use crate::memory::key_usage;
+    use crate::types::State;
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    fn key(name: &'static str) -> Bytes {
+        Bytes::from_static(name.as_bytes())
+    }
+
+    fn full_state() -> State {
+        let state = State::default();
+        let v = key("v");
+        state.kv.insert(key("kv"), v.clone());
+        state.sets.insert(
+            key("sets"),
+            vec![v.clone()].into_iter().collect::<HashSet<_>>(),
+        );
+        state.lists.insert(
+            key("lists"),
+            vec![v.clone()].into_iter().collect::<VecDeque<_>>(),
+        );
+        let mut hash = HashMap::new();
+        hash.insert(v.clone(), v.clone());
+        state.hashes.insert(key("hashes"), hash);
+        let mut zset = SortedSet::new();
+        zset.add(smallvec![(3.into(), v.clone())]);
+        state.zsets.insert(key("zsets"), zset);
+        let mut bloom = BloomFilter::new(0.05, 10);
+        bloom.insert(&v);
+        state.blooms.insert(key("blooms"), bloom);
+        let mut cuckoo = CuckooFilter::new(16);
+        cuckoo.insert(&v).unwrap();
+        state.cuckoos.insert(key("cuckoos"), cuckoo);
+        let mut cms = CountMinSketch::new(20, 3);
+        cms.increment(&v, 2);
+        state.count_mins.insert(key("count_mins"), cms);
+        let mut topk = TopK::new(2, 8, 7, 0.9);
+        topk.add(&v);
+        state.topks.insert(key("topks"), topk);
+        let mut queue = Queue::default();
+        queue.push(v.clone());
+        queue.push(v.clone());
+        queue.pop(0, 1000);
+        state.queues.insert(key("queues"), queue);
+        let mut stack = Stack::default();
+        stack.push(v.clone());
+        state.stacks.insert(key("stacks"), stack);
+        let mut hll = amadeus_streaming::HyperLogLog::new(0.01);
+        hll.push(&v);
+        state.hyperloglogs.insert(key("hyperloglogs"), hll);
+        let mut stream = Stream::new();
+        stream
+            .add(NewId::Auto, vec![(v.clone(), v.clone())], 1)
+            .unwrap();
+        state.streams.insert(key("streams"), stream);
+        state
+    }
+
+    #[test]
+    fn test_round_trip_every_structure() {
+        let source = full_state();
+        let target = State::default();
+        let names = [
+            "kv",
+            "sets",
+            "lists",
+            "hashes",
+            "zsets",
+            "blooms",
+            "stacks",
+            "hyperloglogs",
+            "streams",
+            "cuckoos",
+            "count_mins",
+            "topks",
+            "queues",
+        ];
+        for name in names.iter() {
+            let payload = dump_key(&source, &key(name)).unwrap();
+            let copy = Bytes::from(format!("copy-{}", name));
+            restore_key(&target, copy.clone(), &payload, false).unwrap();
+            assert_eq!(target.structure_of(&copy), Some(*name));
+            let copied = key_usage(&target, &copy).map(|usage| usage - copy.len());
+            let original = key_usage(&source, &key(name)).map(|usage| usage - name.len());
+            // Restored at its size, without any spare room the original grew.
+            assert!(copied.is_some() && copied <= original, "{}", name);
+        }
+        let v = key("v");
+        assert!(target.blooms.get(&key("copy-blooms")).unwrap().contains(&v));
+        assert!(target
+            .cuckoos
+            .get(&key("copy-cuckoos"))
+            .unwrap()
+            .contains(&v));
+        assert_eq!(
+            target.zsets.get(&key("copy-zsets")).unwrap().score(v),
+            Some(3.into())
+        );
+        assert_eq!(dump_key(&source, &key("missing")), None);
+    }
+
+    #[test]
+    fn test_busy_key_and_replace() {
+        let state = full_state();
+        let payload = dump_key(&state, &key("kv")).unwrap();
+        assert_eq!(
+            restore_key(&state, key("lists"), &payload, false),
+            Err(RestoreError::BusyKey)
+        );
+        restore_key(&state, key("lists"), &payload, true).unwrap();
+        assert_eq!(state.structure_of(&key("lists")), Some("kv"));
+        assert!(!state.lists.contains_key(&key("lists")));
+    }
+
+    #[test]
+    fn test_corrupt_payloads() {
+        let state = full_state();
+        let payload = dump_key(&state, &key("hashes")).unwrap();
+        let mut flipped = payload.clone();
+        flipped[0] ^= 1;
+        assert_eq!(
+            restore_key(&state, key("new"), &flipped, false),
+            Err(RestoreError::BadPayload)
+        );
+        assert_eq!(
+            restore_key(&state, key("new"), &payload[..5], false),
+            Err(RestoreError::BadPayload)
+        );
+        // A newer version, correctly signed.
+        let mut newer = payload[..payload.len() - 10].to_vec();
+        newer.extend_from_slice(&(super::DUMP_VERSION + 1).to_be_bytes());
+        let sum = super::checksum(&newer);
+        newer.extend_from_slice(&sum);
+        assert_eq!(
+            restore_key(&state, key("new"), &newer, false),
+            Err(RestoreError::BadPayload)
+        );
+        assert_eq!(state.structure_of(&key("new")), None);
+    }
+
+    #[test]
+    fn test_bad_value_keeps_the_key() {
+        let state = full_state();
+        let dump = super::KeyDump {
+            structure: "hashes".into(),
+            value: b"not a hash".to_vec(),
+        };
+        let mut payload = rmps::to_vec(&dump).unwrap();
+        payload.extend_from_slice(&super::DUMP_VERSION.to_be_bytes());
+        let sum = super::checksum(&payload);
+        payload.extend_from_slice(&sum);
+        assert_eq!(
+            restore_key(&state, key("kv"), &payload, true),
+            Err(RestoreError::BadPayload)
+        );
+        assert_eq!(state.structure_of(&key("kv")), Some("kv"));
+    }
+
+    /// Sign a payload holding `value` as `structure`.
+    fn forged<T: serde::Serialize>(structure: &str, value: &T) -> Vec<u8> {
+        let dump = super::KeyDump {
+            structure: structure.into(),
+            value: rmps::to_vec(value).unwrap(),
+        };
+        let mut payload = rmps::to_vec(&dump).unwrap();
+        payload.extend_from_slice(&super::DUMP_VERSION.to_be_bytes());
+        let sum = super::checksum(&payload);
+        payload.extend_from_slice(&sum);
+        payload
+    }
+
+    #[derive(Serialize)]
+    struct Sketch {
+        width: usize,
+        depth: usize,
+        counters: Vec<u64>,
+        count: u64,
+    }
+
+    #[derive(Serialize)]
+    struct Table {
+        slots: Vec<u8>,
+    }
+
+    #[derive(Serialize)]
+    struct Filter {
+        tables: Vec<Table>,
+        capacity: usize,
+        items: usize,
+    }
+
+    #[test]
+    fn test_broken_structures() {
+        let state = State::default();
+        let sketch = |width, depth, counters| Sketch {
+            width,
+            depth,
+            counters,
+            count: 0,
+        };
+        for broken in [sketch(0, 1, vec![]), sketch(4, 2, vec![0; 3])].iter() {
+            assert_eq!(
+                restore_key(&state, key("k"), &forged("count_mins", broken), false),
+                Err(RestoreError::BadPayload)
+            );
+        }
+        let filter = |slots: &[Vec<u8>], items| Filter {
+            tables: slots.iter().map(|s| Table { slots: s.clone() }).collect(),
+            capacity: 4,
+            items,
+        };
+        let broken = [
+            filter(&[], 0),
+            filter(&[vec![]], 0),
+            filter(&[vec![0; 12]], 0),
+            filter(&[vec![1; 4]], 0),
+        ];
+        for broken in broken.iter() {
+            assert_eq!(
+                restore_key(&state, key("k"), &forged("cuckoos", broken), false),
+                Err(RestoreError::BadPayload)
+            );
+        }
+        assert_eq!(state.structure_of(&key("k")), None);
+        // The same shapes restore when they hold together.
+        restore_key(
+            &state,
+            key("cms"),
+            &forged("count_mins", &sketch(2, 1, vec![0; 2])),
+            false,
+        )
+        .unwrap();
+        restore_key(
+            &state,
+            key("cf"),
+            &forged("cuckoos", &filter(&[vec![1; 4]], 4)),
+            false,
+        )
+        .unwrap();
+    }
+
+    /// A sorted set as version 1 payloads stored it, with integer scores.
+    #[derive(Serialize)]
+    struct IntegerScoreSortedSet {
+        members_hash: HashMap<Bytes, i64>,
+        scores: Vec<u8>,
+    }
+
+    #[test]
+    fn test_old_versions_are_migrated() {
+        let mut members_hash = HashMap::new();
+        members_hash.insert(key("m"), 7);
+        let old = IntegerScoreSortedSet {
+            members_hash,
+            scores: Vec::new(),
+        };
+        let dump = super::KeyDump {
+            structure: "zsets".into(),
+            value: rmps::to_vec(&old).unwrap(),
+        };
+        let mut payload = rmps::to_vec(&dump).unwrap();
+        payload.extend_from_slice(&1u16.to_be_bytes());
+        let sum = super::checksum(&payload);
+        payload.extend_from_slice(&sum);
+        let state = State::default();
+        restore_key(&state, key("z"), &payload, false).unwrap();
+        assert_eq!(
+            state.zsets.get(&key("z")).unwrap().score(key("m")),
+            Some(7.into())
+        );
+    }
+
+    #[test]
+    fn test_copy() {
+        let state = full_state();
+        let other = State::default();
+        state.set_expiry(key("stacks"), u64::MAX);
+        assert!(copy_key(&state, &key("stacks"), &other, key("copy"), false));
+        assert_eq!(other.structure_of(&key("copy")), Some("stacks"));
+        assert_eq!(other.expiry(&key("copy")), Some(u64::MAX));
+        assert!(state.stacks.contains_key(&key("stacks")));
+
+        assert!(!copy_key(&state, &key("missing"), &state, key("kv"), true));
+        assert!(!copy_key(&state, &key("sets"), &state, key("kv"), false));
+        assert!(copy_key(&state, &key("sets"), &state, key("kv"), true));
+        assert_eq!(state.structure_of(&key("kv")), Some("sets"));
+    }
+}
@@ -1,23 +1,501 @@
+use crate::bitfield::{bitfield, FieldOp};
+use crate::expiry::now_ms;
+use crate::key_dump::{dump_key, restore_key, RestoreError};
 use crate::op_variants;
 use crate::ops::RVec;
-use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use crate::types::{Count, Key, RedisValueRef, ReturnValue, State, StateRef, Value};
+use crate::write_effects::{touched, untouched};
+use bytes::BytesMut;
+use dashmap::mapref::entry::Entry;
+
+/// When SET writes its value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    Always,
+    /// NX
+    IfMissing,
+    /// XX
+    IfExists,
+}
+
+impl Default for SetCondition {
+    fn default() -> Self {
+        SetCondition::Always
+    }
+}
+
+/// The options of SET. Plain SET drops any TTL the key had.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SetOptions {
+    /// EX/PX, in milliseconds from when the op runs.
+    pub ttl_ms: Option<u64>,
+    /// KEEPTTL
+    pub keep_ttl: bool,
+    pub condition: SetCondition,
+}
+
+/// What GETEX does to the key's TTL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TtlUpdate {
+    /// No option: leave it as it is.
+    Keep,
+    /// PERSIST
+    Persist,
+    /// EX/PX, in milliseconds from when the op runs.
+    In(u64),
+    /// EXAT/PXAT, as a unix time in milliseconds.
+    At(u64),
+}
+
+/// The operator of BITOP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// The `start end [BYTE|BIT]` range of BITCOUNT and BITPOS.
+/// Negative offsets count back from the end, like redis.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BitRange {
+    pub start: Count,
+    /// None runs to the end of the string.
+    pub end: Option<Count>,
+    /// BIT: the offsets are bits rather than bytes.
+    pub bits: bool,
+}
+
+/// The options of RESTORE.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RestoreOptions {
+    /// Milliseconds to live, or with `absolute` the unix deadline. 0 for none.
+    pub ttl_ms: u64,
+    /// ABSTTL
+    pub absolute: bool,
+    /// REPLACE
+    pub replace: bool,
+}
 
 op_variants! {
     KeyOps,
-    Set(Key, Value),
+    Set(Key, Value, SetOptions),
+    SetNx(Key, Value),
+    GetSet(Key, Value),
+    GetDel(Key),
+    GetEx(Key, TtlUpdate),
     MSet(RVec<(Key, Value)>),
     Get(Key),
     MGet(RVec<Key>),
     Del(RVec<Key>),
     Rename(Key, Key),
-    RenameNx(Key, Key)
+    RenameNx(Key, Key),
+    Incr(Key),
+    Decr(Key),
+    IncrBy(Key, Count),
+    DecrBy(Key, Count),
+    IncrByFloat(Key, f64),
+    Dump(Key),
+    Restore(Key, Value, RestoreOptions),
+    SetBit(Key, u64, bool),
+    GetBit(Key, u64),
+    BitCount(Key, Option<BitRange>),
+    BitPos(Key, bool, Option<BitRange>),
+    BitOp(BitOperation, Key, RVec<Key>),
+    BitField(Key, Vec<FieldOp>),
+    BitFieldRo(Key, Vec<FieldOp>)
+}
+
+impl KeyOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            KeyOps::Get(_)
+            | KeyOps::MGet(_)
+            | KeyOps::Dump(_)
+            | KeyOps::GetEx(_, TtlUpdate::Keep)
+            | KeyOps::GetBit(..)
+            | KeyOps::BitCount(..)
+            | KeyOps::BitPos(..)
+            | KeyOps::BitFieldRo(..) => None,
+            KeyOps::BitField(key, ops) if ops.iter().any(FieldOp::is_write) => {
+                Some(vec![key.clone()])
+            }
+            KeyOps::BitField(..) => None,
+            KeyOps::Set(key, _, _)
+            | KeyOps::SetNx(key, _)
+            | KeyOps::GetSet(key, _)
+            | KeyOps::GetDel(key)
+            | KeyOps::GetEx(key, _) => Some(vec![key.clone()]),
+            KeyOps::MSet(key_vals) => Some(key_vals.iter().map(|(k, _)| k.clone()).collect()),
+            KeyOps::Del(keys) => Some(keys.to_vec()),
+            KeyOps::Rename(key, new_key) | KeyOps::RenameNx(key, new_key) => {
+                Some(vec![key.clone(), new_key.clone()])
+            }
+            KeyOps::Incr(key)
+            | KeyOps::Decr(key)
+            | KeyOps::IncrBy(key, _)
+            | KeyOps::DecrBy(key, _)
+            | KeyOps::IncrByFloat(key, _)
+            | KeyOps::Restore(key, _, _)
+            | KeyOps::SetBit(key, _, _)
+            | KeyOps::BitOp(_, key, _) => Some(vec![key.clone()]),
+        }
+    }
+
+    /// Keys whose current value this op depends on.
+    pub fn read_keys(&self) -> Vec<Key> {
+        match self {
+            KeyOps::Set(key, _, opts)
+                if opts.condition != SetCondition::Always || opts.keep_ttl =>
+            {
+                vec![key.clone()]
+            }
+            KeyOps::Set(..) | KeyOps::MSet(_) => Vec::new(),
+            KeyOps::Get(key)
+            | KeyOps::SetNx(key, _)
+            | KeyOps::GetSet(key, _)
+            | KeyOps::GetDel(key)
+            | KeyOps::GetEx(key, _)
+            | KeyOps::Incr(key)
+            | KeyOps::Decr(key)
+            | KeyOps::IncrBy(key, _)
+            | KeyOps::DecrBy(key, _)
+            | KeyOps::IncrByFloat(key, _)
+            | KeyOps::Dump(key)
+            | KeyOps::Restore(key, _, _)
+            | KeyOps::SetBit(key, _, _)
+            | KeyOps::GetBit(key, _)
+            | KeyOps::BitCount(key, _)
+            | KeyOps::BitPos(key, _, _)
+            | KeyOps::BitField(key, _)
+            | KeyOps::BitFieldRo(key, _) => vec![key.clone()],
+            KeyOps::MGet(keys) | KeyOps::Del(keys) | KeyOps::BitOp(_, _, keys) => keys.to_vec(),
+            KeyOps::Rename(key, new_key) | KeyOps::RenameNx(key, new_key) => {
+                vec![key.clone(), new_key.clone()]
+            }
+        }
+    }
+}
+
+/// Give `key` the TTL asked for by SET.
+fn set_ttl(state: &State, key: Key, opts: &SetOptions) {
+    match opts.ttl_ms {
+        Some(ttl_ms) => state.set_expiry(key, now_ms().saturating_add(ttl_ms)),
+        None if !opts.keep_ttl => {
+            state.clear_expiry(&key);
+        }
+        None => {}
+    }
+}
+
+/// Run SET, returning whether the value was written.
+/// The key stays locked throughout, so NX/XX can't go stale and the TTL matches the value.
+fn set(state: &State, key: Key, value: Value, opts: SetOptions) -> bool {
+    match (state.kv.entry(key.clone()), opts.condition) {
+        (Entry::Occupied(_), SetCondition::IfMissing)
+        | (Entry::Vacant(_), SetCondition::IfExists) => false,
+        (Entry::Occupied(mut entry), _) => {
+            entry.insert(value);
+            set_ttl(state, key, &opts);
+            true
+        }
+        (Entry::Vacant(entry), _) => {
+            let _value = entry.insert(value);
+            set_ttl(state, key, &opts);
+            true
+        }
+    }
+}
+
+const NOT_AN_INTEGER: &[u8] = b"value is not an integer or out of range";
+
+/// Add `by` to the integer at `key` (0 if it's missing), returning the new value.
+/// The key stays locked throughout, so concurrent increments don't race.
+fn incr_by(state: &StateRef, key: Key, by: Count) -> ReturnValue {
+    let mut value = state
+        .kv
+        .entry(key)
+        .or_insert_with(|| Value::from_static(b"0"));
+    let current: Count = match std::str::from_utf8(&value)
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        Some(current) => current,
+        None => return ReturnValue::Error(NOT_AN_INTEGER),
+    };
+    match current.checked_add(by) {
+        Some(new) => {
+            *value = new.to_string().into();
+            ReturnValue::IntRes(new)
+        }
+        None => ReturnValue::Error(b"increment or decrement would overflow"),
+    }
+}
+
+/// Like `incr_by`, but for floats. Replies with the new value as a string.
+fn incr_by_float(state: &StateRef, key: Key, by: f64) -> ReturnValue {
+    let mut value = state
+        .kv
+        .entry(key)
+        .or_insert_with(|| Value::from_static(b"0"));
+    let current: f64 = match std::str::from_utf8(&value)
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        Some(current) => current,
+        None => return ReturnValue::Error(b"value is not a valid float"),
+    };
+    let new = current + by;
+    if !new.is_finite() {
+        return ReturnValue::Error(b"increment would produce NaN or Infinity");
+    }
+    let new: Value = new.to_string().into();
+    *value = new.clone();
+    ReturnValue::StringRes(new)
+}
+
+/// Run GETEX: reply with the value at `key`, then change its TTL.
+/// A deadline that has passed already deletes the key, like redis.
+fn get_ex(state: &State, key: Key, ttl: TtlUpdate) -> ReturnValue {
+    let value = match state.kv.get(&key) {
+        Some(value) => value.value().clone(),
+        None => return ReturnValue::Nil,
+    };
+    match ttl {
+        TtlUpdate::Keep => {}
+        TtlUpdate::Persist => {
+            state.clear_expiry(&key);
+        }
+        TtlUpdate::In(ttl_ms) => state.set_expiry(key, now_ms().saturating_add(ttl_ms)),
+        TtlUpdate::At(deadline) => {
+            state.set_expiry(key.clone(), deadline);
+            state.expire_if_due(&key);
+        }
+    }
+    ReturnValue::StringRes(value)
+}
+
+/// The mask of bit `offset` in its byte. Bit 0 is the most significant bit
+/// of the first byte, like redis.
+fn bit_mask(offset: u64) -> u8 {
+    0x80 >> (offset % 8)
+}
+
+pub(crate) fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    match bytes.get((offset / 8) as usize) {
+        Some(byte) => byte & bit_mask(offset) != 0,
+        None => false,
+    }
+}
+
+/// Set bit `offset` of `bytes`, which must be long enough.
+pub(crate) fn set_bit_in(bytes: &mut [u8], offset: u64, on: bool) {
+    let byte = &mut bytes[(offset / 8) as usize];
+    if on {
+        *byte |= bit_mask(offset);
+    } else {
+        *byte &= !bit_mask(offset);
+    }
+}
+
+/// Run `f` on the bytes of `value`, grown with zeros to at least `len`.
+/// The bytes are changed in place, unless something else shares them.
+pub(crate) fn mutate_bytes<R>(value: &mut Value, len: usize, f: impl FnOnce(&mut [u8]) -> R) -> R {
+    let mut bytes = std::mem::take(value)
+        .try_into_mut()
+        .unwrap_or_else(|shared| BytesMut::from(&shared[..]));
+    if bytes.len() < len {
+        bytes.resize(len, 0);
+    }
+    let res = f(&mut bytes);
+    *value = bytes.freeze();
+    res
+}
+
+/// Run SETBIT, growing the string with zeros as needed. Returns the old bit.
+fn set_bit(state: &State, key: Key, offset: u64, on: bool) -> ReturnValue {
+    let mut value = state.kv.entry(key).or_insert_with(Value::new);
+    let len = (offset / 8) as usize + 1;
+    let old = mutate_bytes(&mut value, len, |bits| {
+        let old = get_bit(bits, offset);
+        set_bit_in(bits, offset, on);
+        old
+    });
+    ReturnValue::IntRes(old as Count)
+}
+
+/// The first and last bits `range` covers in a string of `len` bytes,
+/// or None if it's empty.
+fn bit_span(len: usize, range: &BitRange) -> Option<(u64, u64)> {
+    let total = (if range.bits { len * 8 } else { len }) as Count;
+    let resolve = |offset: Count| {
+        let offset = if offset < 0 { offset + total } else { offset };
+        offset.max(0)
+    };
+    let start = resolve(range.start);
+    let end = resolve(range.end.unwrap_or(-1)).min(total - 1);
+    if start > end {
+        return None;
+    }
+    let (start, end) = (start as u64, end as u64);
+    if range.bits {
+        Some((start, end))
+    } else {
+        Some((start * 8, end * 8 + 7))
+    }
+}
+
+/// How many bits are set from bit `first` to bit `last`, inclusive.
+fn count_bits(bytes: &[u8], first: u64, last: u64) -> u64 {
+    let (first_byte, last_byte) = ((first / 8) as usize, (last / 8) as usize);
+    bytes[first_byte..=last_byte]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| {
+            let mut byte = *byte;
+            if i == 0 {
+                byte &= 0xff >> (first % 8);
+            }
+            if first_byte + i == last_byte {
+                byte &= 0xff << (7 - last % 8);
+            }
+            byte.count_ones() as u64
+        })
+        .sum()
+}
+
+/// The first bit set to `bit` from bit `first` to bit `last`, inclusive.
+fn find_bit(bytes: &[u8], bit: bool, first: u64, last: u64) -> Option<u64> {
+    // Whole bytes of the other bit are skipped at once.
+    let skip = if bit { 0x00 } else { 0xff };
+    let mut offset = first;
+    while offset <= last {
+        if offset % 8 == 0 && offset + 7 <= last && bytes[(offset / 8) as usize] == skip {
+            offset += 8;
+            continue;
+        }
+        if get_bit(bytes, offset) == bit {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+    None
+}
+
+/// Run BITPOS. Looking for a clear bit without an end treats the string as
+/// padded with zeros, so it finds the bit right after it.
+fn bit_pos(state: &State, key: &Key, bit: bool, range: Option<BitRange>) -> ReturnValue {
+    let value = match state.kv.get(key) {
+        Some(value) => value.value().clone(),
+        None => return ReturnValue::IntRes(if bit { -1 } else { 0 }),
+    };
+    let range = range.unwrap_or_default();
+    let (first, last) = match bit_span(value.len(), &range) {
+        Some(span) => span,
+        None => return ReturnValue::IntRes(-1),
+    };
+    match find_bit(&value, bit, first, last) {
+        Some(offset) => ReturnValue::IntRes(offset as Count),
+        None if !bit && range.end.is_none() => ReturnValue::IntRes(last as Count + 1),
+        None => ReturnValue::IntRes(-1),
+    }
+}
+
+/// Run BITOP, storing the result at `dest`. Missing keys are empty strings,
+/// and shorter strings are padded with zeros. An empty result deletes `dest`.
+fn bit_op(state: &State, op: BitOperation, dest: Key, sources: &[Key]) -> ReturnValue {
+    let sources: Vec<Value> = sources
+        .iter()
+        .map(|key| {
+            state
+                .kv
+                .get(key)
+                .map(|v| v.value().clone())
+                .unwrap_or_default()
+        })
+        .collect();
+    let len = sources.iter().map(|source| source.len()).max().unwrap_or(0);
+    let byte = |source: &Value, i: usize| source.get(i).copied().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = sources.iter().map(|source| byte(source, i));
+            let first = bytes.next().unwrap_or(0);
+            match op {
+                BitOperation::And => bytes.fold(first, |acc, b| acc & b),
+                BitOperation::Or => bytes.fold(first, |acc, b| acc | b),
+                BitOperation::Xor => bytes.fold(first, |acc, b| acc ^ b),
+                BitOperation::Not => !first,
+            }
+        })
+        .collect();
+    state.clear_expiry(&dest);
+    if result.is_empty() {
+        state.kv.remove(&dest);
+    } else {
+        state.kv.insert(dest, result.into());
+    }
+    ReturnValue::IntRes(len as Count)
+}
+
+/// Move `key` and its TTL to `new_key`, replacing whatever was there.
+fn rename(state: &State, key: Key, new_key: Key) -> bool {
+    match state.kv.remove(&key) {
+        Some((_, value)) => {
+            let deadline = state.expirations.remove(&key);
+            state.kv.insert(new_key.clone(), value);
+            match deadline {
+                Some((_, deadline)) => state.set_expiry(new_key, deadline),
+                None => {
+                    state.clear_expiry(&new_key);
+                }
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run RESTORE. A deadline that has passed already restores nothing, like redis.
+fn restore(state: &State, key: Key, payload: &[u8], opts: RestoreOptions) -> ReturnValue {
+    if let Err(e) = restore_key(state, key.clone(), payload, opts.replace) {
+        let msg = match e {
+            RestoreError::BusyKey => e.to_string(),
+            RestoreError::BadPayload => format!("ERR {}", e),
+        };
+        return ReturnValue::Ident(RedisValueRef::ErrorMsg(msg.into_bytes()));
+    }
+    if opts.ttl_ms != 0 {
+        let deadline = if opts.absolute {
+            opts.ttl_ms
+        } else {
+            now_ms().saturating_add(opts.ttl_ms)
+        };
+        state.set_expiry(key.clone(), deadline);
+        state.expire_if_due(&key);
+    }
+    ReturnValue::Ok
 }
 
 pub async fn key_interact(key_op: KeyOps, state: StateRef) -> ReturnValue {
+    for key in key_op.read_keys() {
+        state.expire_if_due(&key);
+    }
     match key_op {
-        KeyOps::Get(key) => state.kv.get(&key).map_or(ReturnValue::Nil, |v| {
-            ReturnValue::StringRes(v.value().clone())
-        }),
+        KeyOps::Get(key) => {
+            if state.negative_cache.contains(&key) {
+                return ReturnValue::Nil;
+            }
+            let generation = state.negative_cache.generation();
+            match state.kv.get(&key) {
+                Some(v) => ReturnValue::StringRes(v.value().clone()),
+                None => {
+                    state.negative_cache.insert(&key, generation);
+                    ReturnValue::Nil
+                }
+            }
+        }
         KeyOps::MGet(keys) => {
             let vals = keys
                 .iter()
@@ -28,50 +506,118 @@ pub async fn key_interact(key_op: KeyOps, state: StateRef) -> ReturnValue {
                 .collect();
             ReturnValue::Array(vals)
         }
-        KeyOps::Set(key, value) => {
-            state.kv.insert(key, value);
-            ReturnValue::Ok
+        KeyOps::Set(key, value, opts) => {
+            if set(&state, key, value, opts) {
+                ReturnValue::Ok
+            } else {
+                untouched();
+                ReturnValue::Nil
+            }
+        }
+        KeyOps::SetNx(key, value) => {
+            let opts = SetOptions {
+                condition: SetCondition::IfMissing,
+                ..Default::default()
+            };
+            let was_set = set(&state, key, value, opts);
+            if !was_set {
+                untouched();
+            }
+            ReturnValue::IntRes(was_set as Count)
         }
+        KeyOps::GetSet(key, value) => {
+            let old = state.kv.insert(key.clone(), value);
+            state.clear_expiry(&key);
+            match old {
+                Some(old) => ReturnValue::StringRes(old),
+                None => ReturnValue::Nil,
+            }
+        }
+        KeyOps::GetDel(key) => match state.kv.remove(&key) {
+            Some((_, value)) => {
+                state.clear_expiry(&key);
+                ReturnValue::StringRes(value)
+            }
+            None => {
+                untouched();
+                ReturnValue::Nil
+            }
+        },
+        KeyOps::GetEx(key, ttl) => get_ex(&state, key, ttl),
         KeyOps::MSet(key_vals) => {
             let kv = &state.kv;
             for (key, val) in key_vals.into_iter() {
+                state.clear_expiry(&key);
                 kv.insert(key, val);
             }
             ReturnValue::Ok
         }
         KeyOps::Del(keys) => {
-            let deleted = keys
-                .iter()
-                .map(|x| state.kv.remove(x))
-                .filter(Option::is_some)
-                .count();
-            ReturnValue::IntRes(deleted as Count)
+            let deleted: Vec<Key> = keys
+                .into_iter()
+                .filter(|key| state.remove_key(key))
+                .collect();
+            let count = deleted.len();
+            touched(deleted);
+            ReturnValue::IntRes(count as Count)
         }
-        KeyOps::Rename(key, new_key) => match state.kv.remove(&key) {
-            Some((_, value)) => {
-                state.kv.insert(new_key, value);
+        KeyOps::Rename(key, new_key) => {
+            if rename(&state, key, new_key) {
                 ReturnValue::Ok
+            } else {
+                ReturnValue::Error(b"no such key")
             }
-            None => ReturnValue::Error(b"no such key"),
-        },
+        }
         KeyOps::RenameNx(key, new_key) => {
             if state.kv.contains_key(&new_key) {
+                untouched();
                 return ReturnValue::IntRes(0);
             }
-            match state.kv.remove(&key) {
-                Some((_, value)) => {
-                    state.kv.insert(new_key, value);
-                    ReturnValue::IntRes(1)
-                }
-                None => ReturnValue::Error(b"no such key"),
+            if rename(&state, key, new_key) {
+                ReturnValue::IntRes(1)
+            } else {
+                ReturnValue::Error(b"no such key")
             }
         }
+        KeyOps::Incr(key) => incr_by(&state, key, 1),
+        KeyOps::Decr(key) => incr_by(&state, key, -1),
+        KeyOps::IncrBy(key, by) => incr_by(&state, key, by),
+        KeyOps::DecrBy(key, by) => match by.checked_neg() {
+            Some(by) => incr_by(&state, key, by),
+            None => ReturnValue::Error(b"decrement would overflow"),
+        },
+        KeyOps::IncrByFloat(key, by) => incr_by_float(&state, key, by),
+        KeyOps::Dump(key) => match dump_key(&state, &key) {
+            Some(payload) => ReturnValue::StringRes(payload.into()),
+            None => ReturnValue::Nil,
+        },
+        KeyOps::Restore(key, payload, opts) => restore(&state, key, &payload, opts),
+        KeyOps::SetBit(key, offset, on) => set_bit(&state, key, offset, on),
+        KeyOps::GetBit(key, offset) => match state.kv.get(&key) {
+            Some(value) => ReturnValue::IntRes(get_bit(&value, offset) as Count),
+            None => ReturnValue::IntRes(0),
+        },
+        KeyOps::BitCount(key, range) => {
+            let value = match state.kv.get(&key) {
+                Some(value) => value.value().clone(),
+                None => return ReturnValue::IntRes(0),
+            };
+            let count = bit_span(value.len(), &range.unwrap_or_default())
+                .map_or(0, |(first, last)| count_bits(&value, first, last));
+            ReturnValue::IntRes(count as Count)
+        }
+        KeyOps::BitPos(key, bit, range) => bit_pos(&state, &key, bit, range),
+        KeyOps::BitOp(op, dest, sources) => bit_op(&state, op, dest, &sources),
+        KeyOps::BitField(key, ops) | KeyOps::BitFieldRo(key, ops) => bitfield(&state, key, &ops),
     }
 }
 
 #[cfg(test)]
 mod test_keys {
-    use crate::keys::{key_interact, KeyOps};
+    use crate::keys::{
+        key_interact, mutate_bytes, BitOperation, BitRange, KeyOps, RestoreOptions, SetCondition,
+        SetOptions, TtlUpdate,
+    };
     use crate::types::{ReturnValue, State};
     use bytes::Bytes;
     use smallvec::smallvec;
@@ -85,7 +631,11 @@ mod test_keys {
             ReturnValue::Nil,
             key_interact(KeyOps::Get(v.clone()), eng.clone()).await
         );
-        key_interact(KeyOps::Set(v.clone(), v.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(v.clone(), v.clone(), Default::default()),
+            eng.clone(),
+        )
+        .await;
         assert_eq!(
             ReturnValue::StringRes(v.clone()),
             key_interact(KeyOps::Get(v.clone()), eng.clone()).await
@@ -96,7 +646,11 @@ mod test_keys {
     async fn test_set() {
         let (l, r) = (Bytes::from_static(b"l"), Bytes::from_static(b"r"));
         let eng = Arc::new(State::default());
-        key_interact(KeyOps::Set(l.clone(), r.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(l.clone(), r.clone(), Default::default()),
+            eng.clone(),
+        )
+        .await;
         assert_eq!(
             ReturnValue::StringRes(r.clone()),
             key_interact(KeyOps::Get(l.clone()), eng.clone()).await
@@ -107,7 +661,11 @@ mod test_keys {
     async fn test_del() {
         let (l, unused) = (Bytes::from_static(b"l"), Bytes::from_static(b"r"));
         let eng = Arc::new(State::default());
-        key_interact(KeyOps::Set(l.clone(), l.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(l.clone(), l.clone(), Default::default()),
+            eng.clone(),
+        )
+        .await;
 
         assert_eq!(
             ReturnValue::IntRes(1),
@@ -117,6 +675,18 @@ mod test_keys {
             ReturnValue::IntRes(0),
             key_interact(KeyOps::Del(smallvec![unused]), eng.clone()).await
         );
+
+        // Every structure, with no TTL left behind.
+        let list = Bytes::from_static(b"list");
+        eng.lists.insert(list.clone(), Default::default());
+        eng.stacks.insert(list.clone(), Default::default());
+        eng.set_expiry(list.clone(), u64::MAX);
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            key_interact(KeyOps::Del(smallvec![list.clone()]), eng.clone()).await
+        );
+        assert_eq!(eng.structure_of(&list), None);
+        assert_eq!(eng.expiry(&list), None);
     }
 
     #[tokio::test]
@@ -127,7 +697,11 @@ mod test_keys {
             Bytes::from_static(b"new"),
         );
         let eng = Arc::new(State::default());
-        key_interact(KeyOps::Set(old.clone(), v.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(old.clone(), v.clone(), Default::default()),
+            eng.clone(),
+        )
+        .await;
         // TODO: Make testing Exec_OpionRes tractable
         // assert(ir(eng.clone().exec_op(gp(KeyOps::Rename(new.clone()), old.clone()))).is_error());
         key_interact(KeyOps::Rename(old.clone(), new.clone()), eng.clone()).await;
@@ -136,4 +710,367 @@ mod test_keys {
             key_interact(KeyOps::Get(new), eng.clone()).await
         );
     }
+
+    #[tokio::test]
+    async fn test_incr_family() {
+        let key = Bytes::from_static(b"counter");
+        let eng = Arc::new(State::default());
+        assert_eq!(
+            key_interact(KeyOps::Incr(key.clone()), eng.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(
+            key_interact(KeyOps::IncrBy(key.clone(), 10), eng.clone()).await,
+            ReturnValue::IntRes(11)
+        );
+        assert_eq!(
+            key_interact(KeyOps::DecrBy(key.clone(), 20), eng.clone()).await,
+            ReturnValue::IntRes(-9)
+        );
+        assert_eq!(
+            key_interact(KeyOps::Decr(key.clone()), eng.clone()).await,
+            ReturnValue::IntRes(-10)
+        );
+        assert_eq!(
+            key_interact(KeyOps::Get(key.clone()), eng.clone()).await,
+            ReturnValue::StringRes(Bytes::from_static(b"-10"))
+        );
+        assert_eq!(
+            key_interact(KeyOps::IncrByFloat(key.clone(), 0.5), eng.clone()).await,
+            ReturnValue::StringRes(Bytes::from_static(b"-9.5"))
+        );
+        // No longer an integer.
+        assert!(matches!(
+            key_interact(KeyOps::Incr(key.clone()), eng.clone()).await,
+            ReturnValue::Error(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_incr_errors() {
+        let (key, text) = (Bytes::from_static(b"k"), Bytes::from_static(b"text"));
+        let eng = Arc::new(State::default());
+        key_interact(
+            KeyOps::Set(key.clone(), text.clone(), Default::default()),
+            eng.clone(),
+        )
+        .await;
+        assert!(matches!(
+            key_interact(KeyOps::IncrBy(key.clone(), 1), eng.clone()).await,
+            ReturnValue::Error(_)
+        ));
+        assert!(matches!(
+            key_interact(KeyOps::IncrByFloat(key.clone(), 1.0), eng.clone()).await,
+            ReturnValue::Error(_)
+        ));
+        // Failed increments leave the value alone.
+        assert_eq!(
+            key_interact(KeyOps::Get(key.clone()), eng.clone()).await,
+            ReturnValue::StringRes(text)
+        );
+        key_interact(
+            KeyOps::Set(key.clone(), i64::MAX.to_string().into(), Default::default()),
+            eng.clone(),
+        )
+        .await;
+        assert!(matches!(
+            key_interact(KeyOps::Incr(key.clone()), eng.clone()).await,
+            ReturnValue::Error(_)
+        ));
+        assert!(matches!(
+            key_interact(KeyOps::DecrBy(key, i64::MIN), eng.clone()).await,
+            ReturnValue::Error(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_set_conditions() {
+        let (key, a, b) = (
+            Bytes::from_static(b"k"),
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+        );
+        let eng = Arc::new(State::default());
+        let only = |condition| SetOptions {
+            condition,
+            ..Default::default()
+        };
+        assert_eq!(
+            key_interact(
+                KeyOps::Set(key.clone(), a.clone(), only(SetCondition::IfExists)),
+                eng.clone()
+            )
+            .await,
+            ReturnValue::Nil
+        );
+        assert_eq!(
+            key_interact(
+                KeyOps::Set(key.clone(), a.clone(), only(SetCondition::IfMissing)),
+                eng.clone()
+            )
+            .await,
+            ReturnValue::Ok
+        );
+        assert_eq!(
+            key_interact(KeyOps::SetNx(key.clone(), b.clone()), eng.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        assert_eq!(
+            key_interact(
+                KeyOps::Set(key.clone(), b.clone(), only(SetCondition::IfExists)),
+                eng.clone()
+            )
+            .await,
+            ReturnValue::Ok
+        );
+        assert_eq!(
+            key_interact(KeyOps::GetSet(key.clone(), a.clone()), eng.clone()).await,
+            ReturnValue::StringRes(b)
+        );
+        assert_eq!(
+            key_interact(KeyOps::Get(key), eng.clone()).await,
+            ReturnValue::StringRes(a)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_ttl() {
+        let (key, v) = (Bytes::from_static(b"k"), Bytes::from_static(b"v"));
+        let eng = Arc::new(State::default());
+        let expiring = SetOptions {
+            ttl_ms: Some(60_000),
+            ..Default::default()
+        };
+        key_interact(KeyOps::Set(key.clone(), v.clone(), expiring), eng.clone()).await;
+        assert!(eng.expiry(&key).is_some());
+        let keep = SetOptions {
+            keep_ttl: true,
+            ..Default::default()
+        };
+        key_interact(KeyOps::Set(key.clone(), v.clone(), keep), eng.clone()).await;
+        assert!(eng.expiry(&key).is_some());
+        // Renames carry the TTL along.
+        let new_key = Bytes::from_static(b"new");
+        key_interact(KeyOps::Rename(key.clone(), new_key.clone()), eng.clone()).await;
+        assert!(eng.expiry(&new_key).is_some());
+        key_interact(
+            KeyOps::Set(new_key.clone(), v.clone(), Default::default()),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(eng.expiry(&new_key), None);
+
+        // Expired keys are gone, and don't count for NX.
+        eng.set_expiry(new_key.clone(), 0);
+        assert_eq!(
+            key_interact(KeyOps::SetNx(new_key.clone(), v.clone()), eng.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        eng.set_expiry(new_key.clone(), 0);
+        assert_eq!(
+            key_interact(KeyOps::Get(new_key), eng.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+
+    #[tokio::test]
+    async fn test_getdel_and_getex() {
+        let (key, v) = (Bytes::from_static(b"k"), Bytes::from_static(b"v"));
+        let eng = Arc::new(State::default());
+        let getex = |ttl| key_interact(KeyOps::GetEx(key.clone(), ttl), eng.clone());
+        assert_eq!(getex(TtlUpdate::In(1000)).await, ReturnValue::Nil);
+        assert_eq!(eng.expiry(&key), None);
+
+        key_interact(
+            KeyOps::Set(key.clone(), v.clone(), Default::default()),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(
+            getex(TtlUpdate::Keep).await,
+            ReturnValue::StringRes(v.clone())
+        );
+        assert_eq!(eng.expiry(&key), None);
+        assert_eq!(
+            getex(TtlUpdate::In(60_000)).await,
+            ReturnValue::StringRes(v.clone())
+        );
+        assert!(eng.expiry(&key).is_some());
+        assert_eq!(
+            getex(TtlUpdate::Persist).await,
+            ReturnValue::StringRes(v.clone())
+        );
+        assert_eq!(eng.expiry(&key), None);
+        // A deadline in the past still replies, then deletes the key.
+        assert_eq!(
+            getex(TtlUpdate::At(1)).await,
+            ReturnValue::StringRes(v.clone())
+        );
+        assert!(!eng.kv.contains_key(&key));
+
+        eng.kv.insert(key.clone(), v.clone());
+        eng.set_expiry(key.clone(), u64::MAX);
+        assert_eq!(
+            key_interact(KeyOps::GetDel(key.clone()), eng.clone()).await,
+            ReturnValue::StringRes(v)
+        );
+        assert!(!eng.kv.contains_key(&key));
+        assert_eq!(eng.expiry(&key), None);
+        assert_eq!(
+            key_interact(KeyOps::GetDel(key), eng.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+
+    #[test]
+    fn test_mutate_bytes() {
+        let mut value = Bytes::from(vec![1, 2]);
+        let before = value.as_ptr();
+        mutate_bytes(&mut value, 2, |bytes| bytes[0] = 3);
+        assert_eq!((&value[..], value.as_ptr()), (&[3, 2][..], before));
+
+        // Shared bytes are copied, and the other owner keeps its own.
+        let shared = value.clone();
+        mutate_bytes(&mut value, 4, |bytes| bytes[3] = 1);
+        assert_eq!((&value[..], &shared[..]), (&[3, 2, 0, 1][..], &[3, 2][..]));
+    }
+
+    #[tokio::test]
+    async fn test_bitmaps() {
+        let (key, other, dest) = (
+            Bytes::from_static(b"k"),
+            Bytes::from_static(b"o"),
+            Bytes::from_static(b"d"),
+        );
+        let eng = Arc::new(State::default());
+        let run = |op| key_interact(op, eng.clone());
+        assert_eq!(
+            run(KeyOps::SetBit(key.clone(), 7, true)).await,
+            ReturnValue::IntRes(0)
+        );
+        assert_eq!(
+            run(KeyOps::SetBit(key.clone(), 7, true)).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(*eng.kv.get(&key).unwrap(), Bytes::from_static(b"\x01"));
+        // Setting a bit past the end pads with zeros.
+        run(KeyOps::SetBit(key.clone(), 17, true)).await;
+        assert_eq!(
+            *eng.kv.get(&key).unwrap(),
+            Bytes::from_static(b"\x01\x00\x40")
+        );
+        assert_eq!(
+            run(KeyOps::GetBit(key.clone(), 17)).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(
+            run(KeyOps::GetBit(key.clone(), 1000)).await,
+            ReturnValue::IntRes(0)
+        );
+        assert_eq!(
+            run(KeyOps::GetBit(other.clone(), 0)).await,
+            ReturnValue::IntRes(0)
+        );
+
+        eng.kv.insert(other.clone(), Bytes::from_static(b"foobar"));
+        let count = |range| run(KeyOps::BitCount(other.clone(), range));
+        assert_eq!(count(None).await, ReturnValue::IntRes(26));
+        let bytes = |start, end| BitRange {
+            start,
+            end: Some(end),
+            bits: false,
+        };
+        assert_eq!(count(Some(bytes(0, 0))).await, ReturnValue::IntRes(4));
+        assert_eq!(count(Some(bytes(1, 1))).await, ReturnValue::IntRes(6));
+        assert_eq!(count(Some(bytes(-2, -1))).await, ReturnValue::IntRes(7));
+        assert_eq!(count(Some(bytes(3, 1))).await, ReturnValue::IntRes(0));
+        let bits = BitRange {
+            start: 5,
+            end: Some(30),
+            bits: true,
+        };
+        assert_eq!(count(Some(bits)).await, ReturnValue::IntRes(17));
+
+        eng.kv
+            .insert(other.clone(), Bytes::from_static(b"\xff\xf0\x00"));
+        let pos = |bit, range| run(KeyOps::BitPos(other.clone(), bit, range));
+        assert_eq!(pos(false, None).await, ReturnValue::IntRes(12));
+        assert_eq!(pos(true, Some(bytes(2, -1))).await, ReturnValue::IntRes(-1));
+        assert_eq!(pos(true, None).await, ReturnValue::IntRes(0));
+        eng.kv
+            .insert(other.clone(), Bytes::from_static(b"\xff\xff"));
+        // Clear bits are found past the end, unless there's an end.
+        assert_eq!(pos(false, None).await, ReturnValue::IntRes(16));
+        assert_eq!(
+            pos(false, Some(bytes(0, -1))).await,
+            ReturnValue::IntRes(-1)
+        );
+
+        eng.kv.insert(key.clone(), Bytes::from_static(b"\x0f\xff"));
+        eng.kv.insert(other.clone(), Bytes::from_static(b"\x3c"));
+        let bit_op = |op, sources: Vec<Bytes>| {
+            run(KeyOps::BitOp(
+                op,
+                dest.clone(),
+                sources.into_iter().collect(),
+            ))
+        };
+        let sources = vec![key.clone(), other.clone()];
+        let result = || eng.kv.get(&dest).map(|v| v.value().clone());
+        assert_eq!(
+            bit_op(BitOperation::And, sources.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        assert_eq!(result(), Some(Bytes::from_static(b"\x0c\x00")));
+        bit_op(BitOperation::Or, sources.clone()).await;
+        assert_eq!(result(), Some(Bytes::from_static(b"\x3f\xff")));
+        bit_op(BitOperation::Xor, sources).await;
+        assert_eq!(result(), Some(Bytes::from_static(b"\x33\xff")));
+        bit_op(BitOperation::Not, vec![other.clone()]).await;
+        assert_eq!(result(), Some(Bytes::from_static(b"\xc3")));
+        // Only missing keys make an empty result, which deletes the destination.
+        let missing = Bytes::from_static(b"missing");
+        assert_eq!(
+            bit_op(BitOperation::Or, vec![missing]).await,
+            ReturnValue::IntRes(0)
+        );
+        assert_eq!(result(), None);
+    }
+
+    #[tokio::test]
+    async fn test_dump_and_restore() {
+        let (key, v) = (Bytes::from_static(b"key"), Bytes::from_static(b"v"));
+        let eng = Arc::new(State::default());
+        let dump = |key: &Bytes| key_interact(KeyOps::Dump(key.clone()), eng.clone());
+        assert_eq!(dump(&key).await, ReturnValue::Nil);
+        eng.kv.insert(key.clone(), v.clone());
+        let payload = match dump(&key).await {
+            ReturnValue::StringRes(payload) => payload,
+            other => panic!("unexpected reply {:?}", other),
+        };
+
+        let restore = |opts: RestoreOptions| {
+            key_interact(
+                KeyOps::Restore(key.clone(), payload.clone(), opts),
+                eng.clone(),
+            )
+        };
+        assert!(restore(Default::default()).await.is_error());
+        let replace = RestoreOptions {
+            ttl_ms: 60_000,
+            replace: true,
+            ..Default::default()
+        };
+        assert_eq!(restore(replace).await, ReturnValue::Ok);
+        assert!(eng.expiry(&key).is_some());
+        // A deadline in the past leaves nothing behind.
+        let expired = RestoreOptions {
+            ttl_ms: 1,
+            absolute: true,
+            replace: true,
+        };
+        assert_eq!(restore(expired).await, ReturnValue::Ok);
+        assert!(eng.kv.is_empty());
+        assert_eq!(eng.expiry(&key), None);
+    }
 }
@@ -0,0 +1,268 @@
+//! Keyspace notifications: writes published over pub/sub, like redis'
+//! `notify-keyspace-events`.
+//!
+//! While notifications are on, each db's `WriteTracker` keeps the effects of
+//! its writes. After every command, and on every expiry sweep, they're
+//! published as `__keyspace@<db>__:<key>` with the event as the message, and
+//! `__keyevent@<db>__:<event>` with the key as the message.
+//!
+//! Events are named after the op, so a few differ from redis': LMOVE sends
+//! `lmove` for both keys rather than a pop and a push, and flushes send none.
+
+use crate::types::{Index, State, StateStore, Value};
+
+pub const KEYSPACE: u32 = 1 << 0;
+pub const KEYEVENT: u32 = 1 << 1;
+pub const GENERIC: u32 = 1 << 2;
+pub const STRING: u32 = 1 << 3;
+pub const LIST: u32 = 1 << 4;
+pub const SET: u32 = 1 << 5;
+pub const HASH: u32 = 1 << 6;
+pub const ZSET: u32 = 1 << 7;
+pub const EXPIRED: u32 = 1 << 8;
+pub const EVICTED: u32 = 1 << 9;
+pub const STREAM: u32 = 1 << 10;
+pub const KEY_MISS: u32 = 1 << 11;
+/// Our own types, like bloom filters and stacks.
+pub const MODULE: u32 = 1 << 12;
+
+/// The classes `A` stands for.
+const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+const FLAGS: &[(char, u32)] = &[
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('m', KEY_MISS),
+    ('d', MODULE),
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+];
+
+/// Parse flags like `KEA` or `Kl$`.
+pub fn parse_flags(flags: &str) -> Result<u32, String> {
+    flags.chars().try_fold(0, |parsed, c| {
+        if c == 'A' {
+            return Ok(parsed | ALL);
+        }
+        FLAGS
+            .iter()
+            .find(|(flag, _)| *flag == c)
+            .map(|(_, bit)| parsed | bit)
+            .ok_or_else(|| "Invalid event class character. Use 'Ag$lshzxeKEtmd'.".to_string())
+    })
+}
+
+/// The flags string for `flags`, using `A` where it can.
+pub fn format_flags(flags: u32) -> String {
+    let (mut formatted, rest) = if flags & ALL == ALL {
+        ("A".to_string(), flags & !ALL)
+    } else {
+        (String::new(), flags)
+    };
+    for (flag, bit) in FLAGS {
+        if rest & bit != 0 {
+            formatted.push(*flag);
+        }
+    }
+    formatted
+}
+
+/// Whether `flags` would publish anything: a channel kind and a class.
+pub fn enabled(flags: u32) -> bool {
+    flags & (KEYSPACE | KEYEVENT) != 0 && flags & !(KEYSPACE | KEYEVENT) != 0
+}
+
+/// The class and name of the event the op `name` has on its `n`th key.
+fn event(name: &'static str, n: usize) -> (u32, String) {
+    let (class, event) = match name {
+        "Set" | "SetNx" | "GetSet" | "MSet" | "BitOp" => (STRING, "set"),
+        "SetBit" | "BitField" => (STRING, "setbit"),
+        "Incr" | "Decr" | "IncrBy" | "DecrBy" => (STRING, "incrby"),
+        "IncrByFloat" => (STRING, "incrbyfloat"),
+        "PfAdd" | "PfMerge" => (STRING, "pfadd"),
+        "Del" | "GetDel" => (GENERIC, "del"),
+        "GetEx" => (GENERIC, "expire"),
+        "Restore" => (GENERIC, "restore"),
+        "Copy" => (GENERIC, "copy_to"),
+        "Sort" => (LIST, "sortstore"),
+        "Rename" | "RenameNx" if n == 0 => (GENERIC, "rename_from"),
+        "Rename" | "RenameNx" => (GENERIC, "rename_to"),
+        "MoveFrom" => (GENERIC, "move_from"),
+        "MoveTo" => (GENERIC, "move_to"),
+        "Expired" => (EXPIRED, "expired"),
+        "Evicted" => (EVICTED, "evicted"),
+        "LPush" | "LPushX" => (LIST, "lpush"),
+        "RPush" | "RPushX" => (LIST, "rpush"),
+        "LPop" | "BLPop" => (LIST, "lpop"),
+        "RPop" | "BRPop" => (LIST, "rpop"),
+        "RPopLPush" if n == 0 => (LIST, "rpop"),
+        "RPopLPush" => (LIST, "lpush"),
+        "LMove" | "BLMove" => (LIST, "lmove"),
+        "LInsert" | "LSet" | "LRem" | "LTrim" => return (LIST, name.to_lowercase()),
+        "SMove" if n == 0 => (SET, "srem"),
+        "SMove" => (SET, "sadd"),
+        "SAdd" | "SRem" | "SPop" | "SDiffStore" | "SInterStore" | "SUnionStore" => {
+            return (SET, name.to_lowercase())
+        }
+        "HSet" | "HSetNX" | "HMSet" => (HASH, "hset"),
+        "HDel" | "HIncrBy" | "HIncrByFloat" => return (HASH, name.to_lowercase()),
+        "ZIncrBy" => (ZSET, "zincr"),
+        "GeoAdd" => (ZSET, "zadd"),
+        "ZAdd" | "ZRem" | "ZPopMax" | "ZPopMin" | "ZRemRangeByScore" | "ZRemRangeByRank" => {
+            return (ZSET, name.to_lowercase())
+        }
+        "XAdd" => (STREAM, "xadd"),
+        "XGroupCreate" => (STREAM, "xgroup-create"),
+        "XGroupSetId" => (STREAM, "xgroup-setid"),
+        "XGroupDestroy" => (STREAM, "xgroup-destroy"),
+        "XReadGroup" | "XAck" | "XClaim" | "XAutoClaim" => return (STREAM, name.to_lowercase()),
+        "BfReserve" => (MODULE, "bf.reserve"),
+        "BfMAdd" => (MODULE, "bf.add"),
+        "CfAdd" => (MODULE, "cf.add"),
+        "CfDel" => (MODULE, "cf.del"),
+        "CmsInitByDim" | "CmsInitByProb" => (MODULE, "cms.init"),
+        "CmsIncrBy" => (MODULE, "cms.incrby"),
+        "CmsMerge" => (MODULE, "cms.merge"),
+        "TopKReserve" => (MODULE, "topk.reserve"),
+        "TopKAdd" => (MODULE, "topk.add"),
+        "BInsert" | "STPush" | "STPop" | "STPopN" | "QPush" | "QPop" | "QAck" | "QClaim" => {
+            return (MODULE, name.to_lowercase())
+        }
+        _ => return (GENERIC, name.to_lowercase()),
+    };
+    (class, event.to_string())
+}
+
+/// Publish the effects `state` (db `db`) kept since the last call.
+pub fn publish_keyspace_events(store: &StateStore, db: Index, state: &State) {
+    let flags = store.config.notify_keyspace_events();
+    for effects in state.writes.take_events() {
+        for (n, key) in effects.keys.iter().enumerate() {
+            let (class, event) = event(effects.event, n);
+            if flags & class == 0 {
+                continue;
+            }
+            if flags & KEYSPACE != 0 {
+                let channel = [format!("__keyspace@{}__:", db).as_bytes(), key].concat();
+                store.pubsub.publish(&channel, &Value::from(event.clone()));
+            }
+            if flags & KEYEVENT != 0 {
+                let channel = format!("__keyevent@{}__:{}", db, event);
+                store.pubsub.publish(channel.as_bytes(), key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_keyspace_events {
+    use super::{format_flags, parse_flags, publish_keyspace_events, KEYEVENT, KEYSPACE, LIST};
+    use crate::config::config_set;
+    use crate::ops::{op_interact, translate};
+    use crate::pubsub::Subscriber;
+    use crate::test_util::command;
+    use crate::types::{bulk, RedisValueRef, StateStore};
+    use crate::write_effects::WriteEffects;
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(parse_flags("Kl"), Ok(KEYSPACE | LIST));
+        assert_eq!(format_flags(parse_flags("EKA").unwrap()), "AKE");
+        assert_eq!(format_flags(parse_flags("").unwrap()), "");
+        assert!(parse_flags("KQ").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let store = Arc::new(StateStore::default());
+        let set = |flags: &str| {
+            let pairs = [("notify-keyspace-events".to_string(), flags.to_string())];
+            config_set(&store, &pairs).unwrap();
+        };
+        set("El");
+        assert_eq!(store.config.notify_keyspace_events(), KEYEVENT | LIST);
+        let db = store.get_or_create(2);
+        let mut subscriber = Subscriber::new(store.clone());
+        let subscribe = RedisValueRef::Array(vec![bulk("psubscribe"), bulk("__key*")]);
+        subscriber.run_command(&subscribe);
+
+        // Strings aren't asked for, so only the push is published.
+        WriteEffects::new("Set", vec![Bytes::from_static(b"s")]).apply(&db);
+        WriteEffects::new("LPush", vec![Bytes::from_static(b"l")]).apply(&db);
+        publish_keyspace_events(&store, 2, &db);
+        assert_eq!(
+            subscriber.next_message().await,
+            Some(RedisValueRef::Array(vec![
+                bulk("pmessage"),
+                bulk("__key*"),
+                bulk("__keyevent@2__:lpush"),
+                bulk("l"),
+            ]))
+        );
+
+        // Turning notifications off stops keeping effects.
+        set("");
+        WriteEffects::new("LPush", vec![Bytes::from_static(b"l")]).apply(&db);
+        assert!(db.writes.take_events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_no_op_writes_publish_nothing() {
+        let store = Arc::new(StateStore::default());
+        let pairs = [("notify-keyspace-events".to_string(), "EA".to_string())];
+        config_set(&store, &pairs).unwrap();
+        let db = store.get_default();
+        let mut subscriber = Subscriber::new(store.clone());
+        let subscribe = RedisValueRef::Array(vec![bulk("psubscribe"), bulk("__key*")]);
+        subscriber.run_command(&subscribe);
+        let run = |args: &[&str]| {
+            let op = translate(command(args), store.clone()).unwrap();
+            op_interact(op, db.clone())
+        };
+        run(&["SET", "s", "v"]).await;
+        run(&["SADD", "set", "m"]).await;
+        db.writes.take_events();
+
+        let clock = db.writes.clock();
+        for args in [
+            &["DEL", "missing"][..],
+            &["SET", "s", "w", "NX"][..],
+            &["LPOP", "list"][..],
+            &["SADD", "set", "m"][..],
+            &["QPOP", "queue"][..],
+        ]
+        .iter()
+        {
+            let (_, effects) = run(*args).await;
+            assert_eq!(effects, None, "{:?}", args);
+        }
+        assert_eq!(db.writes.clock(), clock);
+        assert!(db.writes.take_events().is_empty());
+
+        // Only the keys DEL found are deleted.
+        let (_, effects) = run(&["DEL", "missing", "s"]).await;
+        assert_eq!(
+            effects,
+            Some(WriteEffects::new("Del", vec![Bytes::from_static(b"s")]))
+        );
+        publish_keyspace_events(&store, 0, &db);
+        assert_eq!(
+            subscriber.next_message().await,
+            Some(RedisValueRef::Array(vec![
+                bulk("pmessage"),
+                bulk("__key*"),
+                bulk("__keyevent@0__:del"),
+                bulk("s"),
+            ]))
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! Latency monitor: records events that took longer than a threshold, like
+//! redis' LATENCY command family.
+//!
+//! Each event class (running a command, saving the dump file, ...) keeps a
+//! bounded history of spikes, one sample per second holding the worst
+//! latency seen in it. A threshold of 0 disables the monitor.
+
+use crate::types::Value;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Executing a single command.
+pub const COMMAND: &str = "command";
+/// Writing the dump file.
+pub const SAVE: &str = "save";
+/// The longest a save kept writers waiting on one shard.
+pub const SAVE_LOCK: &str = "save-lock";
+
+/// Samples kept per event.
+const HISTORY_LEN: usize = 160;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// Unix time, in seconds.
+    pub time: u64,
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct EventHistory {
+    samples: VecDeque<Sample>,
+    max_ms: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct LatencyMonitor {
+    threshold_ms: AtomicU64,
+    events: Mutex<HashMap<&'static str, EventHistory>>,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// What usually causes spikes of the given event.
+fn advice(event: &str) -> &'static str {
+    match event {
+        COMMAND => "Slow commands: look for KEYS, SMEMBERS or range queries over big collections, and prefer SCAN.",
+        SAVE => "Saving the dump file is slow: raise --ops-until-save, or use --memory-only if persistence isn't needed.",
+        SAVE_LOCK => "Saves copy one shard at a time, and a shard holding big keys is slow to copy: split big collections up.",
+        _ => "",
+    }
+}
+
+impl LatencyMonitor {
+    pub fn set_threshold(&self, threshold_ms: u64) {
+        self.threshold_ms.store(threshold_ms, Ordering::Relaxed);
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold_ms.load(Ordering::Relaxed)
+    }
+
+    /// Record that `event` took `elapsed`, if that's over the threshold.
+    pub fn record(&self, event: &'static str, elapsed: Duration) {
+        self.record_at(event, elapsed, unix_time());
+    }
+
+    fn record_at(&self, event: &'static str, elapsed: Duration, time: u64) {
+        let threshold = self.threshold();
+        let latency_ms = elapsed.as_millis() as u64;
+        if threshold == 0 || latency_ms < threshold {
+            return;
+        }
+        let mut events = self.events.lock();
+        let history = events.entry(event).or_default();
+        history.max_ms = history.max_ms.max(latency_ms);
+        match history.samples.back_mut() {
+            Some(last) if last.time == time => last.latency_ms = last.latency_ms.max(latency_ms),
+            _ => {
+                history.samples.push_back(Sample { time, latency_ms });
+                if history.samples.len() > HISTORY_LEN {
+                    history.samples.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Every recorded sample of `event`, oldest first.
+    pub fn history(&self, event: &[u8]) -> Vec<Sample> {
+        self.events
+            .lock()
+            .iter()
+            .find(|(name, _)| name.as_bytes().eq_ignore_ascii_case(event))
+            .map(|(_, history)| history.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// (event, latest sample, all time max in ms) for every event, sorted by name.
+    pub fn latest(&self) -> Vec<(&'static str, Sample, u64)> {
+        let mut res: Vec<_> = self
+            .events
+            .lock()
+            .iter()
+            .filter_map(|(name, history)| Some((*name, *history.samples.back()?, history.max_ms)))
+            .collect();
+        res.sort_by_key(|(name, _, _)| *name);
+        res
+    }
+
+    /// Forget the given events (every event if none are given). Returns how many were reset.
+    pub fn reset(&self, events: &[Value]) -> usize {
+        let mut recorded = self.events.lock();
+        let before = recorded.len();
+        if events.is_empty() {
+            recorded.clear();
+        } else {
+            recorded.retain(|name, _| {
+                !events
+                    .iter()
+                    .any(|event| name.as_bytes().eq_ignore_ascii_case(event))
+            });
+        }
+        before - recorded.len()
+    }
+
+    /// A human readable report of the spikes seen, and what may cause them.
+    pub fn doctor(&self) -> String {
+        let threshold = self.threshold();
+        if threshold == 0 {
+            return "Latency monitoring is disabled. Enable it with --latency-monitor-threshold <milliseconds>.".into();
+        }
+        let events = self.events.lock();
+        if events.is_empty() {
+            return format!(
+                "No latency spikes above {}ms were recorded. Nothing to worry about.",
+                threshold
+            );
+        }
+        let mut names: Vec<_> = events.keys().copied().collect();
+        names.sort_unstable();
+        let mut lines = vec![format!(
+            "Latency spikes above {}ms were recorded:",
+            threshold
+        )];
+        for name in names {
+            let history = &events[name];
+            let total: u64 = history.samples.iter().map(|s| s.latency_ms).sum();
+            let average = total / history.samples.len().max(1) as u64;
+            lines.push(format!(
+                "- {}: {} spikes, {}ms on average, {}ms at worst. {}",
+                name,
+                history.samples.len(),
+                average,
+                history.max_ms,
+                advice(name)
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test_latency {
+    use super::{LatencyMonitor, Sample, COMMAND, HISTORY_LEN, SAVE};
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    #[test]
+    fn test_threshold() {
+        let monitor = LatencyMonitor::default();
+        monitor.record_at(COMMAND, Duration::from_secs(1), 1);
+        assert!(monitor.latest().is_empty());
+        monitor.set_threshold(100);
+        monitor.record_at(COMMAND, Duration::from_millis(99), 1);
+        assert!(monitor.latest().is_empty());
+        monitor.record_at(COMMAND, Duration::from_millis(100), 1);
+        assert_eq!(monitor.history(b"COMMAND").len(), 1);
+    }
+
+    #[test]
+    fn test_history_is_bounded_and_merged() {
+        let monitor = LatencyMonitor::default();
+        monitor.set_threshold(1);
+        monitor.record_at(SAVE, Duration::from_millis(5), 10);
+        monitor.record_at(SAVE, Duration::from_millis(50), 10);
+        monitor.record_at(SAVE, Duration::from_millis(7), 11);
+        assert_eq!(
+            monitor.history(b"save"),
+            vec![
+                Sample {
+                    time: 10,
+                    latency_ms: 50
+                },
+                Sample {
+                    time: 11,
+                    latency_ms: 7
+                }
+            ]
+        );
+        for time in 0..1000 {
+            monitor.record_at(COMMAND, Duration::from_millis(2), 100 + time);
+        }
+        assert_eq!(monitor.history(b"command").len(), HISTORY_LEN);
+        let latest = monitor.latest();
+        assert_eq!(latest[0].0, COMMAND);
+        assert_eq!(
+            latest[1],
+            (
+                SAVE,
+                Sample {
+                    time: 11,
+                    latency_ms: 7
+                },
+                50
+            )
+        );
+        assert!(monitor.doctor().contains("save: 2 spikes"));
+    }
+
+    #[test]
+    fn test_reset() {
+        let monitor = LatencyMonitor::default();
+        monitor.set_threshold(1);
+        monitor.record_at(SAVE, Duration::from_millis(5), 1);
+        monitor.record_at(COMMAND, Duration::from_millis(5), 1);
+        assert_eq!(monitor.reset(&[Bytes::from_static(b"save")]), 1);
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.latest().is_empty());
+    }
+}
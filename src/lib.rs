@@ -14,25 +14,80 @@ extern crate slog;
 extern crate rmp_serde as rmps;
 
 pub mod asyncresp;
+pub mod backing;
+pub mod batch;
+pub mod bitfield;
 pub mod blocking;
 pub mod bloom;
+pub mod bulk_load;
+pub mod cdc;
+pub mod clients;
+pub mod command_file;
+pub mod command_table;
+pub mod compat;
+pub mod config;
+pub mod connection;
+pub mod coop;
+pub mod count_min;
+pub mod cuckoo;
 pub mod database;
+pub mod dump_format;
+pub mod embedded;
+pub mod escape;
+pub mod eviction;
+pub mod expiry;
+pub mod geo;
+pub mod glob;
+#[cfg(unix)]
+pub mod handoff;
 pub mod hashes;
+pub mod hashing;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod key_dump;
 pub mod keys;
+pub mod keyspace_events;
+pub mod latency;
 pub mod lists;
 pub mod logger;
 #[macro_use]
 pub mod macros;
 pub mod data_structures;
 pub mod hyperloglog;
+pub mod info;
+pub mod memory;
 pub mod misc;
+pub mod monitor;
 pub mod ops;
+pub mod pubsub;
+pub mod queue;
+pub mod rdb;
+pub mod replication;
+pub mod scan;
+// Most of the scripting plumbing is unused without an engine to drive it.
+#[cfg_attr(
+    not(any(feature = "scripting", feature = "wasm")),
+    allow(dead_code, unused_variables)
+)]
 pub mod scripting;
 pub mod server;
 pub mod sets;
+pub mod shutdown;
+pub mod slowlog;
+pub mod snapshot;
+pub mod sort;
 pub mod sorted_sets;
 pub mod stack;
 pub mod startup;
 pub mod state;
+pub mod stats;
+pub mod streams;
+#[cfg(test)]
+mod test_util;
 pub mod timeouts;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod top_k;
+pub mod tree;
 pub mod types;
+pub mod write_effects;
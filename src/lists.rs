@@ -1,29 +1,312 @@
 use crate::ops::RVec;
-use crate::timeouts::blocking_key_timeout;
-use crate::types::{Count, Index, Key, ReturnValue, StateRef, UTimeout, Value};
+use crate::timeouts::blocking_keys_timeout;
+use crate::types::{
+    Count, Index, Key, RedisValueRef, ReturnValue, State, StateRef, UTimeout, Value,
+};
+use crate::write_effects::{touched, untouched};
 use crate::{make_reader, make_writer, op_variants};
+use dashmap::SharedValue;
+use std::collections::VecDeque;
+
+/// Where LINSERT puts the element, relative to the pivot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InsertPosition {
+    Before,
+    After,
+}
+
+/// An end of a list, for LMOVE and BLMOVE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// The options of LPOS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LPosOptions {
+    /// RANK: return matches from the rank-th on. Negative ranks search from the tail.
+    pub rank: Count,
+    /// COUNT: None for just the first match, as an index. Otherwise an array
+    /// of up to this many matches, or every match for zero.
+    pub count: Option<Count>,
+    /// MAXLEN: how many elements to compare, or zero for all of them.
+    pub max_len: Count,
+}
+
+impl Default for LPosOptions {
+    fn default() -> Self {
+        LPosOptions {
+            rank: 1,
+            count: None,
+            max_len: 0,
+        }
+    }
+}
 
 op_variants! {
     ListOps,
     LIndex(Key, Index),
+    LInsert(Key, InsertPosition, Value, Value),
     LLen(Key),
-    LPop(Key),
+    LPop(Key, Option<Count>),
+    LPos(Key, Value, LPosOptions),
     LPush(Key, RVec<Value>),
     LPushX(Key, Value),
     LRange(Key, Index, Index),
+    LRem(Key, Count, Value),
     LSet(Key, Index, Value),
     LTrim(Key, Index, Index),
-    RPop(Key),
+    RPop(Key, Option<Count>),
     RPush(Key, RVec<Value>),
     RPushX(Key, Value),
     RPopLPush(Key, Key),
-    BLPop(Key, UTimeout),
-    BRPop(Key, UTimeout)
+    LMove(Key, Key, ListEnd, ListEnd),
+    BLPop(RVec<Key>, UTimeout),
+    BRPop(RVec<Key>, UTimeout),
+    BLMove(Key, Key, ListEnd, ListEnd, UTimeout)
+}
+
+impl ListOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            ListOps::LIndex(..) | ListOps::LLen(_) | ListOps::LPos(..) | ListOps::LRange(..) => {
+                None
+            }
+            ListOps::LInsert(key, ..)
+            | ListOps::LPop(key, _)
+            | ListOps::LPush(key, _)
+            | ListOps::LPushX(key, _)
+            | ListOps::LRem(key, _, _)
+            | ListOps::LSet(key, _, _)
+            | ListOps::LTrim(key, _, _)
+            | ListOps::RPop(key, _)
+            | ListOps::RPush(key, _)
+            | ListOps::RPushX(key, _) => Some(vec![key.clone()]),
+            ListOps::BLPop(keys, _) | ListOps::BRPop(keys, _) => Some(keys.to_vec()),
+            ListOps::RPopLPush(source, dest)
+            | ListOps::LMove(source, dest, ..)
+            | ListOps::BLMove(source, dest, ..) => Some(vec![source.clone(), dest.clone()]),
+        }
+    }
 }
 
 make_reader!(lists, read_lists);
 make_writer!(lists, write_lists);
 
+/// Delete the list at `key` if it's empty. Must be called without a
+/// reference into `state.lists` held.
+fn remove_if_empty(state: &State, key: &[u8]) {
+    state.lists.remove_if(key, |_, list| list.is_empty());
+}
+
+/// LPOP/RPOP. Without a count, pops one element. With one, pops up to that
+/// many into an array, or replies with a null array if there's no list.
+fn pop(state: &State, key: &[u8], count: Option<Count>, front: bool) -> ReturnValue {
+    let pop_one = |list: &mut VecDeque<Value>| {
+        if front {
+            list.pop_front()
+        } else {
+            list.pop_back()
+        }
+    };
+    let mut list = match write_lists!(state, key) {
+        Some(list) if count.is_none() || !list.is_empty() => list,
+        _ => {
+            untouched();
+            return match count {
+                Some(_) => ReturnValue::Ident(RedisValueRef::NullArray),
+                None => ReturnValue::Nil,
+            };
+        }
+    };
+    let reply = match count {
+        None => match pop_one(&mut list) {
+            Some(value) => ReturnValue::StringRes(value),
+            None => {
+                untouched();
+                ReturnValue::Nil
+            }
+        },
+        Some(count) => {
+            let mut popped = Vec::new();
+            while popped.len() < count as usize {
+                match pop_one(&mut list) {
+                    Some(value) => popped.push(value),
+                    None => break,
+                }
+            }
+            if popped.is_empty() {
+                untouched();
+            }
+            ReturnValue::MultiStringRes(popped)
+        }
+    };
+    drop(list);
+    remove_if_empty(state, key);
+    reply
+}
+
+/// LMOVE: pop from the `from` end of `source` and push onto the `to` end of
+/// `dest`. Returns the moved element, or None if `source` is empty.
+///
+/// Both lists' shards stay write locked for the whole move, so no one sees
+/// the element in neither list. They're locked in shard order, so two moves
+/// going opposite ways can't deadlock, and a shard both lists share is only
+/// locked once.
+fn move_element(
+    state: &State,
+    source: &Key,
+    dest: &Key,
+    from: ListEnd,
+    to: ListEnd,
+) -> Option<Value> {
+    let shards = state.lists.shards();
+    let (source_shard, dest_shard) = (
+        state.lists.determine_map(source),
+        state.lists.determine_map(dest),
+    );
+    let first = source_shard.min(dest_shard);
+    let mut low = shards[first].write();
+    let mut high = if source_shard == dest_shard {
+        None
+    } else {
+        Some(shards[source_shard.max(dest_shard)].write())
+    };
+    let value = {
+        let lists = match high.as_mut() {
+            Some(high) if source_shard != first => high,
+            _ => &mut low,
+        };
+        let list = lists.get_mut(source)?.get_mut();
+        let value = match from {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        }?;
+        if list.is_empty() {
+            lists.remove(source);
+        }
+        value
+    };
+    let lists = match high.as_mut() {
+        Some(high) if dest_shard != first => high,
+        _ => &mut low,
+    };
+    let list = lists
+        .entry(dest.clone())
+        .or_insert_with(|| SharedValue::new(VecDeque::new()))
+        .get_mut();
+    match to {
+        ListEnd::Left => list.push_front(value.clone()),
+        ListEnd::Right => list.push_back(value.clone()),
+    }
+    drop(high);
+    drop(low);
+    state.wake_list(dest);
+    Some(value)
+}
+
+/// The reply to an LMOVE that moved `value`, if any.
+fn moved(value: Option<Value>) -> ReturnValue {
+    match value {
+        Some(value) => ReturnValue::StringRes(value),
+        None => {
+            untouched();
+            ReturnValue::Nil
+        }
+    }
+}
+
+/// BLPOP/BRPOP. Pops from the first non-empty list of `keys`, waiting up to
+/// `timeout` seconds for one. Replies with [key, value], or a null array on timeout.
+async fn blocking_pop(
+    state: StateRef,
+    keys: RVec<Key>,
+    timeout: UTimeout,
+    front: bool,
+) -> ReturnValue {
+    let waiting_on = keys.to_vec();
+    let state_clone = state.clone();
+    let bl = move || {
+        keys.iter().find_map(|key| {
+            let mut list = write_lists!(state, key)?;
+            let value = if front {
+                list.pop_front()
+            } else {
+                list.pop_back()
+            }?;
+            drop(list);
+            remove_if_empty(&state, key);
+            touched(vec![key.clone()]);
+            Some(ReturnValue::MultiStringRes(vec![key.clone(), value]))
+        })
+    };
+    match blocking_keys_timeout(Box::new(bl), state_clone, waiting_on, timeout).await {
+        ReturnValue::Nil => {
+            untouched();
+            ReturnValue::Ident(RedisValueRef::NullArray)
+        }
+        reply => reply,
+    }
+}
+
+/// Remove up to `count` elements equal to `value`, from the head for positive
+/// counts and the tail for negative ones. Zero removes all of them.
+fn remove(list: &mut VecDeque<Value>, count: Count, value: &[u8]) -> usize {
+    let limit = match count {
+        0 => usize::MAX,
+        count => count.unsigned_abs() as usize,
+    };
+    let mut removed = 0;
+    let mut matches = |element: &Value| {
+        let found = removed < limit && element == value;
+        removed += found as usize;
+        found
+    };
+    if count >= 0 {
+        list.retain(|element| !matches(element));
+    } else {
+        let mut kept = VecDeque::with_capacity(list.len());
+        for element in list.drain(..).rev() {
+            if !matches(&element) {
+                kept.push_front(element);
+            }
+        }
+        *list = kept;
+    }
+    removed
+}
+
+/// Indices of the elements equal to `element`, as asked for by LPOS.
+fn positions(list: &VecDeque<Value>, element: &[u8], opts: &LPosOptions) -> Vec<usize> {
+    let scanned = match opts.max_len {
+        0 => list.len(),
+        max_len => (max_len as usize).min(list.len()),
+    };
+    let wanted = match opts.count {
+        None => 1,
+        Some(0) => usize::MAX,
+        Some(count) => count as usize,
+    };
+    let skipped = opts.rank.unsigned_abs() as usize - 1;
+    let found = |index: &usize| list[*index] == element;
+    if opts.rank > 0 {
+        (0..scanned)
+            .filter(found)
+            .skip(skipped)
+            .take(wanted)
+            .collect()
+    } else {
+        (list.len() - scanned..list.len())
+            .rev()
+            .filter(found)
+            .skip(skipped)
+            .take(wanted)
+            .collect()
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub async fn list_interact(list_op: ListOps, state: StateRef) -> ReturnValue {
     match list_op {
@@ -41,7 +324,10 @@ pub async fn list_interact(list_op: ListOps, state: StateRef) -> ReturnValue {
                 state.wake_list(&key);
                 ReturnValue::IntRes(list.len() as Count)
             }
-            None => ReturnValue::IntRes(0),
+            None => {
+                untouched();
+                ReturnValue::IntRes(0)
+            }
         },
         ListOps::RPushX(key, val) => match state.lists.get_mut(&key) {
             Some(mut list) => {
@@ -49,25 +335,61 @@ pub async fn list_interact(list_op: ListOps, state: StateRef) -> ReturnValue {
                 state.wake_list(&key);
                 ReturnValue::IntRes(list.len() as Count)
             }
-            None => ReturnValue::IntRes(0),
+            None => {
+                untouched();
+                ReturnValue::IntRes(0)
+            }
         },
         ListOps::LLen(key) => match read_lists!(state, &key) {
             Some(l) => ReturnValue::IntRes(l.len() as Count),
             None => ReturnValue::IntRes(0),
         },
-        ListOps::LPop(key) => match write_lists!(state, &key).and_then(|mut v| v.pop_front()) {
-            Some(v) => ReturnValue::StringRes(v),
-            None => ReturnValue::Nil,
-        },
-        ListOps::RPop(key) => match write_lists!(state, &key).and_then(|mut v| v.pop_back()) {
-            Some(v) => ReturnValue::StringRes(v),
-            None => ReturnValue::Nil,
+        ListOps::LPop(key, count) => pop(&state, &key, count, true),
+        ListOps::RPop(key, count) => pop(&state, &key, count, false),
+        ListOps::LInsert(key, position, pivot, value) => match write_lists!(state, &key) {
+            Some(mut list) => match list.iter().position(|element| *element == pivot) {
+                Some(index) => {
+                    let index = match position {
+                        InsertPosition::Before => index,
+                        InsertPosition::After => index + 1,
+                    };
+                    list.insert(index, value);
+                    ReturnValue::IntRes(list.len() as Count)
+                }
+                None => ReturnValue::IntRes(-1),
+            },
+            None => ReturnValue::IntRes(0),
         },
+        ListOps::LRem(key, count, value) => {
+            let removed = match write_lists!(state, &key) {
+                Some(mut list) => remove(&mut list, count, &value),
+                None => 0,
+            };
+            remove_if_empty(&state, &key);
+            if removed == 0 {
+                untouched();
+            }
+            ReturnValue::IntRes(removed as Count)
+        }
+        ListOps::LPos(key, element, opts) => {
+            let found = match read_lists!(state, &key) {
+                Some(list) => positions(&list, &element, &opts),
+                None => Vec::new(),
+            };
+            let mut found = found
+                .into_iter()
+                .map(|index| ReturnValue::IntRes(index as Count));
+            match opts.count {
+                Some(_) => ReturnValue::Array(found.collect()),
+                None => found.next().unwrap_or(ReturnValue::Nil),
+            }
+        }
         ListOps::RPush(key, vals) => {
-            let mut list = state.lists.entry(key).or_default();
+            let mut list = state.lists.entry(key.clone()).or_default();
             for val in vals {
                 list.push_back(val)
             }
+            state.wake_list(&key);
             ReturnValue::IntRes(list.len() as Count)
         }
         ListOps::LIndex(key, index) => match write_lists!(state, &key) {
@@ -121,67 +443,304 @@ pub async fn list_interact(list_op: ListOps, state: StateRef) -> ReturnValue {
             None => ReturnValue::MultiStringRes(vec![]),
         },
         ListOps::LTrim(key, start_index, end_index) => {
-            match write_lists!(state, &key) {
-                Some(mut list) => {
-                    let start_index =
-                        std::cmp::max(0, if start_index < 0 { 0 } else { start_index } as usize);
-                    let end_index = std::cmp::min(
-                        list.len(),
-                        if end_index < 0 {
-                            list.len() as i64 + end_index
-                        } else {
-                            end_index
-                        } as usize,
-                    ) + 1;
-                    // Deal with right side
-                    list.truncate(end_index);
-                    // Deal with left side
-                    for _ in 0..start_index {
-                        list.pop_front();
-                    }
-                    ReturnValue::Ok
+            if let Some(mut list) = write_lists!(state, &key) {
+                let start_index =
+                    std::cmp::max(0, if start_index < 0 { 0 } else { start_index } as usize);
+                let end_index = std::cmp::min(
+                    list.len(),
+                    if end_index < 0 {
+                        list.len() as i64 + end_index
+                    } else {
+                        end_index
+                    } as usize,
+                ) + 1;
+                // Deal with right side
+                list.truncate(end_index);
+                // Deal with left side
+                for _ in 0..start_index {
+                    list.pop_front();
                 }
-                None => ReturnValue::Ok,
             }
+            remove_if_empty(&state, &key);
+            ReturnValue::Ok
         }
-        ListOps::RPopLPush(source, dest) => match state.lists.get_mut(&source) {
-            None => ReturnValue::Nil,
-            Some(mut source_list) => match source_list.pop_back() {
-                None => ReturnValue::Nil,
-                Some(value) => {
-                    if source == dest {
-                        source_list.push_back(value.clone());
-                    } else {
-                        state
-                            .lists
-                            .entry(dest.clone())
-                            .or_default()
-                            .push_back(value.clone());
-                        state.wake_list(&dest);
-                    }
-                    ReturnValue::StringRes(value)
-                }
-            },
-        },
-        ListOps::BLPop(key, timeout) => {
-            let state_clone = state.clone();
-            let key_clone = key.clone();
-            let bl = move || {
-                write_lists!(state, &key)
-                    .and_then(|mut v| v.pop_front())
-                    .map(ReturnValue::StringRes)
-            };
-            blocking_key_timeout(Box::new(bl), state_clone, key_clone, timeout).await
+        ListOps::RPopLPush(source, dest) => {
+            let value = move_element(&state, &source, &dest, ListEnd::Right, ListEnd::Left);
+            moved(value)
+        }
+        ListOps::LMove(source, dest, from, to) => {
+            moved(move_element(&state, &source, &dest, from, to))
         }
-        ListOps::BRPop(key, timeout) => {
+        ListOps::BLPop(keys, timeout) => blocking_pop(state, keys, timeout, true).await,
+        ListOps::BRPop(keys, timeout) => blocking_pop(state, keys, timeout, false).await,
+        ListOps::BLMove(source, dest, from, to, timeout) => {
             let state_clone = state.clone();
-            let key_clone = key.clone();
-            let bl = move || {
-                write_lists!(state, &key)
-                    .and_then(|mut v| v.pop_back())
-                    .map(ReturnValue::StringRes)
-            };
-            blocking_key_timeout(Box::new(bl), state_clone, key_clone, timeout).await
+            let waiting_on = vec![source.clone()];
+            let bl =
+                move || move_element(&state, &source, &dest, from, to).map(ReturnValue::StringRes);
+            let reply = blocking_keys_timeout(Box::new(bl), state_clone, waiting_on, timeout).await;
+            if reply == ReturnValue::Nil {
+                untouched();
+            }
+            reply
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_lists {
+    use crate::lists::{
+        list_interact, move_element, InsertPosition, LPosOptions, ListEnd, ListOps,
+    };
+    use crate::types::{RedisValueRef, ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    fn list_of(state: &StateRef, key: &Bytes, values: &[&'static str]) {
+        let values = values.iter().map(|v| Bytes::from_static(v.as_bytes()));
+        state.lists.insert(key.clone(), values.collect());
+    }
+
+    fn strings(values: &[&'static str]) -> ReturnValue {
+        let values = values.iter().map(|v| Bytes::from_static(v.as_bytes()));
+        ReturnValue::MultiStringRes(values.collect())
+    }
+
+    fn ints(values: &[i64]) -> ReturnValue {
+        ReturnValue::Array(values.iter().map(|i| ReturnValue::IntRes(*i)).collect())
+    }
+
+    #[tokio::test]
+    async fn test_pop_count() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"l");
+        list_of(&state, &key, &["a", "b", "c", "d"]);
+        let res = list_interact(ListOps::LPop(key.clone(), Some(2)), state.clone()).await;
+        assert_eq!(res, strings(&["a", "b"]));
+        let res = list_interact(ListOps::RPop(key.clone(), Some(5)), state.clone()).await;
+        assert_eq!(res, strings(&["d", "c"]));
+        let res = list_interact(ListOps::RPop(key.clone(), Some(1)), state.clone()).await;
+        assert_eq!(res, ReturnValue::Ident(RedisValueRef::NullArray));
+        let res = list_interact(ListOps::LPop(key, None), state).await;
+        assert_eq!(res, ReturnValue::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_emptied_lists_are_removed() {
+        let state: StateRef = Arc::new(State::default());
+        let (key, dest) = (Bytes::from_static(b"l"), Bytes::from_static(b"d"));
+        list_of(&state, &key, &["a", "b"]);
+        let op = ListOps::LMove(key.clone(), dest.clone(), ListEnd::Left, ListEnd::Left);
+        list_interact(op, state.clone()).await;
+        let op = ListOps::LRem(key.clone(), 0, Bytes::from_static(b"b"));
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert!(!state.lists.contains_key(&key));
+        list_interact(ListOps::LTrim(dest.clone(), 1, 0), state.clone()).await;
+        assert!(!state.lists.contains_key(&dest));
+    }
+
+    #[tokio::test]
+    async fn test_linsert() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"l");
+        let (a, x) = (Bytes::from_static(b"a"), Bytes::from_static(b"x"));
+        list_of(&state, &key, &["a", "b"]);
+        let op = ListOps::LInsert(key.clone(), InsertPosition::After, a.clone(), x.clone());
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::IntRes(3)
+        );
+        let op = ListOps::LInsert(key.clone(), InsertPosition::Before, a.clone(), x.clone());
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::IntRes(4)
+        );
+        let op = ListOps::LInsert(key.clone(), InsertPosition::Before, key.clone(), x.clone());
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::IntRes(-1)
+        );
+        let missing = Bytes::from_static(b"missing");
+        let op = ListOps::LInsert(missing, InsertPosition::Before, a, x);
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        let res = list_interact(ListOps::LRange(key, 0, -1), state).await;
+        assert_eq!(res, strings(&["x", "a", "x", "b"]));
+    }
+
+    #[tokio::test]
+    async fn test_lrem() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"l");
+        let a = Bytes::from_static(b"a");
+        let cases: &[(i64, i64, &[&'static str])] = &[
+            (2, 2, &["b", "c", "a", "a"]),
+            (-2, 2, &["a", "b", "a", "c"]),
+            (0, 4, &["b", "c"]),
+        ];
+        for (count, removed, left) in cases {
+            list_of(&state, &key, &["a", "b", "a", "c", "a", "a"]);
+            let op = ListOps::LRem(key.clone(), *count, a.clone());
+            assert_eq!(
+                list_interact(op, state.clone()).await,
+                ReturnValue::IntRes(*removed)
+            );
+            let res = list_interact(ListOps::LRange(key.clone(), 0, -1), state.clone()).await;
+            assert_eq!(res, strings(left));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lpos() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"l");
+        let a = Bytes::from_static(b"a");
+        list_of(&state, &key, &["a", "b", "c", "1", "2", "3", "c", "c"]);
+        let lpos = |element: &'static str, opts: LPosOptions| {
+            let op = ListOps::LPos(key.clone(), Bytes::from_static(element.as_bytes()), opts);
+            list_interact(op, state.clone())
+        };
+        assert_eq!(lpos("c", Default::default()).await, ReturnValue::IntRes(2));
+        assert_eq!(lpos("x", Default::default()).await, ReturnValue::Nil);
+        let opts = LPosOptions {
+            rank: 2,
+            ..Default::default()
+        };
+        assert_eq!(lpos("c", opts).await, ReturnValue::IntRes(6));
+        let opts = LPosOptions {
+            count: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(lpos("c", opts).await, ints(&[2, 6, 7]));
+        let opts = LPosOptions {
+            rank: -1,
+            count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(lpos("c", opts).await, ints(&[7, 6]));
+        let opts = LPosOptions {
+            count: Some(0),
+            max_len: 3,
+            ..Default::default()
+        };
+        assert_eq!(lpos("c", opts).await, ints(&[2]));
+        let op = ListOps::LPos(Bytes::from_static(b"missing"), a, Default::default());
+        assert_eq!(list_interact(op, state.clone()).await, ReturnValue::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_multiple_keys() {
+        let state: StateRef = Arc::new(State::default());
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        list_of(&state, &b, &["1", "2"]);
+        // The first non-empty list is popped, and named in the reply.
+        let op = ListOps::BRPop(smallvec![a.clone(), b.clone()], 0);
+        assert_eq!(list_interact(op, state.clone()).await, strings(&["b", "2"]));
+        let op = ListOps::BLPop(smallvec![a.clone()], 0);
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::Ident(RedisValueRef::NullArray)
+        );
+
+        // A push to any of the keys wakes the client.
+        let op = ListOps::BLPop(smallvec![a.clone(), Bytes::from_static(b"c")], 5);
+        let blocked = tokio::spawn(list_interact(op, state.clone()));
+        tokio::task::yield_now().await;
+        let push = ListOps::RPush(Bytes::from_static(b"c"), smallvec![a.clone()]);
+        list_interact(push, state.clone()).await;
+        assert_eq!(blocked.await.unwrap(), strings(&["c", "a"]));
+    }
+
+    #[tokio::test]
+    async fn test_lmove() {
+        let state: StateRef = Arc::new(State::default());
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        list_of(&state, &a, &["1", "2", "3"]);
+        let op = ListOps::LMove(a.clone(), b.clone(), ListEnd::Left, ListEnd::Right);
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::StringRes("1".into())
+        );
+        let op = ListOps::LMove(a.clone(), b.clone(), ListEnd::Right, ListEnd::Left);
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::StringRes("3".into())
+        );
+        // Moving within one list rotates it.
+        list_interact(
+            ListOps::RPush(a.clone(), smallvec![Bytes::from_static(b"4")]),
+            state.clone(),
+        )
+        .await;
+        let op = ListOps::LMove(a.clone(), a.clone(), ListEnd::Left, ListEnd::Right);
+        assert_eq!(
+            list_interact(op, state.clone()).await,
+            ReturnValue::StringRes("2".into())
+        );
+        let res = list_interact(ListOps::LRange(a.clone(), 0, -1), state.clone()).await;
+        assert_eq!(res, strings(&["4", "2"]));
+        let res = list_interact(ListOps::LRange(b.clone(), 0, -1), state.clone()).await;
+        assert_eq!(res, strings(&["3", "1"]));
+        let missing = Bytes::from_static(b"missing");
+        let op = ListOps::LMove(missing, b, ListEnd::Left, ListEnd::Left);
+        assert_eq!(list_interact(op, state).await, ReturnValue::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_blmove_wakes_on_source_and_destination() {
+        let state: StateRef = Arc::new(State::default());
+        let (a, b, c) = (
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"c"),
+        );
+        let op = ListOps::BLMove(a.clone(), b.clone(), ListEnd::Right, ListEnd::Left, 0);
+        assert_eq!(list_interact(op, state.clone()).await, ReturnValue::Nil);
+
+        // One client waits to move a -> b, another to pop from b.
+        let op = ListOps::BLMove(a.clone(), b.clone(), ListEnd::Right, ListEnd::Left, 5);
+        let mover = tokio::spawn(list_interact(op, state.clone()));
+        let popper = tokio::spawn(list_interact(
+            ListOps::BLPop(smallvec![b.clone()], 5),
+            state.clone(),
+        ));
+        tokio::task::yield_now().await;
+        list_interact(
+            ListOps::LPush(a.clone(), smallvec![c.clone()]),
+            state.clone(),
+        )
+        .await;
+        assert_eq!(mover.await.unwrap(), ReturnValue::StringRes(c));
+        assert_eq!(popper.await.unwrap(), strings(&["b", "c"]));
+    }
+
+    #[test]
+    fn test_opposite_moves_keep_every_element() {
+        let state: StateRef = Arc::new(State::default());
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        list_of(&state, &a, &["1", "2", "3", "4"]);
+        list_of(&state, &b, &["5", "6", "7", "8"]);
+        let movers: Vec<_> = vec![(a.clone(), b.clone()), (b.clone(), a.clone())]
+            .into_iter()
+            .map(|(source, dest)| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        move_element(&state, &source, &dest, ListEnd::Left, ListEnd::Right);
+                    }
+                })
+            })
+            .collect();
+        for mover in movers {
+            mover.join().unwrap();
         }
+        let len = |key| state.lists.get(key).map_or(0, |list| list.len());
+        assert_eq!(len(&a) + len(&b), 8);
     }
 }
@@ -48,6 +48,14 @@ macro_rules! op_variants {
             #[derive(Debug, Clone)]
             pub enum $name { $($variant_name($($arg),*),)* }
         }
+        impl $name {
+            /// Name of the variant, like "LPush".
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $($name::$variant_name(..) => stringify!($variant_name),)*
+                }
+            }
+        }
     };
 }
 
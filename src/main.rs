@@ -1,9 +1,24 @@
-use redis_oxide::database::{get_dump_file, load_state};
+use redis_oxide::bulk_load::bulk_load;
+use redis_oxide::command_file::validate_commands;
+use redis_oxide::compat::compat_summary;
+use redis_oxide::database::{
+    export_rdb, get_dump_file, load_state, replay_aof, save_now, save_state_interval, start_aof,
+};
+use redis_oxide::expiry::expire_keys_interval;
+#[cfg(unix)]
+use redis_oxide::handoff::{handoff_listener, receive_handoff};
 use redis_oxide::logger::LOGGER;
-use redis_oxide::scripting::{handle_redis_cmd, ScriptingBridge};
+use redis_oxide::replication::{parse_primary, replicaof};
+use redis_oxide::scripting::{handle_redis_cmd, ScriptingBridge, ScriptingEngine};
 use redis_oxide::server::socket_listener;
-use redis_oxide::startup::{startup_message, Config};
-use redis_oxide::{database::save_state_interval, scripting::ScriptingEngine};
+use redis_oxide::shutdown::shutdown_on_signal;
+use redis_oxide::startup::{generate_completions, startup_message, Config};
+use redis_oxide::types::StateStore;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::mpsc::channel;
 #[macro_use]
 extern crate slog;
@@ -14,16 +29,119 @@ use structopt::StructOpt;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // 1. Get the args.
     let opt = Config::from_args();
+    if let Some(target) = &opt.generate_completions {
+        generate_completions(target, &mut std::io::stdout())?;
+        return Ok(());
+    }
+    if let Some(path) = &opt.validate_commands {
+        let file = BufReader::new(File::open(path)?);
+        let errors = validate_commands(file, Arc::new(StateStore::default()))?;
+        for (line, e) in &errors {
+            eprintln!("{}:{}: {}", path.display(), line, e);
+        }
+        if !errors.is_empty() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     // 2. Print the fancy logo.
     startup_message(&opt);
     // 3. Get the database file, making folders if necessary.
     info!(LOGGER, "Initializing State...");
     let dump_file = get_dump_file(&opt);
     // 4. Load database state if it exists.
-    info!(LOGGER, "Opening Datafile...");
-    let state = load_state(dump_file.clone(), &opt)?;
-    // 5. Spawn the save-occasionally service.
+    let state = match &opt.handoff_from {
+        #[cfg(unix)]
+        Some(path) => {
+            info!(LOGGER, "Taking over state from {:?}...", path);
+            receive_handoff(path, &opt).await?
+        }
+        _ => {
+            info!(LOGGER, "Opening Datafile...");
+            load_state(dump_file.clone(), &opt)?
+        }
+    };
+    if let Some(path) = &opt.export_rdb {
+        export_rdb(&state, path)?;
+        info!(LOGGER, "Exported the state to {:?}", path);
+        return Ok(());
+    }
+    if !opt.memory_only {
+        // Lets SAVE and BGSAVE find it.
+        let _ = state.dump_file.set(dump_file.clone());
+    }
+    let (implemented, partial, missing) = compat_summary(state.clone());
+    info!(
+        LOGGER,
+        "Redis compatibility: {} commands implemented, {} partially, {} missing (see OXIDE COMPAT)",
+        implemented,
+        partial,
+        missing
+    );
+    // 5. Create the channels for scripting
+    let (prog_string_sx, prog_string_rx) = channel(12);
+    let (cmd_result_sx, cmd_result_rx) = channel(12);
+
+    let scripting_bridge = ScriptingBridge::new(prog_string_sx);
+
+    match ScriptingEngine::new(
+        prog_string_rx,
+        cmd_result_sx,
+        state.clone(),
+        dump_file.clone(),
+        scripting_bridge.clone(),
+        &opt,
+    ) {
+        Ok(scripting_engine) => {
+            info!(LOGGER, "ScriptingEngine main loop started");
+            std::thread::spawn(|| scripting_engine.main_loop());
+        }
+        Err(e) => {
+            warn!(
+                LOGGER,
+                "Scripting disabled, the engine failed to start: {}", e
+            );
+            state.scripting_disabled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    tokio::spawn(handle_redis_cmd(
+        cmd_result_rx,
+        state.clone(),
+        dump_file.clone(),
+        scripting_bridge.clone(),
+    ));
+
+    // 6. Replay the AOF (unless the state was handed off), then bulk load from
+    //    stdin if asked to, before anything is served or saved.
+    if opt.appendonly && opt.handoff_from.is_none() {
+        info!(LOGGER, "Replaying the AOF...");
+        replay_aof(state.clone(), dump_file.clone(), scripting_bridge.clone()).await?;
+    }
+    if opt.pipe {
+        info!(LOGGER, "Loading commands from stdin...");
+        let start = Instant::now();
+        let report = bulk_load(tokio::io::stdin(), state.clone(), scripting_bridge.clone()).await?;
+        info!(
+            LOGGER,
+            "Loaded {} commands ({} errors) in {:?}",
+            report.commands,
+            report.errors,
+            start.elapsed()
+        );
+        if !opt.memory_only {
+            save_now(&state, &dump_file)?;
+        }
+    }
+    if opt.appendonly {
+        start_aof(state.clone(), dump_file.clone(), &opt)?;
+    }
+
+    // 7. Spawn the expiry sweep, the save-occasionally service and the
+    //    signal handler.
     info!(LOGGER, "Starting Server...");
+    tokio::spawn(expire_keys_interval(state.clone()));
+    tokio::spawn(shutdown_on_signal(state.clone()));
     if !opt.memory_only {
         info!(LOGGER, "Spawning database saving task...");
         tokio::spawn(save_state_interval(state.clone(), dump_file.clone()));
@@ -33,26 +151,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "Database is in memory-only mode. STATE WILL NOT BE SAVED!"
         );
     }
-    // 6. Create the channels for scripting
-    let (prog_string_sx, prog_string_rx) = channel(12);
-    let (cmd_result_sx, cmd_result_rx) = channel(12);
 
-    let scripting_engine =
-        ScriptingEngine::new(prog_string_rx, cmd_result_sx, state.clone(), &opt)?;
+    #[cfg(feature = "http")]
+    if let Some(port) = opt.http_port {
+        tokio::spawn(redis_oxide::http::serve(
+            port,
+            state.clone(),
+            dump_file.clone(),
+            scripting_bridge.clone(),
+        ));
+    }
+    #[cfg(not(feature = "http"))]
+    if opt.http_port.is_some() {
+        return Err(
+            "redis-oxide was built without the HTTP API (enable the \"http\" feature)".into(),
+        );
+    }
 
-    info!(LOGGER, "ScriptingEngine main loop started");
-    std::thread::spawn(|| scripting_engine.main_loop());
+    #[cfg(not(feature = "tls"))]
+    if opt.tls_cert.is_some() {
+        return Err("redis-oxide was built without TLS (enable the \"tls\" feature)".into());
+    }
 
-    let scripting_bridge = ScriptingBridge::new(prog_string_sx);
+    if let Some(primary) = &opt.replicaof {
+        replicaof(&state, &scripting_bridge, Some(parse_primary(primary)?));
+    }
 
-    tokio::spawn(handle_redis_cmd(
-        cmd_result_rx,
-        state.clone(),
-        dump_file.clone(),
-        scripting_bridge.clone(),
-    ));
+    #[cfg(unix)]
+    if let Some(path) = opt.handoff_socket.clone() {
+        tokio::spawn(handoff_listener(path, state.clone()));
+    }
 
-    // 7. Start the server! It will start listening for connections.
+    // 8. Start the server! It will start listening for connections.
     socket_listener(state.clone(), dump_file.clone(), opt, scripting_bridge).await;
     Ok(())
 }
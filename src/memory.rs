@@ -0,0 +1,661 @@
+//! Rough memory usage estimates for stored values.
+//!
+//! Estimates count the bytes we store, and the structures holding them:
+//! each collection's own header, its slots (spare capacity included) and a
+//! fixed overhead per node of linked structures. They don't try to model
+//! allocator slack. Good enough to find what's big, not to budget memory
+//! exactly.
+
+use crate::coop::{collect_keys, visit_entries, Budget};
+use crate::data_structures::bloom_filter::BloomFilter;
+use crate::data_structures::count_min_sketch::CountMinSketch;
+use crate::data_structures::cuckoo_filter::CuckooFilter;
+use crate::data_structures::heavy_keeper::TopK;
+use crate::data_structures::queue::Queue;
+use crate::data_structures::sorted_set::{SortedSet, SortedSetMember};
+use crate::data_structures::stack::Stack;
+use crate::data_structures::stream::Stream;
+use crate::expiry::now_ms;
+use crate::for_each_structure;
+use crate::state::sample_keys;
+use crate::types::{Key, Score, State, StateRef};
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Bookkeeping bytes charged per node of a linked structure, like the
+/// skiplist of sorted sets.
+pub const ELEMENT_OVERHEAD: usize = size_of::<Bytes>();
+
+/// Bytes of a hash table's slots for `capacity` `T`s, used or not, each
+/// with its control byte.
+fn table_size<T>(capacity: usize) -> usize {
+    capacity * (size_of::<T>() + 1)
+}
+
+/// Total of `len` element sizes, extrapolated from the first `samples` of
+/// them like MEMORY USAGE's SAMPLES. 0 samples adds up every element.
+fn sampled_sum(len: usize, sizes: impl Iterator<Item = usize>, samples: usize) -> usize {
+    if samples == 0 || len <= samples {
+        return sizes.sum();
+    }
+    sizes.take(samples).sum::<usize>() * len / samples
+}
+
+pub trait EstimateSize {
+    /// Approximate bytes used by this value, excluding its key.
+    fn estimated_size(&self) -> usize;
+
+    /// Like `estimated_size`, but collections only look at `samples` of
+    /// their elements (all of them if 0).
+    fn sampled_size(&self, _samples: usize) -> usize {
+        self.estimated_size()
+    }
+
+    /// How many elements a collection holds, found without walking it.
+    /// None for values whose estimate doesn't walk them.
+    fn elements(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Strings are counted by their contents alone: their handle lives in the
+/// keyspace's own slot.
+impl EstimateSize for Bytes {
+    fn estimated_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl EstimateSize for HashSet<Bytes> {
+    fn estimated_size(&self) -> usize {
+        self.sampled_size(0)
+    }
+
+    fn sampled_size(&self, samples: usize) -> usize {
+        let members = sampled_sum(self.len(), self.iter().map(|m| m.len()), samples);
+        size_of::<Self>() + table_size::<Bytes>(self.capacity()) + members
+    }
+
+    fn elements(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl EstimateSize for VecDeque<Bytes> {
+    fn estimated_size(&self) -> usize {
+        self.sampled_size(0)
+    }
+
+    fn sampled_size(&self, samples: usize) -> usize {
+        let items = sampled_sum(self.len(), self.iter().map(|m| m.len()), samples);
+        size_of::<Self>() + self.capacity() * size_of::<Bytes>() + items
+    }
+
+    fn elements(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl EstimateSize for HashMap<Bytes, Bytes> {
+    fn estimated_size(&self) -> usize {
+        self.sampled_size(0)
+    }
+
+    fn sampled_size(&self, samples: usize) -> usize {
+        let sizes = self.iter().map(|(k, v)| k.len() + v.len());
+        let pairs = sampled_sum(self.len(), sizes, samples);
+        size_of::<Self>() + table_size::<(Bytes, Bytes)>(self.capacity()) + pairs
+    }
+
+    fn elements(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+/// Members are kept in a hash of their scores and a skiplist by score, so
+/// each name is stored twice.
+impl EstimateSize for SortedSet {
+    fn estimated_size(&self) -> usize {
+        self.sampled_size(0)
+    }
+
+    fn sampled_size(&self, samples: usize) -> usize {
+        let card = self.card() as usize;
+        let per_member =
+            table_size::<(Key, Score)>(1) + size_of::<SortedSetMember>() + ELEMENT_OVERHEAD;
+        let names = sampled_sum(card, self.iter().map(|(k, _)| 2 * k.len()), samples);
+        size_of::<Self>() + names + card * per_member
+    }
+
+    fn elements(&self) -> Option<usize> {
+        Some(self.card() as usize)
+    }
+}
+
+impl EstimateSize for Stack<Bytes> {
+    fn estimated_size(&self) -> usize {
+        self.sampled_size(0)
+    }
+
+    fn sampled_size(&self, samples: usize) -> usize {
+        let items = sampled_sum(self.size() as usize, self.iter().map(|m| m.len()), samples);
+        size_of::<Self>() + self.capacity() * size_of::<Bytes>() + items
+    }
+
+    fn elements(&self) -> Option<usize> {
+        Some(self.size() as usize)
+    }
+}
+
+impl EstimateSize for Queue {
+    fn estimated_size(&self) -> usize {
+        size_of::<Queue>() + self.size()
+    }
+
+    fn elements(&self) -> Option<usize> {
+        Some(self.len() + self.pending_len())
+    }
+}
+
+impl EstimateSize for Stream {
+    fn estimated_size(&self) -> usize {
+        size_of::<Stream>() + self.size()
+    }
+
+    fn elements(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+/// Filters don't expose their internals, so their bits are estimated from
+/// the settings and item count.
+impl EstimateSize for BloomFilter {
+    fn estimated_size(&self) -> usize {
+        size_of::<BloomFilter>() + self.size()
+    }
+}
+
+impl EstimateSize for CuckooFilter {
+    fn estimated_size(&self) -> usize {
+        size_of::<CuckooFilter>() + self.size()
+    }
+}
+
+impl EstimateSize for CountMinSketch {
+    fn estimated_size(&self) -> usize {
+        size_of::<CountMinSketch>() + self.size()
+    }
+}
+
+impl EstimateSize for TopK {
+    fn estimated_size(&self) -> usize {
+        size_of::<TopK>() + self.size()
+    }
+}
+
+impl EstimateSize for amadeus_streaming::HyperLogLog<Bytes> {
+    fn estimated_size(&self) -> usize {
+        size_of::<amadeus_streaming::HyperLogLog<Bytes>>()
+    }
+}
+
+/// Estimated bytes used by `key` and its value, whichever structure holds it.
+macro_rules! find_key_usage {
+    ($state:expr, $key:expr, $samples:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            if let Some(value) = $state.$type.get($key) {
+                return Some($key.len() + value.sampled_size($samples));
+            }
+        )*
+    };
+}
+
+/// Estimated bytes used by `key`, without accounting for shared buffers.
+pub fn key_usage(state: &State, key: &Key) -> Option<usize> {
+    sampled_key_usage(state, key, 0)
+}
+
+/// Like `key_usage`, only looking at `samples` elements of collections.
+pub fn sampled_key_usage(state: &State, key: &Key, samples: usize) -> Option<usize> {
+    for_each_structure!(find_key_usage!(state, key, samples,));
+    None
+}
+
+/// Which structure holds `key`, and how many elements its value has.
+macro_rules! find_key_shape {
+    ($state:expr, $key:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            if let Some(value) = $state.$type.get($key) {
+                return Some((stringify!($type), value.elements()));
+            }
+        )*
+    };
+}
+
+/// The structure holding `key` and its `EstimateSize::elements`, without
+/// walking its value.
+fn key_shape(state: &State, key: &Key) -> Option<(&'static str, Option<usize>)> {
+    for_each_structure!(find_key_shape!(state, key,));
+    None
+}
+
+/// Whether two values are views of the same bytes, e.g. one was cloned from the other.
+fn same_buffer(a: &Bytes, b: &Bytes) -> bool {
+    a.as_ptr() == b.as_ptr() && a.len() == b.len()
+}
+
+/// How many string values share `key`'s buffer, itself included (like
+/// OBJECT REFCOUNT). Walks every string, so it's only meant for diagnostics.
+pub async fn string_refcount(state: &State, key: &Key, budget: &mut Budget) -> Option<usize> {
+    let value = state.kv.get(key)?.value().clone();
+    let mut count = 0;
+    visit_entries(&state.kv, budget, |_, other| {
+        if same_buffer(&value, other) {
+            count += 1;
+        }
+    })
+    .await;
+    Some(count)
+}
+
+/// Like `sampled_key_usage`, but only charges a string for its share of a
+/// shared buffer.
+pub async fn attributed_usage(
+    state: &State,
+    key: &Key,
+    samples: usize,
+    budget: &mut Budget,
+) -> Option<usize> {
+    let value_len = match state.kv.get(key) {
+        Some(value) => value.len(),
+        None => return sampled_key_usage(state, key, samples),
+    };
+    // The key may be deleted while we count; charge it as unshared then.
+    let refcount = string_refcount(state, key, budget)
+        .await
+        .unwrap_or(1)
+        .max(1);
+    Some(key.len() + (value_len + refcount - 1) / refcount)
+}
+
+/// Add up the keys and estimated bytes of each passed structure.
+macro_rules! tally_structures {
+    ($state:expr, $budget:expr, $tally:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            let (mut keys, mut bytes) = (0, 0);
+            visit_entries(&$state.$type, $budget, |key, value| {
+                keys += 1;
+                bytes += key.len() + value.estimated_size();
+            })
+            .await;
+            $tally.push((stringify!($type), keys, bytes));
+        )*
+    };
+}
+
+/// How many keys each structure of `state` holds, and their estimated bytes,
+/// for MEMORY STATS. Walks every key, yielding as it goes.
+pub async fn structure_usage(
+    state: &State,
+    budget: &mut Budget,
+) -> Vec<(&'static str, usize, usize)> {
+    let mut tally = Vec::new();
+    for_each_structure!(tally_structures!(state, budget, tally,));
+    tally
+}
+
+/// Collect the keys of each passed structure, yielding between shards.
+macro_rules! collect_structure_keys {
+    ($state:expr, $budget:expr, $keys:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            collect_keys(&$state.$type, $budget, $keys).await;
+        )*
+    };
+}
+
+/// What's tracked of each key of a db, for maxmemory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyUsage {
+    /// Estimated bytes of the key and its value.
+    pub bytes: usize,
+    /// Unix ms the key was last used, or 0 if it wasn't since it was counted.
+    pub accessed: u64,
+    /// The structure holding the key, and its element count, when `bytes`
+    /// was worked out.
+    structure: &'static str,
+    elements: Option<usize>,
+}
+
+/// Collections with more elements than this aren't walked when written.
+const RESCALE_PAST: usize = 128;
+
+/// The estimated size of every key of a db, and when each was last used.
+///
+/// A db is only tracked while maxmemory is set: it's counted from scratch
+/// the first time `used` is asked for, and forgotten again by `stop`. A
+/// flush starts it over from nothing, and `recount_in_background` then
+/// picks up whatever keys the db has again. A swapped db keeps its keys, so
+/// its tracker goes with it as is.
+///
+/// Written keys are re-estimated, but a big collection isn't walked again:
+/// its last estimate is scaled by the change in its element count, so each
+/// element added or removed counts as an average one. Elements replaced
+/// with bigger or smaller ones go unnoticed until the db is counted again.
+#[derive(Debug, Default)]
+pub struct MemoryTracker {
+    keys: DashMap<Key, KeyUsage>,
+    total: AtomicUsize,
+    counted: AtomicBool,
+}
+
+impl MemoryTracker {
+    /// Estimated bytes used by every key of `state`.
+    pub fn used(&self, state: &State) -> usize {
+        if !self.counted.swap(true, Ordering::SeqCst) {
+            self.keys.clear();
+            self.total.store(0, Ordering::SeqCst);
+            for key in state.keys() {
+                self.update_key(state, &key, 0);
+            }
+        }
+        self.total.load(Ordering::SeqCst)
+    }
+
+    /// Estimated bytes used by every key, if they're being tracked.
+    pub fn tracked(&self) -> Option<usize> {
+        if self.counted.load(Ordering::SeqCst) {
+            Some(self.total.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    /// Stop tracking until `used` is asked for again.
+    pub fn stop(&self) {
+        self.counted.store(false, Ordering::SeqCst);
+        self.keys.clear();
+        self.total.store(0, Ordering::SeqCst);
+    }
+
+    /// Forget every key, still tracking: the db was flushed.
+    fn reset(&self) {
+        self.keys.clear();
+        self.total.store(0, Ordering::SeqCst);
+    }
+
+    /// Estimate every key of `state` again, yielding as it goes. Keys
+    /// written meanwhile are tracked as usual.
+    pub async fn recount(&self, state: &State, budget: &mut Budget) {
+        if !self.counted.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut keys = Vec::new();
+        for_each_structure!(collect_structure_keys!(state, budget, &mut keys,));
+        for key in keys {
+            let accessed = self.usage_of(&key).map_or(0, |usage| usage.accessed);
+            self.update_key(state, &key, accessed);
+            budget.spend(1).await;
+        }
+    }
+
+    /// `key`'s usage now, from its last estimate if it's a big collection.
+    fn estimate(&self, state: &State, key: &Key, accessed: u64) -> Option<KeyUsage> {
+        let (structure, elements) = key_shape(state, key)?;
+        let last = self.keys.get(key).map(|usage| *usage);
+        let rescaled = match (last, elements) {
+            (Some(last), Some(len)) if last.structure == structure && len > RESCALE_PAST => {
+                match last.elements {
+                    Some(last_len) if last_len > 0 => {
+                        let bytes = last.bytes as u128 * len as u128 / last_len as u128;
+                        Some(bytes as usize)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let bytes = match rescaled {
+            Some(bytes) => bytes,
+            None => key_usage(state, key)?,
+        };
+        Some(KeyUsage {
+            bytes,
+            accessed,
+            structure,
+            elements,
+        })
+    }
+
+    fn update_key(&self, state: &State, key: &Key, accessed: u64) {
+        let old = match self.estimate(state, key, accessed) {
+            Some(usage) => {
+                self.total.fetch_add(usage.bytes, Ordering::SeqCst);
+                self.keys.insert(key.clone(), usage)
+            }
+            None => self.keys.remove(key).map(|(_, usage)| usage),
+        };
+        if let Some(old) = old {
+            self.total.fetch_sub(old.bytes, Ordering::SeqCst);
+        }
+    }
+
+    /// Re-estimate `keys` after they were written. An empty `keys` means
+    /// every key was, as for flushes: every estimate is dropped, for
+    /// `recount_in_background` to make again. Big collections aren't
+    /// walked, see `MemoryTracker`.
+    pub fn written(&self, state: &State, keys: &[Key]) {
+        if !self.counted.load(Ordering::SeqCst) {
+            return;
+        }
+        if keys.is_empty() {
+            self.reset();
+            return;
+        }
+        let now = now_ms();
+        for key in keys {
+            self.update_key(state, key, now);
+        }
+    }
+
+    /// Record `keys` being used at unix ms `now`.
+    pub fn touch<'a>(&self, keys: impl Iterator<Item = &'a [u8]>, now: u64) {
+        for key in keys {
+            if let Some(mut usage) = self.keys.get_mut(key) {
+                usage.accessed = now;
+            }
+        }
+    }
+
+    /// The usage of `key`, if it's tracked.
+    pub fn usage_of(&self, key: &[u8]) -> Option<KeyUsage> {
+        self.keys.get(key).map(|usage| *usage)
+    }
+
+    /// `n` tracked keys picked at random with their usage, or every one if
+    /// there are no more.
+    pub fn sample(&self, n: usize) -> Vec<(Key, KeyUsage)> {
+        sample_keys(&self.keys, n)
+            .into_iter()
+            .filter_map(|key| {
+                let usage = self.usage_of(&key)?;
+                Some((key, usage))
+            })
+            .collect()
+    }
+}
+
+/// Count `db` again in a task of its own after it was flushed or replaced,
+/// if it's tracked, so no command waits on walking every key. Until it's
+/// done, keys it hasn't got to yet aren't counted.
+pub fn recount_in_background(db: StateRef) {
+    if db.memory.tracked().is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        db.memory.recount(&db, &mut Budget::default()).await;
+    });
+}
+
+#[cfg(test)]
+mod test_memory {
+    use super::{
+        attributed_usage, key_usage, sampled_key_usage, string_refcount, structure_usage,
+        EstimateSize, ELEMENT_OVERHEAD,
+    };
+    use crate::coop::Budget;
+    use crate::data_structures::sorted_set::SortedSet;
+    use crate::types::State;
+    use crate::write_effects::WriteEffects;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_estimates_grow_with_contents() {
+        assert_eq!(Bytes::from_static(b"hello").estimated_size(), 5);
+        let mut hash = HashMap::new();
+        let empty = hash.estimated_size();
+        hash.insert(Bytes::from_static(b"ab"), Bytes::from_static(b"cde"));
+        // The pair, and the slots the table grew to hold it.
+        let slots = hash.capacity() * (2 * ELEMENT_OVERHEAD + 1);
+        assert_eq!(hash.estimated_size(), empty + 5 + slots);
+        let mut zset = SortedSet::new();
+        let empty = zset.estimated_size();
+        zset.add(vec![(1.into(), Bytes::from_static(b"member"))].into());
+        assert!(zset.estimated_size() > empty);
+    }
+
+    #[tokio::test]
+    async fn test_structure_usage() {
+        let state = State::default();
+        state
+            .kv
+            .insert(Bytes::from_static(b"a"), Bytes::from_static(b"123"));
+        state
+            .kv
+            .insert(Bytes::from_static(b"b"), Bytes::from_static(b"45"));
+        let mut list = std::collections::VecDeque::new();
+        list.push_back(Bytes::from_static(b"x"));
+        let list_size = list.estimated_size();
+        state.lists.insert(Bytes::from_static(b"l"), list);
+        let tally = structure_usage(&state, &mut Budget::default()).await;
+        assert_eq!(tally.len(), 13);
+        assert_eq!(tally[0], ("kv", 2, 7));
+        assert_eq!(tally[2], ("lists", 1, 1 + list_size));
+        assert_eq!(tally[1], ("sets", 0, 0));
+        // Not tracked until maxmemory asks for it.
+        assert_eq!(state.memory.tracked(), None);
+        state.memory.used(&state);
+        assert_eq!(state.memory.tracked(), Some(8 + list_size));
+    }
+
+    #[test]
+    fn test_tracker_follows_writes() {
+        let state = State::default();
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        state.kv.insert(a.clone(), Bytes::from_static(b"12345"));
+        // Writes aren't tracked until it's counted.
+        state.memory.written(&state, &[a.clone()]);
+        assert_eq!(state.memory.used(&state), 6);
+        state.kv.insert(b.clone(), Bytes::from_static(b"123"));
+        state.kv.remove(&a);
+        state.memory.written(&state, &[a, b]);
+        assert_eq!(state.memory.used(&state), 4);
+        state.memory.touch(std::iter::once(&b"b"[..]), 7);
+        assert_eq!(state.memory.sample(5)[0].1.accessed, 7);
+    }
+
+    #[tokio::test]
+    async fn test_flush_and_swap_keep_tracking() {
+        let state = State::default();
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        state.kv.insert(a.clone(), Bytes::from_static(b"12345"));
+        assert_eq!(state.memory.used(&state), 6);
+        // Swapped dbs take their keys with them.
+        WriteEffects::new("SwapDB", Vec::new()).apply(&state);
+        assert_eq!(state.memory.tracked(), Some(6));
+
+        state.kv.clear();
+        // Written after the flush, but before its effects were applied.
+        state.kv.insert(b.clone(), Bytes::from_static(b"123"));
+        WriteEffects::new("FlushDB", Vec::new()).apply(&state);
+        assert_eq!(state.memory.tracked(), Some(0));
+        state.memory.recount(&state, &mut Budget::new(1)).await;
+        assert_eq!(state.memory.tracked(), Some(4));
+        assert_eq!(state.memory.usage_of(&b).unwrap().bytes, 4);
+        assert_eq!(state.memory.usage_of(&a), None);
+    }
+
+    #[test]
+    fn test_big_collections_rescaled() {
+        let state = State::default();
+        let key = Bytes::from_static(b"set");
+        let members = |range: std::ops::Range<u32>| {
+            range
+                .map(|i| Bytes::from(format!("{:08}", i)))
+                .collect::<Vec<_>>()
+        };
+        state
+            .sets
+            .insert(key.clone(), members(0..1000).into_iter().collect());
+        let counted = state.memory.used(&state);
+        state
+            .sets
+            .get_mut(&key)
+            .unwrap()
+            .extend(members(1000..1500));
+        state.memory.written(&state, &[key.clone()]);
+        // Half as many members again, each the average of the first count.
+        assert_eq!(state.memory.used(&state), counted * 3 / 2);
+
+        // Small ones are counted again.
+        state
+            .sets
+            .insert(key.clone(), members(0..10).into_iter().collect());
+        state.memory.written(&state, &[key.clone()]);
+        assert_eq!(state.memory.used(&state), key_usage(&state, &key).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shared_strings() {
+        let state = State::default();
+        let shared = Bytes::from(vec![b'x'; 100]);
+        let (a, b, c) = (
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"c"),
+        );
+        state.kv.insert(a.clone(), shared.clone());
+        state.kv.insert(b, shared);
+        // Equal contents, separate buffer.
+        state.kv.insert(c.clone(), Bytes::from(vec![b'x'; 100]));
+        let budget = &mut Budget::default();
+        assert_eq!(string_refcount(&state, &a, budget).await, Some(2));
+        assert_eq!(string_refcount(&state, &c, budget).await, Some(1));
+        assert_eq!(string_refcount(&state, &Bytes::new(), budget).await, None);
+        assert_eq!(key_usage(&state, &a), Some(101));
+        assert_eq!(attributed_usage(&state, &a, 5, budget).await, Some(51));
+        assert_eq!(attributed_usage(&state, &c, 5, budget).await, Some(101));
+    }
+
+    #[test]
+    fn test_sampled_usage() {
+        let state = State::default();
+        let key = Bytes::from_static(b"s");
+        let mut set = std::collections::HashSet::new();
+        set.insert(Bytes::from_static(b"a"));
+        set.insert(Bytes::from_static(b"b"));
+        set.insert(Bytes::from(vec![b'x'; 100]));
+        set.insert(Bytes::from(vec![b'y'; 100]));
+        let exact = set.estimated_size();
+        state.sets.insert(key.clone(), set);
+        assert_eq!(sampled_key_usage(&state, &key, 0), Some(1 + exact));
+        assert_eq!(sampled_key_usage(&state, &key, 4), Some(1 + exact));
+        // Two samples see either both short members, both long, or one of each.
+        let sampled = sampled_key_usage(&state, &key, 2).unwrap() - 1;
+        let base = exact - 202;
+        assert!([base + 4, base + 400, base + 202].contains(&sampled));
+    }
+}
@@ -1,22 +1,136 @@
+use std::convert::TryFrom;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::scripting::{Program, ScriptingBridge};
-use crate::types::{Count, Index, Key, RedisValueRef, ReturnValue, StateRef, StateStoreRef, Value};
+use crate::clients::KillFilter;
+use crate::command_table::{command_docs_reply, command_info_reply, command_reply, COMMANDS};
+use crate::compat::compat_reply;
+use crate::config::{config_get, config_rewrite, config_set};
+use crate::coop::{collect_keys, Budget};
+use crate::database::{bgsave, save_now, sync_writes, SaveError};
+use crate::for_each_structure;
+use crate::glob::glob_match;
+use crate::info::info_reply;
+use crate::key_dump::{copy_key, dump_key};
+use crate::logger::LOGGER;
+use crate::memory::{
+    attributed_usage, key_usage, recount_in_background, string_refcount, structure_usage,
+};
+use crate::monitor::Origin;
+use crate::replication::{replicaof, Role};
+use crate::scan::{scan_keys, scan_reply, ScanOptions};
+use crate::scripting::{Program, ScriptingBridge, Unkillable, UNKILLABLE};
+use crate::server::ORIGIN;
+use crate::shutdown::shutdown;
+use crate::slowlog::slowlog_reply;
+use crate::sort::{sort, SortOptions};
+use crate::tree::keyspace_tree;
+use crate::types::{
+    Count, FlushProtection, Index, Key, RedisValueRef, ReturnValue, State, StateRef, StateStore,
+    StateStoreRef, Value,
+};
+use crate::write_effects::WriteEffects;
 
 op_variants! {
     MiscOps,
-    Keys(), // TODO: Add optional glob
+    Keys(Value),
+    RandomKey(),
     Exists(Vec<Key>),
     Pong(),
-    FlushAll(),
-    FlushDB(),
-    // SwapDB(Index, Index),  // TODO: Need to figure out how to best sync clients.
+    FlushAll(bool),
+    FlushDB(bool),
+    SwapDB(Index, Index),
+    Move(Key, Index),
+    Copy(Key, Key, Option<Index>, bool),
+    Sort(Key, SortOptions),
+    DBSize(),
     Echo(Value),
     PrintCmds(),
     Select(Index),
     Script(Value),
+    ScriptFlush(),
+    ScriptLoad(Value),
+    ScriptKill(),
+    ScriptExists(Vec<Value>),
+    Eval(Value, Vec<Value>, Vec<Value>),
+    EvalSha(Value, Vec<Value>, Vec<Value>),
+    FunctionList(),
+    FunctionDelete(Value),
+    FunctionDump(),
     EmbeddedScript(Value, Vec<RedisValueRef>),
-    Info()
+    Info(Vec<Value>),
+    OxideCompat(),
+    Tree(Value, Count),
+    ObjectRefcount(Key),
+    ObjectEncoding(Key),
+    Type(Key),
+    MemoryUsage(Key, usize),
+    MemoryStats(),
+    DebugSleep(u64),
+    DebugObject(Key),
+    DebugSetActiveExpire(bool),
+    DebugNoop(),
+    Scan(u64, ScanOptions),
+    LatencyHistory(Value),
+    LatencyLatest(),
+    LatencyReset(Vec<Value>),
+    LatencyDoctor(),
+    SlowLogGet(Count),
+    SlowLogLen(),
+    SlowLogReset(),
+    ConfigGet(Vec<String>),
+    ConfigSet(Vec<(String, String)>),
+    ConfigRewrite(),
+    BgRewriteAof(),
+    Save(),
+    BgSave(),
+    LastSave(),
+    Shutdown(Option<bool>),
+    Command(),
+    CommandCount(),
+    CommandInfo(Vec<Value>),
+    CommandDocs(Vec<Value>),
+    Publish(Value, Value),
+    Subscribe(Vec<Value>),
+    Unsubscribe(Vec<Value>),
+    PSubscribe(Vec<Value>),
+    PUnsubscribe(Vec<Value>),
+    Hello(Option<Count>, Option<(Value, Value)>),
+    Quit(),
+    Auth(Option<Value>, Value),
+    Monitor(),
+    ClientList(),
+    ClientId(),
+    ClientGetName(),
+    ClientSetName(Value),
+    ClientKill(KillFilter),
+    ReplicaOf(Option<(String, u16)>),
+    ReplConf(Vec<Value>),
+    Sync(),
+    Role(),
+    Wait(Count, Count),
+    WaitAof(Count, Count, Count)
+}
+
+impl MiscOps {
+    /// Keys written by this op, or None if it's read-only.
+    ///
+    /// Flushes and swaps write without naming keys. Scripts are not writes themselves,
+    /// the commands they run are tracked individually.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            MiscOps::FlushAll(_) | MiscOps::FlushDB(_) | MiscOps::SwapDB(..) => Some(Vec::new()),
+            MiscOps::Move(key, _) | MiscOps::Copy(_, key, _, _) => Some(vec![key.clone()]),
+            MiscOps::Sort(
+                _,
+                SortOptions {
+                    store: Some(dest), ..
+                },
+            ) => Some(vec![dest.clone()]),
+            _ => None,
+        }
+    }
 }
 
 macro_rules! create_commands_list {
@@ -34,17 +148,22 @@ macro_rules! create_commands_list {
 
 /// Easily get all keys out of each passed type.
 macro_rules! get_all_keys {
-    ($state:expr, $($type:ident),*) => {
+    ($state:expr, $budget:expr, $($type:ident: $schema:expr),*) => {
         {
             let mut all = Vec::new();
             $(
-                all.extend($state.$type.iter().map(|r| r.key().clone()));
+                collect_keys(&$state.$type, $budget, &mut all).await;
             )*
             all
         }
     }
 }
 
+/// Every key in the db. Yields as it goes so big keyspaces don't stall other clients.
+pub async fn all_keys(state: &State, budget: &mut Budget) -> Vec<Key> {
+    for_each_structure!(get_all_keys!(state, budget,))
+}
+
 lazy_static! {
     static ref ALL_COMMANDS: ReturnValue = {
         use crate::keys::OP_VARIANTS as KEY_VARIANTS;
@@ -55,6 +174,12 @@ lazy_static! {
         use crate::bloom::OP_VARIANTS as BLOOM_VARIANTS;
         use crate::stack::OP_VARIANTS as STACK_VARIANTS;
         use crate::hyperloglog::OP_VARIANTS as HYPERLOGLOG_VARIANTS;
+        use crate::geo::OP_VARIANTS as GEO_VARIANTS;
+        use crate::streams::OP_VARIANTS as STREAM_VARIANTS;
+        use crate::cuckoo::OP_VARIANTS as CUCKOO_VARIANTS;
+        use crate::count_min::OP_VARIANTS as COUNT_MIN_VARIANTS;
+        use crate::top_k::OP_VARIANTS as TOP_K_VARIANTS;
+        use crate::queue::OP_VARIANTS as QUEUE_VARIANTS;
         create_commands_list!(
             KEY_VARIANTS,
             LIST_VARIANTS,
@@ -64,11 +189,142 @@ lazy_static! {
             BLOOM_VARIANTS,
             STACK_VARIANTS,
             HYPERLOGLOG_VARIANTS,
+            GEO_VARIANTS,
+            STREAM_VARIANTS,
+            CUCKOO_VARIANTS,
+            COUNT_MIN_VARIANTS,
+            TOP_K_VARIANTS,
+            QUEUE_VARIANTS,
             OP_VARIANTS // Misc variants
         )
     };
 }
 
+/// The error to reply with if `--protect-flush` forbids this flush.
+fn flush_refusal(state_store: &StateStoreRef, confirmed: bool, all: bool) -> Option<ReturnValue> {
+    match (state_store.protect_flush, confirmed, all) {
+        (FlushProtection::Disabled, _, true) => Some(ReturnValue::Error(b"FLUSHALL is disabled")),
+        (FlushProtection::Disabled, _, false) => Some(ReturnValue::Error(b"FLUSHDB is disabled")),
+        (FlushProtection::Confirm, false, true) => Some(ReturnValue::Error(
+            b"FLUSHALL is protected, call FLUSHALL CONFIRM",
+        )),
+        (FlushProtection::Confirm, false, false) => Some(ReturnValue::Error(
+            b"FLUSHDB is protected, call FLUSHDB CONFIRM",
+        )),
+        _ => None,
+    }
+}
+
+/// Clear flushed dbs from the backing store, so their keys aren't loaded
+/// back on a miss. Replies with an error if that fails.
+async fn clear_backing(state_store: &StateStoreRef, db: Option<Index>) -> ReturnValue {
+    let backing = match &state_store.backing {
+        Some(backing) => backing.clone(),
+        None => return ReturnValue::Ok,
+    };
+    let cleared = tokio::task::spawn_blocking(move || backing.clear(db))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+    match cleared {
+        Ok(()) => ReturnValue::Ok,
+        Err(e) => {
+            error!(LOGGER, "Failed to clear the backing store: {}", e);
+            let err = format!("ERR backing store write failed: {}", e);
+            ReturnValue::Ident(RedisValueRef::ErrorMsg(err.into_bytes()))
+        }
+    }
+}
+
+fn log_flush(command: &str, index: Index, dropped: &[(&'static str, usize)]) {
+    let counts: Vec<String> = dropped
+        .iter()
+        .map(|(structure, count)| format!("{}={}", structure, count))
+        .collect();
+    info!(
+        LOGGER,
+        "{} dropped from db {}: {}",
+        command,
+        index,
+        counts.join(", ")
+    );
+}
+
+/// OK, or the error of a CONFIG SET or REWRITE.
+fn config_reply(res: Result<(), String>) -> ReturnValue {
+    match res {
+        Ok(()) => ReturnValue::Ok,
+        Err(e) => ReturnValue::Ident(RedisValueRef::ErrorMsg(format!("ERR {}", e).into_bytes())),
+    }
+}
+
+const NO_SCRIPT: &[u8] = b"NOSCRIPT No matching script. Please use EVAL.";
+
+/// An EVAL program, with its KEYS and ARGV.
+fn eval_program(source: String, keys: Vec<Value>, argv: Vec<Value>) -> Program {
+    let bulk = |values: Vec<Value>| values.into_iter().map(RedisValueRef::BulkString).collect();
+    Program::Eval(source, bulk(keys), bulk(argv))
+}
+
+const SAVING_OFF: &[u8] = b"ERR saving is off (--memory-only)";
+
+fn save_reply(res: Result<(), SaveError>, ok: ReturnValue) -> ReturnValue {
+    match res {
+        Ok(()) => ok,
+        Err(e) => ReturnValue::Ident(RedisValueRef::ErrorMsg(format!("ERR {}", e).into_bytes())),
+    }
+}
+
+/// WAIT for `replicas` replicas, up to `timeout` milliseconds (0 is forever).
+/// Returns how many replicas applied every write so far.
+async fn wait_for_replicas(state_store: &StateStore, replicas: Count, timeout: Count) -> Count {
+    let timeout = Some(Duration::from_millis(timeout as u64)).filter(|t| !t.is_zero());
+    let replicas = usize::try_from(replicas).unwrap_or(0);
+    let acked = state_store
+        .replication
+        .wait_for_replicas(replicas, timeout)
+        .await;
+    acked as Count
+}
+
+/// ROLE: whether we're a primary or a replica, and how replication is going.
+fn role_reply(state_store: &StateStore) -> ReturnValue {
+    let replication = &state_store.replication;
+    match replication.role() {
+        Role::Primary => {
+            let replicas = replication
+                .replicas()
+                .into_iter()
+                .map(|replica| {
+                    ReturnValue::MultiStringRes(vec![
+                        replica.addr.ip().to_string().into(),
+                        replica.addr.port().to_string().into(),
+                        replica.acked.to_string().into(),
+                    ])
+                })
+                .collect();
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(Value::from_static(b"master")),
+                ReturnValue::IntRes(replication.offset() as Count),
+                ReturnValue::Array(replicas),
+            ])
+        }
+        Role::Replica(link) => ReturnValue::Array(vec![
+            ReturnValue::StringRes(Value::from_static(b"slave")),
+            ReturnValue::StringRes(link.host.into()),
+            ReturnValue::IntRes(link.port as Count),
+            ReturnValue::StringRes(Value::from_static(if link.up {
+                b"connected"
+            } else {
+                b"connect"
+            })),
+            ReturnValue::IntRes(link.offset as Count),
+        ]),
+    }
+}
+
+/// Keys RANDOMKEY picks before giving up, if they keep turning out expired.
+const RANDOMKEY_TRIES: usize = 100;
+
 pub async fn misc_interact(
     misc_op: MiscOps,
     state: &mut StateRef,
@@ -77,37 +333,283 @@ pub async fn misc_interact(
 ) -> ReturnValue {
     match misc_op {
         MiscOps::Pong() => ReturnValue::StringRes(Value::from_static(b"PONG")),
-        MiscOps::FlushAll() => {
-            let clear = |state: &StateRef| {
-                state.kv.clear();
-                state.sets.clear();
-                state.lists.clear();
-                state.hashes.clear();
-                state.zsets.clear();
-                state.blooms.clear();
-            };
-            for state in state_store.states.iter_mut() {
-                clear(&state);
+        MiscOps::FlushAll(confirmed) => {
+            if let Some(refusal) = flush_refusal(&state_store, confirmed, true) {
+                return refusal;
+            }
+            for db in state_store.states.iter() {
+                let dropped = db.clear();
+                WriteEffects::new("FlushAll", Vec::new()).apply(&db);
+                recount_in_background(db.value().clone());
+                log_flush("FLUSHALL", *db.key(), &dropped);
+                state_store.stats.record_flush(&dropped);
+            }
+            clear_backing(&state_store, None).await
+        }
+        MiscOps::FlushDB(confirmed) => {
+            if let Some(refusal) = flush_refusal(&state_store, confirmed, false) {
+                return refusal;
+            }
+            let dropped = state.clear();
+            WriteEffects::new("FlushDB", Vec::new()).apply(state);
+            recount_in_background(state.clone());
+            let index = state_store.db_index(state);
+            if let Some(index) = index {
+                log_flush("FLUSHDB", index, &dropped);
+            }
+            state_store.stats.record_flush(&dropped);
+            match index {
+                Some(index) => clear_backing(&state_store, Some(index)).await,
+                None => ReturnValue::Ok,
             }
-            // let state_guard = state_store.states.lock();
-            // for state in state_guard.values() {
-            //     clear(state);
-            // }
-            ReturnValue::Ok
         }
-        MiscOps::FlushDB() => {
-            *state = Default::default();
+        MiscOps::SwapDB(a, b) => {
+            if state_store.backing.is_some() {
+                return ReturnValue::Error(b"ERR SWAPDB isn't supported with a backing store");
+            }
+            state_store.swap_dbs(a, b);
             ReturnValue::Ok
         }
+        MiscOps::Move(key, index) => {
+            if state_store.backing.is_some() {
+                return ReturnValue::Error(b"ERR MOVE isn't supported with a backing store");
+            }
+            let dest = state_store.get_or_create(index);
+            if Arc::ptr_eq(state, &dest) {
+                return ReturnValue::Error(b"ERR source and destination objects are the same");
+            }
+            state.expire_if_due(&key);
+            if !state.move_key(&key, &dest) {
+                return ReturnValue::IntRes(0);
+            }
+            WriteEffects::new("MoveFrom", vec![key.clone()]).apply(state);
+            WriteEffects::new("MoveTo", vec![key]).apply(&dest);
+            ReturnValue::IntRes(1)
+        }
+        MiscOps::Copy(key, dest_key, index, replace) => {
+            let dest = match index {
+                Some(_) if state_store.backing.is_some() => {
+                    return ReturnValue::Error(
+                        b"ERR COPY to another db isn't supported with a backing store",
+                    )
+                }
+                Some(index) => state_store.get_or_create(index),
+                None => state.clone(),
+            };
+            if Arc::ptr_eq(state, &dest) && key == dest_key {
+                return ReturnValue::Error(b"ERR source and destination objects are the same");
+            }
+            state.expire_if_due(&key);
+            dest.expire_if_due(&dest_key);
+            if !copy_key(state, &key, &dest, dest_key.clone(), replace) {
+                return ReturnValue::IntRes(0);
+            }
+            WriteEffects::new("Copy", vec![dest_key]).apply(&dest);
+            ReturnValue::IntRes(1)
+        }
+        MiscOps::Sort(key, opts) => {
+            let reply = sort(state, &key, &opts).await;
+            if let (Some(dest), false) = (opts.store, reply.is_error()) {
+                WriteEffects::new("Sort", vec![dest]).apply(state);
+            }
+            reply
+        }
+        MiscOps::DBSize() => ReturnValue::IntRes(state.key_count() as Count),
         MiscOps::Exists(keys) => ReturnValue::IntRes(
             keys.iter()
-                .map(|key| state.kv.contains_key(key))
-                .filter(|exists| *exists)
+                .filter(|key| {
+                    state.expire_if_due(key);
+                    state.structure_of(key).is_some()
+                })
                 .count() as Count,
         ),
-        MiscOps::Keys() => {
-            let kv_keys = get_all_keys!(state, kv, sets, lists, hashes, zsets, blooms);
-            ReturnValue::MultiStringRes(kv_keys)
+        MiscOps::LatencyHistory(event) => ReturnValue::Array(
+            state_store
+                .latency
+                .history(&event)
+                .into_iter()
+                .map(|sample| {
+                    ReturnValue::Array(vec![
+                        ReturnValue::IntRes(sample.time as Count),
+                        ReturnValue::IntRes(sample.latency_ms as Count),
+                    ])
+                })
+                .collect(),
+        ),
+        MiscOps::LatencyLatest() => ReturnValue::Array(
+            state_store
+                .latency
+                .latest()
+                .into_iter()
+                .map(|(event, sample, max_ms)| {
+                    ReturnValue::Array(vec![
+                        ReturnValue::StringRes(Value::from_static(event.as_bytes())),
+                        ReturnValue::IntRes(sample.time as Count),
+                        ReturnValue::IntRes(sample.latency_ms as Count),
+                        ReturnValue::IntRes(max_ms as Count),
+                    ])
+                })
+                .collect(),
+        ),
+        MiscOps::LatencyReset(events) => {
+            ReturnValue::IntRes(state_store.latency.reset(&events) as Count)
+        }
+        MiscOps::LatencyDoctor() => ReturnValue::StringRes(state_store.latency.doctor().into()),
+        MiscOps::SlowLogGet(count) => slowlog_reply(state_store.slowlog.get(count)),
+        MiscOps::SlowLogLen() => ReturnValue::IntRes(state_store.slowlog.len() as Count),
+        MiscOps::SlowLogReset() => {
+            state_store.slowlog.reset();
+            ReturnValue::Ok
+        }
+        MiscOps::ConfigGet(patterns) => {
+            let params = config_get(&state_store, &patterns);
+            let flat = params
+                .into_iter()
+                .flat_map(|(name, value)| vec![name.into(), value.into()])
+                .collect();
+            ReturnValue::MultiStringRes(flat)
+        }
+        MiscOps::ConfigSet(pairs) => config_reply(config_set(&state_store, &pairs)),
+        MiscOps::ConfigRewrite() => config_reply(config_rewrite(&state_store)),
+        MiscOps::Save() => match state_store.dump_file.get() {
+            Some(dump_file) => save_reply(save_now(&state_store, dump_file), ReturnValue::Ok),
+            None => ReturnValue::Error(SAVING_OFF),
+        },
+        MiscOps::BgSave() => match state_store.dump_file.get() {
+            Some(dump_file) => save_reply(
+                bgsave(state_store.clone(), dump_file.clone()),
+                ReturnValue::Ident(RedisValueRef::SimpleString(Value::from_static(
+                    b"Background saving started",
+                ))),
+            ),
+            None => ReturnValue::Error(SAVING_OFF),
+        },
+        MiscOps::LastSave() => ReturnValue::IntRes(state_store.stats.last_save() as Count),
+        // Exiting would take the program embedding us down with it.
+        MiscOps::Shutdown(_) if state_store.embedded.load(Ordering::Relaxed) => {
+            ReturnValue::Error(b"ERR SHUTDOWN isn't allowed when embedded")
+        }
+        // Only replies if the server keeps running. SHUTDOWN itself is in
+        // flight, and so is the EVAL of a script running it.
+        MiscOps::Shutdown(save) => {
+            let own = match ORIGIN.try_with(|origin| *origin) {
+                Ok(Origin::Script) => 2,
+                _ => 1,
+            };
+            shutdown(&state_store, save, own).await;
+            ReturnValue::Error(b"ERR Errors trying to SHUTDOWN. Check logs.")
+        }
+        MiscOps::BgRewriteAof() => match state_store.aof.get() {
+            None => ReturnValue::Error(b"ERR The append only file is off (see --appendonly)"),
+            Some(aof) if !aof.start_rewrite() => {
+                ReturnValue::Error(b"ERR Background append only file rewriting already in progress")
+            }
+            Some(_) => {
+                let store = state_store.clone();
+                tokio::spawn(async move {
+                    if let Some(aof) = store.aof.get() {
+                        match aof.rewrite(&store).await {
+                            Ok(()) => info!(LOGGER, "Rewrote the AOF"),
+                            Err(e) => error!(LOGGER, "Failed to rewrite the AOF: {}", e),
+                        }
+                    }
+                });
+                ReturnValue::Ident(RedisValueRef::SimpleString(Value::from_static(
+                    b"Background append only file rewriting started",
+                )))
+            }
+        },
+        MiscOps::Command() => command_reply(),
+        MiscOps::CommandCount() => ReturnValue::IntRes(COMMANDS.len() as Count),
+        MiscOps::CommandInfo(names) if names.is_empty() => command_reply(),
+        MiscOps::CommandInfo(names) => command_info_reply(&names),
+        MiscOps::CommandDocs(names) => command_docs_reply(&names),
+        MiscOps::Publish(channel, message) => {
+            ReturnValue::IntRes(state_store.pubsub.publish(&channel, &message) as Count)
+        }
+        // Client connections run these through their own `Connection`.
+        MiscOps::Subscribe(_)
+        | MiscOps::Unsubscribe(_)
+        | MiscOps::PSubscribe(_)
+        | MiscOps::PUnsubscribe(_) => {
+            ReturnValue::Error(b"subscriptions are only available to client connections")
+        }
+        MiscOps::Hello(..) => ReturnValue::Error(b"HELLO is only available to client connections"),
+        MiscOps::Quit() => ReturnValue::Error(b"QUIT is only available to client connections"),
+        MiscOps::Auth(..) => ReturnValue::Error(b"AUTH is only available to client connections"),
+        MiscOps::Monitor() => {
+            ReturnValue::Error(b"MONITOR is only available to client connections")
+        }
+        MiscOps::ClientList()
+        | MiscOps::ClientId()
+        | MiscOps::ClientGetName()
+        | MiscOps::ClientSetName(_)
+        | MiscOps::ClientKill(_) => {
+            ReturnValue::Error(b"CLIENT is only available to client connections")
+        }
+        MiscOps::ReplicaOf(primary) => {
+            let following = primary.is_some();
+            if replicaof(&state_store, &scripting_bridge, primary) || !following {
+                return ReturnValue::Ok;
+            }
+            ReturnValue::Ident(RedisValueRef::SimpleString(Value::from_static(
+                b"OK Already connected to specified master",
+            )))
+        }
+        // Replicas send their ACKs on their own connection, see `serve_replica`.
+        MiscOps::ReplConf(_) => ReturnValue::Ok,
+        MiscOps::Sync() => ReturnValue::Error(b"PSYNC is only available to client connections"),
+        MiscOps::Role() => role_reply(&state_store),
+        MiscOps::Wait(replicas, timeout) => {
+            if state_store.replication.is_replica() {
+                return ReturnValue::Error(b"ERR WAIT cannot be used with replica instances.");
+            }
+            let acked = wait_for_replicas(&state_store, replicas, timeout).await;
+            ReturnValue::IntRes(acked)
+        }
+        MiscOps::WaitAof(local, replicas, timeout) => {
+            if state_store.replication.is_replica() {
+                return ReturnValue::Error(b"ERR WAITAOF cannot be used with replica instances.");
+            }
+            let synced = if local > 0 {
+                match sync_writes(&state_store) {
+                    Ok(()) => 1,
+                    Err(e) => {
+                        let err = format!("ERR WAITAOF couldn't sync locally: {}", e);
+                        return ReturnValue::Ident(RedisValueRef::ErrorMsg(err.into_bytes()));
+                    }
+                }
+            } else {
+                0
+            };
+            let acked = wait_for_replicas(&state_store, replicas, timeout).await;
+            ReturnValue::Array(vec![
+                ReturnValue::IntRes(synced),
+                ReturnValue::IntRes(acked),
+            ])
+        }
+        MiscOps::Scan(cursor, opts) => {
+            let (next, keys) = scan_keys(state, cursor, &opts);
+            scan_reply(next, keys)
+        }
+        MiscOps::Keys(pattern) => {
+            let keys = all_keys(state, &mut Budget::default())
+                .await
+                .into_iter()
+                .filter(|key| glob_match(&pattern, key) && !state.expire_if_due(key))
+                .collect();
+            ReturnValue::MultiStringRes(keys)
+        }
+        // Like redis, give up after a while if the keys picked keep expiring.
+        MiscOps::RandomKey() => {
+            for _ in 0..RANDOMKEY_TRIES {
+                match state.random_key() {
+                    Some(key) if state.expire_if_due(&key) => continue,
+                    Some(key) => return ReturnValue::StringRes(key),
+                    None => break,
+                }
+            }
+            ReturnValue::Nil
         }
         MiscOps::PrintCmds() => (*ALL_COMMANDS).clone(),
         MiscOps::Select(index) => {
@@ -116,13 +618,100 @@ pub async fn misc_interact(
             ReturnValue::Ok
         }
         MiscOps::Echo(val) => ReturnValue::StringRes(val),
-        MiscOps::Info() => {
-            let info: String = [
-                concat!("redis_version", ":", env!("CARGO_PKG_VERSION")),
-                "arch_bits:64",
-            ]
-            .join("\r\n");
-            ReturnValue::StringRes(info.into())
+        MiscOps::Info(sections) => info_reply(&state_store, &sections),
+        MiscOps::OxideCompat() => compat_reply(state_store),
+        MiscOps::Tree(separator, depth) => {
+            keyspace_tree(state, &separator, depth as usize, &mut Budget::default())
+                .await
+                .into_reply(Value::new())
+        }
+        MiscOps::ObjectRefcount(key) => {
+            if let Some(count) = string_refcount(state, &key, &mut Budget::default()).await {
+                return ReturnValue::IntRes(count as Count);
+            }
+            // Only strings share buffers.
+            match key_usage(state, &key) {
+                Some(_) => ReturnValue::IntRes(1),
+                None => ReturnValue::Nil,
+            }
+        }
+        MiscOps::ObjectEncoding(key) => {
+            state.expire_if_due(&key);
+            match state.encoding(&key) {
+                Some(encoding) => ReturnValue::StringRes(Value::from_static(encoding.as_bytes())),
+                None => ReturnValue::Nil,
+            }
+        }
+        MiscOps::Type(key) => {
+            state.expire_if_due(&key);
+            let name = state.type_name(&key);
+            ReturnValue::Ident(RedisValueRef::SimpleString(Value::from_static(
+                name.as_bytes(),
+            )))
+        }
+        MiscOps::MemoryUsage(key, samples) => {
+            match attributed_usage(state, &key, samples, &mut Budget::default()).await {
+                Some(bytes) => ReturnValue::IntRes(bytes as Count),
+                None => ReturnValue::Nil,
+            }
+        }
+        MiscOps::MemoryStats() => {
+            let mut reply = Vec::new();
+            let mut stat = |name: String, value: usize| {
+                reply.push(ReturnValue::StringRes(Value::from(name)));
+                reply.push(ReturnValue::IntRes(value as Count));
+            };
+            let (mut keys, mut bytes) = (0, 0);
+            for (structure, structure_keys, structure_bytes) in
+                structure_usage(state, &mut Budget::default()).await
+            {
+                stat(format!("{}.bytes", structure), structure_bytes);
+                keys += structure_keys;
+                bytes += structure_bytes;
+            }
+            stat("keys.count".into(), keys);
+            stat("dataset.bytes".into(), bytes);
+            // Only while maxmemory is set, and as of the last write.
+            if let Some(tracked) = state.memory.tracked() {
+                stat("tracked.bytes".into(), tracked);
+            }
+            ReturnValue::Array(reply)
+        }
+        // Only this connection waits.
+        MiscOps::DebugSleep(ms) => {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            ReturnValue::Ok
+        }
+        MiscOps::DebugObject(key) => {
+            state.expire_if_due(&key);
+            let encoding = match state.encoding(&key) {
+                Some(encoding) => encoding,
+                None => return ReturnValue::Error(b"ERR no such key"),
+            };
+            let refcount = string_refcount(state, &key, &mut Budget::default())
+                .await
+                .unwrap_or(1);
+            let serialized = dump_key(state, &key).map_or(0, |payload| payload.len());
+            let object = format!(
+                "refcount:{} encoding:{} serializedlength:{}",
+                refcount, encoding, serialized
+            );
+            ReturnValue::Ident(RedisValueRef::SimpleString(Value::from(object)))
+        }
+        MiscOps::DebugSetActiveExpire(on) => {
+            state_store
+                .active_expire_disabled
+                .store(!on, Ordering::Relaxed);
+            ReturnValue::Ok
+        }
+        MiscOps::DebugNoop() => ReturnValue::Ok,
+        MiscOps::Script(_)
+        | MiscOps::EmbeddedScript(..)
+        | MiscOps::Eval(..)
+        | MiscOps::EvalSha(..)
+            if !state_store.scripting_enabled() =>
+        {
+            ReturnValue::Error(b"ERR scripting disabled")
         }
         MiscOps::Script(program) => {
             let prog_str = String::from_utf8_lossy(&program).to_string();
@@ -131,6 +720,83 @@ pub async fn misc_interact(
                 .await;
             ReturnValue::Ident(res)
         }
+        MiscOps::ScriptFlush() => {
+            state_store.script_cache.flush();
+            ReturnValue::Ok
+        }
+        MiscOps::ScriptKill() => match scripting_bridge.kill_all() {
+            Ok(0) => ReturnValue::Error(b"NOTBUSY No scripts in execution right now."),
+            Ok(_) => ReturnValue::Ok,
+            Err(Unkillable) => ReturnValue::Error(UNKILLABLE),
+        },
+        MiscOps::ScriptLoad(program) => {
+            let source = String::from_utf8_lossy(&program).to_string();
+            ReturnValue::StringRes(state_store.script_cache.insert(source).into())
+        }
+        MiscOps::ScriptExists(shas) => ReturnValue::Array(
+            shas.iter()
+                .map(|sha| {
+                    let sha = String::from_utf8_lossy(sha).to_lowercase();
+                    ReturnValue::IntRes(state_store.script_cache.contains(&sha) as Count)
+                })
+                .collect(),
+        ),
+        MiscOps::FunctionList() => {
+            let mut names: Vec<String> = state_store
+                .foreign_functions
+                .read()
+                .iter()
+                .cloned()
+                .collect();
+            names.sort();
+            ReturnValue::Array(
+                names
+                    .into_iter()
+                    .map(|name| {
+                        let code = state_store
+                            .functions
+                            .get(&name)
+                            .map_or(ReturnValue::Nil, |source| {
+                                ReturnValue::StringRes(source.into())
+                            });
+                        ReturnValue::Array(vec![
+                            ReturnValue::StringRes(Value::from_static(b"name")),
+                            ReturnValue::StringRes(name.into()),
+                            ReturnValue::StringRes(Value::from_static(b"code")),
+                            code,
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+        MiscOps::FunctionDelete(name) => {
+            let name = String::from_utf8_lossy(&name);
+            let saved = state_store.functions.delete(&name);
+            if state_store.remove_foreign_function(&name) || saved {
+                ReturnValue::Ok
+            } else {
+                ReturnValue::Error(b"ERR Function not found")
+            }
+        }
+        MiscOps::FunctionDump() => ReturnValue::StringRes(state_store.functions.dump().into()),
+        MiscOps::Eval(program, keys, argv) => {
+            let source = String::from_utf8_lossy(&program).to_string();
+            let res = scripting_bridge
+                .handle_script_cmd(eval_program(source, keys, argv))
+                .await;
+            ReturnValue::Ident(res)
+        }
+        MiscOps::EvalSha(sha, keys, argv) => {
+            let sha = String::from_utf8_lossy(&sha).to_lowercase();
+            let source = match state_store.script_cache.get(&sha) {
+                Some(source) => source,
+                None => return ReturnValue::Error(NO_SCRIPT),
+            };
+            let res = scripting_bridge
+                .handle_script_cmd(eval_program(source, keys, argv))
+                .await;
+            ReturnValue::Ident(res)
+        }
         MiscOps::EmbeddedScript(fn_name, fn_args) => {
             // We need to send the program over the scripting bridge
             // and wait for the result
@@ -142,3 +808,408 @@ pub async fn misc_interact(
         }
     }
 }
+
+#[cfg(test)]
+mod test_misc {
+    use super::{misc_interact, MiscOps};
+    use crate::ops::{translate, Ops, OpsError};
+    use crate::scripting::ScriptingBridge;
+    use crate::state::SelectedDb;
+    use crate::test_util::{command, command_args};
+    use crate::types::{bulk, Count, FlushProtection, RedisValueRef, ReturnValue, StateStore};
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn test_protected_flushdb() {
+        let mut store = StateStore::default();
+        store.protect_flush = FlushProtection::Confirm;
+        let store = Arc::new(store);
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let key = Bytes::from_static(b"a");
+        state.kv.insert(key.clone(), key.clone());
+        state.stacks.entry(key.clone()).or_default();
+
+        let res = misc_interact(
+            MiscOps::FlushDB(false),
+            &mut state,
+            store.clone(),
+            bridge.clone(),
+        )
+        .await;
+        assert!(matches!(res, ReturnValue::Error(_)));
+        assert_eq!(state.kv.len(), 1);
+
+        let res = misc_interact(MiscOps::FlushDB(true), &mut state, store.clone(), bridge).await;
+        assert_eq!(res, ReturnValue::Ok);
+        // Cleared in place, so the db is still the one in the store.
+        assert!(Arc::ptr_eq(&state, &store.get_default()));
+        assert!(state.kv.is_empty() && state.stacks.is_empty());
+        assert_eq!(store.stats.flushes(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_exists() {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let (set, gone) = (Bytes::from_static(b"set"), Bytes::from_static(b"gone"));
+        state.sets.insert(set.clone(), Default::default());
+        state.hashes.insert(gone.clone(), Default::default());
+        state.set_expiry(gone.clone(), 0);
+        let keys = vec![set.clone(), set, gone, Bytes::from_static(b"missing")];
+        let res = misc_interact(MiscOps::Exists(keys), &mut state, store.clone(), bridge).await;
+        assert_eq!(res, ReturnValue::IntRes(2));
+        assert!(state.hashes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_keys_and_randomkey() {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let op = MiscOps::RandomKey();
+        let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+        assert_eq!(res, ReturnValue::Nil);
+
+        let (a, b) = (Bytes::from_static(b"user:a"), Bytes::from_static(b"user:b"));
+        state.kv.insert(a.clone(), a.clone());
+        state.stacks.insert(b.clone(), Default::default());
+        state
+            .sets
+            .insert(Bytes::from_static(b"other"), Default::default());
+        let gone = Bytes::from_static(b"user:gone");
+        state.lists.insert(gone.clone(), Default::default());
+        state.set_expiry(gone, 0);
+
+        let op = MiscOps::Keys(Bytes::from_static(b"user:[a-z]"));
+        let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+        let mut keys = match res {
+            ReturnValue::MultiStringRes(keys) => keys,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        keys.sort();
+        assert_eq!(keys, vec![a, b]);
+
+        for _ in 0..10 {
+            let op = MiscOps::RandomKey();
+            let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+            assert!(matches!(res, ReturnValue::StringRes(key) if key != "user:gone"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swapdb_and_move() {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let mut selected = SelectedDb::new(&store);
+        let key = Bytes::from_static(b"key");
+        state.kv.insert(key.clone(), key.clone());
+        state.set_expiry(key.clone(), u64::MAX);
+
+        let op = MiscOps::Move(key.clone(), 0);
+        let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+        assert!(res.is_error());
+        let op = MiscOps::Move(key.clone(), 1);
+        let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(1));
+        let other = store.get_or_create(1);
+        assert!(state.kv.is_empty());
+        assert_eq!(other.expiry(&key), Some(u64::MAX));
+        let res = misc_interact(MiscOps::DBSize(), &mut state, store.clone(), bridge.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(0));
+
+        // Connections on db 0 see db 1's keys after swapping.
+        let op = MiscOps::SwapDB(0, 1);
+        let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+        assert_eq!(res, ReturnValue::Ok);
+        let mut state = selected.state(&store);
+        assert!(Arc::ptr_eq(&state, &other));
+        let res = misc_interact(MiscOps::DBSize(), &mut state, store.clone(), bridge.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(1));
+        let op = MiscOps::Move(key, 1);
+        let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(1));
+
+        // A SELECT is followed too.
+        misc_interact(MiscOps::Select(1), &mut state, store.clone(), bridge).await;
+        selected.selected(&store, state);
+        store.swap_dbs(0, 1);
+        assert!(Arc::ptr_eq(&selected.state(&store), &other));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_save_and_bgsave() {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        // Memory only: there's nowhere to save to.
+        let res = misc_interact(MiscOps::Save(), &mut state, store.clone(), bridge.clone()).await;
+        assert!(res.is_error());
+
+        let dir =
+            std::env::temp_dir().join(format!("redis-oxide-test-save-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.rodb");
+        let _ = store.dump_file.set(Arc::new(Mutex::new(path.clone())));
+        let key = Bytes::from_static(b"key");
+        state.kv.insert(key.clone(), key);
+        let res = misc_interact(MiscOps::Save(), &mut state, store.clone(), bridge.clone()).await;
+        assert_eq!(res, ReturnValue::Ok);
+        assert!(path.exists());
+        let res = misc_interact(
+            MiscOps::LastSave(),
+            &mut state,
+            store.clone(),
+            bridge.clone(),
+        )
+        .await;
+        assert_eq!(res, ReturnValue::IntRes(store.stats.last_save() as Count));
+
+        // One save at a time.
+        assert!(store.stats.start_save());
+        let res = misc_interact(MiscOps::BgSave(), &mut state, store.clone(), bridge.clone()).await;
+        assert!(res.is_error());
+        store.stats.finish_save(true);
+        let res = misc_interact(MiscOps::BgSave(), &mut state, store.clone(), bridge).await;
+        assert!(!res.is_error());
+    }
+
+    #[tokio::test]
+    async fn test_scripting_disabled() {
+        let store = Arc::new(StateStore::default());
+        store.scripting_disabled.store(true, Ordering::Relaxed);
+        store.add_foreign_function("my-fn");
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let op = MiscOps::Script(Bytes::from_static(b"(+ 1 1)"));
+        let res = misc_interact(op, &mut state, store.clone(), bridge).await;
+        assert_eq!(res, ReturnValue::Error(b"ERR scripting disabled"));
+        // Functions registered before the engine died aren't called either.
+        let op = translate(RedisValueRef::BulkString("my-fn".into()), store);
+        assert!(matches!(op, Err(OpsError::UnknownOp)));
+    }
+
+    #[tokio::test]
+    async fn test_type_and_encoding() {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let (int, list) = (Bytes::from_static(b"int"), Bytes::from_static(b"list"));
+        state.kv.insert(int.clone(), Bytes::from_static(b"-12"));
+        state.lists.insert(list.clone(), Default::default());
+
+        let cases = vec![
+            (MiscOps::Type(int.clone()), "string"),
+            (MiscOps::Type(list.clone()), "list"),
+            (MiscOps::Type(Bytes::from_static(b"missing")), "none"),
+        ];
+        for (op, name) in cases {
+            let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+            let expected = RedisValueRef::SimpleString(Bytes::from_static(name.as_bytes()));
+            assert_eq!(res, ReturnValue::Ident(expected));
+        }
+
+        let cases = vec![
+            (int.clone(), Bytes::from_static(b"-12"), "int"),
+            (int, Bytes::from_static(b"012"), "embstr"),
+        ];
+        for (key, value, encoding) in cases {
+            state.kv.insert(key.clone(), value);
+            let op = MiscOps::ObjectEncoding(key);
+            let res = misc_interact(op, &mut state, store.clone(), bridge.clone()).await;
+            assert_eq!(res, ReturnValue::StringRes(encoding.into()));
+        }
+        let op = MiscOps::ObjectEncoding(list);
+        let res = misc_interact(op, &mut state, store.clone(), bridge).await;
+        assert_eq!(res, ReturnValue::StringRes("quicklist".into()));
+    }
+
+    #[tokio::test]
+    async fn test_debug() {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let debug = |args: &[&'static str]| {
+            let mut command = vec![bulk("DEBUG")];
+            command.extend(command_args(args));
+            match translate(RedisValueRef::Array(command), store.clone()) {
+                Ok(Ops::Misc(op)) => op,
+                other => panic!("{:?}", other),
+            }
+        };
+        let key = Bytes::from_static(b"key");
+        state.kv.insert(key.clone(), Bytes::from_static(b"12"));
+        let res = misc_interact(
+            debug(&["object", "key"]),
+            &mut state,
+            store.clone(),
+            bridge.clone(),
+        )
+        .await;
+        match res {
+            ReturnValue::Ident(RedisValueRef::SimpleString(object)) => {
+                assert!(object.starts_with(b"refcount:1 encoding:int serializedlength:"))
+            }
+            other => panic!("{:?}", other),
+        }
+        let res = misc_interact(
+            debug(&["OBJECT", "missing"]),
+            &mut state,
+            store.clone(),
+            bridge.clone(),
+        )
+        .await;
+        assert_eq!(res, ReturnValue::Error(b"ERR no such key"));
+
+        let op = debug(&["set-active-expire", "0"]);
+        assert_eq!(
+            misc_interact(op, &mut state, store.clone(), bridge.clone()).await,
+            ReturnValue::Ok
+        );
+        assert!(store.active_expire_disabled.load(Ordering::Relaxed));
+        for args in [&["sleep", "0.01"][..], &["jmap"], &["quickack", "1"]].iter() {
+            let res = misc_interact(debug(args), &mut state, store.clone(), bridge.clone()).await;
+            assert_eq!(res, ReturnValue::Ok);
+        }
+        let bad = vec![
+            RedisValueRef::BulkString(Bytes::from_static(b"DEBUG")),
+            RedisValueRef::BulkString(Bytes::from_static(b"sleep")),
+            RedisValueRef::BulkString(Bytes::from_static(b"-1")),
+        ];
+        assert!(translate(RedisValueRef::Array(bad), store).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_script_cache_commands() {
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+        let parse = |args: &[&'static str]| translate(command(args), store.clone());
+        let op = match parse(&["SCRIPT", "LOAD", "(+ 1 1)"]) {
+            Ok(Ops::Misc(op)) => op,
+            other => panic!("{:?}", other),
+        };
+        let sha = match misc_interact(op, &mut state, store.clone(), bridge.clone()).await {
+            ReturnValue::StringRes(sha) => sha,
+            other => panic!("{:?}", other),
+        };
+        let op = MiscOps::ScriptExists(vec![sha.to_ascii_uppercase().into(), "nope".into()]);
+        assert_eq!(
+            misc_interact(op, &mut state, store.clone(), bridge.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::IntRes(1), ReturnValue::IntRes(0)])
+        );
+        let op = MiscOps::EvalSha("nope".into(), Vec::new(), Vec::new());
+        assert_eq!(
+            misc_interact(op, &mut state, store.clone(), bridge.clone()).await,
+            ReturnValue::Error(super::NO_SCRIPT)
+        );
+
+        match parse(&["EVAL", "(+ 1 1)", "2", "a", "b", "c"]) {
+            Ok(Ops::Misc(MiscOps::Eval(_, keys, argv))) => {
+                assert_eq!(
+                    keys,
+                    vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]
+                );
+                assert_eq!(argv, vec![Bytes::from_static(b"c")]);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(parse(&["EVALSHA", "abc", "2", "a"]).is_err());
+        assert!(parse(&["EVAL", "(+ 1 1)", "-1"]).is_err());
+        for flush in [&["SCRIPT", "FLUSH"][..], &["script", "flush", "async"][..]].iter() {
+            assert!(matches!(
+                parse(flush),
+                Ok(Ops::Misc(MiscOps::ScriptFlush()))
+            ));
+        }
+        assert!(matches!(
+            parse(&["SCRIPT", "FLUSH", "LATER"]),
+            Err(OpsError::SyntaxError)
+        ));
+        assert!(parse(&["SCRIPT", "FLUSH", "SYNC", "ASYNC"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_function_commands() {
+        let dir =
+            std::env::temp_dir().join(format!("redis-oxide-test-functions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(crate::scripting::FUNCTIONS_FILE);
+        let store = Arc::new(StateStore::default());
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let mut state = store.get_default();
+
+        // Definitions before the library opens (the scripts dir) aren't kept.
+        store
+            .functions
+            .define("early", "(def-redis-fn early () 1)".into());
+        assert!(store.functions.open(Some(path.clone())).unwrap().is_empty());
+        for name in ["early", "my-sum", "my-get"].iter() {
+            store.add_foreign_function(name);
+        }
+        let sum = "(def-redis-fn my-sum (a b) (+ a b))";
+        let get = "(def-redis-fn my-get (k) (redis \"get\" k))";
+        store.functions.define("my-sum", sum.into());
+        store.functions.define("my-get", get.into());
+
+        let entry = |name: &'static str, code: ReturnValue| {
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(Bytes::from_static(b"name")),
+                ReturnValue::StringRes(Bytes::from_static(name.as_bytes())),
+                ReturnValue::StringRes(Bytes::from_static(b"code")),
+                code,
+            ])
+        };
+        let op = MiscOps::FunctionList();
+        assert_eq!(
+            misc_interact(op, &mut state, store.clone(), bridge.clone()).await,
+            ReturnValue::Array(vec![
+                entry("early", ReturnValue::Nil),
+                entry("my-get", ReturnValue::StringRes(get.into())),
+                entry("my-sum", ReturnValue::StringRes(sum.into())),
+            ])
+        );
+        let op = MiscOps::FunctionDump();
+        assert_eq!(
+            misc_interact(op, &mut state, store.clone(), bridge.clone()).await,
+            ReturnValue::StringRes(format!("{}\n{}", get, sum).into())
+        );
+
+        let op = MiscOps::FunctionDelete(Bytes::from_static(b"my-get"));
+        assert_eq!(
+            misc_interact(op.clone(), &mut state, store.clone(), bridge.clone()).await,
+            ReturnValue::Ok
+        );
+        assert!(!store.contains_foreign_function("my-get"));
+        assert_eq!(
+            misc_interact(op, &mut state, store.clone(), bridge.clone()).await,
+            ReturnValue::Error(b"ERR Function not found")
+        );
+
+        // A restart defines what's left again.
+        let restarted = StateStore::default();
+        assert_eq!(
+            restarted.functions.open(Some(path)).unwrap(),
+            vec![sum.to_string()]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
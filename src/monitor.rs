@@ -0,0 +1,144 @@
+//! MONITOR: stream every command the server processes to debugging clients.
+//!
+//! `process_command` formats each command as a line like redis does,
+//! `1339518083.107412 [0 127.0.0.1:60866] "keys" "*"`, and broadcasts it.
+//! Connections in monitor mode hold a receiver, and send lines to their
+//! client between commands. Nothing is formatted while nobody is watching.
+
+use crate::escape::escape_bytes;
+use crate::types::{Index, RedisValueRef, Value};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// Lines buffered per monitor. Slower monitors skip the lines they missed.
+const MONITOR_BUFFER: usize = 4096;
+
+/// Where a command came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    /// A RESP or HTTP client, by peer address.
+    Client(SocketAddr),
+    /// A script, calling back into the server.
+    Script,
+    /// A program embedding the server (see `embedded`).
+    Embedded,
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Client(addr) => write!(f, "{}", addr),
+            Origin::Script => write!(f, "script"),
+            Origin::Embedded => write!(f, "embedded"),
+        }
+    }
+}
+
+pub struct Monitor {
+    feed: Sender<Value>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        let (feed, _) = broadcast::channel(MONITOR_BUFFER);
+        Monitor { feed }
+    }
+}
+
+/// Format `command` as a MONITOR line, quoting and escaping each argument.
+fn monitor_line(
+    time: Duration,
+    db: Option<Index>,
+    origin: Origin,
+    command: &RedisValueRef,
+) -> Value {
+    let args = match command {
+        RedisValueRef::Array(args) => &args[..],
+        arg => std::slice::from_ref(arg),
+    };
+    let db = db.map_or_else(|| "?".to_string(), |db| db.to_string());
+    let mut line = format!(
+        "{}.{:06} [{} {}]",
+        time.as_secs(),
+        time.subsec_micros(),
+        db,
+        origin
+    );
+    for arg in args {
+        let shown = match arg {
+            RedisValueRef::BulkString(s) | RedisValueRef::SimpleString(s) => {
+                escape_bytes(s, s.len())
+            }
+            RedisValueRef::Int(i) => i.to_string(),
+            _ => continue,
+        };
+        let _ = write!(line, " \"{}\"", shown);
+    }
+    line.into()
+}
+
+impl Monitor {
+    /// Start watching. The receiver gets every command processed from now on.
+    pub fn subscribe(&self) -> Receiver<Value> {
+        self.feed.subscribe()
+    }
+
+    /// Whether any connection is in monitor mode.
+    pub fn is_watched(&self) -> bool {
+        self.feed.receiver_count() != 0
+    }
+
+    /// Send `command`, run by `origin` against `db`, to every monitor.
+    pub fn publish(&self, db: Option<Index>, origin: Origin, command: &RedisValueRef) {
+        if !self.is_watched() {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // Fails only if the last monitor just left.
+        let _ = self.feed.send(monitor_line(time, db, origin, command));
+    }
+}
+
+#[cfg(test)]
+mod test_monitor {
+    use super::{monitor_line, Monitor, Origin};
+    use crate::test_util::command;
+    use std::time::Duration;
+
+    #[test]
+    fn test_monitor_line() {
+        let origin = Origin::Client("127.0.0.1:60866".parse().unwrap());
+        let line = monitor_line(
+            Duration::from_micros(1339518083107412),
+            Some(0),
+            origin,
+            &command(&["keys", "*"]),
+        );
+        assert_eq!(line, r#"1339518083.107412 [0 127.0.0.1:60866] "keys" "*""#);
+        let line = monitor_line(
+            Duration::from_millis(1500),
+            None,
+            Origin::Script,
+            &command(&["set", "a\n", "\"b\""]),
+        );
+        assert_eq!(line, r#"1.500000 [? script] "set" "a\n" "\"b\"""#);
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let monitor = Monitor::default();
+        // Nobody is watching, so this goes nowhere.
+        monitor.publish(Some(0), Origin::Script, &command(&["get", "a"]));
+        assert!(!monitor.is_watched());
+        let mut watcher = monitor.subscribe();
+        monitor.publish(Some(1), Origin::Script, &command(&["get", "b"]));
+        let line = watcher.recv().await.unwrap();
+        assert!(line.ends_with(br#" [1 script] "get" "b""#));
+        drop(watcher);
+        assert!(!monitor.is_watched());
+    }
+}
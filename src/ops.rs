@@ -1,17 +1,43 @@
 use bytes::Bytes;
 use std::convert::TryFrom;
 use std::fmt::Debug;
+use std::ops::Bound;
 
+use crate::bitfield::{FieldOp, FieldType, Overflow};
 use crate::bloom::{bloom_interact, BloomOps};
+use crate::clients::{ClientId, KillFilter};
+use crate::count_min::{count_min_interact, CountMinOps};
+use crate::cuckoo::{cuckoo_interact, CuckooOps};
+use crate::data_structures::bloom_filter::{bits_for, MIN_ERROR_RATE};
+use crate::data_structures::count_min_sketch::{self, dimensions};
+use crate::data_structures::heavy_keeper::size_for;
+use crate::data_structures::sorted_set::LexBound;
+use crate::data_structures::stream::{Fields, NewId, StreamId};
+use crate::geo::{
+    self, geo_interact, GeoOps, GeoOrigin, GeoSearchOptions, GeoShape, GeoSort, Unit,
+};
 use crate::hashes::{hash_interact, HashOps};
 use crate::hyperloglog::{hyperloglog_interact, HyperLogLogOps};
-use crate::keys::{key_interact, KeyOps};
-use crate::lists::{list_interact, ListOps};
+use crate::keys::{
+    key_interact, BitOperation, BitRange, KeyOps, RestoreOptions, SetCondition, SetOptions,
+    TtlUpdate,
+};
+use crate::lists::{list_interact, InsertPosition, LPosOptions, ListEnd, ListOps};
 use crate::misc::MiscOps;
+use crate::queue::{queue_interact, QueueOps, DEFAULT_VISIBILITY_MS};
+use crate::scan::ScanOptions;
 use crate::sets::{set_interact, SetOps};
-use crate::sorted_sets::{zset_interact, ZSetOps};
+use crate::sort::SortOptions;
+use crate::sorted_sets::{zset_interact, ScoreComparison, ZAddOptions, ZRangeOptions, ZSetOps};
 use crate::stack::{stack_interact, StackOps};
+use crate::streams::{
+    stream_interact, ClaimOptions, GroupRead, PendingRange, ReadFrom, ReadGroupOptions, StreamOps,
+    XAddOptions,
+};
+use crate::top_k::{top_k_interact, TopKOps, DEFAULT_DECAY, DEFAULT_DEPTH, DEFAULT_WIDTH};
+use crate::tree::{DEFAULT_SEPARATOR, DEFAULT_TREE_DEPTH, MAX_TREE_DEPTH};
 use crate::types::{ReturnValue, StateRef, StateStoreRef};
+use crate::write_effects::{track_touched, WriteEffects};
 
 use crate::types::{Count, Index, Key, RedisValueRef, Score, UTimeout, Value};
 
@@ -24,24 +50,125 @@ pub enum Ops {
     Hashes(HashOps),
     ZSets(ZSetOps),
     Blooms(BloomOps),
+    Cuckoos(CuckooOps),
+    CountMins(CountMinOps),
+    TopKs(TopKOps),
+    Queues(QueueOps),
     Stacks(StackOps),
     HyperLogLogs(HyperLogLogOps),
+    Geo(GeoOps),
+    Streams(StreamOps),
+}
+
+impl Ops {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            Ops::Keys(op) => op.write_keys(),
+            Ops::Sets(op) => op.write_keys(),
+            Ops::Lists(op) => op.write_keys(),
+            Ops::Misc(op) => op.write_keys(),
+            Ops::Hashes(op) => op.write_keys(),
+            Ops::ZSets(op) => op.write_keys(),
+            Ops::Blooms(op) => op.write_keys(),
+            Ops::Cuckoos(op) => op.write_keys(),
+            Ops::CountMins(op) => op.write_keys(),
+            Ops::TopKs(op) => op.write_keys(),
+            Ops::Queues(op) => op.write_keys(),
+            Ops::Stacks(op) => op.write_keys(),
+            Ops::HyperLogLogs(op) => op.write_keys(),
+            Ops::Geo(op) => op.write_keys(),
+            Ops::Streams(op) => op.write_keys(),
+        }
+    }
+
+    /// Name of the op, like "LPush".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ops::Keys(op) => op.name(),
+            Ops::Sets(op) => op.name(),
+            Ops::Lists(op) => op.name(),
+            Ops::Misc(op) => op.name(),
+            Ops::Hashes(op) => op.name(),
+            Ops::ZSets(op) => op.name(),
+            Ops::Blooms(op) => op.name(),
+            Ops::Cuckoos(op) => op.name(),
+            Ops::CountMins(op) => op.name(),
+            Ops::TopKs(op) => op.name(),
+            Ops::Queues(op) => op.name(),
+            Ops::Stacks(op) => op.name(),
+            Ops::HyperLogLogs(op) => op.name(),
+            Ops::Geo(op) => op.name(),
+            Ops::Streams(op) => op.name(),
+        }
+    }
+
+    /// String keys this op reads. Backed keys among them are loaded on a miss.
+    pub fn string_reads(&self) -> Vec<Key> {
+        match self {
+            Ops::Keys(op) => op.read_keys(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether this op may wait on other clients, so its run time isn't its own latency.
+    pub fn is_blocking(&self) -> bool {
+        matches!(
+            self,
+            Ops::Lists(ListOps::BLPop(..))
+                | Ops::Lists(ListOps::BRPop(..))
+                | Ops::Lists(ListOps::BLMove(..))
+                | Ops::Misc(MiscOps::Wait(..))
+                | Ops::Misc(MiscOps::WaitAof(..))
+                | Ops::Streams(StreamOps::XRead(_, _, Some(_)))
+                | Ops::Queues(QueueOps::QPop(_, _, Some(_)))
+                | Ops::Streams(StreamOps::XReadGroup(
+                    _,
+                    _,
+                    _,
+                    ReadGroupOptions { block: Some(_), .. }
+                ))
+        )
+    }
 }
 
 /// Top level interaction function. Used by the server to run
 /// operations against state.
-pub async fn op_interact(op: Ops, state: StateRef) -> ReturnValue {
-    match op {
-        Ops::Keys(op) => key_interact(op, state).await,
-        Ops::Sets(op) => set_interact(op, state).await,
-        Ops::Lists(op) => list_interact(op, state).await,
-        Ops::Hashes(op) => hash_interact(op, state).await,
-        Ops::ZSets(op) => zset_interact(op, state).await,
-        Ops::Blooms(op) => bloom_interact(op, state).await,
-        Ops::Stacks(op) => stack_interact(op, state).await,
-        Ops::HyperLogLogs(op) => hyperloglog_interact(op, state).await,
-        _ => unreachable!(),
+///
+/// Applies the op's `WriteEffects` if it succeeds, and returns them with the reply.
+pub async fn op_interact(op: Ops, state: StateRef) -> (ReturnValue, Option<WriteEffects>) {
+    let effects = WriteEffects::of(&op);
+    let interact = {
+        let state = state.clone();
+        async move {
+            match op {
+                Ops::Keys(op) => key_interact(op, state).await,
+                Ops::Sets(op) => set_interact(op, state).await,
+                Ops::Lists(op) => list_interact(op, state).await,
+                Ops::Hashes(op) => hash_interact(op, state).await,
+                Ops::ZSets(op) => zset_interact(op, state).await,
+                Ops::Blooms(op) => bloom_interact(op, state).await,
+                Ops::Cuckoos(op) => cuckoo_interact(op, state).await,
+                Ops::CountMins(op) => count_min_interact(op, state).await,
+                Ops::TopKs(op) => top_k_interact(op, state).await,
+                Ops::Queues(op) => queue_interact(op, state).await,
+                Ops::Stacks(op) => stack_interact(op, state).await,
+                Ops::HyperLogLogs(op) => hyperloglog_interact(op, state).await,
+                Ops::Geo(op) => geo_interact(op, state).await,
+                Ops::Streams(op) => stream_interact(op, state).await,
+                _ => unreachable!(),
+            }
+        }
+    };
+    let (reply, touched) = track_touched(interact).await;
+    if reply.is_error() {
+        return (reply, None);
     }
+    let effects = effects.and_then(|effects| effects.narrow(touched));
+    if let Some(effects) = &effects {
+        effects.apply(&state);
+    }
+    (reply, effects)
 }
 
 #[derive(Debug)]
@@ -157,6 +284,32 @@ impl TryFrom<&RedisValueRef> for String {
     }
 }
 
+impl TryFrom<&RedisValueRef> for f64 {
+    type Error = OpsError;
+
+    fn try_from(r: &RedisValueRef) -> Result<f64, Self::Error> {
+        match r {
+            RedisValueRef::Int(e) => Ok(*e as f64),
+            RedisValueRef::BulkString(s) => std::str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(OpsError::InvalidType),
+            _ => Err(OpsError::InvalidType),
+        }
+    }
+}
+
+impl TryFrom<&RedisValueRef> for Score {
+    type Error = OpsError;
+
+    fn try_from(r: &RedisValueRef) -> Result<Score, Self::Error> {
+        f64::try_from(r)
+            .ok()
+            .and_then(Score::new)
+            .ok_or_else(|| OpsError::InvalidArgs("value is not a valid float".into()))
+    }
+}
+
 impl TryFrom<&RedisValueRef> for Count {
     type Error = OpsError;
 
@@ -184,6 +337,1175 @@ fn ensure_even<T>(v: &[T]) -> Result<(), OpsError> {
     Ok(())
 }
 
+/// Check whether an argument is the given (case insensitive) flag, like WITHSCORES.
+fn is_flag(arg: &RedisValueRef, flag: &str) -> bool {
+    match arg {
+        RedisValueRef::BulkString(s) => s.eq_ignore_ascii_case(flag.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Parse an expire time in `unit_ms` units into milliseconds. Must be positive.
+fn ttl_ms(arg: &RedisValueRef, unit_ms: u64, command: &str) -> Result<u64, OpsError> {
+    match Count::try_from(arg)? {
+        ttl if ttl > 0 => Ok((ttl as u64).saturating_mul(unit_ms)),
+        _ => Err(OpsError::InvalidArgs(format!(
+            "invalid expire time in '{}' command",
+            command
+        ))),
+    }
+}
+
+/// Parse SET key value [EX seconds|PX milliseconds|KEEPTTL] [NX|XX].
+fn set_args(tail: &[&RedisValueRef]) -> Result<(Key, Value, SetOptions), OpsError> {
+    verify_size_lower(tail, 2)?;
+    let key = Key::try_from(tail[0])?;
+    let value = Value::try_from(tail[1])?;
+    let mut opts = SetOptions::default();
+    let mut args = tail[2..].iter();
+    while let Some(arg) = args.next() {
+        let expiring = opts.ttl_ms.is_some() || opts.keep_ttl;
+        let conditional = opts.condition != SetCondition::Always;
+        if (is_flag(arg, "ex") || is_flag(arg, "px")) && !expiring {
+            let unit_ms = if is_flag(arg, "ex") { 1000 } else { 1 };
+            let ttl = args.next().ok_or(OpsError::SyntaxError)?;
+            opts.ttl_ms = Some(ttl_ms(ttl, unit_ms, "set")?);
+        } else if is_flag(arg, "keepttl") && !expiring {
+            opts.keep_ttl = true;
+        } else if is_flag(arg, "nx") && !conditional {
+            opts.condition = SetCondition::IfMissing;
+        } else if is_flag(arg, "xx") && !conditional {
+            opts.condition = SetCondition::IfExists;
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok((key, value, opts))
+}
+
+/// Parse GETEX key [EX seconds|PX milliseconds|EXAT unix-time-seconds|
+/// PXAT unix-time-milliseconds|PERSIST].
+fn getex_args(tail: &[&RedisValueRef]) -> Result<(Key, TtlUpdate), OpsError> {
+    verify_size_lower(tail, 1)?;
+    let key = Key::try_from(tail[0])?;
+    let ttl = match &tail[1..] {
+        [] => TtlUpdate::Keep,
+        [opt] if is_flag(opt, "persist") => TtlUpdate::Persist,
+        [opt, ttl] if is_flag(opt, "ex") => TtlUpdate::In(ttl_ms(ttl, 1000, "getex")?),
+        [opt, ttl] if is_flag(opt, "px") => TtlUpdate::In(ttl_ms(ttl, 1, "getex")?),
+        [opt, at] if is_flag(opt, "exat") => TtlUpdate::At(ttl_ms(at, 1000, "getex")?),
+        [opt, at] if is_flag(opt, "pxat") => TtlUpdate::At(ttl_ms(at, 1, "getex")?),
+        _ => return Err(OpsError::SyntaxError),
+    };
+    Ok((key, ttl))
+}
+
+/// Bitmaps never grow past 512MB, like redis, nor past proto-max-bulk-len.
+/// BF.RESERVE's first filter, count-min sketches and top-k trackers are held
+/// to the same limit.
+const MAX_BITMAP_LEN: u64 = 512 * 1024 * 1024;
+
+/// Parse the bit offset of SETBIT or GETBIT.
+fn bit_offset(arg: &RedisValueRef, state_store: &StateStoreRef) -> Result<u64, OpsError> {
+    let max_len = MAX_BITMAP_LEN.min(state_store.config.resp_limits().max_bulk_len);
+    match Count::try_from(arg) {
+        Ok(offset) if offset >= 0 && (offset as u64) / 8 < max_len => Ok(offset as u64),
+        _ => Err(OpsError::InvalidArgs(
+            "bit offset is not an integer or out of range".into(),
+        )),
+    }
+}
+
+/// Parse a bit, 0 or 1.
+fn bit(arg: &RedisValueRef, what: &str) -> Result<bool, OpsError> {
+    match Count::try_from(arg) {
+        Ok(0) => Ok(false),
+        Ok(1) => Ok(true),
+        _ => Err(OpsError::InvalidArgs(format!(
+            "{} is not an integer or out of range",
+            what
+        ))),
+    }
+}
+
+/// Parse `start [end [BYTE|BIT]]` for BITCOUNT and BITPOS.
+fn bit_range(args: &[&RedisValueRef]) -> Result<BitRange, OpsError> {
+    let mut range = BitRange {
+        start: Count::try_from(args[0])?,
+        ..Default::default()
+    };
+    match &args[1..] {
+        [] => {}
+        [end] => range.end = Some(Count::try_from(*end)?),
+        [end, unit] if is_flag(unit, "byte") || is_flag(unit, "bit") => {
+            range.end = Some(Count::try_from(*end)?);
+            range.bits = is_flag(unit, "bit");
+        }
+        _ => return Err(OpsError::SyntaxError),
+    }
+    Ok(range)
+}
+
+/// Parse a BITFIELD type, like `i8` or `u16`.
+fn field_type(arg: &RedisValueRef) -> Result<FieldType, OpsError> {
+    FieldType::parse(&Value::try_from(arg)?).ok_or_else(|| {
+        OpsError::InvalidArgs(
+            "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is."
+                .into(),
+        )
+    })
+}
+
+/// Parse a BITFIELD offset in bits, or in fields of type `ty` when it starts with `#`.
+/// The whole field has to fit in a bitmap.
+fn field_offset(
+    arg: &RedisValueRef,
+    ty: FieldType,
+    state_store: &StateStoreRef,
+) -> Result<u64, OpsError> {
+    let out_of_range =
+        || OpsError::InvalidArgs("bit offset is not an integer or out of range".into());
+    let offset = Value::try_from(arg)?;
+    let (offset, unit) = match offset.split_first() {
+        Some((b'#', offset)) => (offset, ty.bits as u64),
+        _ => (&offset[..], 1),
+    };
+    let offset: u64 = std::str::from_utf8(offset)
+        .ok()
+        .and_then(|offset| offset.parse().ok())
+        .ok_or_else(out_of_range)?;
+    let max_len = MAX_BITMAP_LEN.min(state_store.config.resp_limits().max_bulk_len);
+    match offset.checked_mul(unit) {
+        Some(offset) if offset.saturating_add(ty.bits as u64 - 1) / 8 < max_len => Ok(offset),
+        _ => Err(out_of_range()),
+    }
+}
+
+/// Parse the subcommands of BITFIELD, or with `read_only` of BITFIELD_RO.
+fn bitfield_args(
+    tail: &[&RedisValueRef],
+    read_only: bool,
+    state_store: &StateStoreRef,
+) -> Result<(Key, Vec<FieldOp>), OpsError> {
+    verify_size_lower(tail, 1)?;
+    let key = Key::try_from(tail[0])?;
+    let mut ops = Vec::new();
+    let mut args = tail[1..].iter();
+    while let Some(subcommand) = args.next() {
+        let mut next = || args.next().ok_or(OpsError::SyntaxError);
+        let op = if is_flag(subcommand, "get") {
+            let ty = field_type(next()?)?;
+            FieldOp::Get(ty, field_offset(next()?, ty, state_store)?)
+        } else if read_only {
+            return Err(OpsError::InvalidArgs(
+                "BITFIELD_RO only supports the GET subcommand".into(),
+            ));
+        } else if is_flag(subcommand, "set") || is_flag(subcommand, "incrby") {
+            let ty = field_type(next()?)?;
+            let offset = field_offset(next()?, ty, state_store)?;
+            let value = Count::try_from(*next()?)?;
+            if is_flag(subcommand, "set") {
+                FieldOp::Set(ty, offset, value)
+            } else {
+                FieldOp::IncrBy(ty, offset, value)
+            }
+        } else if is_flag(subcommand, "overflow") {
+            let behaviour = next()?;
+            FieldOp::Overflow(if is_flag(behaviour, "wrap") {
+                Overflow::Wrap
+            } else if is_flag(behaviour, "sat") {
+                Overflow::Sat
+            } else if is_flag(behaviour, "fail") {
+                Overflow::Fail
+            } else {
+                return Err(OpsError::InvalidArgs(
+                    "Invalid OVERFLOW type specified".into(),
+                ));
+            })
+        } else {
+            return Err(OpsError::SyntaxError);
+        };
+        ops.push(op);
+    }
+    Ok((key, ops))
+}
+
+/// Parse BITOP operation destkey key [key ...].
+fn bitop_args(tail: &[&RedisValueRef]) -> Result<KeyOps, OpsError> {
+    verify_size_lower(tail, 3)?;
+    let op = if is_flag(tail[0], "and") {
+        BitOperation::And
+    } else if is_flag(tail[0], "or") {
+        BitOperation::Or
+    } else if is_flag(tail[0], "xor") {
+        BitOperation::Xor
+    } else if is_flag(tail[0], "not") {
+        BitOperation::Not
+    } else {
+        return Err(OpsError::SyntaxError);
+    };
+    let sources = collect_from_tail(&tail[2..])?;
+    if op == BitOperation::Not && sources.len() != 1 {
+        return Err(OpsError::InvalidArgs(
+            "BITOP NOT must be called with a single source key.".into(),
+        ));
+    }
+    Ok(KeyOps::BitOp(op, Key::try_from(tail[1])?, sources))
+}
+
+/// Parse RESTORE key ttl payload [REPLACE] [ABSTTL].
+fn restore_args(tail: &[&RedisValueRef]) -> Result<(Key, Value, RestoreOptions), OpsError> {
+    verify_size_lower(tail, 3)?;
+    let key = Key::try_from(tail[0])?;
+    let ttl = Count::try_from(tail[1])?;
+    if ttl < 0 {
+        return Err(OpsError::InvalidArgs(
+            "Invalid TTL value, must be >= 0".into(),
+        ));
+    }
+    let payload = Value::try_from(tail[2])?;
+    let mut opts = RestoreOptions {
+        ttl_ms: ttl as u64,
+        ..Default::default()
+    };
+    for arg in tail[3..].iter() {
+        if is_flag(arg, "replace") {
+            opts.replace = true;
+        } else if is_flag(arg, "absttl") {
+            opts.absolute = true;
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok((key, payload, opts))
+}
+
+/// Parse ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...].
+fn zadd_args(tail: &[&RedisValueRef]) -> Result<(Key, ZAddOptions, RVec<(Score, Key)>), OpsError> {
+    verify_size_lower(tail, 3)?;
+    let key = Key::try_from(tail[0])?;
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    let mut opts = ZAddOptions::default();
+    let mut pairs = &tail[1..];
+    while let Some((arg, rest)) = pairs.split_first() {
+        if is_flag(arg, "nx") {
+            nx = true;
+        } else if is_flag(arg, "xx") {
+            xx = true;
+        } else if is_flag(arg, "gt") {
+            gt = true;
+        } else if is_flag(arg, "lt") {
+            lt = true;
+        } else if is_flag(arg, "ch") {
+            opts.changed = true;
+        } else if is_flag(arg, "incr") {
+            opts.incr = true;
+        } else {
+            break;
+        }
+        pairs = rest;
+    }
+    if nx && xx {
+        return Err(OpsError::InvalidArgs(
+            "XX and NX options at the same time are not compatible".into(),
+        ));
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return Err(OpsError::InvalidArgs(
+            "GT, LT, and/or NX options at the same time are not compatible".into(),
+        ));
+    }
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(OpsError::SyntaxError);
+    }
+    if opts.incr && pairs.len() != 2 {
+        return Err(OpsError::InvalidArgs(
+            "INCR option supports a single increment-element pair".into(),
+        ));
+    }
+    opts.condition = match (nx, xx) {
+        (true, _) => SetCondition::IfMissing,
+        (_, true) => SetCondition::IfExists,
+        _ => SetCondition::Always,
+    };
+    opts.comparison = match (gt, lt) {
+        (true, _) => ScoreComparison::Greater,
+        (_, true) => ScoreComparison::Less,
+        _ => ScoreComparison::Always,
+    };
+    Ok((key, opts, get_key_value_pairs(pairs)?))
+}
+
+/// Parse a longitude and latitude redis can index.
+fn lon_lat(lon: &RedisValueRef, lat: &RedisValueRef) -> Result<(f64, f64), OpsError> {
+    let (lon, lat) = (f64::try_from(lon)?, f64::try_from(lat)?);
+    if !geo::is_valid(lon, lat) {
+        return Err(OpsError::InvalidArgs(format!(
+            "invalid longitude,latitude pair {:.6},{:.6}",
+            lon, lat
+        )));
+    }
+    Ok((lon, lat))
+}
+
+fn geo_unit(arg: &RedisValueRef) -> Result<Unit, OpsError> {
+    Unit::parse(&Value::try_from(arg)?).ok_or_else(|| {
+        OpsError::InvalidArgs("unsupported unit provided. please use M, KM, FT, MI".into())
+    })
+}
+
+/// Parse a distance in `unit`, into meters.
+fn geo_distance(arg: &RedisValueRef, unit: Unit) -> Result<f64, OpsError> {
+    match f64::try_from(arg) {
+        Ok(distance) if distance >= 0.0 => Ok(distance * unit.meters()),
+        _ => Err(OpsError::InvalidArgs("need numeric radius".into())),
+    }
+}
+
+/// Parse GEOADD key [NX|XX] [CH] longitude latitude member [...].
+fn geoadd_args(
+    tail: &[&RedisValueRef],
+) -> Result<(Key, ZAddOptions, RVec<(Score, Key)>), OpsError> {
+    verify_size_lower(tail, 4)?;
+    let key = Key::try_from(tail[0])?;
+    let mut opts = ZAddOptions::default();
+    let mut args = &tail[1..];
+    while let Some((arg, rest)) = args.split_first() {
+        if is_flag(arg, "nx") && opts.condition != SetCondition::IfExists {
+            opts.condition = SetCondition::IfMissing;
+        } else if is_flag(arg, "xx") && opts.condition != SetCondition::IfMissing {
+            opts.condition = SetCondition::IfExists;
+        } else if is_flag(arg, "ch") {
+            opts.changed = true;
+        } else {
+            break;
+        }
+        args = rest;
+    }
+    if args.is_empty() || args.len() % 3 != 0 {
+        return Err(OpsError::SyntaxError);
+    }
+    let mut locations = RVec::new();
+    for location in args.chunks(3) {
+        let (lon, lat) = lon_lat(location[0], location[1])?;
+        let score = geo::score(lon, lat).expect("valid locations have a score");
+        locations.push((score, Key::try_from(location[2])?));
+    }
+    Ok((key, opts, locations))
+}
+
+/// Parse GEOSEARCH key FROMMEMBER member|FROMLONLAT longitude latitude
+/// BYRADIUS radius unit|BYBOX width height unit [ASC|DESC] [COUNT count [ANY]]
+/// [WITHCOORD] [WITHDIST] [WITHHASH].
+fn geosearch_args(tail: &[&RedisValueRef]) -> Result<(Key, GeoSearchOptions), OpsError> {
+    verify_size_lower(tail, 1)?;
+    let key = Key::try_from(tail[0])?;
+    let (mut origin, mut shape) = (None, None);
+    let mut unit = Unit::Meters;
+    let (mut sort, mut count, mut any) = (None, None, false);
+    let (mut with_coord, mut with_dist, mut with_hash) = (false, false, false);
+    let mut args = tail[1..].iter();
+    while let Some(arg) = args.next() {
+        let mut next = || args.next().copied().ok_or(OpsError::SyntaxError);
+        if is_flag(arg, "frommember") && origin.is_none() {
+            origin = Some(GeoOrigin::Member(Key::try_from(next()?)?));
+        } else if is_flag(arg, "fromlonlat") && origin.is_none() {
+            let (lon, lat) = (next()?, next()?);
+            let (lon, lat) = lon_lat(lon, lat)?;
+            origin = Some(GeoOrigin::LonLat(lon, lat));
+        } else if is_flag(arg, "byradius") && shape.is_none() {
+            let radius = next()?;
+            unit = geo_unit(next()?)?;
+            shape = Some(GeoShape::Radius(geo_distance(radius, unit)?));
+        } else if is_flag(arg, "bybox") && shape.is_none() {
+            let (width, height) = (next()?, next()?);
+            unit = geo_unit(next()?)?;
+            let (width, height) = (geo_distance(width, unit)?, geo_distance(height, unit)?);
+            shape = Some(GeoShape::Box(width, height));
+        } else if is_flag(arg, "asc") {
+            sort = Some(GeoSort::Asc);
+        } else if is_flag(arg, "desc") {
+            sort = Some(GeoSort::Desc);
+        } else if is_flag(arg, "count") {
+            match Count::try_from(next()?)? {
+                n if n > 0 => count = Some(n as usize),
+                _ => return Err(OpsError::InvalidArgs("COUNT must be > 0".into())),
+            }
+        } else if is_flag(arg, "any") {
+            any = true;
+        } else if is_flag(arg, "withcoord") {
+            with_coord = true;
+        } else if is_flag(arg, "withdist") {
+            with_dist = true;
+        } else if is_flag(arg, "withhash") {
+            with_hash = true;
+        } else if is_flag(arg, "frommember") || is_flag(arg, "fromlonlat") {
+            return Err(OpsError::InvalidArgs(
+                "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH".into(),
+            ));
+        } else if is_flag(arg, "byradius") || is_flag(arg, "bybox") {
+            return Err(OpsError::InvalidArgs(
+                "exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH".into(),
+            ));
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    let origin = origin.ok_or_else(|| {
+        OpsError::InvalidArgs(
+            "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH".into(),
+        )
+    })?;
+    let shape = shape.ok_or_else(|| {
+        OpsError::InvalidArgs(
+            "exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH".into(),
+        )
+    })?;
+    if any && count.is_none() {
+        return Err(OpsError::InvalidArgs(
+            "the ANY argument requires COUNT argument".into(),
+        ));
+    }
+    let opts = GeoSearchOptions {
+        origin,
+        shape,
+        unit,
+        sort,
+        count: count.map(|count| (count, any)),
+        with_coord,
+        with_dist,
+        with_hash,
+    };
+    Ok((key, opts))
+}
+
+fn invalid_stream_id() -> OpsError {
+    OpsError::InvalidArgs("Invalid stream ID specified as stream command argument".into())
+}
+
+/// Parse a COUNT or MAXLEN argument, which can't be negative.
+fn stream_len(arg: &RedisValueRef, name: &str) -> Result<usize, OpsError> {
+    let len = Count::try_from(arg)?;
+    if len < 0 {
+        return Err(OpsError::InvalidArgs(format!(
+            "The {} argument must be >= 0.",
+            name
+        )));
+    }
+    Ok(len as usize)
+}
+
+/// Parse XADD key [NOMKSTREAM] [MAXLEN [=|~] threshold] id field value [field value ...].
+/// `~` trims exactly, like `=`.
+fn xadd_args(tail: &[&RedisValueRef]) -> Result<(Key, XAddOptions, NewId, Fields), OpsError> {
+    verify_size_lower(tail, 4)?;
+    let key = Key::try_from(tail[0])?;
+    let mut opts = XAddOptions::default();
+    let mut args = tail[1..].iter().copied();
+    let id = loop {
+        let arg = args.next().ok_or(OpsError::SyntaxError)?;
+        if is_flag(arg, "nomkstream") {
+            opts.no_mkstream = true;
+        } else if is_flag(arg, "maxlen") {
+            let mut threshold = args.next().ok_or(OpsError::SyntaxError)?;
+            if is_flag(threshold, "=") || is_flag(threshold, "~") {
+                threshold = args.next().ok_or(OpsError::SyntaxError)?;
+            }
+            opts.maxlen = Some(stream_len(threshold, "MAXLEN")?);
+        } else {
+            break NewId::parse(&Value::try_from(arg)?).ok_or_else(invalid_stream_id)?;
+        }
+    };
+    let pairs: Vec<&RedisValueRef> = args.collect();
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(OpsError::InvalidArgs(
+            "wrong number of arguments for 'xadd' command".into(),
+        ));
+    }
+    let fields = pairs
+        .chunks(2)
+        .map(|pair| Ok((Value::try_from(pair[0])?, Value::try_from(pair[1])?)))
+        .collect::<Result<_, OpsError>>()?;
+    Ok((key, opts, id, fields))
+}
+
+/// Parse one end of an XRANGE range: `-`, `+`, an ID, or `(` and an ID to
+/// leave it out. A bare `<ms>` starts at its first entry, and ends at its last.
+fn stream_bound(arg: &RedisValueRef, start: bool) -> Result<StreamId, OpsError> {
+    let bound = Value::try_from(arg)?;
+    match &bound[..] {
+        b"-" => return Ok(StreamId::MIN),
+        b"+" => return Ok(StreamId::MAX),
+        _ => {}
+    }
+    let (exclusive, id) = match bound.split_first() {
+        Some((b'(', id)) => (true, id),
+        _ => (false, &bound[..]),
+    };
+    let id = StreamId::parse(id, if start { 0 } else { u64::MAX }).ok_or_else(invalid_stream_id)?;
+    if !exclusive {
+        return Ok(id);
+    }
+    let id = if start { id.next() } else { id.prev() };
+    id.ok_or_else(|| OpsError::InvalidArgs("invalid start or end ID for the interval".into()))
+}
+
+/// Parse the `start end [COUNT count]` of XRANGE, or XREVRANGE's `end start
+/// [COUNT count]` when `rev`. The range is returned smallest ID first.
+fn xrange_args(
+    tail: &[&RedisValueRef],
+    rev: bool,
+) -> Result<(Key, StreamId, StreamId, Option<usize>), OpsError> {
+    let count = match tail.len() {
+        3 => None,
+        5 if is_flag(tail[3], "count") => Some(stream_len(tail[4], "COUNT")?),
+        _ => return Err(OpsError::SyntaxError),
+    };
+    let key = Key::try_from(tail[0])?;
+    let (start, end) = if rev {
+        (tail[2], tail[1])
+    } else {
+        (tail[1], tail[2])
+    };
+    Ok((
+        key,
+        stream_bound(start, true)?,
+        stream_bound(end, false)?,
+        count,
+    ))
+}
+
+/// Parse a stream ID, with a bare `<ms>` meaning its first sequence number.
+fn stream_id(arg: &RedisValueRef) -> Result<StreamId, OpsError> {
+    StreamId::parse(&Value::try_from(arg)?, 0).ok_or_else(invalid_stream_id)
+}
+
+/// Parse the `[COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...]
+/// id [id ...]` of XREAD (`command`, which takes no NOACK) or XREADGROUP.
+/// Returns the options and each key with its ID.
+#[allow(clippy::type_complexity)]
+fn stream_read_args<'a>(
+    tail: &[&'a RedisValueRef],
+    command: &str,
+) -> Result<(ReadGroupOptions, Vec<(Key, &'a RedisValueRef)>), OpsError> {
+    let mut opts = ReadGroupOptions::default();
+    let mut args = tail.iter().copied();
+    loop {
+        let arg = args.next().ok_or(OpsError::SyntaxError)?;
+        let mut next = || args.next().ok_or(OpsError::SyntaxError);
+        if is_flag(arg, "count") {
+            // COUNT 0 doesn't limit reads.
+            opts.count = Some(stream_len(next()?, "COUNT")?).filter(|count| *count != 0);
+        } else if is_flag(arg, "block") {
+            let ms = UTimeout::try_from(next()?)?;
+            if ms < 0 {
+                return Err(OpsError::InvalidArgs("timeout is negative".into()));
+            }
+            opts.block = Some(ms);
+        } else if is_flag(arg, "noack") && command == "xreadgroup" {
+            opts.noack = true;
+        } else if is_flag(arg, "streams") {
+            break;
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    let streams: Vec<&RedisValueRef> = args.collect();
+    if streams.is_empty() || streams.len() % 2 != 0 {
+        return Err(OpsError::InvalidArgs(format!(
+            "Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.",
+            command,
+            if command == "xread" { "$" } else { ">" }
+        )));
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let mut keyed = Vec::with_capacity(keys.len());
+    for (key, id) in keys.iter().zip(ids) {
+        keyed.push((Key::try_from(*key)?, *id));
+    }
+    Ok((opts, keyed))
+}
+
+/// Parse XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...].
+fn xread_args(
+    tail: &[&RedisValueRef],
+) -> Result<(RVec<(Key, ReadFrom)>, Option<usize>, Option<UTimeout>), OpsError> {
+    let (opts, streams) = stream_read_args(tail, "xread")?;
+    let mut from = RVec::new();
+    for (key, id) in streams {
+        let from_id = if is_flag(id, "$") {
+            ReadFrom::End
+        } else {
+            ReadFrom::After(stream_id(id)?)
+        };
+        from.push((key, from_id));
+    }
+    Ok((from, opts.count, opts.block))
+}
+
+/// Parse XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds]
+/// [NOACK] STREAMS key [key ...] id [id ...].
+fn xreadgroup_args(
+    tail: &[&RedisValueRef],
+) -> Result<(Key, Key, RVec<(Key, GroupRead)>, ReadGroupOptions), OpsError> {
+    verify_size_lower(tail, 6)?;
+    if !is_flag(tail[0], "group") {
+        return Err(OpsError::SyntaxError);
+    }
+    let (group, consumer) = (Key::try_from(tail[1])?, Key::try_from(tail[2])?);
+    let (opts, streams) = stream_read_args(&tail[3..], "xreadgroup")?;
+    let mut from = RVec::new();
+    for (key, id) in streams {
+        let read = if is_flag(id, ">") {
+            GroupRead::New
+        } else {
+            GroupRead::Pending(stream_id(id)?)
+        };
+        from.push((key, read));
+    }
+    Ok((group, consumer, from, opts))
+}
+
+/// Parse a group's `id|$`.
+fn group_start(id: &RedisValueRef) -> Result<ReadFrom, OpsError> {
+    if is_flag(id, "$") {
+        Ok(ReadFrom::End)
+    } else {
+        Ok(ReadFrom::After(stream_id(id)?))
+    }
+}
+
+/// Parse XGROUP CREATE key group id|$ [MKSTREAM], XGROUP SETID key group
+/// id|$ or XGROUP DESTROY key group.
+fn xgroup_args(tail: &[&RedisValueRef]) -> Result<Ops, OpsError> {
+    verify_size_lower(tail, 3)?;
+    let (key, group) = (Key::try_from(tail[1])?, Key::try_from(tail[2])?);
+    if is_flag(tail[0], "create") {
+        let (id, mkstream) = match &tail[3..] {
+            [id] => (id, false),
+            [id, opt] if is_flag(opt, "mkstream") => (id, true),
+            _ => return Err(OpsError::SyntaxError),
+        };
+        Ok(Ops::Streams(StreamOps::XGroupCreate(
+            key,
+            group,
+            group_start(id)?,
+            mkstream,
+        )))
+    } else if is_flag(tail[0], "setid") {
+        verify_size(tail, 4)?;
+        Ok(Ops::Streams(StreamOps::XGroupSetId(
+            key,
+            group,
+            group_start(tail[3])?,
+        )))
+    } else if is_flag(tail[0], "destroy") {
+        verify_size(tail, 3)?;
+        Ok(Ops::Streams(StreamOps::XGroupDestroy(key, group)))
+    } else {
+        Err(OpsError::InvalidArgs("Unknown XGROUP subcommand".into()))
+    }
+}
+
+/// Parse XPENDING key group [[IDLE min-idle-time] start end count [consumer]].
+fn xpending_args(tail: &[&RedisValueRef]) -> Result<(Key, Key, Option<PendingRange>), OpsError> {
+    verify_size_lower(tail, 2)?;
+    let (key, group) = (Key::try_from(tail[0])?, Key::try_from(tail[1])?);
+    let (min_idle, rest) = match &tail[2..] {
+        [] => return Ok((key, group, None)),
+        [idle, min_idle, rest @ ..] if is_flag(idle, "idle") => {
+            (stream_len(min_idle, "IDLE")? as u64, rest)
+        }
+        rest => (0, rest),
+    };
+    let (start, end, count, consumer) = match rest {
+        [start, end, count] => (start, end, count, None),
+        [start, end, count, consumer] => (start, end, count, Some(Key::try_from(*consumer)?)),
+        _ => return Err(OpsError::SyntaxError),
+    };
+    let range = PendingRange {
+        min_idle,
+        start: stream_bound(start, true)?,
+        end: stream_bound(end, false)?,
+        count: stream_len(count, "COUNT")?,
+        consumer,
+    };
+    Ok((key, group, Some(range)))
+}
+
+/// Parse XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms]
+/// [TIME unix-time-milliseconds] [RETRYCOUNT count] [FORCE] [JUSTID].
+#[allow(clippy::type_complexity)]
+fn xclaim_args(
+    tail: &[&RedisValueRef],
+) -> Result<(Key, Key, Key, u64, RVec<StreamId>, ClaimOptions), OpsError> {
+    verify_size_lower(tail, 5)?;
+    let (key, group) = (Key::try_from(tail[0])?, Key::try_from(tail[1])?);
+    let consumer = Key::try_from(tail[2])?;
+    let min_idle = stream_len(tail[3], "min-idle-time")? as u64;
+    let mut args = tail[4..].iter().copied().peekable();
+    // IDs run up to the first option.
+    let mut ids = RVec::new();
+    while let Some(id) = args.peek() {
+        match StreamId::parse(&Value::try_from(*id)?, 0) {
+            Some(id) => ids.push(id),
+            None => break,
+        }
+        args.next();
+    }
+    if ids.is_empty() {
+        return Err(invalid_stream_id());
+    }
+    let mut opts = ClaimOptions::default();
+    while let Some(arg) = args.next() {
+        let mut next = || args.next().ok_or(OpsError::SyntaxError);
+        if is_flag(arg, "idle") {
+            opts.idle = Some(stream_len(next()?, "IDLE")? as u64);
+        } else if is_flag(arg, "time") {
+            opts.time = Some(stream_len(next()?, "TIME")? as u64);
+        } else if is_flag(arg, "retrycount") {
+            opts.retry_count = Some(stream_len(next()?, "RETRYCOUNT")? as u64);
+        } else if is_flag(arg, "force") {
+            opts.force = true;
+        } else if is_flag(arg, "justid") {
+            opts.just_id = true;
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok((key, group, consumer, min_idle, ids, opts))
+}
+
+/// Parse XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID].
+#[allow(clippy::type_complexity)]
+fn xautoclaim_args(
+    tail: &[&RedisValueRef],
+) -> Result<(Key, Key, Key, u64, StreamId, usize, bool), OpsError> {
+    verify_size_lower(tail, 5)?;
+    let (key, group) = (Key::try_from(tail[0])?, Key::try_from(tail[1])?);
+    let consumer = Key::try_from(tail[2])?;
+    let min_idle = stream_len(tail[3], "min-idle-time")? as u64;
+    let start = stream_bound(tail[4], true)?;
+    let (mut count, mut just_id) = (100, false);
+    let mut args = tail[5..].iter().copied();
+    while let Some(arg) = args.next() {
+        if is_flag(arg, "count") {
+            count = stream_len(args.next().ok_or(OpsError::SyntaxError)?, "COUNT")?;
+            if count == 0 {
+                return Err(OpsError::InvalidArgs("COUNT must be > 0".into()));
+            }
+        } else if is_flag(arg, "justid") {
+            just_id = true;
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok((key, group, consumer, min_idle, start, count, just_id))
+}
+
+/// Parse CMS.MERGE's `destination numkeys source... [WEIGHTS weight...]`.
+/// Sources weigh 1 without WEIGHTS.
+fn cms_merge_args(tail: &[&RedisValueRef]) -> Result<(Key, RVec<(Key, Count)>), OpsError> {
+    verify_size_lower(tail, 3)?;
+    let dest = Key::try_from(tail[0])?;
+    let num_keys = match Count::try_from(tail[1])? {
+        n if n > 0 => n as usize,
+        _ => return Err(OpsError::InvalidArgs("CMS: invalid numkeys".into())),
+    };
+    let sources = tail.get(2..2 + num_keys).ok_or(OpsError::SyntaxError)?;
+    let weights = match &tail[2 + num_keys..] {
+        [] => vec![1; num_keys],
+        [weights, rest @ ..] if is_flag(weights, "weights") && rest.len() == num_keys => rest
+            .iter()
+            .map(|weight| Count::try_from(*weight))
+            .collect::<Result<_, _>>()?,
+        _ => return Err(OpsError::SyntaxError),
+    };
+    let sources = sources
+        .iter()
+        .zip(weights)
+        .map(|(key, weight)| Ok((Key::try_from(*key)?, weight)))
+        .collect::<Result<_, OpsError>>()?;
+    Ok((dest, sources))
+}
+
+/// Parse TOPK.RESERVE's `key topk [width depth decay]`.
+fn topk_reserve_args(
+    tail: &[&RedisValueRef],
+    state_store: &StateStoreRef,
+) -> Result<Ops, OpsError> {
+    if tail.len() != 2 && tail.len() != 5 {
+        return Err(OpsError::WrongNumberOfArgs(2, tail.len()));
+    }
+    let key = Key::try_from(tail[0])?;
+    let positive = |arg: &RedisValueRef, name: &str| match Count::try_from(arg)? {
+        n if n > 0 => Ok(n as usize),
+        _ => Err(OpsError::InvalidArgs(format!("TopK: invalid {}", name))),
+    };
+    let k = positive(tail[1], "k")?;
+    let (width, depth, decay) = match tail.get(2..) {
+        Some([width, depth, decay]) => (
+            positive(*width, "width")?,
+            positive(*depth, "depth")?,
+            f64::try_from(*decay)?,
+        ),
+        _ => (DEFAULT_WIDTH, DEFAULT_DEPTH, DEFAULT_DECAY),
+    };
+    if decay.is_nan() || decay <= 0.0 || decay > 1.0 {
+        return Err(OpsError::InvalidArgs("TopK: invalid decay value".into()));
+    }
+    let max_len = MAX_BITMAP_LEN.min(state_store.config.resp_limits().max_bulk_len);
+    match size_for(k, width, depth) {
+        Some(size) if size as u64 <= max_len => {}
+        _ => {
+            return Err(OpsError::InvalidArgs(
+                "TopK: tracker would be larger than proto-max-bulk-len".into(),
+            ))
+        }
+    }
+    Ok(Ops::TopKs(TopKOps::TopKReserve(
+        key, k, width, depth, decay,
+    )))
+}
+
+/// Parse QPOP's `key [VISIBILITY ms] [BLOCK ms]`.
+fn qpop_args(tail: &[&RedisValueRef]) -> Result<Ops, OpsError> {
+    verify_size_lower(tail, 1)?;
+    let key = Key::try_from(tail[0])?;
+    let (mut visibility_ms, mut block) = (DEFAULT_VISIBILITY_MS, None);
+    let mut args = tail[1..].iter().copied();
+    while let Some(arg) = args.next() {
+        let ms = Count::try_from(args.next().ok_or(OpsError::SyntaxError)?)?;
+        if ms < 0 {
+            return Err(OpsError::InvalidArgs("timeout is negative".into()));
+        }
+        if is_flag(arg, "visibility") {
+            visibility_ms = ms as u64;
+        } else if is_flag(arg, "block") {
+            block = Some(ms);
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok(Ops::Queues(QueueOps::QPop(key, visibility_ms, block)))
+}
+
+/// Refuse sketches with more bytes of counters than a bitmap may have.
+fn cms_size(width: usize, depth: usize, state_store: &StateStoreRef) -> Result<(), OpsError> {
+    let max_len = MAX_BITMAP_LEN.min(state_store.config.resp_limits().max_bulk_len);
+    match count_min_sketch::size_for(width, depth) {
+        Some(size) if size as u64 <= max_len => Ok(()),
+        _ => Err(OpsError::InvalidArgs(
+            "CMS: sketch would be larger than proto-max-bulk-len".into(),
+        )),
+    }
+}
+
+/// A CMS.INITBYPROB argument, which must be between 0 and 1.
+fn cms_fraction(arg: &RedisValueRef, name: &str) -> Result<f64, OpsError> {
+    match f64::try_from(arg)? {
+        fraction if fraction > 0.0 && fraction < 1.0 => Ok(fraction),
+        _ => Err(OpsError::InvalidArgs(format!(
+            "CMS: invalid {} value",
+            name
+        ))),
+    }
+}
+
+/// Parse one end of a ZRANGEBYSCORE range: a score, `(score` to exclude it,
+/// or `-inf`/`+inf`.
+fn score_bound(r: &RedisValueRef) -> Result<Bound<Score>, OpsError> {
+    let err = || OpsError::InvalidArgs("min or max is not a float".into());
+    let bound = Value::try_from(r)?;
+    let (exclusive, score) = match bound.split_first() {
+        Some((b'(', score)) => (true, score),
+        _ => (false, &bound[..]),
+    };
+    let score = std::str::from_utf8(score)
+        .ok()
+        .and_then(|score| score.parse().ok())
+        .and_then(Score::new)
+        .ok_or_else(err)?;
+    if exclusive {
+        Ok(Bound::Excluded(score))
+    } else {
+        Ok(Bound::Included(score))
+    }
+}
+
+/// Parse one end of a ZRANGEBYLEX range: `-`, `+`, `[member` or `(member`.
+fn lex_bound(r: &RedisValueRef) -> Result<LexBound, OpsError> {
+    let bound = Value::try_from(r)?;
+    match bound.first() {
+        Some(b'-') if bound.len() == 1 => Ok(LexBound::Min),
+        Some(b'+') if bound.len() == 1 => Ok(LexBound::Max),
+        Some(b'[') => Ok(LexBound::Included(bound.slice(1..))),
+        Some(b'(') => Ok(LexBound::Excluded(bound.slice(1..))),
+        _ => Err(OpsError::InvalidArgs(
+            "min or max not valid string range item".into(),
+        )),
+    }
+}
+
+/// Parse the `[WITHSCORES] [LIMIT offset count]` options of ZRANGEBYSCORE,
+/// or just LIMIT for ZRANGEBYLEX.
+fn zrange_options(
+    tail: &[&RedisValueRef],
+    scores_allowed: bool,
+) -> Result<ZRangeOptions, OpsError> {
+    let mut opts = ZRangeOptions::default();
+    let mut rest = tail;
+    while let Some((arg, more)) = rest.split_first() {
+        if scores_allowed && is_flag(arg, "withscores") {
+            opts.with_scores = true;
+            rest = more;
+        } else if is_flag(arg, "limit") && more.len() >= 2 {
+            opts.limit = Some((Count::try_from(more[0])?, Count::try_from(more[1])?));
+            rest = &more[2..];
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok(opts)
+}
+
+/// Parse SETEX/PSETEX key ttl value, with the ttl in `unit_ms` units.
+fn setex_args(
+    tail: &[&RedisValueRef],
+    unit_ms: u64,
+    command: &str,
+) -> Result<(Key, Value, SetOptions), OpsError> {
+    verify_size(tail, 3)?;
+    let opts = SetOptions {
+        ttl_ms: Some(ttl_ms(tail[1], unit_ms, command)?),
+        ..Default::default()
+    };
+    Ok((Key::try_from(tail[0])?, Value::try_from(tail[2])?, opts))
+}
+
+/// Parse `cursor [MATCH pattern] [COUNT count]` for the SCAN family,
+/// plus `[TYPE type]` for SCAN itself.
+fn scan_args(tail: &[&RedisValueRef], with_type: bool) -> Result<(u64, ScanOptions), OpsError> {
+    verify_size_lower(tail, 1)?;
+    let cursor = Value::try_from(tail[0])?;
+    let cursor = std::str::from_utf8(&cursor)
+        .ok()
+        .and_then(|cursor| cursor.parse().ok())
+        .ok_or_else(|| OpsError::InvalidArgs("invalid cursor".into()))?;
+    let mut opts = ScanOptions::default();
+    for option in tail[1..].chunks(2) {
+        match option {
+            [opt, pattern] if is_flag(opt, "match") => {
+                opts.pattern = Some(Value::try_from(*pattern)?);
+            }
+            [opt, count] if is_flag(opt, "count") => {
+                let count = Count::try_from(*count)?;
+                if count < 1 {
+                    return Err(OpsError::SyntaxError);
+                }
+                opts.count = count as usize;
+            }
+            [opt, type_name] if with_type && is_flag(opt, "type") => {
+                opts.type_name = Some(Value::try_from(*type_name)?);
+            }
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok((cursor, opts))
+}
+
+/// Parse SORT's `[BY pattern] [LIMIT offset count] [GET pattern ...]
+/// [ASC|DESC] [ALPHA] [STORE destination]`.
+fn sort_args(args: &[&RedisValueRef]) -> Result<SortOptions, OpsError> {
+    let mut opts = SortOptions::default();
+    let mut args = args.iter().copied();
+    while let Some(arg) = args.next() {
+        let mut next = || args.next().ok_or(OpsError::SyntaxError);
+        if is_flag(arg, "by") {
+            opts.by = Some(Value::try_from(next()?)?);
+        } else if is_flag(arg, "limit") {
+            let offset = Count::try_from(next()?)?;
+            opts.limit = Some((offset, Count::try_from(next()?)?));
+        } else if is_flag(arg, "get") {
+            opts.get.push(Value::try_from(next()?)?);
+        } else if is_flag(arg, "asc") {
+            opts.desc = false;
+        } else if is_flag(arg, "desc") {
+            opts.desc = true;
+        } else if is_flag(arg, "alpha") {
+            opts.alpha = true;
+        } else if is_flag(arg, "store") {
+            opts.store = Some(Key::try_from(next()?)?);
+        } else {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok(opts)
+}
+
+/// Split EVAL's arguments into the program (or its SHA1), KEYS and ARGV.
+fn eval_args(tail: &[&RedisValueRef]) -> Result<(Value, Vec<Value>, Vec<Value>), OpsError> {
+    verify_size_lower(tail, 2)?;
+    let program = Value::try_from(tail[0])?;
+    let numkeys = Count::try_from(tail[1])?;
+    let args = values_from_tail(&tail[2..])?;
+    if numkeys < 0 {
+        return Err(OpsError::InvalidArgs(
+            "Number of keys can't be negative".into(),
+        ));
+    }
+    if numkeys as usize > args.len() {
+        return Err(OpsError::InvalidArgs(
+            "Number of keys can't be greater than number of args".into(),
+        ));
+    }
+    let mut keys = args;
+    let argv = keys.split_off(numkeys as usize);
+    Ok((program, keys, argv))
+}
+
+/// WAIT's timeout in milliseconds. 0 waits forever.
+fn wait_timeout(timeout: &RedisValueRef) -> Result<Count, OpsError> {
+    let timeout = Count::try_from(timeout)?;
+    if timeout < 0 {
+        return Err(OpsError::InvalidArgs("timeout is negative".into()));
+    }
+    Ok(timeout)
+}
+
+/// Parse CLIENT KILL's `addr`, or `[ID id] [ADDR addr] [SKIPME yes|no]`.
+fn kill_filter(args: &[&RedisValueRef]) -> Result<KillFilter, OpsError> {
+    verify_size_lower(args, 1)?;
+    if let [addr] = args {
+        return Ok(KillFilter {
+            addr: Some(String::try_from(*addr)?),
+            legacy: true,
+            ..KillFilter::default()
+        });
+    }
+    let mut filter = KillFilter::default();
+    for option in args.chunks(2) {
+        match option {
+            [opt, id] if is_flag(opt, "id") => {
+                let id = Count::try_from(*id)?;
+                if id < 1 {
+                    return Err(OpsError::InvalidArgs(
+                        "ERR client-id should be greater than 0".into(),
+                    ));
+                }
+                filter.id = Some(id as ClientId);
+            }
+            [opt, addr] if is_flag(opt, "addr") => {
+                filter.addr = Some(String::try_from(*addr)?);
+            }
+            [opt, skip_me] if is_flag(opt, "skipme") => {
+                filter.skip_me = match String::try_from(*skip_me)?.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(OpsError::SyntaxError),
+                };
+            }
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok(filter)
+}
+
+/// Parse LPOP/RPOP key [count].
+fn list_pop_args(tail: &[&RedisValueRef]) -> Result<(Key, Option<Count>), OpsError> {
+    verify_size_lower(tail, 1)?;
+    if tail.len() > 2 {
+        return Err(OpsError::SyntaxError);
+    }
+    let key = Key::try_from(tail[0])?;
+    let count = tail
+        .get(1)
+        .map(|count| Count::try_from(*count))
+        .transpose()?;
+    if count.map_or(false, |count| count < 0) {
+        return Err(OpsError::InvalidArgs(
+            "value is out of range, must be positive".into(),
+        ));
+    }
+    Ok((key, count))
+}
+
+/// Parse the LEFT or RIGHT of LMOVE and BLMOVE.
+fn list_end(arg: &RedisValueRef) -> Result<ListEnd, OpsError> {
+    if is_flag(arg, "left") {
+        Ok(ListEnd::Left)
+    } else if is_flag(arg, "right") {
+        Ok(ListEnd::Right)
+    } else {
+        Err(OpsError::SyntaxError)
+    }
+}
+
+/// Parse the `[RANK rank] [COUNT num-matches] [MAXLEN len]` options of LPOS.
+fn lpos_options(tail: &[&RedisValueRef]) -> Result<LPosOptions, OpsError> {
+    let mut opts = LPosOptions::default();
+    for option in tail.chunks(2) {
+        match option {
+            [opt, rank] if is_flag(opt, "rank") => {
+                opts.rank = Count::try_from(*rank)?;
+                if opts.rank == 0 {
+                    return Err(OpsError::InvalidArgs(
+                        "RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".into(),
+                    ));
+                }
+            }
+            [opt, count] if is_flag(opt, "count") => {
+                let count = Count::try_from(*count)?;
+                if count < 0 {
+                    return Err(OpsError::InvalidArgs("COUNT can't be negative".into()));
+                }
+                opts.count = Some(count);
+            }
+            [opt, max_len] if is_flag(opt, "maxlen") => {
+                opts.max_len = Count::try_from(*max_len)?;
+                if opts.max_len < 0 {
+                    return Err(OpsError::InvalidArgs("MAXLEN can't be negative".into()));
+                }
+            }
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok(opts)
+}
+
+/// Parse ZPOPMIN/ZPOPMAX key [count]. Count defaults to 1.
+fn zpop_args(tail: &[&RedisValueRef]) -> Result<(Key, Count), OpsError> {
+    verify_size_lower(tail, 1)?;
+    if tail.len() > 2 {
+        return Err(OpsError::SyntaxError);
+    }
+    let key = Key::try_from(tail[0])?;
+    let count = match tail.get(1) {
+        Some(count) => Count::try_from(*count)?,
+        None => 1,
+    };
+    if count < 0 {
+        return Err(OpsError::InvalidArgs(
+            "value is out of range, must be positive".into(),
+        ));
+    }
+    Ok((key, count))
+}
+
+/// Parse FLUSHALL/FLUSHDB [ASYNC|SYNC] [CONFIRM], returning whether CONFIRM was given.
+/// Flushes are always synchronous, so ASYNC and SYNC are accepted and ignored.
+fn flush_confirmed(tail: &[&RedisValueRef]) -> Result<bool, OpsError> {
+    let mut confirmed = false;
+    for arg in tail {
+        if is_flag(arg, "confirm") {
+            confirmed = true;
+        } else if !is_flag(arg, "async") && !is_flag(arg, "sync") {
+            return Err(OpsError::SyntaxError);
+        }
+    }
+    Ok(confirmed)
+}
+
 use smallvec::SmallVec;
 const DEFAULT_SMALL_VEC_SIZE: usize = 2;
 pub type RVec<T> = SmallVec<[T; DEFAULT_SMALL_VEC_SIZE]>;
@@ -212,6 +1534,17 @@ where
     Ok(items)
 }
 
+/// Negative HRANDFIELD and SRANDMEMBER counts pick that many members, one
+/// at a time, so they're capped rather than left to run for ever.
+fn check_rand_count(count: Option<Count>) -> Result<(), OpsError> {
+    match count {
+        Some(count) if count < -Count::from(i32::MAX) => {
+            Err(OpsError::InvalidArgs("ERR value is out of range".into()))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Verify that the collection v has _at least_ min_size values.
 /// e.g. If you wanted to verify that there's two or more items, min_size would be 2.
 fn verify_size_lower<T>(v: &[T], min_size: usize) -> Result<(), OpsError> {
@@ -318,9 +1651,27 @@ macro_rules! ok {
     (BloomOps::$OpName:ident($($OpArg:expr),*)) => {
         Ok(Ops::Blooms(BloomOps::$OpName($( $OpArg ),*)))
     };
+    (CuckooOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Cuckoos(CuckooOps::$OpName($( $OpArg ),*)))
+    };
+    (CountMinOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::CountMins(CountMinOps::$OpName($( $OpArg ),*)))
+    };
+    (TopKOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::TopKs(TopKOps::$OpName($( $OpArg ),*)))
+    };
+    (QueueOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Queues(QueueOps::$OpName($( $OpArg ),*)))
+    };
     (HyperLogLogOps::$OpName:ident($($OpArg:expr),*)) => {
         Ok(Ops::HyperLogLogs(HyperLogLogOps::$OpName($( $OpArg ),*)))
     };
+    (GeoOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Geo(GeoOps::$OpName($( $OpArg ),*)))
+    };
+    (StreamOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Streams(StreamOps::$OpName($( $OpArg ),*)))
+    };
 }
 
 fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Result<Ops, OpsError> {
@@ -329,26 +1680,347 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
     }
     let head = Value::try_from(&array[0])?;
     let head_s = String::from_utf8_lossy(&head);
-    if state_store.contains_foreign_function(&head_s) {
+    if state_store.scripting_enabled() && state_store.contains_foreign_function(&head_s) {
         return ok!(MiscOps::EmbeddedScript(head, array[1..].to_vec()));
     }
     let tail: Vec<&RedisValueRef> = array.iter().skip(1).collect();
     match head_s.to_lowercase().as_ref() {
         "ping" => ok!(MiscOps::Pong()),
-        "keys" => ok!(MiscOps::Keys()),
-        "flushall" => ok!(MiscOps::FlushAll()),
-        "flushdb" => ok!(MiscOps::FlushDB()),
+        "keys" => {
+            verify_size(&tail, 1)?;
+            ok!(MiscOps::Keys(Value::try_from(tail[0])?))
+        }
+        "save" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Save())
+        }
+        "bgsave" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::BgSave())
+        }
+        "lastsave" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::LastSave())
+        }
+        // SHUTDOWN [NOSAVE|SAVE]
+        "shutdown" => match &tail[..] {
+            [] => ok!(MiscOps::Shutdown(None)),
+            [opt] if is_flag(opt, "save") => ok!(MiscOps::Shutdown(Some(true))),
+            [opt] if is_flag(opt, "nosave") => ok!(MiscOps::Shutdown(Some(false))),
+            _ => Err(OpsError::SyntaxError),
+        },
+        "bgrewriteaof" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::BgRewriteAof())
+        }
+        "dbsize" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::DBSize())
+        }
+        "swapdb" => {
+            verify_size(&tail, 2)?;
+            ok!(MiscOps::SwapDB(
+                Index::try_from(tail[0])?,
+                Index::try_from(tail[1])?
+            ))
+        }
+        "move" => {
+            verify_size(&tail, 2)?;
+            ok!(MiscOps::Move(
+                Key::try_from(tail[0])?,
+                Index::try_from(tail[1])?
+            ))
+        }
+        "copy" => {
+            verify_size_lower(&tail, 2)?;
+            let (key, dest_key) = (Key::try_from(tail[0])?, Key::try_from(tail[1])?);
+            let (mut db, mut replace) = (None, false);
+            let mut args = tail[2..].iter();
+            while let Some(arg) = args.next() {
+                if is_flag(arg, "db") && db.is_none() {
+                    let index = args.next().ok_or(OpsError::SyntaxError)?;
+                    db = Some(Index::try_from(*index)?);
+                } else if is_flag(arg, "replace") {
+                    replace = true;
+                } else {
+                    return Err(OpsError::SyntaxError);
+                }
+            }
+            ok!(MiscOps::Copy(key, dest_key, db, replace))
+        }
+        "sort" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(MiscOps::Sort(key, sort_args(&tail[1..])?))
+        }
+        "randomkey" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::RandomKey())
+        }
+        "latency" => {
+            verify_size_lower(&tail, 1)?;
+            let subcommand = String::try_from(tail[0])?.to_lowercase();
+            match subcommand.as_str() {
+                "history" => {
+                    verify_size(&tail, 2)?;
+                    ok!(MiscOps::LatencyHistory(Value::try_from(tail[1])?))
+                }
+                "latest" => ok!(MiscOps::LatencyLatest()),
+                "reset" => ok!(MiscOps::LatencyReset(values_from_tail(&tail[1..])?)),
+                "doctor" => ok!(MiscOps::LatencyDoctor()),
+                _ => Err(OpsError::InvalidArgs(format!(
+                    "Unknown LATENCY subcommand {}",
+                    subcommand
+                ))),
+            }
+        }
+        "slowlog" => {
+            verify_size_lower(&tail, 1)?;
+            let subcommand = String::try_from(tail[0])?.to_lowercase();
+            match subcommand.as_str() {
+                "get" => {
+                    if tail.len() > 2 {
+                        return Err(OpsError::WrongNumberOfArgs(2, tail.len()));
+                    }
+                    let count = match tail.get(1) {
+                        Some(count) => Count::try_from(*count)?,
+                        None => 10,
+                    };
+                    ok!(MiscOps::SlowLogGet(count))
+                }
+                "len" => {
+                    verify_size(&tail, 1)?;
+                    ok!(MiscOps::SlowLogLen())
+                }
+                "reset" => {
+                    verify_size(&tail, 1)?;
+                    ok!(MiscOps::SlowLogReset())
+                }
+                _ => Err(OpsError::InvalidArgs(format!(
+                    "Unknown SLOWLOG subcommand {}",
+                    subcommand
+                ))),
+            }
+        }
+        "config" => {
+            verify_size_lower(&tail, 1)?;
+            let subcommand = String::try_from(tail[0])?.to_lowercase();
+            match subcommand.as_str() {
+                "get" => {
+                    verify_size_lower(&tail, 2)?;
+                    ok!(MiscOps::ConfigGet(values_from_tail(&tail[1..])?))
+                }
+                "set" => {
+                    let pairs = &tail[1..];
+                    if pairs.is_empty() || pairs.len() % 2 != 0 {
+                        return Err(OpsError::WrongNumberOfArgs(3, tail.len()));
+                    }
+                    let pairs = pairs
+                        .chunks(2)
+                        .map(|pair| Ok((String::try_from(pair[0])?, String::try_from(pair[1])?)))
+                        .collect::<Result<_, OpsError>>()?;
+                    ok!(MiscOps::ConfigSet(pairs))
+                }
+                "rewrite" => {
+                    verify_size(&tail, 1)?;
+                    ok!(MiscOps::ConfigRewrite())
+                }
+                _ => Err(OpsError::InvalidArgs(format!(
+                    "Unknown CONFIG subcommand {}",
+                    subcommand
+                ))),
+            }
+        }
+        "command" => {
+            let subcommand = match tail.first() {
+                Some(subcommand) => String::try_from(*subcommand)?.to_lowercase(),
+                None => return ok!(MiscOps::Command()),
+            };
+            match subcommand.as_str() {
+                "count" => {
+                    verify_size(&tail, 1)?;
+                    ok!(MiscOps::CommandCount())
+                }
+                "info" => ok!(MiscOps::CommandInfo(values_from_tail(&tail[1..])?)),
+                "docs" => ok!(MiscOps::CommandDocs(values_from_tail(&tail[1..])?)),
+                _ => Err(OpsError::InvalidArgs(format!(
+                    "Unknown COMMAND subcommand {}",
+                    subcommand
+                ))),
+            }
+        }
+        "scan" => {
+            let (cursor, opts) = scan_args(&tail, true)?;
+            ok!(MiscOps::Scan(cursor, opts))
+        }
+        "sscan" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let (cursor, opts) = scan_args(&tail[1..], false)?;
+            ok!(SetOps::SScan(key, cursor, opts))
+        }
+        "hscan" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let (cursor, opts) = scan_args(&tail[1..], false)?;
+            ok!(HashOps::HScan(key, cursor, opts))
+        }
+        "zscan" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let (cursor, opts) = scan_args(&tail[1..], false)?;
+            ok!(ZSetOps::ZScan(key, cursor, opts))
+        }
+        "flushall" => ok!(MiscOps::FlushAll(flush_confirmed(&tail)?)),
+        "flushdb" => ok!(MiscOps::FlushDB(flush_confirmed(&tail)?)),
         "script" => {
-            verify_size(&tail, 1)?;
+            verify_size_lower(&tail, 1)?;
             let program = Value::try_from(tail[0])?;
+            // SCRIPT FLUSH [ASYNC|SYNC]
+            if program.eq_ignore_ascii_case(b"flush") {
+                return match tail[1..] {
+                    [] => ok!(MiscOps::ScriptFlush()),
+                    [mode] if is_flag(mode, "async") || is_flag(mode, "sync") => {
+                        ok!(MiscOps::ScriptFlush())
+                    }
+                    _ => Err(OpsError::SyntaxError),
+                };
+            }
+            // SCRIPT KILL
+            if program.eq_ignore_ascii_case(b"kill") && tail.len() == 1 {
+                return ok!(MiscOps::ScriptKill());
+            }
+            // SCRIPT LOAD program
+            if program.eq_ignore_ascii_case(b"load") && tail.len() == 2 {
+                return ok!(MiscOps::ScriptLoad(Value::try_from(tail[1])?));
+            }
+            // SCRIPT EXISTS sha [sha ...]
+            if program.eq_ignore_ascii_case(b"exists") && tail.len() >= 2 {
+                return ok!(MiscOps::ScriptExists(values_from_tail(&tail[1..])?));
+            }
+            verify_size(&tail, 1)?;
             ok!(MiscOps::Script(program))
         }
+        // EVAL program numkeys [key ...] [arg ...]
+        "eval" => {
+            let (program, keys, argv) = eval_args(&tail)?;
+            ok!(MiscOps::Eval(program, keys, argv))
+        }
+        // EVALSHA sha numkeys [key ...] [arg ...]
+        "evalsha" => {
+            let (sha, keys, argv) = eval_args(&tail)?;
+            ok!(MiscOps::EvalSha(sha, keys, argv))
+        }
+        // FUNCTION LIST | DELETE name | DUMP
+        "function" => {
+            verify_size_lower(&tail, 1)?;
+            let subcommand = String::try_from(tail[0])?.to_lowercase();
+            match (subcommand.as_str(), &tail[1..]) {
+                ("list", []) => ok!(MiscOps::FunctionList()),
+                ("delete", [name]) => ok!(MiscOps::FunctionDelete(Value::try_from(*name)?)),
+                ("dump", []) => ok!(MiscOps::FunctionDump()),
+                _ => Err(OpsError::InvalidArgs(format!(
+                    "Unknown FUNCTION subcommand or wrong number of arguments for '{}'",
+                    subcommand
+                ))),
+            }
+        }
         // Key-Value
         "set" => {
+            let (key, val, opts) = set_args(&tail)?;
+            ok!(KeyOps::Set(key, val, opts))
+        }
+        "setex" => {
+            let (key, val, opts) = setex_args(&tail, 1000, "setex")?;
+            ok!(KeyOps::Set(key, val, opts))
+        }
+        "psetex" => {
+            let (key, val, opts) = setex_args(&tail, 1, "psetex")?;
+            ok!(KeyOps::Set(key, val, opts))
+        }
+        "setnx" => {
+            let (key, val) = get_key_and_value(array)?;
+            ok!(KeyOps::SetNx(key, val))
+        }
+        "getset" => {
             let (key, val) = get_key_and_value(array)?;
-            ok!(KeyOps::Set(key, val))
+            ok!(KeyOps::GetSet(key, val))
+        }
+        "getdel" => {
+            verify_size(&tail, 1)?;
+            ok!(KeyOps::GetDel(Key::try_from(tail[0])?))
+        }
+        "getex" => {
+            let (key, ttl) = getex_args(&tail)?;
+            ok!(KeyOps::GetEx(key, ttl))
         }
         "mset" => ok!(KeyOps::MSet(get_key_value_pairs(&tail)?)),
+        "setbit" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let offset = bit_offset(tail[1], &state_store)?;
+            ok!(KeyOps::SetBit(key, offset, bit(tail[2], "bit")?))
+        }
+        "getbit" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::GetBit(key, bit_offset(tail[1], &state_store)?))
+        }
+        "bitcount" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            match tail.len() {
+                1 => ok!(KeyOps::BitCount(key, None)),
+                3 | 4 => ok!(KeyOps::BitCount(key, Some(bit_range(&tail[1..])?))),
+                _ => Err(OpsError::SyntaxError),
+            }
+        }
+        "bitpos" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let bit = bit(tail[1], "The bit argument")?;
+            let range = match tail.len() {
+                2 => None,
+                _ => Some(bit_range(&tail[2..])?),
+            };
+            ok!(KeyOps::BitPos(key, bit, range))
+        }
+        "bitop" => Ok(Ops::Keys(bitop_args(&tail)?)),
+        "bitfield" => {
+            let (key, ops) = bitfield_args(&tail, false, &state_store)?;
+            ok!(KeyOps::BitField(key, ops))
+        }
+        "bitfield_ro" => {
+            let (key, ops) = bitfield_args(&tail, true, &state_store)?;
+            ok!(KeyOps::BitFieldRo(key, ops))
+        }
+        "incr" => {
+            verify_size(&tail, 1)?;
+            ok!(KeyOps::Incr(Key::try_from(tail[0])?))
+        }
+        "decr" => {
+            verify_size(&tail, 1)?;
+            ok!(KeyOps::Decr(Key::try_from(tail[0])?))
+        }
+        "incrby" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::IncrBy(key, Count::try_from(tail[1])?))
+        }
+        "decrby" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::DecrBy(key, Count::try_from(tail[1])?))
+        }
+        "incrbyfloat" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let by = f64::try_from(tail[1])?;
+            if !by.is_finite() {
+                return Err(OpsError::InvalidArgs("value is not a valid float".into()));
+            }
+            ok!(KeyOps::IncrByFloat(key, by))
+        }
         "get" => {
             verify_size(&tail, 1)?;
             let key = Key::try_from(tail[0])?;
@@ -376,6 +2048,14 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let new_key = Key::try_from(tail[1])?;
             ok!(KeyOps::RenameNx(key, new_key))
         }
+        "dump" => {
+            verify_size(&tail, 1)?;
+            ok!(KeyOps::Dump(Key::try_from(tail[0])?))
+        }
+        "restore" => {
+            let (key, payload, opts) = restore_args(&tail)?;
+            ok!(KeyOps::Restore(key, payload, opts))
+        }
         "exists" => {
             verify_size_lower(&tail, 1)?;
             let keys = values_from_tail(&tail)?;
@@ -435,12 +2115,23 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
                 Some(c) => Some(Count::try_from(*c)?),
                 None => None,
             };
+            if count.map_or(false, |c| c < 0) {
+                return Err(OpsError::InvalidArgs(
+                    "value is out of range, must be positive".into(),
+                ));
+            }
             ok!(SetOps::SPop(key, count))
         }
         "sismember" => {
             let (key, member) = get_key_and_value(array)?;
             ok!(SetOps::SIsMember(key, member))
         }
+        "smismember" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let members = collect_from_tail(&tail[1..])?;
+            ok!(SetOps::SMIsMember(key, members))
+        }
         "smove" => {
             verify_size(&tail, 3)?;
             let src = Key::try_from(tail[0])?;
@@ -455,6 +2146,7 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
                 Some(c) => Some(Count::try_from(*c)?),
                 None => None,
             };
+            check_rand_count(count)?;
             ok!(SetOps::SRandMembers(key, count))
         }
         "lpush" => {
@@ -483,31 +2175,52 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             ok!(ListOps::LLen(key))
         }
         "lpop" => {
-            verify_size(&tail, 1)?;
-            let key = Key::try_from(tail[0])?;
-            ok!(ListOps::LPop(key))
+            let (key, count) = list_pop_args(&tail)?;
+            ok!(ListOps::LPop(key, count))
         }
         "blpop" => {
-            verify_size(&tail, 2)?;
-            let key = Key::try_from(tail[0])?;
-            let timeout = UTimeout::try_from(tail[1])?;
-            ok!(ListOps::BLPop(key, timeout))
+            verify_size_lower(&tail, 2)?;
+            let keys = collect_from_tail(&tail[..tail.len() - 1])?;
+            let timeout = UTimeout::try_from(tail[tail.len() - 1])?;
+            ok!(ListOps::BLPop(keys, timeout))
         }
         "brpop" => {
-            verify_size(&tail, 2)?;
-            let key = Key::try_from(tail[0])?;
-            let timeout = UTimeout::try_from(tail[1])?;
-            ok!(ListOps::BRPop(key, timeout))
+            verify_size_lower(&tail, 2)?;
+            let keys = collect_from_tail(&tail[..tail.len() - 1])?;
+            let timeout = UTimeout::try_from(tail[tail.len() - 1])?;
+            ok!(ListOps::BRPop(keys, timeout))
         }
         "rpop" => {
-            verify_size(&tail, 1)?;
-            let key = Key::try_from(tail[0])?;
-            ok!(ListOps::RPop(key))
+            let (key, count) = list_pop_args(&tail)?;
+            ok!(ListOps::RPop(key, count))
         }
         "linsert" => {
-            verify_size(&tail, 1)?;
+            verify_size(&tail, 4)?;
+            let key = Key::try_from(tail[0])?;
+            let position = if is_flag(tail[1], "before") {
+                InsertPosition::Before
+            } else if is_flag(tail[1], "after") {
+                InsertPosition::After
+            } else {
+                return Err(OpsError::SyntaxError);
+            };
+            let pivot = Value::try_from(tail[2])?;
+            let value = Value::try_from(tail[3])?;
+            ok!(ListOps::LInsert(key, position, pivot, value))
+        }
+        "lrem" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let count = Count::try_from(tail[1])?;
+            let value = Value::try_from(tail[2])?;
+            ok!(ListOps::LRem(key, count, value))
+        }
+        "lpos" => {
+            verify_size_lower(&tail, 2)?;
             let key = Key::try_from(tail[0])?;
-            ok!(ListOps::LPop(key))
+            let element = Value::try_from(tail[1])?;
+            let opts = lpos_options(&tail[2..])?;
+            ok!(ListOps::LPos(key, element, opts))
         }
         "lindex" => {
             verify_size(&tail, 2)?;
@@ -542,6 +2255,34 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let dest = Key::try_from(tail[1])?;
             ok!(ListOps::RPopLPush(source, dest))
         }
+        "lmove" => {
+            verify_size(&tail, 4)?;
+            let source = Key::try_from(tail[0])?;
+            let dest = Key::try_from(tail[1])?;
+            let (from, to) = (list_end(tail[2])?, list_end(tail[3])?);
+            ok!(ListOps::LMove(source, dest, from, to))
+        }
+        "blmove" => {
+            verify_size(&tail, 5)?;
+            let source = Key::try_from(tail[0])?;
+            let dest = Key::try_from(tail[1])?;
+            let (from, to) = (list_end(tail[2])?, list_end(tail[3])?);
+            let timeout = UTimeout::try_from(tail[4])?;
+            ok!(ListOps::BLMove(source, dest, from, to, timeout))
+        }
+        "brpoplpush" => {
+            verify_size(&tail, 3)?;
+            let source = Key::try_from(tail[0])?;
+            let dest = Key::try_from(tail[1])?;
+            let timeout = UTimeout::try_from(tail[2])?;
+            ok!(ListOps::BLMove(
+                source,
+                dest,
+                ListEnd::Right,
+                ListEnd::Left,
+                timeout
+            ))
+        }
         "hget" => {
             verify_size(&tail, 2)?;
             let key = Key::try_from(tail[0])?;
@@ -630,24 +2371,77 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let value = Count::try_from(tail[2])?;
             Ok(Ops::Hashes(HashOps::HIncrBy(key, field, value)))
         }
+        "hincrbyfloat" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let field = Key::try_from(tail[1])?;
+            let by = f64::try_from(tail[2])?;
+            if !by.is_finite() {
+                return Err(OpsError::InvalidArgs("value is not a valid float".into()));
+            }
+            ok!(HashOps::HIncrByFloat(key, field, by))
+        }
+        "hrandfield" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            let count = tail.get(1).map(|c| Count::try_from(*c)).transpose()?;
+            check_rand_count(count)?;
+            let with_values = match &tail[1..] {
+                [] | [_] => false,
+                [_, opt] if is_flag(opt, "withvalues") => true,
+                _ => return Err(OpsError::SyntaxError),
+            };
+            ok!(HashOps::HRandField(key, count, with_values))
+        }
         // Sorted Sets
         "zadd" => {
-            verify_size_lower(&tail, 3)?;
-            let key = Key::try_from(tail[0])?;
-            let member_scores = get_key_value_pairs(&tail[1..])?;
-            ok!(ZSetOps::ZAdd(key, member_scores))
+            let (key, opts, member_scores) = zadd_args(&tail)?;
+            ok!(ZSetOps::ZAdd(key, opts, member_scores))
         }
         "zrem" => {
             verify_size_lower(&tail, 2)?;
             let (key, keys_to_rem) = get_key_and_tail(&array[1..])?;
             ok!(ZSetOps::ZRem(key, keys_to_rem))
         }
-        "zrange" => {
-            verify_size(&tail, 3)?;
+        "zrange" | "zrevrange" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let lower = Index::try_from(tail[1])?;
+            let upper = Index::try_from(tail[2])?;
+            let with_scores = match &tail[3..] {
+                [] => false,
+                [opt] if is_flag(opt, "withscores") => true,
+                _ => return Err(OpsError::SyntaxError),
+            };
+            if head_s.eq_ignore_ascii_case("zrange") {
+                ok!(ZSetOps::ZRange(key, lower, upper, with_scores))
+            } else {
+                ok!(ZSetOps::ZRevRange(key, lower, upper, with_scores))
+            }
+        }
+        "zrangebyscore" | "zrevrangebyscore" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let opts = zrange_options(&tail[3..], true)?;
+            if head_s.eq_ignore_ascii_case("zrangebyscore") {
+                let (min, max) = (score_bound(tail[1])?, score_bound(tail[2])?);
+                ok!(ZSetOps::ZRangeByScore(key, min, max, opts))
+            } else {
+                let (max, min) = (score_bound(tail[1])?, score_bound(tail[2])?);
+                ok!(ZSetOps::ZRevRangeByScore(key, min, max, opts))
+            }
+        }
+        "zrangebylex" | "zrevrangebylex" => {
+            verify_size_lower(&tail, 3)?;
             let key = Key::try_from(tail[0])?;
-            let lower = Score::try_from(tail[1])?;
-            let upper = Score::try_from(tail[2])?;
-            ok!(ZSetOps::ZRange(key, lower, upper))
+            let opts = zrange_options(&tail[3..], false)?;
+            if head_s.eq_ignore_ascii_case("zrangebylex") {
+                let (min, max) = (lex_bound(tail[1])?, lex_bound(tail[2])?);
+                ok!(ZSetOps::ZRangeByLex(key, min, max, opts))
+            } else {
+                let (max, min) = (lex_bound(tail[1])?, lex_bound(tail[2])?);
+                ok!(ZSetOps::ZRevRangeByLex(key, min, max, opts))
+            }
         }
         "zcard" => {
             verify_size(&tail, 1)?;
@@ -660,20 +2454,38 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let score = Key::try_from(tail[1])?;
             ok!(ZSetOps::ZScore(key, score))
         }
-        "zpopmax" => {
-            verify_size(&tail, 2)?;
+        "zincrby" => {
+            verify_size(&tail, 3)?;
             let key = Key::try_from(tail[0])?;
-            let count = Count::try_from(tail[1])?;
+            let by = Score::try_from(tail[1])?;
+            let member = Key::try_from(tail[2])?;
+            ok!(ZSetOps::ZIncrBy(key, by, member))
+        }
+        "zcount" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (min, max) = (score_bound(tail[1])?, score_bound(tail[2])?);
+            ok!(ZSetOps::ZCount(key, min, max))
+        }
+        "zremrangebyscore" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (min, max) = (score_bound(tail[1])?, score_bound(tail[2])?);
+            ok!(ZSetOps::ZRemRangeByScore(key, min, max))
+        }
+        "zremrangebyrank" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let start = Index::try_from(tail[1])?;
+            let stop = Index::try_from(tail[2])?;
+            ok!(ZSetOps::ZRemRangeByRank(key, start, stop))
+        }
+        "zpopmax" => {
+            let (key, count) = zpop_args(&tail)?;
             ok!(ZSetOps::ZPopMax(key, count))
         }
         "zpopmin" => {
-            verify_size_lower(&tail, 1)?;
-            let key = Key::try_from(tail[0])?;
-            let count = if tail.len() == 1 {
-                1.into()
-            } else {
-                Count::try_from(tail[1])?
-            };
+            let (key, count) = zpop_args(&tail)?;
             ok!(ZSetOps::ZPopMin(key, count))
         }
         "zrank" => {
@@ -694,32 +2506,372 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let value = Value::try_from(tail[1])?;
             ok!(BloomOps::BContains(key, value))
         }
+        "bf.reserve" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let error_rate = f64::try_from(tail[1])?;
+            if error_rate.is_nan() || error_rate <= 0.0 || error_rate >= 1.0 {
+                return Err(OpsError::InvalidArgs("(0 < error rate range < 1)".into()));
+            }
+            if error_rate < MIN_ERROR_RATE {
+                return Err(OpsError::InvalidArgs(format!(
+                    "error rate below {:e} is not supported",
+                    MIN_ERROR_RATE
+                )));
+            }
+            let capacity = Count::try_from(tail[2])?;
+            if capacity <= 0 {
+                return Err(OpsError::InvalidArgs(
+                    "(capacity should be larger than 0)".into(),
+                ));
+            }
+            // The first filter is allocated up front, so it's held to the
+            // same limit as a bitmap.
+            let max_len = MAX_BITMAP_LEN.min(state_store.config.resp_limits().max_bulk_len);
+            if bits_for(error_rate, capacity as f64) / 8.0 > max_len as f64 {
+                return Err(OpsError::InvalidArgs(
+                    "filter would be larger than proto-max-bulk-len".into(),
+                ));
+            }
+            ok!(BloomOps::BfReserve(key, error_rate, capacity as usize))
+        }
+        "bf.madd" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(BloomOps::BfMAdd(key, collect_from_tail(&tail[1..])?))
+        }
+        "bf.mexists" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(BloomOps::BfMExists(key, collect_from_tail(&tail[1..])?))
+        }
+        "bf.info" => {
+            verify_size(&tail, 1)?;
+            ok!(BloomOps::BfInfo(Key::try_from(tail[0])?))
+        }
+        "cf.add" | "cf.exists" | "cf.del" | "cf.count" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let value = Value::try_from(tail[1])?;
+            match head_s.to_lowercase().as_ref() {
+                "cf.add" => ok!(CuckooOps::CfAdd(key, value)),
+                "cf.exists" => ok!(CuckooOps::CfExists(key, value)),
+                "cf.del" => ok!(CuckooOps::CfDel(key, value)),
+                _ => ok!(CuckooOps::CfCount(key, value)),
+            }
+        }
+        // Count-min sketches
+        "cms.initbydim" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (width, depth) = (Count::try_from(tail[1])?, Count::try_from(tail[2])?);
+            if width <= 0 || depth <= 0 {
+                return Err(OpsError::InvalidArgs(
+                    "CMS: invalid width/depth value".into(),
+                ));
+            }
+            cms_size(width as usize, depth as usize, &state_store)?;
+            ok!(CountMinOps::CmsInitByDim(
+                key,
+                width as usize,
+                depth as usize
+            ))
+        }
+        "cms.initbyprob" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let error = cms_fraction(tail[1], "overestimation")?;
+            let probability = cms_fraction(tail[2], "prob")?;
+            let (width, depth) = dimensions(error, probability);
+            cms_size(width, depth, &state_store)?;
+            ok!(CountMinOps::CmsInitByProb(key, error, probability))
+        }
+        "cms.incrby" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let increments: RVec<(Value, Count)> = get_key_value_pairs(&tail[1..])?;
+            if increments.iter().any(|(_, by)| *by < 0) {
+                return Err(OpsError::InvalidArgs("CMS: Cannot parse number".into()));
+            }
+            ok!(CountMinOps::CmsIncrBy(key, increments))
+        }
+        "cms.query" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(CountMinOps::CmsQuery(key, collect_from_tail(&tail[1..])?))
+        }
+        "cms.merge" => {
+            let (dest, sources) = cms_merge_args(&tail)?;
+            ok!(CountMinOps::CmsMerge(dest, sources))
+        }
+        "cms.info" => {
+            verify_size(&tail, 1)?;
+            ok!(CountMinOps::CmsInfo(Key::try_from(tail[0])?))
+        }
+        // Top-k
+        "topk.reserve" => topk_reserve_args(&tail, &state_store),
+        "topk.add" | "topk.query" | "topk.count" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let items = collect_from_tail(&tail[1..])?;
+            match head_s.to_lowercase().as_ref() {
+                "topk.add" => ok!(TopKOps::TopKAdd(key, items)),
+                "topk.query" => ok!(TopKOps::TopKQuery(key, items)),
+                _ => ok!(TopKOps::TopKCount(key, items)),
+            }
+        }
+        "topk.list" => {
+            let key = Key::try_from(*tail.first().ok_or(OpsError::NotEnoughArgs(1, 0))?)?;
+            let with_count = match &tail[1..] {
+                [] => false,
+                [arg] if is_flag(arg, "withcount") => true,
+                _ => return Err(OpsError::SyntaxError),
+            };
+            ok!(TopKOps::TopKList(key, with_count))
+        }
         "select" => {
             verify_size(&tail, 1)?;
             let new_db = Index::try_from(tail[0])?;
             ok!(MiscOps::Select(new_db))
         }
+        "publish" => {
+            verify_size(&tail, 2)?;
+            let (channel, message) = (Value::try_from(tail[0])?, Value::try_from(tail[1])?);
+            ok!(MiscOps::Publish(channel, message))
+        }
+        "subscribe" => {
+            verify_size_lower(&tail, 1)?;
+            ok!(MiscOps::Subscribe(values_from_tail(&tail)?))
+        }
+        "psubscribe" => {
+            verify_size_lower(&tail, 1)?;
+            ok!(MiscOps::PSubscribe(values_from_tail(&tail)?))
+        }
+        "unsubscribe" => ok!(MiscOps::Unsubscribe(values_from_tail(&tail)?)),
+        "punsubscribe" => ok!(MiscOps::PUnsubscribe(values_from_tail(&tail)?)),
+        "hello" => {
+            let auth = match tail.get(1..) {
+                None | Some([]) => None,
+                Some([option, user, password]) if is_flag(option, "auth") => {
+                    Some((Value::try_from(*user)?, Value::try_from(*password)?))
+                }
+                Some(_) => return Err(OpsError::SyntaxError),
+            };
+            let version = tail.first().map(|v| Count::try_from(*v)).transpose()?;
+            if version.map_or(false, |v| v != 2 && v != 3) {
+                return Err(OpsError::InvalidArgs(
+                    "NOPROTO unsupported protocol version".into(),
+                ));
+            }
+            ok!(MiscOps::Hello(version, auth))
+        }
+        "auth" => match tail[..] {
+            [password] => ok!(MiscOps::Auth(None, Value::try_from(password)?)),
+            [user, password] => ok!(MiscOps::Auth(
+                Some(Value::try_from(user)?),
+                Value::try_from(password)?
+            )),
+            _ => Err(OpsError::SyntaxError),
+        },
+        // Like redis, QUIT ignores its arguments.
+        "quit" => ok!(MiscOps::Quit()),
+        "monitor" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Monitor())
+        }
+        "client" => {
+            verify_size_lower(&tail, 1)?;
+            let subcommand = String::try_from(tail[0])?.to_lowercase();
+            match subcommand.as_str() {
+                "list" => {
+                    verify_size(&tail, 1)?;
+                    ok!(MiscOps::ClientList())
+                }
+                "id" => {
+                    verify_size(&tail, 1)?;
+                    ok!(MiscOps::ClientId())
+                }
+                "getname" => {
+                    verify_size(&tail, 1)?;
+                    ok!(MiscOps::ClientGetName())
+                }
+                "setname" => {
+                    verify_size(&tail, 2)?;
+                    let name = Value::try_from(tail[1])?;
+                    if name.iter().any(|b| !(b'!'..=b'~').contains(b)) {
+                        return Err(OpsError::InvalidArgs(
+                            "ERR Client names cannot contain spaces, newlines or special characters."
+                                .into(),
+                        ));
+                    }
+                    ok!(MiscOps::ClientSetName(name))
+                }
+                "kill" => ok!(MiscOps::ClientKill(kill_filter(&tail[1..])?)),
+                _ => Err(OpsError::InvalidArgs(format!(
+                    "Unknown CLIENT subcommand {}",
+                    subcommand
+                ))),
+            }
+        }
+        "replicaof" | "slaveof" => {
+            verify_size(&tail, 2)?;
+            let host = String::try_from(tail[0])?;
+            let port = String::try_from(tail[1])?;
+            if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                return ok!(MiscOps::ReplicaOf(None));
+            }
+            let port = port
+                .parse()
+                .map_err(|_| OpsError::InvalidArgs("Invalid master port".into()))?;
+            ok!(MiscOps::ReplicaOf(Some((host, port))))
+        }
+        "replconf" => ok!(MiscOps::ReplConf(values_from_tail(&tail)?)),
+        // The handshake is handled by the connection, see `PsyncRequest`.
+        "psync" | "sync" => ok!(MiscOps::Sync()),
+        "role" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Role())
+        }
+        "wait" => {
+            verify_size(&tail, 2)?;
+            let replicas = Count::try_from(tail[0])?;
+            ok!(MiscOps::Wait(replicas, wait_timeout(tail[1])?))
+        }
+        "waitaof" => {
+            verify_size(&tail, 3)?;
+            let local = Count::try_from(tail[0])?;
+            let replicas = Count::try_from(tail[1])?;
+            ok!(MiscOps::WaitAof(local, replicas, wait_timeout(tail[2])?))
+        }
         "echo" => {
             verify_size(&tail, 1)?;
             let val = Value::try_from(tail[0])?;
             ok!(MiscOps::Echo(val))
         }
         "info" => {
-            verify_size(&tail, 0)?;
-            ok!(MiscOps::Info())
+            let sections = values_from_tail(&tail)?;
+            ok!(MiscOps::Info(sections))
+        }
+        "oxide" => {
+            verify_size(&tail, 1)?;
+            if !is_flag(tail[0], "compat") {
+                return Err(OpsError::InvalidArgs("Unknown OXIDE subcommand".into()));
+            }
+            ok!(MiscOps::OxideCompat())
+        }
+        "object" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[1])?;
+            if is_flag(tail[0], "refcount") {
+                ok!(MiscOps::ObjectRefcount(key))
+            } else if is_flag(tail[0], "encoding") {
+                ok!(MiscOps::ObjectEncoding(key))
+            } else {
+                Err(OpsError::InvalidArgs("Unknown OBJECT subcommand".into()))
+            }
+        }
+        "type" => {
+            verify_size(&tail, 1)?;
+            ok!(MiscOps::Type(Key::try_from(tail[0])?))
+        }
+        // MEMORY USAGE key [SAMPLES count] | MEMORY STATS. Values are always
+        // fully counted.
+        "memory" => {
+            verify_size_lower(&tail, 1)?;
+            if is_flag(tail[0], "stats") {
+                verify_size(&tail, 1)?;
+                return ok!(MiscOps::MemoryStats());
+            }
+            if !is_flag(tail[0], "usage") {
+                return Err(OpsError::InvalidArgs("Unknown MEMORY subcommand".into()));
+            }
+            verify_size_lower(&tail, 2)?;
+            // Like redis, collections are sized from 5 of their elements
+            // unless asked otherwise; SAMPLES 0 looks at all of them.
+            let samples = match &tail[2..] {
+                [] => 5,
+                [opt, samples] if is_flag(opt, "samples") => {
+                    let samples = Count::try_from(*samples)?;
+                    if samples < 0 {
+                        return Err(OpsError::SyntaxError);
+                    }
+                    samples as usize
+                }
+                _ => return Err(OpsError::SyntaxError),
+            };
+            ok!(MiscOps::MemoryUsage(Key::try_from(tail[1])?, samples))
+        }
+        // DEBUG SLEEP seconds | OBJECT key | SET-ACTIVE-EXPIRE 0|1 | JMAP |
+        // QUICKACK 0|1. JMAP and QUICKACK are accepted and do nothing.
+        "debug" => {
+            verify_size_lower(&tail, 1)?;
+            let subcommand = String::try_from(tail[0])?.to_lowercase();
+            match (subcommand.as_str(), &tail[1..]) {
+                ("sleep", [seconds]) => {
+                    let seconds = f64::try_from(*seconds)?;
+                    if !seconds.is_finite() || seconds < 0.0 {
+                        return Err(OpsError::InvalidArgs("sleep time is invalid".into()));
+                    }
+                    ok!(MiscOps::DebugSleep((seconds * 1000.0) as u64))
+                }
+                ("object", [key]) => ok!(MiscOps::DebugObject(Key::try_from(*key)?)),
+                ("set-active-expire", [on]) => {
+                    ok!(MiscOps::DebugSetActiveExpire(Count::try_from(*on)? != 0))
+                }
+                ("jmap", []) | ("quickack", [_]) => ok!(MiscOps::DebugNoop()),
+                _ => Err(OpsError::InvalidArgs(format!(
+                    "Unknown DEBUG subcommand or wrong number of arguments for '{}'",
+                    subcommand
+                ))),
+            }
+        }
+        // TREE [SEPARATOR sep] [DEPTH n]
+        "tree" => {
+            let mut separator = Value::from_static(DEFAULT_SEPARATOR);
+            let mut depth = DEFAULT_TREE_DEPTH as Count;
+            for option in tail.chunks(2) {
+                match option {
+                    [opt, sep] if is_flag(opt, "separator") => {
+                        separator = Value::try_from(*sep)?;
+                        if separator.is_empty() {
+                            return Err(OpsError::InvalidArgs("Separator can't be empty".into()));
+                        }
+                    }
+                    [opt, d] if is_flag(opt, "depth") => {
+                        depth = Count::try_from(*d)?;
+                        if depth < 1 || depth > MAX_TREE_DEPTH as Count {
+                            return Err(OpsError::InvalidArgs(format!(
+                                "Depth must be between 1 and {}",
+                                MAX_TREE_DEPTH
+                            )));
+                        }
+                    }
+                    _ => return Err(OpsError::SyntaxError),
+                }
+            }
+            ok!(MiscOps::Tree(separator, depth))
         }
         // StackOps
         "stpush" => {
-            verify_size(&tail, 2)?;
-            let key = Key::try_from(tail[0])?;
-            let val = Value::try_from(tail[1])?;
-            ok!(StackOps::STPush(key, val))
+            let (key, vals) = get_key_and_tail(array)?;
+            ok!(StackOps::STPush(key, vals))
         }
         "stpop" => {
             verify_size(&tail, 1)?;
             let key = Key::try_from(tail[0])?;
             ok!(StackOps::STPop(key))
         }
+        "stpopn" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let count = Count::try_from(tail[1])?;
+            if count < 0 {
+                return Err(OpsError::InvalidArgs(
+                    "value is out of range, must be positive".into(),
+                ));
+            }
+            ok!(StackOps::STPopN(key, count))
+        }
         "stpeek" => {
             verify_size(&tail, 1)?;
             let key = Key::try_from(tail[0])?;
@@ -730,10 +2882,37 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let key = Key::try_from(tail[0])?;
             ok!(StackOps::STSize(key))
         }
+        // Queues
+        "qpush" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(QueueOps::QPush(key, collect_from_tail(&tail[1..])?))
+        }
+        "qpop" => qpop_args(&tail),
+        "qack" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let mut ids = RVec::new();
+            for id in tail[1..].iter() {
+                match Count::try_from(*id)? {
+                    id if id > 0 => ids.push(id as u64),
+                    _ => return Err(OpsError::InvalidArgs("invalid job id".into())),
+                }
+            }
+            ok!(QueueOps::QAck(key, ids))
+        }
+        "qclaim" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (id, deadline) = (Count::try_from(tail[1])?, Count::try_from(tail[2])?);
+            if id <= 0 || deadline < 0 {
+                return Err(OpsError::InvalidArgs("invalid job id or deadline".into()));
+            }
+            ok!(QueueOps::QClaim(key, id as u64, deadline as u64))
+        }
         // HyperLogLog
         "pfadd" => {
             verify_size_lower(&tail, 1)?;
-            // TODO: Handle zero values case
             let key = Key::try_from(tail[0])?;
             let vals = collect_from_tail(&tail[1..])?;
             ok!(HyperLogLogOps::PfAdd(key, vals))
@@ -748,6 +2927,79 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let sources = collect_from_tail(&tail[1..])?;
             ok!(HyperLogLogOps::PfMerge(dest, sources))
         }
+        // Geo
+        "geoadd" => {
+            let (key, opts, locations) = geoadd_args(&tail)?;
+            ok!(GeoOps::GeoAdd(key, opts, locations))
+        }
+        "geopos" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(GeoOps::GeoPos(key, collect_from_tail(&tail[1..])?))
+        }
+        "geodist" => {
+            let unit = match tail.len() {
+                3 => Unit::Meters,
+                4 => geo_unit(tail[3])?,
+                _ => return Err(OpsError::SyntaxError),
+            };
+            let key = Key::try_from(tail[0])?;
+            let (a, b) = (Key::try_from(tail[1])?, Key::try_from(tail[2])?);
+            ok!(GeoOps::GeoDist(key, a, b, unit))
+        }
+        "geosearch" => {
+            let (key, opts) = geosearch_args(&tail)?;
+            ok!(GeoOps::GeoSearch(key, opts))
+        }
+        // Streams
+        "xadd" => {
+            let (key, opts, id, fields) = xadd_args(&tail)?;
+            ok!(StreamOps::XAdd(key, opts, id, fields))
+        }
+        "xlen" => {
+            verify_size(&tail, 1)?;
+            ok!(StreamOps::XLen(Key::try_from(tail[0])?))
+        }
+        "xrange" => {
+            let (key, start, end, count) = xrange_args(&tail, false)?;
+            ok!(StreamOps::XRange(key, start, end, count))
+        }
+        "xrevrange" => {
+            let (key, start, end, count) = xrange_args(&tail, true)?;
+            ok!(StreamOps::XRevRange(key, start, end, count))
+        }
+        "xread" => {
+            let (from, count, block) = xread_args(&tail)?;
+            ok!(StreamOps::XRead(from, count, block))
+        }
+        "xgroup" => xgroup_args(&tail),
+        "xreadgroup" => {
+            let (group, consumer, from, opts) = xreadgroup_args(&tail)?;
+            ok!(StreamOps::XReadGroup(group, consumer, from, opts))
+        }
+        "xack" => {
+            verify_size_lower(&tail, 3)?;
+            let (key, group) = (Key::try_from(tail[0])?, Key::try_from(tail[1])?);
+            let ids = tail[2..]
+                .iter()
+                .map(|id| stream_id(id))
+                .collect::<Result<_, _>>()?;
+            ok!(StreamOps::XAck(key, group, ids))
+        }
+        "xpending" => {
+            let (key, group, range) = xpending_args(&tail)?;
+            ok!(StreamOps::XPending(key, group, range))
+        }
+        "xclaim" => {
+            let (key, group, consumer, min_idle, ids, opts) = xclaim_args(&tail)?;
+            ok!(StreamOps::XClaim(key, group, consumer, min_idle, ids, opts))
+        }
+        "xautoclaim" => {
+            let (key, group, consumer, min_idle, start, count, just_id) = xautoclaim_args(&tail)?;
+            ok!(StreamOps::XAutoClaim(
+                key, group, consumer, min_idle, start, count, just_id
+            ))
+        }
         _ => Err(OpsError::UnknownOp),
     }
 }
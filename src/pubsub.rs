@@ -0,0 +1,361 @@
+//! Publish/subscribe: SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE, PUNSUBSCRIBE and PUBLISH.
+//!
+//! `PubSub` (one per server, in `StateStore`) maps channels and patterns to
+//! the inboxes of the connections subscribed to them. Each connection owns a
+//! `Subscriber`, whose inbox the connection drains onto its socket between
+//! commands, so PUBLISH never waits on a slow subscriber.
+//!
+//! Like redis' client-output-buffer-limit for pubsub clients, a subscriber
+//! with more than `SUBSCRIBER_BACKLOG_BYTES` of messages waiting stops
+//! receiving any, and its connection is closed.
+
+use crate::glob::glob_match;
+use crate::misc::MiscOps;
+use crate::ops::{translate, Ops};
+use crate::types::{bulk, RedisValueRef, StateStoreRef, Value};
+use dashmap::DashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+type SubscriberId = u64;
+
+/// Bytes of messages a subscriber may have waiting, like redis' default
+/// hard limit for pubsub clients.
+const SUBSCRIBER_BACKLOG_BYTES: usize = 32 * 1024 * 1024;
+
+/// Where a subscriber's messages wait for its connection.
+#[derive(Clone)]
+struct Inbox {
+    sender: UnboundedSender<(RedisValueRef, usize)>,
+    /// Bytes of the messages waiting.
+    queued: Arc<AtomicUsize>,
+    /// Set once too many bytes were waiting. Nothing is sent after that.
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Inbox {
+    /// Queue `message`, of `size` bytes. Returns false if the subscriber is
+    /// gone or too far behind.
+    fn send(&self, message: RedisValueRef, size: usize) -> bool {
+        if self.overflowed.load(Ordering::Relaxed) {
+            return false;
+        }
+        if self.queued.fetch_add(size, Ordering::SeqCst) + size > SUBSCRIBER_BACKLOG_BYTES {
+            self.overflowed.store(true, Ordering::SeqCst);
+            return false;
+        }
+        self.sender.send((message, size)).is_ok()
+    }
+}
+
+/// Inboxes subscribed to each channel (or pattern).
+type Registry = DashMap<Value, HashMap<SubscriberId, Inbox>>;
+
+/// Commands handled by the connection's `Subscriber` rather than `process_command`.
+const SUBSCRIPTION_COMMANDS: &[&str] = &["subscribe", "unsubscribe", "psubscribe", "punsubscribe"];
+
+#[derive(Default)]
+pub struct PubSub {
+    next_id: AtomicU64,
+    channels: Registry,
+    patterns: Registry,
+}
+
+fn register(registry: &Registry, name: Value, id: SubscriberId, inbox: &Inbox) {
+    registry.entry(name).or_default().insert(id, inbox.clone());
+}
+
+fn unregister(registry: &Registry, name: &[u8], id: SubscriberId) {
+    if let Some(mut subscribers) = registry.get_mut(name) {
+        subscribers.remove(&id);
+    }
+    registry.remove_if(name, |_, subscribers| subscribers.is_empty());
+}
+
+/// Send `message` (`size` bytes) to every inbox, returning how many took it.
+fn deliver<'a>(
+    inboxes: impl Iterator<Item = &'a Inbox>,
+    message: &RedisValueRef,
+    size: usize,
+) -> usize {
+    inboxes
+        .filter(|inbox| inbox.send(message.clone(), size))
+        .count()
+}
+
+impl PubSub {
+    /// Send `message` to the subscribers of `channel`, and of every pattern
+    /// matching it. Returns how many subscriptions received it.
+    pub fn publish(&self, channel: &[u8], message: &Value) -> usize {
+        let mut received = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            let push = RedisValueRef::Array(vec![
+                bulk("message"),
+                bulk(Value::copy_from_slice(channel)),
+                RedisValueRef::BulkString(message.clone()),
+            ]);
+            let size = channel.len() + message.len();
+            received += deliver(subscribers.values(), &push, size);
+        }
+        for subscribers in self.patterns.iter() {
+            if !glob_match(subscribers.key(), channel) {
+                continue;
+            }
+            let push = RedisValueRef::Array(vec![
+                bulk("pmessage"),
+                RedisValueRef::BulkString(subscribers.key().clone()),
+                bulk(Value::copy_from_slice(channel)),
+                RedisValueRef::BulkString(message.clone()),
+            ]);
+            let size = subscribers.key().len() + channel.len() + message.len();
+            received += deliver(subscribers.values(), &push, size);
+        }
+        received
+    }
+}
+
+/// The subscriptions of one connection.
+pub struct Subscriber {
+    id: SubscriberId,
+    state_store: StateStoreRef,
+    inbox: Inbox,
+    messages: UnboundedReceiver<(RedisValueRef, usize)>,
+    channels: BTreeSet<Value>,
+    patterns: BTreeSet<Value>,
+}
+
+/// Whether `redis_value` is one of `SUBSCRIPTION_COMMANDS`.
+fn is_subscription_command(redis_value: &RedisValueRef) -> bool {
+    match redis_value {
+        RedisValueRef::Array(array) => match array.first() {
+            Some(RedisValueRef::BulkString(name)) => SUBSCRIPTION_COMMANDS
+                .iter()
+                .any(|command| name.eq_ignore_ascii_case(command.as_bytes())),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+impl Subscriber {
+    pub fn new(state_store: StateStoreRef) -> Self {
+        let id = state_store.pubsub.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, messages) = unbounded_channel();
+        let inbox = Inbox {
+            sender,
+            queued: Arc::default(),
+            overflowed: Arc::default(),
+        };
+        Subscriber {
+            id,
+            state_store,
+            inbox,
+            messages,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Number of channels and patterns subscribed to.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn reply(&self, kind: &'static [u8], name: Option<Value>) -> RedisValueRef {
+        RedisValueRef::Array(vec![
+            bulk(kind),
+            name.map_or(RedisValueRef::NullBulkString, bulk),
+            RedisValueRef::Int(self.count() as i64),
+        ])
+    }
+
+    fn subscribe(&mut self, names: Vec<Value>, pattern: bool) -> Vec<RedisValueRef> {
+        let (kind, registry): (&'static [u8], _) = if pattern {
+            (b"psubscribe", &self.state_store.pubsub.patterns)
+        } else {
+            (b"subscribe", &self.state_store.pubsub.channels)
+        };
+        let mut replies = Vec::with_capacity(names.len());
+        for name in names {
+            register(registry, name.clone(), self.id, &self.inbox);
+            if pattern {
+                self.patterns.insert(name.clone());
+            } else {
+                self.channels.insert(name.clone());
+            }
+            replies.push(self.reply(kind, Some(name)));
+        }
+        replies
+    }
+
+    /// Unsubscribe from `names`, or from everything if it's empty.
+    fn unsubscribe(&mut self, names: Vec<Value>, pattern: bool) -> Vec<RedisValueRef> {
+        let (kind, subscribed): (&'static [u8], _) = if pattern {
+            (b"punsubscribe", &self.patterns)
+        } else {
+            (b"unsubscribe", &self.channels)
+        };
+        let names = if names.is_empty() {
+            subscribed.iter().cloned().collect()
+        } else {
+            names
+        };
+        if names.is_empty() {
+            return vec![self.reply(kind, None)];
+        }
+        let mut replies = Vec::with_capacity(names.len());
+        for name in names {
+            if pattern {
+                unregister(&self.state_store.pubsub.patterns, &name, self.id);
+                self.patterns.remove(&name);
+            } else {
+                unregister(&self.state_store.pubsub.channels, &name, self.id);
+                self.channels.remove(&name);
+            }
+            replies.push(self.reply(kind, Some(name)));
+        }
+        replies
+    }
+
+    /// Run `redis_value` if it's a subscription command, returning its replies.
+    /// Returns None for every other command.
+    pub fn run_command(&mut self, redis_value: &RedisValueRef) -> Option<Vec<RedisValueRef>> {
+        if !is_subscription_command(redis_value) {
+            return None;
+        }
+        let replies = match translate(redis_value.clone(), self.state_store.clone()) {
+            Ok(Ops::Misc(MiscOps::Subscribe(names))) => self.subscribe(names, false),
+            Ok(Ops::Misc(MiscOps::PSubscribe(names))) => self.subscribe(names, true),
+            Ok(Ops::Misc(MiscOps::Unsubscribe(names))) => self.unsubscribe(names, false),
+            Ok(Ops::Misc(MiscOps::PUnsubscribe(names))) => self.unsubscribe(names, true),
+            Ok(_) => unreachable!("subscription commands translate to subscription ops"),
+            Err(e) => vec![e.into()],
+        };
+        Some(replies)
+    }
+
+    /// Wait for the next message published to our subscriptions. None once
+    /// too many were waiting: the connection should be closed.
+    pub async fn next_message(&mut self) -> Option<RedisValueRef> {
+        let (message, size) = self
+            .messages
+            .recv()
+            .await
+            .expect("the subscriber holds its own inbox open");
+        if self.inbox.overflowed.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.inbox.queued.fetch_sub(size, Ordering::SeqCst);
+        Some(message)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let pubsub = &self.state_store.pubsub;
+        for channel in self.channels.iter() {
+            unregister(&pubsub.channels, channel, self.id);
+        }
+        for pattern in self.patterns.iter() {
+            unregister(&pubsub.patterns, pattern, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_pubsub {
+    use super::Subscriber;
+    use crate::test_util::{command, command_args};
+    use crate::types::{RedisValueRef, StateStore};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_publish_to_channels_and_patterns() {
+        let store = Arc::new(StateStore::default());
+        let mut subscriber = Subscriber::new(store.clone());
+        let replies = subscriber
+            .run_command(&command(&["subscribe", "news", "sport"]))
+            .unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(
+            replies[1],
+            RedisValueRef::Array(vec![
+                RedisValueRef::BulkString("subscribe".into()),
+                RedisValueRef::BulkString("sport".into()),
+                RedisValueRef::Int(2),
+            ])
+        );
+        subscriber.run_command(&command(&["psubscribe", "n*"]));
+
+        let message = Bytes::from_static(b"hi");
+        assert_eq!(store.pubsub.publish(b"news", &message), 2);
+        assert_eq!(store.pubsub.publish(b"weather", &message), 0);
+        let mut expected = command_args(&["message", "news", "hi"]);
+        assert_eq!(
+            subscriber.next_message().await,
+            Some(RedisValueRef::Array(expected))
+        );
+        expected = command_args(&["pmessage", "n*", "news", "hi"]);
+        assert_eq!(
+            subscriber.next_message().await,
+            Some(RedisValueRef::Array(expected))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe() {
+        let store = Arc::new(StateStore::default());
+        let mut subscriber = Subscriber::new(store.clone());
+        assert!(subscriber.run_command(&command(&["get", "a"])).is_none());
+        subscriber.run_command(&command(&["subscribe", "a", "b"]));
+        let replies = subscriber.run_command(&command(&["unsubscribe"])).unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(subscriber.count(), 0);
+        assert_eq!(store.pubsub.publish(b"a", &Bytes::new()), 0);
+        assert!(store.pubsub.channels.is_empty());
+        // Nothing left to unsubscribe from.
+        let replies = subscriber.run_command(&command(&["punsubscribe"])).unwrap();
+        assert_eq!(
+            replies,
+            vec![RedisValueRef::Array(vec![
+                RedisValueRef::BulkString("punsubscribe".into()),
+                RedisValueRef::NullBulkString,
+                RedisValueRef::Int(0),
+            ])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropped_subscribers_are_forgotten() {
+        let store = Arc::new(StateStore::default());
+        let mut subscriber = Subscriber::new(store.clone());
+        subscriber.run_command(&command(&["subscribe", "a"]));
+        subscriber.run_command(&command(&["psubscribe", "*"]));
+        drop(subscriber);
+        assert!(store.pubsub.channels.is_empty());
+        assert!(store.pubsub.patterns.is_empty());
+        assert_eq!(store.pubsub.publish(b"a", &Bytes::new()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscribers_are_cut_off() {
+        let store = Arc::new(StateStore::default());
+        let mut subscriber = Subscriber::new(store.clone());
+        subscriber.run_command(&command(&["subscribe", "a"]));
+        let message = Bytes::from(vec![0; super::SUBSCRIBER_BACKLOG_BYTES / 2]);
+        assert_eq!(store.pubsub.publish(b"a", &message), 1);
+        // Reading makes room again.
+        assert!(subscriber.next_message().await.is_some());
+        assert_eq!(store.pubsub.publish(b"a", &message), 1);
+        assert_eq!(store.pubsub.publish(b"a", &message), 0);
+        assert_eq!(store.pubsub.publish(b"a", &Bytes::new()), 0);
+        assert_eq!(subscriber.next_message().await, None);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::data_structures::queue::JobId;
+use crate::data_structures::receipt_map::KeyTypes;
+use crate::expiry::now_ms;
+use crate::op_variants;
+use crate::ops::RVec;
+use crate::timeouts::{blocking_timeout, wake_after};
+use crate::types::{Count, Key, RedisValueRef, ReturnValue, State, StateRef, UTimeout, Value};
+use crate::write_effects::untouched;
+use bytes::Bytes;
+use std::time::Duration;
+
+op_variants! {
+    QueueOps,
+    QPush(Key, RVec<Value>),
+    QPop(Key, u64, Option<UTimeout>),
+    QAck(Key, RVec<JobId>),
+    QClaim(Key, JobId, u64)
+}
+
+impl QueueOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            QueueOps::QPush(key, _)
+            | QueueOps::QPop(key, ..)
+            | QueueOps::QAck(key, _)
+            | QueueOps::QClaim(key, ..) => Some(vec![key.clone()]),
+        }
+    }
+}
+
+/// How long a popped job has to be acked, without VISIBILITY.
+pub const DEFAULT_VISIBILITY_MS: u64 = 30_000;
+
+/// Hand out the next job of `key`, if any, as `[id, value]`.
+fn pop(state: &StateRef, key: &[u8], visibility_ms: u64) -> Option<ReturnValue> {
+    let (id, value) = state.queues.get_mut(key)?.pop(now_ms(), visibility_ms)?;
+    Some(ReturnValue::Array(vec![
+        ReturnValue::IntRes(id as Count),
+        ReturnValue::StringRes(value),
+    ]))
+}
+
+/// The command to log for QPOP `command`, which replied `res`: a QCLAIM of
+/// the job it handed out until the deadline it got, as what it does depends
+/// on when it ran. None if it handed out nothing. Other ops' commands are
+/// returned as they are.
+pub fn qpop_as_claim(
+    state: &State,
+    op: &str,
+    command: RedisValueRef,
+    res: &ReturnValue,
+) -> Option<RedisValueRef> {
+    if op != "QPop" {
+        return Some(command);
+    }
+    let key = match &command {
+        RedisValueRef::Array(args) => match args.get(1) {
+            Some(RedisValueRef::BulkString(key)) => key.clone(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let id = match res {
+        ReturnValue::Array(job) => match job.first() {
+            Some(ReturnValue::IntRes(id)) => *id as JobId,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let deadline = state.queues.get(&key)?.deadline(id)?;
+    Some(RedisValueRef::Array(vec![
+        RedisValueRef::BulkString(Bytes::from_static(b"QCLAIM")),
+        RedisValueRef::BulkString(key),
+        RedisValueRef::BulkString(Bytes::from(id.to_string())),
+        RedisValueRef::BulkString(Bytes::from(deadline.to_string())),
+    ]))
+}
+
+/// Run a queue op. QPOP takes the visibility timeout in ms, and how many ms
+/// to block for (0 for forever, None to not block).
+pub async fn queue_interact(queue_op: QueueOps, state: StateRef) -> ReturnValue {
+    match queue_op {
+        QueueOps::QPush(key, values) => {
+            let mut queue = state.queues.entry(key.clone()).or_default();
+            for value in values {
+                queue.push(value);
+            }
+            let len = queue.len();
+            drop(queue);
+            state.wake_queue(&key);
+            ReturnValue::IntRes(len as Count)
+        }
+        QueueOps::QPop(key, visibility_ms, block) => {
+            if let Some(reply) = pop(&state, &key, visibility_ms) {
+                return reply;
+            }
+            let limit = match block {
+                None => {
+                    untouched();
+                    return ReturnValue::Nil;
+                }
+                Some(0) => None,
+                Some(ms) => Some(Duration::from_millis(ms as u64)),
+            };
+            let keys = vec![KeyTypes::queue(&key)];
+            let state_clone = state.clone();
+            let poll = move || {
+                let reply = pop(&state, &key, visibility_ms);
+                // Jobs handed out come back at their deadline, without a write
+                // to wake us.
+                let deadline = state.queues.get(&key).and_then(|q| q.next_deadline());
+                if let (None, Some(deadline)) = (&reply, deadline) {
+                    let delay = Duration::from_millis(deadline.saturating_sub(now_ms()) + 1);
+                    wake_after(state.clone(), KeyTypes::queue(&key), delay);
+                }
+                reply
+            };
+            let reply = blocking_timeout(Box::new(poll), state_clone, keys, limit).await;
+            if reply == ReturnValue::Nil {
+                untouched();
+            }
+            reply
+        }
+        QueueOps::QAck(key, ids) => {
+            let acked = match state.queues.get_mut(&key) {
+                Some(mut queue) => ids.iter().filter(|id| queue.ack(**id)).count(),
+                None => 0,
+            };
+            state.queues.remove_if(&key, |_, queue| queue.is_empty());
+            if acked == 0 {
+                untouched();
+            }
+            ReturnValue::IntRes(acked as Count)
+        }
+        QueueOps::QClaim(key, id, deadline) => {
+            let claimed = match state.queues.get_mut(&key) {
+                Some(mut queue) => queue.claim(id, deadline),
+                None => false,
+            };
+            if !claimed {
+                untouched();
+            }
+            ReturnValue::IntRes(claimed as Count)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_queue {
+    use crate::queue::{qpop_as_claim, queue_interact, QueueOps};
+    use crate::test_util::command;
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn job(id: i64, value: &Bytes) -> ReturnValue {
+        ReturnValue::Array(vec![
+            ReturnValue::IntRes(id),
+            ReturnValue::StringRes(value.clone()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_push_pop_ack() {
+        let key = Bytes::from_static(b"jobs");
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let eng = Arc::new(State::default());
+        let push = QueueOps::QPush(key.clone(), smallvec![a.clone(), b.clone()]);
+        assert_eq!(
+            queue_interact(push, eng.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+
+        let pop = QueueOps::QPop(key.clone(), 60_000, None);
+        assert_eq!(queue_interact(pop.clone(), eng.clone()).await, job(1, &a));
+        // Job 2 is due straight back, so it's handed out again.
+        let pop_expiring = QueueOps::QPop(key.clone(), 0, None);
+        assert_eq!(queue_interact(pop_expiring, eng.clone()).await, job(2, &b));
+        assert_eq!(queue_interact(pop.clone(), eng.clone()).await, job(2, &b));
+        assert_eq!(queue_interact(pop, eng.clone()).await, ReturnValue::Nil);
+
+        let ack = QueueOps::QAck(key.clone(), smallvec![1, 2, 3]);
+        assert_eq!(
+            queue_interact(ack, eng.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        // Nothing's left, so the key is gone.
+        assert!(!eng.queues.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_pop_gets_redelivered_job() {
+        let key = Bytes::from_static(b"jobs");
+        let a = Bytes::from_static(b"a");
+        let eng = Arc::new(State::default());
+        let push = QueueOps::QPush(key.clone(), smallvec![a.clone()]);
+        queue_interact(push, eng.clone()).await;
+        let pop = QueueOps::QPop(key.clone(), 50, None);
+        assert_eq!(queue_interact(pop, eng.clone()).await, job(1, &a));
+
+        // Nothing's pushed: the waiter is woken when job 1 comes back.
+        let blocked = QueueOps::QPop(key.clone(), 50, Some(5_000));
+        let res =
+            tokio::time::timeout(Duration::from_secs(2), queue_interact(blocked, eng.clone()))
+                .await;
+        assert_eq!(res.unwrap(), job(1, &a));
+    }
+
+    #[tokio::test]
+    async fn test_qpop_logged_as_claim() {
+        let key = Bytes::from_static(b"jobs");
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let (primary, replica) = (Arc::new(State::default()), Arc::new(State::default()));
+        for state in [&primary, &replica].iter() {
+            let push = QueueOps::QPush(key.clone(), smallvec![a.clone(), b.clone()]);
+            queue_interact(push, (*state).clone()).await;
+        }
+        let qpop = command(&["QPOP", "jobs"]);
+        let pop = QueueOps::QPop(key.clone(), 60_000, None);
+        let res = queue_interact(pop, primary.clone()).await;
+        assert_eq!(res, job(1, &a));
+        let deadline = primary.queues.get(&key).unwrap().deadline(1).unwrap();
+        let logged = qpop_as_claim(&primary, "QPop", qpop.clone(), &res);
+        let claim = command(&["QCLAIM", "jobs", "1", &deadline.to_string()]);
+        assert_eq!(logged, Some(claim));
+        assert_eq!(
+            qpop_as_claim(&primary, "QPop", qpop, &ReturnValue::Nil),
+            None
+        );
+
+        // Replaying the claim leaves the same queue.
+        let claim = QueueOps::QClaim(key.clone(), 1, deadline);
+        let res = queue_interact(claim, replica.clone()).await;
+        assert_eq!(res, ReturnValue::IntRes(1));
+        assert_eq!(
+            *replica.queues.get(&key).unwrap(),
+            *primary.queues.get(&key).unwrap()
+        );
+        let gone = QueueOps::QClaim(key, 3, deadline);
+        assert_eq!(queue_interact(gone, replica).await, ReturnValue::IntRes(0));
+    }
+}
@@ -0,0 +1,760 @@
+//! Redis RDB files, for moving datasets between redis and redis-oxide.
+//!
+//! Reading understands what redis 2.6 through 7.4 write for strings, lists,
+//! sets, sorted sets and hashes, in any of their encodings (ziplists,
+//! listpacks, intsets, quicklists, LZF compressed strings). Keys whose
+//! deadline has passed are left out. Streams and module types can't be
+//! skipped over without understanding them, so files holding any are refused.
+//!
+//! Writing uses RDB version 9 (redis 5.0) and the plain encodings every
+//! redis since reads. Our own types (bloom and cuckoo filters, count-min
+//! sketches, top-k trackers, stacks, queues and hyperloglogs) have no RDB
+//! form and are left out, and so are streams, whose listpack encoding we
+//! don't write.
+
+use crate::expiry::now_ms;
+use crate::types::{Index, Key, Score, State, StateStore, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::io::Write;
+
+const MAGIC: &[u8] = b"REDIS";
+/// The newest version we read (redis 7.4).
+const MAX_VERSION: u32 = 12;
+/// The version we write.
+const WRITE_VERSION: u32 = 9;
+
+const OP_SLOT_INFO: u8 = 244;
+const OP_FUNCTION2: u8 = 245;
+const OP_IDLE: u8 = 248;
+const OP_FREQ: u8 = 249;
+const OP_AUX: u8 = 250;
+const OP_RESIZEDB: u8 = 251;
+const OP_EXPIRETIME_MS: u8 = 252;
+const OP_EXPIRETIME: u8 = 253;
+const OP_SELECTDB: u8 = 254;
+const OP_EOF: u8 = 255;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+/// Quicklist (version 2) nodes holding a single big element rather than a listpack.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+
+fn bad(msg: impl Into<String>) -> Box<dyn Error> {
+    format!("Bad RDB file: {}", msg.into()).into()
+}
+
+/// CRC-64/Jones, the checksum at the end of RDB files.
+fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95ac_9329_ac4b_c9b5
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// The most bytes one byte of LZF can come to: a three byte back reference
+/// copies at most 264.
+const LZF_MAX_EXPANSION: usize = 88;
+
+/// Decompress LZF `input`, which must come to `len` bytes. A `len` more than
+/// `input` could come to is only trusted as far as it could.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(LZF_MAX_EXPANSION)));
+    let mut i = 0;
+    let next = |i: &mut usize| -> Result<usize, Box<dyn Error>> {
+        let byte = *input.get(*i).ok_or_else(|| bad("truncated LZF string"))?;
+        *i += 1;
+        Ok(byte as usize)
+    };
+    while i < input.len() {
+        let ctrl = next(&mut i)?;
+        if ctrl < 32 {
+            let literal = input
+                .get(i..i + ctrl + 1)
+                .ok_or_else(|| bad("truncated LZF string"))?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+            continue;
+        }
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += next(&mut i)?;
+        }
+        run += 2;
+        let back = ((ctrl & 0x1f) << 8) + next(&mut i)? + 1;
+        if back > out.len() {
+            return Err(bad("LZF reference before the start"));
+        }
+        let start = out.len() - back;
+        for n in 0..run {
+            out.push(out[start + n]);
+        }
+        if out.len() > len {
+            return Err(bad("LZF string has the wrong length"));
+        }
+    }
+    if out.len() != len {
+        return Err(bad("LZF string has the wrong length"));
+    }
+    Ok(out)
+}
+
+/// A length, or the special encoding a string is in.
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.buf.get(self.pos..end))
+            .ok_or_else(|| bad("unexpected end"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Box<dyn Error>> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn length(&mut self) -> Result<Length, Box<dyn Error>> {
+        let first = self.u8()?;
+        Ok(match (first >> 6, first) {
+            (0, _) => Length::Len((first & 0x3f) as u64),
+            (1, _) => Length::Len((((first & 0x3f) as u64) << 8) | self.u8()? as u64),
+            (2, 0x80) => Length::Len(u32::from_be_bytes(self.array()?) as u64),
+            (2, 0x81) => Length::Len(u64::from_be_bytes(self.array()?)),
+            (2, _) => return Err(bad(format!("unknown length encoding {:#x}", first))),
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn len(&mut self) -> Result<u64, Box<dyn Error>> {
+        match self.length()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(bad("expected a length")),
+        }
+    }
+
+    /// A count of elements, which has to fit what's left of the file.
+    fn count(&mut self) -> Result<usize, Box<dyn Error>> {
+        let count = self.len()?;
+        if count > (self.buf.len() - self.pos) as u64 {
+            return Err(bad("more elements than bytes left"));
+        }
+        Ok(count as usize)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self.length()? {
+            Length::Len(len) => {
+                let len = usize::try_from(len).map_err(|_| bad("string too long"))?;
+                Ok(self.bytes(len)?.to_vec())
+            }
+            Length::Encoded(0) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(2) => Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(3) => {
+                let compressed = self.count()?;
+                let len = self.len()? as usize;
+                lzf_decompress(self.bytes(compressed)?, len)
+            }
+            Length::Encoded(e) => Err(bad(format!("unknown string encoding {}", e))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Box<dyn Error>> {
+        Ok(self.string()?.into())
+    }
+
+    /// A score as written by the old ZSET type: a length prefixed string.
+    fn string_double(&mut self) -> Result<f64, Box<dyn Error>> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => std::str::from_utf8(self.bytes(len as usize)?)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| bad("bad score")),
+        }
+    }
+
+    fn binary_double(&mut self) -> Result<f64, Box<dyn Error>> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+}
+
+fn int_value(n: i64) -> Value {
+    n.to_string().into()
+}
+
+/// The elements of a ziplist (redis < 7.0's small list, hash and zset encoding).
+fn ziplist(blob: &[u8]) -> Result<Vec<Value>, Box<dyn Error>> {
+    let mut r = Reader::new(blob);
+    r.bytes(4 + 4)?;
+    let mut elements = Vec::with_capacity(u16::from_le_bytes(r.array()?) as usize);
+    loop {
+        let prev_len = r.u8()?;
+        if prev_len == 0xff {
+            return Ok(elements);
+        }
+        if prev_len == 0xfe {
+            r.bytes(4)?;
+        }
+        let encoding = r.u8()?;
+        let element = match encoding >> 6 {
+            0 => Value::copy_from_slice(r.bytes((encoding & 0x3f) as usize)?),
+            1 => {
+                let len = (((encoding & 0x3f) as usize) << 8) | r.u8()? as usize;
+                Value::copy_from_slice(r.bytes(len)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(r.array()?) as usize;
+                Value::copy_from_slice(r.bytes(len)?)
+            }
+            _ => int_value(match encoding {
+                0xc0 => i16::from_le_bytes(r.array()?) as i64,
+                0xd0 => i32::from_le_bytes(r.array()?) as i64,
+                0xe0 => i64::from_le_bytes(r.array()?),
+                0xf0 => {
+                    let [a, b, c] = r.array::<3>()?;
+                    i32::from_le_bytes([0, a, b, c]) as i64 >> 8
+                }
+                0xfe => r.u8()? as i8 as i64,
+                0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                _ => return Err(bad(format!("unknown ziplist encoding {:#x}", encoding))),
+            }),
+        };
+        elements.push(element);
+    }
+}
+
+/// The elements of a listpack (redis >= 7.0's small list, set, hash and zset encoding).
+fn listpack(blob: &[u8]) -> Result<Vec<Value>, Box<dyn Error>> {
+    let mut r = Reader::new(blob);
+    r.bytes(4)?;
+    let mut elements = Vec::with_capacity(u16::from_le_bytes(r.array()?) as usize);
+    loop {
+        let start = r.pos;
+        let encoding = r.u8()?;
+        let element = match encoding {
+            0xff => return Ok(elements),
+            e if e & 0x80 == 0 => int_value((e & 0x7f) as i64),
+            e if e & 0xc0 == 0x80 => Value::copy_from_slice(r.bytes((e & 0x3f) as usize)?),
+            e if e & 0xe0 == 0xc0 => {
+                let n = (((e & 0x1f) as i64) << 8) | r.u8()? as i64;
+                int_value(if n >= 1 << 12 { n - (1 << 13) } else { n })
+            }
+            e if e & 0xf0 == 0xe0 => {
+                let len = (((e & 0x0f) as usize) << 8) | r.u8()? as usize;
+                Value::copy_from_slice(r.bytes(len)?)
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(r.array()?) as usize;
+                Value::copy_from_slice(r.bytes(len)?)
+            }
+            0xf1 => int_value(i16::from_le_bytes(r.array()?) as i64),
+            0xf2 => {
+                let [a, b, c] = r.array::<3>()?;
+                int_value(i32::from_le_bytes([0, a, b, c]) as i64 >> 8)
+            }
+            0xf3 => int_value(i32::from_le_bytes(r.array()?) as i64),
+            0xf4 => int_value(i64::from_le_bytes(r.array()?)),
+            e => return Err(bad(format!("unknown listpack encoding {:#x}", e))),
+        };
+        // Skip the entry's length, kept for walking backwards.
+        let entry_len = r.pos - start;
+        let back_len = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        r.bytes(back_len)?;
+        elements.push(element);
+    }
+}
+
+/// The members of an intset.
+fn intset(blob: &[u8]) -> Result<Vec<Value>, Box<dyn Error>> {
+    let mut r = Reader::new(blob);
+    let width = u32::from_le_bytes(r.array()?);
+    let len = u32::from_le_bytes(r.array()?);
+    (0..len)
+        .map(|_| {
+            Ok(int_value(match width {
+                2 => i16::from_le_bytes(r.array()?) as i64,
+                4 => i32::from_le_bytes(r.array()?) as i64,
+                8 => i64::from_le_bytes(r.array()?),
+                _ => return Err(bad(format!("unknown intset width {}", width))),
+            }))
+        })
+        .collect()
+}
+
+fn pairs(elements: Vec<Value>) -> Result<Vec<(Value, Value)>, Box<dyn Error>> {
+    if elements.len() % 2 != 0 {
+        return Err(bad("odd number of elements in a hash or sorted set"));
+    }
+    let mut elements = elements.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(a), Some(b)) = (elements.next(), elements.next()) {
+        pairs.push((a, b));
+    }
+    Ok(pairs)
+}
+
+fn score(score: f64) -> Result<Score, Box<dyn Error>> {
+    Score::new(score).ok_or_else(|| bad("NaN score"))
+}
+
+fn parsed_score(member: &[u8]) -> Result<Score, Box<dyn Error>> {
+    let parsed = std::str::from_utf8(member)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| bad("bad score"))?;
+    score(parsed)
+}
+
+fn insert_zset(state: &State, key: Key, members: Vec<(Value, Score)>) {
+    let mut zset = state.zsets.entry(key).or_default();
+    for (member, score) in members {
+        zset.insert(member, score);
+    }
+}
+
+/// Read the value of type `value_type` at `key` into `state`.
+fn read_value(
+    r: &mut Reader<'_>,
+    value_type: u8,
+    key: Key,
+    state: &State,
+) -> Result<(), Box<dyn Error>> {
+    match value_type {
+        TYPE_STRING => {
+            state.kv.insert(key, r.value()?);
+        }
+        TYPE_LIST => {
+            let list = (0..r.count()?)
+                .map(|_| r.value())
+                .collect::<Result<_, _>>()?;
+            state.lists.insert(key, list);
+        }
+        TYPE_LIST_ZIPLIST => {
+            state.lists.insert(key, ziplist(&r.string()?)?.into());
+        }
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let mut list = VecDeque::new();
+            for _ in 0..r.count()? {
+                let container = match value_type {
+                    TYPE_LIST_QUICKLIST_2 => r.len()?,
+                    _ => 0,
+                };
+                let node = r.string()?;
+                match (value_type, container) {
+                    (TYPE_LIST_QUICKLIST, _) => list.extend(ziplist(&node)?),
+                    (_, QUICKLIST_NODE_PLAIN) => list.push_back(node.into()),
+                    _ => list.extend(listpack(&node)?),
+                }
+            }
+            state.lists.insert(key, list);
+        }
+        TYPE_SET => {
+            let set = (0..r.count()?)
+                .map(|_| r.value())
+                .collect::<Result<_, _>>()?;
+            state.sets.insert(key, set);
+        }
+        TYPE_SET_INTSET => {
+            let set: HashSet<Value> = intset(&r.string()?)?.into_iter().collect();
+            state.sets.insert(key, set);
+        }
+        TYPE_SET_LISTPACK => {
+            let set: HashSet<Value> = listpack(&r.string()?)?.into_iter().collect();
+            state.sets.insert(key, set);
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let mut members = Vec::new();
+            for _ in 0..r.count()? {
+                let member = r.value()?;
+                let raw = match value_type {
+                    TYPE_ZSET => r.string_double()?,
+                    _ => r.binary_double()?,
+                };
+                members.push((member, score(raw)?));
+            }
+            insert_zset(state, key, members);
+        }
+        TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+            let elements = match value_type {
+                TYPE_ZSET_ZIPLIST => ziplist(&r.string()?)?,
+                _ => listpack(&r.string()?)?,
+            };
+            let members = pairs(elements)?
+                .into_iter()
+                .map(|(member, raw)| Ok((member, parsed_score(&raw)?)))
+                .collect::<Result<_, Box<dyn Error>>>()?;
+            insert_zset(state, key, members);
+        }
+        TYPE_HASH => {
+            let mut hash = HashMap::new();
+            for _ in 0..r.count()? {
+                hash.insert(r.value()?, r.value()?);
+            }
+            state.hashes.insert(key, hash);
+        }
+        TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+            let elements = match value_type {
+                TYPE_HASH_ZIPLIST => ziplist(&r.string()?)?,
+                _ => listpack(&r.string()?)?,
+            };
+            let hash: HashMap<Key, Value> = pairs(elements)?.into_iter().collect();
+            state.hashes.insert(key, hash);
+        }
+        other => {
+            return Err(bad(format!(
+                "value type {} (of key {:?}) isn't supported",
+                other,
+                String::from_utf8_lossy(&key)
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Read an RDB file into a new StateStore.
+pub fn read_rdb(contents: &[u8]) -> Result<StateStore, Box<dyn Error>> {
+    let mut r = Reader::new(contents);
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err("Not an RDB file".into());
+    }
+    let version: u32 = std::str::from_utf8(r.bytes(4)?)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| bad("bad version"))?;
+    if version > MAX_VERSION {
+        return Err(format!(
+            "RDB version {} is newer than we read ({})",
+            version, MAX_VERSION
+        )
+        .into());
+    }
+    let store = StateStore::default();
+    let mut state = store.get_or_create(0);
+    let mut deadline = None;
+    let now = now_ms();
+    loop {
+        match r.u8()? {
+            OP_EOF => break,
+            OP_SELECTDB => state = store.get_or_create(r.len()? as Index),
+            OP_RESIZEDB => {
+                r.len()?;
+                r.len()?;
+            }
+            OP_AUX => {
+                r.string()?;
+                r.string()?;
+            }
+            OP_EXPIRETIME_MS => deadline = Some(u64::from_le_bytes(r.array()?)),
+            OP_EXPIRETIME => deadline = Some(u32::from_le_bytes(r.array()?) as u64 * 1000),
+            OP_IDLE => {
+                r.len()?;
+            }
+            OP_FREQ => {
+                r.u8()?;
+            }
+            OP_FUNCTION2 => {
+                r.string()?;
+            }
+            OP_SLOT_INFO => {
+                for _ in 0..3 {
+                    r.len()?;
+                }
+            }
+            value_type => {
+                let key: Key = r.value()?;
+                read_value(&mut r, value_type, key.clone(), &state)?;
+                match deadline.take() {
+                    Some(deadline) if deadline <= now => {
+                        state.remove_key(&key);
+                    }
+                    Some(deadline) => state.set_expiry(key, deadline),
+                    None => {}
+                }
+            }
+        }
+    }
+    if version >= 5 {
+        let end = r.pos;
+        let expected = u64::from_le_bytes(r.array()?);
+        // Redis writes 0 when checksums are off.
+        if expected != 0 && crc64(0, &contents[..end]) != expected {
+            return Err(bad("checksum mismatch"));
+        }
+    }
+    if !r.is_empty() {
+        return Err(bad("trailing bytes after the end"));
+    }
+    Ok(store)
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    let len = len as u64;
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &[u8]) {
+    write_len(out, s.len());
+    out.extend_from_slice(s);
+}
+
+/// Start a key of `value_type`, with its deadline if it has one.
+fn write_key(out: &mut Vec<u8>, state: &State, value_type: u8, key: &[u8]) {
+    if let Some(deadline) = state.expiry(key) {
+        out.push(OP_EXPIRETIME_MS);
+        out.extend_from_slice(&deadline.to_le_bytes());
+    }
+    out.push(value_type);
+    write_string(out, key);
+}
+
+fn write_db(out: &mut Vec<u8>, state: &State) {
+    for entry in state.kv.iter() {
+        write_key(out, state, TYPE_STRING, entry.key());
+        write_string(out, entry.value());
+    }
+    for entry in state.lists.iter() {
+        write_key(out, state, TYPE_LIST, entry.key());
+        write_len(out, entry.value().len());
+        for element in entry.value() {
+            write_string(out, element);
+        }
+    }
+    for entry in state.sets.iter() {
+        write_key(out, state, TYPE_SET, entry.key());
+        write_len(out, entry.value().len());
+        for member in entry.value() {
+            write_string(out, member);
+        }
+    }
+    for entry in state.zsets.iter() {
+        write_key(out, state, TYPE_ZSET_2, entry.key());
+        write_len(out, entry.value().card() as usize);
+        for (member, score) in entry.value().iter() {
+            write_string(out, member);
+            out.extend_from_slice(&score.value().to_le_bytes());
+        }
+    }
+    for entry in state.hashes.iter() {
+        write_key(out, state, TYPE_HASH, entry.key());
+        write_len(out, entry.value().len());
+        for (field, value) in entry.value() {
+            write_string(out, field);
+            write_string(out, value);
+        }
+    }
+}
+
+/// Write every db of `store` as an RDB file.
+/// Returns how many keys were left out for having no RDB form.
+pub fn write_rdb<W: Write>(out: &mut W, store: &StateStore) -> Result<usize, Box<dyn Error>> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(format!("{:04}", WRITE_VERSION).as_bytes());
+    buf.push(OP_AUX);
+    write_string(&mut buf, b"redis-ver");
+    write_string(&mut buf, b"5.0.0");
+    buf.push(OP_AUX);
+    write_string(&mut buf, b"ctime");
+    write_string(&mut buf, (now_ms() / 1000).to_string().as_bytes());
+    let mut dbs: Vec<Index> = store.states.iter().map(|entry| *entry.key()).collect();
+    dbs.sort_unstable();
+    let mut left_out = 0;
+    for db in dbs.into_iter().filter(|db| *db >= 0) {
+        let state = match store.states.get(&db) {
+            Some(state) => state.clone(),
+            None => continue,
+        };
+        // Every structure but those write_db writes.
+        let written = state.kv.len()
+            + state.sets.len()
+            + state.lists.len()
+            + state.hashes.len()
+            + state.zsets.len();
+        left_out += state.key_count().saturating_sub(written);
+        buf.push(OP_SELECTDB);
+        write_len(&mut buf, db as usize);
+        write_db(&mut buf, &state);
+    }
+    buf.push(OP_EOF);
+    let checksum = crc64(0, &buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    out.write_all(&buf)?;
+    Ok(left_out)
+}
+
+#[cfg(test)]
+mod test_rdb {
+    use super::{crc64, lzf_decompress, read_rdb, write_rdb};
+    use crate::data_structures::cuckoo_filter::CuckooFilter;
+    use crate::expiry::now_ms;
+    use crate::types::{Score, StateStore};
+    use bytes::Bytes;
+
+    fn key(s: &'static str) -> Bytes {
+        Bytes::from_static(s.as_bytes())
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn test_lzf() {
+        let compressed = [0x00, b'a', 0xe0, 0x00, 0x00];
+        assert_eq!(lzf_decompress(&compressed, 10).unwrap(), b"aaaaaaaaaa");
+        assert!(lzf_decompress(&compressed, 11).is_err());
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+        // A huge claimed length isn't allocated up front.
+        assert!(lzf_decompress(&compressed, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let store = StateStore::default();
+        let db = store.get_or_create(3);
+        let deadline = now_ms() + 100_000;
+        db.kv.insert(key("s"), key("value"));
+        db.set_expiry(key("s"), deadline);
+        db.lists
+            .entry(key("l"))
+            .or_default()
+            .extend(vec![key("a"), key("b")]);
+        db.sets.entry(key("set")).or_default().insert(key("m"));
+        db.zsets
+            .entry(key("z"))
+            .or_default()
+            .insert(key("m"), Score::new(1.5).unwrap());
+        db.hashes
+            .entry(key("h"))
+            .or_default()
+            .insert(key("f"), key("v"));
+        db.stacks.entry(key("stack")).or_default();
+        db.streams.entry(key("stream")).or_default();
+        db.cuckoos.insert(key("cuckoo"), CuckooFilter::new(16));
+
+        let mut rdb = Vec::new();
+        assert_eq!(write_rdb(&mut rdb, &store).unwrap(), 3);
+        let loaded = read_rdb(&rdb).unwrap();
+        let db = loaded.get_or_create(3);
+        assert_eq!(db.kv.get(&key("s")).unwrap().value(), &key("value"));
+        assert_eq!(db.expiry(b"s"), Some(deadline));
+        assert_eq!(db.lists.get(&key("l")).unwrap().len(), 2);
+        assert!(db.sets.get(&key("set")).unwrap().contains(&key("m")));
+        assert_eq!(
+            db.zsets.get(&key("z")).unwrap().score(key("m")),
+            Score::new(1.5)
+        );
+        assert_eq!(
+            db.hashes.get(&key("h")).unwrap().get(&key("f")),
+            Some(&key("v"))
+        );
+        assert!(db.stacks.is_empty() && db.streams.is_empty() && db.cuckoos.is_empty());
+
+        // A flipped bit fails the checksum.
+        let last = rdb.len() - 10;
+        rdb[last] ^= 1;
+        assert!(read_rdb(&rdb).is_err());
+    }
+
+    #[test]
+    fn test_compact_encodings() {
+        let mut rdb = b"REDIS0011".to_vec();
+        // AUX redis-ver 7.2.0
+        rdb.extend_from_slice(b"\xfa\x09redis-ver\x057.2.0");
+        rdb.extend_from_slice(b"\xfe\x00");
+        // A listpack hash {a: 1}.
+        rdb.extend_from_slice(b"\x10\x01h\x0c\x0c\x00\x00\x00\x02\x00\x81a\x02\x01\x01\xff");
+        // An intset {1, 300}.
+        rdb.extend_from_slice(b"\x0b\x01s\x0c\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x2c\x01");
+        // A ziplist list [abc, 12].
+        rdb.extend_from_slice(b"\x0a\x01l\x12\x12\x00\x00\x00\x0f\x00\x00\x00\x02\x00");
+        rdb.extend_from_slice(b"\x00\x03abc\x05\xfd\xff");
+        // An LZF compressed string, and an integer encoded one.
+        rdb.extend_from_slice(b"\x00\x03lzf\xc3\x05\x0a\x00a\xe0\x00\x00");
+        rdb.extend_from_slice(b"\x00\x03int\xc1\x39\x30");
+        // A key that expired already.
+        rdb.extend_from_slice(b"\xfc\x01\x00\x00\x00\x00\x00\x00\x00\x00\x04gone\x01x");
+        // EOF, with checksums off.
+        rdb.extend_from_slice(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        let loaded = read_rdb(&rdb).unwrap();
+        let db = loaded.get_default();
+        assert_eq!(
+            db.hashes.get(&key("h")).unwrap().get(&key("a")),
+            Some(&key("1"))
+        );
+        let set = db.sets.get(&key("s")).unwrap();
+        assert!(set.contains(&key("1")) && set.contains(&key("300")));
+        let list: Vec<_> = db.lists.get(&key("l")).unwrap().iter().cloned().collect();
+        assert_eq!(list, vec![key("abc"), key("12")]);
+        assert_eq!(db.kv.get(&key("lzf")).unwrap().value(), &key("aaaaaaaaaa"));
+        assert_eq!(db.kv.get(&key("int")).unwrap().value(), &key("12345"));
+        assert!(!db.kv.contains_key(&key("gone")));
+    }
+}
@@ -0,0 +1,886 @@
+//! Replication: replicas follow the writes of a primary.
+//!
+//! Once a replica has synced, every committed write is fed to the backlog,
+//! encoded the way the AOF logs it (with SELECTs, and SET, RESTORE and SPOP
+//! as the RESTOREs of what they left). Replicas are sent the backlog as it
+//! grows, and send back `REPLCONF ACK <offset>` every second.
+//!
+//! A replica connects with `PSYNC <replid> <offset>`: the id of the stream it
+//! followed, and how far it got. If the backlog still holds the rest, it's
+//! sent on from there (`+CONTINUE`). Otherwise the replica gets
+//! `+FULLRESYNC <replid> <offset>`, a snapshot of every db in the dump
+//! format, and the stream from that offset on. Replicas must be redis-oxide,
+//! as redis expects the snapshot to be an RDB file.
+//!
+//! The link is plain TCP, even with `--features tls`: the snapshot, writes
+//! and masterauth all go unencrypted.
+//!
+//! A replica AUTHs with `masterauth` first, if set. Replicas refuse writes
+//! from clients, and don't sweep expired keys themselves: the primary's DELs
+//! do. `REPLICAOF NO ONE` makes one a primary again, keeping its data.
+
+use crate::asyncresp::{RespLimits, RespParser};
+use crate::database::encode_write;
+use crate::dump_format;
+use crate::logger::LOGGER;
+use crate::scripting::ScriptingBridge;
+use crate::server::apply_replicated;
+use crate::state::SelectedDb;
+use crate::types::{bulk, Index, Key, RedisValueRef, State, StateStore, StateStoreRef};
+use bytes::BytesMut;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task;
+use tokio_util::codec::{Encoder, FramedRead};
+
+/// Default size of the backlog (`--repl-backlog-size`).
+pub const DEFAULT_BACKLOG_SIZE: usize = 1 << 20;
+/// How often replicas acknowledge what they've applied.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a replica waits before reconnecting to a lost primary.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+fn new_replid() -> String {
+    let mut rng = thread_rng();
+    (0..20)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+fn encode(command: RedisValueRef) -> BytesMut {
+    let mut encoded = BytesMut::new();
+    // Encoding into memory can't fail.
+    let _ = RespParser::default().encode(command, &mut encoded);
+    encoded
+}
+
+/// The tail of the write stream.
+struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// Where the stream ends: how many bytes were ever fed.
+    offset: u64,
+    /// The db the stream last SELECTed.
+    db: Option<Index>,
+}
+
+impl Backlog {
+    fn new(capacity: usize) -> Self {
+        Backlog {
+            buf: VecDeque::new(),
+            capacity,
+            offset: 0,
+            db: None,
+        }
+    }
+
+    /// Offset of the oldest byte still held.
+    fn first_offset(&self) -> u64 {
+        self.offset - self.buf.len() as u64
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        if self.buf.len() > self.capacity {
+            let excess = self.buf.len() - self.capacity;
+            self.buf.drain(..excess);
+        }
+        self.offset += bytes.len() as u64;
+    }
+
+    /// The stream after `offset`, or None if it's no longer (or not yet) held.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.first_offset() || offset > self.offset {
+            return None;
+        }
+        let skip = (offset - self.first_offset()) as usize;
+        Some(self.buf.range(skip..).copied().collect())
+    }
+}
+
+/// The primary a replica follows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrimaryLink {
+    pub host: String,
+    pub port: u16,
+    /// Whether we're connected and synced.
+    pub up: bool,
+    /// The stream of the primary we follow, once we've synced.
+    pub replid: Option<String>,
+    /// How far into that stream we've applied.
+    pub offset: u64,
+    pub last_io: Option<Instant>,
+    /// Tells this link from the ones before it.
+    generation: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    Primary,
+    Replica(PrimaryLink),
+}
+
+/// A replica fed by us.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaInfo {
+    pub addr: SocketAddr,
+    /// How far the replica says it's applied.
+    pub acked: u64,
+    pub last_ack: Instant,
+}
+
+pub struct Replication {
+    replid: Mutex<String>,
+    backlog: Mutex<Backlog>,
+    /// Set once a replica has synced. From then on writes are fed to the backlog.
+    feeding: AtomicBool,
+    fed: Notify,
+    role: Mutex<Role>,
+    links: AtomicU64,
+    replicas: Mutex<HashMap<u64, ReplicaInfo>>,
+    next_replica: AtomicU64,
+    /// Woken whenever a replica acknowledges how far it got.
+    acked: Notify,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication::new(DEFAULT_BACKLOG_SIZE)
+    }
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize) -> Self {
+        Replication {
+            replid: Mutex::new(new_replid()),
+            backlog: Mutex::new(Backlog::new(backlog_size)),
+            feeding: AtomicBool::new(false),
+            fed: Notify::new(),
+            role: Mutex::new(Role::Primary),
+            links: AtomicU64::new(0),
+            replicas: Mutex::new(HashMap::new()),
+            next_replica: AtomicU64::new(0),
+            acked: Notify::new(),
+        }
+    }
+
+    /// Whether writes are fed to the backlog.
+    pub fn feeding(&self) -> bool {
+        self.feeding.load(Ordering::SeqCst)
+    }
+
+    pub fn replid(&self) -> String {
+        self.replid.lock().clone()
+    }
+
+    /// How many bytes of writes were fed so far.
+    pub fn offset(&self) -> u64 {
+        self.backlog.lock().offset
+    }
+
+    /// The backlog's size, the offset of its first byte, and how many bytes it holds.
+    pub fn backlog_info(&self) -> (usize, u64, usize) {
+        let backlog = self.backlog.lock();
+        (backlog.capacity, backlog.first_offset(), backlog.buf.len())
+    }
+
+    pub fn role(&self) -> Role {
+        self.role.lock().clone()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        matches!(*self.role.lock(), Role::Replica(_))
+    }
+
+    /// The replicas we feed, in the order they connected.
+    pub fn replicas(&self) -> Vec<ReplicaInfo> {
+        let replicas = self.replicas.lock();
+        let mut ids: Vec<&u64> = replicas.keys().collect();
+        ids.sort_unstable();
+        ids.into_iter().map(|id| replicas[id].clone()).collect()
+    }
+
+    /// Feed a committed write to db `db` to the replicas. Writes call this
+    /// holding the store's write order, so replicas get them in the order
+    /// they landed (blocking pops aside, like in the AOF).
+    pub fn feed(&self, db: Index, state: &State, op: &str, keys: &[Key], command: &RedisValueRef) {
+        let mut entry = BytesMut::new();
+        let mut backlog = self.backlog.lock();
+        encode_write(&mut entry, &mut backlog.db, db, state, op, keys, command);
+        backlog.push(&entry);
+        drop(backlog);
+        self.fed.notify_waiters();
+    }
+
+    /// Start a new stream, which replicas of ours can't continue from the old one.
+    fn restart_stream(&self) {
+        *self.replid.lock() = new_replid();
+        let mut backlog = self.backlog.lock();
+        backlog.buf.clear();
+        backlog.db = None;
+    }
+
+    /// Where a replica asking for `request` can pick up from, if it can.
+    fn continue_from(&self, request: &PsyncRequest) -> Option<u64> {
+        if request.replid.as_deref() != Some(self.replid().as_str()) {
+            return None;
+        }
+        let offset = request.offset?;
+        self.backlog.lock().since(offset).map(|_| offset)
+    }
+
+    /// Snapshot every db, returning the snapshot and the offset of the first
+    /// write it doesn't hold.
+    async fn snapshot(&self, store: &StateStore) -> Result<(Vec<u8>, u64), Box<dyn Error>> {
+        self.feeding.store(true, Ordering::SeqCst);
+        // Wait for writes which saw we weren't feeding yet.
+        let _order = store.write_order.write().await;
+        let offset = {
+            let mut backlog = self.backlog.lock();
+            // The replica doesn't know which db the stream has SELECTed.
+            backlog.db = None;
+            backlog.offset
+        };
+        let mut snapshot = Vec::new();
+        task::block_in_place(|| dump_format::write_state(&mut snapshot, store, &[], false))?;
+        Ok((snapshot, offset))
+    }
+
+    fn add_replica(&self, addr: SocketAddr, offset: u64) -> u64 {
+        let id = self.next_replica.fetch_add(1, Ordering::SeqCst);
+        let info = ReplicaInfo {
+            addr,
+            acked: offset,
+            last_ack: Instant::now(),
+        };
+        self.replicas.lock().insert(id, info);
+        id
+    }
+
+    fn record_ack(&self, id: u64, offset: u64) {
+        if let Some(info) = self.replicas.lock().get_mut(&id) {
+            info.acked = offset;
+            info.last_ack = Instant::now();
+        }
+        self.acked.notify_waiters();
+    }
+
+    /// How many replicas have applied the stream up to `offset`.
+    pub fn acked_by(&self, offset: u64) -> usize {
+        let replicas = self.replicas.lock();
+        replicas
+            .values()
+            .filter(|info| info.acked >= offset)
+            .count()
+    }
+
+    /// Ask the replicas to acknowledge right away, rather than within a second.
+    fn request_acks(&self) {
+        if self.replicas.lock().is_empty() {
+            return;
+        }
+        let getack = RedisValueRef::Array(vec![bulk("REPLCONF"), bulk("GETACK"), bulk("*")]);
+        self.backlog.lock().push(&encode(getack));
+        self.fed.notify_waiters();
+    }
+
+    /// WAIT: wait until `count` replicas applied every write fed so far, or
+    /// `timeout` passes (None waits for as long as it takes). Returns how
+    /// many replicas did.
+    pub async fn wait_for_replicas(&self, count: usize, timeout: Option<Duration>) -> usize {
+        let offset = self.offset();
+        if self.acked_by(offset) < count {
+            self.request_acks();
+            let enough = async {
+                loop {
+                    // Made before looking, so an ack meanwhile still wakes us.
+                    let acked = self.acked.notified();
+                    if self.acked_by(offset) >= count {
+                        return;
+                    }
+                    acked.await;
+                }
+            };
+            match timeout {
+                Some(timeout) => {
+                    let _ = tokio::time::timeout(timeout, enough).await;
+                }
+                None => enough.await,
+            }
+        }
+        self.acked_by(offset)
+    }
+
+    /// Update the link to our primary, unless it's been replaced.
+    fn update_link(&self, generation: u64, update: impl FnOnce(&mut PrimaryLink)) -> bool {
+        match &mut *self.role.lock() {
+            Role::Replica(link) if link.generation == generation => {
+                update(link);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The link we're on, if it's still the one numbered `generation`.
+    fn link(&self, generation: u64) -> Option<PrimaryLink> {
+        match &*self.role.lock() {
+            Role::Replica(link) if link.generation == generation => Some(link.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// What a replica asked for with PSYNC: the stream it followed, and how far
+/// it got. SYNC and PSYNC ? -1 ask for a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PsyncRequest {
+    replid: Option<String>,
+    offset: Option<u64>,
+}
+
+impl PsyncRequest {
+    /// The request, if `redis_value` is a PSYNC or SYNC command.
+    pub fn parse(redis_value: &RedisValueRef) -> Option<Self> {
+        let args = match redis_value {
+            RedisValueRef::Array(args) => args,
+            _ => return None,
+        };
+        let arg = |i: usize| match args.get(i) {
+            Some(RedisValueRef::BulkString(s)) => Some(String::from_utf8_lossy(s).to_string()),
+            _ => None,
+        };
+        match arg(0)?.to_lowercase().as_str() {
+            "psync" => Some(PsyncRequest {
+                replid: arg(1).filter(|replid| replid != "?"),
+                offset: arg(2).and_then(|offset| offset.parse().ok()),
+            }),
+            "sync" => Some(PsyncRequest {
+                replid: None,
+                offset: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn ack(offset: u64) -> BytesMut {
+    encode(RedisValueRef::Array(vec![
+        bulk("REPLCONF"),
+        bulk("ACK"),
+        bulk(offset.to_string()),
+    ]))
+}
+
+/// Whether `redis_value` is the primary asking for an ack (`REPLCONF GETACK *`).
+fn is_getack(redis_value: &RedisValueRef) -> bool {
+    match redis_value {
+        RedisValueRef::Array(args) => matches!(
+            &args[..],
+            [RedisValueRef::BulkString(cmd), RedisValueRef::BulkString(sub), ..]
+                if cmd.eq_ignore_ascii_case(b"replconf") && sub.eq_ignore_ascii_case(b"getack")
+        ),
+        _ => false,
+    }
+}
+
+/// The offset in a `REPLCONF ACK <offset>`.
+fn parse_ack(redis_value: &RedisValueRef) -> Option<u64> {
+    match redis_value {
+        RedisValueRef::Array(args) => match &args[..] {
+            [RedisValueRef::BulkString(cmd), RedisValueRef::BulkString(sub), RedisValueRef::BulkString(offset), ..]
+                if cmd.eq_ignore_ascii_case(b"replconf") && sub.eq_ignore_ascii_case(b"ack") =>
+            {
+                std::str::from_utf8(offset).ok()?.parse().ok()
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+async fn feed_replica<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    buffered: BytesMut,
+    addr: SocketAddr,
+    store: &StateStore,
+    request: PsyncRequest,
+) -> Result<(), Box<dyn Error>> {
+    let (read, mut write) = tokio::io::split(socket);
+    let replication = &store.replication;
+    let mut offset = match replication.continue_from(&request) {
+        Some(offset) => {
+            let reply = format!("+CONTINUE {}\r\n", replication.replid());
+            write.write_all(reply.as_bytes()).await?;
+            info!(LOGGER, "Replica {} continues from offset {}", addr, offset);
+            offset
+        }
+        None => {
+            let (snapshot, offset) = replication.snapshot(store).await?;
+            let reply = format!(
+                "+FULLRESYNC {} {}\r\n${}\r\n",
+                replication.replid(),
+                offset,
+                snapshot.len()
+            );
+            write.write_all(reply.as_bytes()).await?;
+            write.write_all(&snapshot).await?;
+            info!(
+                LOGGER,
+                "Sent replica {} a {} byte snapshot",
+                addr,
+                snapshot.len()
+            );
+            offset
+        }
+    };
+    let id = replication.add_replica(addr, offset);
+    let mut acks = FramedRead::new(read, RespParser::default());
+    // Whatever the replica sent after PSYNC, read along with it.
+    acks.read_buffer_mut().extend_from_slice(&buffered);
+    let res = loop {
+        // Made before looking, so a write fed meanwhile still wakes us.
+        let fed = replication.fed.notified();
+        tokio::pin!(fed);
+        let pending = match replication.backlog.lock().since(offset) {
+            Some(pending) => pending,
+            None => break Err("the replica fell behind the backlog".into()),
+        };
+        if !pending.is_empty() {
+            if let Err(e) = write.write_all(&pending).await {
+                break Err(e.into());
+            }
+            offset += pending.len() as u64;
+        }
+        tokio::select! {
+            _ = &mut fed => {}
+            ack = acks.next() => match ack {
+                Some(Ok(ack)) => {
+                    if let Some(acked) = parse_ack(&ack) {
+                        replication.record_ack(id, acked);
+                    }
+                }
+                Some(Err(e)) => break Err(format!("bad message from the replica: {:?}", e).into()),
+                None => break Ok(()),
+            },
+        }
+    };
+    replication.replicas.lock().remove(&id);
+    res
+}
+
+/// Feed the replica at `addr` which sent `request` over `socket`, until it's
+/// gone. `buffered` is what was read from the socket past the request.
+pub async fn serve_replica<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    buffered: BytesMut,
+    addr: SocketAddr,
+    store: StateStoreRef,
+    request: PsyncRequest,
+) {
+    match feed_replica(socket, buffered, addr, &store, request).await {
+        Ok(()) => info!(LOGGER, "Replica disconnected"),
+        Err(e) => warn!(LOGGER, "Stopped feeding a replica: {}", e),
+    }
+}
+
+/// Read a line, without its line ending.
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<String, Box<dyn Error>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err("the primary closed the connection".into());
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Connect to our primary, sync with it, and apply its writes until the
+/// link drops or is replaced.
+async fn sync_with_primary(
+    store: &StateStoreRef,
+    scripting_bridge: &Arc<ScriptingBridge>,
+    generation: u64,
+    selected: &mut SelectedDb,
+) -> Result<(), Box<dyn Error>> {
+    let replication = &store.replication;
+    let link = replication.link(generation).ok_or("replaced")?;
+    let socket = TcpStream::connect((link.host.as_str(), link.port)).await?;
+    let (read, mut write) = socket.into_split();
+    let mut reader = BufReader::new(read);
+    if let Some(password) = store.config.masterauth() {
+        let auth = RedisValueRef::Array(vec![bulk("AUTH"), bulk(password)]);
+        write.write_all(&encode(auth)).await?;
+        let reply = read_line(&mut reader).await?;
+        if reply.starts_with('-') {
+            return Err(format!("the primary refused masterauth: {}", reply).into());
+        }
+    }
+    let psync = RedisValueRef::Array(vec![
+        bulk("PSYNC"),
+        bulk(link.replid.clone().unwrap_or_else(|| "?".into())),
+        bulk(match link.replid {
+            Some(_) => link.offset.to_string(),
+            None => "-1".into(),
+        }),
+    ]);
+    write.write_all(&encode(psync)).await?;
+    let reply = read_line(&mut reader).await?;
+    let words: Vec<&str> = reply.split(' ').collect();
+    match &words[..] {
+        ["+FULLRESYNC", replid, offset] => {
+            let offset: u64 = offset.parse()?;
+            let len = read_line(&mut reader).await?;
+            let len: usize = len
+                .strip_prefix('$')
+                .ok_or("expected the snapshot's length")?
+                .parse()?;
+            // Grown as it arrives, rather than trusting the length up front.
+            let mut snapshot = Vec::new();
+            (&mut reader)
+                .take(len as u64)
+                .read_to_end(&mut snapshot)
+                .await?;
+            if snapshot.len() != len {
+                return Err("the primary closed the connection mid-snapshot".into());
+            }
+            let loaded = task::block_in_place(|| dump_format::read_state(&snapshot, false))?;
+            {
+                let _order = store.write_order.write().await;
+                task::block_in_place(|| store.replace_with(&loaded));
+                replication.restart_stream();
+            }
+            info!(LOGGER, "Loaded a {} byte snapshot from the primary", len);
+            let replid = replid.to_string();
+            replication.update_link(generation, |link| {
+                link.replid = Some(replid);
+                link.offset = offset;
+            });
+        }
+        ["+CONTINUE", ..] => info!(LOGGER, "Continuing from where we were"),
+        _ => return Err(format!("unexpected reply to PSYNC: {}", reply).into()),
+    }
+    let linked = replication.update_link(generation, |link| {
+        link.up = true;
+        link.last_io = Some(Instant::now());
+    });
+    if !linked {
+        return Ok(());
+    }
+    info!(LOGGER, "Replicating from {}:{}", link.host, link.port);
+    let dump_file = store.dump_file.get().cloned().unwrap_or_default();
+    let mut commands = FramedRead::new(reader, RespParser::with_limits(RespLimits::unlimited()));
+    let mut acks = tokio::time::interval(ACK_INTERVAL);
+    loop {
+        tokio::select! {
+            command = commands.next() => match command {
+                Some(Ok(command)) => {
+                    let size = command.encoded_size() as u64;
+                    let getack = is_getack(&command);
+                    let mut state = selected.state(store);
+                    let reply = apply_replicated(
+                        &mut state,
+                        store.clone(),
+                        dump_file.clone(),
+                        scripting_bridge.clone(),
+                        command,
+                    )
+                    .await;
+                    selected.selected(store, state);
+                    if let RedisValueRef::Error(e) = &reply {
+                        debug!(LOGGER, "Replicated command failed: {:?}", e);
+                    }
+                    let linked = replication.update_link(generation, |link| {
+                        link.offset += size;
+                        link.last_io = Some(Instant::now());
+                    });
+                    if !linked {
+                        return Ok(());
+                    }
+                    if getack {
+                        let offset = replication.link(generation).map_or(0, |link| link.offset);
+                        write.write_all(&ack(offset)).await?;
+                    }
+                }
+                Some(Err(e)) => return Err(format!("bad command from the primary: {:?}", e).into()),
+                None => return Ok(()),
+            },
+            _ = acks.tick() => {
+                let link = match replication.link(generation) {
+                    Some(link) => link,
+                    None => return Ok(()),
+                };
+                write.write_all(&ack(link.offset)).await?;
+            }
+        }
+    }
+}
+
+/// Follow our primary until REPLICAOF picks another (or none), reconnecting
+/// whenever the link drops.
+async fn follow_primary(
+    store: StateStoreRef,
+    scripting_bridge: Arc<ScriptingBridge>,
+    generation: u64,
+) {
+    let mut selected = SelectedDb::new(&store);
+    while let Some(link) = store.replication.link(generation) {
+        match sync_with_primary(&store, &scripting_bridge, generation, &mut selected).await {
+            Ok(()) => info!(LOGGER, "Link to {}:{} closed", link.host, link.port),
+            Err(e) => warn!(
+                LOGGER,
+                "Replication from {}:{} failed: {}", link.host, link.port, e
+            ),
+        }
+        store
+            .replication
+            .update_link(generation, |link| link.up = false);
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Parse a primary given as `host:port`.
+pub fn parse_primary(primary: &str) -> Result<(String, u16), String> {
+    let (host, port) = primary
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected host:port, got {}", primary))?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("bad port in {}", primary))?;
+    Ok((host.to_string(), port))
+}
+
+/// Follow the primary at `host:port`, or stop following any with None.
+/// Returns false if we already follow that primary.
+pub fn replicaof(
+    store: &StateStoreRef,
+    scripting_bridge: &Arc<ScriptingBridge>,
+    primary: Option<(String, u16)>,
+) -> bool {
+    let replication = &store.replication;
+    let mut role = replication.role.lock();
+    let (host, port) = match (primary, &*role) {
+        (None, Role::Primary) => return false,
+        (None, Role::Replica(_)) => {
+            *role = Role::Primary;
+            drop(role);
+            // Our replicas followed our primary's writes, and now follow ours.
+            replication.restart_stream();
+            info!(LOGGER, "No longer a replica");
+            return true;
+        }
+        (Some((host, port)), Role::Replica(link)) if link.host == host && link.port == port => {
+            return false
+        }
+        (Some(primary), _) => primary,
+    };
+    let generation = replication.links.fetch_add(1, Ordering::SeqCst);
+    info!(LOGGER, "Replicating from {}:{}", host, port);
+    *role = Role::Replica(PrimaryLink {
+        host,
+        port,
+        up: false,
+        replid: None,
+        offset: 0,
+        last_io: None,
+        generation,
+    });
+    drop(role);
+    tokio::spawn(follow_primary(
+        store.clone(),
+        scripting_bridge.clone(),
+        generation,
+    ));
+    true
+}
+
+#[cfg(test)]
+mod test_replication {
+    use super::{parse_ack, parse_primary, replicaof, serve_replica, Backlog, PsyncRequest, Role};
+    use crate::asyncresp::RespParser;
+    use crate::monitor::Origin;
+    use crate::scripting::ScriptingBridge;
+    use crate::server::process_command;
+    use crate::test_util::command;
+    use crate::types::{RedisValueRef, StateStore};
+    use bytes::{Bytes, BytesMut};
+    use futures::StreamExt;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::channel;
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(4);
+        backlog.push(b"abc");
+        assert_eq!(backlog.since(1), Some(b"bc".to_vec()));
+        backlog.push(b"def");
+        assert_eq!(backlog.offset, 6);
+        assert_eq!(backlog.first_offset(), 2);
+        assert_eq!(backlog.since(1), None);
+        assert_eq!(backlog.since(2), Some(b"cdef".to_vec()));
+        assert_eq!(backlog.since(6), Some(Vec::new()));
+        assert_eq!(backlog.since(7), None);
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(
+            PsyncRequest::parse(&command(&["psync", "abc", "12"])),
+            Some(PsyncRequest {
+                replid: Some("abc".into()),
+                offset: Some(12),
+            })
+        );
+        let full = PsyncRequest {
+            replid: None,
+            offset: None,
+        };
+        assert_eq!(
+            PsyncRequest::parse(&command(&["PSYNC", "?", "-1"])),
+            Some(full.clone())
+        );
+        assert_eq!(PsyncRequest::parse(&command(&["sync"])), Some(full));
+        assert_eq!(PsyncRequest::parse(&command(&["get", "a"])), None);
+        assert_eq!(parse_ack(&command(&["REPLCONF", "ACK", "42"])), Some(42));
+        assert_eq!(
+            parse_ack(&command(&["replconf", "listening-port", "1"])),
+            None
+        );
+        assert_eq!(
+            parse_primary("localhost:6380"),
+            Ok(("localhost".into(), 6380))
+        );
+        assert!(parse_primary("localhost").is_err());
+    }
+
+    async fn run(store: &Arc<StateStore>, args: &[&'static str]) -> RedisValueRef {
+        let bridge = ScriptingBridge::new(channel(1).0);
+        let mut state = store.get_default();
+        process_command(
+            &mut state,
+            store.clone(),
+            Default::default(),
+            bridge,
+            command(args),
+            Origin::Script,
+        )
+        .await
+    }
+
+    async fn eventually(what: &str, check: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if check() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replicate() {
+        let primary = Arc::new(StateStore::default());
+        run(&primary, &["set", "before", "1"]).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let feeding = primary.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                let request = PsyncRequest {
+                    replid: None,
+                    offset: None,
+                };
+                let buffered = BytesMut::new();
+                tokio::spawn(serve_replica(
+                    socket,
+                    buffered,
+                    peer,
+                    feeding.clone(),
+                    request,
+                ));
+            }
+        });
+
+        let replica = Arc::new(StateStore::default());
+        let bridge = ScriptingBridge::new(channel(1).0);
+        assert!(replicaof(
+            &replica,
+            &bridge,
+            Some(("127.0.0.1".into(), port))
+        ));
+        assert!(!replicaof(
+            &replica,
+            &bridge,
+            Some(("127.0.0.1".into(), port))
+        ));
+        let db = replica.get_default();
+        eventually("the snapshot", || db.kv.contains_key(&b"before"[..])).await;
+        assert!(matches!(replica.replication.role(), Role::Replica(link) if link.up));
+
+        run(&primary, &["set", "after", "2"]).await;
+        run(&primary, &["sadd", "set", "a", "b"]).await;
+        let db = replica.get_default();
+        eventually("the writes", || db.sets.contains_key(&b"set"[..])).await;
+        assert!(db.kv.contains_key(&b"after"[..]));
+        eventually("the ack", || {
+            primary.replication.replicas().first().map(|r| r.acked)
+                == Some(primary.replication.offset())
+        })
+        .await;
+
+        // WAIT returns once the replica has acked the latest write.
+        run(&primary, &["set", "waited", "3"]).await;
+        assert_eq!(
+            run(&primary, &["wait", "1", "5000"]).await,
+            RedisValueRef::Int(1)
+        );
+
+        // Replicas are read only, until they're promoted.
+        assert!(matches!(
+            run(&replica, &["set", "a", "b"]).await,
+            RedisValueRef::ErrorMsg(_)
+        ));
+        assert!(replicaof(&replica, &bridge, None));
+        assert_eq!(replica.replication.role(), Role::Primary);
+        assert_eq!(
+            run(&replica, &["set", "a", "b"]).await,
+            RedisValueRef::SimpleString("OK".into())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_masterauth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let replica = Arc::new(StateStore::default());
+        run(&replica, &["config", "set", "masterauth", "secret"]).await;
+        let bridge = ScriptingBridge::new(channel(1).0);
+        assert!(replicaof(
+            &replica,
+            &bridge,
+            Some(("127.0.0.1".into(), port))
+        ));
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut primary = RespParser::default().framed(socket);
+        let auth = primary.next().await.unwrap().unwrap();
+        assert_eq!(auth, command(&["AUTH", "secret"]));
+        primary.get_mut().write_all(b"+OK\r\n").await.unwrap();
+        let psync = primary.next().await.unwrap().unwrap();
+        assert_eq!(psync, command(&["PSYNC", "?", "-1"]));
+        assert!(replicaof(&replica, &bridge, None));
+    }
+}
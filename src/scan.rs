@@ -0,0 +1,253 @@
+//! Cursor based iteration for SCAN, HSCAN, SSCAN and ZSCAN.
+//!
+//! SCAN cursors count DashMap shards across every structure, and each call
+//! returns whole shards until COUNT keys were looked at. A shard is read in
+//! one go, so keys present for the whole scan are always returned, however
+//! the maps grow in between.
+//!
+//! Collections aren't sharded, so HSCAN/SSCAN/ZSCAN order members by a hash
+//! of their name and use the hash as the cursor. That's stable across
+//! inserts and removals too, at the cost of walking the collection per call.
+
+use crate::for_each_structure;
+use crate::glob::glob_match;
+use crate::hashing::internal_hash;
+use crate::types::{Key, ReturnValue, State, Value};
+use dashmap::DashMap;
+
+/// Keys looked at per call unless COUNT says otherwise.
+pub const DEFAULT_COUNT: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    pub pattern: Option<Value>,
+    pub count: usize,
+    /// Only return keys of this type (SCAN only).
+    pub type_name: Option<Value>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            pattern: None,
+            count: DEFAULT_COUNT,
+            type_name: None,
+        }
+    }
+}
+
+impl ScanOptions {
+    fn matches(&self, name: &[u8]) -> bool {
+        self.pattern
+            .as_ref()
+            .map_or(true, |pattern| glob_match(pattern, name))
+    }
+}
+
+/// A map of keys, readable one shard at a time.
+trait KeyShards {
+    fn num_shards(&self) -> usize;
+    fn shard_keys(&self, shard: usize) -> Vec<Key>;
+}
+
+impl<V> KeyShards for DashMap<Key, V> {
+    fn num_shards(&self) -> usize {
+        self.shards().len()
+    }
+
+    fn shard_keys(&self, shard: usize) -> Vec<Key> {
+        self.shards()[shard].read().keys().cloned().collect()
+    }
+}
+
+/// The name SCAN's TYPE option takes for `structure`. Structures without one
+/// of their own go by their field name.
+fn scan_type(structure: &'static str) -> &'static str {
+    match structure {
+        "kv" => "string",
+        "sets" => "set",
+        "lists" => "list",
+        "hashes" => "hash",
+        "zsets" => "zset",
+        "blooms" => "bloom",
+        "stacks" => "stack",
+        "hyperloglogs" => "hyperloglog",
+        "streams" => "stream",
+        "cuckoos" => "cuckoo",
+        "count_mins" => "cms",
+        "topks" => "topk",
+        "queues" => "queue",
+        other => other,
+    }
+}
+
+/// Pair each passed structure with its SCAN type name.
+macro_rules! scan_structures {
+    ($state:expr, $($type:ident: $schema:expr),*) => {
+        vec![$((scan_type(stringify!($type)), &$state.$type as &dyn KeyShards)),*]
+    };
+}
+
+/// Every structure with the type name SCAN's TYPE option takes, in scan order.
+fn structures(state: &State) -> Vec<(&'static str, &dyn KeyShards)> {
+    for_each_structure!(scan_structures!(state,))
+}
+
+/// Scan keys starting at `cursor`. Returns the next cursor (0 once done) and the matching keys.
+pub fn scan_keys(state: &State, cursor: u64, opts: &ScanOptions) -> (u64, Vec<Key>) {
+    let mut cursor = cursor;
+    let mut keys = Vec::new();
+    let mut scanned = 0;
+    // Cursor of the first shard of the current structure.
+    let mut first_shard = 0;
+    for (type_name, map) in structures(state).iter() {
+        let end = first_shard + map.num_shards() as u64;
+        let wanted = opts
+            .type_name
+            .as_ref()
+            .map_or(true, |t| t.eq_ignore_ascii_case(type_name.as_bytes()));
+        if !wanted {
+            cursor = cursor.max(end);
+        }
+        while cursor < end {
+            if scanned >= opts.count {
+                return (cursor, keys);
+            }
+            let shard_keys = map.shard_keys((cursor - first_shard) as usize);
+            scanned += shard_keys.len();
+            keys.extend(shard_keys.into_iter().filter(|key| opts.matches(key)));
+            cursor += 1;
+        }
+        first_shard = end;
+    }
+    (0, keys)
+}
+
+/// Where a member sits in the order collection scans walk. Never 0, which starts (and ends) a scan.
+fn member_position(member: &[u8]) -> u64 {
+    (internal_hash(member) >> 1) + 1
+}
+
+/// One page of a collection scan: up to `count` of the items positioned at or
+/// after `cursor`, and the next cursor. Items sharing a position may repeat.
+pub fn scan_members<T, F>(
+    items: impl Iterator<Item = T>,
+    name: F,
+    cursor: u64,
+    opts: &ScanOptions,
+) -> (u64, Vec<T>)
+where
+    F: Fn(&T) -> &[u8],
+{
+    let mut page: Vec<(u64, T)> = items
+        .map(|item| (member_position(name(&item)), item))
+        .filter(|(position, _)| *position >= cursor)
+        .collect();
+    let mut next = 0;
+    if page.len() > opts.count {
+        page.select_nth_unstable_by_key(opts.count, |(position, _)| *position);
+        next = page[opts.count].0;
+        page.truncate(opts.count);
+    }
+    let items = page
+        .into_iter()
+        .map(|(_, item)| item)
+        .filter(|item| opts.matches(name(item)))
+        .collect();
+    (next, items)
+}
+
+/// The `[cursor, [elements]]` reply of the SCAN family.
+pub fn scan_reply(cursor: u64, elements: Vec<Value>) -> ReturnValue {
+    ReturnValue::Array(vec![
+        ReturnValue::StringRes(cursor.to_string().into()),
+        ReturnValue::MultiStringRes(elements),
+    ])
+}
+
+#[cfg(test)]
+mod test_scan {
+    use super::{scan_keys, scan_members, ScanOptions};
+    use crate::types::State;
+    use bytes::Bytes;
+    use std::collections::HashSet;
+
+    fn opts(count: usize) -> ScanOptions {
+        ScanOptions {
+            count,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_scan_keys_visits_everything_once() {
+        let state = State::default();
+        for i in 0..1000 {
+            state.kv.insert(format!("k{}", i).into(), Bytes::new());
+        }
+        state
+            .lists
+            .insert(Bytes::from_static(b"list"), Default::default());
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = scan_keys(&state, cursor, &opts(20));
+            for key in keys {
+                assert!(seen.insert(key), "key returned twice");
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(seen.len(), 1001);
+    }
+
+    #[test]
+    fn test_scan_keys_filters() {
+        let state = State::default();
+        state.kv.insert(Bytes::from_static(b"user:1"), Bytes::new());
+        state
+            .kv
+            .insert(Bytes::from_static(b"session:1"), Bytes::new());
+        state
+            .sets
+            .insert(Bytes::from_static(b"user:2"), Default::default());
+        let all = ScanOptions {
+            count: usize::MAX,
+            pattern: Some(Bytes::from_static(b"user:*")),
+            type_name: None,
+        };
+        let (next, mut keys) = scan_keys(&state, 0, &all);
+        keys.sort();
+        assert_eq!(next, 0);
+        assert_eq!(keys, vec!["user:1", "user:2"]);
+        let sets_only = ScanOptions {
+            type_name: Some(Bytes::from_static(b"SET")),
+            ..all
+        };
+        assert_eq!(scan_keys(&state, 0, &sets_only).1, vec!["user:2"]);
+    }
+
+    #[test]
+    fn test_scan_members_survives_changes() {
+        let mut members: HashSet<Bytes> = (0..100).map(|i| format!("m{}", i).into()).collect();
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut round = 0;
+        loop {
+            let (next, page) = scan_members(members.iter().cloned(), |m| &m[..], cursor, &opts(7));
+            seen.extend(page);
+            // Members added mid-scan may or may not be seen, but the originals must be.
+            members.insert(format!("new{}", round).into());
+            round += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in 0..100 {
+            assert!(seen.contains(format!("m{}", i).as_bytes()));
+        }
+    }
+}
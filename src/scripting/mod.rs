@@ -0,0 +1,834 @@
+//! Server-side scripting.
+//!
+//! Programs are sent over a channel to the scripting thread, which hands
+//! them to a `ScriptingBackend` (x7 lisp with the default "scripting" feature,
+//! or WASM with the "wasm" feature).
+//! Backends issue redis commands through a `RedisCaller`, which sends them
+//! back to the tokio runtime to be ran like any other command, or with
+//! --script-read-your-writes runs them on the program's own thread.
+//!
+//! If no engine starts (or none was compiled in), the server runs without
+//! one and scripting commands reply "scripting disabled".
+//!
+//! SCRIPT KILL answers a program's client straight away, and fails the
+//! redis calls the program makes from then on. Backends that can abort a
+//! program (WASM, through epoch interruption) do so as well. x7 has no hook
+//! into its evaluator, so an x7 program busy computing keeps its thread
+//! until it's done or next calls redis.
+//! Like redis, a program that has ran a write command can't be killed, so
+//! its writes are never left half applied.
+//!
+//! Functions defined with `def-redis-fn` are kept in a `FunctionLibrary`,
+//! saved next to the dump file, and defined again when the engine starts.
+
+use crate::hashing::sha1_hex;
+use crate::monitor::Origin;
+use crate::server::{is_write, process_command};
+use parking_lot::{Mutex, RwLock};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+use std::{error::Error, sync::Arc};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::Notify;
+
+use crate::escape::Escaped;
+use crate::startup::Config;
+use crate::types::DumpFile;
+use crate::types::RedisValueRef;
+use crate::{
+    logger::LOGGER,
+    types::{StateRef, StateStoreRef},
+};
+
+#[cfg(feature = "wasm")]
+mod wasm_backend;
+#[cfg(feature = "scripting")]
+mod x7_backend;
+
+/// Result of running a program.
+pub type ScriptResult = Result<RedisValueRef, Box<dyn Error + Send>>;
+
+/// Reply to a client whose program was killed.
+pub const KILLED: &str = "ERR Script killed by user with SCRIPT KILL";
+
+/// Reply to SCRIPT KILL once a program has written.
+pub const UNKILLABLE: &[u8] = b"UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.";
+
+/// States of a running program.
+const SCRIPT_RUNNING: u8 = 0;
+/// The program ran a write command, so it can't be killed.
+const SCRIPT_WROTE: u8 = 1;
+const SCRIPT_KILLED: u8 = 2;
+/// SCRIPT KILL is deciding whether to kill the program. Its redis calls
+/// wait until it's killed or running again.
+const SCRIPT_KILLING: u8 = 3;
+
+/// SCRIPT KILL refused, as a program has written.
+#[derive(Debug, PartialEq)]
+pub struct Unkillable;
+
+/// Where the result of a program is sent.
+pub struct ScriptReturn {
+    sender: OneShotSender<ScriptResult>,
+    status: Arc<AtomicU8>,
+}
+
+impl ScriptReturn {
+    /// Mark the current thread as running this program, so its redis calls
+    /// fail once it's killed. Backends call this on the program's thread.
+    pub fn enter(&self) {
+        PROGRAM_STATUS.with(|status| *status.borrow_mut() = Some(self.status.clone()));
+    }
+
+    /// Whether the program was killed.
+    pub fn killed(&self) -> bool {
+        self.status.load(Ordering::SeqCst) == SCRIPT_KILLED
+    }
+
+    pub fn send(self, result: ScriptResult) -> Result<(), ScriptResult> {
+        self.sender.send(result)
+    }
+}
+
+/// A scripting language.
+///
+/// Backends are driven from the scripting thread, so `run` should hand
+/// anything long running off to another thread.
+pub trait ScriptingBackend: Send {
+    fn name(&self) -> &'static str;
+    /// Load the user's scripts at startup.
+    fn load_scripts_dir(&self, dir: &Path) -> Result<(), Box<dyn Error>>;
+    /// Run `program`, sending the result down `return_channel`.
+    fn run(&self, program: Program, return_channel: ScriptReturn);
+    /// Define a function from the library again, at startup.
+    fn load_function(&self, _source: &str) -> Result<(), Box<dyn Error>> {
+        Err(format!("the {} engine has no def-redis-fn", self.name()).into())
+    }
+    /// Something aborting every running program, for SCRIPT KILL. Without
+    /// one, programs only stop at their next redis call.
+    fn interrupt_handle(&self) -> Option<Interrupt> {
+        None
+    }
+}
+
+/// Aborts every running program of a backend.
+pub type Interrupt = Box<dyn Fn() + Send + Sync>;
+
+/// Lets a script run redis commands. Calls block the script's thread.
+#[derive(Clone)]
+pub struct RedisCaller {
+    route: CallRoute,
+}
+
+#[derive(Clone)]
+enum CallRoute {
+    /// Send commands to `handle_redis_cmd`, which runs them for every program.
+    Channel(Arc<Sender<(Vec<RedisValueRef>, OneShotSender<RedisValueRef>)>>),
+    /// Run commands on the program's own thread.
+    Direct(Arc<DirectCaller>),
+}
+
+thread_local! {
+    /// Db selected by the program running on this thread. Every program
+    /// gets its own thread, so this is per program.
+    static PROGRAM_STATE: RefCell<Option<StateRef>> = RefCell::new(None);
+    /// Whether the program running on this thread has written or was killed.
+    static PROGRAM_STATUS: RefCell<Option<Arc<AtomicU8>>> = RefCell::new(None);
+}
+
+/// Runs script commands synchronously, so a program always reads its own
+/// writes and SELECTs made by other programs can't leak into it.
+struct DirectCaller {
+    runtime: Handle,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+}
+
+impl DirectCaller {
+    fn call(&self, args: Vec<RedisValueRef>) -> RedisValueRef {
+        let mut state = PROGRAM_STATE
+            .with(|s| s.borrow_mut().take())
+            .unwrap_or_else(|| self.state_store.get_default());
+        let res = self.runtime.block_on(process_command(
+            &mut state,
+            self.state_store.clone(),
+            self.dump_file.clone(),
+            self.scripting_bridge.clone(),
+            RedisValueRef::Array(args),
+            Origin::Script,
+        ));
+        PROGRAM_STATE.with(|s| *s.borrow_mut() = Some(state));
+        res
+    }
+}
+
+impl RedisCaller {
+    pub fn call(&self, args: Vec<RedisValueRef>) -> Result<RedisValueRef, String> {
+        if let Some(status) = PROGRAM_STATUS.with(|status| status.borrow().clone()) {
+            // Whichever of SCRIPT KILL and the first write comes first wins.
+            let next = if writes(&args) {
+                SCRIPT_WROTE
+            } else {
+                SCRIPT_RUNNING
+            };
+            loop {
+                let res = status.compare_exchange(
+                    SCRIPT_RUNNING,
+                    next,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+                match res {
+                    Err(SCRIPT_KILLING) => std::thread::yield_now(),
+                    Err(SCRIPT_KILLED) => return Err(KILLED.into()),
+                    _ => break,
+                }
+            }
+        }
+        let cmd_send = match &self.route {
+            CallRoute::Channel(cmd_send) => cmd_send,
+            CallRoute::Direct(direct) => return Ok(direct.call(args)),
+        };
+        let (sx, mut rx) = oneshot_channel();
+        if let Err(e) = cmd_send.blocking_send((args, sx)) {
+            return Err(format!("redis-oxide failed to send the command: {}", e));
+        }
+        loop {
+            match rx.try_recv() {
+                Ok(ret_value) => return Ok(ret_value),
+                Err(TryRecvError::Empty) => continue,
+                Err(TryRecvError::Closed) => {
+                    return Err("redix-oxide failed to return a value!".into())
+                }
+            }
+        }
+    }
+}
+
+/// Whether a script's redis call is a write command.
+fn writes(args: &[RedisValueRef]) -> bool {
+    match args.first() {
+        Some(RedisValueRef::BulkString(name)) | Some(RedisValueRef::SimpleString(name)) => {
+            is_write(&String::from_utf8_lossy(name).to_lowercase())
+        }
+        _ => false,
+    }
+}
+
+/// A program a client is waiting on.
+struct RunningScript {
+    started: Instant,
+    status: Arc<AtomicU8>,
+    kill: Arc<Notify>,
+}
+
+pub struct ScriptingBridge {
+    prog_send: Sender<(Program, ScriptReturn)>,
+    running: Mutex<HashMap<u64, RunningScript>>,
+    next_id: AtomicU64,
+    /// Set by the engine once it's started, if its backend has one.
+    interrupt: RwLock<Option<Interrupt>>,
+}
+
+/// Forgets a program once its client has its reply.
+struct Running<'a> {
+    bridge: &'a ScriptingBridge,
+    id: u64,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.bridge.running.lock().remove(&self.id);
+    }
+}
+
+impl ScriptingBridge {
+    pub fn new(prog_send: Sender<(Program, ScriptReturn)>) -> Arc<Self> {
+        let sb = Self {
+            prog_send,
+            running: Mutex::default(),
+            next_id: AtomicU64::new(0),
+            interrupt: RwLock::new(None),
+        };
+        Arc::new(sb)
+    }
+
+    pub async fn handle_script_cmd(&self, cmd: Program) -> RedisValueRef {
+        let (sx, rx) = oneshot_channel();
+        let (status, kill) = (
+            Arc::new(AtomicU8::new(SCRIPT_RUNNING)),
+            Arc::new(Notify::new()),
+        );
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let script = RunningScript {
+            started: Instant::now(),
+            status: status.clone(),
+            kill: kill.clone(),
+        };
+        self.running.lock().insert(id, script);
+        let _running = Running { bridge: self, id };
+        let ret = ScriptReturn { sender: sx, status };
+        if let Err(e) = self.prog_send.send((cmd, ret)).await {
+            error!(LOGGER, "Failed to send program: {}", e);
+        }
+        let script_result = tokio::select! {
+            script_result = rx => script_result,
+            _ = kill.notified() => return RedisValueRef::Error(KILLED.into()),
+        };
+        match script_result {
+            Ok(script_result) => match script_result {
+                Ok(r) => r,
+                Err(e) => RedisValueRef::Error(format!("{}", e).into()),
+            },
+            Err(e) => RedisValueRef::Error(
+                format!("Failed to receive a response from scripting {}", e).into(),
+            ),
+        }
+    }
+
+    /// Kill every running program (SCRIPT KILL). Returns how many there were,
+    /// or `Unkillable` if one has written, in which case none are killed.
+    pub fn kill_all(&self) -> Result<usize, Unkillable> {
+        let running = self.running.lock();
+        // Claim every program before killing any, so none can write
+        // meanwhile. If one already has, the claimed ones go back to
+        // running: their redis calls waited rather than failing.
+        let mut claimed = Vec::with_capacity(running.len());
+        let mut unkillable = false;
+        for script in running.values() {
+            let res = script.status.compare_exchange(
+                SCRIPT_RUNNING,
+                SCRIPT_KILLING,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            match res {
+                Ok(_) => claimed.push(script),
+                Err(SCRIPT_WROTE) => {
+                    unkillable = true;
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+        let settled = if unkillable {
+            SCRIPT_RUNNING
+        } else {
+            SCRIPT_KILLED
+        };
+        for script in claimed.iter() {
+            script.status.store(settled, Ordering::SeqCst);
+        }
+        if unkillable {
+            return Err(Unkillable);
+        }
+        for script in claimed.iter() {
+            script.kill.notify_one();
+        }
+        // No program left can write, so interrupting the backend only
+        // aborts killed ones.
+        if !claimed.is_empty() {
+            if let Some(interrupt) = &*self.interrupt.read() {
+                interrupt();
+            }
+        }
+        Ok(claimed.len())
+    }
+
+    fn set_interrupt(&self, interrupt: Option<Interrupt>) {
+        *self.interrupt.write() = interrupt;
+    }
+
+    /// Whether a program has been running for longer than `limit`.
+    pub fn busy(&self, limit: Duration) -> bool {
+        self.running
+            .lock()
+            .values()
+            .any(|script| script.started.elapsed() > limit)
+    }
+}
+
+use tokio::sync::oneshot::{channel as oneshot_channel, Sender as OneShotSender};
+
+use tokio::sync::oneshot::error::TryRecvError;
+pub async fn handle_redis_cmd(
+    mut cmd_recv: Receiver<(Vec<RedisValueRef>, OneShotSender<RedisValueRef>)>,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_engine: Arc<ScriptingBridge>,
+) {
+    // TODO: Support, or return an error when interacting with
+    // change db commands
+    let mut state = state_store.get_default();
+    while let Some((cmd, return_channel)) = cmd_recv.recv().await {
+        debug!(LOGGER, "Recieved redis command: {:?}", cmd);
+        let res = process_command(
+            &mut state,
+            state_store.clone(),
+            dump_file.clone(),
+            scripting_engine.clone(),
+            RedisValueRef::Array(cmd),
+            Origin::Script,
+        )
+        .await;
+        if let Err(e) = return_channel.send(res) {
+            error!(LOGGER, "Failed to write response! {:?}", e);
+        }
+    }
+}
+
+pub enum Program {
+    String(String),
+    Function(String, Vec<RedisValueRef>),
+    /// An EVAL program, with its KEYS and ARGV.
+    Eval(String, Vec<RedisValueRef>, Vec<RedisValueRef>),
+}
+
+impl std::fmt::Debug for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Program::String(s) => write!(f, "Program::String({})", Escaped(s.as_bytes())),
+            Program::Function(name, args) => write!(
+                f,
+                "Program::Function({}, {:?})",
+                Escaped(name.as_bytes()),
+                args
+            ),
+            Program::Eval(s, keys, argv) => write!(
+                f,
+                "Program::Eval({}, {:?}, {:?})",
+                Escaped(s.as_bytes()),
+                keys,
+                argv
+            ),
+        }
+    }
+}
+
+struct CachedScript {
+    source: String,
+    compiled: bool,
+}
+
+/// Cache of programs ran through SCRIPT or EVAL (or loaded by SCRIPT LOAD),
+/// keyed by the SHA1 of their source.
+///
+/// Programs are compiled once into an interpreter function, and later runs
+/// just call that function instead of re-parsing the source. SCRIPT FLUSH
+/// leaves the backend to undefine the functions of the programs it removed,
+/// before it runs the next program.
+#[derive(Default)]
+pub struct ScriptCache {
+    scripts: RwLock<HashMap<String, CachedScript>>,
+    /// SHA1s of compiled programs flushed since the backend last looked.
+    flushed: Mutex<Vec<String>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ScriptCache {
+    /// Add a program to the cache, returning its SHA1.
+    pub fn insert(&self, source: String) -> String {
+        let sha = sha1_hex(source.as_bytes());
+        self.scripts
+            .write()
+            .entry(sha.clone())
+            .or_insert(CachedScript {
+                source,
+                compiled: false,
+            });
+        sha
+    }
+
+    /// Get the source of a cached program.
+    pub fn get(&self, sha: &str) -> Option<String> {
+        self.scripts.read().get(sha).map(|s| s.source.clone())
+    }
+
+    pub fn contains(&self, sha: &str) -> bool {
+        self.scripts.read().contains_key(sha)
+    }
+
+    /// Remove every cached program. Returns how many were removed.
+    pub fn flush(&self) -> usize {
+        let mut scripts = self.scripts.write();
+        let len = scripts.len();
+        let compiled = scripts.drain().filter(|(_, script)| script.compiled);
+        self.flushed.lock().extend(compiled.map(|(sha, _)| sha));
+        len
+    }
+
+    /// SHA1s of the compiled programs flushed since the last call, whose
+    /// functions should be undefined.
+    fn take_flushed(&self) -> Vec<String> {
+        std::mem::take(&mut *self.flushed.lock())
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn is_compiled(&self, sha: &str) -> bool {
+        self.scripts.read().get(sha).map_or(false, |s| s.compiled)
+    }
+
+    fn mark_compiled(&self, sha: &str, source: String) {
+        self.scripts
+            .write()
+            .entry(sha.to_string())
+            .or_insert(CachedScript {
+                source,
+                compiled: true,
+            })
+            .compiled = true;
+    }
+}
+
+/// File the function library is saved in, next to the dump file.
+pub const FUNCTIONS_FILE: &str = "functions.rodb";
+
+/// Functions defined with `def-redis-fn`, by name, with the source that
+/// defined them (FUNCTION LIST, DELETE and DUMP).
+///
+/// The library is saved whenever it changes, and its functions are defined
+/// again at startup. It only records once opened: functions from the scripts
+/// dir are loaded before that, as the dir already has their source.
+#[derive(Default)]
+pub struct FunctionLibrary {
+    sources: RwLock<BTreeMap<String, String>>,
+    /// Where the library is saved, None in memory-only mode.
+    path: RwLock<Option<PathBuf>>,
+    open: AtomicBool,
+}
+
+impl FunctionLibrary {
+    /// Start recording definitions, reading the library saved at `path`.
+    /// Returns the saved sources, which should be run to define them again.
+    pub fn open(&self, path: Option<PathBuf>) -> Result<Vec<String>, Box<dyn Error>> {
+        let saved: BTreeMap<String, String> = match &path {
+            Some(path) if path.exists() => rmps::from_read(std::fs::File::open(path)?)?,
+            _ => BTreeMap::new(),
+        };
+        let sources = saved.values().cloned().collect();
+        *self.sources.write() = saved;
+        *self.path.write() = path;
+        self.open.store(true, Ordering::SeqCst);
+        Ok(sources)
+    }
+
+    /// Record that `name` was defined by `source`.
+    pub fn define(&self, name: &str, source: String) {
+        if !self.open.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut sources = self.sources.write();
+        if sources.get(name) == Some(&source) {
+            return;
+        }
+        sources.insert(name.to_string(), source);
+        self.save(&sources);
+    }
+
+    /// Forget `name`, so it isn't defined at the next startup. Returns false
+    /// if it wasn't in the library.
+    pub fn delete(&self, name: &str) -> bool {
+        let mut sources = self.sources.write();
+        let deleted = sources.remove(name).is_some();
+        if deleted {
+            self.save(&sources);
+        }
+        deleted
+    }
+
+    /// Source of the function `name`.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.sources.read().get(name).cloned()
+    }
+
+    /// Every function's source as one program, which defines them all again
+    /// when ran.
+    pub fn dump(&self) -> String {
+        let sources = self.sources.read();
+        sources.values().cloned().collect::<Vec<_>>().join("\n")
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the library to a temporary file and rename it over the last
+    /// one, like dumps. Failures are logged: the functions are still defined.
+    fn save(&self, sources: &BTreeMap<String, String>) {
+        let path = match &*self.path.read() {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let write = || -> Result<(), Box<dyn Error>> {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, rmps::to_vec(sources)?)?;
+            std::fs::rename(&tmp, &path)?;
+            Ok(())
+        };
+        if let Err(e) = write() {
+            error!(
+                LOGGER,
+                "Failed to save the function library to {:?}: {}", path, e
+            );
+        }
+    }
+}
+
+/// Name of the interpreter function a cached program is compiled into.
+fn script_fn_name(sha: &str) -> String {
+    format!("script--{}", sha)
+}
+
+pub struct ScriptingEngine {
+    backend: Box<dyn ScriptingBackend>,
+    prog_revc: Receiver<(Program, ScriptReturn)>,
+}
+
+impl ScriptingEngine {
+    /// Must be called from within the tokio runtime.
+    #[allow(clippy::type_complexity)]
+    pub fn new(
+        prog_revc: Receiver<(Program, ScriptReturn)>,
+        cmd_send: Sender<(Vec<RedisValueRef>, OneShotSender<RedisValueRef>)>,
+        state_store: StateStoreRef,
+        dump_file: DumpFile,
+        scripting_bridge: Arc<ScriptingBridge>,
+        opts: &Config,
+    ) -> Result<Self, Box<dyn Error>> {
+        let functions_path = if state_store.memory_only {
+            None
+        } else {
+            Some(dump_file.lock().with_file_name(FUNCTIONS_FILE))
+        };
+        let route = if opts.script_read_your_writes {
+            CallRoute::Direct(Arc::new(DirectCaller {
+                runtime: Handle::current(),
+                state_store: state_store.clone(),
+                dump_file,
+                scripting_bridge: scripting_bridge.clone(),
+            }))
+        } else {
+            CallRoute::Channel(Arc::new(cmd_send))
+        };
+        let caller = RedisCaller { route };
+        let backend: Box<dyn ScriptingBackend> =
+            match opts.scripting_engine.as_str() {
+                #[cfg(feature = "scripting")]
+                "x7" => Box::new(x7_backend::X7Backend::new(caller, state_store.clone())),
+                #[cfg(not(feature = "scripting"))]
+                "x7" => return Err(
+                    "redis-oxide was built without x7 scripting (enable the \"scripting\" feature)"
+                        .into(),
+                ),
+                #[cfg(feature = "wasm")]
+                "wasm" => Box::new(wasm_backend::WasmBackend::new(caller, state_store.clone())?),
+                #[cfg(not(feature = "wasm"))]
+                "wasm" => return Err(
+                    "redis-oxide was built without WASM scripting (enable the \"wasm\" feature)"
+                        .into(),
+                ),
+                other => return Err(format!("Unknown scripting engine {}", other).into()),
+            };
+        info!(LOGGER, "Using the {} scripting engine", backend.name());
+        scripting_bridge.set_interrupt(backend.interrupt_handle());
+        let res = Self { backend, prog_revc };
+        res.load_scripts_dir(opts)?;
+        res.load_functions(&state_store, functions_path)?;
+        Ok(res)
+    }
+
+    pub fn main_loop(mut self) {
+        while let Some((program, return_channel)) = self.prog_revc.blocking_recv() {
+            debug!(LOGGER, "Recieved this program: {:?}", program);
+            self.backend.run(program, return_channel);
+        }
+    }
+
+    /// Open the function library and define its functions again. One that
+    /// fails to load is logged and kept, so FUNCTION DELETE can drop it.
+    fn load_functions(
+        &self,
+        state_store: &StateStoreRef,
+        path: Option<PathBuf>,
+    ) -> Result<(), Box<dyn Error>> {
+        let sources = state_store.functions.open(path)?;
+        for source in sources {
+            if let Err(e) = self.backend.load_function(&source) {
+                error!(LOGGER, "Failed to define saved function {}: {}", source, e);
+            }
+        }
+        if !state_store.functions.is_empty() {
+            info!(LOGGER, "Loaded {} functions", state_store.functions.len());
+        }
+        Ok(())
+    }
+
+    fn load_scripts_dir(&self, opts: &Config) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &opts.scripts_dir {
+            info!(LOGGER, "Loading scripts in {:?}", path);
+            self.backend.load_scripts_dir(path)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_scripting {
+    use super::{
+        CallRoute, DirectCaller, Program, RedisCaller, ScriptCache, ScriptingBridge, Unkillable,
+        KILLED,
+    };
+    use crate::test_util::command_args;
+    use crate::types::{RedisValueRef, StateStore};
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::channel;
+
+    #[test]
+    fn test_read_your_writes() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let caller = RedisCaller {
+            route: CallRoute::Direct(Arc::new(DirectCaller {
+                runtime: runtime.handle().clone(),
+                state_store: Arc::new(StateStore::default()),
+                dump_file: Arc::new(Mutex::new(PathBuf::new())),
+                scripting_bridge: ScriptingBridge::new(channel(1).0),
+            })),
+        };
+        let call =
+            |caller: &RedisCaller, args: &[&'static str]| caller.call(command_args(args)).unwrap();
+        let value = |v: &'static str| RedisValueRef::BulkString(Bytes::from_static(v.as_bytes()));
+        // Each call sees the ones before it, with nothing in between.
+        call(&caller, &["set", "a", "1"]);
+        assert_eq!(call(&caller, &["incr", "a"]), RedisValueRef::Int(2));
+        assert_eq!(call(&caller, &["get", "a"]), value("2"));
+        // A SELECT only applies to the program that made it.
+        call(&caller, &["select", "1"]);
+        assert_eq!(call(&caller, &["get", "a"]), RedisValueRef::NullBulkString);
+        let other = caller.clone();
+        let seen = std::thread::spawn(move || call(&other, &["get", "a"]))
+            .join()
+            .unwrap();
+        assert_eq!(seen, value("2"));
+    }
+
+    #[test]
+    fn test_flush_undefines_compiled_programs() {
+        let cache = ScriptCache::default();
+        let loaded = cache.insert("(+ 1 1)".into());
+        let compiled = cache.insert("(+ 2 2)".into());
+        cache.mark_compiled(&compiled, "(+ 2 2)".into());
+        assert_eq!(cache.flush(), 2);
+        assert!(!cache.contains(&loaded) && !cache.is_compiled(&compiled));
+        // Only the compiled one has a function to undefine, and only once.
+        assert_eq!(cache.take_flushed(), vec![compiled]);
+        assert!(cache.take_flushed().is_empty());
+    }
+
+    #[test]
+    fn test_no_kill_after_writes() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (prog_sx, mut prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let caller = RedisCaller {
+            route: CallRoute::Direct(Arc::new(DirectCaller {
+                runtime: runtime.handle().clone(),
+                state_store: Arc::new(StateStore::default()),
+                dump_file: Arc::new(Mutex::new(PathBuf::new())),
+                scripting_bridge: bridge.clone(),
+            })),
+        };
+        let start = |bridge: &Arc<ScriptingBridge>| {
+            let bridge = bridge.clone();
+            runtime.spawn(async move {
+                bridge
+                    .handle_script_cmd(Program::String("(+ 1 1)".into()))
+                    .await
+            })
+        };
+        // Reads don't stop a program from being killed...
+        let running = start(&bridge);
+        let (_, ret) = prog_rx.blocking_recv().unwrap();
+        let program = caller.clone();
+        let ret = std::thread::spawn(move || {
+            ret.enter();
+            program.call(command_args(&["GET", "a"])).unwrap();
+            ret
+        })
+        .join()
+        .unwrap();
+        assert_eq!(bridge.kill_all(), Ok(1));
+        assert_eq!(
+            runtime.block_on(running).unwrap(),
+            RedisValueRef::Error(KILLED.into())
+        );
+        let program = caller.clone();
+        let call = std::thread::spawn(move || {
+            ret.enter();
+            program.call(command_args(&["SET", "a", "1"]))
+        });
+        assert_eq!(call.join().unwrap(), Err(KILLED.to_string()));
+
+        // ...writes do, and then programs which haven't written live on too.
+        let running = start(&bridge);
+        let (_, ret) = prog_rx.blocking_recv().unwrap();
+        let reading = start(&bridge);
+        let (_, reader) = prog_rx.blocking_recv().unwrap();
+        let program = caller.clone();
+        let ret = std::thread::spawn(move || {
+            ret.enter();
+            program.call(command_args(&["SET", "a", "1"])).unwrap();
+            ret
+        })
+        .join()
+        .unwrap();
+        assert_eq!(bridge.kill_all(), Err(Unkillable));
+        let reader = std::thread::spawn(move || {
+            reader.enter();
+            caller.call(command_args(&["GET", "a"])).unwrap();
+            reader
+        })
+        .join()
+        .unwrap();
+        assert!(!reader.killed());
+        reader.send(Ok(RedisValueRef::Int(2))).unwrap();
+        assert_eq!(runtime.block_on(reading).unwrap(), RedisValueRef::Int(2));
+        ret.send(Ok(RedisValueRef::Int(1))).unwrap();
+        assert_eq!(runtime.block_on(running).unwrap(), RedisValueRef::Int(1));
+        assert_eq!(bridge.kill_all(), Ok(0));
+    }
+}
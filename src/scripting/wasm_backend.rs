@@ -0,0 +1,432 @@
+//! The WebAssembly scripting backend (enabled by the "wasm" feature).
+//!
+//! Values cross the host/guest boundary as RESP encoded bytes in guest
+//! memory, referenced by an i64 packing `(ptr << 32) | len`. Modules must
+//! export their `memory` and an `alloc(len: i32) -> i32` function the host
+//! uses to hand them bytes. They may import:
+//!
+//! - `env.redis(ptr: i32, len: i32) -> i64`: run the RESP array command at
+//!   ptr/len, returning the packed RESP reply.
+//!
+//! Programs ran with SCRIPT are modules (as WAT text) exporting
+//! `main() -> i64`, which returns a packed RESP reply. Modules in the scripts
+//! dir (`*.wasm` or `*.wat`) export redis functions: every export of type
+//! `(i32, i32) -> i64` (except `alloc`) is callable like a command, and gets
+//! its arguments as a packed RESP array.
+//!
+//! Every run gets a fresh instance, so scripts can't leave state behind.
+//! Its store caps memory and tables at `--script-max-memory` and
+//! `--script-max-table-elements`, so a runaway program fails its allocations
+//! instead of taking the server's memory.
+//!
+//! Epoch interruption is on, and every run may only see the epoch it
+//! started in: SCRIPT KILL moves the engine to the next one, which traps
+//! every running program at its next loop back edge or call.
+
+use super::{Interrupt, Program, RedisCaller, ScriptResult, ScriptReturn, ScriptingBackend};
+use crate::asyncresp::RespParser;
+use crate::hashing::sha1_hex;
+use crate::logger::LOGGER;
+use crate::types::{RedisValueRef, StateStoreRef};
+use bytes::BytesMut;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio_util::codec::{Decoder, Encoder};
+use wasmtime::{
+    AsContext, AsContextMut, Caller, Engine, ExternType, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, Trap, TypedFunc, ValType,
+};
+
+struct HostState {
+    redis: RedisCaller,
+    limits: StoreLimits,
+}
+
+/// Limits of a program's store. 0 disables a limit.
+fn store_limits(max_memory: u64, max_table_elements: u32) -> StoreLimits {
+    let mut limits = StoreLimitsBuilder::new();
+    if max_memory != 0 {
+        limits = limits.memory_size(max_memory.min(usize::MAX as u64) as usize);
+    }
+    if max_table_elements != 0 {
+        limits = limits.table_elements(max_table_elements);
+    }
+    limits.build()
+}
+
+fn script_error(e: impl std::fmt::Display) -> Box<dyn Error + Send> {
+    let e: Box<dyn Error + Send + Sync> = e.to_string().into();
+    e
+}
+
+fn encode(value: RedisValueRef) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    RespParser::default()
+        .encode(value, &mut buf)
+        .expect("encoding into memory can't fail");
+    buf.to_vec()
+}
+
+fn decode(bytes: &[u8]) -> Result<RedisValueRef, String> {
+    let mut buf = BytesMut::from(bytes);
+    match RespParser::default().decode(&mut buf) {
+        Ok(Some(value)) => Ok(value),
+        Ok(None) => Err("incomplete RESP value".into()),
+        Err(e) => Err(format!("invalid RESP value: {:?}", e)),
+    }
+}
+
+fn pack(ptr: i32, len: usize) -> i64 {
+    ((ptr as u32 as i64) << 32) | len as u32 as i64
+}
+
+fn unpack(packed: i64) -> (usize, usize) {
+    ((packed >> 32) as u32 as usize, packed as u32 as usize)
+}
+
+fn read_guest(store: impl AsContext, memory: Memory, packed: i64) -> Result<Vec<u8>, Trap> {
+    let (ptr, len) = unpack(packed);
+    // The length is the guest's: check it before allocating for it.
+    match ptr.checked_add(len) {
+        Some(end) if end <= memory.data_size(&store) => {}
+        _ => return Err(Trap::new("out of bounds memory access")),
+    }
+    let mut buf = vec![0; len];
+    memory
+        .read(&store, ptr, &mut buf)
+        .map_err(|e| Trap::new(e.to_string()))?;
+    Ok(buf)
+}
+
+fn write_guest(
+    mut store: impl AsContextMut,
+    memory: Memory,
+    alloc: &TypedFunc<i32, i32>,
+    bytes: &[u8],
+) -> Result<i64, Trap> {
+    let ptr = alloc.call(&mut store, bytes.len() as i32)?;
+    memory
+        .write(&mut store, ptr as usize, bytes)
+        .map_err(|e| Trap::new(e.to_string()))?;
+    Ok(pack(ptr, bytes.len()))
+}
+
+/// The `redis` host function.
+fn host_redis(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) -> Result<i64, Trap> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| Trap::new("module must export its memory"))?;
+    let alloc = caller
+        .get_export("alloc")
+        .and_then(|e| e.into_func())
+        .ok_or_else(|| Trap::new("module must export alloc"))?
+        .typed::<i32, i32, _>(&caller)
+        .map_err(|e| Trap::new(e.to_string()))?;
+    let cmd = read_guest(&caller, memory, pack(ptr, len as u32 as usize))?;
+    let args = match decode(&cmd).map_err(Trap::new)? {
+        RedisValueRef::Array(args) => args,
+        _ => return Err(Trap::new("redis commands must be RESP arrays")),
+    };
+    let reply = caller.data().redis.call(args).map_err(Trap::new)?;
+    write_guest(&mut caller, memory, &alloc, &encode(reply))
+}
+
+fn is_redis_fn(name: &str, ty: &ExternType) -> bool {
+    match ty {
+        ExternType::Func(f) => {
+            name != "alloc"
+                && f.params().eq([ValType::I32, ValType::I32])
+                && f.results().eq([ValType::I64])
+        }
+        _ => false,
+    }
+}
+
+struct Inner {
+    engine: Engine,
+    linker: Linker<HostState>,
+    redis: RedisCaller,
+    /// Compiled SCRIPT programs, by SHA1.
+    scripts: RwLock<HashMap<String, Module>>,
+    /// Module exporting each redis function.
+    functions: RwLock<HashMap<String, Module>>,
+}
+
+impl Inner {
+    fn run(
+        &self,
+        state_store: &StateStoreRef,
+        module: &Module,
+        entry: &str,
+        args: Option<Vec<RedisValueRef>>,
+    ) -> ScriptResult {
+        let config = &state_store.config;
+        let mut store = Store::new(
+            &self.engine,
+            HostState {
+                redis: self.redis.clone(),
+                limits: store_limits(
+                    config.script_max_memory(),
+                    config.script_max_table_elements(),
+                ),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_epoch_deadline(1);
+        let instance = self
+            .linker
+            .instantiate(&mut store, module)
+            .map_err(script_error)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| script_error("module must export its memory"))?;
+        let packed = match args {
+            None => instance
+                .get_typed_func::<(), i64, _>(&mut store, entry)
+                .map_err(script_error)?
+                .call(&mut store, ())
+                .map_err(script_error)?,
+            Some(args) => {
+                let alloc = instance
+                    .get_typed_func::<i32, i32, _>(&mut store, "alloc")
+                    .map_err(script_error)?;
+                let args = write_guest(
+                    &mut store,
+                    memory,
+                    &alloc,
+                    &encode(RedisValueRef::Array(args)),
+                )
+                .map_err(script_error)?;
+                let (ptr, len) = unpack(args);
+                instance
+                    .get_typed_func::<(i32, i32), i64, _>(&mut store, entry)
+                    .map_err(script_error)?
+                    .call(&mut store, (ptr as i32, len as i32))
+                    .map_err(script_error)?
+            }
+        };
+        let reply = read_guest(&store, memory, packed).map_err(script_error)?;
+        decode(&reply).map_err(script_error)
+    }
+
+    /// Compile (or fetch the compiled) SCRIPT program. Modules of programs
+    /// removed by SCRIPT FLUSH are dropped first, so they're compiled again.
+    fn script_module(
+        &self,
+        state_store: &StateStoreRef,
+        source: String,
+    ) -> Result<Module, Box<dyn Error + Send>> {
+        let cache = &state_store.script_cache;
+        let flushed = cache.take_flushed();
+        if !flushed.is_empty() {
+            let mut scripts = self.scripts.write();
+            for sha in flushed {
+                scripts.remove(&sha);
+            }
+        }
+        let sha = sha1_hex(source.as_bytes());
+        let compiled = self.scripts.read().get(&sha).cloned();
+        if let (Some(module), true) = (compiled, cache.is_compiled(&sha)) {
+            cache.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(module);
+        }
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+        let module = Module::new(&self.engine, &source).map_err(script_error)?;
+        self.scripts.write().insert(sha.clone(), module.clone());
+        cache.mark_compiled(&sha, source);
+        Ok(module)
+    }
+}
+
+pub struct WasmBackend {
+    inner: Arc<Inner>,
+    state_store: StateStoreRef,
+}
+
+impl WasmBackend {
+    pub fn new(redis: RedisCaller, state_store: StateStoreRef) -> Result<Self, Box<dyn Error>> {
+        let mut config = wasmtime::Config::new();
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap("env", "redis", host_redis)
+            .map_err(|e| e.to_string())?;
+        let inner = Inner {
+            engine,
+            linker,
+            redis,
+            scripts: RwLock::default(),
+            functions: RwLock::default(),
+        };
+        Ok(WasmBackend {
+            inner: Arc::new(inner),
+            state_store,
+        })
+    }
+
+    fn load_module(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let module = Module::from_file(&self.inner.engine, path).map_err(|e| e.to_string())?;
+        for export in module.exports() {
+            if is_redis_fn(export.name(), &export.ty()) {
+                info!(
+                    LOGGER,
+                    "Loaded redis function {} from {:?}",
+                    export.name(),
+                    path
+                );
+                self.inner
+                    .functions
+                    .write()
+                    .insert(export.name().to_string(), module.clone());
+                self.state_store.add_foreign_function(export.name());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ScriptingBackend for WasmBackend {
+    fn name(&self) -> &'static str {
+        "wasm"
+    }
+
+    fn interrupt_handle(&self) -> Option<Interrupt> {
+        let engine = self.inner.engine.clone();
+        Some(Box::new(move || engine.increment_epoch()))
+    }
+
+    fn load_scripts_dir(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("wasm") | Some("wat") => self.load_module(&path)?,
+                _ => continue,
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&self, program: Program, return_channel: ScriptReturn) {
+        let inner = self.inner.clone();
+        let state_store = self.state_store.clone();
+        std::thread::spawn(move || {
+            return_channel.enter();
+            let res = match program {
+                Program::String(source) => inner
+                    .script_module(&state_store, source)
+                    .and_then(|module| inner.run(&state_store, &module, "main", None)),
+                // `main` gets `[KEYS, ARGV]` if it takes arguments like a redis function.
+                Program::Eval(source, keys, argv) => inner
+                    .script_module(&state_store, source)
+                    .and_then(|module| {
+                        let takes_args = module
+                            .get_export("main")
+                            .map_or(false, |ty| is_redis_fn("main", &ty));
+                        let args = vec![RedisValueRef::Array(keys), RedisValueRef::Array(argv)];
+                        let args = Some(args).filter(|_| takes_args);
+                        inner.run(&state_store, &module, "main", args)
+                    }),
+                Program::Function(name, args) => {
+                    let module = inner.functions.read().get(&name).cloned();
+                    match module {
+                        Some(module) => inner.run(&state_store, &module, &name, Some(args)),
+                        None => Err(script_error(format!("Unknown function {}", name))),
+                    }
+                }
+            };
+            if let Err(e) = return_channel.send(res) {
+                error!(LOGGER, "Failed to send program result! {:?}", e)
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_wasm_backend {
+    use super::{decode, encode, pack, read_guest, store_limits, unpack};
+    use crate::types::RedisValueRef;
+    use bytes::Bytes;
+    use wasmtime::{Engine, Instance, Memory, MemoryType, Module, Store};
+
+    #[test]
+    fn test_pack() {
+        assert_eq!(unpack(pack(1024, 17)), (1024, 17));
+        assert_eq!(
+            unpack(pack(i32::MAX, u32::MAX as usize)),
+            (i32::MAX as usize, u32::MAX as usize)
+        );
+    }
+
+    #[test]
+    fn test_resp_round_trip() {
+        let value = RedisValueRef::Array(vec![
+            RedisValueRef::BulkString(Bytes::from_static(b"set")),
+            RedisValueRef::Int(3),
+        ]);
+        assert_eq!(decode(&encode(value.clone())), Ok(value));
+        assert!(decode(b"*2\r\n").is_err());
+    }
+
+    #[test]
+    fn test_read_guest_bounds() {
+        let mut store = Store::new(&Engine::default(), ());
+        let memory = Memory::new(&mut store, MemoryType::new(1, None)).unwrap();
+        let page = memory.data_size(&store);
+        assert_eq!(read_guest(&store, memory, pack(0, 4)).unwrap(), vec![0; 4]);
+        assert!(read_guest(&store, memory, pack(page as i32 - 4, 4)).is_ok());
+        assert!(read_guest(&store, memory, pack(page as i32 - 4, 5)).is_err());
+        assert!(read_guest(&store, memory, pack(0, u32::MAX as usize)).is_err());
+    }
+
+    const PAGE: u64 = 64 * 1024;
+
+    /// Grows its memory by `pages`, trapping if it can't, like a guest
+    /// aborting on a failed allocation.
+    fn grower(engine: &Engine, pages: u32) -> Module {
+        let wat = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "main") (result i64)
+                    (if (i32.eq (memory.grow (i32.const {})) (i32.const -1))
+                        (then unreachable))
+                    (i64.const 0)))"#,
+            pages
+        );
+        Module::new(engine, &wat).unwrap()
+    }
+
+    fn run_limited(module: &Module, max_memory: u64) -> Result<i64, wasmtime::Trap> {
+        let mut store = Store::new(module.engine(), store_limits(max_memory, 10));
+        store.limiter(|limits| limits);
+        let instance = Instance::new(&mut store, module, &[]).unwrap();
+        instance
+            .get_typed_func::<(), i64, _>(&mut store, "main")
+            .unwrap()
+            .call(&mut store, ())
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let engine = Engine::default();
+        assert_eq!(run_limited(&grower(&engine, 1), 2 * PAGE).unwrap(), 0);
+        let trap = run_limited(&grower(&engine, 2), 2 * PAGE).unwrap_err();
+        assert!(trap.to_string().contains("unreachable"), "{}", trap);
+        // 0 is no limit.
+        assert_eq!(run_limited(&grower(&engine, 2), 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_table_limit() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, r#"(module (table 11 funcref))"#).unwrap();
+        let mut store = Store::new(&engine, store_limits(0, 10));
+        store.limiter(|limits| limits);
+        assert!(Instance::new(&mut store, &module, &[]).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+//! The x7 lisp scripting backend.
+
+use super::{script_fn_name, Program, RedisCaller, ScriptReturn, ScriptingBackend};
+use crate::hashing::sha1_hex;
+use crate::logger::LOGGER;
+use crate::types::{RedisValueRef, StateStoreRef};
+use num_traits::cast::ToPrimitive;
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use x7::ffi::{ForeignData, IntoX7Function, Variadic, X7Interpreter};
+use x7::symbols::Expr;
+
+fn bytes_to_string(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).to_string()
+}
+
+struct FFIError {
+    reason: String,
+}
+
+impl FFIError {
+    fn boxed(reason: String) -> Box<dyn Error + Send> {
+        Box::new(Self { reason })
+    }
+}
+
+impl std::fmt::Debug for FFIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.reason)
+    }
+}
+
+impl std::fmt::Display for FFIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.reason)
+    }
+}
+
+impl Error for FFIError {}
+
+impl ForeignData for RedisValueRef {
+    fn to_x7(&self) -> Result<Expr, Box<dyn std::error::Error + Send>> {
+        let res = match self {
+            RedisValueRef::BulkString(s) | RedisValueRef::SimpleString(s) => {
+                Expr::String(bytes_to_string(s))
+            }
+            RedisValueRef::Error(e) => {
+                return Err(FFIError::boxed(bytes_to_string(e)));
+            }
+            RedisValueRef::ErrorMsg(e) => {
+                return Err(FFIError::boxed(bytes_to_string(e)));
+            }
+            RedisValueRef::Int(i) => Expr::Integer(*i),
+            RedisValueRef::Array(a) | RedisValueRef::Push(a) => {
+                Expr::Tuple(a.iter().map(|ele| ele.to_x7()).collect::<Result<_, _>>()?)
+            }
+            RedisValueRef::NullArray | RedisValueRef::NullBulkString => Expr::Nil,
+        };
+        Ok(res)
+    }
+
+    fn from_x7(expr: &Expr) -> Result<Self, Box<dyn std::error::Error + Send>> {
+        let res =
+            match expr {
+                Expr::Nil => RedisValueRef::NullArray,
+                Expr::Num(n) => RedisValueRef::Int(n.to_i64().ok_or_else(|| {
+                    FFIError::boxed(format!("Failed to convert {} into an i64", n))
+                })?),
+                Expr::Integer(n) => RedisValueRef::Int(*n),
+                Expr::String(s) => RedisValueRef::BulkString(s.clone().into()),
+                Expr::Symbol(s) => RedisValueRef::BulkString(s.read().into()),
+                Expr::List(l) | Expr::Tuple(l) | Expr::Quote(l) => RedisValueRef::Array(
+                    l.iter()
+                        .map(ForeignData::from_x7)
+                        .collect::<Result<_, _>>()?,
+                ),
+                Expr::Bool(b) => RedisValueRef::BulkString(format!("{}", b).into()),
+                bad_type => {
+                    return Err(FFIError::boxed(format!(
+                        "redis-oxide cannot reason about this type: {:?}",
+                        bad_type
+                    )))
+                }
+            };
+        Ok(res)
+    }
+}
+
+pub struct X7Backend {
+    interpreter: X7Interpreter,
+    caller: RedisCaller,
+    state_store: StateStoreRef,
+}
+
+impl X7Backend {
+    pub fn new(caller: RedisCaller, state_store: StateStoreRef) -> Self {
+        let res = Self {
+            interpreter: X7Interpreter::new(),
+            caller,
+            state_store,
+        };
+        res.setup_interpreter();
+        res
+    }
+
+    /// Swap a program string for a call to its compiled function, compiling
+    /// and caching the program on first sight. Programs are compiled into
+    /// functions of `KEYS` and `ARGV`, which SCRIPT leaves empty.
+    fn compile_cached(&self, program: Program) -> Result<Program, Box<dyn Error + Send>> {
+        let (source, keys, argv) = match program {
+            Program::String(source) => (source, Vec::new(), Vec::new()),
+            Program::Eval(source, keys, argv) => (source, keys, argv),
+            function => return Ok(function),
+        };
+        let cache = &self.state_store.script_cache;
+        for flushed in cache.take_flushed() {
+            // Rebinding the name drops the compiled function.
+            let undefine = format!("(def {} nil)", script_fn_name(&flushed));
+            if let Err(e) = self.interpreter.run_program::<RedisValueRef>(&undefine) {
+                error!(LOGGER, "Failed to undefine a flushed script: {:?}", e);
+            }
+        }
+        let sha = sha1_hex(source.as_bytes());
+        let fn_name = script_fn_name(&sha);
+        if cache.is_compiled(&sha) {
+            cache.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            cache.misses.fetch_add(1, Ordering::Relaxed);
+            let definition = format!("(do (defn {} (KEYS ARGV) (do {})) nil)", fn_name, source);
+            self.interpreter.run_program::<RedisValueRef>(&definition)?;
+            cache.mark_compiled(&sha, source);
+        }
+        let args = vec![RedisValueRef::Array(keys), RedisValueRef::Array(argv)];
+        Ok(Program::Function(fn_name, args))
+    }
+
+    fn add_redis_fn(&self) {
+        let caller = self.caller.clone();
+        let send_fn = move |args: Variadic<RedisValueRef>| {
+            caller.call(args.into_vec()).map_err(FFIError::boxed)
+        };
+        self.interpreter.add_function("redis", send_fn.to_x7_fn());
+    }
+
+    /// Add the "def-redis-fn" function to the interpreter
+    ///
+    /// e.g. script '(def-redis-fn my-sum (a b) (+ a b))'
+    /// >>> my-sum "hello " world
+    /// "hello world"
+    fn embed_foreign_script(&self) {
+        // (def-redis-fn my-sum (a b) (+ a b))
+        let interpreter_clone = self.interpreter.clone();
+        let state_store = self.state_store.clone();
+        let f = move |args: Variadic<Expr>| {
+            let args = args.into_vec();
+            let fn_name = match args[0].get_symbol_string() {
+                Ok(sym) => sym,
+                Err(e) => return Err(e),
+            };
+            let f_args = args[1].clone(); // (arg1 arg2)
+            let f_body = args[2].clone(); // (redis "set" arg1 arg2)
+            let name = fn_name.read().to_string();
+            let source = format!("(def-redis-fn {} {} {})", name, f_args, f_body);
+            let res = interpreter_clone.add_dynamic_function(&fn_name, f_args, f_body);
+            if res.is_ok() {
+                state_store.add_foreign_function(&name);
+                state_store.functions.define(&name, source);
+            }
+            res
+        };
+        self.interpreter
+            .add_unevaled_function("def-redis-fn", f.to_x7_fn());
+    }
+
+    fn setup_interpreter(&self) {
+        // "redis"
+        self.add_redis_fn();
+        // "def-redis-fn"
+        self.embed_foreign_script();
+    }
+
+    fn spawn_handling_thread(&self, program: Program, return_channel: ScriptReturn) {
+        let interpreter = self.interpreter.clone();
+        std::thread::spawn(move || {
+            // Killed while queued: its client already has its reply.
+            if return_channel.killed() {
+                return;
+            }
+            return_channel.enter();
+            let res = match program {
+                // Only functions get here, `compile_cached` turns the rest into one.
+                Program::String(s) | Program::Eval(s, ..) => {
+                    interpreter.run_program::<RedisValueRef>(&s)
+                }
+                Program::Function(fn_name, fn_args) => interpreter.run_function(&fn_name, &fn_args),
+            };
+            if let Err(e) = return_channel.send(res) {
+                error!(LOGGER, "Failed to send program result! {:?}", e)
+            }
+        });
+    }
+}
+
+impl ScriptingBackend for X7Backend {
+    fn name(&self) -> &'static str {
+        "x7"
+    }
+
+    fn load_scripts_dir(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        self.interpreter.load_lib_dir(&dir.to_path_buf())
+    }
+
+    fn load_function(&self, source: &str) -> Result<(), Box<dyn Error>> {
+        self.interpreter
+            .run_program::<RedisValueRef>(source)
+            .map(|_| ())
+            .map_err(|e| e.to_string().into())
+    }
+
+    fn run(&self, program: Program, return_channel: ScriptReturn) {
+        match self.compile_cached(program) {
+            Ok(program) => self.spawn_handling_thread(program, return_channel),
+            Err(e) => {
+                if let Err(e) = return_channel.send(Err(e)) {
+                    error!(LOGGER, "Failed to send program result! {:?}", e)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_x7_backend {
+    use super::X7Backend;
+    use crate::scripting::{
+        CallRoute, DirectCaller, RedisCaller, ScriptingBackend, ScriptingBridge, FUNCTIONS_FILE,
+    };
+    use crate::types::{RedisValueRef, StateStore, StateStoreRef};
+    use parking_lot::Mutex;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::channel;
+
+    fn backend(runtime: &tokio::runtime::Runtime, state_store: &StateStoreRef) -> X7Backend {
+        let caller = RedisCaller {
+            route: CallRoute::Direct(Arc::new(DirectCaller {
+                runtime: runtime.handle().clone(),
+                state_store: state_store.clone(),
+                dump_file: Arc::new(Mutex::new(PathBuf::new())),
+                scripting_bridge: ScriptingBridge::new(channel(1).0),
+            })),
+        };
+        X7Backend::new(caller, state_store.clone())
+    }
+
+    #[test]
+    fn test_functions_survive_restarts() {
+        let dir = std::env::temp_dir().join(format!(
+            "redis-oxide-test-x7-functions-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(FUNCTIONS_FILE);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let run = |backend: &X7Backend, program: &str| {
+            backend
+                .interpreter
+                .run_program::<RedisValueRef>(program)
+                .unwrap()
+        };
+
+        let store = Arc::new(StateStore::default());
+        assert!(store.functions.open(Some(path.clone())).unwrap().is_empty());
+        let before = backend(&runtime, &store);
+        run(&before, "(def-redis-fn my-sum (a b) (+ a b))");
+        run(&before, "(def-redis-fn my-get (k) (redis \"get\" k))");
+
+        // Restart: the saved sources must define the same functions again.
+        let restarted = Arc::new(StateStore::default());
+        let sources = restarted.functions.open(Some(path)).unwrap();
+        assert_eq!(sources.len(), 2);
+        let after = backend(&runtime, &restarted);
+        for source in sources {
+            after.load_function(&source).unwrap();
+        }
+        assert!(restarted.contains_foreign_function("my-sum"));
+        run(&after, "(redis \"set\" \"a\" \"hello\")");
+        assert_eq!(run(&after, "(my-sum 1 2)"), RedisValueRef::Int(3));
+        assert_eq!(
+            run(&after, "(my-get \"a\")"),
+            RedisValueRef::BulkString("hello".into())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,35 +1,131 @@
-use crate::database::save_state;
+use crate::blocking::SCRIPT_GATE;
+use crate::cdc::{self, CdcEvent};
+use crate::clients::KILL_SIGNAL;
+use crate::command_table::command_spec;
+use crate::connection::Connection;
+use crate::database::{save_state, unblocked_command};
+use crate::escape::escape_bytes;
+use crate::eviction::{self, OOM_ERROR};
+use crate::expiry::log_expired;
+use crate::keyspace_events::{self, publish_keyspace_events};
+use crate::latency;
 use crate::misc::misc_interact;
+use crate::monitor::Origin;
 use crate::ops::{op_interact, Ops};
+use crate::queue::qpop_as_claim;
+use crate::replication::{serve_replica, PsyncRequest};
+use crate::state::{SelectedDb, WriteOrder};
+use crate::streams::group_read_as_claims;
 /// Server launch file. Starts the services to make redis-oxide work.
-use crate::{asyncresp::RespParser, scripting::ScriptingBridge};
+use crate::{
+    asyncresp::{RESPError, RespParser},
+    scripting::ScriptingBridge,
+};
 use crate::{logger::LOGGER, types::StateRef};
 use crate::{
     ops::translate,
     startup::Config,
-    types::{DumpFile, RedisValueRef, ReturnValue, StateStoreRef},
+    types::{DumpFile, Key, RedisValueRef, ReturnValue, StateStore, StateStoreRef},
 };
-use futures::StreamExt;
+use futures::future::select_all;
+use futures::{FutureExt, StreamExt};
 use futures_util::sink::SinkExt;
-use std::sync::atomic::Ordering;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::net::{TcpListener, TcpStream};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
+use tokio::task;
 use tokio_util::codec::Decoder;
 
 fn incr_and_save_if_required(state: StateStoreRef, dump_file: DumpFile) {
-    state.commands_ran_since_save.fetch_add(1, Ordering::SeqCst);
-    let should_save = state.commands_ran_since_save.compare_exchange(
-        state.commands_threshold,
-        0,
-        Ordering::SeqCst,
-        Ordering::SeqCst,
-    );
-    if should_save.is_ok() {
-        let state_clone = state;
-        let dump_file_clone = dump_file;
-        tokio::spawn(async {
-            save_state(state_clone, dump_file_clone);
-        });
+    let ran = state.commands_ran_since_save.fetch_add(1, Ordering::SeqCst) + 1;
+    // CONFIG SET may lower the threshold below what already ran.
+    let threshold = state.config.ops_until_save();
+    let should_save = threshold != 0
+        && ran >= threshold
+        && state
+            .commands_ran_since_save
+            .compare_exchange(ran, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+    if should_save {
+        save_state(state, dump_file);
+    }
+}
+
+/// Longest command name kept in statistics; real commands are much shorter.
+const MAX_COMMAND_NAME: usize = 32;
+
+/// Lowercased (and escaped) name of the command in `redis_value`. Used to key statistics.
+pub(crate) fn command_name(redis_value: &RedisValueRef) -> String {
+    let head = match redis_value {
+        RedisValueRef::Array(arr) => arr.first(),
+        bs @ RedisValueRef::BulkString(_) => Some(bs),
+        _ => None,
+    };
+    match head {
+        Some(RedisValueRef::BulkString(s)) => escape_bytes(s, MAX_COMMAND_NAME).to_lowercase(),
+        _ => "unknown".into(),
+    }
+}
+
+/// Record the size of the reply, count and warn if it's big, and enforce the reply size limit.
+/// Returns the reply to send, and its size if it's big.
+fn track_reply_size(
+    state_store: &StateStore,
+    command: &str,
+    reply: RedisValueRef,
+) -> (RedisValueRef, Option<usize>) {
+    let size = reply.encoded_size();
+    // Clients pick the names of unknown commands, so they'd get a histogram each.
+    let known = if command_spec(command.as_bytes()).is_some() {
+        command
+    } else {
+        "unknown"
+    };
+    state_store.stats.record_reply_size(known, size);
+    let limit = state_store.config.max_reply_bytes();
+    if limit != 0 && size as u64 > limit {
+        warn!(
+            LOGGER,
+            "Refusing to send {} byte reply to {} (limit {} bytes)", size, command, limit
+        );
+        let err = format!(
+            "ERR reply of {} bytes exceeds the output buffer limit of {} bytes",
+            size, limit
+        );
+        return (RedisValueRef::ErrorMsg(err.into_bytes()), None);
+    }
+    let big = state_store.config.big_reply_bytes();
+    if big != 0 && size as u64 >= big {
+        warn!(LOGGER, "Big reply: {} returned {} bytes", command, size);
+        state_store.stats.record_big_reply();
+        return (reply, Some(size));
+    }
+    (reply, None)
+}
+
+tokio::task_local! {
+    /// Where the running command came from, for the commands which care.
+    pub static ORIGIN: Origin;
+}
+
+/// Counts a command as in flight until dropped, so handoffs can wait for it.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
@@ -39,87 +135,617 @@ pub async fn process_command(
     dump_file: DumpFile,
     scripting_bridge: Arc<ScriptingBridge>,
     redis_value: RedisValueRef,
+    origin: Origin,
+) -> RedisValueRef {
+    // Register before checking the flag so a handoff can't miss this command.
+    let _in_flight = InFlight::new(&state_store.commands_in_flight);
+    if state_store.handing_off.load(Ordering::SeqCst) {
+        return RedisValueRef::ErrorMsg(b"LOADING server is restarting, retry shortly".to_vec());
+    }
+    state_store.stats.record_command();
+    let command = command_name(&redis_value);
+    if state_store.replication.is_replica() && is_write(&command) {
+        return RedisValueRef::ErrorMsg(
+            b"READONLY You can't write against a read only replica.".to_vec(),
+        );
+    }
+    // Like redis, admin commands aren't shown.
+    if state_store.monitor.is_watched() && !has_flag(&command, "admin") {
+        let db = state_store.db_index(state);
+        state_store.monitor.publish(db, origin, &redis_value);
+    }
+    let script_order = order_script(
+        &state_store,
+        &scripting_bridge,
+        &redis_value,
+        &command,
+        origin,
+    );
+    let script_order = match script_order.await {
+        Ok(order) => order,
+        Err(busy) => return busy,
+    };
+    // Blocking commands share the script lock only while they try, see
+    // `KeyBlocking`, so a script can run while they wait.
+    let gated = script_order.is_some() && has_flag(&command, "blocking");
+    let _script_order = script_order.filter(|_| !gated);
+    // For the slow log, which keeps slow commands and big replies.
+    let timed =
+        (state_store.slowlog.threshold() >= 0).then(|| (Instant::now(), redis_value.clone()));
+    let run = ORIGIN.scope(
+        origin,
+        run_command(
+            state,
+            state_store.clone(),
+            dump_file,
+            scripting_bridge,
+            redis_value,
+        ),
+    );
+    let reply = if gated {
+        SCRIPT_GATE.scope(state_store.clone(), run).await
+    } else {
+        run.await
+    };
+    let (reply, big_reply) = track_reply_size(&state_store, &command, reply);
+    if let Some((started, redis_value)) = timed {
+        let slowlog = &state_store.slowlog;
+        match big_reply {
+            Some(bytes) => slowlog.record_big_reply(&redis_value, origin, started.elapsed(), bytes),
+            // Blocking commands would be logged for as long as they waited.
+            None if !has_flag(&command, "blocking") => {
+                slowlog.record(&redis_value, origin, started.elapsed())
+            }
+            None => {}
+        }
+    }
+    reply
+}
+
+/// Run a write streamed from our primary. Unlike `process_command`, replicas run these.
+pub async fn apply_replicated(
+    state: &mut StateRef,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+    redis_value: RedisValueRef,
+) -> RedisValueRef {
+    let _in_flight = InFlight::new(&state_store.commands_in_flight);
+    state_store.stats.record_command();
+    run_command(
+        state,
+        state_store.clone(),
+        dump_file,
+        scripting_bridge,
+        redis_value,
+    )
+    .await
+}
+
+async fn run_command(
+    state: &mut StateRef,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+    redis_value: RedisValueRef,
 ) -> RedisValueRef {
+    // Taken before checking whether we feed replicas, see `StateStore::order_write`.
+    let shared = state_store.write_order.read().await;
+    let feeding = state_store.replication.feeding();
+    // Only keep the raw command around if something wants to see it.
+    let command = (state_store.cdc.is_some() || state_store.aof.get().is_some() || feeding)
+        .then(|| redis_value.clone());
+    debug!(LOGGER, "running command {:?}", redis_value);
+    let limited = state_store.config.maxmemory() != 0;
+    let denyoom = limited && has_flag(&command_name(&redis_value), "denyoom");
+    eviction::record_access(&state_store, state, &redis_value);
     match translate(redis_value, state_store.clone()) {
         Ok(op) => {
-            debug!(LOGGER, "running op {:?}", op.clone());
+            let write_keys = op.write_keys();
+            let op_name = op.name();
+            let blocking = op.is_blocking();
+            let aof = state_store.aof.get().filter(|_| write_keys.is_some());
+            let backed_db = state_store
+                .backing
+                .as_ref()
+                .and_then(|backing| Some((backing, state_store.db_index(state)?)));
+            // Step 0: Load backed keys we don't have yet
+            if let Some((backing, db)) = backed_db {
+                let reads = op.string_reads();
+                if let Err(e) = task::block_in_place(|| backing.load_missing(db, state, &reads)) {
+                    error!(LOGGER, "Failed to load from the backing store: {}", e);
+                    let err = format!("ERR backing store read failed: {}", e);
+                    return RedisValueRef::ErrorMsg(err.into_bytes());
+                }
+            }
             // Step 1: Execute the operation the operation (from translate above)
-            let res: ReturnValue = match op {
+            let ordered = match write_keys {
+                Some(_) if !blocking => Some(state_store.order_write(shared, feeding).await),
+                _ => {
+                    drop(shared);
+                    None
+                }
+            };
+            // Evict keys over maxmemory, or refuse writes which could use more
+            if write_keys.is_some()
+                && limited
+                && !eviction::make_room(&state_store, feeding)
+                && denyoom
+            {
+                return RedisValueRef::ErrorMsg(OOM_ERROR.to_vec());
+            }
+            let started = Instant::now();
+            let mut res: ReturnValue = match op {
                 Ops::Misc(op) => {
                     misc_interact(op, state, state_store.clone(), scripting_bridge.clone()).await
                 }
-                _ => op_interact(op, state.clone()).await,
+                _ => op_interact(op, state.clone()).await.0,
             };
+            if !blocking {
+                state_store
+                    .latency
+                    .record(latency::COMMAND, started.elapsed());
+            }
             // Step 2: Update commands_ran_since_save counter, and save if necessary
             if !state_store.memory_only {
                 incr_and_save_if_required(state_store.clone(), dump_file.clone());
             }
-            // Step 3: Finally Return
+            // Keys the command expired must go from the store too, or the
+            // next miss would load them back.
+            let expired = state.take_expired();
+            if let Some((backing, db)) = backed_db.filter(|_| !expired.is_empty()) {
+                if let Err(e) = task::block_in_place(|| backing.persist(db, state, &expired)) {
+                    error!(
+                        LOGGER,
+                        "Failed to delete expired keys from the backing store: {}", e
+                    );
+                }
+            }
+            // They're logged as deleted, before the command if it's held
+            // `write_order` all along, or else after it, see `log_expired`.
+            let exclusive = matches!(ordered, Some(WriteOrder::Exclusive { .. }));
+            if exclusive && !expired.is_empty() {
+                if let Some(db) = state_store.db_index(state) {
+                    let logged = task::block_in_place(|| {
+                        log_expired(&state_store, db, state, &expired, true, feeding)
+                    });
+                    if let Err(e) = logged {
+                        error!(LOGGER, "Failed to log expired keys to the AOF: {}", e);
+                    }
+                }
+            }
+            // Blocking pops are logged as the pop they did, if any, and QPOPs
+            // as the job they handed out.
+            let command = command
+                .and_then(|command| unblocked_command(op_name, command, &res))
+                .and_then(|command| qpop_as_claim(state, op_name, command, &res));
+            if let Some(keys) = &write_keys {
+                if let Some((backing, db)) = backed_db.filter(|_| !res.is_error()) {
+                    if let Err(e) = task::block_in_place(|| backing.persist(db, state, keys)) {
+                        error!(LOGGER, "Failed to write to the backing store: {}", e);
+                        let err = format!("ERR backing store write failed: {}", e);
+                        res = ReturnValue::Ident(RedisValueRef::ErrorMsg(err.into_bytes()));
+                    }
+                }
+            }
+            // Group reads are logged as the claims they made.
+            let logged = command
+                .as_ref()
+                .map(|command| group_read_as_claims(state, op_name, command, &res));
+            if let (Some(aof), Some(keys), Some(command)) = (aof, &write_keys, &logged) {
+                if let Some(db) = state_store.db_index(state).filter(|_| !res.is_error()) {
+                    let logged =
+                        task::block_in_place(|| aof.append(db, state, op_name, keys, command));
+                    if let Err(e) = logged {
+                        error!(LOGGER, "Failed to write to the AOF: {}", e);
+                        let err = format!("ERR AOF write failed: {}", e);
+                        res = ReturnValue::Ident(RedisValueRef::ErrorMsg(err.into_bytes()));
+                    }
+                }
+            }
+            if let (Some(keys), Some(command)) = (&write_keys, &logged) {
+                // Writes which didn't see we were feeding are in the replicas' snapshot.
+                // Blocking pops aren't ordered, and may have waited out a snapshot.
+                let feed = if blocking {
+                    state_store.replication.feeding()
+                } else {
+                    feeding
+                };
+                let db = state_store.db_index(state).filter(|_| !res.is_error());
+                if let Some(db) = db.filter(|_| feed) {
+                    state_store
+                        .replication
+                        .feed(db, state, op_name, keys, command);
+                }
+            }
+            let unordered = ordered.is_none() && !expired.is_empty();
+            if unordered && (state_store.aof.get().is_some() || state_store.replication.feeding()) {
+                let _order = state_store.write_order.write().await;
+                let feeding = state_store.replication.feeding();
+                if let Some(db) = state_store.db_index(state) {
+                    let logged = task::block_in_place(|| {
+                        log_expired(&state_store, db, state, &expired, false, feeding)
+                    });
+                    if let Err(e) = logged {
+                        error!(LOGGER, "Failed to log expired keys to the AOF: {}", e);
+                    }
+                }
+            }
+            drop(ordered);
+            // Step 3: Publish committed writes
+            if let (Some(keys), Some(command)) = (write_keys, command) {
+                if !res.is_error() {
+                    publish_write(&state_store, state, &keys, &command);
+                }
+            }
+            if keyspace_events::enabled(state_store.config.notify_keyspace_events()) {
+                if let Some(db) = state_store.db_index(state) {
+                    publish_keyspace_events(&state_store, db, state);
+                }
+            }
+            // Step 4: Finally Return
             res.into()
         }
         Err(e) => RedisValueRef::from(e),
     }
 }
 
+/// Whether `command` (a lowercased name) has `flag` in the command table.
+pub(crate) fn has_flag(command: &str, flag: &str) -> bool {
+    command_spec(command.as_bytes()).map_or(false, |spec| spec.flags.contains(&flag))
+}
+
+/// Whether `command` (a lowercased name) writes.
+pub(crate) fn is_write(command: &str) -> bool {
+    has_flag(command, "write")
+}
+
+/// Held by a command while it runs, when scripts are atomic.
+enum ScriptOrder<'a> {
+    Shared { _guard: RwLockReadGuard<'a, ()> },
+    Exclusive { _guard: RwLockWriteGuard<'a, ()> },
+}
+
+const BUSY: &[u8] = b"BUSY Redis is busy running a script. You can only call SCRIPT KILL.";
+
+/// How often commands waiting on a script check whether it's over its time limit.
+const BUSY_POLL: Duration = Duration::from_millis(10);
+
+/// Whether `redis_value` is SCRIPT KILL, which runs while scripts hold the server.
+fn is_script_kill(redis_value: &RedisValueRef) -> bool {
+    match redis_value {
+        RedisValueRef::Array(args) => matches!(
+            &args[..],
+            [RedisValueRef::BulkString(script), RedisValueRef::BulkString(kill)]
+                if script.eq_ignore_ascii_case(b"script") && kill.eq_ignore_ascii_case(b"kill")
+        ),
+        _ => false,
+    }
+}
+
+/// Wait until `command` may run, if scripts are atomic (`--atomic-scripts`).
+/// Scripts then hold `script_lock` alone while they run, and other clients'
+/// commands share it. Commands from scripts run inside them, so they don't
+/// take it. Blocking commands let it go again straight away, and share it
+/// for each attempt instead, see `blocking::KeyBlocking`.
+///
+/// Once a script is over `--script-time-limit`, commands stop waiting and
+/// get a BUSY error instead, except for SCRIPT KILL.
+async fn order_script<'a>(
+    state_store: &'a StateStore,
+    scripting_bridge: &ScriptingBridge,
+    redis_value: &RedisValueRef,
+    command: &str,
+    origin: Origin,
+) -> Result<Option<ScriptOrder<'a>>, RedisValueRef> {
+    if !state_store.config.atomic_scripts()
+        || origin == Origin::Script
+        || is_script_kill(redis_value)
+    {
+        return Ok(None);
+    }
+    let script = matches!(command, "eval" | "evalsha" | "script")
+        || state_store.contains_foreign_function(command);
+    loop {
+        let order = async {
+            if script {
+                ScriptOrder::Exclusive {
+                    _guard: state_store.script_lock.write().await,
+                }
+            } else {
+                ScriptOrder::Shared {
+                    _guard: state_store.script_lock.read().await,
+                }
+            }
+        };
+        let limit = state_store.config.script_time_limit();
+        if limit == 0 {
+            return Ok(Some(order.await));
+        }
+        if let Ok(order) = tokio::time::timeout(BUSY_POLL, order).await {
+            return Ok(Some(order));
+        }
+        if scripting_bridge.busy(Duration::from_millis(limit)) {
+            return Err(RedisValueRef::ErrorMsg(BUSY.to_vec()));
+        }
+    }
+}
+
+/// Send a committed write to the CDC sink, if one is configured. Writers
+/// never wait on the sink: events it can't keep up with are dropped.
+fn publish_write(
+    state_store: &StateStore,
+    state: &StateRef,
+    keys: &[Key],
+    command: &RedisValueRef,
+) {
+    if let Some(cdc) = &state_store.cdc {
+        let event = CdcEvent::new(state_store.db_index(state), keys, command);
+        if !cdc::publish(cdc, event) {
+            state_store.stats.record_cdc_drop();
+        }
+    }
+}
+
 /// Spawn a RESP handler for the given socket.
 ///
 /// This will synchronously process requests / responses for this
 /// connection only. Other connections will be spread across the
 /// thread pool.
-async fn process(
-    socket: TcpStream,
+async fn process<S>(
+    socket: S,
+    peer: SocketAddr,
     state_store: StateStoreRef,
     dump_file: DumpFile,
     scripting_bridge: Arc<ScriptingBridge>,
-) {
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     tokio::spawn(async move {
-        let mut state = state_store.get_default();
-        let mut transport = RespParser::default().framed(socket);
-        while let Some(redis_value) = transport.next().await {
-            if let Err(e) = redis_value {
-                error!(LOGGER, "Error recieving redis value {:?}", e);
-                continue;
+        state_store.stats.client_connected();
+        let mut selected = SelectedDb::new(&state_store);
+        let mut connection = Connection::new(state_store.clone(), peer);
+        let killed = connection.client().kill_signal();
+        let limits = state_store.config.resp_limits();
+        let mut transport = RespParser::with_limits(limits).framed(socket);
+        let mut batched = 0;
+        loop {
+            // Pipelined commands are ran back to back, their replies sent
+            // together once there's nothing more to read right away.
+            let pipelined = if batched < MAX_PIPELINE_BATCH {
+                transport.next().now_or_never()
+            } else {
+                None
+            };
+            let redis_value = match pipelined {
+                Some(Some(redis_value)) => {
+                    batched += 1;
+                    redis_value
+                }
+                Some(None) => {
+                    // The client may only have stopped writing: send what it's owed.
+                    if let Err(e) = transport.flush().await {
+                        error!(LOGGER, "Failed to send data to client! {:?}", e);
+                    }
+                    break;
+                }
+                None => {
+                    batched = 0;
+                    if let Err(e) = transport.flush().await {
+                        error!(LOGGER, "Failed to send data to client! {:?}", e);
+                        break;
+                    }
+                    // Deliver published messages while waiting for the next command.
+                    tokio::select! {
+                        redis_value = transport.next() => match redis_value {
+                            Some(redis_value) => redis_value,
+                            None => break,
+                        },
+                        _ = killed.notified() => {
+                            debug!(LOGGER, "Client {} killed", peer);
+                            break;
+                        }
+                        message = connection.next_message() => {
+                            let message = match message {
+                                Some(message) => message,
+                                None => {
+                                    warn!(LOGGER, "Client {} disconnected for falling behind on its messages", peer);
+                                    break;
+                                }
+                            };
+                            if let Err(e) = transport.send(message).await {
+                                error!(LOGGER, "Failed to send data to client! {:?}", e)
+                            }
+                            continue;
+                        }
+                    }
+                }
+            };
+            let redis_value = match redis_value {
+                Ok(redis_value) => redis_value,
+                Err(e) => {
+                    if let RESPError::CrossProtocol = e {
+                        warn!(LOGGER, "Possible SECURITY ATTACK detected. It looks like somebody is sending POST or Host: commands to redis-oxide. This is likely due to an attacker attempting to use Cross Protocol Scripting to compromise your server. Connection from {} aborted.", peer);
+                        break;
+                    }
+                    error!(LOGGER, "Error recieving redis value {:?}", e);
+                    // The rest of the input can't be trusted: say what went wrong and hang up.
+                    if !matches!(e, RESPError::IOError(_)) {
+                        let msg = format!("ERR Protocol error: {}", e);
+                        let _ = transport
+                            .send(RedisValueRef::ErrorMsg(msg.into_bytes()))
+                            .await;
+                    }
+                    break;
+                }
+            };
+            let psync = PsyncRequest::parse(&redis_value);
+            if let Some(request) = psync.filter(|_| connection.is_authenticated()) {
+                // The connection is a replica's from now on.
+                if let Err(e) = transport.flush().await {
+                    error!(LOGGER, "Failed to send data to client! {:?}", e);
+                    break;
+                }
+                let parts = transport.into_parts();
+                serve_replica(parts.io, parts.read_buf, peer, state_store.clone(), request).await;
+                break;
             }
-            let res = process_command(
-                &mut state,
-                state_store.clone(),
-                dump_file.clone(),
-                scripting_bridge.clone(),
-                redis_value.unwrap(),
-            )
-            .await;
-            // let res = match translate(redis_value.unwrap()) {
-            //     Ok(op) => {
-            //         debug!(LOGGER, "running op {:?}", op.clone());
-            //         // Step 1: Execute the operation the operation (from translate above)
-            //         let res: ReturnValue = match op {
-            //             Ops::Misc(op) => {
-            //                 misc_interact(
-            //                     op,
-            //                     &mut state,
-            //                     state_store.clone(),
-            //                     scripting_bridge.clone(),
-            //                 )
-            //                 .await
-            //             }
-            //             _ => op_interact(op, state.clone()).await,
-            //         };
-            //         // Step 2: Update commands_ran_since_save counter, and save if necessary
-            //         if !state_store.memory_only {
-            //             incr_and_save_if_required(state_store.clone(), dump_file.clone());
-            //         }
-            //         // Step 3: Finally Return
-            //         res.into()
-            //     }
-            //     Err(e) => RedisValueRef::from(e),
-            // };
-            if let Err(e) = transport.send(res).await {
-                error!(LOGGER, "Failed to send data to client! {:?}", e)
+            let name = command_name(&redis_value);
+            // Replies to the commands pipelined before shouldn't wait on this one.
+            if batched > 0 && has_flag(&name, "blocking") {
+                if let Err(e) = transport.flush().await {
+                    error!(LOGGER, "Failed to send data to client! {:?}", e);
+                    break;
+                }
+            }
+            connection.client().record_command(&name);
+            let replies = match connection.run_command(&redis_value) {
+                Some(replies) => replies,
+                None => {
+                    let mut state = selected.state(&state_store);
+                    let command = process_command(
+                        &mut state,
+                        state_store.clone(),
+                        dump_file.clone(),
+                        scripting_bridge.clone(),
+                        redis_value,
+                        Origin::Client(peer),
+                    );
+                    // So blocking commands stop waiting once the client is killed.
+                    let reply = KILL_SIGNAL.scope(killed.clone(), command).await;
+                    selected.selected(&state_store, state);
+                    connection.client().set_db(selected.index());
+                    vec![reply]
+                }
             };
+            for res in replies {
+                // Flushed before waiting for more input (and as the buffer fills up).
+                if let Err(e) = transport.feed(res).await {
+                    error!(LOGGER, "Failed to send data to client! {:?}", e)
+                };
+            }
+            if connection.is_closing() {
+                // Flush the OK before hanging up.
+                if let Err(e) = transport.close().await {
+                    error!(LOGGER, "Failed to close client connection! {:?}", e)
+                }
+                break;
+            }
         }
+        // Dropping the connection unregisters its subscriptions and client entry.
+        drop(connection);
+        state_store.stats.client_disconnected();
+        debug!(LOGGER, "Closed connection!");
     });
 }
 
+/// Pipelined commands ran before replies are flushed and other events
+/// (published messages, CLIENT KILL) are looked at.
+const MAX_PIPELINE_BATCH: usize = 1024;
+
+/// Bind the listening socket.
+///
+/// When warm restarts are in use both the old and new process bind the
+/// same port, so SO_REUSEPORT is set. It's left off otherwise so two
+/// servers can't silently share a port.
+fn bind(addr: SocketAddr, config: &Config) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    if config.handoff_socket.is_some() || config.handoff_from.is_some() {
+        socket.set_reuseport(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = config;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// How long a process that took over the state waits for the old one to
+/// release the port.
+const HANDOFF_BIND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bind `addr`, and if we took over from another process that's still
+/// holding it (it exits right after the handoff, but may not have set
+/// SO_REUSEPORT), retry until it's gone.
+async fn bind_after_handoff(addr: SocketAddr, config: &Config) -> std::io::Result<TcpListener> {
+    let deadline = Instant::now() + HANDOFF_BIND_TIMEOUT;
+    loop {
+        match bind(addr, config) {
+            Err(e)
+                if e.kind() == std::io::ErrorKind::AddrInUse
+                    && config.handoff_from.is_some()
+                    && Instant::now() < deadline =>
+            {
+                debug!(
+                    LOGGER,
+                    "{} is still in use, waiting for the old process", addr
+                );
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        // Also ::ffff:127.0.0.0/104, IPv4 loopback seen through a dual-stack socket.
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || matches!(
+                    ip.octets(),
+                    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 127, ..]
+                )
+        }
+    }
+}
+
+/// Whether protected mode turns `peer` away: there's no password, and it
+/// isn't connecting from this host.
+fn is_protected(state_store: &StateStore, peer: SocketAddr) -> bool {
+    state_store.config.protected_mode()
+        && state_store.config.requirepass().is_none()
+        && !is_loopback(peer.ip())
+}
+
+const DENIED: &[u8] = b"-DENIED redis-oxide is running in protected mode because protected mode is enabled and no password is set. In this mode connections are only accepted from the loopback interface. Set a password with --requirepass (or CONFIG SET requirepass), or disable protected mode with --protected-mode no (or CONFIG SET protected-mode no) from the loopback interface, making sure the server isn't reachable from the internet.\r\n";
+
+/// Warn about listening on other interfaces than loopback without a password.
+fn warn_if_public(config: &Config) {
+    let public: Vec<String> = config
+        .bind
+        .iter()
+        .filter(|ip| !is_loopback(**ip))
+        .map(|ip| ip.to_string())
+        .collect();
+    let has_password = config
+        .requirepass
+        .as_deref()
+        .map_or(false, |pass| !pass.is_empty());
+    if public.is_empty() || has_password {
+        return;
+    }
+    if config.protected_mode == "yes" {
+        warn!(
+            LOGGER,
+            "Listening on {} without a password: protected mode only accepts clients from the loopback interface. Set --requirepass to serve other hosts.",
+            public.join(", ")
+        );
+    } else {
+        warn!(
+            LOGGER,
+            "Listening on {} without a password or protected mode: anyone who can reach the server can run any command!",
+            public.join(", ")
+        );
+    }
+}
+
 /// The listener for redis-oxide. Accepts connections and spawns handlers.
 pub async fn socket_listener(
     state_store: StateStoreRef,
@@ -127,43 +753,96 @@ pub async fn socket_listener(
     config: Config,
     scripting_bridge: Arc<ScriptingBridge>,
 ) {
-    // First, get the address determined and parsed.
-    let addr_str = format!("{}:{}", "127.0.0.1", config.port);
-    let addr = match addr_str.parse::<SocketAddr>() {
-        Ok(s) => s,
-        Err(e) => {
+    let port = match u16::try_from(config.port) {
+        Ok(port) => port,
+        Err(_) => {
             error!(
                 LOGGER,
-                "Could not start server! Could not parse {} as listening address, given error: {}",
-                addr_str,
-                e
+                "Could not start server! {} isn't a valid port", config.port
             );
             return;
         }
     };
-
-    // Second, bind/listen on that address
-    let listener = match TcpListener::bind(&addr).await {
-        Ok(s) => s,
-        Err(e) => {
-            error!(
-                LOGGER,
-                "Could not start server! Could not bind to {}, given error: {}", addr_str, e
-            );
-            if config.port <= 1024 {
-                info!(LOGGER, "Hint: You're attempting to bind to a privileged port. Try using -p 6379 or -p 8888");
+    // First, bind/listen on every address.
+    let mut listeners = Vec::with_capacity(config.bind.len());
+    for ip in &config.bind {
+        let addr = SocketAddr::new(*ip, port);
+        match bind_after_handoff(addr, &config).await {
+            Ok(listener) => {
+                info!(LOGGER, "Listening on: {}", addr);
+                listeners.push(listener);
+            }
+            Err(e) => {
+                error!(
+                    LOGGER,
+                    "Could not start server! Could not bind to {}, given error: {}", addr, e
+                );
+                if config.port <= 1024 {
+                    info!(LOGGER, "Hint: You're attempting to bind to a privileged port. Try using -p 6379 or -p 8888");
+                }
+                return;
             }
-            return;
         }
+    }
+    warn_if_public(&config);
+    #[cfg(feature = "tls")]
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match crate::tls::acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!(
+                    LOGGER,
+                    "Could not start server! Could not load TLS certificate: {}", e
+                );
+                return;
+            }
+        },
+        _ => None,
     };
     // Finally, loop over each TCP accept and spawn a handler.
-    info!(LOGGER, "Listening on: {}", addr);
     loop {
-        match listener.accept().await {
-            Ok((socket, _)) => {
+        let accept_any = select_all(listeners.iter().map(|listener| Box::pin(listener.accept())));
+        let accepted = tokio::select! {
+            (accepted, _, _) = accept_any => accepted,
+            _ = state_store.stop_accepting.notified() => {
+                // New clients wait in the backlog until we exit, or resume
+                // after a failed shutdown.
+                info!(LOGGER, "No longer accepting connections.");
+                state_store.resume_accepting.notified().await;
+                info!(LOGGER, "Accepting connections again.");
+                continue;
+            }
+        };
+        match accepted {
+            Ok((mut socket, peer)) => {
                 debug!(LOGGER, "Accepted connection!");
+                if is_protected(&state_store, peer) {
+                    debug!(LOGGER, "Refused {}, protected mode is on", peer);
+                    tokio::spawn(async move {
+                        let _ = socket.write_all(DENIED).await;
+                    });
+                    continue;
+                }
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = tls.clone() {
+                    // Handshake off the accept loop, so a slow client can't hold it up.
+                    let state_store = state_store.clone();
+                    let dump_file = dump_file.clone();
+                    let scripting_bridge = scripting_bridge.clone();
+                    tokio::spawn(async move {
+                        match acceptor.accept(socket).await {
+                            Ok(socket) => {
+                                process(socket, peer, state_store, dump_file, scripting_bridge)
+                                    .await
+                            }
+                            Err(e) => warn!(LOGGER, "TLS handshake with {} failed: {}", peer, e),
+                        }
+                    });
+                    continue;
+                }
                 process(
                     socket,
+                    peer,
                     state_store.clone(),
                     dump_file.clone(),
                     scripting_bridge.clone(),
@@ -174,3 +853,213 @@ pub async fn socket_listener(
         };
     }
 }
+
+#[cfg(test)]
+mod test_server {
+    use super::{
+        is_loopback, is_protected, order_script, process, process_command, track_reply_size,
+    };
+    use crate::config::config_set;
+    use crate::monitor::Origin;
+    use crate::scripting::{Program, ScriptingBridge, KILLED};
+    use crate::test_util::command;
+    use crate::types::{RedisValueRef, StateStore};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc::channel;
+
+    fn set(store: &StateStore, name: &str, value: &str) {
+        config_set(store, &[(name.to_string(), value.to_string())]).unwrap();
+    }
+
+    #[test]
+    fn test_protected_mode() {
+        assert!(is_loopback("127.0.0.2".parse().unwrap()));
+        assert!(is_loopback("::1".parse().unwrap()));
+        assert!(is_loopback("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!is_loopback("::ffff:10.0.0.1".parse().unwrap()));
+
+        let store = StateStore::default();
+        let local: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let remote: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        set(&store, "protected-mode", "yes");
+        assert!(!is_protected(&store, local));
+        assert!(is_protected(&store, remote));
+        set(&store, "requirepass", "secret");
+        assert!(!is_protected(&store, remote));
+        set(&store, "requirepass", "");
+        set(&store, "protected-mode", "no");
+        assert!(!is_protected(&store, remote));
+    }
+
+    #[tokio::test]
+    async fn test_atomic_scripts() {
+        let store = StateStore::default();
+        let bridge = ScriptingBridge::new(channel(1).0);
+        let client = Origin::Client("127.0.0.1:5000".parse().unwrap());
+        let order = |command: &'static str, origin| {
+            let redis_value = RedisValueRef::Array(vec![RedisValueRef::BulkString(command.into())]);
+            let store = &store;
+            let bridge = bridge.clone();
+            async move {
+                order_script(store, &bridge, &redis_value, command, origin)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert!(order("eval", client).await.is_none());
+
+        set(&store, "atomic-scripts", "yes");
+        let script = order("eval", client).await;
+        assert!(script.is_some());
+        let waiting = tokio::time::timeout(Duration::from_millis(20), order("get", client));
+        assert!(waiting.await.is_err());
+        // The script's own commands don't wait, unlike blocking ones.
+        assert!(order("get", Origin::Script).await.is_none());
+        let waiting = tokio::time::timeout(Duration::from_millis(20), order("blpop", client));
+        assert!(waiting.await.is_err());
+        drop(script);
+        assert!(order("get", client).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_commands_wait_for_scripts() {
+        let store = Arc::new(StateStore::default());
+        set(&store, "atomic-scripts", "yes");
+        let run = |args: &'static [&'static str], origin| {
+            let store = store.clone();
+            async move {
+                let bridge = ScriptingBridge::new(channel(1).0);
+                let mut state = store.get_default();
+                process_command(
+                    &mut state,
+                    store,
+                    Default::default(),
+                    bridge,
+                    command(args),
+                    origin,
+                )
+                .await
+            }
+        };
+        let client = Origin::Client("127.0.0.1:5000".parse().unwrap());
+        let blpop = tokio::spawn(run(&["BLPOP", "list", "60"], client));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // A push from a running script wakes the BLPOP, which doesn't pop
+        // until the script is done.
+        let script = store.script_lock.write().await;
+        run(&["RPUSH", "list", "x"], Origin::Script).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            store.get_default().lists.get(&b"list"[..]).unwrap().len(),
+            1
+        );
+        drop(script);
+        let popped = tokio::time::timeout(Duration::from_secs(2), blpop).await;
+        assert_eq!(
+            popped.unwrap().unwrap(),
+            RedisValueRef::Array(vec![
+                RedisValueRef::BulkString("list".into()),
+                RedisValueRef::BulkString("x".into()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_busy_script_and_kill() {
+        let store = StateStore::default();
+        set(&store, "atomic-scripts", "yes");
+        set(&store, "script-time-limit", "1");
+        // Nothing answers programs, so this one runs until it's killed.
+        let (prog_sx, _prog_rx) = channel(1);
+        let bridge = ScriptingBridge::new(prog_sx);
+        let running = {
+            let bridge = bridge.clone();
+            tokio::spawn(async move {
+                bridge
+                    .handle_script_cmd(Program::String("(+ 1 1)".into()))
+                    .await
+            })
+        };
+        let _script = store.script_lock.write().await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let client = Origin::Client("127.0.0.1:5000".parse().unwrap());
+        let get = RedisValueRef::Array(vec![RedisValueRef::BulkString("get".into())]);
+        let res = order_script(&store, &bridge, &get, "get", client).await;
+        assert_eq!(
+            res.err(),
+            Some(RedisValueRef::ErrorMsg(super::BUSY.to_vec()))
+        );
+        let kill = RedisValueRef::Array(vec![
+            RedisValueRef::BulkString("SCRIPT".into()),
+            RedisValueRef::BulkString("kill".into()),
+        ]);
+        let res = order_script(&store, &bridge, &kill, "script", client).await;
+        assert!(matches!(res, Ok(None)));
+
+        assert_eq!(bridge.kill_all(), Ok(1));
+        let reply = running.await.unwrap();
+        assert_eq!(reply, RedisValueRef::Error(KILLED.into()));
+        assert_eq!(bridge.kill_all(), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_replies_are_flushed() {
+        let store = Arc::new(StateStore::default());
+        let bridge = ScriptingBridge::new(channel(1).0);
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let (mut client, socket) = tokio::io::duplex(4096);
+        process(socket, peer, store, Default::default(), bridge).await;
+
+        // The SET's reply isn't held back by the BLPOP pipelined after it.
+        client
+            .write_all(b"SET a 1\r\nBLPOP list 0\r\n")
+            .await
+            .unwrap();
+        let mut ok = [0; 5];
+        let read = client.read_exact(&mut ok);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&ok, b"+OK\r\n");
+
+        // Nor is a reply to a client that stopped writing.
+        let (mut client, socket) = tokio::io::duplex(4096);
+        let store = Arc::new(StateStore::default());
+        let bridge = ScriptingBridge::new(channel(1).0);
+        process(socket, peer, store, Default::default(), bridge).await;
+        client.write_all(b"SET a 1\r\nGET a\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, b"+OK\r\n$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn test_track_reply_size() {
+        let store = StateStore::default();
+        set(&store, "big-reply-bytes", "10");
+        let small = RedisValueRef::SimpleString("OK".into());
+        let big = RedisValueRef::BulkString(vec![b'x'; 100].into());
+        assert_eq!(track_reply_size(&store, "get", small.clone()).1, None);
+        assert_eq!(track_reply_size(&store, "get", big).1, Some(108));
+        track_reply_size(&store, "nosuchcommand1", small.clone());
+        track_reply_size(&store, "nosuchcommand2", small);
+        let names: Vec<_> = store
+            .stats
+            .reply_sizes()
+            .into_iter()
+            .map(|(name, hist)| (name, hist.calls()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("get".to_string(), 2), ("unknown".to_string(), 2)]
+        );
+        assert_eq!(store.stats.big_replies(), 1);
+    }
+}
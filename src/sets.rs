@@ -1,6 +1,10 @@
+use crate::coop::Budget;
 use crate::op_variants;
 use crate::ops::RVec;
+use crate::scan::{scan_members, scan_reply, ScanOptions};
 use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use crate::write_effects::untouched;
+use rand::seq::{IteratorRandom, SliceRandom};
 use std::collections::HashSet;
 
 op_variants! {
@@ -12,13 +16,39 @@ op_variants! {
     SInter(RVec<Value>),
     SInterStore(Key, RVec<Value>),
     SIsMember(Key, Value),
+    SMIsMember(Key, RVec<Value>),
     SMembers(Key),
     SMove(Key, Key, Value),
     SPop(Key, Option<Count>),
     SRandMembers(Key, Option<Count>),
     SRem(Key, RVec<Value>),
     SUnion(RVec<Value>),
-    SUnionStore(Key, RVec<Value>)
+    SUnionStore(Key, RVec<Value>),
+    SScan(Key, u64, ScanOptions)
+}
+
+impl SetOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            SetOps::SCard(_)
+            | SetOps::SDiff(_)
+            | SetOps::SInter(_)
+            | SetOps::SIsMember(..)
+            | SetOps::SMIsMember(..)
+            | SetOps::SMembers(_)
+            | SetOps::SRandMembers(..)
+            | SetOps::SScan(..)
+            | SetOps::SUnion(_) => None,
+            SetOps::SAdd(key, _)
+            | SetOps::SDiffStore(key, _)
+            | SetOps::SInterStore(key, _)
+            | SetOps::SPop(key, _)
+            | SetOps::SRem(key, _)
+            | SetOps::SUnionStore(key, _) => Some(vec![key.clone()]),
+            SetOps::SMove(src, dest, _) => Some(vec![src.clone(), dest.clone()]),
+        }
+    }
 }
 
 pub enum SetAction {
@@ -30,7 +60,8 @@ pub enum SetAction {
 make_reader!(sets, read_sets);
 make_writer!(sets, write_sets);
 
-fn many_set_op(state: &StateRef, keys: RVec<Key>, op: SetAction) -> Option<HashSet<Value>> {
+/// Combine the given sets. Yields between sets, as each can be large.
+async fn many_set_op(state: &StateRef, keys: RVec<Key>, op: SetAction) -> Option<HashSet<Value>> {
     let sets_that_exist: Vec<_> = keys
         .iter()
         .filter(|&k| state.sets.contains_key(k))
@@ -38,112 +69,159 @@ fn many_set_op(state: &StateRef, keys: RVec<Key>, op: SetAction) -> Option<HashS
     if sets_that_exist.is_empty() {
         return None;
     }
+    let mut budget = Budget::default();
     #[allow(clippy::mutable_key_type)]
-    let mut head: HashSet<Key> = state
-        .sets
-        .get_mut(sets_that_exist[0])
-        .unwrap()
-        .value_mut()
-        .clone();
-    // TODO: Make this _way_ cleaner.
+    let mut head: HashSet<Key> = read_sets!(state, sets_that_exist[0])
+        .map(|set| set.value().clone())
+        .unwrap_or_default();
     for set_key in sets_that_exist.into_iter().skip(1) {
+        budget.spend(head.len()).await;
+        // The set may have been removed while we yielded.
+        #[allow(clippy::mutable_key_type)]
+        let empty = HashSet::new();
+        let other = read_sets!(state, set_key);
+        let other = other.as_ref().map(|set| set.value()).unwrap_or(&empty);
         head = match op {
-            SetAction::Diff => head
-                .difference(state.sets.get(set_key).unwrap().value())
-                .cloned()
-                .collect(),
-            SetAction::Union => head
-                .union(state.sets.get(set_key).unwrap().value())
-                .cloned()
-                .collect(),
-            SetAction::Inter => head
-                .intersection(state.sets.get(set_key).unwrap().value())
-                .cloned()
-                .collect(),
+            SetAction::Diff => head.difference(other).cloned().collect(),
+            SetAction::Union => head.union(other).cloned().collect(),
+            SetAction::Inter => head.intersection(other).cloned().collect(),
         }
     }
     Some(head)
 }
 
+/// Replace `key` with `set`, or delete it if `set` is empty. Replies with
+/// the size of `set`.
+#[allow(clippy::mutable_key_type)]
+fn store(state: &StateRef, key: Key, set: HashSet<Value>) -> ReturnValue {
+    let size = set.len();
+    if set.is_empty() {
+        state.sets.remove(&key);
+    } else {
+        state.sets.insert(key, set);
+    }
+    ReturnValue::IntRes(size as Count)
+}
+
 pub async fn set_interact(set_op: SetOps, state: StateRef) -> ReturnValue {
     match set_op {
         SetOps::SAdd(set_key, vals) => {
             let mut set = state.sets.entry(set_key).or_default();
-            vals.into_iter()
-                .fold(0, |acc, val| acc + set.insert(val) as Count)
-                .into()
+            let added = vals
+                .into_iter()
+                .fold(0, |acc, val| acc + set.insert(val) as Count);
+            if added == 0 {
+                untouched();
+            }
+            added.into()
+        }
+        // Members come back in the set's (arbitrary) iteration order. The
+        // set is copied as it was, then turned into the reply with the lock
+        // released, yielding as it goes.
+        SetOps::SMembers(set_key) => {
+            #[allow(clippy::mutable_key_type)]
+            let set: HashSet<Value> = read_sets!(state, &set_key)
+                .map(|set| set.value().clone())
+                .unwrap_or_default();
+            let mut budget = Budget::default();
+            let mut members = RVec::with_capacity(set.len());
+            for member in set {
+                members.push(member);
+                budget.spend(1).await;
+            }
+            members.into()
+        }
+        SetOps::SScan(set_key, cursor, opts) => {
+            let (next, members) = read_sets!(state, &set_key)
+                .map(|set| scan_members(set.iter().cloned(), |m| &m[..], cursor, &opts))
+                .unwrap_or_default();
+            scan_reply(next, members)
         }
-        SetOps::SMembers(set_key) => read_sets!(state, &set_key)
-            .map(|set| set.iter().cloned().collect())
-            .unwrap_or_else(RVec::new)
-            .into(),
         SetOps::SCard(set_key) => read_sets!(state, &set_key)
             .map(|set| set.len() as Count)
             .unwrap_or(0)
             .into(),
-        SetOps::SRem(set_key, vals) => write_sets!(state, &set_key)
-            .map(|mut set| {
-                vals.into_iter()
-                    .fold(0, |acc, val| acc + set.insert(val) as Count)
-            })
-            .unwrap_or(0)
-            .into(),
+        SetOps::SRem(set_key, vals) => {
+            let removed = write_sets!(state, &set_key)
+                .map(|mut set| {
+                    vals.iter()
+                        .fold(0, |acc, val| acc + set.remove(val) as Count)
+                })
+                .unwrap_or(0);
+            state.sets.remove_if(&set_key, |_, set| set.is_empty());
+            if removed == 0 {
+                untouched();
+            }
+            removed.into()
+        }
         SetOps::SDiff(keys) => many_set_op(&state, keys, SetAction::Diff)
+            .await
             .map(|set| set.into_iter().collect())
             .unwrap_or_else(RVec::new)
             .into(),
         SetOps::SUnion(keys) => many_set_op(&state, keys, SetAction::Union)
+            .await
             .map(|set| set.into_iter().collect())
             .unwrap_or_else(RVec::new)
             .into(),
         SetOps::SInter(keys) => many_set_op(&state, keys, SetAction::Inter)
+            .await
             .map(|set| set.into_iter().collect())
             .unwrap_or_else(RVec::new)
             .into(),
-        SetOps::SDiffStore(to_store, keys) => match many_set_op(&state, keys, SetAction::Diff) {
-            Some(hash_set) => {
-                let hash_set_size = hash_set.len();
-                write_sets!(state).insert(to_store, hash_set);
-                ReturnValue::IntRes(hash_set_size as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
-        SetOps::SUnionStore(to_store, keys) => match many_set_op(&state, keys, SetAction::Union) {
-            Some(hash_set) => {
-                let hash_set_size = hash_set.len();
-                write_sets!(state).insert(to_store, hash_set);
-                ReturnValue::IntRes(hash_set_size as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
-        SetOps::SInterStore(to_store, keys) => match many_set_op(&state, keys, SetAction::Inter) {
-            Some(hash_set) => {
-                let hash_set_size = hash_set.len();
-                write_sets!(state).insert(to_store, hash_set);
-                ReturnValue::IntRes(hash_set_size as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
-        // There's some surprising complexity behind this command
+        SetOps::SDiffStore(to_store, keys) => {
+            let set = many_set_op(&state, keys, SetAction::Diff).await;
+            store(&state, to_store, set.unwrap_or_default())
+        }
+        SetOps::SUnionStore(to_store, keys) => {
+            let set = many_set_op(&state, keys, SetAction::Union).await;
+            store(&state, to_store, set.unwrap_or_default())
+        }
+        SetOps::SInterStore(to_store, keys) => {
+            let set = many_set_op(&state, keys, SetAction::Inter).await;
+            store(&state, to_store, set.unwrap_or_default())
+        }
+        // Without a count, SPOP and SRANDMEMBER reply with a single member.
+        //
+        // A HashSet can't hand out a random member directly, so picking one
+        // walks the set to a random position: O(N) in the set's size, like
+        // SMEMBERS, rather than redis' O(1). Picking several walks it once.
         SetOps::SPop(key, count) => {
             let mut set = match state.sets.get_mut(&key) {
                 Some(s) => s,
-                None => return ReturnValue::Nil,
+                None => {
+                    untouched();
+                    return match count {
+                        Some(_) => ReturnValue::MultiStringRes(vec![]),
+                        None => ReturnValue::Nil,
+                    };
+                }
             };
-            if set.is_empty() && count.is_some() {
-                return ReturnValue::MultiStringRes(vec![]);
-            } else if set.is_empty() {
-                return ReturnValue::Nil;
-            }
-            let count = count.unwrap_or(1);
-            if count < 0 {
+            let n = count.unwrap_or(1);
+            if n < 0 {
                 return ReturnValue::Error(b"Count cannot be less than 0!");
             }
-            let eles: Vec<Value> = set.iter().take(count as usize).cloned().collect();
+            if n == 0 {
+                untouched();
+            }
+            let eles: Vec<Value> = set
+                .iter()
+                .choose_multiple(&mut rand::thread_rng(), n as usize)
+                .into_iter()
+                .cloned()
+                .collect();
             for ele in eles.iter() {
                 set.remove(ele);
             }
-            ReturnValue::MultiStringRes(eles)
+            drop(set);
+            state.sets.remove_if(&key, |_, set| set.is_empty());
+            match count {
+                Some(_) => ReturnValue::MultiStringRes(eles),
+                None => eles
+                    .into_iter()
+                    .next()
+                    .map_or(ReturnValue::Nil, ReturnValue::StringRes),
+            }
         }
         SetOps::SIsMember(key, member) => match read_sets!(state, &key) {
             Some(set) => match set.get(&member) {
@@ -152,33 +230,150 @@ pub async fn set_interact(set_op: SetOps, state: StateRef) -> ReturnValue {
             },
             None => ReturnValue::IntRes(0),
         },
+        SetOps::SMIsMember(key, members) => {
+            let set = read_sets!(state, &key);
+            let is_member = |m: &Value| set.as_ref().map_or(false, |set| set.contains(m));
+            let found = members
+                .iter()
+                .map(|m| ReturnValue::IntRes(is_member(m) as Count))
+                .collect();
+            ReturnValue::Array(found)
+        }
         SetOps::SMove(src, dest, member) => {
             let sets = read_sets!(state);
             if !sets.contains_key(&src) || !sets.contains_key(&dest) {
+                untouched();
                 return ReturnValue::IntRes(0);
             }
 
-            // TODO: Why are we allowed to unwrap here? It may not be alive at this time.
-            let mut src_set = state.sets.get_mut(&src).unwrap();
-            match src_set.take(&member) {
+            // Both sets may live in the same shard, so let go of the source first.
+            let taken = sets.get_mut(&src).and_then(|mut set| set.take(&member));
+            sets.remove_if(&src, |_, set| set.is_empty());
+            match taken {
                 Some(res) => {
-                    sets.get_mut(&dest).unwrap().insert(res);
+                    sets.entry(dest).or_default().insert(res);
                     ReturnValue::IntRes(1)
                 }
-                None => ReturnValue::IntRes(0),
+                None => {
+                    untouched();
+                    ReturnValue::IntRes(0)
+                }
             }
         }
-        SetOps::SRandMembers(key, count) => match read_sets!(state, &key) {
-            Some(set) => {
-                let count = count.unwrap_or(1);
-                if count < 0 {
-                    return ReturnValue::MultiStringRes(
-                        set.iter().cycle().take(-count as usize).cloned().collect(),
-                    );
-                };
-                ReturnValue::MultiStringRes(set.iter().take(count as usize).cloned().collect())
+        // A negative count may pick the same member more than once. The set
+        // is walked once whatever the count; the parser caps negative ones.
+        SetOps::SRandMembers(key, count) => {
+            let set = match read_sets!(state, &key) {
+                Some(s) => s,
+                None if count.is_some() => return ReturnValue::MultiStringRes(vec![]),
+                None => return ReturnValue::Nil,
+            };
+            let mut rng = rand::thread_rng();
+            match count {
+                None => set
+                    .iter()
+                    .choose(&mut rng)
+                    .map_or(ReturnValue::Nil, |m| ReturnValue::StringRes(m.clone())),
+                Some(count) if count >= 0 => {
+                    let picked = set.iter().choose_multiple(&mut rng, count as usize);
+                    ReturnValue::MultiStringRes(picked.into_iter().cloned().collect())
+                }
+                Some(count) => {
+                    let members: Vec<&Value> = set.iter().collect();
+                    let picked = (0..count.unsigned_abs()).filter_map(|_| members.choose(&mut rng));
+                    ReturnValue::MultiStringRes(picked.map(|m| (*m).clone()).collect())
+                }
             }
-            None => ReturnValue::Nil,
-        },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_sets {
+    use crate::sets::{set_interact, SetOps};
+    use crate::types::{ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    fn len(reply: ReturnValue) -> usize {
+        match reply {
+            ReturnValue::MultiStringRes(members) => members.len(),
+            _ => panic!("expected members"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spop_and_srandmember() {
+        let eng: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"key");
+        let members = smallvec![
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"c"),
+        ];
+        set_interact(SetOps::SAdd(key.clone(), members), eng.clone()).await;
+        let rand = |count| SetOps::SRandMembers(key.clone(), count);
+        assert_eq!(len(set_interact(rand(Some(2)), eng.clone()).await), 2);
+        assert_eq!(len(set_interact(rand(Some(5)), eng.clone()).await), 3);
+        assert_eq!(len(set_interact(rand(Some(-5)), eng.clone()).await), 5);
+        assert!(matches!(
+            set_interact(rand(None), eng.clone()).await,
+            ReturnValue::StringRes(_)
+        ));
+
+        let pop = |count| SetOps::SPop(key.clone(), count);
+        assert_eq!(len(set_interact(pop(Some(2)), eng.clone()).await), 2);
+        assert!(matches!(
+            set_interact(pop(None), eng.clone()).await,
+            ReturnValue::StringRes(_)
+        ));
+        assert!(!eng.sets.contains_key(&key));
+        assert_eq!(set_interact(pop(None), eng.clone()).await, ReturnValue::Nil);
+        assert_eq!(len(set_interact(pop(Some(1)), eng.clone()).await), 0);
+    }
+
+    #[tokio::test]
+    async fn test_emptied_sets_are_removed() {
+        let eng: StateRef = Arc::new(State::default());
+        let (key, dest) = (Bytes::from_static(b"key"), Bytes::from_static(b"dest"));
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let add = SetOps::SAdd(key.clone(), smallvec![a.clone(), b.clone()]);
+        set_interact(add, eng.clone()).await;
+        set_interact(
+            SetOps::SAdd(dest.clone(), smallvec![b.clone()]),
+            eng.clone(),
+        )
+        .await;
+        let rem = SetOps::SRem(key.clone(), smallvec![b.clone(), b.clone()]);
+        assert_eq!(set_interact(rem, eng.clone()).await, ReturnValue::IntRes(1));
+        let smove = SetOps::SMove(key.clone(), dest.clone(), a.clone());
+        assert_eq!(
+            set_interact(smove, eng.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert!(!eng.sets.contains_key(&key));
+
+        let store = SetOps::SDiffStore(dest.clone(), smallvec![dest.clone(), dest.clone()]);
+        assert_eq!(
+            set_interact(store, eng.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        assert!(!eng.sets.contains_key(&dest));
+    }
+
+    #[tokio::test]
+    async fn test_smismember() {
+        let eng: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"key");
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let op = || SetOps::SMIsMember(key.clone(), smallvec![a.clone(), b.clone()]);
+        let none = ReturnValue::Array(vec![ReturnValue::IntRes(0), ReturnValue::IntRes(0)]);
+        assert_eq!(set_interact(op(), eng.clone()).await, none);
+        set_interact(SetOps::SAdd(key.clone(), smallvec![b.clone()]), eng.clone()).await;
+        assert_eq!(
+            set_interact(op(), eng.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::IntRes(0), ReturnValue::IntRes(1)])
+        );
     }
 }
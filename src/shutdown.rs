@@ -0,0 +1,188 @@
+//! Graceful shutdown, with SHUTDOWN or on SIGINT/SIGTERM.
+//!
+//! The server stops accepting connections and serving commands, waits for
+//! in-flight ones to finish (like a handoff), saves the dump file one last
+//! time, syncs the AOF and exits. If the final save fails it goes back to
+//! serving instead, like redis, so no writes are lost.
+
+use crate::database::{save_now, SaveError};
+use crate::logger::LOGGER;
+use crate::types::{StateStore, StateStoreRef};
+use std::error::Error;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::task;
+
+/// How long to wait for in-flight commands (e.g. BLPOP) before going ahead anyway.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// Stop serving commands and wait for in-flight ones to finish, except the
+/// `own` commands the caller is running. Returns false if some were still
+/// running after DRAIN_TIMEOUT.
+pub async fn drain(state_store: &StateStore, own: usize) -> bool {
+    state_store.handing_off.store(true, Ordering::SeqCst);
+    let start = Instant::now();
+    while state_store.commands_in_flight.load(Ordering::SeqCst) > own {
+        if start.elapsed() > DRAIN_TIMEOUT {
+            return false;
+        }
+        tokio::time::sleep(DRAIN_POLL).await;
+    }
+    true
+}
+
+/// Save the dump file, if `save` says to (None saves unless --memory-only),
+/// and sync the AOF.
+async fn final_save(state_store: &StateStore, save: Option<bool>) -> Result<(), Box<dyn Error>> {
+    if save.unwrap_or(!state_store.memory_only) {
+        let dump_file = state_store
+            .dump_file
+            .get()
+            .ok_or("nothing is saved to disk (--memory-only)")?;
+        info!(LOGGER, "Saving the final state...");
+        loop {
+            match save_now(state_store, dump_file) {
+                Err(SaveError::InProgress) => {}
+                res => break res?,
+            }
+            // A background save has the dump file, and it can't have our last writes.
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+    }
+    if let Some(aof) = state_store.aof.get() {
+        task::block_in_place(|| aof.sync_now())?;
+    }
+    Ok(())
+}
+
+/// Drain, save and exit. `own` is how many commands the caller is running,
+/// e.g. 1 for SHUTDOWN itself. Only returns if the final save failed, once
+/// commands are served again.
+pub async fn shutdown(state_store: &StateStore, save: Option<bool>, own: usize) -> Box<dyn Error> {
+    info!(LOGGER, "Shutting down...");
+    state_store.stop_accepting.notify_one();
+    if !drain(state_store, own).await {
+        warn!(LOGGER, "Shutting down with commands still in flight!");
+    }
+    match final_save(state_store, save).await {
+        Ok(()) => {
+            info!(LOGGER, "redis-oxide is now ready to exit, bye bye...");
+            std::process::exit(0);
+        }
+        Err(e) => {
+            error!(LOGGER, "Errors trying to shut down, still serving: {}", e);
+            state_store.handing_off.store(false, Ordering::SeqCst);
+            state_store.resume_accepting.notify_one();
+            e
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn signalled() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signalled() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Shut down (saving unless --memory-only) on SIGINT or SIGTERM.
+pub async fn shutdown_on_signal(state_store: StateStoreRef) {
+    loop {
+        if let Err(e) = signalled().await {
+            error!(
+                LOGGER,
+                "Can't listen for signals, they won't shut down cleanly: {}", e
+            );
+            return;
+        }
+        shutdown(&state_store, None, 0).await;
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::{drain, shutdown};
+    use crate::misc::{misc_interact, MiscOps};
+    use crate::monitor::Origin;
+    use crate::ops::{translate, Ops};
+    use crate::scripting::ScriptingBridge;
+    use crate::server::ORIGIN;
+    use crate::test_util::command;
+    use crate::types::{ReturnValue, StateStore};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc::channel;
+
+    #[test]
+    fn test_shutdown_options() {
+        let store = Arc::new(StateStore::default());
+        let parse = |args: &[&'static str]| translate(command(args), store.clone());
+        for (args, save) in [
+            (&["SHUTDOWN"][..], None),
+            (&["shutdown", "nosave"][..], Some(false)),
+            (&["SHUTDOWN", "SAVE"][..], Some(true)),
+        ]
+        .iter()
+        {
+            match parse(*args) {
+                Ok(Ops::Misc(MiscOps::Shutdown(parsed))) => assert_eq!(parsed, *save),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert!(parse(&["SHUTDOWN", "NOW"]).is_err());
+        assert!(parse(&["SHUTDOWN", "SAVE", "NOSAVE"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_keeps_serving() {
+        let store = StateStore::default();
+        // Nowhere to save to, as with --memory-only.
+        let e = shutdown(&store, Some(true), 0).await;
+        assert!(e.to_string().contains("memory-only"));
+        assert!(!store.handing_off.load(Ordering::SeqCst));
+        // The listener was told to stop accepting connections, then to resume.
+        let second = Duration::from_secs(1);
+        let stopped = tokio::time::timeout(second, store.stop_accepting.notified());
+        assert!(stopped.await.is_ok());
+        let resumed = tokio::time::timeout(second, store.resume_accepting.notified());
+        assert!(resumed.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_scripted_shutdown_skips_its_eval() {
+        let store = Arc::new(StateStore::default());
+        let bridge = ScriptingBridge::new(channel(1).0);
+        let mut state = store.get_default();
+        // The script's EVAL, and the SHUTDOWN it runs.
+        store.commands_in_flight.store(2, Ordering::SeqCst);
+        let started = Instant::now();
+        let op = MiscOps::Shutdown(Some(true));
+        let res = ORIGIN
+            .scope(
+                Origin::Script,
+                misc_interact(op, &mut state, store.clone(), bridge),
+            )
+            .await;
+        assert!(matches!(res, ReturnValue::Error(_)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_drain_skips_own_commands() {
+        let store = StateStore::default();
+        store.commands_in_flight.store(1, Ordering::SeqCst);
+        assert!(drain(&store, 1).await);
+        assert!(store.handing_off.load(Ordering::SeqCst));
+    }
+}
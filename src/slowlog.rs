@@ -0,0 +1,285 @@
+//! SLOWLOG: remember the commands that took longer than a threshold to run,
+//! like redis' slow log.
+//!
+//! `process_command` times every command and hands the slow ones over. The
+//! log keeps the newest `max_len` of them, each with a unique id so tools
+//! polling SLOWLOG GET can tell which entries they've already seen. Long
+//! argument lists and arguments are cut short, as redis does.
+
+use crate::monitor::Origin;
+use crate::types::{Count, RedisValueRef, ReturnValue, Value};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Arguments kept per entry, counting the command name.
+const MAX_ARGS: usize = 32;
+/// Bytes kept per argument.
+const MAX_ARG_LEN: usize = 128;
+
+pub const DEFAULT_THRESHOLD_US: i64 = 10_000;
+pub const DEFAULT_MAX_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    /// Unix time the command finished, in seconds.
+    pub time: u64,
+    pub duration_us: u64,
+    pub args: Vec<Value>,
+    pub origin: Origin,
+}
+
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold_us: AtomicI64,
+    max_len: AtomicUsize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog {
+            threshold_us: AtomicI64::new(DEFAULT_THRESHOLD_US),
+            max_len: AtomicUsize::new(DEFAULT_MAX_LEN),
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+/// The arguments of `command` to keep, shortened if they're long.
+fn logged_args(command: &RedisValueRef) -> Vec<Value> {
+    let args = match command {
+        RedisValueRef::Array(args) => &args[..],
+        arg => std::slice::from_ref(arg),
+    };
+    let shown = if args.len() > MAX_ARGS {
+        MAX_ARGS - 1
+    } else {
+        args.len()
+    };
+    let mut logged: Vec<Value> = args[..shown]
+        .iter()
+        .map(|arg| match arg {
+            RedisValueRef::BulkString(s) | RedisValueRef::SimpleString(s)
+                if s.len() > MAX_ARG_LEN =>
+            {
+                let more = format!("... ({} more bytes)", s.len() - MAX_ARG_LEN);
+                [&s[..MAX_ARG_LEN], more.as_bytes()].concat().into()
+            }
+            RedisValueRef::BulkString(s) | RedisValueRef::SimpleString(s) => s.clone(),
+            RedisValueRef::Int(i) => i.to_string().into(),
+            _ => Value::new(),
+        })
+        .collect();
+    if shown < args.len() {
+        let more = format!("... ({} more arguments)", args.len() - shown);
+        logged.push(more.into());
+    }
+    logged
+}
+
+impl SlowLog {
+    /// Commands taking at least this many microseconds are logged.
+    /// Negative disables the log, 0 logs every command.
+    pub fn threshold(&self) -> i64 {
+        self.threshold_us.load(Ordering::Relaxed)
+    }
+
+    pub fn set_threshold(&self, threshold_us: i64) {
+        self.threshold_us.store(threshold_us, Ordering::Relaxed);
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_len(&self, max_len: usize) {
+        self.max_len.store(max_len, Ordering::Relaxed);
+        self.entries.lock().truncate(max_len);
+    }
+
+    /// Log `command`, ran by `origin`, if it took long enough.
+    pub fn record(&self, command: &RedisValueRef, origin: Origin, elapsed: Duration) {
+        let threshold = self.threshold();
+        let duration_us = elapsed.as_micros() as u64;
+        if threshold < 0 || duration_us < threshold as u64 {
+            return;
+        }
+        self.push(duration_us, logged_args(command), origin);
+    }
+
+    /// Log `command`, ran by `origin`, for replying with `reply_bytes` bytes
+    /// (past --big-reply-bytes), however long it took.
+    pub fn record_big_reply(
+        &self,
+        command: &RedisValueRef,
+        origin: Origin,
+        elapsed: Duration,
+        reply_bytes: usize,
+    ) {
+        if self.threshold() < 0 {
+            return;
+        }
+        let mut args = logged_args(command);
+        args.push(format!("... ({} byte reply)", reply_bytes).into());
+        self.push(elapsed.as_micros() as u64, args, origin);
+    }
+
+    fn push(&self, duration_us: u64, args: Vec<Value>, origin: Origin) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let entry = SlowLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            time,
+            duration_us,
+            args,
+            origin,
+        };
+        let mut entries = self.entries.lock();
+        // Newest first, like SLOWLOG GET shows them.
+        entries.push_front(entry);
+        entries.truncate(self.max_len());
+    }
+
+    /// The newest `count` entries, newest first. Negative counts get every entry.
+    pub fn get(&self, count: Count) -> Vec<SlowLogEntry> {
+        let entries = self.entries.lock();
+        let count = usize::try_from(count).unwrap_or(entries.len());
+        entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().clear();
+    }
+}
+
+/// SLOWLOG GET: [id, time, microseconds, args, client address, client name] per entry.
+pub fn slowlog_reply(entries: Vec<SlowLogEntry>) -> ReturnValue {
+    let entries = entries
+        .into_iter()
+        .map(|entry| {
+            let addr = match entry.origin {
+                Origin::Client(addr) => addr.to_string(),
+                Origin::Script | Origin::Embedded => String::new(),
+            };
+            ReturnValue::Array(vec![
+                ReturnValue::IntRes(entry.id as Count),
+                ReturnValue::IntRes(entry.time as Count),
+                ReturnValue::IntRes(entry.duration_us as Count),
+                ReturnValue::MultiStringRes(entry.args),
+                ReturnValue::StringRes(addr.into()),
+                ReturnValue::StringRes(Value::new()),
+            ])
+        })
+        .collect();
+    ReturnValue::Array(entries)
+}
+
+#[cfg(test)]
+mod test_slowlog {
+    use super::{logged_args, SlowLog, MAX_ARGS, MAX_ARG_LEN};
+    use crate::monitor::Origin;
+    use crate::test_util::command;
+    use crate::types::RedisValueRef;
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    #[test]
+    fn test_threshold_and_len() {
+        let log = SlowLog::default();
+        log.set_threshold(100);
+        log.record(
+            &command(&["get", "a"]),
+            Origin::Script,
+            Duration::from_micros(99),
+        );
+        assert!(log.is_empty());
+        log.set_max_len(2);
+        for key in &["a", "b", "c"] {
+            log.record(
+                &command(&["get", *key]),
+                Origin::Script,
+                Duration::from_micros(100),
+            );
+        }
+        let entries = log.get(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, entries[1].id), (2, 1));
+        assert_eq!(entries[0].args, vec![Bytes::from("get"), Bytes::from("c")]);
+        assert_eq!(log.get(1).len(), 1);
+        assert_eq!(log.get(-1).len(), 2);
+
+        log.set_threshold(-1);
+        log.record(
+            &command(&["get", "d"]),
+            Origin::Script,
+            Duration::from_secs(1),
+        );
+        assert_eq!(log.len(), 2);
+        log.reset();
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn test_big_replies() {
+        let log = SlowLog::default();
+        log.set_threshold(1_000_000);
+        log.record_big_reply(
+            &command(&["get", "a"]),
+            Origin::Script,
+            Duration::from_micros(5),
+            2_000_000,
+        );
+        let entries = log.get(10);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].args,
+            vec![
+                Bytes::from("get"),
+                Bytes::from("a"),
+                Bytes::from("... (2000000 byte reply)")
+            ]
+        );
+
+        log.set_threshold(-1);
+        log.record_big_reply(
+            &command(&["get", "a"]),
+            Origin::Script,
+            Duration::from_micros(5),
+            2_000_000,
+        );
+        assert_eq!(log.len(), 1);
+    }
+
+    #[test]
+    fn test_long_commands_are_shortened() {
+        let long = vec![b'x'; MAX_ARG_LEN + 10];
+        let args = logged_args(&RedisValueRef::Array(vec![
+            RedisValueRef::BulkString(Bytes::from_static(b"set")),
+            RedisValueRef::BulkString(long.into()),
+        ]));
+        assert!(args[1].ends_with(b"... (10 more bytes)"));
+        assert_eq!(args[1].len(), MAX_ARG_LEN + "... (10 more bytes)".len());
+
+        let many: Vec<RedisValueRef> = (0..MAX_ARGS + 5)
+            .map(|_| RedisValueRef::BulkString(Bytes::from_static(b"k")))
+            .collect();
+        let args = logged_args(&RedisValueRef::Array(many));
+        assert_eq!(args.len(), MAX_ARGS);
+        assert_eq!(args[MAX_ARGS - 1], Bytes::from("... (6 more arguments)"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Copies of a `State`, for saving and for tests that check invariants
+//! across keys without racing live writes.
+//!
+//! An atomic snapshot read locks every shard of every structure before
+//! copying any of them, so it holds either all or none of each op's writes
+//! (as long as the op keeps its keys locked while writing them, like SET does
+//! with the TTL). Readers carry on during the copy; writers wait for it.
+//!
+//! On big instances that wait is long, so saves take a chunked snapshot
+//! instead: one shard is locked and copied at a time, letting writers in
+//! between. Ops landing mid-copy may then be only partly in it, which the
+//! snapshot's `SnapshotStats` owns up to.
+
+use crate::for_each_structure;
+use crate::types::State;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+/// How a snapshot got along with writers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnapshotStats {
+    /// The longest writers were locked out for.
+    pub longest_lock: Duration,
+    /// Whether nothing was written during the copy, making it a point in time.
+    /// Always true for atomic snapshots.
+    pub consistent: bool,
+}
+
+impl Default for SnapshotStats {
+    fn default() -> Self {
+        SnapshotStats {
+            longest_lock: Duration::default(),
+            consistent: true,
+        }
+    }
+}
+
+impl SnapshotStats {
+    /// Stats covering both `self` and `other`, like for a snapshot of every db.
+    pub fn merge(self, other: SnapshotStats) -> SnapshotStats {
+        SnapshotStats {
+            longest_lock: self.longest_lock.max(other.longest_lock),
+            consistent: self.consistent && other.consistent,
+        }
+    }
+}
+
+/// A detached copy of a `State`. Writes to the original don't show up in it.
+pub struct Snapshot {
+    state: State,
+    pub stats: SnapshotStats,
+}
+
+impl Deref for Snapshot {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.state
+    }
+}
+
+/// Lock the shards of each passed structure, then copy them all into a new State.
+/// Structures are locked in `for_each_structure!` order, the order `State` declares them.
+macro_rules! copy_structures {
+    ($state:expr, $($type:ident: $schema:expr),*) => {{
+        $(
+            let $type: Vec<_> = $state.$type.shards().iter().map(|shard| shard.read()).collect();
+        )*
+        let copy = State::default();
+        $(
+            for shard in $type.iter() {
+                for (key, value) in shard.iter() {
+                    copy.$type.insert(key.clone(), value.get().clone());
+                }
+            }
+        )*
+        copy
+    }};
+}
+
+/// Copy the passed structures one shard at a time, yielding between shards.
+/// Evaluates to the copy and the longest a shard was locked for.
+macro_rules! copy_structures_chunked {
+    ($state:expr, $($type:ident: $schema:expr),*) => {{
+        let copy = State::default();
+        let mut longest_lock = Duration::default();
+        $(
+            for shard in $state.$type.shards().iter() {
+                let locked_at = Instant::now();
+                for (key, value) in shard.read().iter() {
+                    copy.$type.insert(key.clone(), value.get().clone());
+                }
+                longest_lock = longest_lock.max(locked_at.elapsed());
+                std::thread::yield_now();
+            }
+        )*
+        (copy, longest_lock)
+    }};
+}
+
+impl State {
+    /// Copy every key (and its TTL) as of one point in time.
+    ///
+    /// Don't call this while holding a reference into the state: it waits on
+    /// every shard.
+    pub fn snapshot(&self) -> Snapshot {
+        let locked_at = Instant::now();
+        let state = for_each_structure!(with_expirations, copy_structures!(self,));
+        let stats = SnapshotStats {
+            longest_lock: locked_at.elapsed(),
+            consistent: true,
+        };
+        Snapshot { state, stats }
+    }
+
+    /// Copy every key (and its TTL), holding at most one shard at a time.
+    ///
+    /// Writes made during the copy may be partly in it: `stats.consistent`
+    /// says whether there were any. Like `snapshot`, don't call this while
+    /// holding a reference into the state.
+    pub fn chunked_snapshot(&self) -> Snapshot {
+        let clock = self.writes.clock();
+        let (state, longest_lock) =
+            for_each_structure!(with_expirations, copy_structures_chunked!(self,));
+        let stats = SnapshotStats {
+            longest_lock,
+            consistent: self.writes.clock() == clock,
+        };
+        Snapshot { state, stats }
+    }
+}
+
+#[cfg(test)]
+mod test_snapshot {
+    use crate::keys::KeyOps;
+    use crate::ops::{op_interact, Ops};
+    use crate::scan::{scan_keys, ScanOptions};
+    use crate::types::State;
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_snapshot_is_detached() {
+        let state = State::default();
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        state.kv.insert(a.clone(), a.clone());
+        state.lists.insert(b.clone(), vec![b.clone()].into());
+        state.set_expiry(a.clone(), 42);
+        let snapshot = state.snapshot();
+        state.kv.insert(a.clone(), b.clone());
+        state.lists.remove(&b);
+        state.clear_expiry(&a);
+        assert_eq!(*snapshot.kv.get(&a).unwrap(), a);
+        assert_eq!(snapshot.structure_of(&b), Some("lists"));
+        assert_eq!(snapshot.expiry(&a), Some(42));
+    }
+
+    #[test]
+    fn test_snapshot_never_sees_half_a_write() {
+        // The writer keeps the string "n" equal to the length of the list
+        // "n", updating the list while holding the string.
+        let state = Arc::new(State::default());
+        let key = Bytes::from_static(b"n");
+        state.kv.insert(key.clone(), Bytes::from_static(b"0"));
+        state.lists.insert(key.clone(), Default::default());
+        let writer = {
+            let (state, key) = (state.clone(), key.clone());
+            std::thread::spawn(move || {
+                for _ in 0..2000 {
+                    let mut count = state.kv.get_mut(&key).unwrap();
+                    let mut list = state.lists.get_mut(&key).unwrap();
+                    list.push_back(Bytes::new());
+                    *count = list.len().to_string().into();
+                }
+            })
+        };
+        for _ in 0..200 {
+            let snapshot = state.snapshot();
+            let count = snapshot.kv.get(&key).unwrap().clone();
+            let len = snapshot.lists.get(&key).unwrap().len();
+            assert_eq!(count, len.to_string());
+            let all = ScanOptions {
+                count: usize::MAX,
+                ..Default::default()
+            };
+            assert_eq!(scan_keys(&snapshot, 0, &all).1.len(), 2);
+        }
+        writer.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunked_snapshot_under_writes() {
+        let state = Arc::new(State::default());
+        for i in 0..1000 {
+            let key = Bytes::from(format!("key-{}", i));
+            state.kv.insert(key.clone(), key);
+        }
+        let quiet = state.chunked_snapshot();
+        assert!(quiet.stats.consistent);
+        assert_eq!(quiet.kv.len(), 1000);
+
+        let writer = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                for i in 0..2000 {
+                    let key = Bytes::from(format!("new-{}", i));
+                    let op = KeyOps::Set(key.clone(), key, Default::default());
+                    op_interact(Ops::Keys(op), state.clone()).await;
+                }
+            })
+        };
+        for _ in 0..50 {
+            // Keys nobody touches are always there, whatever lands mid-copy.
+            assert!(state.chunked_snapshot().kv.len() >= 1000);
+        }
+        writer.await.unwrap();
+        let after = state.chunked_snapshot();
+        assert!(after.stats.consistent);
+        assert_eq!(after.kv.len(), 3000);
+    }
+}
@@ -0,0 +1,312 @@
+//! SORT, over lists and sets.
+//!
+//! Elements sort as numbers, or as bytes with ALPHA. A BY pattern sorts them
+//! by the string it names instead: the pattern with its first `*` replaced
+//! by the element, or a hash field with `key->field`. A BY pattern without a
+//! `*` skips sorting. GET patterns are looked up the same way, with `#`
+//! standing for the element itself.
+//!
+//! Equal weights fall back to comparing the elements, and unsorted sets are
+//! put in byte order, so a set comes out the same every time.
+//!
+//! Elements are weighed and looked up with no lock held, spending a `Budget`
+//! as they go, so sorting a big collection doesn't stall other clients.
+
+use crate::coop::Budget;
+use crate::types::{Count, Key, ReturnValue, Score, State, Value};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortOptions {
+    /// BY: sort by what this pattern names rather than the elements.
+    pub by: Option<Value>,
+    /// LIMIT offset count. A negative count takes everything past `offset`.
+    pub limit: Option<(Count, Count)>,
+    /// GET: reply with what these patterns name rather than the elements.
+    pub get: Vec<Value>,
+    pub desc: bool,
+    pub alpha: bool,
+    /// STORE: save the result as a list here, and reply with its length.
+    pub store: Option<Key>,
+}
+
+/// What an element sorts by. Missing BY keys weigh 0, or sort first with ALPHA.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Weight {
+    Number(Score),
+    Bytes(Option<Value>),
+}
+
+/// Read what `pattern` names for `element`: a string, or with `key->field`
+/// a hash field. `#` is the element itself, and patterns without a `*` name
+/// nothing.
+fn lookup(state: &State, pattern: &[u8], element: &[u8]) -> Option<Value> {
+    if pattern == b"#" {
+        return Some(Value::copy_from_slice(element));
+    }
+    let star = pattern.iter().position(|b| *b == b'*')?;
+    let arrow = pattern[star + 1..]
+        .windows(2)
+        .position(|w| w == b"->")
+        .map(|at| star + 1 + at)
+        .filter(|at| at + 2 < pattern.len());
+    let (key_pattern, field) = match arrow {
+        Some(at) => (&pattern[..at], Some(&pattern[at + 2..])),
+        None => (pattern, None),
+    };
+    let mut key = Vec::with_capacity(key_pattern.len() + element.len());
+    key.extend_from_slice(&key_pattern[..star]);
+    key.extend_from_slice(element);
+    key.extend_from_slice(&key_pattern[star + 1..]);
+    state.expire_if_due(&key);
+    match field {
+        None => state.kv.get(&key[..]).map(|value| value.value().clone()),
+        Some(field) => state.hashes.get(&key[..])?.get(field).cloned(),
+    }
+}
+
+fn weight(state: &State, opts: &SortOptions, element: &Value) -> Result<Weight, ReturnValue> {
+    let by = match &opts.by {
+        Some(pattern) => lookup(state, pattern, element),
+        None => Some(element.clone()),
+    };
+    if opts.alpha {
+        return Ok(Weight::Bytes(by));
+    }
+    let number = match by {
+        Some(by) => std::str::from_utf8(&by)
+            .ok()
+            .and_then(|by| by.parse().ok())
+            .and_then(Score::new),
+        None => Score::new(0.0),
+    };
+    number.map(Weight::Number).ok_or(ReturnValue::Error(
+        b"ERR One or more scores can't be converted into double",
+    ))
+}
+
+/// Sort `key`, which is missing (and sorts as empty), a list or a set.
+/// Returns the elements, or what GET found for them, in order.
+async fn sorted(
+    state: &State,
+    key: &[u8],
+    opts: &SortOptions,
+) -> Result<Vec<Option<Value>>, ReturnValue> {
+    let mut budget = Budget::default();
+    let is_set = match state.structure_of(key) {
+        None | Some("lists") => false,
+        Some("sets") => true,
+        Some(_) => {
+            return Err(ReturnValue::Error(
+                b"WRONGTYPE Operation against a key holding the wrong kind of value",
+            ))
+        }
+    };
+    let mut elements: Vec<Value> = if is_set {
+        state
+            .sets
+            .get(key)
+            .map_or_else(Vec::new, |set| set.iter().cloned().collect())
+    } else {
+        state
+            .lists
+            .get(key)
+            .map_or_else(Vec::new, |list| list.iter().cloned().collect())
+    };
+    budget.spend(elements.len()).await;
+    let dont_sort = opts.by.as_ref().map_or(false, |by| !by.contains(&b'*'));
+    if !dont_sort {
+        let mut weighed = Vec::with_capacity(elements.len());
+        for element in elements {
+            weighed.push((weight(state, opts, &element)?, element));
+            budget.spend(1).await;
+        }
+        weighed.sort();
+        budget.spend(weighed.len()).await;
+        if opts.desc {
+            weighed.reverse();
+        }
+        elements = weighed.into_iter().map(|(_, element)| element).collect();
+    } else if is_set {
+        elements.sort();
+    }
+    let (offset, count) = opts.limit.unwrap_or((0, -1));
+    let offset = offset.max(0) as usize;
+    let count = if count < 0 {
+        usize::MAX
+    } else {
+        count as usize
+    };
+    let picked = elements.into_iter().skip(offset).take(count);
+    if opts.get.is_empty() {
+        return Ok(picked.map(Some).collect());
+    }
+    let mut found = Vec::new();
+    for element in picked {
+        for pattern in &opts.get {
+            found.push(lookup(state, pattern, &element));
+        }
+        budget.spend(opts.get.len()).await;
+    }
+    Ok(found)
+}
+
+/// Run SORT on `key`. With STORE, the result replaces whatever the
+/// destination held (deleting it if empty), nils stored as empty strings.
+pub async fn sort(state: &State, key: &[u8], opts: &SortOptions) -> ReturnValue {
+    state.expire_if_due(key);
+    let found = match sorted(state, key, opts).await {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+    let dest = match &opts.store {
+        Some(dest) => dest,
+        None if opts.get.is_empty() => {
+            return ReturnValue::MultiStringRes(found.into_iter().flatten().collect())
+        }
+        None => {
+            return ReturnValue::Array(
+                found
+                    .into_iter()
+                    .map(|value| value.map_or(ReturnValue::Nil, ReturnValue::StringRes))
+                    .collect(),
+            )
+        }
+    };
+    let len = found.len();
+    state.remove_key(dest);
+    if len > 0 {
+        let list: VecDeque<Value> = found.into_iter().map(Option::unwrap_or_default).collect();
+        state.lists.insert(dest.clone(), list);
+        state.wake_list(dest);
+    }
+    ReturnValue::IntRes(len as Count)
+}
+
+#[cfg(test)]
+mod test_sort {
+    use super::{sort, SortOptions};
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+
+    fn b(s: &'static str) -> Bytes {
+        Bytes::from_static(s.as_bytes())
+    }
+
+    fn values(values: &[&'static str]) -> ReturnValue {
+        ReturnValue::MultiStringRes(values.iter().map(|v| b(v)).collect())
+    }
+
+    #[tokio::test]
+    async fn test_sort_values() {
+        let state = State::default();
+        let list = b("list");
+        state.lists.insert(
+            list.clone(),
+            vec![b("3"), b("10"), b("1.5"), b("-2")].into(),
+        );
+        assert_eq!(
+            sort(&state, &list, &SortOptions::default()).await,
+            values(&["-2", "1.5", "3", "10"])
+        );
+        let opts = SortOptions {
+            alpha: true,
+            desc: true,
+            limit: Some((1, 2)),
+            ..SortOptions::default()
+        };
+        assert_eq!(sort(&state, &list, &opts).await, values(&["10", "1.5"]));
+        assert_eq!(
+            sort(&state, &b("missing"), &SortOptions::default()).await,
+            values(&[])
+        );
+
+        state.lists.get_mut(&list).unwrap().push_back(b("x"));
+        assert_eq!(
+            sort(&state, &list, &SortOptions::default()).await,
+            ReturnValue::Error(b"ERR One or more scores can't be converted into double")
+        );
+        state.kv.insert(b("string"), b("1"));
+        assert!(sort(&state, &b("string"), &SortOptions::default())
+            .await
+            .is_error());
+    }
+
+    #[tokio::test]
+    async fn test_sort_by_and_get() {
+        let state = State::default();
+        let users = b("users");
+        state.sets.insert(
+            users.clone(),
+            vec![b("a"), b("b"), b("c")].into_iter().collect(),
+        );
+        state.kv.insert(b("age_a"), b("30"));
+        state.kv.insert(b("age_b"), b("20"));
+        let mut c: HashMap<Bytes, Bytes> = HashMap::new();
+        c.insert(b("name"), b("Carol"));
+        state.hashes.insert(b("user_c"), c);
+
+        // c has no age, so weighs 0.
+        let opts = SortOptions {
+            by: Some(b("age_*")),
+            get: vec![b("#"), b("user_*->name")],
+            ..SortOptions::default()
+        };
+        assert_eq!(
+            sort(&state, &users, &opts).await,
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(b("c")),
+                ReturnValue::StringRes(b("Carol")),
+                ReturnValue::StringRes(b("b")),
+                ReturnValue::Nil,
+                ReturnValue::StringRes(b("a")),
+                ReturnValue::Nil,
+            ])
+        );
+
+        // Sets come out in a stable order without sorting.
+        let opts = SortOptions {
+            by: Some(b("nosort")),
+            ..SortOptions::default()
+        };
+        assert_eq!(sort(&state, &users, &opts).await, values(&["a", "b", "c"]));
+    }
+
+    #[tokio::test]
+    async fn test_sort_store() {
+        let state = State::default();
+        let (list, dest) = (b("list"), b("dest"));
+        state
+            .lists
+            .insert(list.clone(), vec![b("2"), b("1")].into());
+        state.kv.insert(dest.clone(), b("old"));
+        let opts = SortOptions {
+            get: vec![b("#"), b("missing_*")],
+            store: Some(dest.clone()),
+            ..SortOptions::default()
+        };
+        assert_eq!(sort(&state, &list, &opts).await, ReturnValue::IntRes(4));
+        assert!(!state.kv.contains_key(&dest));
+        assert_eq!(
+            state
+                .lists
+                .get(&dest)
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![b("1"), b(""), b("2"), b("")]
+        );
+
+        let opts = SortOptions {
+            store: Some(dest.clone()),
+            ..SortOptions::default()
+        };
+        assert_eq!(
+            sort(&state, &b("missing"), &opts).await,
+            ReturnValue::IntRes(0)
+        );
+        assert!(!state.lists.contains_key(&dest));
+    }
+}
@@ -1,85 +1,616 @@
+use crate::data_structures::sorted_set::{LexBound, SortedSet, SortedSetMember};
+use crate::keys::SetCondition;
 use crate::ops::RVec;
-use crate::types::{Count, Index, Key, ReturnValue, Score, StateRef};
+use crate::scan::{scan_members, scan_reply, ScanOptions};
+use crate::types::{Count, Index, Key, ReturnValue, Score, StateRef, Value};
+use crate::write_effects::untouched;
 use crate::{make_reader, make_writer, op_variants};
+use std::ops::{Bound, Range};
+
+/// Whether ZADD may change the score of an existing member.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreComparison {
+    Always,
+    /// GT: only if the new score is greater.
+    Greater,
+    /// LT: only if the new score is less.
+    Less,
+}
+
+impl Default for ScoreComparison {
+    fn default() -> Self {
+        ScoreComparison::Always
+    }
+}
+
+/// The options of ZADD.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZAddOptions {
+    /// NX only adds new members, XX only updates existing ones.
+    pub condition: SetCondition,
+    pub comparison: ScoreComparison,
+    /// CH: count members whose score changed, not just new ones.
+    pub changed: bool,
+    /// INCR: add to the member's score, and reply with the new score.
+    pub incr: bool,
+}
+
+/// The options of ZRANGEBYSCORE and ZRANGEBYLEX.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZRangeOptions {
+    pub with_scores: bool,
+    /// LIMIT offset count. A negative count means every member past the offset.
+    pub limit: Option<(Count, Count)>,
+}
+
+impl ZRangeOptions {
+    /// The members ranked in `ranks` that LIMIT picks, lowest first, or
+    /// highest first with `rev`. Only those members are cloned.
+    fn apply(&self, zset: &SortedSet, ranks: Range<usize>, rev: bool) -> Vec<SortedSetMember> {
+        let ranks = match self.limit {
+            None => ranks,
+            Some((offset, _)) if offset < 0 => 0..0,
+            Some((offset, count)) => {
+                let len = ranks.len().saturating_sub(offset as usize);
+                let len = if count < 0 {
+                    len
+                } else {
+                    len.min(count as usize)
+                };
+                // LIMIT counts from the highest score when reversed.
+                let offset = (offset as usize).min(ranks.len());
+                if rev {
+                    ranks.end - offset - len..ranks.end - offset
+                } else {
+                    ranks.start + offset..ranks.start + offset + len
+                }
+            }
+        };
+        zset.members_in(ranks, rev).cloned().collect()
+    }
+}
 
 op_variants! {
     ZSetOps,
-    ZAdd(Key, RVec<(Score, Key)>),
+    ZAdd(Key, ZAddOptions, RVec<(Score, Key)>),
     ZRem(Key, RVec<Key>),
-    ZRange(Key, Score, Score),
+    ZRange(Key, Index, Index, bool),
+    ZRevRange(Key, Index, Index, bool),
+    ZRangeByScore(Key, Bound<Score>, Bound<Score>, ZRangeOptions),
+    ZRevRangeByScore(Key, Bound<Score>, Bound<Score>, ZRangeOptions),
+    ZRangeByLex(Key, LexBound, LexBound, ZRangeOptions),
+    ZRevRangeByLex(Key, LexBound, LexBound, ZRangeOptions),
     ZCard(Key),
     ZScore(Key, Key),
+    ZIncrBy(Key, Score, Key),
+    ZCount(Key, Bound<Score>, Bound<Score>),
+    ZRemRangeByScore(Key, Bound<Score>, Bound<Score>),
+    ZRemRangeByRank(Key, Index, Index),
     ZPopMax(Key, Count),
     ZPopMin(Key, Count),
-    ZRank(Key, Key)
+    ZRank(Key, Key),
+    ZScan(Key, u64, ScanOptions)
+}
+
+impl ZSetOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            ZSetOps::ZRange(..)
+            | ZSetOps::ZRevRange(..)
+            | ZSetOps::ZRangeByScore(..)
+            | ZSetOps::ZRevRangeByScore(..)
+            | ZSetOps::ZRangeByLex(..)
+            | ZSetOps::ZRevRangeByLex(..)
+            | ZSetOps::ZCard(_)
+            | ZSetOps::ZScore(..)
+            | ZSetOps::ZCount(..)
+            | ZSetOps::ZRank(..)
+            | ZSetOps::ZScan(..) => None,
+            ZSetOps::ZAdd(key, ..)
+            | ZSetOps::ZRem(key, _)
+            | ZSetOps::ZIncrBy(key, ..)
+            | ZSetOps::ZRemRangeByScore(key, ..)
+            | ZSetOps::ZRemRangeByRank(key, ..)
+            | ZSetOps::ZPopMax(key, _)
+            | ZSetOps::ZPopMin(key, _) => Some(vec![key.clone()]),
+        }
+    }
 }
 
 make_reader!(zsets, read_zsets);
 make_writer!(zsets, write_zsets);
 
-fn deal_with_negative_indices(coll_size: Count, bounds: (Index, Index)) -> (Index, Index) {
-    let (start, end) = bounds;
-    let start = if start < 0 { start + coll_size } else { start };
-    let end = if end < 0 { end + coll_size } else { end };
-    (start, end)
+/// What ZADD did to one member.
+enum ZAddOutcome {
+    Added(Score),
+    Updated(Score),
+    Unchanged(Score),
+    /// The options kept it from being added or updated.
+    Skipped,
+}
+
+fn zadd_member(
+    zset: &mut SortedSet,
+    opts: &ZAddOptions,
+    score: Score,
+    member: Key,
+) -> Result<ZAddOutcome, &'static [u8]> {
+    let current = zset.score(member.clone());
+    match (current, opts.condition) {
+        (Some(_), SetCondition::IfMissing) | (None, SetCondition::IfExists) => {
+            return Ok(ZAddOutcome::Skipped)
+        }
+        _ => {}
+    }
+    let score = match current {
+        Some(current) if opts.incr => Score::new(current.value() + score.value())
+            .ok_or(&b"ERR resulting score is not a number (NaN)"[..])?,
+        _ => score,
+    };
+    let current = match current {
+        None => {
+            zset.insert(member, score);
+            return Ok(ZAddOutcome::Added(score));
+        }
+        Some(current) => current,
+    };
+    let allowed = match opts.comparison {
+        ScoreComparison::Always => true,
+        ScoreComparison::Greater => score > current,
+        ScoreComparison::Less => score < current,
+    };
+    if !allowed {
+        Ok(ZAddOutcome::Skipped)
+    } else if score == current {
+        Ok(ZAddOutcome::Unchanged(score))
+    } else {
+        zset.insert(member, score);
+        Ok(ZAddOutcome::Updated(score))
+    }
+}
+
+/// Flatten members into a reply. With scores, each member is directly
+/// followed by its score, like redis' WITHSCORES.
+fn members_reply<I>(members: I, with_scores: bool) -> ReturnValue
+where
+    I: IntoIterator<Item = SortedSetMember>,
+{
+    let mut ret: Vec<Value> = Vec::new();
+    for mem in members {
+        ret.push(mem.member);
+        if with_scores {
+            ret.push(mem.score.to_string().into());
+        }
+    }
+    ReturnValue::MultiStringRes(ret)
 }
 
 pub async fn zset_interact(zset_op: ZSetOps, state: StateRef) -> ReturnValue {
     match zset_op {
-        ZSetOps::ZAdd(zset_key, member_scores) => {
-            let mut zset = state.zsets.entry(zset_key).or_default();
-            let num_added = zset.add(member_scores);
-            ReturnValue::IntRes(num_added)
+        ZSetOps::ZAdd(zset_key, opts, member_scores) => {
+            let mut outcomes = Vec::with_capacity(member_scores.len());
+            {
+                let mut zset = state.zsets.entry(zset_key.clone()).or_default();
+                for (score, member) in member_scores {
+                    match zadd_member(&mut zset, &opts, score, member) {
+                        Ok(outcome) => outcomes.push(outcome),
+                        Err(e) => return ReturnValue::Error(e),
+                    }
+                }
+            }
+            // XX, or INCR failing, may leave nothing behind.
+            state.zsets.remove_if(&zset_key, |_, zset| zset.card() == 0);
+            let changed = |outcome: &ZAddOutcome| {
+                matches!(outcome, ZAddOutcome::Added(_) | ZAddOutcome::Updated(_))
+            };
+            if !outcomes.iter().any(changed) {
+                untouched();
+            }
+            if opts.incr {
+                return match outcomes.pop() {
+                    Some(ZAddOutcome::Added(score))
+                    | Some(ZAddOutcome::Updated(score))
+                    | Some(ZAddOutcome::Unchanged(score)) => {
+                        ReturnValue::StringRes(score.to_string().into())
+                    }
+                    _ => ReturnValue::Nil,
+                };
+            }
+            let counted = outcomes
+                .iter()
+                .filter(|outcome| match outcome {
+                    ZAddOutcome::Added(_) => true,
+                    ZAddOutcome::Updated(_) => opts.changed,
+                    _ => false,
+                })
+                .count();
+            ReturnValue::IntRes(counted as Count)
+        }
+        ZSetOps::ZRem(zset_key, keys) => {
+            let removed = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.remove(&keys))
+                .unwrap_or(0);
+            if removed == 0 {
+                untouched();
+            }
+            removed.into()
+        }
+        ZSetOps::ZRange(zset_key, start, stop, with_scores) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| zset.range_by_rank(start, stop, false))
+                .unwrap_or_default();
+            members_reply(members, with_scores)
+        }
+        ZSetOps::ZRevRange(zset_key, start, stop, with_scores) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| zset.range_by_rank(start, stop, true))
+                .unwrap_or_default();
+            members_reply(members, with_scores)
+        }
+        ZSetOps::ZRangeByScore(zset_key, min, max, opts) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| opts.apply(&zset, zset.score_ranks(&min, &max), false))
+                .unwrap_or_default();
+            members_reply(members, opts.with_scores)
+        }
+        ZSetOps::ZRevRangeByScore(zset_key, min, max, opts) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| opts.apply(&zset, zset.score_ranks(&min, &max), true))
+                .unwrap_or_default();
+            members_reply(members, opts.with_scores)
+        }
+        ZSetOps::ZRangeByLex(zset_key, min, max, opts) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| opts.apply(&zset, zset.lex_ranks(&min, &max), false))
+                .unwrap_or_default();
+            members_reply(members, false)
+        }
+        ZSetOps::ZRevRangeByLex(zset_key, min, max, opts) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| opts.apply(&zset, zset.lex_ranks(&min, &max), true))
+                .unwrap_or_default();
+            members_reply(members, false)
         }
-        ZSetOps::ZRem(zset_key, keys) => write_zsets!(state, &zset_key)
-            .map(|mut zset| zset.remove(&keys))
-            .unwrap_or(0)
-            .into(),
-        ZSetOps::ZRange(zset_key, start, stop) => read_zsets!(state, &zset_key)
-            .map(|zset| {
-                let (start, stop) = deal_with_negative_indices(zset.card(), (start, stop));
-                zset.range((start, stop))
-                    .into_iter()
-                    .map(|item| item.member)
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default()
-            .into(),
         ZSetOps::ZCard(zset_key) => read_zsets!(state, &zset_key)
             .map(|zset| zset.card())
             .unwrap_or(0)
             .into(),
         ZSetOps::ZScore(zset_key, member_key) => read_zsets!(state, &zset_key)
             .and_then(|zset| zset.score(member_key))
-            .map(ReturnValue::IntRes)
+            .map(|score| ReturnValue::StringRes(score.to_string().into()))
             .unwrap_or(ReturnValue::Nil),
-        ZSetOps::ZPopMax(zset_key, count) => write_zsets!(state, &zset_key)
-            .map(|mut zset| {
-                zset.pop_max(count)
-                    .into_iter()
-                    .fold(Vec::new(), |mut acc, zset_mem| {
-                        acc.push(ReturnValue::IntRes(zset_mem.score));
-                        acc.push(ReturnValue::StringRes(zset_mem.member.into()));
-                        acc
-                    })
-            })
-            .map(ReturnValue::Array)
-            .unwrap_or_else(|| ReturnValue::Array(vec![])),
-        ZSetOps::ZPopMin(zset_key, count) => write_zsets!(state, &zset_key)
-            .map(|mut zset| {
-                zset.pop_min(count)
-                    .into_iter()
-                    .fold(Vec::new(), |mut acc, zset_mem| {
-                        acc.push(ReturnValue::IntRes(zset_mem.score));
-                        acc.push(ReturnValue::StringRes(zset_mem.member.into()));
-                        acc
-                    })
-            })
-            .map(ReturnValue::Array)
-            .unwrap_or_else(|| ReturnValue::Array(vec![])),
+        ZSetOps::ZIncrBy(zset_key, by, member) => {
+            let score = state.zsets.entry(zset_key).or_default().incr(member, by);
+            match score {
+                Some(score) => ReturnValue::StringRes(score.to_string().into()),
+                None => ReturnValue::Error(b"ERR resulting score is not a number (NaN)"),
+            }
+        }
+        ZSetOps::ZCount(zset_key, min, max) => read_zsets!(state, &zset_key)
+            .map(|zset| zset.count_by_score(min, max))
+            .unwrap_or(0)
+            .into(),
+        ZSetOps::ZRemRangeByScore(zset_key, min, max) => {
+            let removed = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.remove_range_by_score(min, max))
+                .unwrap_or(0);
+            state.zsets.remove_if(&zset_key, |_, zset| zset.card() == 0);
+            if removed == 0 {
+                untouched();
+            }
+            removed.into()
+        }
+        ZSetOps::ZRemRangeByRank(zset_key, start, stop) => {
+            let removed = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.remove_range_by_rank(start, stop))
+                .unwrap_or(0);
+            state.zsets.remove_if(&zset_key, |_, zset| zset.card() == 0);
+            if removed == 0 {
+                untouched();
+            }
+            removed.into()
+        }
+        ZSetOps::ZPopMax(zset_key, count) => {
+            let popped = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.pop_max(count as usize))
+                .unwrap_or_default();
+            if popped.is_empty() {
+                untouched();
+            }
+            members_reply(popped, true)
+        }
+        ZSetOps::ZPopMin(zset_key, count) => {
+            let popped = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.pop_min(count as usize))
+                .unwrap_or_default();
+            if popped.is_empty() {
+                untouched();
+            }
+            members_reply(popped, true)
+        }
+        ZSetOps::ZScan(zset_key, cursor, opts) => {
+            let (next, members) = read_zsets!(state, &zset_key)
+                .map(|zset| {
+                    let members = zset.iter().map(|(m, score)| (m.clone(), *score));
+                    scan_members(members, |(m, _)| &m[..], cursor, &opts)
+                })
+                .unwrap_or_default();
+            let flat = members
+                .into_iter()
+                .flat_map(|(m, score)| vec![m, score.to_string().into()])
+                .collect();
+            scan_reply(next, flat)
+        }
         ZSetOps::ZRank(zset_key, mem_key) => read_zsets!(state, &zset_key)
             .and_then(|zset| zset.rank(mem_key))
             .map(ReturnValue::IntRes)
             .unwrap_or(ReturnValue::Nil),
     }
 }
+
+#[cfg(test)]
+mod test_sorted_sets {
+    use crate::data_structures::sorted_set::LexBound;
+    use crate::keys::SetCondition;
+    use crate::sorted_sets::{zset_interact, ScoreComparison, ZAddOptions, ZRangeOptions, ZSetOps};
+    use crate::types::{ReturnValue, Score, State, StateRef};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_zrange_with_scores() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let members = smallvec![
+            (1.into(), Bytes::from_static(b"a")),
+            (2.into(), Bytes::from_static(b"b")),
+        ];
+        zset_interact(
+            ZSetOps::ZAdd(key.clone(), Default::default(), members),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(
+            zset_interact(ZSetOps::ZRange(key.clone(), 0, 10, false), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZRange(key.clone(), 0, 10, true), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["a".into(), "1".into(), "b".into(), "2".into()])
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZRevRange(key, 0, 10, true), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["b".into(), "2".into(), "a".into(), "1".into()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zpop_replies_members_then_scores() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let members = smallvec![
+            (1.into(), Bytes::from_static(b"a")),
+            (2.into(), Bytes::from_static(b"b")),
+            (3.into(), Bytes::from_static(b"c")),
+        ];
+        zset_interact(
+            ZSetOps::ZAdd(key.clone(), Default::default(), members),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(
+            zset_interact(ZSetOps::ZPopMax(key.clone(), 2), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["c".into(), "3".into(), "b".into(), "2".into()])
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZPopMin(key.clone(), 10), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["a".into(), "1".into()])
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZPopMin(key, 1), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_members() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let member = Bytes::from_static(b"\xc3\x28");
+        let members = smallvec![
+            (1.into(), Bytes::from_static(b"a")),
+            (2.into(), member.clone())
+        ];
+        zset_interact(
+            ZSetOps::ZAdd(key.clone(), Default::default(), members),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(
+            zset_interact(ZSetOps::ZScore(key.clone(), member.clone()), eng.clone()).await,
+            ReturnValue::StringRes("2".into())
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZRank(key.clone(), member.clone()), eng.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZRange(key, 1, 1, false), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec![member])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zadd_options() {
+        let key = Bytes::from_static(b"key");
+        let eng: StateRef = Arc::new(State::default());
+        let a = Bytes::from_static(b"a");
+        let zadd = |opts: ZAddOptions, score: f64, member: &'static str| {
+            let members = smallvec![(
+                Score::new(score).unwrap(),
+                Bytes::from_static(member.as_bytes())
+            )];
+            zset_interact(ZSetOps::ZAdd(key.clone(), opts, members), eng.clone())
+        };
+        let score_of = || zset_interact(ZSetOps::ZScore(key.clone(), a.clone()), eng.clone());
+        let xx = ZAddOptions {
+            condition: SetCondition::IfExists,
+            ..Default::default()
+        };
+        assert_eq!(zadd(xx.clone(), 1.0, "a").await, ReturnValue::IntRes(0));
+        assert!(!eng.zsets.contains_key(&key));
+        assert_eq!(
+            zadd(Default::default(), 1.5, "a").await,
+            ReturnValue::IntRes(1)
+        );
+        let nx = ZAddOptions {
+            condition: SetCondition::IfMissing,
+            ..Default::default()
+        };
+        assert_eq!(zadd(nx, 3.0, "a").await, ReturnValue::IntRes(0));
+        assert_eq!(score_of().await, ReturnValue::StringRes("1.5".into()));
+
+        // CH counts updates, GT and LT only move scores one way.
+        let ch = ZAddOptions {
+            changed: true,
+            ..xx.clone()
+        };
+        assert_eq!(zadd(ch.clone(), 2.0, "a").await, ReturnValue::IntRes(1));
+        assert_eq!(zadd(ch.clone(), 2.0, "a").await, ReturnValue::IntRes(0));
+        let gt = ZAddOptions {
+            comparison: ScoreComparison::Greater,
+            ..ch.clone()
+        };
+        assert_eq!(zadd(gt.clone(), 1.0, "a").await, ReturnValue::IntRes(0));
+        assert_eq!(zadd(gt, f64::INFINITY, "a").await, ReturnValue::IntRes(1));
+        assert_eq!(score_of().await, ReturnValue::StringRes("inf".into()));
+        let lt = ZAddOptions {
+            comparison: ScoreComparison::Less,
+            ..ch
+        };
+        assert_eq!(zadd(lt, -2.5, "a").await, ReturnValue::IntRes(1));
+
+        // INCR replies with the new score, or nil if the options refused it.
+        let incr = ZAddOptions {
+            incr: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            zadd(incr.clone(), 0.5, "a").await,
+            ReturnValue::StringRes("-2".into())
+        );
+        assert_eq!(
+            zadd(incr.clone(), 4.0, "b").await,
+            ReturnValue::StringRes("4".into())
+        );
+        let incr_lt = ZAddOptions {
+            comparison: ScoreComparison::Less,
+            ..incr.clone()
+        };
+        assert_eq!(zadd(incr_lt, 1.0, "a").await, ReturnValue::Nil);
+        zadd(Default::default(), f64::INFINITY, "a").await;
+        assert!(zadd(incr, f64::NEG_INFINITY, "a").await.is_error());
+        assert_eq!(score_of().await, ReturnValue::StringRes("inf".into()));
+    }
+
+    #[tokio::test]
+    async fn test_zrange_by_score_and_lex() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let members = smallvec![
+            (1.into(), Bytes::from_static(b"a")),
+            (2.into(), Bytes::from_static(b"b")),
+            (3.into(), Bytes::from_static(b"c")),
+        ];
+        zset_interact(
+            ZSetOps::ZAdd(key.clone(), Default::default(), members),
+            eng.clone(),
+        )
+        .await;
+        let (one, three) = (Bound::Included(1.into()), Bound::Excluded(3.into()));
+        let op = ZSetOps::ZRangeByScore(key.clone(), one, three, Default::default());
+        assert_eq!(
+            zset_interact(op, eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["a".into(), "b".into()])
+        );
+        let opts = ZRangeOptions {
+            with_scores: true,
+            limit: Some((1, 1)),
+        };
+        let op = ZSetOps::ZRevRangeByScore(key.clone(), Bound::Unbounded, Bound::Unbounded, opts);
+        assert_eq!(
+            zset_interact(op, eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["b".into(), "2".into()])
+        );
+
+        let b = LexBound::Included(Bytes::from_static(b"b"));
+        let op = ZSetOps::ZRangeByLex(key.clone(), b.clone(), LexBound::Max, Default::default());
+        assert_eq!(
+            zset_interact(op, eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["b".into(), "c".into()])
+        );
+        let opts = ZRangeOptions {
+            limit: Some((0, -1)),
+            ..Default::default()
+        };
+        let op = ZSetOps::ZRevRangeByLex(key.clone(), LexBound::Min, b, opts);
+        assert_eq!(
+            zset_interact(op, eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["b".into(), "a".into()])
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZRevRange(key, -2, -1, false), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec!["b".into(), "a".into()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zincrby_zcount_and_range_removal() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let a = Bytes::from_static(b"a");
+        assert_eq!(
+            zset_interact(
+                ZSetOps::ZIncrBy(key.clone(), 2.into(), a.clone()),
+                eng.clone()
+            )
+            .await,
+            ReturnValue::StringRes("2".into())
+        );
+        let by = Score::new(1.5).unwrap();
+        assert_eq!(
+            zset_interact(ZSetOps::ZIncrBy(key.clone(), by, a.clone()), eng.clone()).await,
+            ReturnValue::StringRes("3.5".into())
+        );
+        let members = smallvec![
+            (1.into(), Bytes::from_static(b"b")),
+            (5.into(), Bytes::from_static(b"c")),
+        ];
+        zset_interact(
+            ZSetOps::ZAdd(key.clone(), Default::default(), members),
+            eng.clone(),
+        )
+        .await;
+        let (min, max) = (Bound::Excluded(1.into()), Bound::Included(5.into()));
+        assert_eq!(
+            zset_interact(ZSetOps::ZCount(key.clone(), min, max), eng.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        assert_eq!(
+            zset_interact(
+                ZSetOps::ZRemRangeByScore(key.clone(), min, max),
+                eng.clone()
+            )
+            .await,
+            ReturnValue::IntRes(2)
+        );
+        assert_eq!(
+            zset_interact(ZSetOps::ZRemRangeByRank(key.clone(), 0, -1), eng.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert!(!eng.zsets.contains_key(&key));
+    }
+}
@@ -1,26 +1,66 @@
 use crate::make_reader;
 use crate::op_variants;
-use crate::types::{Key, ReturnValue, StateRef, Value};
+use crate::ops::RVec;
+use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use crate::write_effects::untouched;
 
 op_variants! {
     StackOps,
-    STPush(Key, Value),
+    STPush(Key, RVec<Value>),
     STPop(Key),
+    STPopN(Key, Count),
     STPeek(Key),
     STSize(Key)
 }
 
+impl StackOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            StackOps::STPush(key, _) | StackOps::STPop(key) | StackOps::STPopN(key, _) => {
+                Some(vec![key.clone()])
+            }
+            StackOps::STPeek(_) | StackOps::STSize(_) => None,
+        }
+    }
+}
+
 make_reader!(stacks, read_stacks);
 
+/// Pop up to `count` values off `key`, top first. The stack is deleted once
+/// it's empty.
+fn pop(state: &StateRef, key: &[u8], count: usize) -> Vec<Value> {
+    let popped = match state.stacks.get_mut(key) {
+        Some(mut stack) => stack.pop_n(count),
+        None => return Vec::new(),
+    };
+    state.stacks.remove_if(key, |_, stack| stack.is_empty());
+    popped
+}
+
 pub async fn stack_interact(stack_op: StackOps, state: StateRef) -> ReturnValue {
     match stack_op {
-        StackOps::STPush(key, value) => state.stacks.entry(key).or_default().push(value).into(),
-        StackOps::STPop(key) => state
-            .stacks
-            .get_mut(&key)
-            .and_then(|mut st| st.pop())
-            .map(ReturnValue::StringRes)
-            .unwrap_or(ReturnValue::Nil),
+        StackOps::STPush(key, values) => {
+            let mut stack = state.stacks.entry(key).or_default();
+            for value in values {
+                stack.push(value);
+            }
+            ReturnValue::IntRes(stack.size())
+        }
+        StackOps::STPop(key) => match pop(&state, &key, 1).pop() {
+            Some(value) => ReturnValue::StringRes(value),
+            None => {
+                untouched();
+                ReturnValue::Nil
+            }
+        },
+        StackOps::STPopN(key, count) => {
+            let popped = pop(&state, &key, count as usize);
+            if popped.is_empty() {
+                untouched();
+            }
+            ReturnValue::MultiStringRes(popped)
+        }
         StackOps::STPeek(key) => read_stacks!(state, &key)
             .and_then(|st| st.peek())
             .map(ReturnValue::StringRes)
@@ -31,3 +71,45 @@ pub async fn stack_interact(stack_op: StackOps, state: StateRef) -> ReturnValue
             .unwrap_or(ReturnValue::Nil),
     }
 }
+
+#[cfg(test)]
+mod test_stack {
+    use crate::stack::{stack_interact, StackOps};
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_push_popn_removes_empty() {
+        let key = Bytes::from_static(b"stack");
+        let (a, b, c) = (
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"c"),
+        );
+        let eng = Arc::new(State::default());
+        let push = StackOps::STPush(key.clone(), smallvec![a.clone(), b.clone(), c.clone()]);
+        assert_eq!(
+            stack_interact(push, eng.clone()).await,
+            ReturnValue::IntRes(3)
+        );
+        assert_eq!(
+            stack_interact(StackOps::STPopN(key.clone(), 2), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec![c, b])
+        );
+        assert_eq!(
+            stack_interact(StackOps::STPop(key.clone()), eng.clone()).await,
+            ReturnValue::StringRes(a)
+        );
+        assert!(!eng.stacks.contains_key(&key));
+        assert_eq!(
+            stack_interact(StackOps::STPopN(key.clone(), 1), eng.clone()).await,
+            ReturnValue::MultiStringRes(vec![])
+        );
+        assert_eq!(
+            stack_interact(StackOps::STSize(key), eng.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+}
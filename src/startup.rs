@@ -1,8 +1,16 @@
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
+use crate::eviction::POLICIES;
 use crate::logger::LOGGER;
+use crate::types::Index;
+use std::io::{self, Write};
+use std::net::IpAddr;
 use std::path::PathBuf;
 
+/// Targets accepted by --generate-completions.
+pub const COMPLETION_TARGETS: &[&str] = &["bash", "zsh", "fish", "man"];
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "redis-oxide",
@@ -19,11 +27,208 @@ pub struct Config {
     pub ops_until_save: u64,
     #[structopt(short = "p", long = "port", default_value = "6379")]
     pub port: u64,
+    /// Addresses to listen on, e.g. `--bind 127.0.0.1 ::1`. 0.0.0.0 (or ::) listens on every interface
+    #[structopt(long = "bind", default_value = "127.0.0.1")]
+    pub bind: Vec<IpAddr>,
+    /// Without a password, only accept clients connecting from the loopback interface
+    #[structopt(long = "protected-mode", default_value = "yes", possible_values = &["yes", "no"])]
+    pub protected_mode: String,
     /// Run in memory only mode. Don't save database state to disk
     #[structopt(short = "m", long = "memory-only")]
     pub memory_only: bool,
     #[structopt(short = "f", long = "scripts-dir")]
     pub scripts_dir: Option<std::path::PathBuf>,
+    /// Scripting backend to use. "wasm" requires building with the "wasm" feature
+    #[structopt(long = "scripting-engine", default_value = "x7", possible_values = &["x7", "wasm"])]
+    pub scripting_engine: String,
+    /// Run commands from scripts on the script's own thread instead of a shared loop, so each
+    /// program reads its own writes and keeps its own SELECTed db
+    #[structopt(long = "script-read-your-writes")]
+    pub script_read_your_writes: bool,
+    /// Run each script as one atomic unit: other clients' commands wait until it's done
+    #[structopt(long = "atomic-scripts")]
+    pub atomic_scripts: bool,
+    /// Milliseconds an atomic script may run before other clients' commands get BUSY errors
+    /// instead of waiting. Kill it with SCRIPT KILL. 0 waits forever.
+    #[structopt(long = "script-time-limit", default_value = "5000")]
+    pub script_time_limit: u64,
+    /// Bytes of memory each WASM program may grow to: memory.grow past it fails, and modules
+    /// starting out bigger aren't instantiated. 0 disables the limit.
+    #[structopt(long = "script-max-memory", default_value = "67108864")]
+    pub script_max_memory: u64,
+    /// Elements each table of a WASM program may grow to. 0 disables the limit.
+    #[structopt(long = "script-max-table-elements", default_value = "10000")]
+    pub script_max_table_elements: u32,
+    /// Maximum size in bytes of a single reply. Bigger replies are replaced with an error. 0 disables the limit.
+    #[structopt(long = "max-reply-bytes", default_value = "0")]
+    pub max_reply_bytes: u64,
+    /// Log a warning when a reply is at least this many bytes. 0 disables the warning.
+    #[structopt(long = "big-reply-bytes", default_value = "1048576")]
+    pub big_reply_bytes: u64,
+    /// Append every committed write as a line of JSON to this file (change data capture)
+    #[structopt(long = "cdc-file", parse(from_os_str))]
+    pub cdc_file: Option<PathBuf>,
+    /// Stream every committed write as a line of JSON to this TCP address (change data capture)
+    #[structopt(long = "cdc-tcp")]
+    pub cdc_tcp: Option<String>,
+    /// Remember up to this many missed keys per database to answer repeated misses cheaply. 0 disables it
+    #[structopt(long = "negative-cache-size", default_value = "0")]
+    pub negative_cache_size: usize,
+    /// Serve clients over TLS with this PEM certificate chain (needs the "tls" feature)
+    #[structopt(long = "tls-cert", parse(from_os_str), requires = "tls-key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls-cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Serve a HTTP/JSON API on this port (needs the "http" feature)
+    #[structopt(long = "http-port")]
+    pub http_port: Option<u16>,
+    /// Directory to persist backed key prefixes to
+    #[structopt(long = "backing-dir", parse(from_os_str))]
+    pub backing_dir: Option<PathBuf>,
+    /// Persist string keys starting with PREFIX to the backing store, and load them on a miss.
+    /// Given as PREFIX=through (write on every change) or PREFIX=behind (write in batches). Can be repeated
+    #[structopt(long = "backing-prefix", number_of_values = 1)]
+    pub backing_prefixes: Vec<String>,
+    /// How often write-behind keys are flushed to the backing store, in milliseconds
+    #[structopt(long = "write-behind-ms", default_value = "1000")]
+    pub write_behind_ms: u64,
+    /// Listen on this unix socket for a new process to hand our state off to
+    #[structopt(long = "handoff-socket", parse(from_os_str))]
+    pub handoff_socket: Option<PathBuf>,
+    /// Take over the state of the process listening on this unix socket instead of loading the dump file
+    #[structopt(long = "handoff-from", parse(from_os_str))]
+    pub handoff_from: Option<PathBuf>,
+    /// Load dumps (or handoffs) with structures this version can't read by dropping those structures
+    #[structopt(long = "lossy-load")]
+    pub lossy_load: bool,
+    /// Record events (commands, saves) slower than this many milliseconds for LATENCY. 0 disables it
+    #[structopt(long = "latency-monitor-threshold", default_value = "0")]
+    pub latency_monitor_threshold: u64,
+    /// Require clients to AUTH with this password before running any other command
+    #[structopt(long = "requirepass")]
+    pub requirepass: Option<String>,
+    /// Log commands taking at least this many microseconds for SLOWLOG. Negative disables it, 0 logs every command
+    #[structopt(
+        long = "slowlog-log-slower-than",
+        default_value = "10000",
+        allow_hyphen_values = true
+    )]
+    pub slowlog_log_slower_than: i64,
+    /// How many slow commands SLOWLOG keeps
+    #[structopt(long = "slowlog-max-len", default_value = "128")]
+    pub slowlog_max_len: usize,
+    /// Longest bulk string a client may send, in bytes. 0 disables the limit
+    #[structopt(long = "proto-max-bulk-len", default_value = "536870912")]
+    pub proto_max_bulk_len: u64,
+    /// Most arguments (array elements) a client may send in one command. 0 disables the limit
+    #[structopt(long = "proto-max-array-len", default_value = "1048576")]
+    pub proto_max_array_len: u64,
+    /// Guard FLUSHALL/FLUSHDB: "confirm" requires a trailing CONFIRM argument, "disabled" refuses them
+    #[structopt(long = "protect-flush", default_value = "off", possible_values = &["off", "confirm", "disabled"])]
+    pub protect_flush: String,
+    /// What PFADD's reply means: "registers" (like redis) is 1 if any register changed, "cardinality" is 1 if the estimate changed
+    #[structopt(long = "pfadd-reply", default_value = "registers", possible_values = &["registers", "cardinality"])]
+    pub pfadd_reply: String,
+    /// Before serving, apply the commands (RESP or inline) read from stdin, like `redis-cli --pipe`
+    #[structopt(long = "pipe")]
+    pub pipe: bool,
+    /// Check that every inline command in this file parses, report errors with line numbers, and exit
+    #[structopt(long = "validate-commands", parse(from_os_str))]
+    pub validate_commands: Option<PathBuf>,
+    /// Apply `name value` lines from this file at startup, like CONFIG SET. CONFIG REWRITE saves to it
+    #[structopt(long = "config-file", parse(from_os_str))]
+    pub config_file: Option<PathBuf>,
+    /// Load this redis RDB file instead of the dump file, to migrate from redis
+    #[structopt(long = "import-rdb", parse(from_os_str))]
+    pub import_rdb: Option<PathBuf>,
+    /// Write the loaded state to this file as a redis RDB file and exit. Bloom and cuckoo filters, count-min sketches, top-k trackers, stacks, queues, hyperloglogs and streams are left out
+    #[structopt(long = "export-rdb", parse(from_os_str))]
+    pub export_rdb: Option<PathBuf>,
+    /// Log every write to appendonly.aof next to the dump file, and load from it instead of the dump on startup
+    #[structopt(long = "appendonly")]
+    pub appendonly: bool,
+    /// When the append-only file is synced to disk: after every write, once a second, or whenever the OS likes
+    #[structopt(long = "appendfsync", default_value = "everysec", possible_values = &["always", "everysec", "no"])]
+    pub appendfsync: String,
+    /// How dump files are split: every db in dump.rodb ("single"), a file per db ("db"), or a file per structure of each db ("structure")
+    #[structopt(long = "dump-layout", default_value = "single", possible_values = &["single", "db", "structure"])]
+    pub dump_layout: String,
+    /// Only save (and log to the append-only file) these dbs, e.g. `--persist-dbs 0,1`. Every db is by default
+    #[structopt(long = "persist-dbs", use_delimiter = true)]
+    pub persist_dbs: Vec<Index>,
+    /// Evict keys (or refuse writes) once the estimated size of every db passes this many bytes. 0 disables the limit
+    #[structopt(long = "maxmemory", default_value = "0")]
+    pub maxmemory: u64,
+    /// Which keys go once over --maxmemory: none (refusing writes instead), random ones, the least recently used, or those expiring soonest
+    #[structopt(long = "maxmemory-policy", default_value = "noeviction", possible_values = POLICIES)]
+    pub maxmemory_policy: String,
+    /// How many keys of each db are sampled to pick one to evict. More are closer to the true least recently used (or soonest expiring) key, but slower
+    #[structopt(long = "maxmemory-samples", default_value = "5")]
+    pub maxmemory_samples: usize,
+    /// Replicate from the primary at HOST:PORT, refusing client writes until REPLICAOF NO ONE. The link isn't encrypted, even with TLS
+    #[structopt(long = "replicaof")]
+    pub replicaof: Option<String>,
+    /// The password to AUTH with to the primary, when it has a --requirepass
+    #[structopt(long = "masterauth")]
+    pub masterauth: Option<String>,
+    /// Bytes of recent writes kept so replicas that reconnect can continue instead of resyncing
+    #[structopt(long = "repl-backlog-size", default_value = "1048576")]
+    pub repl_backlog_size: usize,
+    /// Print shell completions (bash, zsh, fish) or a man page (man) to stdout and exit
+    #[structopt(long = "generate-completions", possible_values = COMPLETION_TARGETS)]
+    pub generate_completions: Option<String>,
+}
+
+/// Escape text so roff prints it verbatim.
+fn roff_escape(line: &str) -> String {
+    let escaped = line.replace('\\', "\\e");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{}", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// Write a man page built from the Config's help text.
+fn write_man_page<W: Write>(out: &mut W) -> io::Result<()> {
+    let mut help = Vec::new();
+    Config::clap()
+        .write_long_help(&mut help)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let name = env!("CARGO_PKG_NAME");
+    writeln!(
+        out,
+        ".TH {} 1 \"\" \"{} {}\"",
+        name.to_uppercase(),
+        name,
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(
+        out,
+        "{} \\- {}",
+        name,
+        roff_escape(env!("CARGO_PKG_DESCRIPTION"))
+    )?;
+    writeln!(out, ".SH DESCRIPTION")?;
+    writeln!(out, ".nf")?;
+    for line in String::from_utf8_lossy(&help).lines() {
+        writeln!(out, "{}", roff_escape(line))?;
+    }
+    writeln!(out, ".fi")
+}
+
+/// Write completions for the given shell, or a man page for "man".
+pub fn generate_completions<W: Write>(target: &str, out: &mut W) -> io::Result<()> {
+    if target == "man" {
+        return write_man_page(out);
+    }
+    let shell: Shell = target
+        .parse()
+        .map_err(|e: String| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Config::clap().gen_completions_to(env!("CARGO_PKG_NAME"), shell, out);
+    Ok(())
 }
 
 pub fn startup_message(config: &Config) {
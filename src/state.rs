@@ -1,6 +1,17 @@
 use crate::data_structures::receipt_map::{KeyTypes, Receipt};
-use crate::types::{Index, ReturnValue, State, StateRef, StateStore};
+use crate::escape::{Escaped, EscapedList};
+use crate::for_each_structure;
+use crate::keyspace_events;
+use crate::memory::recount_in_background;
+use crate::types::{Index, Key, ReturnValue, State, StateRef, StateStore};
+use crate::write_effects::WriteEffects;
+use dashmap::mapref::entry::Entry;
+use dashmap::{DashMap, SharedValue};
+use rand::{thread_rng, Rng};
 use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
 
 const DEFAULT_DB: Index = 0;
 
@@ -8,43 +19,448 @@ impl fmt::Display for ReturnValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ReturnValue::Ok => write!(f, "OK"),
-            ReturnValue::StringRes(s) => write!(f, "{:?}", s),
+            ReturnValue::StringRes(s) => write!(f, "{}", Escaped(s)),
             ReturnValue::IntRes(i) => write!(f, "{:?}", i),
-            ReturnValue::MultiStringRes(ss) => write!(f, "{:?}", ss),
+            ReturnValue::MultiStringRes(ss) => write!(f, "{}", EscapedList(ss)),
             ReturnValue::Nil => write!(f, "(nil)"),
-            ReturnValue::Error(e) => write!(f, "ERR {:?}", e),
-            ReturnValue::Array(a) => write!(f, "{:?}", a),
+            ReturnValue::Error(e) => write!(f, "ERR {}", Escaped(e)),
+            ReturnValue::Array(a) => {
+                write!(f, "[")?;
+                for (i, item) in a.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
             ReturnValue::Ident(r) => write!(f, "{:?}", r),
         }
     }
 }
 
+/// Clear each passed structure, collecting how many keys it held.
+macro_rules! clear_structures {
+    ($state:expr, $($type:ident: $schema:expr),*) => {
+        vec![$(
+            {
+                let dropped = $state.$type.len();
+                $state.$type.clear();
+                (stringify!($type), dropped)
+            }
+        ),*]
+    };
+}
+
+/// Return the name of the first structure holding the key.
+macro_rules! find_structure {
+    ($state:expr, $key:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            if $state.$type.contains_key($key) {
+                return Some(stringify!($type));
+            }
+        )*
+    };
+}
+
+/// Remove the key from each passed structure, returning whether any held it.
+macro_rules! remove_from_structures {
+    ($state:expr, $key:expr, $($type:ident: $schema:expr),*) => {
+        {
+            let mut removed = false;
+            $(
+                removed |= $state.$type.remove($key).is_some();
+            )*
+            removed
+        }
+    };
+}
+
+/// Every key of the passed structures, one after the other.
+macro_rules! chain_keys {
+    ($state:expr, $first:ident: $first_schema:expr $(, $type:ident: $schema:expr)*) => {
+        $state
+            .$first
+            .iter()
+            .map(|entry| entry.key().clone())
+            $(.chain($state.$type.iter().map(|entry| entry.key().clone())))*
+    };
+}
+
+/// Return a random key of the structure the `n`th key falls in, counting
+/// through the passed structures in order.
+macro_rules! random_in_structures {
+    ($state:expr, $n:expr, $($type:ident: $schema:expr),*) => {
+        let n = $n;
+        $(
+            let len = $state.$type.len();
+            if n < len {
+                return random_in_map(&$state.$type);
+            }
+            let n = n - len;
+        )*
+        let _ = n;
+    };
+}
+
+/// Move the key from one db to the other, returning whether any structure held it.
+macro_rules! move_between_structures {
+    ($from:expr, $to:expr, $key:expr, $($type:ident: $schema:expr),*) => {
+        {
+            let mut moved = false;
+            $(
+                if $from.$type.contains_key($key) {
+                    moved |= move_entry(&$from.$type, &$to.$type, $key);
+                }
+            )*
+            moved
+        }
+    };
+}
+
+/// Move every entry of the passed structures from one db to the other.
+macro_rules! move_structures {
+    ($from:expr, $to:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            let keys: Vec<Key> = $from.$type.iter().map(|entry| entry.key().clone()).collect();
+            for key in keys {
+                if let Some((key, value)) = $from.$type.remove(&key) {
+                    $to.$type.insert(key, value);
+                }
+            }
+        )*
+    };
+}
+
+/// Sum how many keys each passed structure holds.
+macro_rules! count_structures {
+    ($state:expr, $($type:ident: $schema:expr),*) => {
+        0 $(+ $state.$type.len())*
+    };
+}
+
+/// A random key of `map`. A shard is picked by how many keys it holds, and
+/// only that shard is walked.
+fn random_in_map<V>(map: &DashMap<Key, V>) -> Option<Key> {
+    let shards = map.shards();
+    let lens: Vec<usize> = shards.iter().map(|shard| shard.read().len()).collect();
+    let total: usize = lens.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut n = thread_rng().gen_range(0, total);
+    for (shard, len) in shards.iter().zip(lens) {
+        if n < len {
+            return shard.read().keys().nth(n).cloned();
+        }
+        n -= len;
+    }
+    None
+}
+
+/// `n` keys of `map` picked at random, or every key if it has no more.
+/// Picks may repeat.
+pub fn sample_keys<V>(map: &DashMap<Key, V>, n: usize) -> Vec<Key> {
+    if map.len() <= n {
+        return map.iter().map(|entry| entry.key().clone()).collect();
+    }
+    (0..n).filter_map(|_| random_in_map(map)).collect()
+}
+
+/// Move `key` from `from` to `to`, unless `to` has it already. Both entries
+/// are held at once, so a key written to `to` meanwhile isn't overwritten.
+/// They're taken in address order, so moves going opposite ways can't
+/// deadlock.
+fn move_entry<V>(from: &DashMap<Key, V>, to: &DashMap<Key, V>, key: &[u8]) -> bool {
+    if std::ptr::eq(from, to) {
+        return false;
+    }
+    let key = Key::copy_from_slice(key);
+    let (source, dest) = if (from as *const DashMap<Key, V>) < (to as *const DashMap<Key, V>) {
+        let source = from.entry(key.clone());
+        (source, to.entry(key))
+    } else {
+        let dest = to.entry(key.clone());
+        (from.entry(key), dest)
+    };
+    match (source, dest) {
+        (Entry::Occupied(source), Entry::Vacant(dest)) => {
+            dest.insert(source.remove());
+            true
+        }
+        _ => false,
+    }
+}
+
 impl State {
+    /// Number of keys, across every structure.
+    pub fn key_count(&self) -> usize {
+        for_each_structure!(count_structures!(self,))
+    }
+
+    /// A key picked uniformly at random, across every structure. Only the
+    /// structure and shard it lands in are walked, not every key before it.
+    pub fn random_key(&self) -> Option<Key> {
+        let count = self.key_count();
+        if count == 0 {
+            return None;
+        }
+        let n = thread_rng().gen_range(0, count);
+        // Keys may have gone since counting; that's just a miss.
+        for_each_structure!(random_in_structures!(self, n,));
+        None
+    }
+
+    /// Every key, across every structure. Keys written meanwhile may or may not show up.
+    pub fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        for_each_structure!(chain_keys!(self,))
+    }
+
+    /// Name of the structure holding `key`, if any.
+    pub fn structure_of(&self, key: &[u8]) -> Option<&'static str> {
+        for_each_structure!(find_structure!(self, key,));
+        None
+    }
+
+    /// The TYPE of `key`: redis' name for its structure, or "none".
+    ///
+    /// Hyperloglogs are strings in redis, so they're reported as such.
+    /// Blooms use RedisBloom's name.
+    pub fn type_name(&self, key: &[u8]) -> &'static str {
+        match self.structure_of(key) {
+            Some("kv") | Some("hyperloglogs") => "string",
+            Some("sets") => "set",
+            Some("lists") => "list",
+            Some("hashes") => "hash",
+            Some("zsets") => "zset",
+            Some("blooms") => "MBbloom--",
+            Some("stacks") => "stack",
+            Some("streams") => "stream",
+            Some("cuckoos") => "MBbloomCF",
+            Some("count_mins") => "CMSk-TYPE",
+            Some("topks") => "TopK-TYPE",
+            Some("queues") => "queue",
+            Some(other) => unreachable!("unnamed structure {}", other),
+            None => "none",
+        }
+    }
+
+    /// The closest redis OBJECT ENCODING for `key`, if it exists. Strings
+    /// are classified like redis does; other structures report the encoding
+    /// redis uses once they outgrow their compact form.
+    pub fn encoding(&self, key: &[u8]) -> Option<&'static str> {
+        if let Some(value) = self.kv.get(key) {
+            // Redis only keeps canonical integers ("12", not "012") as ints.
+            let is_int = std::str::from_utf8(&value)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .map_or(false, |i| value[..] == *i.to_string().as_bytes());
+            return Some(if is_int {
+                "int"
+            } else if value.len() <= 44 {
+                "embstr"
+            } else {
+                "raw"
+            });
+        }
+        Some(match self.structure_of(key)? {
+            "sets" | "hashes" => "hashtable",
+            "lists" => "quicklist",
+            "zsets" => "skiplist",
+            "streams" => "stream",
+            _ => "raw",
+        })
+    }
+
+    /// Remove `key` from every structure, with its deadline. Returns whether it existed.
+    pub fn remove_key(&self, key: &[u8]) -> bool {
+        self.clear_expiry(key);
+        for_each_structure!(remove_from_structures!(self, key,))
+    }
+
+    /// Move `key`, with its deadline, to `dest`. Returns false if it isn't
+    /// here, or `dest` has it already.
+    pub fn move_key(&self, key: &[u8], dest: &State) -> bool {
+        dest.expire_if_due(key);
+        if dest.structure_of(key).is_some() {
+            return false;
+        }
+        let moved = for_each_structure!(move_between_structures!(self, dest, key,));
+        if !moved {
+            return false;
+        }
+        if let Some((key, deadline)) = self.expirations.remove(key) {
+            dest.expirations.insert(key, deadline);
+        }
+        true
+    }
+
+    /// Remove every key, returning how many were dropped from each structure.
+    pub fn clear(&self) -> Vec<(&'static str, usize)> {
+        self.expirations.clear();
+        for_each_structure!(clear_structures!(self,))
+    }
+
+    /// Replace every key with the keys of `other`, deadlines included,
+    /// leaving `other` empty.
+    pub fn replace_with(&self, other: &State) {
+        self.clear();
+        for_each_structure!(with_expirations, move_structures!(other, self,));
+        WriteEffects::new("FlushAll", Vec::new()).apply(self);
+    }
+
     pub fn get_receipt(&self) -> Receipt {
         let mut rm = self.reciept_map.lock();
         rm.get_receipt()
     }
 
-    pub fn receipt_timed_out(&self, receipt: Receipt) -> bool {
-        let rm = self.reciept_map.lock();
-        rm.receipt_timed_out(receipt)
-    }
-
     pub fn wake_list(&self, list_key: &[u8]) {
         let mut rm = self.reciept_map.lock();
         rm.wake_with_key(KeyTypes::list(list_key));
     }
+
+    /// Wake every XREAD waiting on `stream_key`.
+    pub fn wake_stream(&self, stream_key: &[u8]) {
+        let mut rm = self.reciept_map.lock();
+        rm.wake_all_with_key(KeyTypes::stream(stream_key));
+    }
+
+    /// Wake every QPOP waiting on `queue_key`, when jobs are pushed or due
+    /// back.
+    pub fn wake_queue(&self, queue_key: &[u8]) {
+        let mut rm = self.reciept_map.lock();
+        rm.wake_all_with_key(KeyTypes::queue(queue_key));
+    }
 }
 
 impl StateStore {
     pub fn get_or_create(&self, index: Index) -> StateRef {
-        self.states.entry(index).or_default().clone()
+        self.states
+            .entry(index)
+            .or_insert_with(|| self.new_state())
+            .clone()
+    }
+
+    /// Create an empty db configured like the rest of the store.
+    fn new_state(&self) -> StateRef {
+        let state = State::default();
+        state
+            .negative_cache
+            .set_capacity(self.config.negative_cache_size());
+        state.writes.keep_events(keyspace_events::enabled(
+            self.config.notify_keyspace_events(),
+        ));
+        state
+            .pfadd_cardinality_reply
+            .store(self.pfadd_cardinality_reply, Ordering::Relaxed);
+        Arc::new(state)
     }
 
     pub fn get_default(&self) -> StateRef {
         self.get_or_create(DEFAULT_DB)
     }
 
+    /// Every db with its index, in order. Holds no lock on `states` once
+    /// it returns, so dbs can be created while these are worked through.
+    pub fn dbs(&self) -> Vec<(Index, StateRef)> {
+        let mut dbs: Vec<(Index, StateRef)> = self
+            .states
+            .iter()
+            .map(|db| (*db.key(), db.value().clone()))
+            .collect();
+        dbs.sort_unstable_by_key(|(index, _)| *index);
+        dbs
+    }
+
+    /// Exchange dbs `a` and `b`. Connections follow by index, see `SelectedDb`.
+    ///
+    /// Both indices change under one lock of their shards, so nobody sees
+    /// the same db at both. The shards are locked in order, and a shard they
+    /// share only once.
+    pub fn swap_dbs(&self, a: Index, b: Index) {
+        if a == b {
+            return;
+        }
+        let _swapping = self.swapping_dbs.lock();
+        let (state_a, state_b) = (self.get_or_create(a), self.get_or_create(b));
+        {
+            let shards = self.states.shards();
+            let (shard_a, shard_b) = (self.states.determine_map(&a), self.states.determine_map(&b));
+            let first = shard_a.min(shard_b);
+            let mut low = shards[first].write();
+            let mut high = if shard_a == shard_b {
+                None
+            } else {
+                Some(shards[shard_a.max(shard_b)].write())
+            };
+            let dbs = match high.as_mut() {
+                Some(high) if shard_a != first => high,
+                _ => &mut low,
+            };
+            dbs.insert(a, SharedValue::new(state_b.clone()));
+            let dbs = match high.as_mut() {
+                Some(high) if shard_b != first => high,
+                _ => &mut low,
+            };
+            dbs.insert(b, SharedValue::new(state_a.clone()));
+        }
+        self.db_swaps.fetch_add(1, Ordering::SeqCst);
+        // Every key of both dbs changed, as far as anyone watching is concerned.
+        WriteEffects::new("SwapDB", Vec::new()).apply(&state_a);
+        WriteEffects::new("SwapDB", Vec::new()).apply(&state_b);
+    }
+
+    /// Find the index of the given db, if it's still in the store.
+    pub fn db_index(&self, state: &StateRef) -> Option<Index> {
+        self.states
+            .iter()
+            .find(|db| Arc::ptr_eq(db.value(), state))
+            .map(|db| *db.key())
+    }
+
+    /// Replace the contents of every db with those of `other`.
+    pub fn replace_with(&self, other: &StateStore) {
+        let indices: Vec<Index> = self.states.iter().map(|db| *db.key()).collect();
+        for index in indices {
+            if !other.states.contains_key(&index) {
+                let state = self.get_or_create(index);
+                state.clear();
+                WriteEffects::new("FlushAll", Vec::new()).apply(&state);
+                recount_in_background(state);
+            }
+        }
+        for db in other.states.iter() {
+            let state = self.get_or_create(*db.key());
+            state.replace_with(db.value());
+            recount_in_background(state);
+        }
+    }
+
+    /// Wait until a write may run. Writes hold this until they're logged to
+    /// the AOF and fed to replicas, so those see them in the order they
+    /// landed. Without either, writes only share it, and run side by side.
+    ///
+    /// Commands share `write_order` from before checking whether we're
+    /// `feeding` replicas. A replica's snapshot waits for it, so the writes
+    /// which saw we weren't are in the snapshot, and the rest are fed.
+    pub async fn order_write<'a>(
+        &'a self,
+        shared: RwLockReadGuard<'a, ()>,
+        feeding: bool,
+    ) -> WriteOrder<'a> {
+        if self.aof.get().is_none() && !feeding {
+            return WriteOrder::Shared { _guard: shared };
+        }
+        drop(shared);
+        WriteOrder::Exclusive {
+            _guard: self.write_order.write().await,
+        }
+    }
+
+    pub fn scripting_enabled(&self) -> bool {
+        !self.scripting_disabled.load(Ordering::Relaxed)
+    }
+
     pub fn contains_foreign_function(&self, function_symbol: &str) -> bool {
         self.foreign_functions.read().contains(function_symbol)
     }
@@ -54,4 +470,56 @@ impl StateStore {
             .write()
             .insert(function_symbol.into());
     }
+
+    /// Stop routing `function_symbol` to scripting. Returns false if it
+    /// wasn't a foreign function.
+    pub fn remove_foreign_function(&self, function_symbol: &str) -> bool {
+        self.foreign_functions.write().remove(function_symbol)
+    }
+}
+
+/// Held by a write while it runs, see `StateStore::order_write`.
+pub enum WriteOrder<'a> {
+    Shared { _guard: RwLockReadGuard<'a, ()> },
+    Exclusive { _guard: RwLockWriteGuard<'a, ()> },
+}
+
+/// The db a connection has selected. Held by index, so that after a SWAPDB
+/// the connection uses whichever db has that index now.
+pub struct SelectedDb {
+    index: Index,
+    state: StateRef,
+    swaps: u64,
+}
+
+impl SelectedDb {
+    pub fn new(state_store: &StateStore) -> Self {
+        SelectedDb {
+            index: DEFAULT_DB,
+            state: state_store.get_default(),
+            swaps: state_store.db_swaps.load(Ordering::SeqCst),
+        }
+    }
+
+    /// The selected db, looked up again if dbs were swapped since last time.
+    pub fn state(&mut self, state_store: &StateStore) -> StateRef {
+        let swaps = state_store.db_swaps.load(Ordering::SeqCst);
+        if swaps != self.swaps {
+            self.swaps = swaps;
+            self.state = state_store.get_or_create(self.index);
+        }
+        self.state.clone()
+    }
+
+    pub fn index(&self) -> Index {
+        self.index
+    }
+
+    /// Remember the db a command left selected, in case it was a SELECT.
+    pub fn selected(&mut self, state_store: &StateStore, state: StateRef) {
+        if !Arc::ptr_eq(&state, &self.state) {
+            self.index = state_store.db_index(&state).unwrap_or(self.index);
+            self.state = state;
+        }
+    }
 }
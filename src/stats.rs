@@ -0,0 +1,444 @@
+//! Runtime statistics collected while serving commands.
+
+use crate::expiry::now_ms;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Smallest reply size bucket is 2^MIN_BUCKET_POW bytes.
+const MIN_BUCKET_POW: u32 = 6;
+/// Number of reply size buckets. The last bucket holds everything larger.
+const NUM_BUCKETS: usize = 20;
+
+/// Power-of-two histogram of reply sizes (in bytes).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReplySizeHistogram {
+    buckets: [u64; NUM_BUCKETS],
+    calls: u64,
+    total_bytes: u64,
+    max_bytes: usize,
+}
+
+impl ReplySizeHistogram {
+    fn bucket_index(size: usize) -> usize {
+        let pow = (usize::BITS - size.leading_zeros()).saturating_sub(MIN_BUCKET_POW);
+        std::cmp::min(pow as usize, NUM_BUCKETS - 1)
+    }
+
+    /// Record a reply of `size` bytes.
+    pub fn record(&mut self, size: usize) {
+        self.buckets[Self::bucket_index(size)] += 1;
+        self.calls += 1;
+        self.total_bytes = self.total_bytes.saturating_add(size as u64);
+        self.max_bytes = std::cmp::max(self.max_bytes, size);
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.calls
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Iterate over (exclusive upper bound in bytes, count) for each non-empty bucket.
+    /// The last bucket is unbounded and reported with `usize::MAX`.
+    pub fn buckets(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count != 0)
+            .map(|(idx, &count)| {
+                let bound = if idx == NUM_BUCKETS - 1 {
+                    usize::MAX
+                } else {
+                    1 << (idx as u32 + MIN_BUCKET_POW)
+                };
+                (bound, count)
+            })
+    }
+}
+
+/// Commands run in the current and the previous second, for ops/sec.
+#[derive(Debug, Default)]
+struct OpsMeter {
+    /// Unix time, in seconds, `current` counts commands of.
+    second: AtomicU64,
+    current: AtomicU64,
+    previous: AtomicU64,
+}
+
+impl OpsMeter {
+    fn record(&self, now_sec: u64) {
+        let second = self.second.load(Ordering::Relaxed);
+        if second != now_sec
+            && self
+                .second
+                .compare_exchange(second, now_sec, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let count = self.current.swap(0, Ordering::Relaxed);
+            let previous = if now_sec == second + 1 { count } else { 0 };
+            self.previous.store(previous, Ordering::Relaxed);
+        }
+        self.current.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Commands run in the last full second.
+    fn per_sec(&self, now_sec: u64) -> u64 {
+        let second = self.second.load(Ordering::Relaxed);
+        if second == now_sec {
+            self.previous.load(Ordering::Relaxed)
+        } else if second + 1 == now_sec {
+            self.current.load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+}
+
+/// How far a running save got, for INFO persistence.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SaveProgress {
+    pub secs: u64,
+    pub bytes: u64,
+    pub dbs_done: u64,
+    pub dbs: u64,
+}
+
+/// Statistics shared by every connection.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    reply_sizes: DashMap<String, ReplySizeHistogram>,
+    flushes: AtomicU64,
+    /// Keys dropped by flushes, per structure.
+    flushed_keys: DashMap<&'static str, u64>,
+    connected_clients: AtomicU64,
+    total_connections: AtomicU64,
+    commands: AtomicU64,
+    ops: OpsMeter,
+    /// Unix time, in seconds, of the last save. Starts at startup, like redis.
+    last_save: AtomicU64,
+    /// Whether a save is running.
+    saving: AtomicBool,
+    /// Whether the last save went through.
+    last_save_ok: AtomicBool,
+    /// Unix ms the running save started at.
+    save_started: AtomicU64,
+    /// Bytes the running save wrote so far.
+    save_bytes: AtomicU64,
+    /// Dbs the running save wrote so far, and has to write.
+    save_dbs_done: AtomicU64,
+    save_dbs: AtomicU64,
+    /// Seconds the last save took, -1 before the first.
+    last_save_secs: AtomicI64,
+    /// Bytes the last save wrote.
+    last_save_bytes: AtomicU64,
+    /// Replies of --big-reply-bytes or more.
+    big_replies: AtomicU64,
+    /// Keys evicted for maxmemory.
+    evicted_keys: AtomicU64,
+    /// Writes left out of CDC as the sink was behind.
+    cdc_dropped_events: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            reply_sizes: DashMap::new(),
+            flushes: AtomicU64::new(0),
+            flushed_keys: DashMap::new(),
+            connected_clients: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            commands: AtomicU64::new(0),
+            ops: OpsMeter::default(),
+            last_save: AtomicU64::new(now_ms() / 1000),
+            saving: AtomicBool::new(false),
+            last_save_ok: AtomicBool::new(true),
+            save_started: AtomicU64::new(0),
+            save_bytes: AtomicU64::new(0),
+            save_dbs_done: AtomicU64::new(0),
+            save_dbs: AtomicU64::new(0),
+            last_save_secs: AtomicI64::new(-1),
+            last_save_bytes: AtomicU64::new(0),
+            big_replies: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            cdc_dropped_events: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Stats {
+    /// Record the encoded size of a reply to `command`.
+    pub fn record_reply_size(&self, command: &str, size: usize) {
+        match self.reply_sizes.get_mut(command) {
+            Some(mut hist) => hist.record(size),
+            None => self
+                .reply_sizes
+                .entry(command.to_string())
+                .or_default()
+                .record(size),
+        }
+    }
+
+    /// Record a reply of --big-reply-bytes or more.
+    pub fn record_big_reply(&self) {
+        self.big_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Replies of --big-reply-bytes or more since startup.
+    pub fn big_replies(&self) -> u64 {
+        self.big_replies.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of reply size histograms, sorted by command name.
+    pub fn reply_sizes(&self) -> Vec<(String, ReplySizeHistogram)> {
+        let mut res: Vec<_> = self
+            .reply_sizes
+            .iter()
+            .map(|r| (r.key().clone(), r.value().clone()))
+            .collect();
+        res.sort_by(|l, r| l.0.cmp(&r.0));
+        res
+    }
+
+    /// Record one db being flushed, with the keys dropped per structure.
+    pub fn record_flush(&self, dropped: &[(&'static str, usize)]) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        for (structure, count) in dropped {
+            *self.flushed_keys.entry(*structure).or_default() += *count as u64;
+        }
+    }
+
+    /// Number of dbs flushed so far.
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Time since the server started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connected_clients(&self) -> u64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    /// Connections accepted since startup.
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    /// Record one command being run.
+    pub fn record_command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        self.ops.record(now_ms() / 1000);
+    }
+
+    /// Commands run since startup.
+    pub fn total_commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    /// Commands run in the last full second.
+    pub fn ops_per_sec(&self) -> u64 {
+        self.ops.per_sec(now_ms() / 1000)
+    }
+
+    /// Record the dump file being saved.
+    pub fn record_save(&self) {
+        self.last_save.store(now_ms() / 1000, Ordering::Relaxed);
+    }
+
+    /// Unix time, in seconds, of the last save.
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    /// Mark a save as started. False if one already is.
+    pub fn start_save(&self) -> bool {
+        if self.saving.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.save_started.store(now_ms(), Ordering::Relaxed);
+        self.save_bytes.store(0, Ordering::Relaxed);
+        self.save_dbs_done.store(0, Ordering::Relaxed);
+        self.save_dbs.store(0, Ordering::Relaxed);
+        true
+    }
+
+    /// Record that the running save has `dbs` dbs to write.
+    pub fn record_save_dbs(&self, dbs: usize) {
+        self.save_dbs.store(dbs as u64, Ordering::Relaxed);
+    }
+
+    /// Record the running save writing `bytes` more bytes.
+    pub fn record_save_bytes(&self, bytes: usize) {
+        self.save_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record the running save finishing a db.
+    pub fn record_save_db(&self) {
+        self.save_dbs_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mark the running save as done.
+    pub fn finish_save(&self, ok: bool) {
+        let took = now_ms().saturating_sub(self.save_started.load(Ordering::Relaxed)) / 1000;
+        self.last_save_secs.store(took as i64, Ordering::Relaxed);
+        self.last_save_bytes
+            .store(self.save_bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.last_save_ok.store(ok, Ordering::Relaxed);
+        self.saving.store(false, Ordering::SeqCst);
+    }
+
+    /// How far the running save got, if one is running.
+    pub fn save_progress(&self) -> Option<SaveProgress> {
+        if !self.saving() {
+            return None;
+        }
+        let started = self.save_started.load(Ordering::Relaxed);
+        Some(SaveProgress {
+            secs: now_ms().saturating_sub(started) / 1000,
+            bytes: self.save_bytes.load(Ordering::Relaxed),
+            dbs_done: self.save_dbs_done.load(Ordering::Relaxed),
+            dbs: self.save_dbs.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Seconds the last save took, -1 before the first.
+    pub fn last_save_secs(&self) -> i64 {
+        self.last_save_secs.load(Ordering::Relaxed)
+    }
+
+    /// Bytes the last save wrote.
+    pub fn last_save_bytes(&self) -> u64 {
+        self.last_save_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Whether a save is running.
+    pub fn saving(&self) -> bool {
+        self.saving.load(Ordering::SeqCst)
+    }
+
+    /// Whether the last save went through.
+    pub fn last_save_ok(&self) -> bool {
+        self.last_save_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn record_evictions(&self, keys: u64) {
+        self.evicted_keys.fetch_add(keys, Ordering::Relaxed);
+    }
+
+    /// Keys evicted for maxmemory since startup.
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn record_cdc_drop(&self) {
+        self.cdc_dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Writes left out of CDC since startup.
+    pub fn cdc_dropped_events(&self) -> u64 {
+        self.cdc_dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Keys dropped by flushes, sorted by structure name.
+    pub fn flushed_keys(&self) -> Vec<(&'static str, u64)> {
+        let mut res: Vec<_> = self
+            .flushed_keys
+            .iter()
+            .map(|r| (*r.key(), *r.value()))
+            .collect();
+        res.sort_unstable();
+        res
+    }
+}
+
+#[cfg(test)]
+mod test_stats {
+    use crate::stats::{OpsMeter, ReplySizeHistogram, Stats};
+
+    #[test]
+    fn test_histogram_buckets() {
+        let mut hist = ReplySizeHistogram::default();
+        hist.record(5);
+        hist.record(63);
+        hist.record(64);
+        hist.record(1000);
+        hist.record(usize::MAX);
+        let buckets: Vec<_> = hist.buckets().collect();
+        assert_eq!(buckets, vec![(64, 2), (128, 1), (1024, 1), (usize::MAX, 1)]);
+        assert_eq!(hist.calls(), 5);
+        assert_eq!(hist.total_bytes(), u64::MAX);
+        assert_eq!(hist.max_bytes(), usize::MAX);
+    }
+
+    #[test]
+    fn test_record_reply_size() {
+        let stats = Stats::default();
+        stats.record_reply_size("get", 10);
+        stats.record_reply_size("get", 30);
+        stats.record_reply_size("del", 4);
+        let sizes = stats.reply_sizes();
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0].0, "del");
+        assert_eq!(sizes[1].1.calls(), 2);
+        assert_eq!(sizes[1].1.total_bytes(), 40);
+    }
+
+    #[test]
+    fn test_record_flush() {
+        let stats = Stats::default();
+        stats.record_flush(&[("kv", 3), ("sets", 0)]);
+        stats.record_flush(&[("kv", 2), ("lists", 1)]);
+        assert_eq!(stats.flushes(), 2);
+        assert_eq!(
+            stats.flushed_keys(),
+            vec![("kv", 5), ("lists", 1), ("sets", 0)]
+        );
+    }
+
+    #[test]
+    fn test_ops_meter() {
+        let ops = OpsMeter::default();
+        for _ in 0..3 {
+            ops.record(10);
+        }
+        ops.record(11);
+        assert_eq!(ops.per_sec(11), 3);
+        assert_eq!(ops.per_sec(12), 1);
+        assert_eq!(ops.per_sec(13), 0);
+        // A quiet second in between leaves nothing for the previous one.
+        ops.record(13);
+        assert_eq!(ops.per_sec(13), 0);
+    }
+
+    #[test]
+    fn test_clients_and_commands() {
+        let stats = Stats::default();
+        stats.client_connected();
+        stats.client_connected();
+        stats.client_disconnected();
+        stats.record_command();
+        assert_eq!(stats.connected_clients(), 1);
+        assert_eq!(stats.total_connections(), 2);
+        assert_eq!(stats.total_commands(), 1);
+    }
+}
@@ -0,0 +1,1204 @@
+use crate::data_structures::receipt_map::KeyTypes;
+use crate::data_structures::stream::{
+    AddError, ConsumerGroup, Fields, NewId, PendingEntry, StreamId,
+};
+use crate::expiry::now_ms;
+use crate::ops::RVec;
+use crate::timeouts::blocking_timeout;
+use crate::types::{Key, RedisValueRef, ReturnValue, State, StateRef, UTimeout};
+use crate::{make_reader, op_variants};
+use bytes::Bytes;
+use std::borrow::Cow;
+use std::ops::Bound;
+use std::time::Duration;
+
+/// The options of XADD.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct XAddOptions {
+    /// NOMKSTREAM: don't create the stream if it's missing.
+    pub no_mkstream: bool,
+    /// MAXLEN: drop the oldest entries past this many.
+    pub maxlen: Option<usize>,
+}
+
+/// Where XREAD starts reading a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadFrom {
+    /// Entries after this ID.
+    After(StreamId),
+    /// `$`: entries added after XREAD was called.
+    End,
+}
+
+/// Where XREADGROUP reads a stream from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupRead {
+    /// `>`: entries no consumer of the group has had.
+    New,
+    /// The consumer's own pending entries after this ID.
+    Pending(StreamId),
+}
+
+/// The options of XREADGROUP.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadGroupOptions {
+    pub count: Option<usize>,
+    /// Milliseconds to wait for new entries, 0 for forever.
+    pub block: Option<UTimeout>,
+    /// NOACK: don't make the entries pending.
+    pub noack: bool,
+}
+
+/// The extended form of XPENDING: `[IDLE min-idle] start end count [consumer]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRange {
+    pub min_idle: u64,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<Key>,
+}
+
+/// The options of XCLAIM.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClaimOptions {
+    /// IDLE: set the idle time, in milliseconds.
+    pub idle: Option<u64>,
+    /// TIME: set the delivery time, in unix milliseconds.
+    pub time: Option<u64>,
+    /// RETRYCOUNT: set the delivery count.
+    pub retry_count: Option<u64>,
+    /// FORCE: make entries pending first if they aren't.
+    pub force: bool,
+    /// JUSTID: reply with IDs only, and don't count a delivery.
+    pub just_id: bool,
+}
+
+op_variants! {
+    StreamOps,
+    XAdd(Key, XAddOptions, NewId, Fields),
+    XLen(Key),
+    XRange(Key, StreamId, StreamId, Option<usize>),
+    XRevRange(Key, StreamId, StreamId, Option<usize>),
+    XRead(RVec<(Key, ReadFrom)>, Option<usize>, Option<UTimeout>),
+    XGroupCreate(Key, Key, ReadFrom, bool),
+    XGroupSetId(Key, Key, ReadFrom),
+    XGroupDestroy(Key, Key),
+    XReadGroup(Key, Key, RVec<(Key, GroupRead)>, ReadGroupOptions),
+    XAck(Key, Key, RVec<StreamId>),
+    XPending(Key, Key, Option<PendingRange>),
+    XClaim(Key, Key, Key, u64, RVec<StreamId>, ClaimOptions),
+    XAutoClaim(Key, Key, Key, u64, StreamId, usize, bool)
+}
+
+impl StreamOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            StreamOps::XAdd(key, ..)
+            | StreamOps::XGroupCreate(key, ..)
+            | StreamOps::XGroupSetId(key, ..)
+            | StreamOps::XGroupDestroy(key, _)
+            | StreamOps::XAck(key, ..)
+            | StreamOps::XClaim(key, ..)
+            | StreamOps::XAutoClaim(key, ..) => Some(vec![key.clone()]),
+            StreamOps::XReadGroup(_, _, from, _) => {
+                Some(from.iter().map(|(key, _)| key.clone()).collect())
+            }
+            StreamOps::XLen(_)
+            | StreamOps::XRange(..)
+            | StreamOps::XRevRange(..)
+            | StreamOps::XRead(..)
+            | StreamOps::XPending(..) => None,
+        }
+    }
+}
+
+make_reader!(streams, read_streams);
+
+fn id_reply(id: StreamId) -> ReturnValue {
+    ReturnValue::StringRes(Bytes::from(id.to_string()))
+}
+
+/// An entry as XRANGE replies with it: its ID, then its fields and values.
+fn entry_reply(id: &StreamId, fields: &Fields) -> ReturnValue {
+    let flat = fields
+        .iter()
+        .flat_map(|(field, value)| vec![field.clone(), value.clone()])
+        .collect();
+    ReturnValue::Array(vec![id_reply(*id), ReturnValue::MultiStringRes(flat)])
+}
+
+/// XADD's reply: the new entry's ID, or Nil if NOMKSTREAM found no stream.
+fn add(state: &State, key: Key, opts: XAddOptions, id: NewId, fields: Fields) -> ReturnValue {
+    let created = !state.streams.contains_key(&key);
+    if created && opts.no_mkstream {
+        return ReturnValue::Nil;
+    }
+    let mut stream = state.streams.entry(key.clone()).or_default();
+    let added = stream.add(id, fields, now_ms());
+    if added.is_ok() {
+        if let Some(maxlen) = opts.maxlen {
+            stream.trim(maxlen);
+        }
+    }
+    drop(stream);
+    match added {
+        Ok(id) => {
+            state.wake_stream(&key);
+            id_reply(id)
+        }
+        Err(e) => {
+            if created {
+                // Unless another client has added to it since.
+                state.streams.remove_if(&key, |_, stream| stream.is_empty());
+            }
+            match e {
+                AddError::Zero => {
+                    ReturnValue::Error(b"ERR The ID specified in XADD must be greater than 0-0")
+                }
+                AddError::TooSmall => ReturnValue::Error(
+                    b"ERR The ID specified in XADD is equal or smaller than the target stream top item",
+                ),
+            }
+        }
+    }
+}
+
+/// XRANGE's reply, or XREVRANGE's (newest first) if `rev`.
+fn range(
+    state: &State,
+    key: &Key,
+    start: StreamId,
+    end: StreamId,
+    count: Option<usize>,
+    rev: bool,
+) -> ReturnValue {
+    let stream = match read_streams!(state, key) {
+        Some(stream) => stream,
+        None => return ReturnValue::Array(Vec::new()),
+    };
+    let count = count.unwrap_or(usize::MAX);
+    let entries = stream.range(start, end);
+    let entries: Vec<_> = if rev {
+        entries
+            .rev()
+            .take(count)
+            .map(|(id, f)| entry_reply(id, f))
+            .collect()
+    } else {
+        entries
+            .take(count)
+            .map(|(id, f)| entry_reply(id, f))
+            .collect()
+    };
+    ReturnValue::Array(entries)
+}
+
+/// Every stream of `from` with entries after its ID, with up to `count` of them.
+fn read(state: &State, from: &[(Key, StreamId)], count: Option<usize>) -> Vec<ReturnValue> {
+    from.iter()
+        .filter_map(|(key, after)| {
+            let stream = read_streams!(state, key)?;
+            let entries: Vec<_> = stream
+                .after(*after)
+                .take(count.unwrap_or(usize::MAX))
+                .map(|(id, fields)| entry_reply(id, fields))
+                .collect();
+            if entries.is_empty() {
+                return None;
+            }
+            Some(ReturnValue::Array(vec![
+                ReturnValue::StringRes(key.clone()),
+                ReturnValue::Array(entries),
+            ]))
+        })
+        .collect()
+}
+
+/// XREAD, waiting up to `block` milliseconds (0 for forever) for new entries if there are none.
+async fn xread(
+    state: StateRef,
+    from: RVec<(Key, ReadFrom)>,
+    count: Option<usize>,
+    block: Option<UTimeout>,
+) -> ReturnValue {
+    // `$` is whatever was last when we were called, not when an entry lands.
+    let from: Vec<(Key, StreamId)> = from
+        .into_iter()
+        .map(|(key, from)| {
+            let after = match from {
+                ReadFrom::After(id) => id,
+                ReadFrom::End => read_streams!(state, &key).map_or(StreamId::MIN, |s| s.last_id()),
+            };
+            (key, after)
+        })
+        .collect();
+    let found = read(&state, &from, count);
+    let block = match block {
+        Some(block) if found.is_empty() => block,
+        _ if found.is_empty() => return ReturnValue::Ident(RedisValueRef::NullArray),
+        _ => return ReturnValue::Array(found),
+    };
+    let keys = from.iter().map(|(key, _)| KeyTypes::stream(key)).collect();
+    let limit = match block {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    };
+    let state_clone = state.clone();
+    let poll = move || {
+        let found = read(&state, &from, count);
+        if found.is_empty() {
+            None
+        } else {
+            Some(ReturnValue::Array(found))
+        }
+    };
+    match blocking_timeout(Box::new(poll), state_clone, keys, limit).await {
+        ReturnValue::Nil => ReturnValue::Ident(RedisValueRef::NullArray),
+        reply => reply,
+    }
+}
+
+/// The reply when `key` has no group `group`. `context` ends the message.
+fn no_group(key: &[u8], group: &[u8], context: &str) -> ReturnValue {
+    let msg = format!(
+        "NOGROUP No such key '{}' or consumer group '{}'{}",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group),
+        context
+    );
+    ReturnValue::Ident(RedisValueRef::ErrorMsg(msg.into_bytes()))
+}
+
+/// XGROUP CREATE. The group reads after `from`, `$` being the last entry.
+fn create_group(
+    state: &State,
+    key: Key,
+    group: Key,
+    from: ReadFrom,
+    mkstream: bool,
+) -> ReturnValue {
+    if !mkstream && !state.streams.contains_key(&key) {
+        return ReturnValue::Error(
+            b"ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+        );
+    }
+    let mut stream = state.streams.entry(key).or_default();
+    let last_delivered = match from {
+        ReadFrom::After(id) => id,
+        ReadFrom::End => stream.last_id(),
+    };
+    if stream.create_group(group, last_delivered) {
+        ReturnValue::Ok
+    } else {
+        ReturnValue::Error(b"BUSYGROUP Consumer Group name already exists")
+    }
+}
+
+/// Hand `consumer` what it asked for from each stream of `from`. Streams
+/// read for new entries are left out if they have none. Fails, changing
+/// nothing, if any stream lacks the group.
+fn read_group(
+    state: &State,
+    group: &Key,
+    consumer: &Key,
+    from: &[(Key, GroupRead)],
+    opts: &ReadGroupOptions,
+) -> Result<Vec<ReturnValue>, ReturnValue> {
+    for (key, _) in from {
+        if read_streams!(state, key).map_or(true, |s| s.group(group).is_none()) {
+            return Err(no_group(key, group, " in XREADGROUP with GROUP option"));
+        }
+    }
+    let count = opts.count.unwrap_or(usize::MAX);
+    let now = now_ms();
+    let mut found = Vec::new();
+    for (key, read) in from {
+        let mut stream = match state.streams.get_mut(key) {
+            Some(stream) => stream,
+            None => continue,
+        };
+        let (group, entries) = match stream.group_mut(group) {
+            Some(group) => group,
+            None => continue,
+        };
+        let replies: Vec<ReturnValue> = match read {
+            GroupRead::New => {
+                let new = group.deliver_new(entries, consumer, count, opts.noack, now);
+                if new.is_empty() {
+                    continue;
+                }
+                new.iter().map(|(id, f)| entry_reply(id, f)).collect()
+            }
+            GroupRead::Pending(after) => group
+                .redeliver(entries, consumer, *after, count, now)
+                .iter()
+                .map(|(id, fields)| match fields {
+                    Some(fields) => entry_reply(id, fields),
+                    None => ReturnValue::Array(vec![
+                        id_reply(*id),
+                        ReturnValue::Ident(RedisValueRef::NullArray),
+                    ]),
+                })
+                .collect(),
+        };
+        found.push(ReturnValue::Array(vec![
+            ReturnValue::StringRes(key.clone()),
+            ReturnValue::Array(replies),
+        ]));
+    }
+    Ok(found)
+}
+
+/// XREADGROUP. Only reads of new entries wait, for up to `opts.block` milliseconds.
+async fn xreadgroup(
+    state: StateRef,
+    group: Key,
+    consumer: Key,
+    from: RVec<(Key, GroupRead)>,
+    opts: ReadGroupOptions,
+) -> ReturnValue {
+    let found = match read_group(&state, &group, &consumer, &from, &opts) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+    let only_new = from.iter().all(|(_, read)| *read == GroupRead::New);
+    let block = match opts.block {
+        Some(block) if found.is_empty() && only_new => block,
+        _ if found.is_empty() => return ReturnValue::Ident(RedisValueRef::NullArray),
+        _ => return ReturnValue::Array(found),
+    };
+    let keys = from.iter().map(|(key, _)| KeyTypes::stream(key)).collect();
+    let limit = match block {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    };
+    let state_clone = state.clone();
+    let poll = move || match read_group(&state, &group, &consumer, &from, &opts) {
+        Ok(found) if found.is_empty() => None,
+        Ok(found) => Some(ReturnValue::Array(found)),
+        Err(e) => Some(e),
+    };
+    match blocking_timeout(Box::new(poll), state_clone, keys, limit).await {
+        ReturnValue::Nil => ReturnValue::Ident(RedisValueRef::NullArray),
+        reply => reply,
+    }
+}
+
+/// XPENDING without a range: how many entries are pending, the smallest and
+/// greatest of their IDs, and how many each consumer has.
+fn pending_summary(group: &ConsumerGroup) -> ReturnValue {
+    let pending = group.pending();
+    let (first, last) = match (pending.keys().next(), pending.keys().next_back()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => {
+            return ReturnValue::Array(vec![
+                ReturnValue::IntRes(0),
+                ReturnValue::Nil,
+                ReturnValue::Nil,
+                ReturnValue::Ident(RedisValueRef::NullArray),
+            ])
+        }
+    };
+    let per_consumer = group
+        .consumers()
+        .iter()
+        .filter(|(_, consumer)| !consumer.pending().is_empty())
+        .map(|(name, consumer)| {
+            let n = consumer.pending().len();
+            ReturnValue::MultiStringRes(vec![name.clone(), Bytes::from(n.to_string())])
+        })
+        .collect();
+    ReturnValue::Array(vec![
+        ReturnValue::IntRes(pending.len() as i64),
+        id_reply(first),
+        id_reply(last),
+        ReturnValue::Array(per_consumer),
+    ])
+}
+
+/// XPENDING with a range: each pending entry in it, with its consumer, idle
+/// time and delivery count.
+fn pending_range(group: &ConsumerGroup, range: &PendingRange) -> ReturnValue {
+    if range.start > range.end {
+        return ReturnValue::Array(Vec::new());
+    }
+    let now = now_ms();
+    let pending: Box<dyn Iterator<Item = (&StreamId, &PendingEntry)>> = match &range.consumer {
+        // Only that consumer's entries are looked at.
+        Some(consumer) => match group.consumers().get(consumer) {
+            Some(consumer) => Box::new(
+                consumer
+                    .pending()
+                    .range(range.start..=range.end)
+                    .filter_map(|id| Some((id, group.pending().get(id)?))),
+            ),
+            None => return ReturnValue::Array(Vec::new()),
+        },
+        None => Box::new(group.pending().range(range.start..=range.end)),
+    };
+    let entries = pending
+        .filter(|(_, entry)| entry.idle(now) >= range.min_idle)
+        .take(range.count)
+        .map(|(id, entry)| {
+            ReturnValue::Array(vec![
+                id_reply(*id),
+                ReturnValue::StringRes(entry.consumer.clone()),
+                ReturnValue::IntRes(entry.idle(now) as i64),
+                ReturnValue::IntRes(entry.deliveries as i64),
+            ])
+        })
+        .collect();
+    ReturnValue::Array(entries)
+}
+
+/// XCLAIM: give `consumer` the entries of `ids` idle for at least `min_idle`
+/// milliseconds. Entries gone from the stream are dropped from the group.
+fn claim(
+    state: &State,
+    key: &Key,
+    group_name: &Key,
+    consumer: &Key,
+    min_idle: u64,
+    ids: &[StreamId],
+    opts: &ClaimOptions,
+) -> ReturnValue {
+    let mut stream = match state.streams.get_mut(key) {
+        Some(stream) => stream,
+        None => return no_group(key, group_name, ""),
+    };
+    let (group, entries) = match stream.group_mut(group_name) {
+        Some(group) => group,
+        None => return no_group(key, group_name, ""),
+    };
+    let now = now_ms();
+    let mut claimed = Vec::new();
+    for id in ids {
+        let fields = match entries.get(id) {
+            Some(fields) => fields,
+            None => {
+                group.ack(*id);
+                continue;
+            }
+        };
+        if opts.force {
+            group.force_pending(*id, consumer, now);
+        }
+        let entry = match group.claim(*id, consumer, min_idle, now) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        entry.delivered_at = match (opts.idle, opts.time) {
+            (Some(idle), _) => now.saturating_sub(idle),
+            (None, Some(time)) => time,
+            (None, None) => now,
+        };
+        if let Some(retry_count) = opts.retry_count {
+            entry.deliveries = retry_count;
+        } else if !opts.just_id {
+            entry.deliveries += 1;
+        }
+        claimed.push(if opts.just_id {
+            id_reply(*id)
+        } else {
+            entry_reply(id, fields)
+        });
+    }
+    ReturnValue::Array(claimed)
+}
+
+/// How many pending entries XAUTOCLAIM looks at per entry it may claim.
+const AUTOCLAIM_ATTEMPTS: usize = 10;
+
+/// XAUTOCLAIM: claim up to `count` entries idle for at least `min_idle`
+/// milliseconds, scanning the pending entries from `start`. Replies with
+/// where to continue (0-0 once done), the claimed entries, and the IDs of
+/// the ones gone from the stream, which are dropped from the group.
+#[allow(clippy::too_many_arguments)]
+fn autoclaim(
+    state: &State,
+    key: &Key,
+    group_name: &Key,
+    consumer: &Key,
+    min_idle: u64,
+    start: StreamId,
+    count: usize,
+    just_id: bool,
+) -> ReturnValue {
+    let mut stream = match state.streams.get_mut(key) {
+        Some(stream) => stream,
+        None => return no_group(key, group_name, ""),
+    };
+    let (group, entries) = match stream.group_mut(group_name) {
+        Some(group) => group,
+        None => return no_group(key, group_name, ""),
+    };
+    let now = now_ms();
+    let candidates: Vec<StreamId> = group
+        .pending()
+        .range(start..)
+        .map(|(id, _)| *id)
+        .take(count.saturating_mul(AUTOCLAIM_ATTEMPTS))
+        .collect();
+    let mut unscanned = candidates.iter().copied();
+    let (mut claimed, mut deleted) = (Vec::new(), Vec::new());
+    while claimed.len() < count {
+        let id = match unscanned.next() {
+            Some(id) => id,
+            None => break,
+        };
+        let fields = match entries.get(&id) {
+            Some(fields) => fields,
+            None => {
+                group.ack(id);
+                deleted.push(id_reply(id));
+                continue;
+            }
+        };
+        if let Some(entry) = group.claim(id, consumer, min_idle, now) {
+            entry.delivered_at = now;
+            if !just_id {
+                entry.deliveries += 1;
+            }
+            claimed.push(if just_id {
+                id_reply(id)
+            } else {
+                entry_reply(&id, fields)
+            });
+        }
+    }
+    let cursor = unscanned.next().or_else(|| {
+        let last = *candidates.last()?;
+        let after = group
+            .pending()
+            .range((Bound::Excluded(last), Bound::Unbounded));
+        after.map(|(id, _)| *id).next()
+    });
+    ReturnValue::Array(vec![
+        id_reply(cursor.unwrap_or(StreamId::MIN)),
+        ReturnValue::Array(claimed),
+        ReturnValue::Array(deleted),
+    ])
+}
+
+/// Ops handing out pending entries, which depend on when they ran.
+pub const GROUP_READS: &[&str] = &["XReadGroup", "XClaim", "XAutoClaim"];
+
+/// A command putting entry `id` of group `group` of stream `key` back as it
+/// is now: an XCLAIM ... FORCE JUSTID if it's pending, else an XACK.
+fn pending_state_command(state: &State, key: &Key, group: &Key, id: StreamId) -> RedisValueRef {
+    let bulk = |b: Bytes| RedisValueRef::BulkString(b);
+    let text = |s: String| RedisValueRef::BulkString(Bytes::from(s));
+    let stream = read_streams!(state, key);
+    let pending = stream
+        .as_ref()
+        .and_then(|stream| stream.group(group)?.pending().get(&id).cloned());
+    match pending {
+        Some(entry) => RedisValueRef::Array(vec![
+            text("XCLAIM".into()),
+            bulk(key.clone()),
+            bulk(group.clone()),
+            bulk(entry.consumer),
+            text("0".into()),
+            text(id.to_string()),
+            text("TIME".into()),
+            text(entry.delivered_at.to_string()),
+            text("RETRYCOUNT".into()),
+            text(entry.deliveries.to_string()),
+            text("FORCE".into()),
+            text("JUSTID".into()),
+        ]),
+        None => RedisValueRef::Array(vec![
+            text("XACK".into()),
+            bulk(key.clone()),
+            bulk(group.clone()),
+            text(id.to_string()),
+        ]),
+    }
+}
+
+/// The ID of an entry as replies give it, alone or with its fields.
+fn reply_id(reply: &ReturnValue) -> Option<StreamId> {
+    match reply {
+        ReturnValue::StringRes(id) => StreamId::parse(id, 0),
+        ReturnValue::Array(entry) => reply_id(entry.first()?),
+        _ => None,
+    }
+}
+
+/// The `command` of group read `op` (one of `GROUP_READS`), which replied
+/// `res`, as the AOF and replicas get it: an array of commands putting each
+/// entry it handed out or dropped back as it is now (see
+/// `pending_state_command`), then for XREADGROUP an XGROUP SETID moving the
+/// group past what it read. Other ops' commands are returned as they are.
+pub fn group_read_as_claims<'a>(
+    state: &State,
+    op: &str,
+    command: &'a RedisValueRef,
+    res: &ReturnValue,
+) -> Cow<'a, RedisValueRef> {
+    let args = match command {
+        RedisValueRef::Array(args) if GROUP_READS.contains(&op) => args,
+        _ => return Cow::Borrowed(command),
+    };
+    let arg = |at: usize| match args.get(at) {
+        Some(RedisValueRef::BulkString(arg)) => Some(arg.clone()),
+        _ => None,
+    };
+    let mut commands = Vec::new();
+    match (op, res) {
+        // XREADGROUP GROUP group consumer ..., replying [[key, entries]...].
+        ("XReadGroup", ReturnValue::Array(streams)) => {
+            let group = arg(2).unwrap_or_default();
+            for found in streams {
+                let (key, entries) = match found {
+                    ReturnValue::Array(found) => match &found[..] {
+                        [ReturnValue::StringRes(key), ReturnValue::Array(entries)] => {
+                            (key, entries)
+                        }
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                for id in entries.iter().filter_map(reply_id) {
+                    commands.push(pending_state_command(state, key, &group, id));
+                }
+                let last_delivered = read_streams!(state, key)
+                    .and_then(|stream| Some(stream.group(&group)?.last_delivered()));
+                if let Some(id) = last_delivered {
+                    commands.push(RedisValueRef::Array(vec![
+                        RedisValueRef::BulkString(Bytes::from_static(b"XGROUP")),
+                        RedisValueRef::BulkString(Bytes::from_static(b"SETID")),
+                        RedisValueRef::BulkString(key.clone()),
+                        RedisValueRef::BulkString(group.clone()),
+                        RedisValueRef::BulkString(Bytes::from(id.to_string())),
+                    ]));
+                }
+            }
+        }
+        // XCLAIM key group consumer min-idle id... [options]: entries gone
+        // from the stream aren't in the reply, so every ID given is logged.
+        ("XClaim", _) => {
+            let (key, group) = (arg(1).unwrap_or_default(), arg(2).unwrap_or_default());
+            let ids = (5..).map_while(|at| StreamId::parse(&arg(at)?, 0));
+            for id in ids {
+                commands.push(pending_state_command(state, &key, &group, id));
+            }
+        }
+        // XAUTOCLAIM replies [cursor, claimed, deleted].
+        ("XAutoClaim", ReturnValue::Array(reply)) => {
+            let (key, group) = (arg(1).unwrap_or_default(), arg(2).unwrap_or_default());
+            let touched = reply.iter().skip(1).flat_map(|ids| match ids {
+                ReturnValue::Array(ids) => ids.iter().filter_map(reply_id).collect(),
+                _ => Vec::new(),
+            });
+            for id in touched {
+                commands.push(pending_state_command(state, &key, &group, id));
+            }
+        }
+        _ => {}
+    }
+    Cow::Owned(RedisValueRef::Array(commands))
+}
+
+/// The XADD `command` as the AOF and replicas get it: with the ID the entry
+/// got in place of the one asked for, so a replay adds the same entry.
+/// `key` is the stream, which the XADD was the last write to.
+pub fn xadd_as_added(state: &State, key: &Key, command: &RedisValueRef) -> RedisValueRef {
+    let (args, last_id) = match (command, read_streams!(state, key)) {
+        (RedisValueRef::Array(args), Some(stream)) => (args, stream.last_id()),
+        _ => return command.clone(),
+    };
+    let is = |arg: &RedisValueRef, flag: &str| match arg {
+        RedisValueRef::BulkString(s) => s.eq_ignore_ascii_case(flag.as_bytes()),
+        _ => false,
+    };
+    // Skip XADD, the key and the options, which parsing already checked.
+    let mut at = 2;
+    while let Some(arg) = args.get(at) {
+        if is(arg, "nomkstream") {
+            at += 1;
+        } else if is(arg, "maxlen") {
+            let exact = args.get(at + 1).map_or(false, |a| is(a, "=") || is(a, "~"));
+            at += if exact { 3 } else { 2 };
+        } else {
+            break;
+        }
+    }
+    let mut args = args.clone();
+    if let Some(id) = args.get_mut(at) {
+        *id = RedisValueRef::BulkString(Bytes::from(last_id.to_string()));
+    }
+    RedisValueRef::Array(args)
+}
+
+pub async fn stream_interact(stream_op: StreamOps, state: StateRef) -> ReturnValue {
+    match stream_op {
+        StreamOps::XAdd(key, opts, id, fields) => add(&state, key, opts, id, fields),
+        StreamOps::XLen(key) => {
+            ReturnValue::IntRes(read_streams!(state, &key).map_or(0, |s| s.len()) as i64)
+        }
+        StreamOps::XRange(key, start, end, count) => range(&state, &key, start, end, count, false),
+        StreamOps::XRevRange(key, start, end, count) => {
+            range(&state, &key, start, end, count, true)
+        }
+        StreamOps::XRead(from, count, block) => xread(state, from, count, block).await,
+        StreamOps::XGroupCreate(key, group, from, mkstream) => {
+            create_group(&state, key, group, from, mkstream)
+        }
+        StreamOps::XGroupSetId(key, group_name, from) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return no_group(&key, &group_name, ""),
+            };
+            let last_delivered = match from {
+                ReadFrom::After(id) => id,
+                ReadFrom::End => stream.last_id(),
+            };
+            match stream.group_mut(&group_name) {
+                Some((group, _)) => {
+                    group.set_last_delivered(last_delivered);
+                    ReturnValue::Ok
+                }
+                None => no_group(&key, &group_name, ""),
+            }
+        }
+        StreamOps::XGroupDestroy(key, group) => {
+            let destroyed = state
+                .streams
+                .get_mut(&key)
+                .map_or(false, |mut s| s.destroy_group(&group));
+            // Consumers blocked on the group find it gone.
+            state.wake_stream(&key);
+            ReturnValue::IntRes(destroyed as i64)
+        }
+        StreamOps::XReadGroup(group, consumer, from, opts) => {
+            xreadgroup(state, group, consumer, from, opts).await
+        }
+        StreamOps::XAck(key, group, ids) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return ReturnValue::IntRes(0),
+            };
+            let acked = match stream.group_mut(&group) {
+                Some((group, _)) => ids.iter().filter(|id| group.ack(**id)).count(),
+                None => 0,
+            };
+            ReturnValue::IntRes(acked as i64)
+        }
+        StreamOps::XPending(key, group_name, range) => {
+            let stream = read_streams!(state, &key);
+            let group = match stream.as_ref().and_then(|s| s.group(&group_name)) {
+                Some(group) => group,
+                None => return no_group(&key, &group_name, ""),
+            };
+            match range {
+                Some(range) => pending_range(group, &range),
+                None => pending_summary(group),
+            }
+        }
+        StreamOps::XClaim(key, group, consumer, min_idle, ids, opts) => {
+            claim(&state, &key, &group, &consumer, min_idle, &ids, &opts)
+        }
+        StreamOps::XAutoClaim(key, group, consumer, min_idle, start, count, just_id) => autoclaim(
+            &state, &key, &group, &consumer, min_idle, start, count, just_id,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test_streams {
+    use super::{
+        group_read_as_claims, stream_interact, xadd_as_added, ClaimOptions, GroupRead,
+        PendingRange, ReadFrom, ReadGroupOptions, StreamOps, XAddOptions,
+    };
+    use crate::data_structures::stream::{NewId, StreamId};
+    use crate::ops::RVec;
+    use crate::test_util::command;
+    use crate::types::{RedisValueRef, ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    fn b(s: &'static str) -> Bytes {
+        Bytes::from_static(s.as_bytes())
+    }
+
+    fn xadd(key: &Bytes, id: &str, value: &'static str) -> StreamOps {
+        StreamOps::XAdd(
+            key.clone(),
+            XAddOptions::default(),
+            NewId::parse(id.as_bytes()).unwrap(),
+            vec![(b("f"), b(value))],
+        )
+    }
+
+    fn entry(id: &str, value: &'static str) -> ReturnValue {
+        ReturnValue::Array(vec![
+            ReturnValue::StringRes(Bytes::from(id.to_string())),
+            ReturnValue::MultiStringRes(vec![b("f"), b(value)]),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_add_and_range() {
+        let state: StateRef = Arc::new(State::default());
+        let s = b("s");
+        for (id, value) in &[("1-1", "a"), ("1-*", "b"), ("3", "c")] {
+            stream_interact(xadd(&s, id, value), state.clone()).await;
+        }
+        assert_eq!(
+            stream_interact(xadd(&s, "2-0", "d"), state.clone()).await,
+            ReturnValue::Error(
+                b"ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+        assert_eq!(
+            stream_interact(StreamOps::XLen(s.clone()), state.clone()).await,
+            ReturnValue::IntRes(3)
+        );
+        let op = StreamOps::XRange(s.clone(), StreamId::new(1, 2), StreamId::MAX, None);
+        assert_eq!(
+            stream_interact(op, state.clone()).await,
+            ReturnValue::Array(vec![entry("1-2", "b"), entry("3-0", "c")])
+        );
+        let op = StreamOps::XRevRange(s.clone(), StreamId::MIN, StreamId::MAX, Some(2));
+        assert_eq!(
+            stream_interact(op, state.clone()).await,
+            ReturnValue::Array(vec![entry("3-0", "c"), entry("1-2", "b")])
+        );
+
+        // A failed XADD doesn't leave an empty stream behind.
+        let t = b("t");
+        stream_interact(xadd(&t, "0-0", "a"), state.clone()).await;
+        assert!(!state.streams.contains_key(&t));
+        let opts = XAddOptions {
+            no_mkstream: true,
+            maxlen: None,
+        };
+        let op = StreamOps::XAdd(t.clone(), opts, NewId::Auto, vec![(b("f"), b("v"))]);
+        assert_eq!(stream_interact(op, state.clone()).await, ReturnValue::Nil);
+        assert!(!state.streams.contains_key(&t));
+
+        let opts = XAddOptions {
+            no_mkstream: false,
+            maxlen: Some(1),
+        };
+        let op = StreamOps::XAdd(s.clone(), opts, NewId::Auto, vec![(b("f"), b("v"))]);
+        stream_interact(op, state.clone()).await;
+        assert_eq!(state.streams.get(&s).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let state: StateRef = Arc::new(State::default());
+        let (s, t) = (b("s"), b("t"));
+        stream_interact(xadd(&s, "1", "a"), state.clone()).await;
+        stream_interact(xadd(&s, "2", "b"), state.clone()).await;
+        let from = smallvec![
+            (s.clone(), ReadFrom::After(StreamId::new(1, 0))),
+            (t.clone(), ReadFrom::After(StreamId::MIN)),
+        ];
+        assert_eq!(
+            stream_interact(StreamOps::XRead(from, None, None), state.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::Array(vec![
+                ReturnValue::StringRes(s.clone()),
+                ReturnValue::Array(vec![entry("2-0", "b")]),
+            ])])
+        );
+        let from = smallvec![(s.clone(), ReadFrom::End)];
+        assert_eq!(
+            stream_interact(StreamOps::XRead(from, None, None), state.clone()).await,
+            ReturnValue::Ident(RedisValueRef::NullArray)
+        );
+        let from = smallvec![(s.clone(), ReadFrom::End)];
+        assert_eq!(
+            stream_interact(StreamOps::XRead(from, None, Some(1)), state.clone()).await,
+            ReturnValue::Ident(RedisValueRef::NullArray)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_read_wakes_every_reader() {
+        let state: StateRef = Arc::new(State::default());
+        let s = b("s");
+        stream_interact(xadd(&s, "1", "a"), state.clone()).await;
+        let reader = || {
+            let from = smallvec![(s.clone(), ReadFrom::End)];
+            tokio::spawn(stream_interact(
+                StreamOps::XRead(from, None, Some(0)),
+                state.clone(),
+            ))
+        };
+        let (first, second) = (reader(), reader());
+        tokio::task::yield_now().await;
+        stream_interact(xadd(&s, "2", "b"), state.clone()).await;
+        let expected = ReturnValue::Array(vec![ReturnValue::Array(vec![
+            ReturnValue::StringRes(s.clone()),
+            ReturnValue::Array(vec![entry("2-0", "b")]),
+        ])]);
+        assert_eq!(first.await.unwrap(), expected);
+        assert_eq!(second.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_consumer_groups() {
+        let state: StateRef = Arc::new(State::default());
+        let (s, g) = (b("s"), b("g"));
+        let create = StreamOps::XGroupCreate(s.clone(), g.clone(), ReadFrom::End, false);
+        assert_eq!(
+            stream_interact(create.clone(), state.clone()).await,
+            ReturnValue::Error(
+                b"ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+            )
+        );
+        let mkstream = StreamOps::XGroupCreate(s.clone(), g.clone(), ReadFrom::End, true);
+        assert_eq!(
+            stream_interact(mkstream, state.clone()).await,
+            ReturnValue::Ok
+        );
+        assert_eq!(
+            stream_interact(create, state.clone()).await,
+            ReturnValue::Error(b"BUSYGROUP Consumer Group name already exists")
+        );
+        stream_interact(xadd(&s, "1", "a"), state.clone()).await;
+        stream_interact(xadd(&s, "2", "b"), state.clone()).await;
+
+        let read = |consumer: &'static str, from: GroupRead| {
+            let op = StreamOps::XReadGroup(
+                g.clone(),
+                b(consumer),
+                smallvec![(s.clone(), from)],
+                ReadGroupOptions {
+                    count: Some(1),
+                    ..ReadGroupOptions::default()
+                },
+            );
+            stream_interact(op, state.clone())
+        };
+        let reply = |entries| {
+            ReturnValue::Array(vec![ReturnValue::Array(vec![
+                ReturnValue::StringRes(s.clone()),
+                ReturnValue::Array(entries),
+            ])])
+        };
+        assert_eq!(
+            read("alice", GroupRead::New).await,
+            reply(vec![entry("1-0", "a")])
+        );
+        assert_eq!(
+            read("bob", GroupRead::New).await,
+            reply(vec![entry("2-0", "b")])
+        );
+        assert_eq!(
+            read("bob", GroupRead::New).await,
+            ReturnValue::Ident(RedisValueRef::NullArray)
+        );
+        // Re-reading only shows the consumer's own pending entries.
+        assert_eq!(
+            read("alice", GroupRead::Pending(StreamId::MIN)).await,
+            reply(vec![entry("1-0", "a")])
+        );
+
+        let pending = StreamOps::XPending(s.clone(), g.clone(), None);
+        assert_eq!(
+            stream_interact(pending.clone(), state.clone()).await,
+            ReturnValue::Array(vec![
+                ReturnValue::IntRes(2),
+                ReturnValue::StringRes(b("1-0")),
+                ReturnValue::StringRes(b("2-0")),
+                ReturnValue::Array(vec![
+                    ReturnValue::MultiStringRes(vec![b("alice"), b("1")]),
+                    ReturnValue::MultiStringRes(vec![b("bob"), b("1")]),
+                ]),
+            ])
+        );
+        let range = PendingRange {
+            min_idle: 0,
+            start: StreamId::MIN,
+            end: StreamId::MAX,
+            count: 10,
+            consumer: Some(b("alice")),
+        };
+        let op = StreamOps::XPending(s.clone(), g.clone(), Some(range));
+        match stream_interact(op, state.clone()).await {
+            ReturnValue::Array(entries) => assert_eq!(entries.len(), 1),
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        // Entries idle long enough move to the claiming consumer.
+        let opts = ClaimOptions {
+            just_id: true,
+            ..ClaimOptions::default()
+        };
+        let ids: RVec<StreamId> = smallvec![StreamId::new(1, 0)];
+        let op = StreamOps::XClaim(
+            s.clone(),
+            g.clone(),
+            b("bob"),
+            60_000,
+            ids.clone(),
+            opts.clone(),
+        );
+        assert_eq!(
+            stream_interact(op, state.clone()).await,
+            ReturnValue::Array(vec![])
+        );
+        let op = StreamOps::XClaim(s.clone(), g.clone(), b("bob"), 0, ids, opts);
+        assert_eq!(
+            stream_interact(op, state.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::StringRes(b("1-0"))])
+        );
+        let op =
+            StreamOps::XAutoClaim(s.clone(), g.clone(), b("carol"), 0, StreamId::MIN, 1, false);
+        assert_eq!(
+            stream_interact(op, state.clone()).await,
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(b("2-0")),
+                ReturnValue::Array(vec![entry("1-0", "a")]),
+                ReturnValue::Array(vec![]),
+            ])
+        );
+
+        let ack = StreamOps::XAck(s.clone(), g.clone(), smallvec![StreamId::new(1, 0)]);
+        assert_eq!(
+            stream_interact(ack.clone(), state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(
+            stream_interact(ack, state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        match stream_interact(pending.clone(), state.clone()).await {
+            ReturnValue::Array(summary) => assert_eq!(summary[0], ReturnValue::IntRes(1)),
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        // Moving the group back hands out entries again.
+        let setid = StreamOps::XGroupSetId(s.clone(), g.clone(), ReadFrom::After(StreamId::MIN));
+        assert_eq!(stream_interact(setid, state.clone()).await, ReturnValue::Ok);
+        assert_eq!(
+            read("dave", GroupRead::New).await,
+            reply(vec![entry("1-0", "a")])
+        );
+
+        let destroy = StreamOps::XGroupDestroy(s.clone(), g.clone());
+        assert_eq!(
+            stream_interact(destroy, state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert!(matches!(
+            stream_interact(pending, state.clone()).await,
+            ReturnValue::Ident(RedisValueRef::ErrorMsg(_))
+        ));
+    }
+
+    #[test]
+    fn test_logged_with_the_added_id() {
+        let state = State::default();
+        let s = b("s");
+        state
+            .streams
+            .entry(s.clone())
+            .or_default()
+            .add(NewId::Auto, vec![(b("f"), b("v"))], 5)
+            .unwrap();
+        assert_eq!(
+            xadd_as_added(
+                &state,
+                &s,
+                &command(&["XADD", "s", "NOMKSTREAM", "MAXLEN", "~", "9", "*", "f", "v"])
+            ),
+            command(&[
+                "XADD",
+                "s",
+                "NOMKSTREAM",
+                "MAXLEN",
+                "~",
+                "9",
+                "5-0",
+                "f",
+                "v"
+            ])
+        );
+        assert_eq!(
+            xadd_as_added(
+                &state,
+                &s,
+                &command(&["xadd", "s", "maxlen", "9", "*", "f", "v"])
+            ),
+            command(&["xadd", "s", "maxlen", "9", "5-0", "f", "v"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_reads_logged_as_claims() {
+        let state: StateRef = Arc::new(State::default());
+        let (s, g) = (b("s"), b("g"));
+        stream_interact(xadd(&s, "1", "a"), state.clone()).await;
+        stream_interact(xadd(&s, "2", "b"), state.clone()).await;
+        let create =
+            StreamOps::XGroupCreate(s.clone(), g.clone(), ReadFrom::After(StreamId::MIN), false);
+        stream_interact(create, state.clone()).await;
+        let op = StreamOps::XReadGroup(
+            g.clone(),
+            b("alice"),
+            smallvec![(s.clone(), GroupRead::New)],
+            ReadGroupOptions {
+                count: Some(1),
+                ..ReadGroupOptions::default()
+            },
+        );
+        let res = stream_interact(op, state.clone()).await;
+        let delivered_at = state.streams.get(&s).unwrap().group(&g).unwrap().pending()
+            [&StreamId::new(1, 0)]
+            .delivered_at
+            .to_string();
+        let read = command(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "alice",
+            "COUNT",
+            "1",
+            "STREAMS",
+            "s",
+            ">",
+        ]);
+        assert_eq!(
+            group_read_as_claims(&state, "XReadGroup", &read, &res).into_owned(),
+            RedisValueRef::Array(vec![
+                command(&[
+                    "XCLAIM",
+                    "s",
+                    "g",
+                    "alice",
+                    "0",
+                    "1-0",
+                    "TIME",
+                    &delivered_at,
+                    "RETRYCOUNT",
+                    "1",
+                    "FORCE",
+                    "JUSTID"
+                ]),
+                command(&["XGROUP", "SETID", "s", "g", "1-0"]),
+            ])
+        );
+
+        // Entries no longer pending are logged as acknowledged.
+        let claim = command(&["XCLAIM", "s", "g", "bob", "0", "2-0", "JUSTID"]);
+        assert_eq!(
+            group_read_as_claims(&state, "XClaim", &claim, &ReturnValue::Array(vec![]))
+                .into_owned(),
+            RedisValueRef::Array(vec![command(&["XACK", "s", "g", "2-0"])])
+        );
+        // Other commands are logged as they are.
+        let add = command(&["XADD", "s", "*", "f", "v"]);
+        assert_eq!(
+            group_read_as_claims(&state, "XAdd", &add, &ReturnValue::Nil).into_owned(),
+            add
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! Helpers shared by the unit tests.
+
+use crate::types::{bulk, RedisValueRef};
+
+/// The arguments of a command, as a client sends them.
+pub fn command_args(args: &[&str]) -> Vec<RedisValueRef> {
+    args.iter().map(|arg| bulk(arg.to_string())).collect()
+}
+
+/// A command, as a client sends it.
+pub fn command(args: &[&str]) -> RedisValueRef {
+    RedisValueRef::Array(command_args(args))
+}
@@ -1,33 +1,52 @@
 use crate::blocking::{KeyBlocking, YieldingFn};
-use crate::data_structures::receipt_map::Receipt;
+use crate::clients::killed;
+use crate::data_structures::receipt_map::KeyTypes;
 use crate::types::{Key, ReturnValue, StateRef, UTimeout};
-use std::future::Future;
 use std::time::Duration;
 use tokio::time;
 
-pub async fn blocking_key_timeout(
+/// Run `f` until it returns something, retrying whenever one of `keys` is
+/// written. Replies with Nil if that takes longer than `seconds`.
+pub async fn blocking_keys_timeout(
     f: YieldingFn,
     state: StateRef,
-    key: Key,
+    keys: Vec<Key>,
     seconds: UTimeout,
 ) -> ReturnValue {
-    let receipt = state.get_receipt();
-    let kb = KeyBlocking::new(f, state.clone(), key.clone(), receipt);
-    timeout(kb, seconds, state, receipt).await
+    let keys = keys.iter().map(|key| KeyTypes::list(key)).collect();
+    let limit = Duration::from_secs(seconds as u64);
+    blocking_timeout(f, state, keys, Some(limit)).await
 }
 
-async fn timeout<T: Future<Output = ReturnValue>>(
-    fut: T,
-    secs: UTimeout,
+/// Like `blocking_keys_timeout`, for any kind of key. Waits forever if `limit`
+/// is None, or until the client is killed.
+pub async fn blocking_timeout(
+    f: YieldingFn,
     state: StateRef,
-    receipt: Receipt,
+    keys: Vec<KeyTypes>,
+    limit: Option<Duration>,
 ) -> ReturnValue {
-    match time::timeout(Duration::from_secs(secs as u64), fut).await {
-        Ok(ret) => ret,
-        Err(_) => {
-            let mut rm = state.reciept_map.lock();
-            rm.timeout_receipt(receipt);
-            ReturnValue::Nil
+    let receipt = state.get_receipt();
+    let kb = KeyBlocking::new(f, state, keys, receipt);
+    let waited = async {
+        match limit {
+            // Dropping `kb` stops it waiting.
+            Some(limit) => time::timeout(limit, kb).await.unwrap_or(ReturnValue::Nil),
+            None => kb.await,
         }
+    };
+    // A killed client stops waiting, and is hung up on.
+    tokio::select! {
+        res = waited => res,
+        _ = killed() => ReturnValue::Nil,
     }
 }
+
+/// Wake whoever waits on `key` once `delay` passes, for things which become
+/// ready with time rather than a write.
+pub fn wake_after(state: StateRef, key: KeyTypes, delay: Duration) {
+    tokio::spawn(async move {
+        time::sleep(delay).await;
+        state.reciept_map.lock().wake_all_with_key(key);
+    });
+}
@@ -0,0 +1,67 @@
+//! TLS for client connections (enabled by the "tls" feature).
+//!
+//! Given `--tls-cert` and `--tls-key`, `socket_listener` wraps every
+//! accepted connection in a TLS session before serving it. Both files are
+//! PEM: the certificate chain, leaf first, and a PKCS#8 or RSA private key.
+
+use rustls_pemfile::Item;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("no private key found in {}", path.display()).into())
+}
+
+/// Build the acceptor serving the certificate chain in `cert` with the key in `key`.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod test_tls {
+    use super::{load_certs, load_key};
+    use std::io::Write;
+
+    #[test]
+    fn test_missing_pem_contents() {
+        let dir = std::env::temp_dir().join(format!("redis-oxide-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("empty.pem");
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "not a pem file").unwrap();
+        let err = load_certs(&path).unwrap_err().to_string();
+        assert!(err.starts_with("no certificates found"), "{}", err);
+        let err = load_key(&path).unwrap_err().to_string();
+        assert!(err.starts_with("no private key found"), "{}", err);
+        assert!(load_certs(&dir.join("missing.pem")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use crate::data_structures::heavy_keeper::TopK;
+use crate::ops::RVec;
+use crate::types::{Count, Key, RedisBool, ReturnValue, StateRef, Value};
+use crate::{make_reader, op_variants};
+use dashmap::mapref::entry::Entry;
+
+op_variants! {
+    TopKOps,
+    TopKReserve(Key, usize, usize, usize, f64),
+    TopKAdd(Key, RVec<Value>),
+    TopKQuery(Key, RVec<Value>),
+    TopKCount(Key, RVec<Value>),
+    TopKList(Key, bool)
+}
+
+impl TopKOps {
+    /// Keys written by this op, or None if it's read-only.
+    pub fn write_keys(&self) -> Option<Vec<Key>> {
+        match self {
+            TopKOps::TopKReserve(key, ..) | TopKOps::TopKAdd(key, _) => Some(vec![key.clone()]),
+            TopKOps::TopKQuery(..) | TopKOps::TopKCount(..) | TopKOps::TopKList(..) => None,
+        }
+    }
+}
+
+/// The width, depth and decay of TOPK.RESERVE without them, like RedisBloom.
+pub const DEFAULT_WIDTH: usize = 8;
+pub const DEFAULT_DEPTH: usize = 7;
+pub const DEFAULT_DECAY: f64 = 0.9;
+
+make_reader!(topks, read_topks);
+
+const MISSING: ReturnValue = ReturnValue::Error(b"TopK: key does not exist");
+
+pub async fn top_k_interact(top_k_op: TopKOps, state: StateRef) -> ReturnValue {
+    match top_k_op {
+        TopKOps::TopKReserve(key, k, width, depth, decay) => match state.topks.entry(key) {
+            Entry::Occupied(_) => ReturnValue::Error(b"TopK: key already exists"),
+            Entry::Vacant(entry) => {
+                entry.insert(TopK::new(k, width, depth, decay));
+                ReturnValue::Ok
+            }
+        },
+        TopKOps::TopKAdd(key, items) => match state.topks.get_mut(&key) {
+            Some(mut topk) => ReturnValue::Array(
+                items
+                    .iter()
+                    .map(|item| {
+                        topk.add(item)
+                            .map_or(ReturnValue::Nil, ReturnValue::StringRes)
+                    })
+                    .collect(),
+            ),
+            None => MISSING,
+        },
+        TopKOps::TopKQuery(key, items) => match read_topks!(state, &key) {
+            Some(topk) => ReturnValue::Array(
+                items
+                    .iter()
+                    .map(|item| ReturnValue::IntRes(topk.contains(item) as RedisBool))
+                    .collect(),
+            ),
+            None => MISSING,
+        },
+        TopKOps::TopKCount(key, items) => match read_topks!(state, &key) {
+            Some(topk) => ReturnValue::Array(
+                items
+                    .iter()
+                    .map(|item| ReturnValue::IntRes(topk.count(item) as Count))
+                    .collect(),
+            ),
+            None => MISSING,
+        },
+        TopKOps::TopKList(key, with_count) => match read_topks!(state, &key) {
+            Some(topk) => ReturnValue::Array(
+                topk.list()
+                    .into_iter()
+                    .flat_map(|(item, count)| {
+                        let mut reply = vec![ReturnValue::StringRes(item)];
+                        if with_count {
+                            reply.push(ReturnValue::IntRes(count as Count));
+                        }
+                        reply
+                    })
+                    .collect(),
+            ),
+            None => MISSING,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_top_k {
+    use crate::ops::{translate, Ops};
+    use crate::test_util::command;
+    use crate::top_k::{top_k_interact, TopKOps};
+    use crate::types::{ReturnValue, State, StateStore};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_reserve_add_list() {
+        let key = Bytes::from_static(b"key");
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let eng = Arc::new(State::default());
+        let add = TopKOps::TopKAdd(key.clone(), smallvec![a.clone()]);
+        let res = top_k_interact(add, eng.clone()).await;
+        assert_eq!(res, ReturnValue::Error(b"TopK: key does not exist"));
+
+        let reserve = TopKOps::TopKReserve(key.clone(), 1, 10, 2, 0.9);
+        assert_eq!(
+            top_k_interact(reserve.clone(), eng.clone()).await,
+            ReturnValue::Ok
+        );
+        let res = top_k_interact(reserve, eng.clone()).await;
+        assert_eq!(res, ReturnValue::Error(b"TopK: key already exists"));
+
+        let add = TopKOps::TopKAdd(key.clone(), smallvec![a.clone(), b.clone(), b.clone()]);
+        assert_eq!(
+            top_k_interact(add, eng.clone()).await,
+            ReturnValue::Array(vec![
+                ReturnValue::Nil,
+                ReturnValue::Nil,
+                ReturnValue::StringRes(a.clone())
+            ])
+        );
+        let query = TopKOps::TopKQuery(key.clone(), smallvec![a.clone(), b.clone()]);
+        assert_eq!(
+            top_k_interact(query, eng.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::IntRes(0), ReturnValue::IntRes(1)])
+        );
+        let count = TopKOps::TopKCount(key.clone(), smallvec![a, b.clone()]);
+        assert_eq!(
+            top_k_interact(count, eng.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::IntRes(1), ReturnValue::IntRes(2)])
+        );
+        assert_eq!(
+            top_k_interact(TopKOps::TopKList(key, true), eng.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::StringRes(b), ReturnValue::IntRes(2)])
+        );
+    }
+
+    #[test]
+    fn test_reserve_limits() {
+        let store = Arc::new(StateStore::default());
+        let reserve = |args: &[&str]| {
+            let mut reserve = vec!["TOPK.RESERVE", "key"];
+            reserve.extend(args);
+            translate(command(&reserve), store.clone())
+        };
+        assert!(matches!(reserve(&["10"]), Ok(Ops::TopKs(_))));
+        assert!(matches!(
+            reserve(&["10", "1000", "5", "0.9"]),
+            Ok(Ops::TopKs(_))
+        ));
+        let huge = i64::MAX.to_string();
+        assert!(reserve(&[&huge]).is_err());
+        assert!(reserve(&["10", &huge, "3", "0.9"]).is_err());
+        assert!(reserve(&["10", "100000000", "7", "0.9"]).is_err());
+    }
+}
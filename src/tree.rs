@@ -0,0 +1,238 @@
+//! Keyspace tree (the TREE command), for finding what's filling up a db.
+//!
+//! Keys are grouped by the prefixes before each separator, so with ":"
+//! "user:1:name" is counted under "user:" and "user:1:". Only prefixes become
+//! nodes, but every distinct prefix does: keys like "session:<id>:data" add
+//! a node per id. A key adds at most one node per level, and levels stop at
+//! the depth asked for, so the tree is at most that many times the keys.
+
+use crate::coop::{visit_entries, Budget};
+use crate::for_each_structure;
+use crate::memory::EstimateSize;
+use crate::types::{Count, ReturnValue, State, Value};
+use bytes::Bytes;
+use std::collections::{btree_map, BTreeMap};
+
+pub const DEFAULT_SEPARATOR: &[u8] = b":";
+/// Levels of prefixes below the root when DEPTH isn't given.
+pub const DEFAULT_TREE_DEPTH: usize = 16;
+/// The most levels DEPTH may ask for.
+pub const MAX_TREE_DEPTH: usize = 128;
+
+/// Keys under a prefix, and roughly how much memory they use.
+#[derive(Debug, Default, PartialEq)]
+pub struct TreeNode {
+    pub keys: u64,
+    pub bytes: u64,
+    /// Keyed by the full prefix, separator included.
+    pub children: BTreeMap<Value, TreeNode>,
+}
+
+/// Offsets just past each separator in `key`.
+fn prefix_ends<'a>(key: &'a [u8], separator: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    let mut start = 0;
+    std::iter::from_fn(move || {
+        if separator.is_empty() {
+            return None;
+        }
+        while start + separator.len() <= key.len() {
+            if key[start..].starts_with(separator) {
+                start += separator.len();
+                return Some(start);
+            }
+            start += 1;
+        }
+        None
+    })
+}
+
+impl TreeNode {
+    /// Count a key using `size` bytes here and under each of its prefixes,
+    /// going at most `max_depth` levels down.
+    pub fn insert(&mut self, key: &[u8], size: usize, separator: &[u8], max_depth: usize) {
+        self.keys += 1;
+        self.bytes += size as u64;
+        let mut node = self;
+        for end in prefix_ends(key, separator).take(max_depth) {
+            let prefix = &key[..end];
+            // Most prefixes are already there: only copy new ones.
+            if !node.children.contains_key(prefix) {
+                let prefix = Bytes::copy_from_slice(prefix);
+                node.children.insert(prefix, TreeNode::default());
+            }
+            node = node.children.get_mut(prefix).expect("the prefix was added");
+            node.keys += 1;
+            node.bytes += size as u64;
+        }
+    }
+
+    /// Reply as nested `[prefix, keys, bytes, [children...]]` arrays. Built
+    /// with a stack of the nodes on the way down rather than by recursion.
+    pub fn into_reply(mut self, prefix: Value) -> ReturnValue {
+        let mut stack = vec![ReplyFrame::new(prefix, &mut self)];
+        loop {
+            let top = stack.last_mut().expect("the root is popped last");
+            if let Some((prefix, mut child)) = top.children.next() {
+                stack.push(ReplyFrame::new(prefix, &mut child));
+                continue;
+            }
+            let reply = stack.pop().expect("the root is popped last").into_reply();
+            match stack.last_mut() {
+                Some(parent) => parent.replies.push(reply),
+                None => return reply,
+            }
+        }
+    }
+}
+
+/// A node whose children are being turned into replies.
+struct ReplyFrame {
+    prefix: Value,
+    keys: u64,
+    bytes: u64,
+    children: btree_map::IntoIter<Value, TreeNode>,
+    replies: Vec<ReturnValue>,
+}
+
+impl ReplyFrame {
+    fn new(prefix: Value, node: &mut TreeNode) -> ReplyFrame {
+        let children = std::mem::take(&mut node.children);
+        ReplyFrame {
+            prefix,
+            keys: node.keys,
+            bytes: node.bytes,
+            replies: Vec::with_capacity(children.len()),
+            children: children.into_iter(),
+        }
+    }
+
+    fn into_reply(self) -> ReturnValue {
+        ReturnValue::Array(vec![
+            ReturnValue::StringRes(self.prefix),
+            ReturnValue::IntRes(self.keys as Count),
+            ReturnValue::IntRes(self.bytes as Count),
+            ReturnValue::Array(self.replies),
+        ])
+    }
+}
+
+impl Drop for TreeNode {
+    /// Drop descendants one at a time rather than recursively.
+    fn drop(&mut self) {
+        let mut stack: Vec<TreeNode> = std::mem::take(&mut self.children)
+            .into_iter()
+            .map(|(_, child)| child)
+            .collect();
+        while let Some(mut node) = stack.pop() {
+            stack.extend(
+                std::mem::take(&mut node.children)
+                    .into_iter()
+                    .map(|(_, child)| child),
+            );
+        }
+    }
+}
+
+/// Add every key of each passed structure to the tree.
+macro_rules! insert_structures {
+    ($state:expr, $budget:expr, $root:expr, $separator:expr, $max_depth:expr, $($type:ident: $schema:expr),*) => {
+        $(
+            visit_entries(&$state.$type, $budget, |key, value| {
+                let size = key.len() + value.estimated_size();
+                $root.insert(key, size, $separator, $max_depth);
+            })
+            .await;
+        )*
+    };
+}
+
+/// Build the keyspace tree of a db. Yields as it goes, and never holds
+/// more than the tree itself in memory.
+pub async fn keyspace_tree(
+    state: &State,
+    separator: &[u8],
+    max_depth: usize,
+    budget: &mut Budget,
+) -> TreeNode {
+    let mut root = TreeNode::default();
+    for_each_structure!(insert_structures!(
+        state, budget, root, separator, max_depth,
+    ));
+    root
+}
+
+#[cfg(test)]
+mod test_tree {
+    use super::{keyspace_tree, prefix_ends, TreeNode, DEFAULT_SEPARATOR, DEFAULT_TREE_DEPTH};
+    use crate::coop::Budget;
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_prefix_ends() {
+        let ends: Vec<usize> = prefix_ends(b"a:bc:d", b":").collect();
+        assert_eq!(ends, vec![2, 5]);
+        let ends: Vec<usize> = prefix_ends(b"a::b::", b"::").collect();
+        assert_eq!(ends, vec![3, 6]);
+        assert_eq!(prefix_ends(b"plain", b":").count(), 0);
+    }
+
+    #[test]
+    fn test_insert_depth() {
+        let mut root = TreeNode::default();
+        root.insert(b"user:1:name", 10, b":", 2);
+        root.insert(b"user:2:name", 5, b":", 1);
+        root.insert(b"flat", 1, b":", 2);
+        assert_eq!((root.keys, root.bytes), (3, 16));
+        assert_eq!(root.children.len(), 1);
+        let user = &root.children[&Bytes::from_static(b"user:")];
+        assert_eq!((user.keys, user.bytes), (2, 15));
+        // The second key stopped at depth 1.
+        assert_eq!(user.children.len(), 1);
+        assert_eq!(user.children[&Bytes::from_static(b"user:1:")].keys, 1);
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_tree() {
+        let state = State::default();
+        state
+            .kv
+            .insert(Bytes::from_static(b"a:x"), Bytes::from_static(b"12345"));
+        let list: VecDeque<Bytes> = vec![Bytes::from_static(b"1")].into();
+        state.lists.insert(Bytes::from_static(b"a:y"), list);
+        let root = keyspace_tree(
+            &state,
+            DEFAULT_SEPARATOR,
+            DEFAULT_TREE_DEPTH,
+            &mut Budget::default(),
+        )
+        .await;
+        assert_eq!(root.keys, 2);
+        assert_eq!(root.children[&Bytes::from_static(b"a:")].keys, 2);
+        match root.into_reply(Bytes::new()) {
+            ReturnValue::Array(node) => assert_eq!(node[1], ReturnValue::IntRes(2)),
+            _ => panic!("tree reply should be an array"),
+        }
+    }
+
+    #[test]
+    fn test_deep_reply() {
+        let key = ":".repeat(10_000);
+        let mut root = TreeNode::default();
+        root.insert(key.as_bytes(), 1, b":", usize::MAX);
+        // Deep enough to overflow the stack if built recursively.
+        let mut reply = root.into_reply(Bytes::new());
+        let mut depth = 0;
+        while let ReturnValue::Array(mut node) = reply {
+            match node.pop() {
+                Some(ReturnValue::Array(mut children)) if !children.is_empty() => {
+                    reply = children.pop().unwrap();
+                    depth += 1;
+                }
+                _ => break,
+            }
+        }
+        assert_eq!(depth, 10_000);
+    }
+}
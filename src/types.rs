@@ -1,18 +1,40 @@
 use bytes::Bytes;
 use dashmap::DashMap;
-use growable_bloom_filter::GrowableBloom;
 /// Common Types in the project.
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::From;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::Arc;
 
 use parking_lot::{Mutex, RwLock};
-use std::fs::File;
+use std::path::PathBuf;
+use tokio::sync::{Notify, OnceCell};
 
+use crate::backing::Backing;
+use crate::cdc::CdcSender;
+use crate::clients::ClientRegistry;
+use crate::config::RuntimeConfig;
+use crate::data_structures::bloom_filter::BloomFilter;
+use crate::data_structures::count_min_sketch::CountMinSketch;
+use crate::data_structures::cuckoo_filter::CuckooFilter;
+use crate::data_structures::heavy_keeper::TopK;
+use crate::data_structures::negative_cache::NegativeCache;
+use crate::data_structures::queue::Queue;
 use crate::data_structures::receipt_map::RecieptMap;
 use crate::data_structures::sorted_set::SortedSet;
 use crate::data_structures::stack::Stack;
+use crate::data_structures::stream::Stream;
+use crate::database::{Aof, DumpLayout, PersistDbs};
+use crate::escape::Escaped;
+use crate::latency::LatencyMonitor;
+use crate::memory::MemoryTracker;
+use crate::monitor::Monitor;
+use crate::pubsub::PubSub;
+use crate::replication::Replication;
+use crate::scripting::{FunctionLibrary, ScriptCache};
+use crate::slowlog::SlowLog;
+use crate::stats::Stats;
+use crate::write_effects::WriteTracker;
 
 /// These types are used by state and ops to actually perform useful work.
 pub type Value = Bytes;
@@ -22,15 +44,72 @@ pub type Key = Bytes;
 pub type Count = i64;
 /// Index is used to represent indices in structures.
 pub type Index = i64;
-/// Score is used in sorted sets
-pub type Score = i64;
+/// Score is used in sorted sets. A float which is never NaN, so scores
+/// are totally ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Score(f64);
+
+impl Score {
+    /// None for NaN. Negative zero becomes zero, so equal scores look the same.
+    pub fn new(score: f64) -> Option<Score> {
+        match score {
+            s if s.is_nan() => None,
+            s if s == 0.0 => Some(Score(0.0)),
+            s => Some(Score(s)),
+        }
+    }
+
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+// Goes through Score::new, so a NaN in a dump can't break the ordering.
+impl<'de> serde::Deserialize<'de> for Score {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let score = f64::deserialize(deserializer)?;
+        Score::new(score).ok_or_else(|| serde::de::Error::custom("score is not a number"))
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.partial_cmp(&other.0).expect("scores are never NaN")
+    }
+}
+
+impl From<i64> for Score {
+    fn from(score: i64) -> Score {
+        Score(score as f64)
+    }
+}
+
+/// Formats scores like redis: "1", "1.5", "inf" and "-inf".
+impl std::fmt::Display for Score {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            s if s == f64::INFINITY => write!(f, "inf"),
+            s if s == f64::NEG_INFINITY => write!(f, "-inf"),
+            s => write!(f, "{}", s),
+        }
+    }
+}
+
 /// Timeout unit
 pub type UTimeout = i64;
 /// Bool type
 pub type RedisBool = i64;
 
-/// DumpTimeoutUnitpe alias.
-pub type DumpFile = Arc<Mutex<File>>;
+/// Path of the dump file. The lock is held while saving.
+pub type DumpFile = Arc<Mutex<PathBuf>>;
 
 /// RedisValueRef is the canonical type for values flowing
 /// through the system. Inputs are converted into RedisValues,
@@ -43,6 +122,8 @@ pub enum RedisValueRef {
     ErrorMsg(Vec<u8>),
     Int(i64),
     Array(Vec<RedisValueRef>),
+    /// Out of band data for RESP3 clients, like pub/sub messages.
+    Push(Vec<RedisValueRef>),
     NullArray,
     NullBulkString,
 }
@@ -50,20 +131,12 @@ pub enum RedisValueRef {
 impl std::fmt::Debug for RedisValueRef {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RedisValueRef::BulkString(s) => write!(
-                f,
-                "RedisValueRef::BulkString({:?})",
-                String::from_utf8_lossy(s)
-            ),
-            RedisValueRef::SimpleString(s) => write!(
-                f,
-                "RedisValueRef::SimpleString({:?})",
-                String::from_utf8_lossy(s)
-            ),
-            RedisValueRef::Error(s) => {
-                write!(f, "RedisValueRef::Error({:?})", String::from_utf8_lossy(s))
+            RedisValueRef::BulkString(s) => write!(f, "RedisValueRef::BulkString({})", Escaped(s)),
+            RedisValueRef::SimpleString(s) => {
+                write!(f, "RedisValueRef::SimpleString({})", Escaped(s))
             }
-            RedisValueRef::ErrorMsg(s) => write!(f, "RedisValueRef::ErrorMsg({:?})", s),
+            RedisValueRef::Error(s) => write!(f, "RedisValueRef::Error({})", Escaped(s)),
+            RedisValueRef::ErrorMsg(s) => write!(f, "RedisValueRef::ErrorMsg({})", Escaped(s)),
 
             RedisValueRef::Int(i) => write!(f, "RedisValueRef::Int({:?})", i),
             RedisValueRef::NullBulkString => write!(f, "RedisValueRef::NullBulkString"),
@@ -77,10 +150,24 @@ impl std::fmt::Debug for RedisValueRef {
                 write!(f, ")")?;
                 Ok(())
             }
+            RedisValueRef::Push(arr) => {
+                write!(f, "RedisValueRef::Push(")?;
+                for item in arr {
+                    write!(f, "{:?}", item)?;
+                    write!(f, ",")?;
+                }
+                write!(f, ")")?;
+                Ok(())
+            }
         }
     }
 }
 
+/// A bulk string, for building commands and replies.
+pub fn bulk(b: impl Into<Bytes>) -> RedisValueRef {
+    RedisValueRef::BulkString(b.into())
+}
+
 // // TODO: Get rid of this
 // impl<'a> From<RedisValueRef> for RedisValueRef {
 //     fn from(other: RedisValueRef) -> RedisValueRef {
@@ -143,10 +230,12 @@ impl From<Vec<String>> for ReturnValue {
 /// Convenience method to determine an error. Used in testing.
 impl ReturnValue {
     pub fn is_error(&self) -> bool {
-        if let ReturnValue::Error(_) = *self {
-            return true;
-        }
-        false
+        matches!(
+            self,
+            ReturnValue::Error(_)
+                | ReturnValue::Ident(RedisValueRef::Error(_))
+                | ReturnValue::Ident(RedisValueRef::ErrorMsg(_))
+        )
     }
 }
 
@@ -161,9 +250,16 @@ type KeyHash = DashMap<Key, HashMap<Key, Value>>;
 /// Canonical type for Key-Hash storage.
 type KeyZSet = DashMap<Key, SortedSet>;
 /// Canonical type for Key-Bloom storage.
-type KeyBloom = DashMap<Key, GrowableBloom>;
+type KeyBloom = DashMap<Key, BloomFilter>;
 type KeyStack = DashMap<Key, Stack<Value>>;
 type KeyHyperLogLog = DashMap<Key, amadeus_streaming::HyperLogLog<Value>>;
+type KeyStream = DashMap<Key, Stream>;
+type KeyCuckoo = DashMap<Key, CuckooFilter>;
+type KeyCountMin = DashMap<Key, CountMinSketch>;
+type KeyTopK = DashMap<Key, TopK>;
+type KeyQueue = DashMap<Key, Queue>;
+/// Deadline of each expiring key, in unix milliseconds.
+type KeyExpirations = DashMap<Key, u64>;
 
 /// Top level database struct.
 /// Holds all StateRef dbs, and will hand them out on request.
@@ -173,11 +269,95 @@ pub struct StateStore {
     #[serde(skip)]
     pub commands_ran_since_save: AtomicU64,
     #[serde(skip)]
-    pub commands_threshold: u64,
-    #[serde(skip)]
     pub memory_only: bool,
     #[serde(skip)]
     pub foreign_functions: RwLock<HashSet<String>>,
+    /// Settings CONFIG can read and change.
+    #[serde(skip)]
+    pub config: RuntimeConfig,
+    #[serde(skip)]
+    pub stats: Stats,
+    #[serde(skip)]
+    pub script_cache: ScriptCache,
+    /// Sources of functions defined with def-redis-fn.
+    #[serde(skip)]
+    pub functions: FunctionLibrary,
+    #[serde(skip)]
+    pub cdc: Option<CdcSender>,
+    #[serde(skip)]
+    pub backing: Option<Arc<Backing>>,
+    /// Where saves go. Set once at startup.
+    #[serde(skip)]
+    pub dump_file: OnceCell<DumpFile>,
+    /// Set once the append-only file is started.
+    #[serde(skip)]
+    pub aof: OnceCell<Aof>,
+    #[serde(skip)]
+    pub dump_layout: DumpLayout,
+    #[serde(skip)]
+    pub persist_dbs: PersistDbs,
+    #[serde(skip)]
+    pub protect_flush: FlushProtection,
+    #[serde(skip)]
+    pub pfadd_cardinality_reply: bool,
+    #[serde(skip)]
+    pub latency: LatencyMonitor,
+    #[serde(skip)]
+    pub slowlog: SlowLog,
+    #[serde(skip)]
+    pub pubsub: PubSub,
+    #[serde(skip)]
+    pub monitor: Monitor,
+    #[serde(skip)]
+    pub clients: ClientRegistry,
+    /// Set when no scripting engine is running, so scripts are refused.
+    #[serde(skip)]
+    pub scripting_disabled: AtomicBool,
+    /// Set when running inside another program, which SHUTDOWN mustn't exit.
+    #[serde(skip)]
+    pub embedded: AtomicBool,
+    /// Set by DEBUG SET-ACTIVE-EXPIRE 0: keys then only expire when touched.
+    #[serde(skip)]
+    pub active_expire_disabled: AtomicBool,
+    /// Set once a warm restart or shutdown has begun; no more commands are accepted.
+    #[serde(skip)]
+    pub handing_off: AtomicBool,
+    #[serde(skip)]
+    pub commands_in_flight: AtomicUsize,
+    /// Tells the socket listener to stop accepting connections.
+    #[serde(skip)]
+    pub stop_accepting: Notify,
+    /// Tells a stopped socket listener to accept connections again.
+    #[serde(skip)]
+    pub resume_accepting: Notify,
+    /// SWAPDBs so far, so connections know to look their db up again.
+    #[serde(skip)]
+    pub db_swaps: AtomicU64,
+    #[serde(skip)]
+    pub swapping_dbs: Mutex<()>,
+    /// Held by writes from running until they're logged, see `order_write`.
+    #[serde(skip)]
+    pub write_order: tokio::sync::RwLock<()>,
+    /// Held alone by atomic scripts while they run, see `server::order_script`.
+    #[serde(skip)]
+    pub script_lock: tokio::sync::RwLock<()>,
+    #[serde(skip)]
+    pub replication: Replication,
+}
+
+/// How FLUSHALL/FLUSHDB are guarded (`--protect-flush`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushProtection {
+    Off,
+    /// Only flush when called with CONFIRM.
+    Confirm,
+    Disabled,
+}
+
+impl Default for FlushProtection {
+    fn default() -> Self {
+        FlushProtection::Off
+    }
 }
 
 /// Reference type for `StateStore`
@@ -206,8 +386,35 @@ pub struct State {
     pub stacks: KeyStack,
     #[serde(default)]
     pub hyperloglogs: KeyHyperLogLog,
+    #[serde(default)]
+    pub streams: KeyStream,
+    #[serde(default)]
+    pub cuckoos: KeyCuckoo,
+    #[serde(default)]
+    pub count_mins: KeyCountMin,
+    #[serde(default)]
+    pub topks: KeyTopK,
+    #[serde(default)]
+    pub queues: KeyQueue,
+    #[serde(default)]
+    pub expirations: KeyExpirations,
     #[serde(skip)]
     pub reciept_map: Mutex<RecieptMap>,
+    #[serde(skip)]
+    pub negative_cache: NegativeCache,
+    #[serde(skip)]
+    pub writes: WriteTracker,
+    #[serde(skip)]
+    pub memory: MemoryTracker,
+    /// Keys expired since the last `take_expired`.
+    #[serde(skip)]
+    pub expired: Mutex<Vec<Key>>,
+    /// Where the active expiry sweep carries on: (shard, offset) in `expirations`.
+    #[serde(skip)]
+    pub expire_cursor: Mutex<(usize, usize)>,
+    /// Reply to PFADD with whether the estimate changed, rather than whether a register did.
+    #[serde(skip)]
+    pub pfadd_cardinality_reply: AtomicBool,
 }
 
 /// Mapping of a ReturnValue to a RedisValueRef.
@@ -229,3 +436,17 @@ impl From<ReturnValue> for RedisValueRef {
         }
     }
 }
+
+#[cfg(test)]
+mod test_types {
+    use crate::types::Score;
+
+    #[test]
+    fn test_nan_scores_are_rejected() {
+        let bytes = rmps::to_vec(&Score::new(1.5).unwrap()).unwrap();
+        let score: Score = rmps::from_read_ref(&bytes).unwrap();
+        assert_eq!(score, Score::new(1.5).unwrap());
+        let nan = rmps::to_vec(&f64::NAN).unwrap();
+        assert!(rmps::from_read_ref::<_, Score>(&nan).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! Bookkeeping shared by every write.
+//!
+//! Rather than each command invalidating caches and bumping versions itself,
+//! `op_interact` works out the `WriteEffects` of an op from its write keys and
+//! applies them once the op succeeds. Applying them:
+//!
+//! - makes the negative cache forget the keys,
+//! - ticks the db's write clock, for snapshots to tell if they're consistent,
+//! - marks them dirty, for incremental persistence,
+//! - bumps their versions, for WATCH,
+//! - re-estimates their memory use, while maxmemory is set,
+//! - tells anyone listening through `WriteTracker::subscribe`,
+//! - and keeps them for keyspace notifications, when those are on.
+//!
+//! An op that turns out to change only some of its write keys, like a DEL of
+//! missing keys or an LPOP of an empty list, reports the ones it did change
+//! with `touched` (or `untouched`), and only those have effects.
+
+use crate::ops::Ops;
+use crate::types::{Key, State};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::broadcast;
+
+/// Notifications kept for slow listeners before they start missing some.
+const NOTIFICATION_BACKLOG: usize = 1024;
+
+/// Dirty keys kept before the whole db is marked dirty instead.
+const DIRTY_KEYS_LIMIT: usize = 64 * 1024;
+
+tokio::task_local! {
+    /// The keys the running op reported changing, see `touched`.
+    static TOUCHED: RefCell<Option<Vec<Key>>>;
+}
+
+/// Report that the running op changed only `keys` of its write keys.
+///
+/// The keys keep the order of the op's write keys. Outside `track_touched`,
+/// like in tests calling an interact function directly, it does nothing.
+pub fn touched(keys: Vec<Key>) {
+    let _ = TOUCHED.try_with(|touched| *touched.borrow_mut() = Some(keys));
+}
+
+/// Report that the running op changed nothing, like SADD of a member the set
+/// has already.
+pub fn untouched() {
+    touched(Vec::new());
+}
+
+/// Run `op`, returning what it reported with `touched`: None if it didn't,
+/// so every write key counts as changed.
+pub async fn track_touched<F: Future>(op: F) -> (F::Output, Option<Vec<Key>>) {
+    TOUCHED
+        .scope(RefCell::new(None), async {
+            let output = op.await;
+            (output, TOUCHED.with(|touched| touched.borrow_mut().take()))
+        })
+        .await
+}
+
+/// What a successful write did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteEffects {
+    /// The op's name, like "LPush", or "Expired" for keys that expired.
+    pub event: &'static str,
+    /// The keys written. Empty when every key was, like for flushes.
+    pub keys: Vec<Key>,
+}
+
+impl WriteEffects {
+    pub fn new(event: &'static str, keys: Vec<Key>) -> Self {
+        WriteEffects { event, keys }
+    }
+
+    /// The effects `op` has if it succeeds, or None if it's read-only.
+    pub fn of(op: &Ops) -> Option<Self> {
+        Some(WriteEffects::new(op.name(), op.write_keys()?))
+    }
+
+    /// Narrow these effects to the keys the op reported changing with
+    /// `touched`. None if it changed nothing.
+    pub fn narrow(mut self, touched: Option<Vec<Key>>) -> Option<Self> {
+        match touched {
+            None => Some(self),
+            Some(touched) if touched.is_empty() => None,
+            Some(touched) => {
+                self.keys.retain(|key| touched.contains(key));
+                Some(self)
+            }
+        }
+    }
+
+    /// Whether every key in the db was written.
+    pub fn is_flush(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Record these effects against `state`.
+    pub fn apply(&self, state: &State) {
+        state.negative_cache.invalidate(&self.keys);
+        // A swapped db keeps its keys, and so their estimates.
+        if self.event != "SwapDB" {
+            state.memory.written(state, &self.keys);
+        }
+        state.writes.record(state, self);
+    }
+}
+
+/// Keys written since the db was last persisted.
+#[derive(Debug, Default, PartialEq)]
+pub struct Dirty {
+    /// The db was flushed, or too many keys were written to keep track of,
+    /// so everything needs persisting.
+    pub everything: bool,
+    pub keys: HashSet<Key>,
+}
+
+/// The write clock, dirty keys, key versions and write notifications of one db.
+///
+/// Only keys that exist have a version, and dirty keys are dropped once
+/// persisted (or past `DIRTY_KEYS_LIMIT`), so neither grows with every key
+/// ever written.
+pub struct WriteTracker {
+    clock: AtomicU64,
+    /// Clock reading of the last flush, swap or delete. Keys without a
+    /// version have this one.
+    forgotten_at: AtomicU64,
+    versions: DashMap<Key, u64>,
+    dirty: Mutex<Dirty>,
+    notifications: broadcast::Sender<WriteEffects>,
+    /// Effects not yet published as keyspace notifications.
+    events: Mutex<Vec<WriteEffects>>,
+    keep_events: AtomicBool,
+}
+
+impl Default for WriteTracker {
+    fn default() -> Self {
+        let (notifications, _) = broadcast::channel(NOTIFICATION_BACKLOG);
+        WriteTracker {
+            clock: AtomicU64::new(0),
+            forgotten_at: AtomicU64::new(0),
+            versions: DashMap::new(),
+            dirty: Mutex::new(Dirty::default()),
+            notifications,
+            events: Mutex::new(Vec::new()),
+            keep_events: AtomicBool::new(false),
+        }
+    }
+}
+
+impl WriteTracker {
+    fn record(&self, state: &State, effects: &WriteEffects) {
+        let now = self.clock.fetch_add(1, Ordering::SeqCst) + 1;
+        if effects.is_flush() {
+            self.forget_all(now);
+        } else {
+            for key in effects.keys.iter() {
+                if state.structure_of(key).is_some() {
+                    self.versions.insert(key.clone(), now);
+                } else {
+                    // Moving every untracked key's version along may make
+                    // a WATCH fail needlessly, but never miss this delete.
+                    self.forgotten_at.store(now, Ordering::SeqCst);
+                    self.versions.remove(key);
+                }
+            }
+            let mut dirty = self.dirty.lock();
+            if !dirty.everything {
+                dirty.keys.extend(effects.keys.iter().cloned());
+                if dirty.keys.len() > DIRTY_KEYS_LIMIT {
+                    dirty.everything = true;
+                    dirty.keys = HashSet::new();
+                }
+            }
+        }
+        if self.keep_events.load(Ordering::Relaxed) {
+            self.events.lock().push(effects.clone());
+        }
+        // Nobody listening isn't an error.
+        let _ = self.notifications.send(effects.clone());
+    }
+
+    /// Every key changed at `now`, like on a flush or swap.
+    fn forget_all(&self, now: u64) {
+        self.forgotten_at.store(now, Ordering::SeqCst);
+        self.versions.clear();
+        let mut dirty = self.dirty.lock();
+        dirty.everything = true;
+        dirty.keys = HashSet::new();
+    }
+
+    /// Version of `key`. It changes whenever the key is written.
+    pub fn version(&self, key: &[u8]) -> u64 {
+        match self.versions.get(key) {
+            Some(version) => *version,
+            None => self.forgotten_at.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Writes applied so far. Compare two readings to tell whether anything
+    /// was written in between.
+    pub fn clock(&self) -> u64 {
+        self.clock.load(Ordering::SeqCst)
+    }
+
+    /// Take the keys written since the last call, like when the db is
+    /// persisted.
+    pub fn take_dirty(&self) -> Dirty {
+        std::mem::take(&mut *self.dirty.lock())
+    }
+
+    /// Whether to keep effects for `take_events`.
+    pub fn keep_events(&self, keep: bool) {
+        self.keep_events.store(keep, Ordering::Relaxed);
+        if !keep {
+            self.events.lock().clear();
+        }
+    }
+
+    /// Take the effects kept since the last call.
+    pub fn take_events(&self) -> Vec<WriteEffects> {
+        std::mem::take(&mut *self.events.lock())
+    }
+
+    /// Listen to every write applied from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<WriteEffects> {
+        self.notifications.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod test_write_effects {
+    use super::{WriteEffects, DIRTY_KEYS_LIMIT};
+    use crate::keys::KeyOps;
+    use crate::ops::{op_interact, Ops};
+    use crate::types::{ReturnValue, State, StateRef, StateStore};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_writes_are_tracked() {
+        let state: StateRef = Arc::new(State::default());
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        let mut notifications = state.writes.subscribe();
+        let before = state.writes.clock();
+        let version = state.writes.version(&a);
+
+        let op = KeyOps::Set(a.clone(), b.clone(), Default::default());
+        let (reply, effects) = op_interact(Ops::Keys(op), state.clone()).await;
+        assert_eq!(reply, ReturnValue::Ok);
+        let expected = WriteEffects::new("Set", vec![a.clone()]);
+        assert_eq!(effects, Some(expected.clone()));
+        assert_eq!(notifications.try_recv().unwrap(), expected);
+        assert_eq!(state.writes.clock(), before + 1);
+        assert_ne!(state.writes.version(&a), version);
+        assert_eq!(state.writes.version(&b), version);
+
+        // Reads and failed writes have no effects.
+        let (_, effects) = op_interact(Ops::Keys(KeyOps::Get(a.clone())), state.clone()).await;
+        assert_eq!(effects, None);
+        let (reply, effects) = op_interact(Ops::Keys(KeyOps::Incr(a.clone())), state.clone()).await;
+        assert!(reply.is_error());
+        assert_eq!(effects, None);
+        assert_eq!(state.writes.clock(), before + 1);
+
+        let dirty = state.writes.take_dirty();
+        assert!(!dirty.everything);
+        assert_eq!(dirty.keys.into_iter().collect::<Vec<_>>(), vec![a]);
+        assert!(state.writes.take_dirty().keys.is_empty());
+    }
+
+    #[test]
+    fn test_flushes_touch_every_key() {
+        let state = State::default();
+        let a = Bytes::from_static(b"a");
+        state.kv.insert(a.clone(), a.clone());
+        WriteEffects::new("Set", vec![a.clone()]).apply(&state);
+        let version = state.writes.version(&a);
+        let untouched = state.writes.version(b"b");
+        WriteEffects::new("FlushDB", Vec::new()).apply(&state);
+        assert_ne!(state.writes.version(&a), version);
+        assert_ne!(state.writes.version(b"b"), untouched);
+        assert!(state.writes.versions.is_empty());
+        assert!(state.writes.take_dirty().everything);
+    }
+
+    #[tokio::test]
+    async fn test_tracking_is_bounded() {
+        let store = StateStore::default();
+        let state = store.get_default();
+        let key = |i: usize| Bytes::from(i.to_string());
+        for i in 0..10 {
+            let op = KeyOps::Set(key(i), key(i), Default::default());
+            op_interact(Ops::Keys(op), state.clone()).await;
+        }
+        assert_eq!(state.writes.versions.len(), 10);
+
+        // Deleted keys lose their versions, which still change.
+        let version = state.writes.version(&key(0));
+        let del = KeyOps::Del(smallvec![key(0), key(1)]);
+        op_interact(Ops::Keys(del), state.clone()).await;
+        assert_eq!(state.writes.versions.len(), 8);
+        assert_ne!(state.writes.version(&key(0)), version);
+
+        // Persisting takes the dirty keys.
+        assert_eq!(state.writes.take_dirty().keys.len(), 10);
+        assert!(state.writes.take_dirty().keys.is_empty());
+
+        // Swapping dbs forgets the keys of both.
+        store.swap_dbs(0, 1);
+        assert!(state.writes.versions.is_empty());
+        assert!(state.writes.take_dirty().everything);
+
+        // Past the limit, the whole db is dirty instead.
+        for i in 0..=DIRTY_KEYS_LIMIT {
+            WriteEffects::new("Set", vec![key(i)]).apply(&state);
+        }
+        let dirty = state.writes.take_dirty();
+        assert!(dirty.everything);
+        assert!(dirty.keys.is_empty());
+    }
+}